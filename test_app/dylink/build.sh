@@ -0,0 +1,32 @@
+#!/bin/sh
+# Builds the main and side module pair that the DynamicLinker tests load.
+#
+# With emscripten, the same pair comes from
+#
+#     emcc -O2 -sSIDE_MODULE=1 side.c -o side.wasm
+#     emcc -O2 -sMAIN_MODULE=2 main.c side.wasm -o main.wasm
+#
+# or with clang and wasm-ld, from
+#
+#     clang --target=wasm32-unknown-emscripten -O2 -fPIC -S -emit-llvm side.c -o side.ll
+#     clang --target=wasm32-unknown-emscripten -O2 -fPIC -S -emit-llvm main.c -o main.ll
+#
+# and then the steps below. The .ll files checked in are the ones these wasm files were built from,
+# with llc 14 and the wasm-ld that ships with the Rust toolchain (LLD 22), where clang itself
+# wasn't available. They were written to match the C rather than generated from it.
+set -e
+cd "$(dirname "$0")"
+
+LLC=${LLC:-llc}
+WASM_LD=${WASM_LD:-wasm-ld}
+
+$LLC -mtriple=wasm32-unknown-emscripten -relocation-model=pic -O2 -filetype=obj side.ll -o side.o
+$LLC -mtriple=wasm32-unknown-emscripten -relocation-model=pic -O2 -filetype=obj main.ll -o main.o
+
+# The side module imports what it doesn't define, and the main module is linked against it, which
+# is what puts side.wasm in its dylink.0 section's needed list
+$WASM_LD --experimental-pic -shared --unresolved-symbols=import-dynamic -o side.wasm side.o
+$WASM_LD --experimental-pic -pie --no-entry --import-memory --import-table --export-dynamic \
+    -o main.wasm main.o side.wasm
+
+rm side.o main.o
@@ -0,0 +1,18 @@
+// The main module, which needs side.wasm
+int side_add(int x);
+int (*side_pick(void))(int);
+extern int side_counter;
+
+int main_value = 7;
+
+int main_callback(int x) {
+    return main_value * x;
+}
+
+int run(int x) {
+    return side_add(x) + side_counter;
+}
+
+int run_indirect(int x) {
+    return side_pick()(x);
+}
@@ -0,0 +1,31 @@
+; main.c, as clang -O2 lowers it for wasm32
+target datalayout = "e-m:e-p:32:32-p10:8:8-p20:8:8-i64:64-n32:64-S128-ni:1:10:20"
+target triple = "wasm32-unknown-emscripten"
+
+@main_value = global i32 7, align 4
+@side_counter = external global i32, align 4
+
+define i32 @main_callback(i32 %x) {
+entry:
+  %0 = load i32, i32* @main_value, align 4
+  %mul = mul nsw i32 %0, %x
+  ret i32 %mul
+}
+
+define i32 @run(i32 %x) {
+entry:
+  %call = tail call i32 @side_add(i32 %x)
+  %0 = load i32, i32* @side_counter, align 4
+  %add = add nsw i32 %0, %call
+  ret i32 %add
+}
+
+define i32 @run_indirect(i32 %x) {
+entry:
+  %call = tail call i32 (i32)* @side_pick()
+  %call1 = tail call i32 %call(i32 %x)
+  ret i32 %call1
+}
+
+declare i32 @side_add(i32)
+declare i32 (i32)* @side_pick()
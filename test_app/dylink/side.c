@@ -0,0 +1,16 @@
+// A side module: a shared library that the main module needs loaded
+extern int main_value;
+int main_callback(int x);
+
+int side_counter = 100;
+
+// Called by the main module, and calls back into it
+int side_add(int x) {
+    side_counter += x;
+    return main_callback(x) + main_value + side_counter;
+}
+
+// Hands the main module back a pointer to one of its own functions
+int (*side_pick(void))(int) {
+    return main_callback;
+}
@@ -0,0 +1,25 @@
+; side.c, as clang -O2 lowers it for wasm32
+target datalayout = "e-m:e-p:32:32-p10:8:8-p20:8:8-i64:64-n32:64-S128-ni:1:10:20"
+target triple = "wasm32-unknown-emscripten"
+
+@side_counter = global i32 100, align 4
+@main_value = external global i32, align 4
+
+define i32 @side_add(i32 %x) {
+entry:
+  %0 = load i32, i32* @side_counter, align 4
+  %add = add nsw i32 %0, %x
+  store i32 %add, i32* @side_counter, align 4
+  %call = tail call i32 @main_callback(i32 %x)
+  %1 = load i32, i32* @main_value, align 4
+  %add1 = add nsw i32 %call, %1
+  %add2 = add nsw i32 %add1, %add
+  ret i32 %add2
+}
+
+define i32 (i32)* @side_pick() {
+entry:
+  ret i32 (i32)* @main_callback
+}
+
+declare i32 @main_callback(i32)
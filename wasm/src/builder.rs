@@ -0,0 +1,325 @@
+use anyhow::Result;
+use std::io::Write;
+
+use crate::core::{
+    Data, ElemType, Element, Export, ExportDesc, Expr, Func, FuncType, GlobalDef, GlobalType,
+    Import, ImportDesc, Limits, Locals, MemType, RawModule, TableType, ValueType,
+};
+pub use crate::parser::Instr;
+use crate::parser::Opcode;
+use crate::writer::WriterUtil;
+
+impl Instr {
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let write_opcode = |writer: &mut W, opcode: Opcode| writer.write_u8(opcode.into());
+
+        match self {
+            Instr::Unreachable => write_opcode(writer, Opcode::Unreachable),
+            Instr::Nop => write_opcode(writer, Opcode::Nop),
+            Instr::Block(block_type) | Instr::Loop(block_type) | Instr::If(block_type) => {
+                let opcode = match self {
+                    Instr::Block(_) => Opcode::Block,
+                    Instr::Loop(_) => Opcode::Loop,
+                    _ => Opcode::If,
+                };
+                write_opcode(writer, opcode)?;
+                writer.write_u8(block_type.clone().into())
+            }
+            Instr::Else => write_opcode(writer, Opcode::Else),
+            Instr::End => write_opcode(writer, Opcode::End),
+            Instr::Br(label) => {
+                write_opcode(writer, Opcode::Br)?;
+                writer.write_leb_u32(*label)
+            }
+            Instr::BrIf(label) => {
+                write_opcode(writer, Opcode::BrIf)?;
+                writer.write_leb_u32(*label)
+            }
+            Instr::BrTable(labels, default) => {
+                write_opcode(writer, Opcode::BrTable)?;
+                writer.write_vec(labels, |w, label| w.write_leb_u32(*label))?;
+                writer.write_leb_u32(*default)
+            }
+            Instr::Return => write_opcode(writer, Opcode::Return),
+            Instr::Call(func_idx) => {
+                write_opcode(writer, Opcode::Call)?;
+                writer.write_leb_u32(*func_idx)
+            }
+            Instr::CallIndirect(type_idx) => {
+                write_opcode(writer, Opcode::CallIndirect)?;
+                writer.write_leb_u32(*type_idx)?;
+                writer.write_u8(0)
+            }
+            Instr::Drop => write_opcode(writer, Opcode::Drop),
+            Instr::Select => write_opcode(writer, Opcode::Select),
+            Instr::LocalGet(idx) => {
+                write_opcode(writer, Opcode::LocalGet)?;
+                writer.write_leb_u32(*idx)
+            }
+            Instr::LocalSet(idx) => {
+                write_opcode(writer, Opcode::LocalSet)?;
+                writer.write_leb_u32(*idx)
+            }
+            Instr::LocalTee(idx) => {
+                write_opcode(writer, Opcode::LocalTee)?;
+                writer.write_leb_u32(*idx)
+            }
+            Instr::GlobalGet(idx) => {
+                write_opcode(writer, Opcode::GlobalGet)?;
+                writer.write_leb_u32(*idx)
+            }
+            Instr::GlobalSet(idx) => {
+                write_opcode(writer, Opcode::GlobalSet)?;
+                writer.write_leb_u32(*idx)
+            }
+            Instr::Memory(opcode, align, offset) => {
+                write_opcode(writer, *opcode)?;
+                writer.write_leb_u32(*align)?;
+                writer.write_leb_u32(*offset)
+            }
+            Instr::MemorySize => {
+                write_opcode(writer, Opcode::MemorySize)?;
+                writer.write_u8(0)
+            }
+            Instr::MemoryGrow => {
+                write_opcode(writer, Opcode::MemoryGrow)?;
+                writer.write_u8(0)
+            }
+            Instr::I32Const(value) => {
+                write_opcode(writer, Opcode::I32Const)?;
+                writer.write_leb_i32(*value)
+            }
+            Instr::I64Const(value) => {
+                write_opcode(writer, Opcode::I64Const)?;
+                writer.write_leb_i64(*value)
+            }
+            Instr::F32Const(value) => {
+                write_opcode(writer, Opcode::F32Const)?;
+                writer.write_all(&value.to_le_bytes())?;
+                Ok(())
+            }
+            Instr::F64Const(value) => {
+                write_opcode(writer, Opcode::F64Const)?;
+                writer.write_all(&value.to_le_bytes())?;
+                Ok(())
+            }
+            Instr::Op(opcode) => write_opcode(writer, *opcode),
+            Instr::Raw(bytes) => {
+                writer.write_all(bytes)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+// Encodes a sequence of instructions as an expression, adding the end that closes it
+fn make_expr(instructions: &[Instr]) -> Expr {
+    let mut bytes = Vec::new();
+    for instruction in instructions.iter().chain(std::iter::once(&Instr::End)) {
+        instruction
+            .write(&mut bytes)
+            .expect("Writing to a vector can't fail");
+    }
+    Expr::new(bytes)
+}
+
+// Groups runs of locals with the same type, the way the binary format stores them
+fn make_locals(locals: &[ValueType]) -> Vec<Locals> {
+    let mut grouped: Vec<Locals> = Vec::new();
+    for local in locals {
+        match grouped.last_mut() {
+            Some(last) if last.value_type() == *local => {
+                *last = Locals::new(last.count() + 1, local.clone());
+            }
+            _ => grouped.push(Locals::new(1, local.clone())),
+        }
+    }
+    grouped
+}
+
+/// Builds a `RawModule` in memory, for tests and code generation, without going through the
+/// binary format.
+///
+/// Function indices count imported functions first, the same as in a binary module, so the
+/// first defined function's index is the number of function imports added.
+///
+/// ```
+/// use wasm::builder::{Instr, RawModuleBuilder};
+/// use wasm::core::{EmptyResolver, FuncType, Module, ValueType};
+/// use wasm::parser::Opcode;
+///
+/// let raw = RawModuleBuilder::new()
+///     .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+///     .add_function(
+///         0,
+///         vec![],
+///         vec![Instr::LocalGet(0), Instr::I32Const(2), Instr::Op(Opcode::I32Mul)],
+///     )
+///     .add_memory(1, None)
+///     .export_func("double", 0)
+///     .build();
+///
+/// let mut instance = Module::new(raw).instantiate(EmptyResolver::instance()).unwrap();
+/// assert_eq!(
+///     instance.invoke_export("double", &[21_i32.into()]).unwrap(),
+///     [42_i32.into()]
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct RawModuleBuilder {
+    types: Vec<FuncType>,
+    typeidx: Vec<usize>,
+    funcs: Vec<Func>,
+    tables: Vec<TableType>,
+    mems: Vec<MemType>,
+    globals: Vec<GlobalDef>,
+    elem: Vec<Element>,
+    data: Vec<Data>,
+    start: Option<usize>,
+    imports: Vec<Import>,
+    exports: Vec<Export>,
+}
+
+impl RawModuleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_type(mut self, func_type: FuncType) -> Self {
+        self.types.push(func_type);
+        self
+    }
+
+    pub fn add_function(
+        self,
+        type_idx: usize,
+        locals: Vec<ValueType>,
+        instructions: Vec<Instr>,
+    ) -> Self {
+        let body = make_expr(&instructions);
+        self.add_function_expr(type_idx, locals, body)
+    }
+
+    // For a body that has already been encoded, including the end instruction
+    pub fn add_function_bytes(
+        self,
+        type_idx: usize,
+        locals: Vec<ValueType>,
+        bytes: Vec<u8>,
+    ) -> Self {
+        self.add_function_expr(type_idx, locals, Expr::new(bytes))
+    }
+
+    fn add_function_expr(mut self, type_idx: usize, locals: Vec<ValueType>, body: Expr) -> Self {
+        self.typeidx.push(type_idx);
+        self.funcs.push(Func::new(make_locals(&locals), body));
+        self
+    }
+
+    pub fn add_table(mut self, min: usize, max: Option<usize>) -> Self {
+        self.tables
+            .push(TableType::new(ElemType::FuncRef, Limits::new(min, max)));
+        self
+    }
+
+    pub fn add_memory(mut self, min: usize, max: Option<usize>) -> Self {
+        self.mems.push(MemType::new(Limits::new(min, max)));
+        self
+    }
+
+    pub fn add_global(mut self, global_type: GlobalType, init: Vec<Instr>) -> Self {
+        self.globals
+            .push(GlobalDef::new(global_type, make_expr(&init)));
+        self
+    }
+
+    pub fn add_elem(
+        mut self,
+        table_idx: usize,
+        offset: Vec<Instr>,
+        func_indices: Vec<usize>,
+    ) -> Self {
+        self.elem
+            .push(Element::new(table_idx, make_expr(&offset), func_indices));
+        self
+    }
+
+    // For a segment that isn't laid out like add_elem's, such as a passive one or one with
+    // expressions for its entries
+    pub fn add_element(mut self, element: Element) -> Self {
+        self.elem.push(element);
+        self
+    }
+
+    pub fn add_data(mut self, mem_idx: usize, offset: Vec<Instr>, bytes: Vec<u8>) -> Self {
+        self.data
+            .push(Data::new(mem_idx, make_expr(&offset), bytes));
+        self
+    }
+
+    pub fn start(mut self, func_idx: usize) -> Self {
+        self.start = Some(func_idx);
+        self
+    }
+
+    fn import(mut self, mod_name: &str, name: &str, desc: ImportDesc) -> Self {
+        self.imports
+            .push(Import::new(mod_name.to_string(), name.to_string(), desc));
+        self
+    }
+
+    pub fn import_func(self, mod_name: &str, name: &str, type_idx: usize) -> Self {
+        self.import(mod_name, name, ImportDesc::TypeIdx(type_idx))
+    }
+
+    pub fn import_table(self, mod_name: &str, name: &str, min: usize, max: Option<usize>) -> Self {
+        let table_type = TableType::new(ElemType::FuncRef, Limits::new(min, max));
+        self.import(mod_name, name, ImportDesc::TableType(table_type))
+    }
+
+    pub fn import_memory(self, mod_name: &str, name: &str, min: usize, max: Option<usize>) -> Self {
+        let mem_type = MemType::new(Limits::new(min, max));
+        self.import(mod_name, name, ImportDesc::MemType(mem_type))
+    }
+
+    pub fn import_global(self, mod_name: &str, name: &str, global_type: GlobalType) -> Self {
+        self.import(mod_name, name, ImportDesc::GlobalType(global_type))
+    }
+
+    fn export(mut self, name: &str, desc: ExportDesc) -> Self {
+        self.exports.push(Export::new(name.to_string(), desc));
+        self
+    }
+
+    pub fn export_func(self, name: &str, func_idx: usize) -> Self {
+        self.export(name, ExportDesc::Func(func_idx))
+    }
+
+    pub fn export_table(self, name: &str, table_idx: usize) -> Self {
+        self.export(name, ExportDesc::Table(table_idx))
+    }
+
+    pub fn export_memory(self, name: &str, mem_idx: usize) -> Self {
+        self.export(name, ExportDesc::Mem(mem_idx))
+    }
+
+    pub fn export_global(self, name: &str, global_idx: usize) -> Self {
+        self.export(name, ExportDesc::Global(global_idx))
+    }
+
+    pub fn build(self) -> RawModule {
+        RawModule::new(
+            self.types,
+            self.typeidx,
+            self.funcs,
+            self.tables,
+            self.mems,
+            self.globals,
+            self.elem,
+            self.data,
+            self.start,
+            self.imports,
+            self.exports,
+        )
+    }
+}
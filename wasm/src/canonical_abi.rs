@@ -0,0 +1,177 @@
+// Passing strings and lists in and out of a module the way the component model's canonical ABI
+// does, which is what wit-bindgen and most toolchains generate for compound values:
+//
+// * A string or list is passed as a pointer and a length, both i32. A string's length is in
+//   bytes of UTF-8, and a list's is in elements.
+// * Memory for values passed into the module is allocated by calling the module's realloc
+//   export, cabi_realloc(old_ptr, old_size, align, new_size) -> ptr. The module owns that memory
+//   once it has been passed to it.
+// * A function with more than one flat result, such as one returning a string, returns a pointer
+//   to a return area with the results in it, which is what read_pair reads.
+// * The results belong to the module, which frees them when the host calls
+//   cabi_post_<export>, if there is one, with the results the export returned. post_return does
+//   that, once everything has been lifted out.
+use anyhow::{anyhow, Context, Result};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::mem;
+use std::rc::Rc;
+
+use crate::core::{stack_entry::StackEntry, ExportKind, Extern, Instance, LEByteConvert, Memory};
+
+pub const DEFAULT_REALLOC: &str = "cabi_realloc";
+
+pub struct CanonicalAbi<'a> {
+    instance: &'a mut Instance,
+    memory: Rc<RefCell<Memory>>,
+    realloc: String,
+}
+
+impl<'a> CanonicalAbi<'a> {
+    // For a module that exports its memory as "memory" and its allocator as cabi_realloc
+    pub fn new(instance: &'a mut Instance) -> Result<Self> {
+        Self::with_allocator(instance, DEFAULT_REALLOC)
+    }
+
+    // For a module whose allocator is exported with another name, but has the same signature
+    pub fn with_allocator(instance: &'a mut Instance, realloc: &str) -> Result<Self> {
+        let memory = match instance
+            .get_export("memory", ExportKind::Memory)
+            .context("The module doesn't export its memory as \"memory\"")?
+        {
+            Extern::Memory(memory) => memory.clone(),
+            _ => unreachable!(),
+        };
+        instance
+            .get_function(realloc)
+            .with_context(|| format!("The module doesn't export an allocator {}", realloc))?;
+
+        Ok(Self {
+            instance,
+            memory,
+            realloc: realloc.to_string(),
+        })
+    }
+
+    // The instance, for calling the functions that the lowered values are passed to
+    pub fn instance(&mut self) -> &mut Instance {
+        self.instance
+    }
+
+    pub fn memory(&self) -> &Rc<RefCell<Memory>> {
+        &self.memory
+    }
+
+    // Asks the module for a new allocation, which has to come back aligned
+    pub fn allocate(&mut self, size: u32, align: u32) -> Result<u32> {
+        let args = [0_u32.into(), 0_u32.into(), align.into(), size.into()];
+        let results = self.instance.invoke_export(&self.realloc, &args)?;
+        let ptr = match results.as_slice() {
+            [result] => u32::try_from(*result)?,
+            _ => return Err(anyhow!("{} should return a pointer", self.realloc)),
+        };
+
+        if ptr % align != 0 {
+            return Err(anyhow!(
+                "{} returned {:#x}, which isn't aligned to {}",
+                self.realloc,
+                ptr,
+                align
+            ));
+        }
+        Ok(ptr)
+    }
+
+    // Copies the string into a new allocation, and returns its pointer and its length in bytes
+    pub fn lower_string(&mut self, value: &str) -> Result<(u32, u32)> {
+        self.lower_bytes(value.as_bytes(), 1)
+    }
+
+    pub fn lower_list_u8(&mut self, values: &[u8]) -> Result<(u32, u32)> {
+        self.lower_bytes(values, 1)
+    }
+
+    // Copies a list of scalars into a new allocation aligned to their size, and returns its
+    // pointer and its length in elements
+    pub fn lower_list<T: LEByteConvert>(&mut self, values: &[T]) -> Result<(u32, u32)> {
+        let size = mem::size_of::<T>();
+        let mut bytes = Vec::with_capacity(mem::size_of_val(values));
+        for value in values {
+            bytes.extend_from_slice(&value.to_bytes());
+        }
+
+        let (ptr, _) = self.lower_bytes(&bytes, size as u32)?;
+        Ok((ptr, list_len(values.len())?))
+    }
+
+    fn lower_bytes(&mut self, bytes: &[u8], align: u32) -> Result<(u32, u32)> {
+        let len = list_len(bytes.len())?;
+        let ptr = self.allocate(len, align)?;
+        self.memory.borrow_mut().set_data(ptr as usize, bytes)?;
+        Ok((ptr, len))
+    }
+
+    // Copies a string out of the module's memory. It has to be valid UTF-8.
+    pub fn lift_string(&self, ptr: u32, len: u32) -> Result<String> {
+        let bytes = self.lift_list_u8(ptr, len)?;
+        String::from_utf8(bytes)
+            .map_err(|e| anyhow!("String at {:#x} isn't valid UTF-8: {}", ptr, e.utf8_error()))
+    }
+
+    pub fn lift_list_u8(&self, ptr: u32, len: u32) -> Result<Vec<u8>> {
+        let memory = self.memory.borrow();
+        // A bad length shouldn't make for a huge allocation before it's found to be bad
+        memory.check_bounds(ptr as usize, len as usize, false)?;
+        let mut bytes = vec![0; len as usize];
+        memory.get_data(ptr as usize, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    // Copies a list of scalars out of the module's memory, which has to be aligned to their size
+    pub fn lift_list<T: LEByteConvert>(&self, ptr: u32, len: u32) -> Result<Vec<T>> {
+        let size = mem::size_of::<T>();
+        if ptr as usize & (size - 1) != 0 {
+            return Err(anyhow!(
+                "List at {:#x} isn't aligned to {}, the size of its elements",
+                ptr,
+                size
+            ));
+        }
+
+        let memory = self.memory.borrow();
+        let byte_len = (len as usize)
+            .checked_mul(size)
+            .ok_or_else(|| anyhow!("A list of {} elements is too long for wasm", len))?;
+        memory.check_bounds(ptr as usize, byte_len, false)?;
+        (0..len as usize)
+            .map(|idx| memory.read::<T>(ptr as usize + idx * size))
+            .collect()
+    }
+
+    // Reads the pointer and length that a function returning a string or list left in its return
+    // area
+    pub fn read_pair(&self, retptr: u32) -> Result<(u32, u32)> {
+        if retptr & 3 != 0 {
+            return Err(anyhow!("Return area {:#x} isn't aligned to 4", retptr));
+        }
+        let memory = self.memory.borrow();
+        Ok((
+            memory.read_u32(retptr as usize)?,
+            memory.read_u32(retptr as usize + 4)?,
+        ))
+    }
+
+    // Lets the module free the results an export returned, once they've been lifted. Exports
+    // without a cabi_post_ function don't need anything freeing.
+    pub fn post_return(&mut self, export: &str, results: &[StackEntry]) -> Result<()> {
+        let post = format!("cabi_post_{}", export);
+        if self.instance.exports.contains_key(&post) {
+            self.instance.invoke_export(&post, results)?;
+        }
+        Ok(())
+    }
+}
+
+fn list_len(len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| anyhow!("A list of {} elements is too long for wasm", len))
+}
@@ -0,0 +1,273 @@
+// Reading the outer structure of a component model binary, far enough to pull out the core
+// modules embedded in it and to say what the component imports and exports. Components can't be
+// instantiated, as that needs the canonical ABI lifting and lowering and component level linking,
+// but a core module that doesn't depend on any of that can be run on its own:
+//
+//     let component = Component::read(&bytes)?;
+//     let module = Module::new(component.core_modules[0].clone());
+//
+// Only the core module, nested component, import and export sections are decoded. The others,
+// such as the type, canon and alias sections, are skipped over by their size.
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+
+use crate::core::RawModule;
+use crate::reader::ReaderUtil;
+
+// The magic number, then version 0x0d and layer 1, which is what sets it apart from a core module
+pub const COMPONENT_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x0d, 0x00, 0x01, 0x00];
+
+const CUSTOM_SECTION: u8 = 0;
+const CORE_MODULE_SECTION: u8 = 1;
+const COMPONENT_SECTION: u8 = 4;
+const IMPORT_SECTION: u8 = 10;
+const EXPORT_SECTION: u8 = 11;
+
+// Whether the bytes start like a component, whatever its version, rather than a core module
+pub fn is_component(bytes: &[u8]) -> bool {
+    bytes.get(..4) == Some(&COMPONENT_HEADER[..4]) && bytes.get(6..8) == Some(&[0x01, 0x00])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentItemKind {
+    CoreFunc,
+    CoreTable,
+    CoreMemory,
+    CoreGlobal,
+    CoreType,
+    CoreModule,
+    CoreInstance,
+    Func,
+    Value,
+    Type,
+    Component,
+    Instance,
+}
+
+impl fmt::Display for ComponentItemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ComponentItemKind::CoreFunc => "core func",
+            ComponentItemKind::CoreTable => "core table",
+            ComponentItemKind::CoreMemory => "core memory",
+            ComponentItemKind::CoreGlobal => "core global",
+            ComponentItemKind::CoreType => "core type",
+            ComponentItemKind::CoreModule => "core module",
+            ComponentItemKind::CoreInstance => "core instance",
+            ComponentItemKind::Func => "func",
+            ComponentItemKind::Value => "value",
+            ComponentItemKind::Type => "type",
+            ComponentItemKind::Component => "component",
+            ComponentItemKind::Instance => "instance",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// An import or export of a component, by name and by what sort of thing it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentItem {
+    pub name: String,
+    pub kind: ComponentItemKind,
+}
+
+#[derive(Debug)]
+pub struct Component {
+    // The core modules in the order they appear, not counting those in nested components
+    pub core_modules: Vec<RawModule>,
+    pub components: Vec<Component>,
+    pub imports: Vec<ComponentItem>,
+    pub exports: Vec<ComponentItem>,
+}
+
+impl Component {
+    pub fn read(bytes: &[u8]) -> Result<Self> {
+        if !is_component(bytes) {
+            return Err(anyhow!("Invalid component header"));
+        }
+        if bytes[..COMPONENT_HEADER.len()] != COMPONENT_HEADER {
+            return Err(anyhow!(
+                "Component version 0x{:02x}{:02x} isn't supported",
+                bytes[5],
+                bytes[4]
+            ));
+        }
+
+        let mut component = Component {
+            core_modules: Vec::new(),
+            components: Vec::new(),
+            imports: Vec::new(),
+            exports: Vec::new(),
+        };
+
+        let mut remaining = &bytes[COMPONENT_HEADER.len()..];
+        while !remaining.is_empty() {
+            let offset = bytes.len() - remaining.len();
+            let id = remaining.read_u8()?;
+            let size = remaining.read_leb_usize()?;
+            if size > remaining.len() {
+                return Err(anyhow!(
+                    "Section at offset 0x{:x} is {} bytes, but the file ends before that",
+                    offset,
+                    size
+                ));
+            }
+            let (payload, rest) = remaining.split_at(size);
+            remaining = rest;
+
+            component
+                .read_section(id, payload)
+                .with_context(|| format!("Invalid section {} at offset 0x{:x}", id, offset))?;
+        }
+        Ok(component)
+    }
+
+    fn read_section(&mut self, id: u8, mut payload: &[u8]) -> Result<()> {
+        match id {
+            CORE_MODULE_SECTION => {
+                let module =
+                    RawModule::from_shared(payload.to_vec().into()).with_context(|| {
+                        format!("Core module {} isn't valid", self.core_modules.len())
+                    })?;
+                self.core_modules.push(module);
+            }
+            COMPONENT_SECTION => self.components.push(Component::read(payload)?),
+            IMPORT_SECTION => {
+                let imports = payload.read_vec(|reader| {
+                    let name = read_extern_name(reader)?;
+                    let kind = read_extern_desc(reader)?;
+                    Ok(ComponentItem { name, kind })
+                })?;
+                self.imports.extend(imports);
+            }
+            EXPORT_SECTION => {
+                let exports = payload.read_vec(|reader| {
+                    let name = read_extern_name(reader)?;
+                    let kind = read_sort(reader)?;
+                    reader.read_leb_u32()?;
+                    // The type it's exported as can be given too
+                    match reader.read_u8()? {
+                        0x00 => {}
+                        0x01 => {
+                            read_extern_desc(reader)?;
+                        }
+                        byte => return Err(anyhow!("Invalid export type flag 0x{:02x}", byte)),
+                    }
+                    Ok(ComponentItem { name, kind })
+                })?;
+                self.exports.extend(exports);
+            }
+            // The custom sections and everything else that's needed to instantiate it
+            CUSTOM_SECTION | 2..=3 | 5..=9 | 12..=13 => {}
+            _ => return Err(anyhow!("Unknown component section")),
+        }
+        Ok(())
+    }
+}
+
+// A summary of what's in the component, for when it's been given to something that can only run
+// core modules
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Component with {} core module{} and {} nested component{}",
+            self.core_modules.len(),
+            if self.core_modules.len() == 1 {
+                ""
+            } else {
+                "s"
+            },
+            self.components.len(),
+            if self.components.len() == 1 { "" } else { "s" },
+        )?;
+        for import in &self.imports {
+            writeln!(f, "  import {} ({})", import.name, import.kind)?;
+        }
+        for export in &self.exports {
+            writeln!(f, "  export {} ({})", export.name, export.kind)?;
+        }
+        Ok(())
+    }
+}
+
+// Names are a flag and then the name. With the flag 0x01 there's another string after the name,
+// which was a URL in older binaries, and is skipped.
+fn read_extern_name(reader: &mut &[u8]) -> Result<String> {
+    match reader.read_u8()? {
+        0x00 => reader.read_name(),
+        0x01 => {
+            let name = reader.read_name()?;
+            reader.read_name()?;
+            Ok(name)
+        }
+        byte => Err(anyhow!("Invalid name flag 0x{:02x}", byte)),
+    }
+}
+
+fn read_sort(reader: &mut &[u8]) -> Result<ComponentItemKind> {
+    let kind = match reader.read_u8()? {
+        0x00 => match reader.read_u8()? {
+            0x00 => ComponentItemKind::CoreFunc,
+            0x01 => ComponentItemKind::CoreTable,
+            0x02 => ComponentItemKind::CoreMemory,
+            0x03 => ComponentItemKind::CoreGlobal,
+            0x10 => ComponentItemKind::CoreType,
+            0x11 => ComponentItemKind::CoreModule,
+            0x12 => ComponentItemKind::CoreInstance,
+            byte => return Err(anyhow!("Invalid core sort 0x{:02x}", byte)),
+        },
+        0x01 => ComponentItemKind::Func,
+        0x02 => ComponentItemKind::Value,
+        0x03 => ComponentItemKind::Type,
+        0x04 => ComponentItemKind::Component,
+        0x05 => ComponentItemKind::Instance,
+        byte => return Err(anyhow!("Invalid sort 0x{:02x}", byte)),
+    };
+    Ok(kind)
+}
+
+// What sort of thing an import is, skipping over the type it has to have
+fn read_extern_desc(reader: &mut &[u8]) -> Result<ComponentItemKind> {
+    let kind = match reader.read_u8()? {
+        0x00 => match reader.read_u8()? {
+            0x11 => {
+                reader.read_leb_u32()?;
+                ComponentItemKind::CoreModule
+            }
+            byte => return Err(anyhow!("Invalid core import type 0x{:02x}", byte)),
+        },
+        0x01 => {
+            reader.read_leb_u32()?;
+            ComponentItemKind::Func
+        }
+        // Either equal to another value, or of a value type, which is a signed LEB128 integer
+        0x02 => {
+            reader.read_u8()?;
+            skip_leb(reader)?;
+            ComponentItemKind::Value
+        }
+        // Either equal to another type, or a new resource type
+        0x03 => {
+            if reader.read_u8()? == 0x00 {
+                reader.read_leb_u32()?;
+            }
+            ComponentItemKind::Type
+        }
+        0x04 => {
+            reader.read_leb_u32()?;
+            ComponentItemKind::Component
+        }
+        0x05 => {
+            reader.read_leb_u32()?;
+            ComponentItemKind::Instance
+        }
+        byte => return Err(anyhow!("Invalid import type 0x{:02x}", byte)),
+    };
+    Ok(kind)
+}
+
+fn skip_leb(reader: &mut &[u8]) -> Result<()> {
+    while reader.read_u8()? & 0x80 != 0 {}
+    Ok(())
+}
@@ -1,24 +1,101 @@
+mod budget;
+mod call_graph;
+mod call_observer;
 mod callable;
+mod caller;
+mod compatibility;
 mod core_types;
+mod disassemble;
+mod dylink;
+mod dynamic_linker;
+mod execution_summary;
 mod executor;
+mod export_lookup;
+mod extern_ref;
+mod externs;
+mod features;
 mod global;
+mod import_error;
+mod instance;
+mod instance_pool;
+#[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+mod mapped_file;
 mod memory;
+mod memory_io;
 pub mod memory_page;
 mod module;
+pub(crate) mod module_hash;
+mod name_section;
+pub(crate) mod phase_trace;
+#[cfg(all(
+    feature = "guard-pages",
+    any(unix, windows),
+    target_pointer_width = "64"
+))]
+mod reserved_memory;
 mod resolver;
+mod resource_metrics;
 mod section;
+mod shared_bytes;
 mod stack;
 pub mod stack_entry;
+mod stack_snapshot;
 mod table;
+mod trap;
+mod write_trace;
 
-pub use callable::{Callable, WasmExprCallable};
+pub(crate) use budget::DATA_BYTES_PER_FUEL;
+pub use budget::{InstantiationBudgetExceeded, InterruptHandle};
+pub use call_graph::CallGraph;
+pub use call_observer::{CallObserver, CallOutcome, TraceEvent, TraceEventKind, TraceRecorder};
+pub use callable::{Callable, HostCallable, WasmExprCallable};
+pub use caller::Caller;
+pub use compatibility::{CompatibilityIssue, CompatibilityIssueKind, CompatibilityReport};
 pub use core_types::*;
-pub use executor::{evaluate_constant_expression, execute_expression, store_access};
+pub use dylink::{DylinkInfo, DYLINK_SECTION, SYMBOL_TLS, SYMBOL_WEAK};
+pub use dynamic_linker::{DynamicLinker, DynamicLinkerConfig, LoadedModule};
+pub(crate) use execution_summary::CountingStore;
+pub use execution_summary::ExecutionSummary;
+pub use executor::memory_access::LEByteConvert;
+pub use executor::{
+    evaluate_constant_expression, evaluate_reference_expression, execute_expression, store_access,
+};
+pub use export_lookup::ExportNotFound;
+pub use extern_ref::{ExternRef, ExternRefError, ExternRefTable};
+pub use externs::{Extern, ExternType};
+pub use features::{
+    Feature, FeatureSet, TargetFeature, TargetFeaturePrefix, TargetFeatures,
+    TARGET_FEATURES_SECTION,
+};
 pub use global::Global;
-pub use memory::Memory;
-pub use module::{ExportValue, Module, RawModule};
-pub use resolver::{EmptyResolver, Resolver};
+pub use import_error::{ImportError, ImportErrorReason};
+pub use instance::{
+    Completion, ExportKind, ExportValue, ImportType, Instance, InstanceOptions, ResolvedImport,
+};
+pub use instance_pool::{InstancePool, PoolConfig, PoolExhausted, PoolExhaustion};
+pub(crate) use instance_pool::{PoolSlot, PooledBuffer, PooledStack};
+#[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+pub(crate) use mapped_file::MappedFile;
+pub use memory::{AccessSite, Memory, MemoryAccess, WatchKind};
+pub use memory_io::{MemReader, MemWriter};
+pub use module::{Module, RawModule};
+pub(crate) use module_hash::{write_stream, ModuleHasher, ModuleHashes};
+pub use module_hash::{HashedSections, ModuleHash};
+pub use name_section::NameSection;
+#[cfg(feature = "phase-trace")]
+pub use phase_trace::{
+    find_field, set_phase_subscriber, Field, FieldValue, Phase, PhaseSubscriber,
+};
+pub use resolver::{CachingResolver, EmptyResolver, ImportObject, Resolver};
+pub use resource_metrics::ResourceMetrics;
 pub use section::SectionType;
-pub use stack::Stack;
+pub(crate) use shared_bytes::SharedBytes;
+pub use stack::{FrameInfo, Stack};
+pub use stack_snapshot::{FrameSnapshot, SnapshotLimits, StackSnapshot};
 pub use store_access::{ConstantExpressionStore, ExpressionStore};
 pub use table::Table;
+pub(crate) use trap::{attach_snapshot, locate_trap, name_trap_memory};
+pub use trap::{exit_code, Trap, TrapKind, TrapOrigin};
+pub use write_trace::{
+    first_write_mismatch, LineSink, WriteMismatch, WriteRecord, WriteSink, WriteTraceOptions,
+};
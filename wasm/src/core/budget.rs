@@ -0,0 +1,67 @@
+// Bounds on how long the guest can run for. Fuel is used up as the guest runs, one unit for each
+// instruction, and the guest traps with OutOfFuel when there isn't enough left for the next one.
+// An interrupt handle can stop it from anywhere, including another thread, at the next
+// instruction. Both apply to instantiation as well as to the calls made afterwards, so that a
+// start function that never finishes can't hang whatever is loading the module.
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::core::{Trap, TrapKind};
+
+// How many bytes of a data segment cost one unit of fuel to copy when a module is instantiated,
+// about what the stores to write them would. Each element of an element segment costs one.
+pub(crate) const DATA_BYTES_PER_FUEL: usize = 8;
+
+// Stops whatever the instance it was given to is running, at its next instruction. The guest
+// traps with Interrupted, and the interrupt is used up by the trap, so the next call runs as
+// normal. An interrupt made while nothing is running stops the next call as soon as it starts.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Release);
+    }
+
+    // Whether there's an interrupt waiting, clearing it if so. This runs for every instruction,
+    // so it only does the read-modify-write once a plain load has seen the flag set.
+    pub(crate) fn take(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed) && self.interrupted.swap(false, Ordering::AcqRel)
+    }
+}
+
+// Instantiation ran out of fuel or was interrupted, whether in the start function or while the
+// segments were being copied. It's the context of the trap that stopped it, so it can be found
+// with downcast_ref. The instance is dropped without being handed back, but anything imported
+// that was written to before it stopped, such as an imported memory, keeps what was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstantiationBudgetExceeded {
+    pub kind: TrapKind,
+}
+
+impl InstantiationBudgetExceeded {
+    // The trap's kind, if the error is one of the two that stop instantiation early
+    pub(crate) fn from_error(error: &anyhow::Error) -> Option<Self> {
+        match error.downcast_ref::<Trap>().map(|trap| trap.kind()) {
+            Some(kind @ TrapKind::OutOfFuel) | Some(kind @ TrapKind::Interrupted) => {
+                Some(Self { kind })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InstantiationBudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instantiation exceeded its budget: {}", self.kind)
+    }
+}
+
+impl std::error::Error for InstantiationBudgetExceeded {}
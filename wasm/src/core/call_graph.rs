@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::core::{self, RawModule};
+use crate::parser::Instr;
+
+// What each function calls, over the whole function index space. Imported functions are in the
+// graph, but since they have no bodies here they never call anything.
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    func_types: Vec<core::FuncType>,
+    names: Vec<Option<String>>,
+    direct_calls: Vec<BTreeSet<usize>>,
+    indirect_call_types: Vec<BTreeSet<usize>>,
+    types: Vec<core::FuncType>,
+    table_targets: BTreeSet<usize>,
+    exported: BTreeSet<usize>,
+    start: Option<usize>,
+}
+
+impl CallGraph {
+    pub fn num_functions(&self) -> usize {
+        self.direct_calls.len()
+    }
+
+    // The functions a function calls by index
+    pub fn direct_calls(&self, func_idx: usize) -> impl Iterator<Item = usize> + '_ {
+        self.direct_calls[func_idx].iter().cloned()
+    }
+
+    // The type indices a function makes indirect calls through
+    pub fn indirect_call_types(&self, func_idx: usize) -> impl Iterator<Item = usize> + '_ {
+        self.indirect_call_types[func_idx].iter().cloned()
+    }
+
+    // The functions that are put into tables by element segments, so can be called indirectly
+    pub fn table_targets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table_targets.iter().cloned()
+    }
+
+    // The exported functions and the start function, which are where anything can be called from
+    pub fn roots(&self) -> BTreeSet<usize> {
+        let mut roots = self.exported.clone();
+        roots.extend(self.start);
+        roots
+    }
+
+    // The functions an indirect call through a type could end up in
+    fn indirect_targets(&self, type_idx: usize) -> impl Iterator<Item = usize> + '_ {
+        let call_type = self.types.get(type_idx);
+        self.table_targets
+            .iter()
+            .cloned()
+            .filter(move |target| call_type.is_some() && call_type == self.func_types.get(*target))
+    }
+
+    // The functions that could be called at all, starting from the roots and following both direct
+    // calls and indirect calls to the table targets with a matching type
+    pub fn reachable(&self) -> BTreeSet<usize> {
+        let mut reachable = BTreeSet::new();
+        let mut pending: Vec<usize> = self.roots().into_iter().collect();
+
+        while let Some(func_idx) = pending.pop() {
+            if func_idx >= self.num_functions() || !reachable.insert(func_idx) {
+                continue;
+            }
+
+            pending.extend(self.direct_calls(func_idx));
+            for type_idx in self.indirect_call_types(func_idx) {
+                pending.extend(self.indirect_targets(type_idx));
+            }
+        }
+
+        reachable
+    }
+
+    pub fn is_reachable(&self, func_idx: usize) -> bool {
+        self.reachable().contains(&func_idx)
+    }
+
+    pub fn unreachable(&self) -> BTreeSet<usize> {
+        let reachable = self.reachable();
+        (0..self.num_functions())
+            .filter(|func_idx| !reachable.contains(func_idx))
+            .collect()
+    }
+
+    // Writes the graph out for graphviz. Direct calls are solid edges and indirect calls are
+    // dashed edges to each possible target, and the roots are drawn with a double border.
+    pub fn to_dot(&self) -> String {
+        let roots = self.roots();
+        let mut dot = String::from("digraph calls {\n");
+
+        for func_idx in 0..self.num_functions() {
+            let label = match &self.names[func_idx] {
+                Some(name) => format!("{}: {}", func_idx, name),
+                None => format!("{}", func_idx),
+            };
+            let shape = if roots.contains(&func_idx) {
+                ", peripheries=2"
+            } else {
+                ""
+            };
+            writeln!(dot, "  f{} [label=\"{}\"{}];", func_idx, label, shape).unwrap();
+        }
+
+        for func_idx in 0..self.num_functions() {
+            for callee in self.direct_calls(func_idx) {
+                writeln!(dot, "  f{} -> f{};", func_idx, callee).unwrap();
+            }
+
+            let indirect: BTreeSet<usize> = self
+                .indirect_call_types(func_idx)
+                .flat_map(|type_idx| self.indirect_targets(type_idx))
+                .collect();
+            for callee in indirect {
+                writeln!(dot, "  f{} -> f{} [style=dashed];", func_idx, callee).unwrap();
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl RawModule {
+    pub fn call_graph(&self) -> Result<CallGraph> {
+        let types = self.types().to_vec();
+        let func_type = |type_idx: usize| {
+            types
+                .get(type_idx)
+                .cloned()
+                .ok_or_else(|| anyhow!("Invalid function type index {}", type_idx))
+        };
+
+        // The type and name of every function, imported ones first
+        let mut func_types = Vec::new();
+        let mut names = Vec::new();
+        for import in self.imports() {
+            if let core::ImportDesc::TypeIdx(type_idx) = import.desc() {
+                let name = match self.func_name(func_types.len()) {
+                    Some(name) => name.to_string(),
+                    None => format!("{}::{}", import.mod_name(), import.name()),
+                };
+                func_types.push(func_type(*type_idx)?);
+                names.push(Some(name));
+            }
+        }
+        let num_imported = func_types.len();
+        for type_idx in self.func_type_indices() {
+            names.push(
+                self.func_name(func_types.len())
+                    .map(|name| name.to_string()),
+            );
+            func_types.push(func_type(*type_idx)?);
+        }
+
+        let mut direct_calls = vec![BTreeSet::new(); func_types.len()];
+        let mut indirect_call_types = vec![BTreeSet::new(); func_types.len()];
+        for (defined_idx, func) in self.funcs().iter().enumerate() {
+            let func_idx = num_imported + defined_idx;
+
+            for instruction in func.instructions() {
+                match instruction? {
+                    (_, Instr::Call(callee)) => {
+                        direct_calls[func_idx].insert(callee as usize);
+                    }
+                    (_, Instr::CallIndirect(type_idx)) => {
+                        indirect_call_types[func_idx].insert(type_idx as usize);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let table_targets = self
+            .elems()
+            .iter()
+            .flat_map(|elem| elem.referenced_funcs())
+            .collect();
+        let exported = self
+            .exports()
+            .iter()
+            .filter_map(|export| match export.d {
+                core::ExportDesc::Func(func_idx) => Some(func_idx),
+                _ => None,
+            })
+            .collect();
+
+        Ok(CallGraph {
+            func_types,
+            names,
+            direct_calls,
+            indirect_call_types,
+            types,
+            table_targets,
+            exported,
+            start: self.start(),
+        })
+    }
+}
@@ -0,0 +1,179 @@
+use crate::core::Module;
+use std::cell::RefCell;
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant};
+
+// How a call finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallOutcome {
+    Returned,
+    // The frame was unwound by a trap, or by any other error, including an exit
+    Trapped,
+}
+
+// Told about every call an instance makes, wasm and host functions alike, as the call starts and
+// as it finishes. Every on_enter is matched by an on_exit for the same function, including for
+// frames a trap unwinds, so the calls always nest and an observer can keep its own shadow stack.
+//
+// The function index is where the function is in the instance's function index space. It is
+// None for a host function that isn't called by its index, such as one called through a table
+// or exported from another module.
+pub trait CallObserver: fmt::Debug {
+    fn on_enter(&self, func_idx: Option<usize>, is_host: bool);
+
+    fn on_exit(&self, func_idx: Option<usize>, outcome: CallOutcome);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEventKind {
+    Enter { is_host: bool },
+    Exit(CallOutcome),
+}
+
+// A call or return, and when it happened, from when the recorder was made
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub func_idx: Option<usize>,
+    pub kind: TraceEventKind,
+    pub time: Duration,
+}
+
+// An observer that keeps every event, so the calls can be looked at afterwards, such as in a
+// flame graph from the Chrome trace-event JSON it writes out
+#[derive(Debug)]
+pub struct TraceRecorder {
+    start: Instant,
+    events: RefCell<Vec<TraceEvent>>,
+}
+
+impl TraceRecorder {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            events: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.events.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.events.borrow_mut().clear();
+    }
+
+    // The events in Chrome's trace-event format, which chrome://tracing and most flame graph
+    // tools can load. Functions are named from the module if there is one.
+    pub fn to_chrome_trace(&self, module: Option<&Module>) -> String {
+        let mut out = String::from("{\"traceEvents\":[");
+        // The category of each call that's still going, so that its end can have the same one
+        let mut categories = Vec::new();
+
+        for (idx, event) in self.events.borrow().iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+
+            let name = match (event.func_idx, module) {
+                (Some(func_idx), Some(module)) => module.describe_func(func_idx),
+                (Some(func_idx), None) => format!("func {}", func_idx),
+                (None, _) => "host function".to_string(),
+            };
+            let (phase, category, outcome) = match event.kind {
+                TraceEventKind::Enter { is_host } => {
+                    let category = if is_host { "host" } else { "wasm" };
+                    categories.push(category);
+                    ("B", category, None)
+                }
+                TraceEventKind::Exit(outcome) => {
+                    let category = categories.pop().unwrap_or("wasm");
+                    ("E", category, Some(outcome))
+                }
+            };
+
+            let _ = write!(
+                out,
+                "{{\"name\":{},\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":0,\"tid\":0",
+                json_string(&name),
+                category,
+                phase,
+                event.time.as_nanos() as f64 / 1000.0
+            );
+            if let Some(outcome) = outcome {
+                let outcome = match outcome {
+                    CallOutcome::Returned => "returned",
+                    CallOutcome::Trapped => "trapped",
+                };
+                let _ = write!(out, ",\"args\":{{\"outcome\":\"{}\"}}", outcome);
+            }
+            out.push('}');
+        }
+
+        out.push_str("]}");
+        out
+    }
+
+    fn record(&self, func_idx: Option<usize>, kind: TraceEventKind) {
+        self.events.borrow_mut().push(TraceEvent {
+            func_idx,
+            kind,
+            time: self.start.elapsed(),
+        });
+    }
+}
+
+impl Default for TraceRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CallObserver for TraceRecorder {
+    fn on_enter(&self, func_idx: Option<usize>, is_host: bool) {
+        self.record(func_idx, TraceEventKind::Enter { is_host });
+    }
+
+    fn on_exit(&self, func_idx: Option<usize>, outcome: CallOutcome) {
+        self.record(func_idx, TraceEventKind::Exit(outcome));
+    }
+}
+
+// Names come from the module's name section, so they can have anything in them
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chrome_trace() {
+        let recorder = TraceRecorder::new();
+        recorder.on_enter(Some(1), false);
+        recorder.on_enter(None, true);
+        recorder.on_exit(None, CallOutcome::Trapped);
+        recorder.on_exit(Some(1), CallOutcome::Trapped);
+
+        let trace = recorder.to_chrome_trace(None);
+        assert!(trace.starts_with("{\"traceEvents\":[{\"name\":\"func 1\",\"cat\":\"wasm\""));
+        assert_eq!(trace.matches("\"ph\":\"B\"").count(), 2);
+        assert_eq!(trace.matches("\"outcome\":\"trapped\"").count(), 2);
+        assert!(trace.contains("\"name\":\"host function\",\"cat\":\"host\",\"ph\":\"E\""));
+
+        assert_eq!(json_string("a\"b\\\n"), "\"a\\\"b\\\\\\u000a\"");
+    }
+}
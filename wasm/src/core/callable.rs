@@ -1,56 +1,285 @@
-use crate::core::{execute_expression, Expr, ExpressionStore, Func, FuncType, Locals, Stack};
-use anyhow::Result;
+use crate::core::{
+    attach_snapshot, execute_expression, stack_entry::StackEntry, CallOutcome, Caller, Expr,
+    ExpressionStore, Func, FuncType, Locals, Stack,
+};
+use crate::parser::InstructionSource;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::rc::Rc;
+
+/// A function implemented by the host rather than by wasm, such as one that forwards calls to
+/// another process, or wraps another callable to record or replay what it does.
+///
+/// The contract is:
+///
+/// * `func_type` is the signature the function is called with. It must not change, since it is
+///   what imports are matched against and what arguments and results are checked against.
+/// * `call` is given the arguments in order, already checked against the parameter types. It
+///   returns the results in order, and they are checked against the result types before they
+///   are handed back to wasm. Returning the wrong number or type of results is an error.
+/// * Returning an error stops execution, and the error is passed back out to whoever called into
+///   wasm. A `Trap` should be returned for the conditions the specification calls traps, so that
+///   the embedder can tell them apart with `downcast_ref::<Trap>()`. A trap for a reason of the
+///   host's own is made with `Trap::host` or `Trap::with_payload`, and comes back out of the
+///   invoke call as it was, however many wasm and host frames it unwinds through.
+///
+/// Any host callable can be turned into a `Callable` with `Callable::from_host`, which is what a
+/// resolver hands back to satisfy a function import. As an example, this wraps another host
+/// callable and records every call made through it:
+///
+/// ```
+/// use std::{cell::RefCell, convert::TryFrom, rc::Rc};
+/// use anyhow::Result;
+/// use wasm::core::{stack_entry::StackEntry, Callable, FuncType, HostCallable, ValueType};
+///
+/// #[derive(Debug)]
+/// struct Add {
+///     func_type: FuncType,
+/// }
+///
+/// impl HostCallable for Add {
+///     fn func_type(&self) -> &FuncType {
+///         &self.func_type
+///     }
+///
+///     fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+///         let (a, b) = (i32::try_from(args[0])?, i32::try_from(args[1])?);
+///         Ok(vec![a.wrapping_add(b).into()])
+///     }
+/// }
+///
+/// #[derive(Debug)]
+/// struct Recording {
+///     inner: Rc<dyn HostCallable>,
+///     calls: RefCell<Vec<(Vec<StackEntry>, Vec<StackEntry>)>>,
+/// }
+///
+/// impl HostCallable for Recording {
+///     fn func_type(&self) -> &FuncType {
+///         self.inner.func_type()
+///     }
+///
+///     fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+///         let results = self.inner.call(args)?;
+///         self.calls.borrow_mut().push((args.to_vec(), results.clone()));
+///         Ok(results)
+///     }
+/// }
+///
+/// let add = Add {
+///     func_type: FuncType::new(vec![ValueType::I32, ValueType::I32], vec![ValueType::I32]),
+/// };
+/// let recording = Rc::new(Recording {
+///     inner: Rc::new(add),
+///     calls: RefCell::new(Vec::new()),
+/// });
+///
+/// // This is what a resolver would return for the import
+/// let callable = Callable::from_host(recording.clone());
+/// assert!(callable.is_host());
+/// assert_eq!(format!("{}", callable.func_type()), "(i32, i32) -> i32");
+///
+/// recording.call(&[2_i32.into(), 3_i32.into()]).unwrap();
+/// assert_eq!(
+///     recording.calls.borrow()[0],
+///     (vec![2_i32.into(), 3_i32.into()], vec![5_i32.into()])
+/// );
+/// ```
+pub trait HostCallable: fmt::Debug {
+    fn func_type(&self) -> &FuncType;
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>>;
+
+    // What wasm calls the function with. A function that needs to get at the instance calling it,
+    // to read a buffer the guest passed by pointer, say, overrides this rather than call, which
+    // is then only used from outside of wasm.
+    fn call_with_caller(
+        &self,
+        caller: &mut Caller<'_>,
+        args: &[StackEntry],
+    ) -> Result<Vec<StackEntry>> {
+        let _ = caller;
+        self.call(args)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct WasmExprCallable {
-    func_type: FuncType,
-    locals: Vec<Locals>,
+    // Where the function is in its module's function index space, so that the stack frames it
+    // pushes can say which function they belong to
+    func_idx: Option<usize>,
+    // The type and locals are shared with every other function made from the same module
+    func_type: Rc<FuncType>,
+    locals: Rc<[Locals]>,
     expr: Expr,
 }
 
 #[derive(Debug, Clone)]
 pub enum Callable {
     WasmExpr(WasmExprCallable),
+    Host(Rc<dyn HostCallable>),
 }
 
 impl Callable {
+    pub fn from_host(host: Rc<dyn HostCallable>) -> Self {
+        Callable::Host(host)
+    }
+
     pub fn call<Store: ExpressionStore>(&self, stack: &mut Stack, store: &mut Store) -> Result<()> {
+        self.call_as(self.func_idx(), stack, store)
+    }
+
+    // Where a wasm function is in the function index space of the instance it belongs to. A host
+    // function doesn't know.
+    pub(crate) fn func_idx(&self) -> Option<usize> {
         match &self {
+            Callable::WasmExpr(e) => e.func_idx,
+            Callable::Host(_) => None,
+        }
+    }
+
+    // Calls the function as the given function index, which is what the call observer is told.
+    // A host function doesn't know what index it was imported as, so only a call by index can
+    // say which one it is.
+    pub(crate) fn call_as<Store: ExpressionStore>(
+        &self,
+        func_idx: Option<usize>,
+        stack: &mut Stack,
+        store: &mut Store,
+    ) -> Result<()> {
+        let observer = store.call_observer();
+        if let Some(observer) = &observer {
+            observer.on_enter(func_idx, self.is_host());
+        }
+
+        let result = match &self {
             Callable::WasmExpr(e) => e.call(stack, store),
+            Callable::Host(h) => {
+                store.on_call(stack, true);
+                call_host(h.as_ref(), stack, &mut store.caller())
+            }
+        };
+
+        if let Some(observer) = &observer {
+            let outcome = match result {
+                Ok(()) => CallOutcome::Returned,
+                Err(_) => CallOutcome::Trapped,
+            };
+            observer.on_exit(func_idx, outcome);
+        }
+        result
+    }
+
+    pub fn is_host(&self) -> bool {
+        match &self {
+            Callable::WasmExpr(_) => false,
+            Callable::Host(_) => true,
         }
     }
 
     pub fn func_type(&self) -> &FuncType {
         match &self {
             Callable::WasmExpr(e) => &e.func_type,
+            Callable::Host(h) => h.func_type(),
         }
     }
 }
 
+fn check_value_types(entries: &[StackEntry], func_type: &FuncType, results: bool) -> Result<()> {
+    let (value_types, what) = if results {
+        (func_type.results(), "result")
+    } else {
+        (func_type.params(), "argument")
+    };
+
+    if entries.len() != value_types.len() {
+        return Err(anyhow!(
+            "Host function {} expects {} {}s, but there are {}",
+            func_type,
+            value_types.len(),
+            what,
+            entries.len()
+        ));
+    }
+
+    for (idx, (entry, value_type)) in entries.iter().zip(value_types).enumerate() {
+        if entry.ty() != *value_type {
+            return Err(anyhow!(
+                "Host function {} {} {} should be {}, but is {}",
+                func_type,
+                what,
+                idx,
+                value_type,
+                entry.ty()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn call_host(host: &dyn HostCallable, stack: &mut Stack, caller: &mut Caller) -> Result<()> {
+    let func_type = host.func_type();
+    let arg_count = func_type.params().len();
+    if stack.working_count() < arg_count {
+        return Err(anyhow!("Not enough arguments on working stack"));
+    }
+
+    // The arguments are passed straight from the stack rather than copied out of it
+    let args = stack.working_top(arg_count);
+    check_value_types(args, func_type, false)?;
+
+    let results = host.call_with_caller(caller, args)?;
+    check_value_types(&results, func_type, true)?;
+
+    // The arguments are only taken off the stack once the call has worked
+    stack.pop_n(arg_count);
+    stack.push_from_slice(&results);
+    Ok(())
+}
+
 impl WasmExprCallable {
     pub fn new(func_type: FuncType, func: Func) -> Callable {
-        Self::new_base(func_type, func.locals().clone(), func.expr().clone())
+        Self::new_base(func_type, func.locals().to_vec(), func.expr().clone())
     }
 
-    pub fn new_base(func_type: FuncType, locals: Vec<Locals>, expr: Expr) -> Callable {
+    pub fn new_at_index(func_idx: usize, func_type: Rc<FuncType>, func: &Func) -> Callable {
         Callable::WasmExpr(Self {
+            func_idx: Some(func_idx),
             func_type,
-            locals,
+            locals: func.shared_locals(),
+            expr: func.expr().clone(),
+        })
+    }
+
+    pub fn new_base(func_type: FuncType, locals: Vec<Locals>, expr: Expr) -> Callable {
+        Callable::WasmExpr(Self {
+            func_idx: None,
+            func_type: Rc::new(func_type),
+            locals: locals.into(),
             expr,
         })
     }
 
     fn call<Store: ExpressionStore>(&self, stack: &mut Stack, store: &mut Store) -> Result<()> {
         // Create the call frame for the function on the stack
-        stack.push_typed_frame(&self.func_type, &self.locals)?;
+        stack.push_function_frame(self.func_idx, &self.func_type, &self.locals)?;
+        stack.set_frame_code(self.expr.get_instruction_bytes());
+        store.on_call(stack, false);
 
         // Now execute the function on the stack
-        let result = execute_expression(&self.expr, stack, store);
+        if let Err(mut e) = execute_expression(&self.expr, stack, store) {
+            if let Some(limits) = store.trap_snapshot_limits() {
+                attach_snapshot(&mut e, || stack.snapshot(limits));
+            }
 
-        // Pop the function frame off the stack
-        stack.pop_typed_frame()?;
+            // The function didn't finish, so there are no results to check. The frame is thrown
+            // away as is, so that the error is passed on rather than one about the return values.
+            stack.unwind_frame();
+            return Err(e);
+        }
 
-        // And we're done
-        result
+        // Pop the function frame off the stack
+        stack.pop_typed_frame()
     }
 }
@@ -0,0 +1,74 @@
+// What a host function is given to get at the instance that called it. Nothing in the instance is
+// borrowed while a host function runs, so it's all borrowed again here on demand, for as long as
+// each call takes. That lets a host function read a buffer the guest passed it by pointer, write
+// its results back, look at the guest's globals and call back into the guest, all in the one call.
+//
+// A store that isn't an instance, such as the one the executor's own tests use, gives a detached
+// caller, which has nothing to get at.
+use std::{cell::RefCell, rc::Rc};
+
+use anyhow::{anyhow, Result};
+
+use crate::core::{
+    stack_entry::StackEntry, ConstantExpressionStore, ExpressionStore, Instance, Memory,
+};
+
+pub struct Caller<'a> {
+    instance: Option<&'a mut Instance>,
+}
+
+impl<'a> Caller<'a> {
+    pub(crate) fn new(instance: &'a mut Instance) -> Self {
+        Self {
+            instance: Some(instance),
+        }
+    }
+
+    // A caller for calling a host function from outside of wasm
+    pub fn detached() -> Caller<'static> {
+        Caller { instance: None }
+    }
+
+    pub fn instance(&mut self) -> Option<&mut Instance> {
+        self.instance.as_deref_mut()
+    }
+
+    fn instance_or_err(&mut self) -> Result<&mut Instance> {
+        self.instance
+            .as_deref_mut()
+            .ok_or_else(|| anyhow!("The host function wasn't called from an instance"))
+    }
+
+    // The memory itself, for a host function that wants to borrow it for longer. It must not
+    // still be borrowed when the host function calls back into wasm.
+    pub fn memory(&mut self, mem_idx: usize) -> Result<Rc<RefCell<Memory>>> {
+        self.instance_or_err()?
+            .memories
+            .get(mem_idx)
+            .cloned()
+            .ok_or_else(|| anyhow!("Memory index out of range"))
+    }
+
+    pub fn read_memory(&mut self, mem_idx: usize, offset: usize, data: &mut [u8]) -> Result<()> {
+        self.instance_or_err()?.read_data(mem_idx, offset, data)
+    }
+
+    pub fn write_memory(&mut self, mem_idx: usize, offset: usize, data: &[u8]) -> Result<()> {
+        self.instance_or_err()?.write_data(mem_idx, offset, data)
+    }
+
+    pub fn global_value(&mut self, global_idx: usize) -> Result<StackEntry> {
+        self.instance_or_err()?.get_global_value(global_idx)
+    }
+
+    pub fn set_global_value(&mut self, global_idx: usize, value: StackEntry) -> Result<()> {
+        self.instance_or_err()?.set_global_value(global_idx, value)
+    }
+
+    // Calls back into the instance. A trap in the call comes back out here, for the host function
+    // to handle or pass on, and so does an exit, whatever its code, so that passing it on exits
+    // the outer call too.
+    pub fn invoke_export(&mut self, name: &str, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        self.instance_or_err()?.call_export(name, args)
+    }
+}
@@ -0,0 +1,657 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::io::{Cursor, Read};
+
+use crate::core::{BlockType, Feature, FeatureSet, RawModule, SectionType};
+use crate::parser::{decode_unsupported, make_slice_accumulator, InstructionCategory, Opcode};
+use crate::reader::{ReaderUtil, Section, SectionIter, MODULE_HEADER};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompatibilityIssueKind {
+    // Part of a proposal that the interpreter doesn't implement
+    Unsupported(Feature),
+    // Valid WebAssembly that the interpreter can't handle for some other reason
+    Limitation,
+    // Not valid WebAssembly at all, so whatever follows it in the same section can't be scanned
+    Malformed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompatibilityIssue {
+    kind: CompatibilityIssueKind,
+    // The function the issue is in, when it's in a function body
+    func_idx: Option<usize>,
+    // The offset from the start of the module
+    offset: usize,
+    description: String,
+}
+
+impl CompatibilityIssue {
+    pub fn kind(&self) -> CompatibilityIssueKind {
+        self.kind
+    }
+
+    pub fn func_idx(&self) -> Option<usize> {
+        self.func_idx
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl fmt::Display for CompatibilityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.offset)?;
+        if let Some(func_idx) = self.func_idx {
+            write!(f, " func {}", func_idx)?;
+        }
+        write!(f, ": {}", self.description)?;
+        match self.kind {
+            CompatibilityIssueKind::Unsupported(feature) => write!(f, " ({})", feature),
+            CompatibilityIssueKind::Limitation => Ok(()),
+            CompatibilityIssueKind::Malformed => write!(f, " (malformed)"),
+        }
+    }
+}
+
+// Everything in a module that stops it from being loaded or run, in the order it appears in the
+// module
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompatibilityReport {
+    issues: Vec<CompatibilityIssue>,
+}
+
+impl CompatibilityReport {
+    pub fn is_compatible(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn issues(&self) -> &[CompatibilityIssue] {
+        &self.issues
+    }
+
+    // The proposals the module would need that aren't implemented
+    pub fn unsupported_features(&self) -> FeatureSet {
+        let mut features = FeatureSet::default();
+        for issue in &self.issues {
+            if let CompatibilityIssueKind::Unsupported(feature) = issue.kind {
+                features.insert(feature);
+            }
+        }
+        features
+    }
+}
+
+impl fmt::Display for CompatibilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_compatible() {
+            return writeln!(f, "no compatibility issues");
+        }
+
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+// Reads through a section's payload, keeping track of where it is in the module
+struct PayloadReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+    payload_offset: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(section: &'a Section) -> Self {
+        Self {
+            cursor: Cursor::new(section.payload()),
+            payload_offset: section.payload_offset(),
+        }
+    }
+
+    fn position(&self) -> usize {
+        self.cursor.position() as usize
+    }
+
+    fn module_offset(&self) -> usize {
+        self.payload_offset + self.position()
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        let payload: &'a [u8] = self.cursor.get_ref();
+        &payload[self.position()..]
+    }
+
+    fn skip(&mut self, length: usize) -> Result<()> {
+        if length > self.remaining().len() {
+            return Err(anyhow!("Unexpected end of section"));
+        }
+        self.cursor.set_position((self.position() + length) as u64);
+        Ok(())
+    }
+
+    fn skip_name(&mut self) -> Result<()> {
+        let length = self.cursor.read_leb_usize()?;
+        self.skip(length)
+    }
+}
+
+#[derive(Default)]
+struct Scanner {
+    issues: Vec<CompatibilityIssue>,
+    num_imported_funcs: usize,
+    num_tables: usize,
+    num_memories: usize,
+}
+
+impl Scanner {
+    fn report(
+        &mut self,
+        kind: CompatibilityIssueKind,
+        func_idx: Option<usize>,
+        offset: usize,
+        description: String,
+    ) {
+        self.issues.push(CompatibilityIssue {
+            kind,
+            func_idx,
+            offset,
+            description,
+        });
+    }
+
+    fn unsupported(&mut self, feature: Feature, offset: usize, description: String) {
+        self.report(
+            CompatibilityIssueKind::Unsupported(feature),
+            None,
+            offset,
+            description,
+        );
+    }
+
+    fn scan_section(&mut self, section: &Section) {
+        let section_type = match section.section_type() {
+            Some(section_type) => section_type,
+            None => {
+                let (kind, description) = match section.id() {
+                    12 => (
+                        CompatibilityIssueKind::Unsupported(Feature::BulkMemory),
+                        "data count section".to_string(),
+                    ),
+                    13 => (
+                        CompatibilityIssueKind::Unsupported(Feature::ExceptionHandling),
+                        "tag section".to_string(),
+                    ),
+                    id => (
+                        CompatibilityIssueKind::Malformed,
+                        format!("unknown section id {}", id),
+                    ),
+                };
+                self.report(kind, None, section.offset(), description);
+                return;
+            }
+        };
+
+        let mut reader = PayloadReader::new(section);
+        let result = match section_type {
+            SectionType::TypeSection => self.scan_types(&mut reader),
+            SectionType::ImportSection => self.scan_imports(&mut reader),
+            SectionType::TableSection => self.scan_vec(&mut reader, Self::scan_table_type),
+            SectionType::MemorySection => self.scan_vec(&mut reader, Self::scan_mem_type),
+            SectionType::GlobalSection => self.scan_vec(&mut reader, |scanner, reader| {
+                scanner.scan_global_type(reader)?;
+                scanner.scan_expr(reader, None)
+            }),
+            SectionType::ExportSection => self.scan_vec(&mut reader, Self::scan_export),
+            SectionType::ElementSection => self.scan_vec(&mut reader, Self::scan_element),
+            SectionType::CodeSection => self.scan_code(&mut reader),
+            SectionType::DataSection => self.scan_vec(&mut reader, Self::scan_data),
+            SectionType::CustomSection
+            | SectionType::FunctionSection
+            | SectionType::StartSection => Ok(()),
+        };
+
+        if let Err(err) = result {
+            self.report(
+                CompatibilityIssueKind::Malformed,
+                None,
+                reader.module_offset(),
+                format!("invalid {:?} section: {}", section_type, err),
+            );
+        }
+    }
+
+    fn scan_vec<F>(&mut self, reader: &mut PayloadReader, scan_fn: F) -> Result<()>
+    where
+        F: Fn(&mut Self, &mut PayloadReader) -> Result<()>,
+    {
+        let count = reader.cursor.read_leb_usize()?;
+        for _ in 0..count {
+            scan_fn(self, reader)?;
+        }
+        Ok(())
+    }
+
+    fn scan_value_type(
+        &mut self,
+        reader: &mut PayloadReader,
+        func_idx: Option<usize>,
+    ) -> Result<()> {
+        let offset = reader.module_offset();
+        match reader.cursor.read_u8()? {
+            0x7c..=0x7f => {}
+            0x7b => self.report(
+                CompatibilityIssueKind::Unsupported(Feature::Simd),
+                func_idx,
+                offset,
+                "v128 value type".to_string(),
+            ),
+            0x70 | 0x6f => self.report(
+                CompatibilityIssueKind::Unsupported(Feature::ReferenceTypes),
+                func_idx,
+                offset,
+                "reference value type".to_string(),
+            ),
+            byte => return Err(anyhow!("Invalid value type byte 0x{:02x}", byte)),
+        }
+        Ok(())
+    }
+
+    fn scan_types(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        self.scan_vec(reader, |scanner, reader| {
+            let header = reader.cursor.read_u8()?;
+            if header != 0x60 {
+                return Err(anyhow!("Invalid function type header 0x{:02x}", header));
+            }
+            // The parameters and then the results
+            scanner.scan_vec(reader, |scanner, reader| {
+                scanner.scan_value_type(reader, None)
+            })?;
+            scanner.scan_vec(reader, |scanner, reader| {
+                scanner.scan_value_type(reader, None)
+            })
+        })
+    }
+
+    fn scan_imports(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        self.scan_vec(reader, |scanner, reader| {
+            reader.skip_name()?;
+            reader.skip_name()?;
+            let offset = reader.module_offset();
+            match reader.cursor.read_u8()? {
+                0x00 => {
+                    reader.cursor.read_leb_u32()?;
+                    scanner.num_imported_funcs += 1;
+                }
+                0x01 => scanner.scan_table_type(reader)?,
+                0x02 => scanner.scan_mem_type(reader)?,
+                0x03 => scanner.scan_global_type(reader)?,
+                0x04 => {
+                    scanner.unsupported(
+                        Feature::ExceptionHandling,
+                        offset,
+                        "tag import".to_string(),
+                    );
+                    // The attribute and then the type index
+                    reader.cursor.read_u8()?;
+                    reader.cursor.read_leb_u32()?;
+                }
+                kind => return Err(anyhow!("Invalid import kind {}", kind)),
+            }
+            Ok(())
+        })
+    }
+
+    fn scan_limits(&mut self, reader: &mut PayloadReader, is_memory: bool) -> Result<()> {
+        let offset = reader.module_offset();
+        let flags = reader.cursor.read_u8()?;
+        match flags {
+            0x00 | 0x01 => {}
+            0x02 | 0x03 if is_memory => {
+                self.unsupported(Feature::Threads, offset, "shared memory".to_string())
+            }
+            0x04..=0x07 if is_memory => {
+                self.unsupported(Feature::Memory64, offset, "64-bit memory".to_string())
+            }
+            _ => return Err(anyhow!("Invalid limits flags 0x{:02x}", flags)),
+        }
+
+        // The minimum, and the maximum if there is one
+        reader.cursor.read_leb_usize()?;
+        if flags & 0x01 != 0 {
+            reader.cursor.read_leb_usize()?;
+        }
+        Ok(())
+    }
+
+    fn scan_table_type(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let offset = reader.module_offset();
+        self.num_tables += 1;
+        if self.num_tables == 2 {
+            self.unsupported(
+                Feature::ReferenceTypes,
+                offset,
+                "more than one table".to_string(),
+            );
+        }
+
+        match reader.cursor.read_u8()? {
+            0x70 => {}
+            0x6f => self.unsupported(
+                Feature::ReferenceTypes,
+                offset,
+                "externref table".to_string(),
+            ),
+            byte => return Err(anyhow!("Invalid table element type 0x{:02x}", byte)),
+        }
+        self.scan_limits(reader, false)
+    }
+
+    fn scan_mem_type(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        self.num_memories += 1;
+        if self.num_memories == 2 {
+            let offset = reader.module_offset();
+            self.unsupported(
+                Feature::MultiMemory,
+                offset,
+                "more than one memory".to_string(),
+            );
+        }
+        self.scan_limits(reader, true)
+    }
+
+    fn scan_global_type(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        self.scan_value_type(reader, None)?;
+        match reader.cursor.read_u8()? {
+            0x00 | 0x01 => Ok(()),
+            byte => Err(anyhow!("Invalid global mutability 0x{:02x}", byte)),
+        }
+    }
+
+    fn scan_export(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        reader.skip_name()?;
+        let offset = reader.module_offset();
+        match reader.cursor.read_u8()? {
+            0x00..=0x03 => {}
+            0x04 => self.unsupported(Feature::ExceptionHandling, offset, "tag export".to_string()),
+            kind => return Err(anyhow!("Invalid export kind {}", kind)),
+        }
+        reader.cursor.read_leb_u32()?;
+        Ok(())
+    }
+
+    fn scan_element(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let flags = reader.cursor.read_leb_u32()?;
+        if flags > 7 {
+            return Err(anyhow!("Invalid element segment flags {}", flags));
+        }
+
+        // See the element reader for how the flags lay the segment out
+        if flags & 0x1 == 0 {
+            if flags & 0x2 != 0 {
+                reader.cursor.read_leb_u32()?;
+            }
+            self.scan_expr(reader, None)?;
+        }
+
+        let exprs = flags & 0x4 != 0;
+        if flags & 0x3 != 0 {
+            let offset = reader.module_offset();
+            match (exprs, reader.cursor.read_u8()?) {
+                (false, 0x00) | (true, 0x70) => {}
+                (true, 0x6f) => self.unsupported(
+                    Feature::ReferenceTypes,
+                    offset,
+                    "externref element segment".to_string(),
+                ),
+                (_, byte) => return Err(anyhow!("Invalid element type 0x{:02x}", byte)),
+            }
+        }
+
+        if exprs {
+            self.scan_vec(reader, |_, reader| Self::scan_element_expr(reader))
+        } else {
+            self.scan_vec(reader, |_, reader| reader.cursor.read_leb_u32().map(|_| ()))
+        }
+    }
+
+    // The expressions an element segment's entries can be, which are a single ref.null, ref.func
+    // or global.get
+    fn scan_element_expr(reader: &mut PayloadReader) -> Result<()> {
+        match reader.cursor.read_u8()? {
+            0xd0 => {
+                reader.cursor.read_u8()?;
+            }
+            0xd2 | 0x23 => {
+                reader.cursor.read_leb_u32()?;
+            }
+            byte => return Err(anyhow!("Invalid element expression opcode 0x{:02x}", byte)),
+        }
+        match reader.cursor.read_u8()? {
+            0x0b => Ok(()),
+            _ => Err(anyhow!("Element expression has more than one instruction")),
+        }
+    }
+
+    fn scan_data(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let offset = reader.module_offset();
+        match reader.cursor.read_leb_u32()? {
+            0 => self.scan_expr(reader, None)?,
+            1 => self.unsupported(
+                Feature::BulkMemory,
+                offset,
+                "passive data segment".to_string(),
+            ),
+            2 => {
+                self.unsupported(
+                    Feature::BulkMemory,
+                    offset,
+                    "data segment with a memory index".to_string(),
+                );
+                reader.cursor.read_leb_u32()?;
+                self.scan_expr(reader, None)?;
+            }
+            flags => return Err(anyhow!("Invalid data segment flags {}", flags)),
+        }
+
+        let length = reader.cursor.read_leb_usize()?;
+        reader.skip(length)
+    }
+
+    fn scan_code(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let count = reader.cursor.read_leb_usize()?;
+        for defined_idx in 0..count {
+            let func_idx = self.num_imported_funcs + defined_idx;
+            let size = reader.cursor.read_leb_usize()?;
+            let body_end = reader.position() + size;
+            if size > reader.remaining().len() {
+                return Err(anyhow!("Body of func {} runs past the section", func_idx));
+            }
+
+            let body = self.scan_vec(reader, |scanner, reader| {
+                reader.cursor.read_leb_u32()?;
+                scanner.scan_value_type(reader, Some(func_idx))
+            });
+            let body = body.and_then(|_| self.scan_expr(reader, Some(func_idx)));
+            if let Err(err) = body {
+                self.report(
+                    CompatibilityIssueKind::Malformed,
+                    Some(func_idx),
+                    reader.module_offset(),
+                    err.to_string(),
+                );
+            }
+
+            // Carry on with the next body whatever happened in this one
+            reader.cursor.set_position(body_end as u64);
+        }
+        Ok(())
+    }
+
+    // Goes through an expression up to and including its final end. Instructions that the
+    // interpreter doesn't support are reported and skipped over where that's possible. On an error
+    // the reader is left at the instruction that couldn't be read.
+    fn scan_expr(&mut self, reader: &mut PayloadReader, func_idx: Option<usize>) -> Result<()> {
+        let bytes = reader.remaining();
+        let base_offset = reader.module_offset();
+        let mut offset = 0;
+        let result = self.scan_expr_bytes(bytes, base_offset, func_idx, &mut offset);
+        reader.skip(offset)?;
+        result
+    }
+
+    fn scan_expr_bytes(
+        &mut self,
+        bytes: &[u8],
+        base_offset: usize,
+        func_idx: Option<usize>,
+        offset: &mut usize,
+    ) -> Result<()> {
+        let mut acc = make_slice_accumulator(bytes);
+        let mut depth = 0;
+
+        loop {
+            let lead_byte = *bytes
+                .get(*offset)
+                .ok_or_else(|| anyhow!("Expression is missing its end"))?;
+            let module_offset = base_offset + *offset;
+
+            let length = match Opcode::from_byte(lead_byte) {
+                Ok(opcode) => {
+                    let cat = InstructionCategory::from_opcode(opcode);
+                    match cat {
+                        InstructionCategory::Block(_) => {
+                            depth += 1;
+                            self.block_type_length(bytes, *offset, func_idx, module_offset)?
+                        }
+                        InstructionCategory::End if depth == 0 => {
+                            *offset += 1;
+                            return Ok(());
+                        }
+                        InstructionCategory::End => {
+                            depth -= 1;
+                            1
+                        }
+                        _ => cat
+                            .ensure_flat_instruction(&mut acc, *offset)
+                            .map_err(|_| anyhow!("Invalid {} instruction", opcode.name()))?,
+                    }
+                }
+                Err(_) => {
+                    let unsupported = decode_unsupported(bytes, *offset)
+                        .ok_or_else(|| anyhow!("Invalid opcode byte 0x{:02x}", lead_byte))?;
+                    self.report(
+                        CompatibilityIssueKind::Unsupported(unsupported.feature),
+                        func_idx,
+                        module_offset,
+                        unsupported.name.to_string(),
+                    );
+
+                    // try opens a block and delegate closes one
+                    match lead_byte {
+                        0x06 => depth += 1,
+                        0x18 => depth -= 1,
+                        _ => {}
+                    }
+
+                    match unsupported.length {
+                        Some(length) => length,
+                        None => {
+                            // Nothing after it can be found, so the rest of the expression is
+                            // left out of the report
+                            *offset = bytes.len();
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+
+            *offset += length;
+        }
+    }
+
+    // The length of a block instruction without its contents
+    fn block_type_length(
+        &mut self,
+        bytes: &[u8],
+        offset: usize,
+        func_idx: Option<usize>,
+        module_offset: usize,
+    ) -> Result<usize> {
+        let byte = *bytes
+            .get(offset + 1)
+            .ok_or_else(|| anyhow!("Block has no type"))?;
+        if BlockType::from_byte(byte).is_ok() {
+            return Ok(2);
+        }
+
+        let (kind, description) = match byte {
+            // The spec's encoding of an empty block type, where this decoder only takes 0x00
+            0x40 => (
+                CompatibilityIssueKind::Limitation,
+                "block with the empty block type 0x40",
+            ),
+            0x7b => (
+                CompatibilityIssueKind::Unsupported(Feature::Simd),
+                "block with a v128 result",
+            ),
+            0x70 | 0x6f => (
+                CompatibilityIssueKind::Unsupported(Feature::ReferenceTypes),
+                "block with a reference result",
+            ),
+            // Any other negative value isn't a type
+            _ if byte & 0xc0 == 0x40 => return Err(anyhow!("Invalid block type 0x{:02x}", byte)),
+            // and anything else is a type index
+            _ => (
+                CompatibilityIssueKind::Unsupported(Feature::MultiValue),
+                "block with a type index",
+            ),
+        };
+        self.report(kind, func_idx, module_offset, description.to_string());
+
+        // The type index is a signed LEB128, the other block types are a single byte
+        let type_length = bytes[offset + 1..]
+            .iter()
+            .position(|byte| byte & 0x80 == 0)
+            .ok_or_else(|| anyhow!("Block type runs past the end"))?;
+        Ok(2 + type_length)
+    }
+}
+
+impl RawModule {
+    // Goes through a whole module looking for everything that would stop it from loading or
+    // running, rather than stopping at the first problem like reading it does. Each issue has its
+    // offset in the module, and the function it's in if it's in a function body.
+    pub fn compatibility_report<R: Read>(reader: R) -> Result<CompatibilityReport> {
+        let mut scanner = Scanner::default();
+        let mut end_offset = MODULE_HEADER.len();
+
+        for section in SectionIter::new(reader)? {
+            match section {
+                Ok(section) => {
+                    scanner.scan_section(&section);
+                    end_offset = section.payload_offset() + section.payload().len();
+                }
+                Err(err) => {
+                    // The section sizes can't be trusted from here on
+                    scanner.report(
+                        CompatibilityIssueKind::Malformed,
+                        None,
+                        end_offset,
+                        format!("{:#}", err),
+                    );
+                    break;
+                }
+            }
+        }
+
+        Ok(CompatibilityReport {
+            issues: scanner.issues,
+        })
+    }
+}
@@ -1,7 +1,11 @@
-use crate::parser::InstructionSource;
+use crate::core::{ExportKind, SectionType, SharedBytes};
+use crate::parser::{InstrIterator, InstructionSource};
+use crate::reader::{DecodeError, ReaderUtil};
 use anyhow::{anyhow, Result};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
@@ -17,11 +21,23 @@ impl ValueType {
         // actual values are offset by 0x7C [cb]
         match byte.try_into() {
             Ok(v) => Ok(v),
-            _ => Err(anyhow!("Invalid value type byte 0x{:02x}", byte)),
+            _ => Err(DecodeError::InvalidValueType(byte).into()),
         }
     }
 }
 
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValueType::F64 => "f64",
+            ValueType::F32 => "f32",
+            ValueType::I64 => "i64",
+            ValueType::I32 => "i32",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum BlockType {
@@ -101,13 +117,58 @@ impl ElemType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Limits {
     Unbounded(usize),
     Bounded(usize, usize),
 }
 
-#[derive(Debug, Clone)]
+impl Limits {
+    pub fn new(min: usize, max: Option<usize>) -> Self {
+        match max {
+            Some(max) => Limits::Bounded(min, max),
+            None => Limits::Unbounded(min),
+        }
+    }
+
+    pub fn min(&self) -> usize {
+        match self {
+            Limits::Unbounded(min) | Limits::Bounded(min, _) => *min,
+        }
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        match self {
+            Limits::Unbounded(_) => None,
+            Limits::Bounded(_, max) => Some(*max),
+        }
+    }
+
+    // Whether something with these limits can be imported as something declared with the others.
+    // It has to be at least as big as declared to begin with, and if there's a declared maximum,
+    // it can't be allowed to grow any bigger than that.
+    pub fn matches(&self, declared: &Limits) -> bool {
+        if self.min() < declared.min() {
+            return false;
+        }
+        match (self.max(), declared.max()) {
+            (_, None) => true,
+            (Some(max), Some(declared_max)) => max <= declared_max,
+            (None, Some(_)) => false,
+        }
+    }
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limits::Unbounded(min) => write!(f, "min {}, no max", min),
+            Limits::Bounded(min, max) => write!(f, "min {}, max {}", min, max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TableType {
     et: ElemType,
     lim: Limits,
@@ -127,7 +188,7 @@ impl TableType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MemType {
     limits: Limits,
 }
@@ -165,6 +226,17 @@ impl GlobalType {
     }
 }
 
+// Displays the way the text format writes it, such as i32 or (mut i32)
+impl fmt::Display for GlobalType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_mutable() {
+            write!(f, "(mut {})", self.t)
+        } else {
+            write!(f, "{}", self.t)
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct FuncType {
     arg_types: Vec<ValueType>,
@@ -186,9 +258,40 @@ impl FuncType {
     pub fn return_types(&self) -> &Vec<ValueType> {
         &self.ret_types
     }
+
+    pub fn params(&self) -> &[ValueType] {
+        &self.arg_types
+    }
+
+    pub fn results(&self) -> &[ValueType] {
+        &self.ret_types
+    }
 }
 
-#[derive(Debug)]
+fn write_value_types(f: &mut fmt::Formatter<'_>, value_types: &[ValueType]) -> fmt::Result {
+    write!(f, "(")?;
+    for (idx, value_type) in value_types.iter().enumerate() {
+        if idx > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", value_type)?;
+    }
+    write!(f, ")")
+}
+
+// Displays like (i32, f64) -> i32, with the results only in brackets when there isn't exactly one
+impl fmt::Display for FuncType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_value_types(f, self.params())?;
+        write!(f, " -> ")?;
+        match self.results() {
+            [result] => write!(f, "{}", result),
+            results => write_value_types(f, results),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum ImportDesc {
     TypeIdx(usize),
     TableType(TableType),
@@ -196,7 +299,7 @@ pub enum ImportDesc {
     GlobalType(GlobalType),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Import {
     mod_name: String,
     name: String,
@@ -227,14 +330,31 @@ impl Import {
 
 #[derive(Debug, Clone)]
 pub struct Expr {
-    // So, a basic expr is just the bytes that make up the expression
-    instr: Vec<u8>,
+    // So, a basic expr is just the bytes that make up the expression. They never change once
+    // they've been read, so they are shared between every copy of the expression, and with the
+    // module they were read from.
+    instr: SharedBytes,
 }
 
 impl Expr {
     pub fn new(instr: Vec<u8>) -> Self {
+        Self {
+            instr: instr.into(),
+        }
+    }
+
+    pub(crate) fn from_shared(instr: SharedBytes) -> Self {
         Self { instr }
     }
+
+    pub(crate) fn shared_bytes(&self) -> &SharedBytes {
+        &self.instr
+    }
+
+    // Every instruction in the expression, with the offset of each one from its start
+    pub fn instructions(&self) -> InstrIterator<'_> {
+        InstrIterator::new(&self.instr)
+    }
 }
 
 impl InstructionSource for Expr {
@@ -243,7 +363,7 @@ impl InstructionSource for Expr {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GlobalDef {
     gt: GlobalType,
     e: Expr,
@@ -263,7 +383,7 @@ impl GlobalDef {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ExportDesc {
     Func(usize),
     Table(usize),
@@ -271,40 +391,132 @@ pub enum ExportDesc {
     Global(usize),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Export {
     pub nm: String,
     pub d: ExportDesc,
 }
 
+impl ExportDesc {
+    pub fn kind(&self) -> ExportKind {
+        match self {
+            ExportDesc::Func(_) => ExportKind::Function,
+            ExportDesc::Table(_) => ExportKind::Table,
+            ExportDesc::Mem(_) => ExportKind::Memory,
+            ExportDesc::Global(_) => ExportKind::Global,
+        }
+    }
+}
+
 impl Export {
     pub fn new(nm: String, d: ExportDesc) -> Self {
         Self { nm, d }
     }
 }
 
-#[derive(Debug)]
+// Where an element segment's entries go. Only an active segment is written into a table when the
+// module is instantiated. A passive one is there for table.init to copy from, and a declared one
+// only declares the functions that ref.func may refer to, so it has nothing in it at runtime.
+#[derive(Debug, Clone)]
+pub enum ElementMode {
+    Active { table_idx: usize, offset: Expr },
+    Passive,
+    Declared,
+}
+
+#[derive(Debug, Clone)]
+pub enum ElementItems {
+    FuncIndices(Vec<usize>),
+    // Constant expressions that each give a reference, which is ref.func, ref.null or a global.get
+    Exprs(Vec<Expr>),
+}
+
+#[derive(Debug, Clone)]
 pub struct Element {
-    x: usize,
-    e: Expr,
-    y: Vec<usize>,
+    mode: ElementMode,
+    et: ElemType,
+    items: ElementItems,
 }
 
 impl Element {
+    // An active segment for the table, given as function indices, like the MVP has them
     pub fn new(x: usize, e: Expr, y: Vec<usize>) -> Self {
-        Self { x, e, y }
+        Self::with_mode(
+            ElementMode::Active {
+                table_idx: x,
+                offset: e,
+            },
+            ElemType::FuncRef,
+            ElementItems::FuncIndices(y),
+        )
     }
 
-    pub fn table_idx(&self) -> usize {
-        self.x
+    pub fn with_mode(mode: ElementMode, et: ElemType, items: ElementItems) -> Self {
+        Self { mode, et, items }
     }
 
+    pub fn mode(&self) -> &ElementMode {
+        &self.mode
+    }
+
+    pub fn elem_type(&self) -> &ElemType {
+        &self.et
+    }
+
+    pub fn items(&self) -> &ElementItems {
+        &self.items
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.items {
+            ElementItems::FuncIndices(func_indices) => func_indices.len(),
+            ElementItems::Exprs(exprs) => exprs.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The table an active segment is written into
+    pub fn table_idx(&self) -> Option<usize> {
+        match &self.mode {
+            ElementMode::Active { table_idx, .. } => Some(*table_idx),
+            _ => None,
+        }
+    }
+
+    pub fn offset_expr(&self) -> Option<&Expr> {
+        match &self.mode {
+            ElementMode::Active { offset, .. } => Some(offset),
+            _ => None,
+        }
+    }
+
+    // The entries of a segment that's given as function indices. One that's given as expressions
+    // has none.
     pub fn func_indices(&self) -> &[usize] {
-        &self.y
+        match &self.items {
+            ElementItems::FuncIndices(func_indices) => func_indices,
+            ElementItems::Exprs(_) => &[],
+        }
     }
 
-    pub fn expr(&self) -> &Expr {
-        &self.e
+    // Every function the segment refers to, whichever way its entries are given
+    pub fn referenced_funcs(&self) -> Vec<usize> {
+        match &self.items {
+            ElementItems::FuncIndices(func_indices) => func_indices.clone(),
+            ElementItems::Exprs(exprs) => exprs.iter().filter_map(ref_func_idx).collect(),
+        }
+    }
+}
+
+// The function a ref.func expression refers to
+fn ref_func_idx(expr: &Expr) -> Option<usize> {
+    let mut bytes = expr.get_instruction_bytes();
+    match bytes.read_u8() {
+        Ok(0xd2) => bytes.read_leb_usize().ok(),
+        _ => None,
     }
 }
 
@@ -330,36 +542,57 @@ impl Locals {
 
 #[derive(Debug, Clone)]
 pub struct Func {
-    locals: Vec<Locals>,
+    // Shared like the expression, so copies of a function don't copy its locals
+    locals: Rc<[Locals]>,
     e: Expr,
 }
 
 impl Func {
     pub fn new(locals: Vec<Locals>, e: Expr) -> Self {
-        Self { locals, e }
+        Self {
+            locals: locals.into(),
+            e,
+        }
     }
 
-    pub fn locals(&self) -> &Vec<Locals> {
+    pub fn locals(&self) -> &[Locals] {
         &self.locals
     }
 
     pub fn expr(&self) -> &Expr {
         &self.e
     }
+
+    pub(crate) fn shared_locals(&self) -> Rc<[Locals]> {
+        self.locals.clone()
+    }
+
+    // The offsets are from the start of the body's expression, after the locals
+    pub fn instructions(&self) -> InstrIterator<'_> {
+        self.e.instructions()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Data {
     x: usize,
     e: Expr,
-    b: Vec<u8>,
+    b: SharedBytes,
 }
 
 impl Data {
     pub fn new(x: usize, e: Expr, b: Vec<u8>) -> Self {
+        Self { x, e, b: b.into() }
+    }
+
+    pub(crate) fn from_shared(x: usize, e: Expr, b: SharedBytes) -> Self {
         Self { x, e, b }
     }
 
+    pub(crate) fn shared_bytes(&self) -> &SharedBytes {
+        &self.b
+    }
+
     pub fn mem_idx(&self) -> usize {
         self.x
     }
@@ -372,3 +605,34 @@ impl Data {
         &self.b
     }
 }
+
+// A custom section kept from the binary so that it can be written back out. It goes after the
+// section it followed in the original module, or before all of them if there wasn't one.
+#[derive(Debug, Clone)]
+pub struct CustomSection {
+    name: String,
+    bytes: Rc<[u8]>,
+    after: Option<SectionType>,
+}
+
+impl CustomSection {
+    pub fn new(name: String, bytes: Vec<u8>, after: Option<SectionType>) -> Self {
+        Self {
+            name,
+            bytes: bytes.into(),
+            after,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn after(&self) -> Option<SectionType> {
+        self.after
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+use crate::core::{self, BlockType, Module, RawModule};
+use crate::parser::Instr;
+
+// How far each level of nesting is indented
+const INDENT: &str = "  ";
+
+fn block_type_text(block_type: &BlockType) -> String {
+    match core::ValueType::try_from(block_type.clone()) {
+        Ok(value_type) => format!(" (result {})", value_type),
+        Err(_) => String::new(),
+    }
+}
+
+struct Disassembler<'a> {
+    raw: &'a RawModule,
+    func_idx: usize,
+    func_imports: Vec<String>,
+    global_imports: Vec<String>,
+    // The label of each block that is currently open, innermost last
+    labels: Vec<usize>,
+    next_label: usize,
+}
+
+impl<'a> Disassembler<'a> {
+    fn new(raw: &'a RawModule, func_idx: usize) -> Self {
+        // Imported functions and globals are named after what they import
+        let mut func_imports = Vec::new();
+        let mut global_imports = Vec::new();
+        for import in raw.imports() {
+            let name = format!("{}::{}", import.mod_name(), import.name());
+            match import.desc() {
+                core::ImportDesc::TypeIdx(_) => func_imports.push(name),
+                core::ImportDesc::GlobalType(_) => global_imports.push(name),
+                _ => {}
+            }
+        }
+
+        Self {
+            raw,
+            func_idx,
+            func_imports,
+            global_imports,
+            labels: Vec::new(),
+            next_label: 0,
+        }
+    }
+
+    fn func_text(&self, func_idx: u32) -> String {
+        let func_idx = func_idx as usize;
+        match self.raw.func_name(func_idx) {
+            Some(name) => format!("{} '{}'", func_idx, name),
+            None => match self.func_imports.get(func_idx) {
+                Some(name) => format!("{} '{}'", func_idx, name),
+                None => format!("{}", func_idx),
+            },
+        }
+    }
+
+    fn global_text(&self, global_idx: u32) -> String {
+        let name = self.raw.names().global_name(global_idx as usize);
+        match name.or_else(|| {
+            self.global_imports
+                .get(global_idx as usize)
+                .map(|n| n.as_str())
+        }) {
+            Some(name) => format!("{} '{}'", global_idx, name),
+            None => format!("{}", global_idx),
+        }
+    }
+
+    fn local_text(&self, local_idx: u32) -> String {
+        match self
+            .raw
+            .names()
+            .local_name(self.func_idx, local_idx as usize)
+        {
+            Some(name) => format!("{} '{}'", local_idx, name),
+            None => format!("{}", local_idx),
+        }
+    }
+
+    // A block's label, along with its name if it has one
+    fn label_name(&self, label: usize) -> String {
+        match self.raw.names().label_name(self.func_idx, label) {
+            Some(name) => format!("@{} '{}'", label, name),
+            None => format!("@{}", label),
+        }
+    }
+
+    // Only the instructions that take a memory index have the memory written out, but the others
+    // all use memory 0, so it's named on them too if it has a name
+    fn memory_text(&self) -> String {
+        match self.raw.names().memory_name(0) {
+            Some(name) => format!(" (memory 0 '{}')", name),
+            None => String::new(),
+        }
+    }
+
+    fn label_text(&self, depth: u32) -> String {
+        let depth = depth as usize;
+        if depth < self.labels.len() {
+            format!(
+                "{} ({})",
+                depth,
+                self.label_name(self.labels[self.labels.len() - 1 - depth])
+            )
+        } else {
+            // Branching to the function body itself returns from it
+            format!("{} (return)", depth)
+        }
+    }
+
+    fn open_block(&mut self, name: &str, block_type: &BlockType) -> String {
+        let label = self.next_label;
+        self.next_label += 1;
+        self.labels.push(label);
+        format!(
+            "{}{} {}",
+            name,
+            block_type_text(block_type),
+            self.label_name(label)
+        )
+    }
+
+    // Returns the text for the instruction, and how deeply it is nested
+    fn instr_text(&mut self, instr: &Instr) -> (String, usize) {
+        let depth = self.labels.len();
+
+        let text = match instr {
+            Instr::Block(block_type) => self.open_block("block", block_type),
+            Instr::Loop(block_type) => self.open_block("loop", block_type),
+            Instr::If(block_type) => self.open_block("if", block_type),
+            Instr::Else => {
+                let label = self.labels.last().map(|label| format!(" @{}", label));
+                return (
+                    format!("else{}", label.unwrap_or_default()),
+                    depth.saturating_sub(1),
+                );
+            }
+            Instr::End => {
+                // The last end closes the function body, which doesn't have a label
+                let label = self.labels.pop().map(|label| format!(" @{}", label));
+                return (
+                    format!("end{}", label.unwrap_or_default()),
+                    self.labels.len(),
+                );
+            }
+            Instr::Br(depth) => format!("br {}", self.label_text(*depth)),
+            Instr::BrIf(depth) => format!("br_if {}", self.label_text(*depth)),
+            Instr::BrTable(targets, default) => {
+                let mut text = String::from("br_table");
+                for target in targets.iter().chain(std::iter::once(default)) {
+                    write!(text, " {}", self.label_text(*target)).unwrap();
+                }
+                text
+            }
+            Instr::Call(func_idx) => format!("call {}", self.func_text(*func_idx)),
+            Instr::CallIndirect(type_idx) => match self.raw.types().get(*type_idx as usize) {
+                Some(func_type) => format!("call_indirect {} {}", type_idx, func_type),
+                None => format!("call_indirect {}", type_idx),
+            },
+            Instr::LocalGet(idx) => format!("local.get {}", self.local_text(*idx)),
+            Instr::LocalSet(idx) => format!("local.set {}", self.local_text(*idx)),
+            Instr::LocalTee(idx) => format!("local.tee {}", self.local_text(*idx)),
+            Instr::GlobalGet(idx) => format!("global.get {}", self.global_text(*idx)),
+            Instr::GlobalSet(idx) => format!("global.set {}", self.global_text(*idx)),
+            Instr::Memory(opcode, align, offset) => format!(
+                "{} offset={} align={}{}",
+                opcode.name(),
+                offset,
+                1u64 << (*align).min(63),
+                self.memory_text()
+            ),
+            Instr::I32Const(value) => format!("i32.const {}", value),
+            Instr::I64Const(value) => format!("i64.const {}", value),
+            Instr::F32Const(value) => format!("f32.const {:?}", value),
+            Instr::F64Const(value) => format!("f64.const {:?}", value),
+            Instr::Unreachable => "unreachable".to_string(),
+            Instr::Nop => "nop".to_string(),
+            Instr::Return => "return".to_string(),
+            Instr::Drop => "drop".to_string(),
+            Instr::Select => "select".to_string(),
+            Instr::MemorySize => format!("memory.size{}", self.memory_text()),
+            Instr::MemoryGrow => format!("memory.grow{}", self.memory_text()),
+            Instr::Op(opcode) => opcode.name().to_string(),
+            Instr::Raw(bytes) => format!("raw {:02x?}", bytes),
+        };
+
+        (text, depth)
+    }
+}
+
+impl Module {
+    // Writes out the body of a defined function in the flat text form, one instruction per line,
+    // with the offset of each one from the start of the function's expression in the left hand
+    // column. Blocks are labelled @0, @1 and so on, so branches and ends can be matched up with
+    // them. The output only depends on the module, so it can be diffed between builds.
+    pub fn disassemble_function(&self, func_idx: usize) -> Result<String> {
+        let raw = self.raw_module();
+        let num_imported = self.num_imported_functions();
+
+        if func_idx < num_imported {
+            return Err(anyhow!(
+                "Cannot disassemble {}, it has no body",
+                self.describe_func(func_idx)
+            ));
+        }
+        let func = raw
+            .funcs()
+            .get(func_idx - num_imported)
+            .ok_or_else(|| anyhow!("Cannot disassemble {}", self.describe_func(func_idx)))?;
+        let type_idx = raw.func_type_indices()[func_idx - num_imported];
+        let func_type = raw
+            .types()
+            .get(type_idx)
+            .ok_or_else(|| anyhow!("Function {} has invalid type index {}", func_idx, type_idx))?;
+
+        let mut text = format!("func {}", func_idx);
+        if let Some(name) = raw.func_name(func_idx) {
+            write!(text, " '{}'", name).unwrap();
+        }
+        writeln!(text, " {}", func_type).unwrap();
+
+        // Locals are numbered after the parameters
+        let mut disassembler = Disassembler::new(raw, func_idx);
+        let mut local_idx = func_type.params().len();
+        for locals in func.locals() {
+            for _ in 0..locals.count() {
+                let local_text = disassembler.local_text(local_idx as u32);
+                writeln!(text, "       local {} {}", local_text, locals.value_type()).unwrap();
+                local_idx += 1;
+            }
+        }
+
+        for instruction in func.instructions() {
+            let (offset, instr) = instruction?;
+            let (instr_text, depth) = disassembler.instr_text(&instr);
+            writeln!(
+                text,
+                "{:#06x} {}{}",
+                offset,
+                INDENT.repeat(depth + 1),
+                instr_text
+            )
+            .unwrap();
+        }
+
+        Ok(text)
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use std::io::Read;
+
+use crate::core::RawModule;
+use crate::reader::{ReaderUtil, TypeReader};
+
+pub const DYLINK_SECTION: &str = "dylink.0";
+
+// The subsections of dylink.0, as the tool conventions number them
+const MEM_INFO: u8 = 1;
+const NEEDED: u8 = 2;
+const EXPORT_INFO: u8 = 3;
+const IMPORT_INFO: u8 = 4;
+const RUNTIME_PATH: u8 = 5;
+
+// The symbol flags that matter to a dynamic linker. A weak import can be left undefined, and a
+// thread local export is an offset into the thread's TLS block rather than into the module's data.
+pub const SYMBOL_WEAK: u32 = 0x1;
+pub const SYMBOL_TLS: u32 = 0x100;
+
+// What a module built as a shared library, or as a main module that loads them, says about
+// itself in its dylink.0 section. The memory and table sizes are what the module needs set aside
+// for its data and its function pointers, wherever the loader puts them, and the alignments are
+// powers of two. Subsections that aren't known are skipped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DylinkInfo {
+    pub memory_size: u32,
+    pub memory_alignment: u32,
+    pub table_size: u32,
+    pub table_alignment: u32,
+    // The other shared libraries it needs loaded first, by name
+    pub needed: Vec<String>,
+    // The flags of its exports, by export name
+    pub export_info: Vec<(String, u32)>,
+    // The flags of its imports, by module and name
+    pub import_info: Vec<(String, String, u32)>,
+    pub runtime_paths: Vec<String>,
+}
+
+impl DylinkInfo {
+    pub fn export_flags(&self, name: &str) -> u32 {
+        self.export_info
+            .iter()
+            .find(|(export, _)| export == name)
+            .map_or(0, |(_, flags)| *flags)
+    }
+
+    pub fn import_flags(&self, mod_name: &str, name: &str) -> u32 {
+        self.import_info
+            .iter()
+            .find(|(module, field, _)| module == mod_name && field == name)
+            .map_or(0, |(_, _, flags)| *flags)
+    }
+
+    pub fn is_weak_import(&self, mod_name: &str, name: &str) -> bool {
+        self.import_flags(mod_name, name) & SYMBOL_WEAK != 0
+    }
+}
+
+impl TypeReader for DylinkInfo {
+    fn read<T: Read>(reader: &mut T) -> Result<Self> {
+        let bytes = reader.read_bytes_to_end()?;
+        let mut remaining = &bytes[..];
+        let mut info = DylinkInfo::default();
+        while !remaining.is_empty() {
+            let id = remaining.read_u8()?;
+            let size = remaining.read_leb_usize()?;
+            if size > remaining.len() {
+                return Err(anyhow!(
+                    "dylink.0 subsection {} is {} bytes, but only {} are left",
+                    id,
+                    size,
+                    remaining.len()
+                ));
+            }
+            let (mut subsection, rest) = remaining.split_at(size);
+            remaining = rest;
+
+            let reader = &mut subsection;
+            match id {
+                MEM_INFO => {
+                    info.memory_size = reader.read_leb_u32()?;
+                    info.memory_alignment = reader.read_leb_u32()?;
+                    info.table_size = reader.read_leb_u32()?;
+                    info.table_alignment = reader.read_leb_u32()?;
+                }
+                NEEDED => info.needed = reader.read_vec(|r| r.read_name())?,
+                EXPORT_INFO => {
+                    info.export_info =
+                        reader.read_vec(|r| Ok((r.read_name()?, r.read_leb_u32()?)))?
+                }
+                IMPORT_INFO => {
+                    info.import_info = reader
+                        .read_vec(|r| Ok((r.read_name()?, r.read_name()?, r.read_leb_u32()?)))?
+                }
+                RUNTIME_PATH => info.runtime_paths = reader.read_vec(|r| r.read_name())?,
+                _ => continue,
+            }
+            if !subsection.is_empty() {
+                return Err(anyhow!(
+                    "dylink.0 subsection {} has {} bytes left over",
+                    id,
+                    subsection.len()
+                ));
+            }
+        }
+        Ok(info)
+    }
+}
+
+impl RawModule {
+    // What the dylink.0 section says, if the module has one that could be read
+    pub fn dylink(&self) -> Option<&DylinkInfo> {
+        self.dylink.as_ref()
+    }
+}
@@ -0,0 +1,865 @@
+// Loads a main module and the shared libraries it needs into one memory and one function table,
+// the way emscripten's dynamic loader does, for modules built as position independent code with
+// a dylink.0 section. Every module gets these imports from the linker:
+//
+//     env.memory                     the memory they all share
+//     env.__indirect_function_table  the table they all share, whose slot 0 is left empty, as
+//                                    the null function pointer
+//     env.__stack_pointer            the stack pointer they all share, which starts at the top of
+//                                    a stack of stack_size bytes at global_base
+//     env.__memory_base              where the module's data was put, which is as much memory as
+//                                    its dylink.0 section asks for, aligned as it asks
+//     env.__table_base               where its function pointers were put, the same way
+//     GOT.mem.<symbol>               a global holding the address of a data symbol
+//     GOT.func.<symbol>              a global holding the table slot of a function symbol
+//
+// Anything else is looked up in what the host defined first, and then, for imports from env, in
+// the exports of the modules that have been loaded, which is how they call each other. A function
+// no module exports yet, because the one that does comes later in the order they're loaded in, is
+// looked up again each time it's called.
+//
+// Loading a module loads the ones it needs first, depth first through their needed lists. Once
+// they're all instantiated, the GOT globals are filled in, and then each module's start function,
+// __wasm_apply_data_relocs and __wasm_call_ctors are run, in the same order, which is what fixes
+// up the pointers in its data and sets up its statics. An undefined symbol that none of its
+// importers say is weak is an error by then, and a weak one is left as 0. Where two modules
+// export the same name, the one loaded earlier wins, and of those loaded together, the one that's
+// nearer to the module asked for, breadth first, as an ELF loader searches them.
+//
+// An instance only runs its own functions, so the functions in the export map, and the ones the
+// modules' element segments put in the table, are wrapped to run on the instance that defines
+// them, with its globals. That includes a module calling back into one that's calling it, as a
+// library calls back into the main module.
+//
+// Thread local storage isn't supported, and nothing is ever unloaded. A load that fails drops the
+// modules it loaded again, and the symbols, GOT entries and function pointers that lead to them,
+// but the memory and table slots they were given aren't reused.
+use anyhow::{anyhow, Context, Result};
+use std::cell::{Ref, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::rc::{Rc, Weak};
+
+use crate::core::{
+    memory_page::{WASM_PAGE_SHIFT, WASM_PAGE_SIZE_IN_BYTES},
+    stack_entry::StackEntry,
+    Callable, Caller, DylinkInfo, ElemType, Extern, ExternType, FuncType, Global, GlobalType,
+    HostCallable, ImportObject, Instance, InstanceOptions, Limits, MemType, Memory, Module,
+    Resolver, Table, TableType, ValueType, SYMBOL_TLS,
+};
+
+const APPLY_DATA_RELOCS: &str = "__wasm_apply_data_relocs";
+const CALL_CTORS: &str = "__wasm_call_ctors";
+
+#[derive(Debug, Clone)]
+pub struct DynamicLinkerConfig {
+    // Where the stack starts. Nothing is put below it, so a null pointer never points at anything.
+    pub global_base: u32,
+    pub stack_size: u32,
+    // The most pages the memory can grow to, by loading modules or by memory.grow
+    pub max_memory_pages: Option<usize>,
+}
+
+impl Default for DynamicLinkerConfig {
+    fn default() -> Self {
+        Self {
+            global_base: 1024,
+            stack_size: 64 * 1024,
+            max_memory_pages: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LoadedModule {
+    pub name: String,
+    pub memory_base: u32,
+    pub table_base: u32,
+    pub instance: Rc<RefCell<Instance>>,
+}
+
+// A GOT global, which is filled in once its symbol is defined
+#[derive(Debug)]
+struct GotEntry {
+    global: Rc<RefCell<Global>>,
+    // Only while every module that imports it says it's weak
+    weak: bool,
+    resolved: bool,
+}
+
+// What there was before a load, for undoing it if it fails. The GOT entries are by name, with
+// whether each was weak and whether it was filled in.
+struct Checkpoint {
+    loaded: usize,
+    table_len: usize,
+    got_mem: HashMap<String, (bool, bool)>,
+    got_func: HashMap<String, (bool, bool)>,
+}
+
+// A function import that no module exported when it was resolved
+#[derive(Debug)]
+struct Undefined {
+    importer: String,
+    name: String,
+    weak: bool,
+}
+
+#[derive(Debug)]
+pub struct DynamicLinker {
+    memory: Rc<RefCell<Memory>>,
+    table: Rc<RefCell<Table>>,
+    stack_pointer: Rc<RefCell<Global>>,
+    // Where the next module's data can go
+    data_end: u32,
+    host: ImportObject,
+    available: HashMap<String, Rc<Module>>,
+    // In the order they were instantiated in
+    loaded: Vec<LoadedModule>,
+    symbols: Rc<RefCell<HashMap<String, Extern>>>,
+    // Which of the loaded modules each symbol comes from
+    defined_by: HashMap<String, usize>,
+    got_mem: RefCell<HashMap<String, GotEntry>>,
+    got_func: RefCell<HashMap<String, GotEntry>>,
+    // The slot each function symbol's address was given, so it's the same wherever it's taken
+    func_slots: HashMap<String, u32>,
+    undefined: Vec<Undefined>,
+}
+
+impl DynamicLinker {
+    pub fn new(config: DynamicLinkerConfig) -> Result<Self> {
+        let stack_top = config
+            .global_base
+            .checked_add(config.stack_size)
+            .ok_or_else(|| anyhow!("The stack doesn't fit below 4GiB"))?;
+        let pages = pages_for(stack_top.into());
+        if let Some(max) = config.max_memory_pages {
+            if max < pages {
+                return Err(anyhow!("The stack needs {} pages of memory", pages));
+            }
+        }
+
+        Ok(Self {
+            memory: Rc::new(RefCell::new(Memory::new(MemType::new(Limits::new(
+                pages,
+                config.max_memory_pages,
+            ))))),
+            table: Rc::new(RefCell::new(Table::new(TableType::new(
+                ElemType::FuncRef,
+                Limits::new(1, None),
+            )))),
+            stack_pointer: Rc::new(RefCell::new(Global::new_host(stack_top.into(), true))),
+            data_end: stack_top,
+            host: ImportObject::new(),
+            available: HashMap::new(),
+            loaded: Vec::new(),
+            symbols: Rc::default(),
+            defined_by: HashMap::new(),
+            got_mem: RefCell::default(),
+            got_func: RefCell::default(),
+            func_slots: HashMap::new(),
+            undefined: Vec::new(),
+        })
+    }
+
+    // Something the host provides, such as the functions libc makes system calls with. It's used
+    // ahead of anything the modules export.
+    pub fn define(&mut self, mod_name: &str, name: &str, value: impl Into<Extern>) -> &mut Self {
+        self.host.define(mod_name, name, value);
+        self
+    }
+
+    // Makes a module available to load, by the name other modules' needed lists give it
+    pub fn add_module(&mut self, name: &str, module: Module) -> &mut Self {
+        self.available.insert(name.to_string(), Rc::new(module));
+        self
+    }
+
+    // Loads the module, and everything it needs that isn't loaded already
+    pub fn load(&mut self, name: &str) -> Result<()> {
+        let mut order = Vec::new();
+        self.dependencies_first(name, &mut HashSet::new(), &mut order)?;
+
+        let checkpoint = self.checkpoint();
+        let result = self.load_all(name, &order);
+        if result.is_err() {
+            self.roll_back(checkpoint);
+        }
+        result
+    }
+
+    // Calls a function that one of the modules exports, on the module it's from
+    pub fn invoke(&self, name: &str, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        match self.defined_by.get(name) {
+            Some(idx) => self.loaded[*idx]
+                .instance
+                .borrow_mut()
+                .invoke_export(name, args),
+            None => Err(anyhow!("None of the modules export {}", name)),
+        }
+    }
+
+    // Everything the loaded modules export, by name. A data symbol is a global holding its
+    // address, rather than where it is in its module's data, and a function runs on the instance
+    // it's from, whoever calls it.
+    pub fn exports(&self) -> Ref<'_, HashMap<String, Extern>> {
+        self.symbols.borrow()
+    }
+
+    pub fn modules(&self) -> &[LoadedModule] {
+        &self.loaded
+    }
+
+    pub fn module(&self, name: &str) -> Option<&LoadedModule> {
+        self.loaded.iter().find(|module| module.name == name)
+    }
+
+    pub fn memory(&self) -> Rc<RefCell<Memory>> {
+        self.memory.clone()
+    }
+
+    pub fn table(&self) -> Rc<RefCell<Table>> {
+        self.table.clone()
+    }
+
+    pub fn stack_pointer(&self) -> Rc<RefCell<Global>> {
+        self.stack_pointer.clone()
+    }
+
+    // The end of the modules' data, above which the memory is free, for a heap
+    pub fn data_end(&self) -> u32 {
+        self.data_end
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        let got_state = |got: &RefCell<HashMap<String, GotEntry>>| {
+            got.borrow()
+                .iter()
+                .map(|(name, entry)| (name.clone(), (entry.weak, entry.resolved)))
+                .collect()
+        };
+        Checkpoint {
+            loaded: self.loaded.len(),
+            table_len: self.table.borrow().len(),
+            got_mem: got_state(&self.got_mem),
+            got_func: got_state(&self.got_func),
+        }
+    }
+
+    // Undoes everything a failed load did apart from reserving memory and table slots, so that
+    // nothing refers to the modules it dropped and loading them again starts afresh
+    fn roll_back(&mut self, checkpoint: Checkpoint) {
+        let first = checkpoint.loaded;
+        self.loaded.truncate(first);
+        let mut symbols = self.symbols.borrow_mut();
+        self.defined_by.retain(|name, module| {
+            let keep = *module < first;
+            if !keep {
+                symbols.remove(name);
+            }
+            keep
+        });
+
+        // The GOT entries the load made are dropped, and the ones from before it that it filled
+        // in are emptied again, as what they were filled in with may have been one of its modules
+        for (got, before) in &[
+            (&self.got_mem, &checkpoint.got_mem),
+            (&self.got_func, &checkpoint.got_func),
+        ] {
+            got.borrow_mut()
+                .retain(|name, entry| match before.get(name) {
+                    Some(&(weak, resolved)) => {
+                        entry.weak = weak;
+                        if !resolved && entry.resolved {
+                            entry.resolved = false;
+                            entry
+                                .global
+                                .borrow_mut()
+                                .set_value(0_u32.into())
+                                .expect("GOT globals are mutable i32s");
+                        }
+                        true
+                    }
+                    None => false,
+                });
+        }
+
+        // Every slot the load gave out is past the end the table had before it
+        let mut table = self.table.borrow_mut();
+        for idx in checkpoint.table_len..table.len() {
+            table[idx] = None;
+        }
+        self.func_slots
+            .retain(|_, slot| (*slot as usize) < checkpoint.table_len);
+        self.undefined.clear();
+    }
+
+    fn dependencies_first(
+        &self,
+        name: &str,
+        seen: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) -> Result<()> {
+        if self.module(name).is_some() || !seen.insert(name.to_string()) {
+            return Ok(());
+        }
+
+        let dylink = self.dylink(name)?;
+        for needed in &dylink.needed {
+            self.dependencies_first(needed, seen, order)
+                .with_context(|| format!("{} needs {}", name, needed))?;
+        }
+        order.push(name.to_string());
+        Ok(())
+    }
+
+    // The modules being loaded, nearest to the one asked for first
+    fn search_order(&self, name: &str, loading: &[String]) -> Vec<String> {
+        let mut order: Vec<String> = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(name.to_string());
+        while let Some(name) = queue.pop_front() {
+            if !loading.contains(&name) || order.contains(&name) {
+                continue;
+            }
+            if let Ok(dylink) = self.dylink(&name) {
+                queue.extend(dylink.needed.iter().cloned());
+            }
+            order.push(name);
+        }
+        order
+    }
+
+    fn dylink(&self, name: &str) -> Result<&DylinkInfo> {
+        let module = self
+            .available
+            .get(name)
+            .ok_or_else(|| anyhow!("There's no module called {}", name))?;
+        dylink_info(name, module)
+    }
+
+    fn load_all(&mut self, name: &str, order: &[String]) -> Result<()> {
+        let first = self.loaded.len();
+        for name in order {
+            self.instantiate(name)
+                .with_context(|| format!("Instantiating {} failed", name))?;
+        }
+
+        for name in self.search_order(name, order) {
+            let idx = first + order.iter().position(|loaded| *loaded == name).unwrap();
+            self.define_symbols(idx);
+        }
+        self.fill_got()?;
+        self.check_undefined()?;
+
+        for module in &self.loaded[first..] {
+            initialize(module).with_context(|| format!("Initializing {} failed", module.name))?;
+        }
+        Ok(())
+    }
+
+    fn instantiate(&mut self, name: &str) -> Result<()> {
+        let module = self.available[name].clone();
+        let dylink = dylink_info(name, &module)?;
+        let memory_base = self.reserve_memory(dylink.memory_size, dylink.memory_alignment)?;
+        let table_base = self.reserve_table(dylink.table_size, dylink.table_alignment)?;
+
+        let resolver = LinkResolver {
+            linker: self,
+            name,
+            dylink,
+            memory_base,
+            table_base,
+            undefined: RefCell::default(),
+        };
+        let options = InstanceOptions {
+            defer_start: true,
+            ..Default::default()
+        };
+        let instance = module.instantiate_with_options(&resolver, &options)?;
+        let undefined = resolver.undefined.into_inner();
+        self.undefined.extend(undefined);
+
+        let instance = Rc::new(RefCell::new(instance));
+        self.bind_table(&instance);
+        self.loaded.push(LoadedModule {
+            name: name.to_string(),
+            memory_base,
+            table_base,
+            instance,
+        });
+        Ok(())
+    }
+
+    fn reserve_memory(&mut self, size: u32, alignment: u32) -> Result<u32> {
+        let base = align(self.data_end.into(), alignment)?;
+        let end = base + u64::from(size);
+        if end > u64::from(std::u32::MAX) {
+            return Err(anyhow!("There isn't room for {} bytes of data", size));
+        }
+
+        let pages = pages_for(end);
+        let mut memory = self.memory.borrow_mut();
+        if pages > memory.current_size() {
+            let grow_by = pages - memory.current_size();
+            memory
+                .grow_by(grow_by)
+                .with_context(|| format!("The modules need {} pages of memory", pages))?;
+        }
+        self.data_end = end as u32;
+        Ok(base as u32)
+    }
+
+    fn reserve_table(&mut self, size: u32, alignment: u32) -> Result<u32> {
+        let mut table = self.table.borrow_mut();
+        let base = align(table.len() as u64, alignment)?;
+        let end = base + u64::from(size);
+        if end > u64::from(std::u32::MAX) {
+            return Err(anyhow!("There isn't room for {} table slots", size));
+        }
+        let grow_by = end as usize - table.len();
+        table.grow_by(grow_by)?;
+        Ok(base as u32)
+    }
+
+    // Whatever the instance's element segments put in the table is only run on it. What's there
+    // already has been bound to the instances before it.
+    fn bind_table(&self, instance: &Rc<RefCell<Instance>>) {
+        let mut table = self.table.borrow_mut();
+        for idx in 0..table.len() {
+            if let Some(function) = table[idx].clone() {
+                table[idx] = Some(bind(&function, instance));
+            }
+        }
+    }
+
+    fn define_symbols(&mut self, idx: usize) {
+        let module = &self.loaded[idx];
+        let instance = module.instance.borrow();
+        let mut symbols = self.symbols.borrow_mut();
+        for (name, value) in instance.exports_in_order() {
+            if symbols.contains_key(name) {
+                continue;
+            }
+            let value = match value {
+                Extern::Func(function) => Extern::Func(bind(function, &module.instance)),
+                Extern::Global(global) => relocate(global, module.memory_base),
+                other => other.clone(),
+            };
+            symbols.insert(name.to_string(), value);
+            self.defined_by.insert(name.to_string(), idx);
+        }
+    }
+
+    fn fill_got(&mut self) -> Result<()> {
+        let symbols = self.symbols.borrow();
+        fill_got_entries(&self.got_mem, "GOT.mem", |name| match symbols.get(name) {
+            Some(Extern::Global(global)) => Ok(Some(u32::try_from(*global.borrow().get_value())?)),
+            Some(other) => Err(anyhow!("{} is a {}, not data", name, other)),
+            None => Ok(None),
+        })?;
+
+        let (table, func_slots, host) = (&self.table, &mut self.func_slots, &self.host);
+        fill_got_entries(&self.got_func, "GOT.func", |name| {
+            if let Some(slot) = func_slots.get(name) {
+                return Ok(Some(*slot));
+            }
+            let function = match symbols.get(name).or_else(|| host.get("env", name)) {
+                Some(Extern::Func(function)) => function.clone(),
+                Some(other) => return Err(anyhow!("{} is a {}, not a function", name, other)),
+                None => return Ok(None),
+            };
+            let mut table = table.borrow_mut();
+            let slot = table.len();
+            table.grow_by(1)?;
+            table[slot] = Some(function);
+            func_slots.insert(name.to_string(), slot as u32);
+            Ok(Some(slot as u32))
+        })
+    }
+
+    fn check_undefined(&mut self) -> Result<()> {
+        let symbols = self.symbols.borrow();
+        let undefined = std::mem::take(&mut self.undefined);
+        match undefined
+            .iter()
+            .find(|undefined| !undefined.weak && !symbols.contains_key(&undefined.name))
+        {
+            Some(undefined) => Err(anyhow!(
+                "Undefined symbol {}, which {} imports",
+                undefined.name,
+                undefined.importer
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn dylink_info<'a>(name: &str, module: &'a Module) -> Result<&'a DylinkInfo> {
+    let dylink = module.raw_module().dylink().ok_or_else(|| {
+        anyhow!(
+            "{} has no dylink.0 section, so it can't be linked dynamically",
+            name
+        )
+    })?;
+    if let Some((export, _)) = dylink
+        .export_info
+        .iter()
+        .find(|(_, flags)| flags & SYMBOL_TLS != 0)
+    {
+        return Err(anyhow!(
+            "{} exports the thread local {}, which isn't supported",
+            name,
+            export
+        ));
+    }
+    Ok(dylink)
+}
+
+fn pages_for(end: u64) -> usize {
+    ((end + WASM_PAGE_SIZE_IN_BYTES as u64 - 1) >> WASM_PAGE_SHIFT) as usize
+}
+
+// The alignment is a power of two
+fn align(value: u64, alignment: u32) -> Result<u64> {
+    if alignment >= 32 {
+        return Err(anyhow!("An alignment of 2^{} is too big", alignment));
+    }
+    let align = 1_u64 << alignment;
+    Ok(match value % align {
+        0 => value,
+        rem => value + align - rem,
+    })
+}
+
+fn fill_got_entries(
+    entries: &RefCell<HashMap<String, GotEntry>>,
+    got: &str,
+    mut address: impl FnMut(&str) -> Result<Option<u32>>,
+) -> Result<()> {
+    for (name, entry) in entries.borrow_mut().iter_mut() {
+        if entry.resolved {
+            continue;
+        }
+        match address(name).with_context(|| format!("Resolving {}.{} failed", got, name))? {
+            Some(address) => {
+                entry.global.borrow_mut().set_value(address.into())?;
+                entry.resolved = true;
+            }
+            None if entry.weak => {}
+            None => return Err(anyhow!("Undefined symbol {}, from {}", name, got)),
+        }
+    }
+    Ok(())
+}
+
+fn initialize(module: &LoadedModule) -> Result<()> {
+    let mut instance = module.instance.borrow_mut();
+    instance.run_start()?;
+    for name in &[APPLY_DATA_RELOCS, CALL_CTORS] {
+        if instance.get_function(name).is_ok() {
+            instance.invoke_export(name, &[])?;
+        }
+    }
+    Ok(())
+}
+
+// A module's data symbols are exported as immutable i32 globals holding where they are in its
+// data, which the module's memory base is added to
+fn relocate(global: &Rc<RefCell<Global>>, memory_base: u32) -> Extern {
+    let offset = {
+        let global = global.borrow();
+        if global.is_mutable() || *global.value_type() != ValueType::I32 {
+            None
+        } else {
+            u32::try_from(*global.get_value()).ok()
+        }
+    };
+    match offset {
+        Some(offset) => Extern::Global(Rc::new(RefCell::new(Global::new_host(
+            offset.wrapping_add(memory_base).into(),
+            false,
+        )))),
+        None => Extern::Global(global.clone()),
+    }
+}
+
+// A wasm function, wrapped to run on the instance it's from. A host function runs the same
+// wherever it's called from, so it's left as it is.
+fn bind(
+    function: &Rc<RefCell<Callable>>,
+    instance: &Rc<RefCell<Instance>>,
+) -> Rc<RefCell<Callable>> {
+    let func_type = match &*function.borrow() {
+        Callable::WasmExpr(_) => function.borrow().func_type().clone(),
+        Callable::Host(_) => return function.clone(),
+    };
+    Rc::new(RefCell::new(Callable::from_host(Rc::new(BoundFunction {
+        function: function.clone(),
+        func_type,
+        instance: Rc::downgrade(instance),
+    }))))
+}
+
+thread_local! {
+    // The instances further up the stack that are calling out to another one, most recent last
+    #[allow(clippy::missing_const_for_thread_local)]
+    static LENT: RefCell<Vec<*mut Instance>> = RefCell::new(Vec::new());
+}
+
+// Lends an instance out for as long as it's alive
+struct Lend;
+
+impl Lend {
+    fn new(instance: *mut Instance) -> Self {
+        LENT.with(|lent| lent.borrow_mut().push(instance));
+        Lend
+    }
+}
+
+impl Drop for Lend {
+    fn drop(&mut self) {
+        LENT.with(|lent| lent.borrow_mut().pop());
+    }
+}
+
+// The instance is only held weakly, since its own table and imports hold functions like this
+#[derive(Debug)]
+struct BoundFunction {
+    function: Rc<RefCell<Callable>>,
+    func_type: FuncType,
+    instance: Weak<RefCell<Instance>>,
+}
+
+impl HostCallable for BoundFunction {
+    fn func_type(&self) -> &FuncType {
+        &self.func_type
+    }
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        self.call_with_caller(&mut Caller::detached(), args)
+    }
+
+    fn call_with_caller(
+        &self,
+        caller: &mut Caller<'_>,
+        args: &[StackEntry],
+    ) -> Result<Vec<StackEntry>> {
+        let target = self
+            .instance
+            .upgrade()
+            .ok_or_else(|| anyhow!("The module the function is from has been dropped"))?;
+        let current = caller.instance().map(|instance| instance as *mut Instance);
+        if current == Some(target.as_ptr()) {
+            // A module calling its own function through the table
+            return caller
+                .instance()
+                .unwrap()
+                .call_own_function(&self.function, args);
+        }
+
+        let _lend = current.map(Lend::new);
+        if let Ok(mut instance) = target.try_borrow_mut() {
+            return instance.call_own_function(&self.function, args);
+        }
+        let lent = LENT.with(|lent| {
+            lent.borrow()
+                .iter()
+                .rev()
+                .find(|lent| **lent == target.as_ptr())
+                .copied()
+        });
+        match lent {
+            // SAFETY: The pointer came from the instance a call further up this thread's stack
+            // was made from, which is waiting for this call to return, and has nothing of the
+            // instance borrowed while it does, as for a host function calling back into the
+            // instance through its Caller. The Lend that put it there is dropped before that
+            // call returns.
+            Some(instance) => unsafe { &mut *instance }.call_own_function(&self.function, args),
+            None => Err(anyhow!(
+                "The module the function is from is already running, and isn't calling out"
+            )),
+        }
+    }
+}
+
+// A function import that's looked up in the export map each time it's called, since the module
+// that exports it wasn't loaded when it was imported
+#[derive(Debug)]
+struct LazyFunction {
+    name: String,
+    func_type: FuncType,
+    symbols: Weak<RefCell<HashMap<String, Extern>>>,
+}
+
+impl HostCallable for LazyFunction {
+    fn func_type(&self) -> &FuncType {
+        &self.func_type
+    }
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        self.call_with_caller(&mut Caller::detached(), args)
+    }
+
+    fn call_with_caller(
+        &self,
+        caller: &mut Caller<'_>,
+        args: &[StackEntry],
+    ) -> Result<Vec<StackEntry>> {
+        let symbol = self
+            .symbols
+            .upgrade()
+            .and_then(|symbols| symbols.borrow().get(&self.name).cloned());
+        let function = match symbol {
+            Some(Extern::Func(function)) => function.borrow().clone(),
+            Some(other) => return Err(anyhow!("{} is a {}, not a function", self.name, other)),
+            None => return Err(anyhow!("Undefined symbol {} was called", self.name)),
+        };
+        if *function.func_type() != self.func_type {
+            return Err(anyhow!(
+                "{} is {}, but was imported as {}",
+                self.name,
+                function.func_type(),
+                self.func_type
+            ));
+        }
+        match function {
+            Callable::Host(host) => host.call_with_caller(caller, args),
+            Callable::WasmExpr(_) => Err(anyhow!("{} isn't bound to an instance", self.name)),
+        }
+    }
+}
+
+struct LinkResolver<'a> {
+    linker: &'a DynamicLinker,
+    name: &'a str,
+    dylink: &'a DylinkInfo,
+    memory_base: u32,
+    table_base: u32,
+    undefined: RefCell<Vec<Undefined>>,
+}
+
+impl LinkResolver<'_> {
+    fn got(
+        &self,
+        entries: &RefCell<HashMap<String, GotEntry>>,
+        mod_name: &str,
+        name: &str,
+    ) -> Extern {
+        let weak = self.dylink.is_weak_import(mod_name, name);
+        let mut entries = entries.borrow_mut();
+        let entry = entries.entry(name.to_string()).or_insert_with(|| GotEntry {
+            global: Rc::new(RefCell::new(Global::new_host(0_u32.into(), true))),
+            weak,
+            resolved: false,
+        });
+        entry.weak &= weak;
+        Extern::Global(entry.global.clone())
+    }
+
+    fn resolve_as<T>(
+        &self,
+        mod_name: &str,
+        name: &str,
+        extern_type: ExternType,
+        into: impl FnOnce(Extern) -> std::result::Result<T, Extern>,
+    ) -> Result<T> {
+        into(self.resolve(mod_name, name, &extern_type)?).map_err(|value| {
+            anyhow!(
+                "Imported {} {}:{} is a {}",
+                extern_type.kind(),
+                mod_name,
+                name,
+                value.kind()
+            )
+        })
+    }
+}
+
+impl Resolver for LinkResolver<'_> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        let extern_type = ExternType::Func(func_type.clone());
+        self.resolve_as(mod_name, name, extern_type, Extern::into_function)
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        let extern_type = ExternType::Table(table_type.clone());
+        self.resolve_as(mod_name, name, extern_type, Extern::into_table)
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        let extern_type = ExternType::Memory(mem_type.clone());
+        self.resolve_as(mod_name, name, extern_type, Extern::into_memory)
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        let extern_type = ExternType::Global(global_type.clone());
+        self.resolve_as(mod_name, name, extern_type, Extern::into_global)
+    }
+
+    fn resolve(&self, mod_name: &str, name: &str, extern_type: &ExternType) -> Result<Extern> {
+        let linker = self.linker;
+        let base = |value: u32| {
+            Extern::Global(Rc::new(RefCell::new(Global::new_host(value.into(), false))))
+        };
+        Ok(match (mod_name, name) {
+            ("env", "memory") => Extern::Memory(linker.memory.clone()),
+            ("env", "__indirect_function_table") => Extern::Table(linker.table.clone()),
+            ("env", "__stack_pointer") => Extern::Global(linker.stack_pointer.clone()),
+            ("env", "__memory_base") => base(self.memory_base),
+            ("env", "__table_base") => base(self.table_base),
+            ("GOT.mem", _) => self.got(&linker.got_mem, mod_name, name),
+            ("GOT.func", _) => self.got(&linker.got_func, mod_name, name),
+            _ => {
+                if let Some(value) = linker.host.get(mod_name, name) {
+                    return Ok(value.clone());
+                }
+                if mod_name == "env" {
+                    if let Some(value) = linker.symbols.borrow().get(name) {
+                        return Ok(value.clone());
+                    }
+                }
+                match extern_type {
+                    ExternType::Func(func_type) if mod_name == "env" => {
+                        self.undefined.borrow_mut().push(Undefined {
+                            importer: self.name.to_string(),
+                            name: name.to_string(),
+                            weak: self.dylink.is_weak_import(mod_name, name),
+                        });
+                        Extern::Func(Rc::new(RefCell::new(Callable::from_host(Rc::new(
+                            LazyFunction {
+                                name: name.to_string(),
+                                func_type: func_type.clone(),
+                                symbols: Rc::downgrade(&linker.symbols),
+                            },
+                        )))))
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "Imported {} {}:{} not found",
+                            extern_type.kind(),
+                            mod_name,
+                            name
+                        ))
+                    }
+                }
+            }
+        })
+    }
+}
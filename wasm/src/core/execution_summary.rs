@@ -0,0 +1,158 @@
+use crate::core::store_access::{ExpressionStore, LifetimeToRef, LifetimeToRefMut};
+use crate::core::{
+    CallObserver, Callable, Caller, FuncType, Global, Memory, SnapshotLimits, Stack, Table,
+};
+use anyhow::Result;
+use std::fmt;
+use std::rc::Rc;
+
+// How much work a call did. The stack depth counts everything on the value stack, which holds
+// the parameters and locals of every frame as well as their operands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutionSummary {
+    instructions: u64,
+    max_stack_depth: usize,
+    max_call_depth: usize,
+}
+
+impl ExecutionSummary {
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn max_stack_depth(&self) -> usize {
+        self.max_stack_depth
+    }
+
+    pub fn max_call_depth(&self) -> usize {
+        self.max_call_depth
+    }
+
+    // The summary of a call that failed, which is attached to the error as its context
+    pub fn from_error(error: &anyhow::Error) -> Option<&ExecutionSummary> {
+        error.downcast_ref::<ExecutionSummary>()
+    }
+
+    pub(crate) fn record(&mut self, stack: &Stack) {
+        self.max_stack_depth = self.max_stack_depth.max(stack.height());
+        self.max_call_depth = self.max_call_depth.max(stack.frame_count());
+    }
+}
+
+impl fmt::Display for ExecutionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "After {} instructions, with a stack depth of at most {} and a call depth of at most {}",
+            self.instructions, self.max_stack_depth, self.max_call_depth
+        )
+    }
+}
+
+// A store that passes everything through to another one, and keeps a summary of what was run
+// against it
+pub(crate) struct CountingStore<'a, Store> {
+    store: &'a mut Store,
+    summary: ExecutionSummary,
+}
+
+impl<'a, Store: ExpressionStore> CountingStore<'a, Store> {
+    pub fn new(store: &'a mut Store) -> Self {
+        Self {
+            store,
+            summary: ExecutionSummary::default(),
+        }
+    }
+
+    pub fn summary(&self) -> ExecutionSummary {
+        self.summary
+    }
+}
+
+impl<'a, Store: ExpressionStore> crate::core::ConstantExpressionStore for CountingStore<'a, Store> {
+    type GlobalRef = Store::GlobalRef;
+
+    fn global_idx<'b>(
+        &'b self,
+        idx: usize,
+    ) -> Result<<Self::GlobalRef as LifetimeToRef<'b, Global>>::Output> {
+        self.store.global_idx(idx)
+    }
+}
+
+impl<'a, Store: ExpressionStore> ExpressionStore for CountingStore<'a, Store> {
+    type GlobalRefMut = Store::GlobalRefMut;
+    type FuncTypeRef = Store::FuncTypeRef;
+    type TableRef = Store::TableRef;
+    type CallableRef = Store::CallableRef;
+    type MemoryRef = Store::MemoryRef;
+    type MemoryRefMut = Store::MemoryRefMut;
+
+    fn global_idx_mut<'b>(
+        &'b mut self,
+        idx: usize,
+    ) -> Result<<Self::GlobalRefMut as LifetimeToRefMut<'b, Global>>::Output> {
+        self.store.global_idx_mut(idx)
+    }
+
+    fn func_type_idx<'b>(
+        &'b self,
+        idx: usize,
+    ) -> Result<<Self::FuncTypeRef as LifetimeToRef<'b, FuncType>>::Output> {
+        self.store.func_type_idx(idx)
+    }
+
+    fn table_idx<'b>(
+        &'b self,
+        idx: usize,
+    ) -> Result<<Self::TableRef as LifetimeToRef<'b, Table>>::Output> {
+        self.store.table_idx(idx)
+    }
+
+    fn callable_idx<'b>(
+        &'b self,
+        idx: usize,
+    ) -> Result<<Self::CallableRef as LifetimeToRef<'b, Callable>>::Output> {
+        self.store.callable_idx(idx)
+    }
+
+    fn mem_idx<'b>(
+        &'b self,
+        idx: usize,
+    ) -> Result<<Self::MemoryRef as LifetimeToRef<'b, Memory>>::Output> {
+        self.store.mem_idx(idx)
+    }
+
+    fn mem_idx_mut<'b>(
+        &'b mut self,
+        idx: usize,
+    ) -> Result<<Self::MemoryRefMut as LifetimeToRefMut<'b, Memory>>::Output> {
+        self.store.mem_idx_mut(idx)
+    }
+
+    fn on_instruction(&mut self, stack: &Stack) -> Result<()> {
+        self.summary.instructions += 1;
+        self.summary.record(stack);
+        self.store.on_instruction(stack)
+    }
+
+    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<()> {
+        self.store.grow_memory_by(mem_idx, grow_by)
+    }
+
+    fn on_call(&mut self, stack: &Stack, is_host: bool) {
+        self.store.on_call(stack, is_host);
+    }
+
+    fn call_observer(&self) -> Option<Rc<dyn CallObserver>> {
+        self.store.call_observer()
+    }
+
+    fn caller(&mut self) -> Caller<'_> {
+        self.store.caller()
+    }
+
+    fn trap_snapshot_limits(&self) -> Option<SnapshotLimits> {
+        self.store.trap_snapshot_limits()
+    }
+}
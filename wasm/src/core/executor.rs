@@ -1,10 +1,12 @@
 pub mod execute_core;
+pub mod float_ops;
 pub mod memory_access;
 pub mod stack_ops;
 pub mod store_access;
 
 pub use execute_core::{
-    evaluate_constant_expression, execute_constant_expression, execute_expression,
+    evaluate_constant_expression, evaluate_reference_expression, execute_constant_expression,
+    execute_expression,
 };
 pub use store_access::{ConstantExpressionStore, ExpressionStore};
 
@@ -1,11 +1,20 @@
 use std::{cell::RefCell, convert::TryFrom, rc::Rc};
 
-use crate::core::{stack_entry::StackEntry, BlockType, Callable, FuncType, Stack};
+use crate::core::{
+    locate_trap, stack_entry::StackEntry, BlockType, Callable, Stack, Trap, TrapKind,
+};
 use crate::parser::{Instruction, InstructionSource, Opcode};
+use crate::reader::ReaderUtil;
 use anyhow::{anyhow, Result};
 
+use super::float_ops::{
+    trunc_to_i32, trunc_to_i64, trunc_to_u32, trunc_to_u64, wasm_demote, wasm_promote, MinMaxOps,
+    SignBitOps,
+};
 use super::memory_access::{mem_load, mem_store};
-use super::stack_ops::{binary_boolean_op, binary_op, get_stack_top, unary_boolean_op, unary_op};
+use super::stack_ops::{
+    binary_boolean_op, binary_op, get_stack_top, unary_boolean_op, unary_op, unary_try_op,
+};
 
 pub use super::store_access::{
     CellRefMutType, CellRefType, ConstantExpressionStore, ExpressionStore, LifetimeToRef,
@@ -47,6 +56,27 @@ fn execute_single_constant_instruction(
     Ok(())
 }
 
+fn set_local(stack: &mut Stack, local_idx: usize, arg: StackEntry) -> Result<()> {
+    if local_idx >= stack.parameter_count() + stack.local_count() {
+        return Err(anyhow!("Local index out of range"));
+    }
+
+    // Locals are given a value of the right type when the frame is pushed, so this catches
+    // anything trying to change the type of a local
+    let local = &mut stack.local_mut()[local_idx];
+    if !local.is_same_type(&arg) {
+        return Err(anyhow!("Local type mismatch"));
+    }
+
+    *local = arg;
+    Ok(())
+}
+
+fn shift_count(count: u64) -> u32 {
+    // The i64 shifts take their count as an i64, but only the bottom 6 bits are significant
+    (count & 63) as u32
+}
+
 #[derive(Debug, Clone, PartialEq)]
 enum InstructionResult {
     Block,
@@ -72,7 +102,7 @@ fn execute_single_instruction(
     store: &mut impl ExpressionStore,
 ) -> Result<SingleInstructionResult> {
     match instruction.opcode() {
-        Opcode::Unreachable => return Err(anyhow!("Unreachable opcode")),
+        Opcode::Unreachable => return Err(Trap::new(TrapKind::Unreachable).into()),
         Opcode::Nop => {}
         Opcode::Block => {
             return Ok(SingleInstructionResult::ControlInstruction(
@@ -258,20 +288,15 @@ fn execute_single_instruction(
 
             stack.push(stack.local()[local_idx]);
         }
-        opcode @ Opcode::LocalSet | opcode @ Opcode::LocalTee => {
+        Opcode::LocalSet => {
             let arg = get_stack_top(stack, 1)?[0];
+            set_local(stack, instruction.get_single_u32_as_usize_arg(), arg)?;
             stack.pop();
-
-            let local_idx = instruction.get_single_u32_as_usize_arg();
-            if local_idx >= stack.parameter_count() + stack.local_count() {
-                return Err(anyhow!("Local index out of range"));
-            }
-
-            stack.local_mut()[local_idx] = arg;
-
-            if opcode == Opcode::LocalTee {
-                stack.push(arg);
-            }
+        }
+        Opcode::LocalTee => {
+            // The value stays where it is, so it remains visible to any enclosing block's arity
+            let arg = get_stack_top(stack, 1)?[0];
+            set_local(stack, instruction.get_single_u32_as_usize_arg(), arg)?;
         }
         Opcode::GlobalGet => {
             stack.push(store.get_global_value(instruction.get_single_u32_as_usize_arg())?)
@@ -348,11 +373,12 @@ fn execute_single_instruction(
         Opcode::I32And => binary_op(stack, |a: u32, b: u32| a & b)?,
         Opcode::I32Or => binary_op(stack, |a: u32, b: u32| a | b)?,
         Opcode::I32Xor => binary_op(stack, |a: u32, b: u32| a ^ b)?,
-        Opcode::I32Shl => binary_op(stack, |a: u32, b: u32| a << (b % 32))?,
-        Opcode::I32ShrS => binary_op(stack, |a: i32, b: i32| a >> (b % 32))?,
-        Opcode::I32ShrU => binary_op(stack, |a: u32, b: u32| a >> (b % 32))?,
-        Opcode::I32Rotl => binary_op(stack, |a: u32, b: u32| a.rotate_left(b % 32))?,
-        Opcode::I32Rotr => binary_op(stack, |a: u32, b: u32| a.rotate_right(b % 32))?,
+        // Shift and rotate counts are taken modulo the bit width, whatever their sign
+        Opcode::I32Shl => binary_op(stack, |a: u32, b: u32| a.wrapping_shl(b & 31))?,
+        Opcode::I32ShrS => binary_op(stack, |a: i32, b: i32| a.wrapping_shr((b & 31) as u32))?,
+        Opcode::I32ShrU => binary_op(stack, |a: u32, b: u32| a.wrapping_shr(b & 31))?,
+        Opcode::I32Rotl => binary_op(stack, |a: u32, b: u32| a.rotate_left(b & 31))?,
+        Opcode::I32Rotr => binary_op(stack, |a: u32, b: u32| a.rotate_right(b & 31))?,
 
         Opcode::I64Clz => unary_op(stack, |a: u64| u64::from(a.leading_zeros()))?,
         Opcode::I64Ctz => unary_op(stack, |a: u64| u64::from(a.trailing_zeros()))?,
@@ -367,18 +393,16 @@ fn execute_single_instruction(
         Opcode::I64And => binary_op(stack, |a: u64, b: u64| a & b)?,
         Opcode::I64Or => binary_op(stack, |a: u64, b: u64| a | b)?,
         Opcode::I64Xor => binary_op(stack, |a: u64, b: u64| a ^ b)?,
-        Opcode::I64Shl => binary_op(stack, |a: u64, b: u64| a << (b % 32))?,
-        Opcode::I64ShrS => binary_op(stack, |a: i64, b: i64| a >> (b % 32))?,
-        Opcode::I64ShrU => binary_op(stack, |a: u64, b: u64| a >> (b % 32))?,
-        Opcode::I64Rotl => binary_op(stack, |a: u64, b: u64| {
-            a.rotate_left(u32::try_from(b % 32).unwrap())
-        })?,
-        Opcode::I64Rotr => binary_op(stack, |a: u64, b: u64| {
-            a.rotate_right(u32::try_from(b % 32).unwrap())
+        Opcode::I64Shl => binary_op(stack, |a: u64, b: u64| a.wrapping_shl(shift_count(b)))?,
+        Opcode::I64ShrS => binary_op(stack, |a: i64, b: i64| {
+            a.wrapping_shr(shift_count(b as u64))
         })?,
+        Opcode::I64ShrU => binary_op(stack, |a: u64, b: u64| a.wrapping_shr(shift_count(b)))?,
+        Opcode::I64Rotl => binary_op(stack, |a: u64, b: u64| a.rotate_left(shift_count(b)))?,
+        Opcode::I64Rotr => binary_op(stack, |a: u64, b: u64| a.rotate_right(shift_count(b)))?,
 
-        Opcode::F32Abs => unary_op(stack, |a: f32| a.abs())?,
-        Opcode::F32Neg => unary_op(stack, |a: f32| -a)?,
+        Opcode::F32Abs => unary_op(stack, |a: f32| a.wasm_abs())?,
+        Opcode::F32Neg => unary_op(stack, |a: f32| a.wasm_neg())?,
         Opcode::F32Ceil => unary_op(stack, |a: f32| a.ceil())?,
         Opcode::F32Floor => unary_op(stack, |a: f32| a.floor())?,
         Opcode::F32Trunc => unary_op(stack, |a: f32| a.trunc())?,
@@ -388,12 +412,12 @@ fn execute_single_instruction(
         Opcode::F32Sub => binary_op(stack, |a: f32, b: f32| a - b)?,
         Opcode::F32Mul => binary_op(stack, |a: f32, b: f32| a * b)?,
         Opcode::F32Div => binary_op(stack, |a: f32, b: f32| a / b)?,
-        Opcode::F32Min => binary_op(stack, |a: f32, b: f32| a.min(b))?,
-        Opcode::F32Max => binary_op(stack, |a: f32, b: f32| a.max(b))?,
-        Opcode::F32CopySign => binary_op(stack, |a: f32, b: f32| a.copysign(b))?,
+        Opcode::F32Min => binary_op(stack, |a: f32, b: f32| a.wasm_min(b))?,
+        Opcode::F32Max => binary_op(stack, |a: f32, b: f32| a.wasm_max(b))?,
+        Opcode::F32CopySign => binary_op(stack, |a: f32, b: f32| a.wasm_copysign(b))?,
 
-        Opcode::F64Abs => unary_op(stack, |a: f64| a.abs())?,
-        Opcode::F64Neg => unary_op(stack, |a: f64| -a)?,
+        Opcode::F64Abs => unary_op(stack, |a: f64| a.wasm_abs())?,
+        Opcode::F64Neg => unary_op(stack, |a: f64| a.wasm_neg())?,
         Opcode::F64Ceil => unary_op(stack, |a: f64| a.ceil())?,
         Opcode::F64Floor => unary_op(stack, |a: f64| a.floor())?,
         Opcode::F64Trunc => unary_op(stack, |a: f64| a.trunc())?,
@@ -403,43 +427,42 @@ fn execute_single_instruction(
         Opcode::F64Sub => binary_op(stack, |a: f64, b: f64| a - b)?,
         Opcode::F64Mul => binary_op(stack, |a: f64, b: f64| a * b)?,
         Opcode::F64Div => binary_op(stack, |a: f64, b: f64| a / b)?,
-        Opcode::F64Min => binary_op(stack, |a: f64, b: f64| a.min(b))?,
-        Opcode::F64Max => binary_op(stack, |a: f64, b: f64| a.max(b))?,
-        Opcode::F64CopySign => binary_op(stack, |a: f64, b: f64| a.copysign(b))?,
+        Opcode::F64Min => binary_op(stack, |a: f64, b: f64| a.wasm_min(b))?,
+        Opcode::F64Max => binary_op(stack, |a: f64, b: f64| a.wasm_max(b))?,
+        Opcode::F64CopySign => binary_op(stack, |a: f64, b: f64| a.wasm_copysign(b))?,
 
         Opcode::I32WrapI64 => unary_op(stack, |a: u64| a as u32)?,
-        Opcode::I32TruncF32S => unary_op(stack, |a: f32| a as i32)?,
-        Opcode::I32TruncF32U => unary_op(stack, |a: f32| a as u32)?,
-        Opcode::I32TruncF64S => unary_op(stack, |a: f64| a as i32)?,
-        Opcode::I32TruncF64U => unary_op(stack, |a: f64| a as u32)?,
+        Opcode::I32TruncF32S => unary_try_op(stack, |a: f32| trunc_to_i32(f64::from(a)))?,
+        Opcode::I32TruncF32U => unary_try_op(stack, |a: f32| trunc_to_u32(f64::from(a)))?,
+        Opcode::I32TruncF64S => unary_try_op(stack, trunc_to_i32)?,
+        Opcode::I32TruncF64U => unary_try_op(stack, trunc_to_u32)?,
         Opcode::I64ExtendI32S => unary_op(stack, |a: i32| a as i64)?,
         Opcode::I64ExtendI32U => unary_op(stack, |a: u32| a as u64)?,
-        Opcode::I64TruncF32S => unary_op(stack, |a: f32| a as i64)?,
-        Opcode::I64TruncF32U => unary_op(stack, |a: f32| a as u64)?,
-        Opcode::I64TruncF64S => unary_op(stack, |a: f64| a as i64)?,
-        Opcode::I64TruncF64U => unary_op(stack, |a: f64| a as u64)?,
+        Opcode::I64TruncF32S => unary_try_op(stack, |a: f32| trunc_to_i64(f64::from(a)))?,
+        Opcode::I64TruncF32U => unary_try_op(stack, |a: f32| trunc_to_u64(f64::from(a)))?,
+        Opcode::I64TruncF64S => unary_try_op(stack, trunc_to_i64)?,
+        Opcode::I64TruncF64U => unary_try_op(stack, trunc_to_u64)?,
         Opcode::F32ConvertI32S => unary_op(stack, |a: i32| a as f32)?,
         Opcode::F32ConvertI32U => unary_op(stack, |a: u32| a as f32)?,
         Opcode::F32ConvertI64S => unary_op(stack, |a: i64| a as f32)?,
         Opcode::F32ConvertI64U => unary_op(stack, |a: u64| a as f32)?,
-        Opcode::F32DemoteF64 => unary_op(stack, |a: f64| a as f32)?,
+        Opcode::F32DemoteF64 => unary_op(stack, wasm_demote)?,
         Opcode::F64ConvertI32S => unary_op(stack, |a: i32| a as f64)?,
         Opcode::F64ConvertI32U => unary_op(stack, |a: u32| a as f64)?,
         Opcode::F64ConvertI64S => unary_op(stack, |a: i64| a as f64)?,
         Opcode::F64ConvertI64U => unary_op(stack, |a: u64| a as f64)?,
-        Opcode::F64PromoteF32 => unary_op(stack, |a: f32| a as f64)?,
-        Opcode::I32ReinterpretF32 => {
-            unary_op(stack, |a: f32| -> u32 { unsafe { std::mem::transmute(a) } })?
-        }
-        Opcode::I64ReinterpretF64 => {
-            unary_op(stack, |a: f64| -> u64 { unsafe { std::mem::transmute(a) } })?
-        }
-        Opcode::F32ReinterpretI32 => {
-            unary_op(stack, |a: i32| -> f32 { unsafe { std::mem::transmute(a) } })?
-        }
-        Opcode::F64ReinterpretI64 => {
-            unary_op(stack, |a: i64| -> f64 { unsafe { std::mem::transmute(a) } })?
-        }
+        Opcode::F64PromoteF32 => unary_op(stack, wasm_promote)?,
+        // Reinterpretation is a pure bit copy, so NaN payloads must come through untouched
+        Opcode::I32ReinterpretF32 => unary_op(stack, |a: f32| a.to_bits())?,
+        Opcode::I64ReinterpretF64 => unary_op(stack, |a: f64| a.to_bits())?,
+        Opcode::F32ReinterpretI32 => unary_op(stack, |a: u32| f32::from_bits(a))?,
+        Opcode::F64ReinterpretI64 => unary_op(stack, |a: u64| f64::from_bits(a))?,
+
+        Opcode::I32Extend8S => unary_op(stack, |a: i32| i32::from(a as i8))?,
+        Opcode::I32Extend16S => unary_op(stack, |a: i32| i32::from(a as i16))?,
+        Opcode::I64Extend8S => unary_op(stack, |a: i64| i64::from(a as i8))?,
+        Opcode::I64Extend16S => unary_op(stack, |a: i64| i64::from(a as i16))?,
+        Opcode::I64Extend32S => unary_op(stack, |a: i64| i64::from(a as i32))?,
     }
 
     Ok(SingleInstructionResult::Done)
@@ -472,6 +495,43 @@ pub fn evaluate_constant_expression(
     Ok(stack.frame()[stack.working_limit() - arity..stack.working_limit()].to_vec())
 }
 
+// Evaluates one of the expressions an element segment gives its entries as, which give a function
+// reference rather than a value, so don't go on the stack. None is the null reference. There are
+// no reference typed globals, so a global can't be where the reference comes from.
+pub fn evaluate_reference_expression(
+    expr: &impl InstructionSource,
+    store: &impl ConstantExpressionStore,
+) -> Result<Option<usize>> {
+    let mut bytes = expr.get_instruction_bytes();
+    let reference = match bytes.read_u8()? {
+        0xd0 => {
+            bytes.read_u8()?;
+            None
+        }
+        0xd2 => Some(bytes.read_leb_usize()?),
+        0x23 => {
+            let global_idx = bytes.read_leb_usize()?;
+            let value = store.get_global_value(global_idx)?;
+            return Err(anyhow!(
+                "Global {} holds an {}, not a function reference",
+                global_idx,
+                value.ty()
+            ));
+        }
+        opcode => {
+            return Err(anyhow!(
+                "Opcode 0x{:02x} is not valid in an element expression",
+                opcode
+            ))
+        }
+    };
+
+    if bytes != [Opcode::End as u8] {
+        return Err(anyhow!("Element expression has more than one instruction"));
+    }
+    Ok(reference)
+}
+
 fn execute_inner_loop<'a>(
     iter: &'_ mut impl Iterator<Item = Result<Instruction<'a>>>,
     stack: &'_ mut Stack,
@@ -486,7 +546,9 @@ fn execute_inner_loop<'a>(
                 return Some(Err(e));
             }
             Some(Ok(instruction)) => {
-                match execute_single_instruction(&instruction, stack, store) {
+                let result = execute_single_instruction(&instruction, stack, store);
+                let checked = store.on_instruction(stack);
+                match result.and_then(|result| checked.map(|_| result)) {
                     Ok(SingleInstructionResult::Done) => {} // Normal instruction executed normally
                     Ok(SingleInstructionResult::ControlInstruction(ir)) => {
                         return Some(Ok((ir, instruction)));
@@ -683,24 +745,27 @@ fn execute_call(
     // TODOTODOTODO This is nasty and points to a broader problem that having
     // the store own the callables is a sharing and mutability problem.
     let callable = store.callable_idx(idx)?.clone();
-    callable.call(stack, store)?;
+    callable.call_as(Some(idx), stack, store)?;
     Ok(BranchControl::no_branch())
 }
 
+// The callable in a table slot, once it's been checked to have the type the call expects. The type
+// is compared where it is rather than copied, so an indirect call doesn't allocate.
 fn get_indirect_callable_from_table(
     store: &impl ExpressionStore,
     func_type_idx: usize,
     table_idx: usize,
     elem_idx: usize,
-) -> Result<(FuncType, Rc<RefCell<Callable>>)> {
-    // TODOTODOTODO This is nasty and points to a broader problem that having
-    // the store own the callables is a sharing and mutability problem.
-    let func_type = store.func_type_idx(func_type_idx)?.clone();
+) -> Result<Rc<RefCell<Callable>>> {
+    let func_type = store.func_type_idx(func_type_idx)?;
     let table = store.table_idx(table_idx)?;
 
     let callable = table.get_entry(elem_idx)?;
-
-    Ok((func_type, callable))
+    if *callable.borrow().func_type() == *func_type {
+        Ok(callable)
+    } else {
+        Err(anyhow!("Indirect function call type does not match"))
+    }
 }
 
 fn execute_call_indirect<'a>(
@@ -713,17 +778,12 @@ fn execute_call_indirect<'a>(
     let elem_idx = u32::try_from(get_stack_top(stack, 1)?[0])? as usize;
     stack.pop();
 
-    let (func_type, callable) =
-        get_indirect_callable_from_table(store, func_type_idx, table_idx, elem_idx)?;
-    let callable = callable.borrow();
-
-    // Check the function types
-    if *callable.func_type() == func_type {
-        callable.call(stack, store)?;
-        Ok(BranchControl::no_branch())
-    } else {
-        Err(anyhow!("Indirect function call type does not match"))
-    }
+    let callable = get_indirect_callable_from_table(store, func_type_idx, table_idx, elem_idx)
+        .map_err(|error| locate_trap(error, None, stack.access_site(instruction.bytes())))?;
+    // Copied out of the table, so the slot isn't borrowed while the function runs
+    let callable = callable.borrow().clone();
+    callable.call(stack, store)?;
+    Ok(BranchControl::no_branch())
 }
 
 fn execute_return(_stack: &mut Stack, _store: &mut impl ExpressionStore) -> Result<BranchControl> {
@@ -0,0 +1,164 @@
+use crate::core::{Trap, TrapKind};
+use anyhow::Result;
+
+// The sign manipulation instructions are defined in terms of the sign bit alone, so they have
+// to be implemented as bit operations. Anything that goes through comparisons or arithmetic gets
+// NaNs wrong, because NaNs don't compare and arithmetic is allowed to change the payload.
+pub trait SignBitOps: Sized + Copy {
+    fn wasm_abs(self) -> Self;
+    fn wasm_neg(self) -> Self;
+    fn wasm_copysign(self, sign: Self) -> Self;
+}
+
+const F32_SIGN_MASK: u32 = 0x8000_0000;
+const F64_SIGN_MASK: u64 = 0x8000_0000_0000_0000;
+
+impl SignBitOps for f32 {
+    fn wasm_abs(self) -> Self {
+        Self::from_bits(self.to_bits() & !F32_SIGN_MASK)
+    }
+
+    fn wasm_neg(self) -> Self {
+        Self::from_bits(self.to_bits() ^ F32_SIGN_MASK)
+    }
+
+    fn wasm_copysign(self, sign: Self) -> Self {
+        Self::from_bits((self.to_bits() & !F32_SIGN_MASK) | (sign.to_bits() & F32_SIGN_MASK))
+    }
+}
+
+impl SignBitOps for f64 {
+    fn wasm_abs(self) -> Self {
+        Self::from_bits(self.to_bits() & !F64_SIGN_MASK)
+    }
+
+    fn wasm_neg(self) -> Self {
+        Self::from_bits(self.to_bits() ^ F64_SIGN_MASK)
+    }
+
+    fn wasm_copysign(self, sign: Self) -> Self {
+        Self::from_bits((self.to_bits() & !F64_SIGN_MASK) | (sign.to_bits() & F64_SIGN_MASK))
+    }
+}
+
+// Rust's min and max return the other operand when one of them is NaN, and either zero when
+// comparing -0 with +0. Wasm's return NaN if either operand is, and take -0 to be below +0.
+pub trait MinMaxOps: Sized + Copy {
+    fn wasm_min(self, other: Self) -> Self;
+    fn wasm_max(self, other: Self) -> Self;
+}
+
+impl MinMaxOps for f32 {
+    fn wasm_min(self, other: Self) -> Self {
+        if self.is_nan() || other.is_nan() {
+            self + other
+        } else if self == other {
+            Self::from_bits(self.to_bits() | other.to_bits())
+        } else {
+            self.min(other)
+        }
+    }
+
+    fn wasm_max(self, other: Self) -> Self {
+        if self.is_nan() || other.is_nan() {
+            self + other
+        } else if self == other {
+            Self::from_bits(self.to_bits() & other.to_bits())
+        } else {
+            self.max(other)
+        }
+    }
+}
+
+impl MinMaxOps for f64 {
+    fn wasm_min(self, other: Self) -> Self {
+        if self.is_nan() || other.is_nan() {
+            self + other
+        } else if self == other {
+            Self::from_bits(self.to_bits() | other.to_bits())
+        } else {
+            self.min(other)
+        }
+    }
+
+    fn wasm_max(self, other: Self) -> Self {
+        if self.is_nan() || other.is_nan() {
+            self + other
+        } else if self == other {
+            Self::from_bits(self.to_bits() & other.to_bits())
+        } else {
+            self.max(other)
+        }
+    }
+}
+
+const F32_CANONICAL_NAN: u32 = 0x7fc0_0000;
+const F64_CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+// Converting between float widths is exactly what the `as` casts do for numbers (promotion is
+// exact, demotion rounds to nearest even and overflows to infinity). NaNs are the exception,
+// where the casts leave the payload up to the host, so they are canonicalized explicitly.
+pub fn wasm_demote(value: f64) -> f32 {
+    if value.is_nan() {
+        let sign = if value.is_sign_negative() {
+            F32_SIGN_MASK
+        } else {
+            0
+        };
+        f32::from_bits(F32_CANONICAL_NAN | sign)
+    } else {
+        value as f32
+    }
+}
+
+pub fn wasm_promote(value: f32) -> f64 {
+    if value.is_nan() {
+        let sign = if value.is_sign_negative() {
+            F64_SIGN_MASK
+        } else {
+            0
+        };
+        f64::from_bits(F64_CANONICAL_NAN | sign)
+    } else {
+        f64::from(value)
+    }
+}
+
+// The truncating conversions trap rather than saturate, so the truncated value has to be range
+// checked first. The bounds are all exactly representable as f64, and f32 converts to f64 exactly,
+// so doing the checks in f64 avoids worrying about which integer limits f32 can represent.
+fn check_trunc_range(value: f64, lower_exclusive: f64, upper_exclusive: f64) -> Result<f64> {
+    if value.is_nan() {
+        Err(Trap::new(TrapKind::InvalidConversionToInteger).into())
+    } else if value <= lower_exclusive || value >= upper_exclusive {
+        Err(Trap::new(TrapKind::IntegerOverflow).into())
+    } else {
+        Ok(value.trunc())
+    }
+}
+
+pub fn trunc_to_i32(value: f64) -> Result<i32> {
+    check_trunc_range(value, -2_147_483_649.0, 2_147_483_648.0).map(|v| v as i32)
+}
+
+pub fn trunc_to_u32(value: f64) -> Result<u32> {
+    check_trunc_range(value, -1.0, 4_294_967_296.0).map(|v| v as u32)
+}
+
+pub fn trunc_to_i64(value: f64) -> Result<i64> {
+    // The next f64 down from -2^63 is already out of range, so -2^63 is the only value at the
+    // bottom of the range that doesn't fit the exclusive check
+    if value == -9_223_372_036_854_775_808.0 {
+        return Ok(value as i64);
+    }
+    check_trunc_range(
+        value,
+        -9_223_372_036_854_775_808.0,
+        9_223_372_036_854_775_808.0,
+    )
+    .map(|v| v as i64)
+}
+
+pub fn trunc_to_u64(value: f64) -> Result<u64> {
+    check_trunc_range(value, -1.0, 18_446_744_073_709_551_616.0).map(|v| v as u64)
+}
@@ -1,6 +1,9 @@
 use std::convert::TryFrom;
 
-use crate::core::{stack_entry::StackEntry, Stack};
+use crate::core::{
+    locate_trap, memory_page::WASM_PAGE_SIZE_IN_BYTES, stack_entry::StackEntry, Stack, Trap,
+    TrapKind,
+};
 use crate::parser::Instruction;
 use anyhow::Result;
 use generic_array::typenum::consts::{U1, U2, U4, U8};
@@ -9,6 +12,9 @@ use generic_array::{ArrayLength, GenericArray};
 use super::stack_ops::get_stack_top;
 use super::ExpressionStore;
 
+// Converts values to and from the bytes wasm keeps them as in memory, which are always little
+// endian. Everything that reads or writes memory a value at a time goes through this rather than
+// the host's own byte order, so the guest sees the same bytes on a big endian host.
 pub trait LEByteConvert {
     type ArrayLength: ArrayLength<u8>;
 
@@ -88,6 +94,18 @@ impl LEByteConvert for u32 {
     }
 }
 
+impl LEByteConvert for i64 {
+    type ArrayLength = U8;
+
+    fn from_bytes(bytes: GenericArray<u8, Self::ArrayLength>) -> Self {
+        Self::from_le_bytes(bytes.into())
+    }
+
+    fn to_bytes(&self) -> GenericArray<u8, Self::ArrayLength> {
+        self.to_le_bytes().into()
+    }
+}
+
 impl LEByteConvert for u64 {
     type ArrayLength = U8;
 
@@ -124,6 +142,32 @@ impl LEByteConvert for f64 {
     }
 }
 
+// The memarg immediate is the alignment hint followed by the static offset. There is no memory
+// index in the encoding, so everything targets memory zero.
+const MEMORY_INDEX: usize = 0;
+
+fn effective_address(
+    base_address: usize,
+    offset: usize,
+    width: usize,
+    write: bool,
+    store: &impl ExpressionStore,
+) -> Result<usize> {
+    // The effective address is a 33 bit quantity, so on a 32 bit host this can overflow. The
+    // access is out of bounds either way, so it just gets reported as such.
+    base_address.checked_add(offset).ok_or_else(|| {
+        let memory_size = store.get_memory_size(MEMORY_INDEX).unwrap_or(0);
+        Trap::new(TrapKind::MemoryOutOfBounds {
+            mem_idx: MEMORY_INDEX,
+            address: base_address as u64 + offset as u64,
+            width,
+            write,
+            memory_size: memory_size * WASM_PAGE_SIZE_IN_BYTES,
+        })
+        .into()
+    })
+}
+
 pub fn mem_load<
     ValueType: Sized + Into<StackEntry>,
     IntType: Sized + LEByteConvert,
@@ -135,19 +179,29 @@ pub fn mem_load<
     store: &mut Store,
     func: FuncType,
 ) -> Result<()> {
-    let (mem_idx, offset) = instruction.get_pair_u32_as_usize_arg();
+    let (_align, offset) = instruction.get_pair_u32_as_usize_arg();
 
     let base_address = get_stack_top(stack, 1)?[0];
     let base_address = usize::try_from(u32::try_from(base_address)?).unwrap();
     stack.pop();
 
-    let final_address = base_address + offset;
-
     // A limitaton of the rust syntax here means you can't make the array the correct
     // size. Which is a bit annoying, but not very.
-    let mut bytes: GenericArray<u8, IntType::ArrayLength> =
-        unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-    store.read_data(mem_idx, final_address, &mut bytes)?;
+    let mut bytes: GenericArray<u8, IntType::ArrayLength> = Default::default();
+    let width = bytes.len();
+    effective_address(base_address, offset, width, false, store)
+        .and_then(|final_address| {
+            store.read_data_from(MEMORY_INDEX, final_address, &mut bytes, || {
+                stack.access_site(instruction.bytes())
+            })
+        })
+        .map_err(|error| {
+            locate_trap(
+                error,
+                Some(MEMORY_INDEX),
+                stack.access_site(instruction.bytes()),
+            )
+        })?;
 
     let int_value = IntType::from_bytes(bytes);
     let ret_value = func(int_value);
@@ -168,7 +222,7 @@ pub fn mem_store<
     store: &mut Store,
     func: FuncType,
 ) -> Result<()> {
-    let (mem_idx, offset) = instruction.get_pair_u32_as_usize_arg();
+    let (_align, offset) = instruction.get_pair_u32_as_usize_arg();
 
     let value = get_stack_top(stack, 1)?[0];
     let value = ValueType::try_from(value)?;
@@ -178,10 +232,20 @@ pub fn mem_store<
     let base_address = usize::try_from(u32::try_from(base_address)?).unwrap();
     stack.pop();
 
-    let final_address = base_address + offset;
-
     let bytes = func(value).to_bytes();
-    store.write_data(mem_idx, final_address, &bytes)?;
+    effective_address(base_address, offset, bytes.len(), true, store)
+        .and_then(|final_address| {
+            store.write_data_from(MEMORY_INDEX, final_address, &bytes, || {
+                stack.access_site(instruction.bytes())
+            })
+        })
+        .map_err(|error| {
+            locate_trap(
+                error,
+                Some(MEMORY_INDEX),
+                stack.access_site(instruction.bytes()),
+            )
+        })?;
 
     Ok(())
 }
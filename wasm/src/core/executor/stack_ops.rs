@@ -27,6 +27,23 @@ pub fn unary_op<
     Ok(())
 }
 
+// For operations that can trap, so the function returns a result
+pub fn unary_try_op<
+    ParamType: Sized + TryFrom<StackEntry, Error = anyhow::Error>,
+    RetType: Into<StackEntry>,
+    Func: Fn(ParamType) -> Result<RetType>,
+>(
+    stack: &mut Stack,
+    func: Func,
+) -> Result<()> {
+    let arg = get_stack_top(stack, 1)?[0];
+    let ret = func(arg.try_into()?)?;
+
+    stack.pop();
+    stack.push(ret.into());
+    Ok(())
+}
+
 pub fn unary_boolean_op<
     ParamType: Sized + TryFrom<StackEntry, Error = anyhow::Error>,
     Func: Fn(ParamType) -> bool,
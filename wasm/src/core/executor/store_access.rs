@@ -1,9 +1,13 @@
-use crate::core::{stack_entry::StackEntry, Callable, FuncType, Global, Memory, Table};
+use crate::core::{
+    stack_entry::StackEntry, AccessSite, CallObserver, Callable, Caller, FuncType, Global, Memory,
+    SnapshotLimits, Stack, Table,
+};
 use anyhow::Result;
 use std::{
     cell::{Ref, RefMut},
     marker::PhantomData,
     ops::{Deref, DerefMut},
+    rc::Rc,
 };
 
 pub trait LifetimeToRef<'a, T> {
@@ -108,6 +112,28 @@ pub trait ExpressionStore: ConstantExpressionStore {
         self.mem_idx_mut(mem_idx)?.set_data(offset, data)
     }
 
+    // The guest's loads and stores, which say where they came from in case the memory has any
+    // watchpoints
+    fn read_data_from(
+        &self,
+        mem_idx: usize,
+        offset: usize,
+        data: &mut [u8],
+        site: impl FnOnce() -> AccessSite,
+    ) -> Result<()> {
+        self.mem_idx(mem_idx)?.get_data_from(offset, data, site)
+    }
+
+    fn write_data_from(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        data: &[u8],
+        site: impl FnOnce() -> AccessSite,
+    ) -> Result<()> {
+        self.mem_idx_mut(mem_idx)?.set_data_from(offset, data, site)
+    }
+
     fn get_memory_size(&self, mem_idx: usize) -> Result<usize> {
         Ok(self.mem_idx(mem_idx)?.current_size())
     }
@@ -115,4 +141,33 @@ pub trait ExpressionStore: ConstantExpressionStore {
     fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<()> {
         self.mem_idx_mut(mem_idx)?.grow_by(grow_by)
     }
+
+    // Called after every instruction the executor runs, whether or not it worked. It does nothing
+    // unless a store overrides it, and as the executor is generic over the store, a store that
+    // doesn't pays nothing for it. An error stops the guest there, as a trap would.
+    #[inline(always)]
+    fn on_instruction(&mut self, _stack: &Stack) -> Result<()> {
+        Ok(())
+    }
+
+    // Called as each function is entered, once a wasm function's frame has been pushed, or just
+    // before a host function is called. Like on_instruction, it does nothing unless a store
+    // overrides it.
+    #[inline(always)]
+    fn on_call(&mut self, _stack: &Stack, _is_host: bool) {}
+
+    // Who to tell about calls as they start and finish, if anyone
+    fn call_observer(&self) -> Option<Rc<dyn CallObserver>> {
+        None
+    }
+
+    // What a host function it calls is given to get at it
+    fn caller(&mut self) -> Caller<'_> {
+        Caller::detached()
+    }
+
+    // How much of the stack to keep with a trap, if any of it
+    fn trap_snapshot_limits(&self) -> Option<SnapshotLimits> {
+        None
+    }
 }
@@ -1,6 +1,7 @@
 use crate::core::{
-    executor::execute_expression, stack_entry::StackEntry, BlockType, FuncType, Locals, Stack,
-    Table, ValueType, WasmExprCallable,
+    executor::{execute_expression, ExpressionStore},
+    stack_entry::StackEntry,
+    BlockType, FuncType, Locals, Stack, Table, Trap, TrapKind, ValueType, WasmExprCallable,
 };
 use crate::parser::{InstructionSource, Opcode};
 
@@ -114,8 +115,10 @@ fn test_loop_block() {
     let mut stack = Stack::new();
     let mut store = TestStore::new();
 
-    // We push a frame onto the stack with the one local we use
-    assert!(stack.push_test_frame(1).is_ok());
+    // We push a frame onto the stack with the one i64 local we use
+    let func_type = FuncType::new(vec![], vec![]);
+    let locals = vec![Locals::new(1, ValueType::I64)];
+    assert!(stack.push_typed_frame(&func_type, &locals).is_ok());
 
     assert!(execute_expression(&expr, &mut stack, &mut store).is_ok());
     assert_eq!(stack.working_count(), 1);
@@ -273,3 +276,294 @@ fn test_indirect_call() {
         }
     }
 }
+
+#[test]
+fn test_local_tee_chained_assignment() {
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+
+    // int f() { return 7; }
+    let mut func_writer = make_expression_writer();
+    func_writer.write_const_instruction(7_i32);
+    assert_eq!(
+        store.add_function(
+            func_writer,
+            FuncType::new(vec![], vec![ValueType::I32]),
+            vec![]
+        ),
+        0
+    );
+
+    // int x, y; x = y = f(); return x * 10 + y;
+    // This is what clang emits for the chained assignment, once the locals are in registers
+    let mut expr = make_expression_writer();
+    expr.write_single_leb_instruction(Opcode::Call, 0);
+    expr.write_single_leb_instruction(Opcode::LocalTee, 1);
+    expr.write_single_leb_instruction(Opcode::LocalSet, 0);
+    expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    expr.write_const_instruction(10_i32);
+    expr.write_single_byte_instruction(Opcode::I32Mul);
+    expr.write_single_leb_instruction(Opcode::LocalGet, 1);
+    expr.write_single_byte_instruction(Opcode::I32Add);
+
+    assert!(stack.push_test_frame(2).is_ok());
+    assert!(execute_expression(&expr, &mut stack, &mut store).is_ok());
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 77_i32.into());
+    assert_eq!(stack.local(), &[7_i32.into(), 7_i32.into()]);
+}
+
+#[test]
+fn test_local_tee_block_result() {
+    // The teed value is the result of the block, so it has to still be on the stack when the
+    // block ends
+    let expr = make_expression_writer();
+    let mut block_expr = expr.write_block_instruction(Opcode::Block, BlockType::I32);
+    block_expr.write_const_instruction(5_i32);
+    block_expr.write_single_leb_instruction(Opcode::LocalTee, 0);
+    block_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    block_expr.write_single_leb_instruction(Opcode::BrIf, 0);
+    let mut expr = block_expr.do_end();
+    expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    expr.write_single_byte_instruction(Opcode::I32Add);
+
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+    assert!(stack.push_test_frame(1).is_ok());
+    assert!(execute_expression(&expr, &mut stack, &mut store).is_ok());
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 10_i32.into());
+
+    // A tee that doesn't match the type of the local fails, and doesn't touch the local
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(5_i64);
+    expr.write_single_leb_instruction(Opcode::LocalTee, 0);
+
+    let mut stack = Stack::new();
+    assert!(stack.push_test_frame(1).is_ok());
+    assert!(execute_expression(&expr, &mut stack, &mut store).is_err());
+    assert_eq!(stack.local(), &[0_i32.into()]);
+}
+
+// Writes a function body that leaves junk on the stack at every level of nesting, and then
+// leaves the function from three blocks deep, with the given instruction, returning local 0
+fn write_deep_exit_function(exit_opcode: Opcode) -> ExpressionWriter {
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(1_i64);
+
+    let mut block_expr = expr.write_block_instruction(Opcode::Block, BlockType::None);
+    block_expr.write_const_instruction(2.0_f32);
+
+    let mut loop_expr = block_expr.write_block_instruction(Opcode::Loop, BlockType::None);
+    loop_expr.write_const_instruction(3_i32);
+    loop_expr.write_const_instruction(1_i32);
+
+    // The if arm promises an i64, which the return never provides
+    let mut if_expr = loop_expr.write_block_instruction(Opcode::If, BlockType::I64);
+    if_expr.write_const_instruction(4.0_f64);
+    if_expr.write_const_instruction(5_i32);
+    if_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    if exit_opcode == Opcode::Return {
+        if_expr.write_single_byte_instruction(Opcode::Return);
+    } else {
+        if_expr.write_single_leb_instruction(exit_opcode, 3);
+    }
+    let mut else_expr = if_expr.do_else();
+    else_expr.write_const_instruction(6_i64);
+
+    let mut loop_expr = else_expr.do_end();
+    loop_expr.write_single_byte_instruction(Opcode::Drop);
+    loop_expr.write_single_byte_instruction(Opcode::Drop);
+
+    let mut block_expr = loop_expr.do_end();
+    block_expr.write_single_byte_instruction(Opcode::Drop);
+
+    let mut expr = block_expr.do_end();
+    expr.write_single_byte_instruction(Opcode::Drop);
+    expr.write_const_instruction(-1_i32);
+    expr
+}
+
+#[test]
+fn test_deep_function_exit() {
+    for exit_opcode in &[Opcode::Return, Opcode::Br] {
+        let mut stack = Stack::new();
+        let mut store = TestStore::new();
+
+        assert_eq!(
+            store.add_function(
+                write_deep_exit_function(*exit_opcode),
+                FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+                vec![]
+            ),
+            0
+        );
+
+        // Call the function twice with a value already live on the caller's stack. Each call
+        // has to leave exactly its one result, without disturbing the value underneath.
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction(99_i64);
+        expr.write_const_instruction(10_i32);
+        expr.write_single_leb_instruction(Opcode::Call, 0);
+        expr.write_single_leb_instruction(Opcode::Call, 0);
+        expr.write_const_instruction(20_i32);
+        expr.write_single_leb_instruction(Opcode::Call, 0);
+        expr.write_single_byte_instruction(Opcode::I32Add);
+
+        assert!(stack.push_test_frame(0).is_ok());
+        assert!(execute_expression(&expr, &mut stack, &mut store).is_ok());
+        assert_eq!(stack.working_count(), 2);
+        assert_eq!(stack.working_top(2), &[99_i64.into(), 30_i32.into()]);
+    }
+}
+
+#[test]
+fn test_return_through_nested_calls() {
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+
+    // The first function returns out of its blocks, then the second calls it from inside its own
+    // blocks and keeps going afterwards, so any leftover labels or operands would be visible
+    assert_eq!(
+        store.add_function(
+            write_deep_exit_function(Opcode::Return),
+            FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+            vec![]
+        ),
+        0
+    );
+
+    let expr = make_expression_writer();
+    let mut block_expr = expr.write_block_instruction(Opcode::Block, BlockType::I32);
+    block_expr.write_const_instruction(7_i64);
+    block_expr.write_single_byte_instruction(Opcode::Drop);
+    block_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    block_expr.write_single_leb_instruction(Opcode::Call, 0);
+    let mut expr = block_expr.do_end();
+    expr.write_const_instruction(2_i32);
+    expr.write_single_byte_instruction(Opcode::I32Mul);
+
+    assert_eq!(
+        store.add_function(
+            expr,
+            FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+            vec![]
+        ),
+        1
+    );
+
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(21_i32);
+    expr.write_single_leb_instruction(Opcode::Call, 1);
+
+    assert!(stack.push_test_frame(0).is_ok());
+    assert!(execute_expression(&expr, &mut stack, &mut store).is_ok());
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 42_i32.into());
+}
+
+#[test]
+fn test_nop() {
+    let mut expr = make_expression_writer();
+    expr.write_single_byte_instruction(Opcode::Nop);
+    expr.write_const_instruction(1_i32);
+    expr.write_single_byte_instruction(Opcode::Nop);
+    let mut block_expr = expr.write_block_instruction(Opcode::Block, BlockType::I32);
+    block_expr.write_single_byte_instruction(Opcode::Nop);
+    block_expr.write_const_instruction(2_i32);
+    block_expr.write_single_byte_instruction(Opcode::Nop);
+    let mut expr = block_expr.do_end();
+    expr.write_single_byte_instruction(Opcode::Nop);
+    expr.write_single_byte_instruction(Opcode::I32Add);
+    expr.write_single_byte_instruction(Opcode::Nop);
+
+    test_single_return_expression!(expr, 3_i32);
+}
+
+// Adds a function that stores its argument to memory, pushes some junk, and then calls the
+// given function from inside a block
+fn add_trap_chain_function(store: &mut TestStore, address: u32, callee: u64) -> usize {
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(address);
+    expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    expr.write_two_leb_instruction(Opcode::I32Store, 2, 0);
+    expr.write_const_instruction(3.0_f64);
+
+    let mut block_expr = expr.write_block_instruction(Opcode::Block, BlockType::I32);
+    block_expr.write_const_instruction(4_i64);
+    block_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    block_expr.write_single_leb_instruction(Opcode::Call, callee);
+    let mut expr = block_expr.do_end();
+    expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    expr.write_single_byte_instruction(Opcode::I32Add);
+
+    store.add_function(
+        expr,
+        FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+        vec![],
+    )
+}
+
+#[test]
+fn test_unreachable_trap_propagation() {
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+    store.enable_memory();
+
+    // The innermost function traps if its argument is zero, and otherwise returns it
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(5_i64);
+    let mut loop_expr = expr.write_block_instruction(Opcode::Loop, BlockType::None);
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_single_byte_instruction(Opcode::I32Eqz);
+    let mut if_expr = loop_expr.write_block_instruction(Opcode::If, BlockType::None);
+    if_expr.write_single_byte_instruction(Opcode::Unreachable);
+    let loop_expr = if_expr.do_end();
+    let mut expr = loop_expr.do_end();
+    expr.write_single_byte_instruction(Opcode::Drop);
+    expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+
+    let leaf = store.add_function(
+        expr,
+        FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+        vec![],
+    );
+    let middle = add_trap_chain_function(&mut store, 8, leaf as u64);
+    let top = add_trap_chain_function(&mut store, 16, middle as u64);
+
+    let call_top = |argument: i32| {
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction(argument);
+        expr.write_single_leb_instruction(Opcode::Call, top as u64);
+        expr
+    };
+
+    store.write_data(0, 8, &[0xff; 4]).unwrap();
+    store.write_data(0, 16, &[0xff; 4]).unwrap();
+
+    assert!(stack.push_test_frame(0).is_ok());
+    stack.push(99_i64.into());
+
+    // The trap comes all the way out, unchanged, and all three frames are gone
+    let error = execute_expression(&call_top(0), &mut stack, &mut store).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(|t| t.kind()),
+        Some(TrapKind::Unreachable)
+    );
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 99_i64.into());
+
+    // Memory writes made before the trap stay put
+    let mut check_bytes: [u8; 4] = [0xff; 4];
+    store.read_data(0, 8, &mut check_bytes).unwrap();
+    assert_eq!(check_bytes, [0; 4]);
+    store.read_data(0, 16, &mut check_bytes).unwrap();
+    assert_eq!(check_bytes, [0; 4]);
+
+    // And the next call works as normal, seeing nothing left over from the last one
+    assert!(execute_expression(&call_top(7), &mut stack, &mut store).is_ok());
+    assert_eq!(stack.working_count(), 2);
+    assert_eq!(stack.working_top(2), &[99_i64.into(), 21_i32.into()]);
+    store.read_data(0, 8, &mut check_bytes).unwrap();
+    assert_eq!(check_bytes, [7, 0, 0, 0]);
+}
@@ -1,6 +1,4 @@
-use std::convert::TryInto;
-
-use crate::core::{stack_entry::StackEntry, ExpressionStore, Stack};
+use crate::core::{stack_entry::StackEntry, ExpressionStore, Stack, Trap, TrapKind};
 use crate::parser::{InstructionSource, Opcode};
 
 use super::instruction_generator::make_expression_writer;
@@ -105,6 +103,29 @@ macro_rules! test_unary_opcode {
     };
 }
 
+// Runs a unary op that is expected to fail, returning the kind of trap if it was one
+pub fn test_unary_opcode_trap_impl(p1: impl Into<StackEntry>, opcode: Opcode) -> Option<TrapKind> {
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(p1.into());
+    expr.write_single_byte_instruction(opcode);
+
+    let mut stack = Stack::new();
+    let mut test_store = TestStore::new();
+    assert!(stack.push_test_frame(0).is_ok());
+
+    match execute_expression(&expr, &mut stack, &mut test_store) {
+        Err(e) => e.downcast_ref::<Trap>().map(|t| t.kind()),
+        Ok(_) => None,
+    }
+}
+
+#[macro_export]
+macro_rules! test_unary_opcode_trap {
+    ($p1:expr, $opcode:expr, $kind:expr) => {
+        assert_eq!(test_unary_opcode_trap_impl($p1, $opcode), Some($kind));
+    };
+}
+
 pub fn test_binary_opcode_impl(
     p1: impl Into<StackEntry>,
     p2: impl Into<StackEntry>,
@@ -129,24 +150,24 @@ macro_rules! test_binary_opcode {
 fn memory_load_expression(
     opcode: Opcode,
     address: u32,
-    mem_idx: usize,
+    align: u32,
     offset: u32,
 ) -> impl InstructionSource {
     let mut expr = make_expression_writer();
     expr.write_const_instruction(address);
-    expr.write_two_leb_instruction(opcode, mem_idx.try_into().unwrap(), offset.into());
+    expr.write_two_leb_instruction(opcode, align.into(), offset.into());
     expr
 }
 
 pub fn test_memory_load_impl(
     opcode: Opcode,
     address: u32,
-    mem_idx: usize,
+    align: u32,
     offset: u32,
     stack: &mut Stack,
     store: &mut impl ExpressionStore,
 ) -> Option<StackEntry> {
-    let expr = memory_load_expression(opcode, address, mem_idx, offset);
+    let expr = memory_load_expression(opcode, address, align, offset);
     if let Err(_) = execute_expression(&expr, stack, store) {
         None
     } else {
@@ -162,9 +183,9 @@ pub fn test_memory_load_impl(
 
 #[macro_export]
 macro_rules! test_memory_load {
-    ($opcode:expr, $address:expr, $mem_idx:expr, $offset:expr, $stack:expr, $store:expr, $r:expr) => {
+    ($opcode:expr, $address:expr, $align:expr, $offset:expr, $stack:expr, $store:expr, $r:expr) => {
         assert_eq!(
-            test_memory_load_impl($opcode, $address, $mem_idx, $offset, $stack, $store),
+            test_memory_load_impl($opcode, $address, $align, $offset, $stack, $store),
             Some($r.into())
         );
     };
@@ -173,27 +194,27 @@ macro_rules! test_memory_load {
 fn memory_store_expression(
     opcode: Opcode,
     address: u32,
-    mem_idx: usize,
+    align: u32,
     offset: u32,
     value: impl Into<StackEntry>,
 ) -> impl InstructionSource {
     let mut expr = make_expression_writer();
     expr.write_const_instruction(address);
     expr.write_const_instruction(value);
-    expr.write_two_leb_instruction(opcode, mem_idx.try_into().unwrap(), offset.into());
+    expr.write_two_leb_instruction(opcode, align.into(), offset.into());
     expr
 }
 
 pub fn test_memory_store_impl(
     opcode: Opcode,
     address: u32,
-    mem_idx: usize,
+    align: u32,
     offset: u32,
     value: impl Into<StackEntry>,
     stack: &mut Stack,
     store: &mut impl ExpressionStore,
 ) -> Option<()> {
-    let expr = memory_store_expression(opcode, address, mem_idx, offset, value);
+    let expr = memory_store_expression(opcode, address, align, offset, value);
     if let Err(_) = execute_expression(&expr, stack, store) {
         None
     } else {
@@ -207,9 +228,9 @@ pub fn test_memory_store_impl(
 
 #[macro_export]
 macro_rules! test_memory_store {
-    ($opcode:expr, $address:expr, $mem_idx:expr, $offset:expr, $value:expr, $stack:expr, $store:expr) => {
+    ($opcode:expr, $address:expr, $align:expr, $offset:expr, $value:expr, $stack:expr, $store:expr) => {
         assert_eq!(
-            test_memory_store_impl($opcode, $address, $mem_idx, $offset, $value, $stack, $store),
+            test_memory_store_impl($opcode, $address, $align, $offset, $value, $stack, $store),
             Some(())
         );
     };
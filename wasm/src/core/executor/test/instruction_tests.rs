@@ -1,9 +1,10 @@
 use crate::core::{
     executor::{execute_expression, ExpressionStore},
     stack_entry::StackEntry,
-    Stack,
+    BlockType, FuncType, Locals, Memory, Stack, Trap, TrapKind, ValueType,
 };
 use crate::parser::Opcode;
+use std::convert::TryFrom;
 
 use super::instruction_generator::make_expression_writer;
 use super::instruction_test_helpers::*;
@@ -82,11 +83,13 @@ fn test_basic_ops() {
     test_constant_opcode!(-1i32);
     test_constant_opcode!(-256i32);
     test_constant_opcode!(-65536i32);
+    test_constant_opcode!(-2147483648i32);
     test_constant_opcode!(0u32);
     test_constant_opcode!(1u32);
     test_constant_opcode!(2u32);
     test_constant_opcode!(256u32);
     test_constant_opcode!(0i64);
+    test_constant_opcode!(-9223372036854775808i64);
     test_constant_opcode!(0u64);
     test_constant_opcode!(0.0f32);
     test_constant_opcode!(0.0f64);
@@ -308,6 +311,553 @@ fn test_basic_ops() {
     test_unary_opcode!(-1.0f64, Opcode::I64ReinterpretF64, 0xbff0000000000000u64);
     test_unary_opcode!(0xbf800000u32, Opcode::F32ReinterpretI32, -1.0f32);
     test_unary_opcode!(0xbff0000000000000u64, Opcode::F64ReinterpretI64, -1.0f64);
+
+    test_unary_opcode!(0x1234_5680i32, Opcode::I32Extend8S, -128i32);
+    test_unary_opcode!(0x1234_567Fi32, Opcode::I32Extend8S, 0x7Fi32);
+    test_unary_opcode!(0x1234_8000i32, Opcode::I32Extend16S, -32768i32);
+    test_unary_opcode!(0x1234_5680i64, Opcode::I64Extend8S, -128i64);
+    test_unary_opcode!(0x1234_8000i64, Opcode::I64Extend16S, -32768i64);
+    test_unary_opcode!(0x1234_8000_0000i64, Opcode::I64Extend32S, -2147483648i64);
+    test_unary_opcode!(0x1234_7FFF_FFFFi64, Opcode::I64Extend32S, 0x7FFF_FFFFi64);
+}
+
+#[test]
+fn test_integer_comparisons_at_sign_boundary() {
+    // Each pair is on opposite sides of the sign boundary, or equal, so the signed and unsigned
+    // comparisons have to disagree for some of them
+    let i32_pairs: &[(u32, u32)] = &[
+        (0x8000_0000, 1),
+        (1, 0x8000_0000),
+        (0xffff_ffff, 1),
+        (0x7fff_ffff, 0x8000_0000),
+        (0x8000_0000, 0x8000_0000),
+        (0, 0xffff_ffff),
+    ];
+
+    for (a, b) in i32_pairs {
+        let (sa, sb) = (*a as i32, *b as i32);
+        for (opcode, expected) in &[
+            (Opcode::I32Eq, a == b),
+            (Opcode::I32Ne, a != b),
+            (Opcode::I32LtS, sa < sb),
+            (Opcode::I32LtU, a < b),
+            (Opcode::I32GtS, sa > sb),
+            (Opcode::I32GtU, a > b),
+            (Opcode::I32LeS, sa <= sb),
+            (Opcode::I32LeU, a <= b),
+            (Opcode::I32GeS, sa >= sb),
+            (Opcode::I32GeU, a >= b),
+        ] {
+            test_binary_opcode!(*a, *b, *opcode, u32::from(*expected));
+        }
+        test_unary_opcode!(*a, Opcode::I32Eqz, u32::from(*a == 0));
+    }
+
+    let i64_pairs: &[(u64, u64)] = &[
+        (0x8000_0000_0000_0000, 1),
+        (1, 0x8000_0000_0000_0000),
+        (0xffff_ffff_ffff_ffff, 1),
+        (0x7fff_ffff_ffff_ffff, 0x8000_0000_0000_0000),
+        (0x8000_0000_0000_0000, 0x8000_0000_0000_0000),
+        (0, 0xffff_ffff_ffff_ffff),
+        (0x8000_0000, 1),
+    ];
+
+    for (a, b) in i64_pairs {
+        let (sa, sb) = (*a as i64, *b as i64);
+        for (opcode, expected) in &[
+            (Opcode::I64Eq, a == b),
+            (Opcode::I64Ne, a != b),
+            (Opcode::I64LtS, sa < sb),
+            (Opcode::I64LtU, a < b),
+            (Opcode::I64GtS, sa > sb),
+            (Opcode::I64GtU, a > b),
+            (Opcode::I64LeS, sa <= sb),
+            (Opcode::I64LeU, a <= b),
+            (Opcode::I64GeS, sa >= sb),
+            (Opcode::I64GeU, a >= b),
+        ] {
+            // The result of a 64 bit comparison is still an i32
+            test_binary_opcode!(*a, *b, *opcode, u32::from(*expected));
+        }
+        test_unary_opcode!(*a, Opcode::I64Eqz, u32::from(*a == 0));
+    }
+
+    // Spot check the case from the bug report without relying on the host comparisons
+    test_binary_opcode!(0xffff_ffff_ffff_ffff_u64, 1_u64, Opcode::I64LtU, 0_u32);
+    test_binary_opcode!(0xffff_ffff_ffff_ffff_u64, 1_u64, Opcode::I64LtS, 1_u32);
+    test_binary_opcode!(0xffff_ffff_ffff_ffff_u64, 1_u64, Opcode::I64GtU, 1_u32);
+    test_binary_opcode!(0xffff_ffff_ffff_ffff_u64, 1_u64, Opcode::I64GtS, 0_u32);
+}
+
+#[test]
+fn test_shift_count_masking() {
+    // Counts are taken modulo the bit width, so a count of exactly the width is a no-op
+    for count in &[0_u32, 32, 64, 0x8000_0000] {
+        test_binary_opcode!(0x8000_0001_u32, *count, Opcode::I32Shl, 0x8000_0001_u32);
+        test_binary_opcode!(0x8000_0001_u32, *count, Opcode::I32ShrS, 0x8000_0001_u32);
+        test_binary_opcode!(0x8000_0001_u32, *count, Opcode::I32ShrU, 0x8000_0001_u32);
+        test_binary_opcode!(0x8000_0001_u32, *count, Opcode::I32Rotl, 0x8000_0001_u32);
+        test_binary_opcode!(0x8000_0001_u32, *count, Opcode::I32Rotr, 0x8000_0001_u32);
+    }
+
+    test_binary_opcode!(1_u32, 37_u32, Opcode::I32Shl, 0x20_u32);
+    test_binary_opcode!(0x8000_0000_u32, 33_u32, Opcode::I32ShrS, 0xc000_0000_u32);
+    test_binary_opcode!(0x8000_0000_u32, 33_u32, Opcode::I32ShrU, 0x4000_0000_u32);
+    test_binary_opcode!(0x8000_0000_u32, 33_u32, Opcode::I32Rotl, 1_u32);
+    test_binary_opcode!(1_u32, 33_u32, Opcode::I32Rotr, 0x8000_0000_u32);
+    test_binary_opcode!(1_u32, -1_i32, Opcode::I32Shl, 0x8000_0000_u32);
+    test_binary_opcode!(0x8000_0000_u32, -1_i32, Opcode::I32ShrS, 0xffff_ffff_u32);
+    test_binary_opcode!(0x8000_0000_u32, -1_i32, Opcode::I32ShrU, 1_u32);
+    test_binary_opcode!(0x8000_0000_u32, -31_i32, Opcode::I32Rotl, 1_u32);
+
+    const I64_VALUE: u64 = 0x8000_0000_0000_0001;
+    for count in &[0_u64, 64, 128, 0x8000_0000_0000_0000] {
+        test_binary_opcode!(I64_VALUE, *count, Opcode::I64Shl, I64_VALUE);
+        test_binary_opcode!(I64_VALUE, *count, Opcode::I64ShrS, I64_VALUE);
+        test_binary_opcode!(I64_VALUE, *count, Opcode::I64ShrU, I64_VALUE);
+        test_binary_opcode!(I64_VALUE, *count, Opcode::I64Rotl, I64_VALUE);
+        test_binary_opcode!(I64_VALUE, *count, Opcode::I64Rotr, I64_VALUE);
+    }
+
+    // Counts between 32 and 63 are meaningful for the 64 bit shifts
+    test_binary_opcode!(1_u64, 32_u64, Opcode::I64Shl, 0x1_0000_0000_u64);
+    test_binary_opcode!(1_u64, 63_u64, Opcode::I64Shl, 0x8000_0000_0000_0000_u64);
+    test_binary_opcode!(1_u64, 69_u64, Opcode::I64Shl, 0x20_u64);
+    test_binary_opcode!(
+        I64_VALUE,
+        36_u64,
+        Opcode::I64ShrS,
+        0xffff_ffff_f800_0000_u64
+    );
+    test_binary_opcode!(I64_VALUE, 36_u64, Opcode::I64ShrU, 0x0800_0000_u64);
+    test_binary_opcode!(I64_VALUE, 100_u64, Opcode::I64ShrU, 0x0800_0000_u64);
+    test_binary_opcode!(I64_VALUE, 36_u64, Opcode::I64Rotl, 0x18_0000_0000_u64);
+    test_binary_opcode!(I64_VALUE, 36_u64, Opcode::I64Rotr, 0x1800_0000_u64);
+    test_binary_opcode!(1_u64, -1_i64, Opcode::I64Shl, 0x8000_0000_0000_0000_u64);
+    test_binary_opcode!(
+        I64_VALUE,
+        -1_i64,
+        Opcode::I64ShrS,
+        0xffff_ffff_ffff_ffff_u64
+    );
+    test_binary_opcode!(I64_VALUE, -1_i64, Opcode::I64ShrU, 1_u64);
+}
+
+#[test]
+fn test_trapping_truncation() {
+    // Cases taken from the spec test suite's conversions.wast
+    test_unary_opcode!(-0.0_f32, Opcode::I32TruncF32S, 0_i32);
+    test_unary_opcode!(f32::from_bits(1), Opcode::I32TruncF32S, 0_i32);
+    test_unary_opcode!(-1.9_f32, Opcode::I32TruncF32S, -1_i32);
+    test_unary_opcode!(2147483520.0_f32, Opcode::I32TruncF32S, 2147483520_i32);
+    test_unary_opcode!(-2147483648.0_f32, Opcode::I32TruncF32S, -2147483648_i32);
+    test_unary_opcode_trap!(
+        2147483648.0_f32,
+        Opcode::I32TruncF32S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        -2147483904.0_f32,
+        Opcode::I32TruncF32S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        std::f32::INFINITY,
+        Opcode::I32TruncF32S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        std::f32::NEG_INFINITY,
+        Opcode::I32TruncF32S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        std::f32::NAN,
+        Opcode::I32TruncF32S,
+        TrapKind::InvalidConversionToInteger
+    );
+    test_unary_opcode_trap!(
+        f32::from_bits(0xffa0_0000),
+        Opcode::I32TruncF32S,
+        TrapKind::InvalidConversionToInteger
+    );
+
+    test_unary_opcode!(-0.9_f32, Opcode::I32TruncF32U, 0_u32);
+    test_unary_opcode!(1.9_f32, Opcode::I32TruncF32U, 1_u32);
+    test_unary_opcode!(2147483648.0_f32, Opcode::I32TruncF32U, 0x8000_0000_u32);
+    test_unary_opcode!(4294967040.0_f32, Opcode::I32TruncF32U, 4294967040_u32);
+    test_unary_opcode_trap!(
+        4294967296.0_f32,
+        Opcode::I32TruncF32U,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(-1.0_f32, Opcode::I32TruncF32U, TrapKind::IntegerOverflow);
+    test_unary_opcode_trap!(
+        std::f32::NAN,
+        Opcode::I32TruncF32U,
+        TrapKind::InvalidConversionToInteger
+    );
+
+    test_unary_opcode!(2147483647.9_f64, Opcode::I32TruncF64S, 2147483647_i32);
+    test_unary_opcode!(-2147483648.9_f64, Opcode::I32TruncF64S, -2147483648_i32);
+    test_unary_opcode!(-1.5_f64, Opcode::I32TruncF64S, -1_i32);
+    test_unary_opcode_trap!(
+        2147483648.0_f64,
+        Opcode::I32TruncF64S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        -2147483649.0_f64,
+        Opcode::I32TruncF64S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        std::f64::NAN,
+        Opcode::I32TruncF64S,
+        TrapKind::InvalidConversionToInteger
+    );
+
+    test_unary_opcode!(-0.9_f64, Opcode::I32TruncF64U, 0_u32);
+    test_unary_opcode!(4294967295.9_f64, Opcode::I32TruncF64U, 4294967295_u32);
+    test_unary_opcode!(1e8_f64, Opcode::I32TruncF64U, 100000000_u32);
+    test_unary_opcode_trap!(
+        4294967296.0_f64,
+        Opcode::I32TruncF64U,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(-1.0_f64, Opcode::I32TruncF64U, TrapKind::IntegerOverflow);
+    test_unary_opcode_trap!(1e16_f64, Opcode::I32TruncF64U, TrapKind::IntegerOverflow);
+    test_unary_opcode_trap!(
+        std::f64::INFINITY,
+        Opcode::I32TruncF64U,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        std::f64::NAN,
+        Opcode::I32TruncF64U,
+        TrapKind::InvalidConversionToInteger
+    );
+
+    // The i64 limits aren't representable in f32, so the last valid values are well inside them
+    test_unary_opcode!(-1.9_f32, Opcode::I64TruncF32S, -1_i64);
+    test_unary_opcode!(4294967296.0_f32, Opcode::I64TruncF32S, 4294967296_i64);
+    test_unary_opcode!(
+        9223371487098961920.0_f32,
+        Opcode::I64TruncF32S,
+        9223371487098961920_i64
+    );
+    test_unary_opcode!(
+        -9223372036854775808.0_f32,
+        Opcode::I64TruncF32S,
+        -9223372036854775808_i64
+    );
+    test_unary_opcode_trap!(
+        9223372036854775808.0_f32,
+        Opcode::I64TruncF32S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        -9223373136366403584.0_f32,
+        Opcode::I64TruncF32S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        std::f32::NAN,
+        Opcode::I64TruncF32S,
+        TrapKind::InvalidConversionToInteger
+    );
+
+    test_unary_opcode!(-0.9_f32, Opcode::I64TruncF32U, 0_u64);
+    test_unary_opcode!(
+        18446742974197923840.0_f32,
+        Opcode::I64TruncF32U,
+        18446742974197923840_u64
+    );
+    test_unary_opcode_trap!(
+        18446744073709551616.0_f32,
+        Opcode::I64TruncF32U,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(-1.0_f32, Opcode::I64TruncF32U, TrapKind::IntegerOverflow);
+    test_unary_opcode_trap!(
+        std::f32::NAN,
+        Opcode::I64TruncF32U,
+        TrapKind::InvalidConversionToInteger
+    );
+
+    test_unary_opcode!(
+        9223372036854774784.0_f64,
+        Opcode::I64TruncF64S,
+        9223372036854774784_i64
+    );
+    test_unary_opcode!(
+        -9223372036854775808.0_f64,
+        Opcode::I64TruncF64S,
+        -9223372036854775808_i64
+    );
+    test_unary_opcode_trap!(
+        9223372036854775808.0_f64,
+        Opcode::I64TruncF64S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        -9223372036854777856.0_f64,
+        Opcode::I64TruncF64S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        std::f64::NEG_INFINITY,
+        Opcode::I64TruncF64S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(
+        std::f64::NAN,
+        Opcode::I64TruncF64S,
+        TrapKind::InvalidConversionToInteger
+    );
+
+    test_unary_opcode!(-0.9_f64, Opcode::I64TruncF64U, 0_u64);
+    test_unary_opcode!(
+        18446744073709549568.0_f64,
+        Opcode::I64TruncF64U,
+        18446744073709549568_u64
+    );
+    test_unary_opcode!(
+        9223372036854775808.0_f64,
+        Opcode::I64TruncF64U,
+        0x8000_0000_0000_0000_u64
+    );
+    test_unary_opcode_trap!(
+        18446744073709551616.0_f64,
+        Opcode::I64TruncF64U,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_trap!(-1.0_f64, Opcode::I64TruncF64U, TrapKind::IntegerOverflow);
+    test_unary_opcode_trap!(
+        std::f64::NAN,
+        Opcode::I64TruncF64U,
+        TrapKind::InvalidConversionToInteger
+    );
+}
+
+fn reinterpret_bits(p1: impl Into<StackEntry>, opcode: Opcode) -> Option<StackEntry> {
+    test_unary_opcode_impl(p1, opcode)
+}
+
+#[test]
+fn test_reinterpret_ops() {
+    // A signalling NaN with a non-trivial payload. These have to survive the trip in both
+    // directions bit for bit, which is why the comparisons are done on the raw bits.
+    const F32_SNAN_BITS: u32 = 0x7fa0_0001;
+    const F64_SNAN_BITS: u64 = 0x7ff4_0000_0000_0001;
+
+    let as_float = reinterpret_bits(F32_SNAN_BITS, Opcode::F32ReinterpretI32).unwrap();
+    let as_float = f32::try_from(as_float).unwrap();
+    assert!(as_float.is_nan());
+    assert_eq!(as_float.to_bits(), F32_SNAN_BITS);
+    assert_eq!(
+        reinterpret_bits(as_float, Opcode::I32ReinterpretF32),
+        Some(F32_SNAN_BITS.into())
+    );
+
+    let as_double = reinterpret_bits(F64_SNAN_BITS, Opcode::F64ReinterpretI64).unwrap();
+    let as_double = f64::try_from(as_double).unwrap();
+    assert!(as_double.is_nan());
+    assert_eq!(as_double.to_bits(), F64_SNAN_BITS);
+    assert_eq!(
+        reinterpret_bits(as_double, Opcode::I64ReinterpretF64),
+        Some(F64_SNAN_BITS.into())
+    );
+
+    // Negative zero compares equal to zero, so again we have to look at the bits
+    assert_eq!(
+        reinterpret_bits(-0.0f32, Opcode::I32ReinterpretF32),
+        Some(0x8000_0000u32.into())
+    );
+    let neg_zero = reinterpret_bits(0x8000_0000u32, Opcode::F32ReinterpretI32).unwrap();
+    assert_eq!(f32::try_from(neg_zero).unwrap().to_bits(), 0x8000_0000);
+
+    assert_eq!(
+        reinterpret_bits(-0.0f64, Opcode::I64ReinterpretF64),
+        Some(0x8000_0000_0000_0000u64.into())
+    );
+    let neg_zero = reinterpret_bits(0x8000_0000_0000_0000u64, Opcode::F64ReinterpretI64).unwrap();
+    assert_eq!(
+        f64::try_from(neg_zero).unwrap().to_bits(),
+        0x8000_0000_0000_0000
+    );
+}
+
+fn f32_op_bits(args: &[u32], opcode: Opcode) -> u32 {
+    let result = match args {
+        [a] => test_unary_opcode_impl(f32::from_bits(*a), opcode),
+        [a, b] => test_binary_opcode_impl(f32::from_bits(*a), f32::from_bits(*b), opcode),
+        _ => panic!("Unsupported argument count"),
+    };
+    f32::try_from(result.unwrap()).unwrap().to_bits()
+}
+
+fn f64_op_bits(args: &[u64], opcode: Opcode) -> u64 {
+    let result = match args {
+        [a] => test_unary_opcode_impl(f64::from_bits(*a), opcode),
+        [a, b] => test_binary_opcode_impl(f64::from_bits(*a), f64::from_bits(*b), opcode),
+        _ => panic!("Unsupported argument count"),
+    };
+    f64::try_from(result.unwrap()).unwrap().to_bits()
+}
+
+#[test]
+fn test_float_min_max() {
+    const F32_NAN: u32 = 0x7fc0_0000;
+    const F32_ZERO: u32 = 0x0000_0000;
+    const F32_NEG_ZERO: u32 = 0x8000_0000;
+    const F32_ONE: u32 = 0x3f80_0000;
+    const F64_NAN: u64 = 0x7ff8_0000_0000_0000;
+    const F64_ZERO: u64 = 0;
+    const F64_NEG_ZERO: u64 = 0x8000_0000_0000_0000;
+    const F64_ONE: u64 = 0x3ff0_0000_0000_0000;
+
+    // A NaN operand makes the result NaN, whichever side it's on, where Rust's min and max would
+    // return the other operand
+    for &opcode in &[Opcode::F32Min, Opcode::F32Max] {
+        assert!(f32::from_bits(f32_op_bits(&[F32_NAN, F32_ONE], opcode)).is_nan());
+        assert!(f32::from_bits(f32_op_bits(&[F32_ONE, F32_NAN], opcode)).is_nan());
+    }
+    for &opcode in &[Opcode::F64Min, Opcode::F64Max] {
+        assert!(f64::from_bits(f64_op_bits(&[F64_NAN, F64_ONE], opcode)).is_nan());
+        assert!(f64::from_bits(f64_op_bits(&[F64_ONE, F64_NAN], opcode)).is_nan());
+    }
+
+    // And -0 is below +0, in either order
+    for &(a, b) in &[(F32_ZERO, F32_NEG_ZERO), (F32_NEG_ZERO, F32_ZERO)] {
+        assert_eq!(f32_op_bits(&[a, b], Opcode::F32Min), F32_NEG_ZERO);
+        assert_eq!(f32_op_bits(&[a, b], Opcode::F32Max), F32_ZERO);
+    }
+    for &(a, b) in &[(F64_ZERO, F64_NEG_ZERO), (F64_NEG_ZERO, F64_ZERO)] {
+        assert_eq!(f64_op_bits(&[a, b], Opcode::F64Min), F64_NEG_ZERO);
+        assert_eq!(f64_op_bits(&[a, b], Opcode::F64Max), F64_ZERO);
+    }
+}
+
+#[test]
+fn test_float_sign_ops() {
+    const F32_NAN: u32 = 0x7fc0_1234;
+    const F32_NEG_NAN: u32 = 0xffc0_1234;
+    const F32_NEG_ZERO: u32 = 0x8000_0000;
+    const F32_INF: u32 = 0x7f80_0000;
+    const F32_NEG_INF: u32 = 0xff80_0000;
+    const F32_ONE: u32 = 0x3f80_0000;
+    const F32_NEG_ONE: u32 = 0xbf80_0000;
+
+    // The sign operations only ever touch the sign bit, even for NaNs
+    assert_eq!(f32_op_bits(&[F32_NEG_NAN], Opcode::F32Abs), F32_NAN);
+    assert_eq!(f32_op_bits(&[F32_NAN], Opcode::F32Abs), F32_NAN);
+    assert_eq!(f32_op_bits(&[F32_NAN], Opcode::F32Neg), F32_NEG_NAN);
+    assert_eq!(f32_op_bits(&[F32_NEG_NAN], Opcode::F32Neg), F32_NAN);
+    assert_eq!(f32_op_bits(&[F32_NEG_ZERO], Opcode::F32Abs), 0);
+    assert_eq!(f32_op_bits(&[0], Opcode::F32Neg), F32_NEG_ZERO);
+    assert_eq!(f32_op_bits(&[F32_NEG_INF], Opcode::F32Abs), F32_INF);
+    assert_eq!(f32_op_bits(&[F32_INF], Opcode::F32Neg), F32_NEG_INF);
+    assert_eq!(
+        f32_op_bits(&[F32_NAN, F32_NEG_ZERO], Opcode::F32CopySign),
+        F32_NEG_NAN
+    );
+    assert_eq!(
+        f32_op_bits(&[F32_ONE, F32_NEG_NAN], Opcode::F32CopySign),
+        F32_NEG_ONE
+    );
+    assert_eq!(
+        f32_op_bits(&[F32_NEG_NAN, F32_ONE], Opcode::F32CopySign),
+        F32_NAN
+    );
+
+    assert_eq!(f32_op_bits(&[F32_NEG_ZERO], Opcode::F32Sqrt), F32_NEG_ZERO);
+    assert_eq!(f32_op_bits(&[F32_INF], Opcode::F32Sqrt), F32_INF);
+    assert!(f32::from_bits(f32_op_bits(&[F32_NEG_ONE], Opcode::F32Sqrt)).is_nan());
+
+    const F64_NAN: u64 = 0x7ff8_0000_0000_1234;
+    const F64_NEG_NAN: u64 = 0xfff8_0000_0000_1234;
+    const F64_NEG_ZERO: u64 = 0x8000_0000_0000_0000;
+    const F64_INF: u64 = 0x7ff0_0000_0000_0000;
+    const F64_NEG_INF: u64 = 0xfff0_0000_0000_0000;
+    const F64_ONE: u64 = 0x3ff0_0000_0000_0000;
+    const F64_NEG_ONE: u64 = 0xbff0_0000_0000_0000;
+
+    assert_eq!(f64_op_bits(&[F64_NEG_NAN], Opcode::F64Abs), F64_NAN);
+    assert_eq!(f64_op_bits(&[F64_NAN], Opcode::F64Abs), F64_NAN);
+    assert_eq!(f64_op_bits(&[F64_NAN], Opcode::F64Neg), F64_NEG_NAN);
+    assert_eq!(f64_op_bits(&[F64_NEG_NAN], Opcode::F64Neg), F64_NAN);
+    assert_eq!(f64_op_bits(&[F64_NEG_ZERO], Opcode::F64Abs), 0);
+    assert_eq!(f64_op_bits(&[0], Opcode::F64Neg), F64_NEG_ZERO);
+    assert_eq!(f64_op_bits(&[F64_NEG_INF], Opcode::F64Abs), F64_INF);
+    assert_eq!(f64_op_bits(&[F64_INF], Opcode::F64Neg), F64_NEG_INF);
+    assert_eq!(
+        f64_op_bits(&[F64_NAN, F64_NEG_ZERO], Opcode::F64CopySign),
+        F64_NEG_NAN
+    );
+    assert_eq!(
+        f64_op_bits(&[F64_ONE, F64_NEG_NAN], Opcode::F64CopySign),
+        F64_NEG_ONE
+    );
+    assert_eq!(
+        f64_op_bits(&[F64_NEG_NAN, F64_ONE], Opcode::F64CopySign),
+        F64_NAN
+    );
+
+    assert_eq!(f64_op_bits(&[F64_NEG_ZERO], Opcode::F64Sqrt), F64_NEG_ZERO);
+    assert_eq!(f64_op_bits(&[F64_INF], Opcode::F64Sqrt), F64_INF);
+    assert!(f64::from_bits(f64_op_bits(&[F64_NEG_ONE], Opcode::F64Sqrt)).is_nan());
+}
+
+#[test]
+fn test_float_width_conversions() {
+    let demote = |value: f64| {
+        f32::try_from(test_unary_opcode_impl(value, Opcode::F32DemoteF64).unwrap()).unwrap()
+    };
+    let promote = |value: f32| {
+        f64::try_from(test_unary_opcode_impl(value, Opcode::F64PromoteF32).unwrap()).unwrap()
+    };
+
+    // Promotion is always exact
+    assert_eq!(promote(0.1_f32), 13421773.0 / 134217728.0);
+    assert_eq!(promote(std::f32::MAX), 3.4028234663852886e38_f64);
+    assert_eq!(
+        promote(std::f32::MIN_POSITIVE / 4.0).to_bits(),
+        0x37f0_0000_0000_0000
+    );
+    assert_eq!(promote(-0.0_f32).to_bits(), 0x8000_0000_0000_0000);
+    assert_eq!(promote(std::f32::INFINITY), std::f64::INFINITY);
+    assert_eq!(promote(std::f32::NEG_INFINITY), std::f64::NEG_INFINITY);
+
+    // Exactly representable values survive demotion unchanged
+    assert_eq!(demote(-1.5), -1.5_f32);
+    assert_eq!(demote(-0.0).to_bits(), 0x8000_0000);
+    assert_eq!(demote(f64::from(std::f32::MAX)), std::f32::MAX);
+
+    // Everything else rounds to nearest, with ties going to the even mantissa
+    let ulp = f64::from(std::f32::EPSILON);
+    assert_eq!(demote(1.0 + ulp / 4.0), 1.0_f32);
+    assert_eq!(demote(1.0 + ulp * 3.0 / 4.0), 1.0_f32 + std::f32::EPSILON);
+    assert_eq!(demote(1.0 + ulp / 2.0), 1.0_f32);
+    assert_eq!(
+        demote(1.0 + ulp * 3.0 / 2.0),
+        1.0_f32 + 2.0 * std::f32::EPSILON
+    );
+    assert_eq!(demote(0.1), 0.1_f32);
+    assert_eq!(demote(1e-50), 0.0_f32);
+
+    // Overflow goes to infinity, rather than saturating at the largest finite value
+    assert_eq!(demote(std::f64::MAX), std::f32::INFINITY);
+    assert_eq!(demote(-1e39), std::f32::NEG_INFINITY);
+    assert_eq!(demote(std::f64::INFINITY), std::f32::INFINITY);
+    assert_eq!(demote(std::f64::NEG_INFINITY), std::f32::NEG_INFINITY);
+
+    // NaNs stay NaNs in both directions, whatever their payload
+    assert!(demote(f64::from_bits(0x7ff0_0000_0000_0001)).is_nan());
+    assert!(demote(f64::from_bits(0xfff8_0000_dead_beef)).is_nan());
+    assert!(promote(f32::from_bits(0x7f80_0001)).is_nan());
+    assert!(promote(f32::from_bits(0xffc0_1234)).is_nan());
 }
 
 fn do_local_get(
@@ -590,3 +1140,591 @@ fn test_memory_ops() {
 
     assert_eq!(store.get_memory_size(0).ok(), Some(2));
 }
+
+#[test]
+fn test_sub_width_memory_ops() {
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+
+    store.enable_memory();
+
+    static FIXED_DATA: [u8; 4] = [0xff, 0xff, 0x80, 0x7f];
+    store.write_data(0, 64, &FIXED_DATA).unwrap();
+
+    // The narrow loads either sign or zero extend into the full width of the result
+    test_memory_load!(Opcode::I32Load8S, 64, 0, 0, &mut stack, &mut store, -1_i32);
+    test_memory_load!(
+        Opcode::I32Load8U,
+        64,
+        0,
+        0,
+        &mut stack,
+        &mut store,
+        0xff_u32
+    );
+    test_memory_load!(Opcode::I32Load16S, 64, 1, 0, &mut stack, &mut store, -1_i32);
+    test_memory_load!(
+        Opcode::I32Load16U,
+        64,
+        1,
+        0,
+        &mut stack,
+        &mut store,
+        0xffff_u32
+    );
+    test_memory_load!(Opcode::I64Load8S, 64, 0, 0, &mut stack, &mut store, -1_i64);
+    test_memory_load!(
+        Opcode::I64Load8U,
+        64,
+        0,
+        0,
+        &mut stack,
+        &mut store,
+        0xff_u64
+    );
+    test_memory_load!(Opcode::I64Load16S, 64, 1, 0, &mut stack, &mut store, -1_i64);
+    test_memory_load!(
+        Opcode::I64Load16U,
+        64,
+        1,
+        0,
+        &mut stack,
+        &mut store,
+        0xffff_u64
+    );
+    test_memory_load!(
+        Opcode::I32Load16S,
+        66,
+        1,
+        0,
+        &mut stack,
+        &mut store,
+        0x7f80_i32
+    );
+
+    // The static offset is added to the dynamic address, and the alignment hint has no effect
+    test_memory_load!(
+        Opcode::I32Load8U,
+        60,
+        2,
+        6,
+        &mut stack,
+        &mut store,
+        0x80_u32
+    );
+    test_memory_load!(
+        Opcode::I32Load8S,
+        0,
+        0,
+        66,
+        &mut stack,
+        &mut store,
+        -128_i32
+    );
+
+    // Narrow stores only write the low bytes of the value, in little endian order
+    test_memory_store!(
+        Opcode::I32Store16,
+        0,
+        1,
+        128,
+        0x1234_5678_u32,
+        &mut stack,
+        &mut store
+    );
+    test_memory_store!(
+        Opcode::I64Store32,
+        130,
+        2,
+        0,
+        0x0102_0304_0506_0708_u64,
+        &mut stack,
+        &mut store
+    );
+
+    let mut check_bytes: [u8; 6] = [0; 6];
+    store.read_data(0, 128, &mut check_bytes).unwrap();
+    assert_eq!(check_bytes, [0x78, 0x56, 0x08, 0x07, 0x06, 0x05]);
+
+    // An access that straddles the end of memory traps, even when the first byte is in bounds
+    let memory_end = (store.get_memory_size(0).unwrap() * 65536) as u32;
+    assert_eq!(
+        test_memory_load_impl(
+            Opcode::I32Load16U,
+            memory_end - 1,
+            1,
+            0,
+            &mut stack,
+            &mut store
+        ),
+        None
+    );
+    let count = stack.working_count();
+    stack.pop_n(count);
+    test_memory_load!(
+        Opcode::I32Load16U,
+        memory_end - 2,
+        1,
+        0,
+        &mut stack,
+        &mut store,
+        0_u32
+    );
+
+    // The effective address is computed without wrapping, so an offset pushing the address past
+    // 32 bits is out of bounds rather than wrapping round to the start of memory
+    for (address, offset) in &[
+        (0xffff_ffff_u32, 1_u32),
+        (1, 0xffff_ffff),
+        (0x8000_0000, 0x8000_0000),
+    ] {
+        assert_eq!(
+            test_memory_load_impl(
+                Opcode::I32Load8U,
+                *address,
+                0,
+                *offset,
+                &mut stack,
+                &mut store
+            ),
+            None
+        );
+        let count = stack.working_count();
+        stack.pop_n(count);
+        assert_eq!(
+            test_memory_store_impl(
+                Opcode::I32Store8,
+                *address,
+                0,
+                *offset,
+                0_u32,
+                &mut stack,
+                &mut store
+            ),
+            None
+        );
+        let count = stack.working_count();
+        stack.pop_n(count);
+    }
+}
+
+#[test]
+fn test_unaligned_memory_ops() {
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+
+    store.enable_memory();
+
+    // Grow to two pages, so that accesses can also straddle the boundary between them
+    assert!(store.grow_memory_by(0, 1).is_ok());
+    let memory_end = (store.get_memory_size(0).unwrap() * 65536) as u32;
+
+    let mut addresses = vec![];
+    for misalignment in &[1_u32, 2, 3, 7] {
+        addresses.push(*misalignment);
+        addresses.push(65536 - 8 + misalignment);
+        addresses.push(memory_end - 16 + misalignment);
+    }
+
+    for address in addresses {
+        // Store the full width values with the alignment hint claiming natural alignment, then
+        // check every load width sees the expected little endian bytes
+        test_memory_store!(
+            Opcode::I64Store,
+            address,
+            3,
+            0,
+            0x8887_8685_8483_8281_u64,
+            &mut stack,
+            &mut store
+        );
+
+        let mut check_bytes: [u8; 8] = [0; 8];
+        store
+            .read_data(0, address as usize, &mut check_bytes)
+            .unwrap();
+        assert_eq!(
+            check_bytes,
+            [0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88]
+        );
+
+        test_memory_load!(
+            Opcode::I64Load,
+            address,
+            3,
+            0,
+            &mut stack,
+            &mut store,
+            0x8887_8685_8483_8281_u64
+        );
+        test_memory_load!(
+            Opcode::I32Load,
+            address,
+            2,
+            0,
+            &mut stack,
+            &mut store,
+            0x8483_8281_u32
+        );
+        test_memory_load!(
+            Opcode::I32Load16U,
+            address,
+            1,
+            0,
+            &mut stack,
+            &mut store,
+            0x8281_u32
+        );
+        test_memory_load!(
+            Opcode::I32Load16S,
+            address,
+            1,
+            0,
+            &mut stack,
+            &mut store,
+            0xffff_8281_u32
+        );
+        test_memory_load!(
+            Opcode::I32Load8U,
+            address,
+            0,
+            0,
+            &mut stack,
+            &mut store,
+            0x81_u32
+        );
+        test_memory_load!(
+            Opcode::I64Load32U,
+            address,
+            2,
+            0,
+            &mut stack,
+            &mut store,
+            0x8483_8281_u64
+        );
+        test_memory_load!(
+            Opcode::I64Load32S,
+            address,
+            2,
+            0,
+            &mut stack,
+            &mut store,
+            0xffff_ffff_8483_8281_u64
+        );
+        test_memory_load!(
+            Opcode::I64Load16U,
+            address,
+            1,
+            0,
+            &mut stack,
+            &mut store,
+            0x8281_u64
+        );
+        test_memory_load!(
+            Opcode::I64Load8S,
+            address,
+            0,
+            0,
+            &mut stack,
+            &mut store,
+            -127_i64
+        );
+
+        test_memory_store!(
+            Opcode::F64Store,
+            address,
+            3,
+            0,
+            -1.5_f64,
+            &mut stack,
+            &mut store
+        );
+        test_memory_load!(
+            Opcode::F64Load,
+            address,
+            3,
+            0,
+            &mut stack,
+            &mut store,
+            -1.5_f64
+        );
+        test_memory_store!(
+            Opcode::F32Store,
+            address,
+            2,
+            0,
+            0.25_f32,
+            &mut stack,
+            &mut store
+        );
+        test_memory_load!(
+            Opcode::F32Load,
+            address,
+            2,
+            0,
+            &mut stack,
+            &mut store,
+            0.25_f32
+        );
+        test_memory_store!(
+            Opcode::I32Store,
+            address,
+            2,
+            0,
+            0x1234_5678_u32,
+            &mut stack,
+            &mut store
+        );
+        test_memory_load!(
+            Opcode::I32Load,
+            address,
+            2,
+            0,
+            &mut stack,
+            &mut store,
+            0x1234_5678_u32
+        );
+        test_memory_store!(
+            Opcode::I32Store16,
+            address,
+            1,
+            0,
+            0xabcd_u32,
+            &mut stack,
+            &mut store
+        );
+        test_memory_load!(
+            Opcode::I32Load16U,
+            address,
+            1,
+            0,
+            &mut stack,
+            &mut store,
+            0xabcd_u32
+        );
+        test_memory_store!(
+            Opcode::I64Store32,
+            address,
+            2,
+            0,
+            0x9876_5432_u64,
+            &mut stack,
+            &mut store
+        );
+        test_memory_load!(
+            Opcode::I64Load32U,
+            address,
+            2,
+            0,
+            &mut stack,
+            &mut store,
+            0x9876_5432_u64
+        );
+        test_memory_store!(
+            Opcode::I64Store16,
+            address,
+            1,
+            0,
+            0x4321_u64,
+            &mut stack,
+            &mut store
+        );
+        test_memory_load!(
+            Opcode::I64Load16U,
+            address,
+            1,
+            0,
+            &mut stack,
+            &mut store,
+            0x4321_u64
+        );
+        test_memory_store!(
+            Opcode::I64Store8,
+            address,
+            0,
+            0,
+            0x42_u64,
+            &mut stack,
+            &mut store
+        );
+        test_memory_load!(
+            Opcode::I64Load8U,
+            address,
+            0,
+            0,
+            &mut stack,
+            &mut store,
+            0x42_u64
+        );
+    }
+}
+
+fn do_memory_grow(stack: &mut Stack, store: &mut TestStore, grow_by: u32) -> Option<StackEntry> {
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(grow_by);
+    expr.write_single_leb_instruction(Opcode::MemoryGrow, 0);
+
+    if execute_expression(&expr, stack, store).is_err() || stack.working_count() != 1 {
+        return None;
+    }
+
+    let r = stack.working_top(1)[0];
+    stack.pop();
+    Some(r)
+}
+
+#[test]
+fn test_memory_grow() {
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+
+    store.set_memory(Memory::new_from_bounds(1, None));
+
+    static FIXED_DATA: [u8; 4] = [0x01, 0x02, 0x03, 0x04];
+    store.write_data(0, 65534, &FIXED_DATA[0..2]).unwrap();
+
+    // Growing by zero is a way of querying the size
+    assert_eq!(
+        do_memory_grow(&mut stack, &mut store, 0),
+        Some(1_u32.into())
+    );
+    assert_eq!(
+        do_memory_grow(&mut stack, &mut store, 2),
+        Some(1_u32.into())
+    );
+    assert_eq!(store.get_memory_size(0).ok(), Some(3));
+
+    // Existing contents survive the grow, and the new pages are zeroed
+    let mut check_bytes: [u8; 4] = [0xff; 4];
+    store.read_data(0, 65534, &mut check_bytes).unwrap();
+    assert_eq!(check_bytes, [0x01, 0x02, 0x00, 0x00]);
+    store.read_data(0, 3 * 65536 - 4, &mut check_bytes).unwrap();
+    assert_eq!(check_bytes, [0x00; 4]);
+
+    // Failing to grow past the 4GiB address space isn't a trap, and leaves the memory alone
+    for grow_by in &[65534_u32, 0x1_0000, 0xffff_ffff] {
+        assert_eq!(
+            do_memory_grow(&mut stack, &mut store, *grow_by),
+            Some(StackEntry::from(-1_i32))
+        );
+        assert_eq!(store.get_memory_size(0).ok(), Some(3));
+    }
+
+    store.read_data(0, 65534, &mut check_bytes).unwrap();
+    assert_eq!(check_bytes, [0x01, 0x02, 0x00, 0x00]);
+
+    // The declared maximum is respected too
+    store.set_memory(Memory::new_from_bounds(1, Some(2)));
+    assert_eq!(
+        do_memory_grow(&mut stack, &mut store, 2),
+        Some(StackEntry::from(-1_i32))
+    );
+    assert_eq!(
+        do_memory_grow(&mut stack, &mut store, 1),
+        Some(1_u32.into())
+    );
+    assert_eq!(
+        do_memory_grow(&mut stack, &mut store, 1),
+        Some(StackEntry::from(-1_i32))
+    );
+    assert_eq!(store.get_memory_size(0).ok(), Some(2));
+}
+
+#[test]
+fn test_memory_slice_access() {
+    const MEMORY_BYTES: u32 = 2 * 65536;
+
+    // Have the guest fill the memory with a loop that stores each word's own address into it
+    let mut expr = make_expression_writer();
+    let mut loop_expr = expr.write_block_instruction(Opcode::Loop, BlockType::None);
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_two_leb_instruction(Opcode::I32Store, 2, 0);
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_const_instruction(4_u32);
+    loop_expr.write_single_byte_instruction(Opcode::I32Add);
+    loop_expr.write_single_leb_instruction(Opcode::LocalTee, 0);
+    loop_expr.write_const_instruction(MEMORY_BYTES);
+    loop_expr.write_single_byte_instruction(Opcode::I32Ne);
+    loop_expr.write_single_leb_instruction(Opcode::BrIf, 0);
+    expr = loop_expr.do_end();
+
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+    store.set_memory(Memory::new_from_bounds(2, None));
+
+    let func_type = FuncType::new(vec![], vec![]);
+    let locals = vec![Locals::new(1, ValueType::I32)];
+    assert!(stack.push_typed_frame(&func_type, &locals).is_ok());
+    assert!(execute_expression(&expr, &mut stack, &mut store).is_ok());
+
+    // The host can look at everything the guest wrote, including across the page boundary,
+    // without copying it out
+    {
+        let memory = store.mem_idx(0).unwrap();
+        let data = memory.data();
+        assert_eq!(data.len(), MEMORY_BYTES as usize);
+        assert_eq!(memory.len(), data.len());
+
+        for (index, word) in data.chunks(4).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            assert_eq!(word as usize, index * 4);
+        }
+    }
+
+    // Writes through the mutable slice are seen by the guest
+    store.mem_idx_mut(0).unwrap().data_mut()[65534..65538].copy_from_slice(&[1, 2, 3, 4]);
+    test_memory_load!(
+        Opcode::I32Load,
+        65534,
+        0,
+        0,
+        &mut stack,
+        &mut store,
+        0x04030201_u32
+    );
+
+    // Growing makes the slice longer, with the old contents kept
+    {
+        let memory = store.mem_idx_mut(0).unwrap();
+        memory.grow_by(1).unwrap();
+        assert_eq!(memory.len(), 3 * 65536);
+        assert_eq!(memory.data()[65534..65538], [1, 2, 3, 4]);
+        assert!(memory.data()[MEMORY_BYTES as usize..]
+            .iter()
+            .all(|b| *b == 0));
+
+        let ptr = memory.data_ptr();
+        assert_eq!(ptr as *const u8, memory.data().as_ptr());
+    }
+}
+
+#[test]
+fn test_out_of_bounds_memory_trap() {
+    let mut stack = Stack::new();
+    let mut store = TestStore::new();
+    store.set_memory(Memory::new_from_bounds(1, None));
+
+    // A load that runs off the end of memory, one whose offset takes it past the end, and a store
+    let mut loads = vec![];
+    for (address, offset) in &[(65533_u32, 0_u32), (0, 65536), (0xffff_ffff, 0xffff_ffff)] {
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction(*address);
+        expr.write_two_leb_instruction(Opcode::I32Load, 2, (*offset).into());
+        loads.push(expr);
+    }
+
+    let mut store_expr = make_expression_writer();
+    store_expr.write_const_instruction(65535_u32);
+    store_expr.write_const_instruction(0_u64);
+    store_expr.write_two_leb_instruction(Opcode::I64Store16, 1, 0);
+    loads.push(store_expr);
+
+    for expr in &loads {
+        let error = execute_expression(expr, &mut stack, &mut store).unwrap_err();
+        match error.downcast_ref::<Trap>().map(|t| t.kind()) {
+            Some(TrapKind::MemoryOutOfBounds { .. }) => {}
+            other => panic!("Expected an out of bounds trap, got {:?}", other),
+        }
+    }
+}
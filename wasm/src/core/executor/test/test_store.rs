@@ -44,6 +44,11 @@ impl TestStore {
         self.functions.len() - 1
     }
 
+    pub fn set_memory(&mut self, memory: Memory) {
+        self.memory = memory;
+        self.memory_enabled = true;
+    }
+
     pub fn set_func_types(&mut self, func_types: Vec<FuncType>) {
         self.func_types = func_types;
     }
@@ -0,0 +1,203 @@
+// The error for an export that isn't there, which suggests the names that were probably meant.
+// It's carried in an anyhow error, like ImportError, so it can be found with downcast_ref.
+use std::fmt;
+
+use crate::core::ExportKind;
+
+const MAX_SUGGESTIONS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportNotFound {
+    pub name: String,
+    pub kind: ExportKind,
+    // The closest exports of the kind that was wanted, best first
+    pub suggestions: Vec<String>,
+    pub export_count: usize,
+}
+
+impl ExportNotFound {
+    // The exports should be in the order they were declared, which is how ties are broken
+    pub fn new<'a>(
+        name: &str,
+        kind: ExportKind,
+        exports: impl IntoIterator<Item = (&'a str, ExportKind)>,
+    ) -> Self {
+        let mut export_count = 0;
+        let mut candidates = Vec::new();
+        for (export_name, export_kind) in exports {
+            export_count += 1;
+            if export_kind == kind {
+                candidates.push(export_name);
+            }
+        }
+
+        Self {
+            name: name.to_string(),
+            kind,
+            suggestions: closest_names(name, candidates)
+                .into_iter()
+                .map(|name| name.to_string())
+                .collect(),
+            export_count,
+        }
+    }
+}
+
+impl fmt::Display for ExportNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No {} export `{}`", self.kind, self.name)?;
+        for (idx, suggestion) in self.suggestions.iter().enumerate() {
+            let separator = match idx {
+                0 => "; did you mean",
+                idx if idx + 1 == self.suggestions.len() => " or",
+                _ => ",",
+            };
+            write!(f, "{} `{}` ({})", separator, suggestion, self.kind)?;
+        }
+        write!(
+            f,
+            "{}",
+            if self.suggestions.is_empty() {
+                "."
+            } else {
+                "?"
+            }
+        )?;
+
+        match self.export_count {
+            0 => write!(f, " The module has no exports"),
+            1 => write!(f, " The module has 1 export, which exports_in_order lists"),
+            count => write!(
+                f,
+                " The module has {} exports, which exports_in_order lists",
+                count
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExportNotFound {}
+
+// Up to three of the candidates that are close enough to the name to be what was meant, closest
+// first. That's either a few typos away, or the name is part of it or it's part of the name, like
+// fib of fibonacci.
+fn closest_names<'a>(name: &str, candidates: Vec<&'a str>) -> Vec<&'a str> {
+    let typos = std::cmp::max(1, name.chars().count() / 3);
+    let mut scored: Vec<_> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let distance = edit_distance(name, candidate);
+            let related = !name.is_empty()
+                && !candidate.is_empty()
+                && (candidate.contains(name) || name.contains(candidate));
+            if related || distance <= typos {
+                Some((distance, candidate))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    // The sort is stable, so equally close names stay in the order they came in
+    scored.sort_by_key(|(distance, _)| *distance);
+    scored
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+// The number of chars that have to be added, removed or changed, or pairs of neighbours swapped,
+// to turn one string into the other. Swaps are counted as one since they're such a common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // distances[i][j] is the distance between the first i chars of a and the first j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (distances[i - 1][j - 1] + substitution)
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("fib", "fbi"), 1);
+        assert_eq!(edit_distance("mian", "main"), 1);
+        assert_eq!(edit_distance("naïve", "naive"), 1);
+    }
+
+    #[test]
+    fn test_closest_names() {
+        let exports = vec!["fibonacci", "fib_iter", "main", "memory_size", "fi"];
+        assert_eq!(
+            closest_names("fib", exports.clone()),
+            vec!["fi", "fib_iter", "fibonacci"]
+        );
+        assert_eq!(closest_names("mian", exports.clone()), vec!["main"]);
+        assert!(closest_names("unrelated", exports).is_empty());
+
+        // Ties keep the order the candidates came in, and only three are kept
+        assert_eq!(
+            closest_names("ab", vec!["xb", "ax", "a", "b"]),
+            vec!["xb", "ax", "a"]
+        );
+        assert!(closest_names("fib", vec![]).is_empty());
+        assert_eq!(closest_names("", vec!["a", "bc"]), vec!["a"]);
+    }
+
+    #[test]
+    fn test_export_not_found() {
+        let exports = vec![
+            ("fibonacci", ExportKind::Function),
+            ("fib_iter", ExportKind::Function),
+            ("fib", ExportKind::Global),
+        ];
+        let error = ExportNotFound::new("fib", ExportKind::Function, exports.clone());
+        assert_eq!(error.suggestions, vec!["fib_iter", "fibonacci"]);
+        assert_eq!(
+            error.to_string(),
+            "No function export `fib`; did you mean `fib_iter` (function) or `fibonacci` \
+             (function)? The module has 3 exports, which exports_in_order lists"
+        );
+
+        let error = ExportNotFound::new("fibs", ExportKind::Global, exports);
+        assert_eq!(
+            error.to_string(),
+            "No global export `fibs`; did you mean `fib` (global)? The module has 3 exports, \
+             which exports_in_order lists"
+        );
+
+        let error = ExportNotFound::new("main", ExportKind::Function, vec![]);
+        assert_eq!(
+            error.to_string(),
+            "No function export `main`. The module has no exports"
+        );
+    }
+}
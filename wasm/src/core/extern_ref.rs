@@ -0,0 +1,259 @@
+// Handles to host objects that a guest can hold on to indefinitely, such as in a global or in
+// memory, and pass back to the host functions that use them. There's no externref value type yet,
+// so a handle goes through wasm as an i64, which the guest can only store and pass back.
+//
+// A handle names a slot in a table along with the slot's generation. The generation goes up each
+// time the slot is emptied, so a handle that has been removed is found to be stale, rather than
+// picking up whatever goes in the slot next. A slot whose generation would wrap is never used
+// again. Handles also carry the id of their table, so one from another table is found to be
+// foreign. There are 65535 ids to go round, so that only tells apart tables made close together.
+//
+// Host functions that use a table share it, usually as an Rc<RefCell<ExternRefTable<T>>>. Since
+// the instance owns the host functions it was resolved with, the values left in the table are
+// dropped with the instance, once the host has let go of it too.
+use anyhow::Result;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::core::stack_entry::StackEntry;
+
+static NEXT_TABLE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// The table id is in the top 16 bits, then the generation, then the slot. Zero is never a handle,
+// since no table has the id 0, so it makes a natural null.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExternRef {
+    table: u16,
+    generation: u16,
+    slot: u32,
+}
+
+impl ExternRef {
+    pub fn to_bits(self) -> u64 {
+        (u64::from(self.table) << 48) | (u64::from(self.generation) << 32) | u64::from(self.slot)
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self {
+            table: (bits >> 48) as u16,
+            generation: (bits >> 32) as u16,
+            slot: bits as u32,
+        }
+    }
+
+    pub fn is_null(self) -> bool {
+        self.table == 0
+    }
+}
+
+impl From<ExternRef> for StackEntry {
+    fn from(handle: ExternRef) -> Self {
+        StackEntry::from(handle.to_bits())
+    }
+}
+
+impl TryFrom<StackEntry> for ExternRef {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: StackEntry) -> Result<Self> {
+        u64::try_from(entry).map(ExternRef::from_bits)
+    }
+}
+
+// Why a handle the guest passed in doesn't name a value. It's carried in an anyhow error, so host
+// functions can return it with ?, and the embedder can find it with downcast_ref.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExternRefError {
+    Null,
+    Foreign(ExternRef),
+    Stale(ExternRef),
+}
+
+impl fmt::Display for ExternRefError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternRefError::Null => write!(f, "Null host handle"),
+            ExternRefError::Foreign(handle) => write!(
+                f,
+                "Host handle {:#x} is from another table",
+                handle.to_bits()
+            ),
+            ExternRefError::Stale(handle) => write!(
+                f,
+                "Host handle {:#x} was removed from its table",
+                handle.to_bits()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExternRefError {}
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: u16,
+    value: Option<T>,
+}
+
+#[derive(Debug)]
+pub struct ExternRefTable<T> {
+    id: u16,
+    slots: Vec<Slot<T>>,
+    // The empty slots that can be used again, most recently emptied last
+    free: Vec<u32>,
+    len: usize,
+}
+
+impl<T> ExternRefTable<T> {
+    pub fn new() -> Self {
+        let id = NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed) % 0xffff + 1;
+        Self {
+            id: id as u16,
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> ExternRef {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                let slot = u32::try_from(self.slots.len()).expect("Too many host handles");
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                });
+                slot
+            }
+        };
+
+        let entry = &mut self.slots[slot as usize];
+        entry.value = Some(value);
+        self.len += 1;
+        ExternRef {
+            table: self.id,
+            generation: entry.generation,
+            slot,
+        }
+    }
+
+    // Why the handle doesn't name a value in this table, if it doesn't
+    pub fn check(&self, handle: &ExternRef) -> std::result::Result<(), ExternRefError> {
+        if handle.is_null() {
+            return Err(ExternRefError::Null);
+        }
+        if handle.table != self.id {
+            return Err(ExternRefError::Foreign(*handle));
+        }
+        match self.slots.get(handle.slot as usize) {
+            Some(slot) if slot.generation == handle.generation && slot.value.is_some() => Ok(()),
+            // A slot past the end can only come from a handle that was made up
+            _ => Err(ExternRefError::Stale(*handle)),
+        }
+    }
+
+    pub fn get(&self, handle: &ExternRef) -> Option<&T> {
+        self.lookup(handle).ok()
+    }
+
+    pub fn get_mut(&mut self, handle: &ExternRef) -> Option<&mut T> {
+        self.check(handle).ok()?;
+        self.slots[handle.slot as usize].value.as_mut()
+    }
+
+    // The same as get, but says what's wrong with the handle if there's nothing there
+    pub fn lookup(&self, handle: &ExternRef) -> std::result::Result<&T, ExternRefError> {
+        self.check(handle)?;
+        Ok(self.slots[handle.slot as usize].value.as_ref().unwrap())
+    }
+
+    // Takes the value out, after which the handle and any copies of it are stale
+    pub fn remove(&mut self, handle: &ExternRef) -> Option<T> {
+        self.check(handle).ok()?;
+        let slot = &mut self.slots[handle.slot as usize];
+        let value = slot.value.take();
+        self.len -= 1;
+
+        if let Some(generation) = slot.generation.checked_add(1) {
+            slot.generation = generation;
+            self.free.push(handle.slot);
+        }
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T> Default for ExternRefTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extern_ref_table() {
+        let mut table = ExternRefTable::new();
+        let first = table.insert("first".to_string());
+        let second = table.insert("second".to_string());
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(&first).unwrap(), "first");
+        assert_eq!(ExternRef::from_bits(second.to_bits()), second);
+
+        // The slot is reused, but the old handle doesn't see what's in it now
+        assert_eq!(table.remove(&first).unwrap(), "first");
+        let third = table.insert("third".to_string());
+        assert_eq!(third.slot, first.slot);
+        assert_eq!(table.get(&first), None);
+        assert_eq!(table.lookup(&first), Err(ExternRefError::Stale(first)));
+        assert_eq!(table.remove(&first), None);
+        assert_eq!(table.get(&third).unwrap(), "third");
+        assert_eq!(table.len(), 2);
+
+        let mut other = ExternRefTable::new();
+        let foreign = other.insert("other".to_string());
+        assert_eq!(
+            table.lookup(&foreign),
+            Err(ExternRefError::Foreign(foreign))
+        );
+        let null = ExternRef::from_bits(0);
+        assert_eq!(table.lookup(&null), Err(ExternRefError::Null));
+
+        // Nothing is found past the end of the table, or with a generation it hasn't got to yet
+        let made_up = ExternRef { slot: 7, ..second };
+        assert_eq!(table.lookup(&made_up), Err(ExternRefError::Stale(made_up)));
+        let future = ExternRef {
+            generation: 9,
+            ..second
+        };
+        assert_eq!(table.get(&future), None);
+    }
+
+    #[test]
+    fn test_extern_ref_slot_retired_before_wrapping() {
+        let mut table = ExternRefTable::new();
+        let mut handle = table.insert(0);
+        for value in 1..=u32::from(u16::max_value()) {
+            table.remove(&handle);
+            handle = table.insert(value);
+            assert_eq!(handle.slot, 0);
+        }
+
+        // The slot's generation can't go any higher, so it's left empty for good
+        table.remove(&handle);
+        let next = table.insert(0);
+        assert_eq!(next.slot, 1);
+        assert_eq!(table.get(&handle), None);
+    }
+}
@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::core::{
+    Callable, ExportKind, FuncType, Global, GlobalType, Limits, MemType, Memory, Table, TableType,
+};
+
+// One of the four kinds of entity a module can import or export, as the handle that the instances
+// using it share. What an instance exports is one of these, and so is what a resolver provides
+// for an import.
+#[derive(Debug, Clone)]
+pub enum Extern {
+    Func(Rc<RefCell<Callable>>),
+    Table(Rc<RefCell<Table>>),
+    Memory(Rc<RefCell<Memory>>),
+    Global(Rc<RefCell<Global>>),
+}
+
+/// Accessors for getting at the value without matching on every variant.
+///
+/// ```
+/// use std::{cell::RefCell, rc::Rc};
+/// use wasm::core::{ExportKind, Extern, Global, GlobalType, MutableType, ValueType};
+///
+/// let global_type = GlobalType::new(ValueType::I32, MutableType::Const);
+/// let global = Global::new(global_type, 7_u32.into()).unwrap();
+/// let export = Extern::Global(Rc::new(RefCell::new(global)));
+///
+/// assert_eq!(export.kind(), ExportKind::Global);
+/// assert!(export.as_function().is_none());
+/// assert_eq!(export.as_global().unwrap().borrow().get_value(), &7_u32.into());
+///
+/// let error = export.into_function().unwrap_err();
+/// assert_eq!(format!("{}", error), "global export");
+/// ```
+impl Extern {
+    pub fn kind(&self) -> ExportKind {
+        match self {
+            Extern::Func(_) => ExportKind::Function,
+            Extern::Table(_) => ExportKind::Table,
+            Extern::Memory(_) => ExportKind::Memory,
+            Extern::Global(_) => ExportKind::Global,
+        }
+    }
+
+    // Its type as it is now, so a table or memory's minimum is the size it's grown to, which is
+    // what an import it's given to is checked against
+    pub fn ty(&self) -> ExternType {
+        match self {
+            Extern::Func(f) => ExternType::Func(f.borrow().func_type().clone()),
+            Extern::Table(t) => {
+                let table = t.borrow();
+                ExternType::Table(TableType::new(
+                    table.ty().elem_type().clone(),
+                    Limits::new(table.current_size(), table.max_size()),
+                ))
+            }
+            Extern::Memory(m) => {
+                let memory = m.borrow();
+                ExternType::Memory(MemType::new(Limits::new(
+                    memory.current_size(),
+                    memory.max_size(),
+                )))
+            }
+            Extern::Global(g) => ExternType::Global(g.borrow().global_type().clone()),
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&Rc<RefCell<Callable>>> {
+        match self {
+            Extern::Func(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Rc<RefCell<Table>>> {
+        match self {
+            Extern::Table(t) => Some(t),
+            _ => None,
+        }
+    }
+
+    pub fn as_memory(&self) -> Option<&Rc<RefCell<Memory>>> {
+        match self {
+            Extern::Memory(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn as_global(&self) -> Option<&Rc<RefCell<Global>>> {
+        match self {
+            Extern::Global(g) => Some(g),
+            _ => None,
+        }
+    }
+
+    /// Takes the function out, or hands the extern back if it is something else.
+    pub fn into_function(self) -> std::result::Result<Rc<RefCell<Callable>>, Extern> {
+        match self {
+            Extern::Func(f) => Ok(f),
+            other => Err(other),
+        }
+    }
+
+    pub fn into_table(self) -> std::result::Result<Rc<RefCell<Table>>, Extern> {
+        match self {
+            Extern::Table(t) => Ok(t),
+            other => Err(other),
+        }
+    }
+
+    pub fn into_memory(self) -> std::result::Result<Rc<RefCell<Memory>>, Extern> {
+        match self {
+            Extern::Memory(m) => Ok(m),
+            other => Err(other),
+        }
+    }
+
+    pub fn into_global(self) -> std::result::Result<Rc<RefCell<Global>>, Extern> {
+        match self {
+            Extern::Global(g) => Ok(g),
+            other => Err(other),
+        }
+    }
+}
+
+impl From<Rc<RefCell<Callable>>> for Extern {
+    fn from(function: Rc<RefCell<Callable>>) -> Self {
+        Extern::Func(function)
+    }
+}
+
+impl From<Rc<RefCell<Table>>> for Extern {
+    fn from(table: Rc<RefCell<Table>>) -> Self {
+        Extern::Table(table)
+    }
+}
+
+impl From<Rc<RefCell<Memory>>> for Extern {
+    fn from(memory: Rc<RefCell<Memory>>) -> Self {
+        Extern::Memory(memory)
+    }
+}
+
+impl From<Rc<RefCell<Global>>> for Extern {
+    fn from(global: Rc<RefCell<Global>>) -> Self {
+        Extern::Global(global)
+    }
+}
+
+// Displays as the kind of export it is, which is what matters when an export turns out not to
+// be the kind that was asked for
+impl fmt::Display for Extern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} export", self.kind())
+    }
+}
+
+// The type an import is declared with, or that an extern has, with function type indices already
+// looked up
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExternType {
+    Func(FuncType),
+    Table(TableType),
+    Memory(MemType),
+    Global(GlobalType),
+}
+
+impl ExternType {
+    pub fn kind(&self) -> ExportKind {
+        match self {
+            ExternType::Func(_) => ExportKind::Function,
+            ExternType::Table(_) => ExportKind::Table,
+            ExternType::Memory(_) => ExportKind::Memory,
+            ExternType::Global(_) => ExportKind::Global,
+        }
+    }
+}
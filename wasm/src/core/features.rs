@@ -0,0 +1,358 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Read;
+
+use crate::core::{self, RawModule};
+use crate::parser::Opcode;
+use crate::reader::{ReaderUtil, SectionIter, TypeReader};
+
+pub const TARGET_FEATURES_SECTION: &str = "target_features";
+
+// The proposals on top of the MVP that a module can depend on. Not all of them can be decoded
+// yet, so some of these are never reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feature {
+    MutableGlobals,
+    SignExtension,
+    SaturatingFloatToInt,
+    MultiValue,
+    BulkMemory,
+    ReferenceTypes,
+    Simd,
+    RelaxedSimd,
+    Threads,
+    TailCall,
+    ExceptionHandling,
+    MultiMemory,
+    Memory64,
+}
+
+impl Feature {
+    // The feature an instruction needs, if it isn't part of the MVP
+    pub fn for_opcode(opcode: Opcode) -> Option<Feature> {
+        match opcode {
+            Opcode::I32Extend8S
+            | Opcode::I32Extend16S
+            | Opcode::I64Extend8S
+            | Opcode::I64Extend16S
+            | Opcode::I64Extend32S => Some(Feature::SignExtension),
+            _ => None,
+        }
+    }
+
+    // The feature with the name LLVM gives it in the target_features section, if it's one of
+    // these
+    pub fn from_target_name(name: &str) -> Option<Feature> {
+        let feature = match name {
+            "mutable-globals" => Feature::MutableGlobals,
+            "sign-ext" => Feature::SignExtension,
+            "nontrapping-fptoint" => Feature::SaturatingFloatToInt,
+            "multivalue" => Feature::MultiValue,
+            "bulk-memory" => Feature::BulkMemory,
+            "reference-types" => Feature::ReferenceTypes,
+            "simd128" => Feature::Simd,
+            "relaxed-simd" => Feature::RelaxedSimd,
+            "atomics" => Feature::Threads,
+            "tail-call" => Feature::TailCall,
+            "exception-handling" => Feature::ExceptionHandling,
+            "multimemory" => Feature::MultiMemory,
+            "memory64" => Feature::Memory64,
+            _ => return None,
+        };
+        Some(feature)
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Feature::MutableGlobals => "mutable-globals",
+            Feature::SignExtension => "sign-ext",
+            Feature::SaturatingFloatToInt => "saturating-trunc",
+            Feature::MultiValue => "multi-value",
+            Feature::BulkMemory => "bulk-memory",
+            Feature::ReferenceTypes => "reference-types",
+            Feature::Simd => "simd",
+            Feature::RelaxedSimd => "relaxed-simd",
+            Feature::Threads => "threads",
+            Feature::TailCall => "tail-call",
+            Feature::ExceptionHandling => "exceptions",
+            Feature::MultiMemory => "multi-memory",
+            Feature::Memory64 => "memory64",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeatureSet {
+    features: BTreeSet<Feature>,
+}
+
+impl FeatureSet {
+    // The proposals the interpreter implements, which are all a module can use and still run
+    pub fn supported() -> Self {
+        let mut features = Self::default();
+        features.insert(Feature::MutableGlobals);
+        features.insert(Feature::SignExtension);
+        features.insert(Feature::MultiValue);
+        features
+    }
+
+    pub fn insert(&mut self, feature: Feature) {
+        self.features.insert(feature);
+    }
+
+    pub fn contains(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+        self.features.iter().cloned()
+    }
+}
+
+impl fmt::Display for FeatureSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "mvp");
+        }
+
+        for (idx, feature) in self.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", feature)?;
+        }
+        Ok(())
+    }
+}
+
+// What the target_features custom section says about a feature. A feature the module was built
+// to use is "+", one it needs whoever links it to use is "=", and one it was built to not use is
+// "-", which is only there to stop it being linked with something that does use it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetFeaturePrefix {
+    Used,
+    Required,
+    Disallowed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetFeature {
+    prefix: TargetFeaturePrefix,
+    name: String,
+}
+
+impl TargetFeature {
+    pub fn prefix(&self) -> TargetFeaturePrefix {
+        self.prefix
+    }
+
+    // The name as LLVM gives it, such as "simd128"
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn feature(&self) -> Option<Feature> {
+        Feature::from_target_name(&self.name)
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.prefix != TargetFeaturePrefix::Disallowed
+    }
+}
+
+impl fmt::Display for TargetFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = match self.prefix {
+            TargetFeaturePrefix::Used => '+',
+            TargetFeaturePrefix::Required => '=',
+            TargetFeaturePrefix::Disallowed => '-',
+        };
+        write!(f, "{}{}", prefix, self.name)
+    }
+}
+
+// The contents of a target_features custom section, in the order they're listed. Names that
+// aren't one of the Features are kept too, so nothing is lost, but can't be checked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TargetFeatures {
+    entries: Vec<TargetFeature>,
+}
+
+impl TargetFeatures {
+    pub fn entries(&self) -> &[TargetFeature] {
+        &self.entries
+    }
+
+    // The features the module was built to use, of those that are known
+    pub fn used(&self) -> FeatureSet {
+        let mut features = FeatureSet::default();
+        for feature in self.entries.iter().filter(|entry| entry.is_used()) {
+            if let Some(feature) = feature.feature() {
+                features.insert(feature);
+            }
+        }
+        features
+    }
+
+    // The names of the features the module was built to use that aren't enabled
+    fn missing(&self, enabled: &FeatureSet) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.is_used())
+            .filter(|entry| match entry.feature() {
+                Some(feature) => !enabled.contains(feature),
+                None => false,
+            })
+            .map(|entry| entry.name.clone())
+            .collect()
+    }
+}
+
+impl TypeReader for TargetFeatures {
+    fn read<T: Read>(reader: &mut T) -> Result<Self> {
+        let entries = reader.read_vec(|r| {
+            let prefix = match r.read_u8()? {
+                b'+' => TargetFeaturePrefix::Used,
+                b'=' => TargetFeaturePrefix::Required,
+                b'-' => TargetFeaturePrefix::Disallowed,
+                byte => return Err(anyhow!("Invalid target feature prefix 0x{:02x}", byte)),
+            };
+            Ok(TargetFeature {
+                prefix,
+                name: r.read_name()?,
+            })
+        })?;
+        Ok(Self { entries })
+    }
+}
+
+impl fmt::Display for TargetFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, entry) in self.entries.iter().enumerate() {
+            if idx > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+fn missing_features_error(missing: &[String]) -> anyhow::Error {
+    anyhow!(
+        "Module requires {} which {} not enabled",
+        missing.join(", "),
+        if missing.len() == 1 { "is" } else { "are" }
+    )
+}
+
+impl RawModule {
+    // Checks that a module only uses enabled features before it's decoded, so that using one that
+    // isn't gets an error saying so, rather than one about whichever instruction or section is
+    // the first to need it. The target_features section says what the module was built to use. If
+    // there isn't one, the module is scanned for what it uses instead, which only finds the
+    // features that the interpreter can't decode.
+    pub fn check_features(bytes: &[u8], enabled: &FeatureSet) -> Result<()> {
+        for section in SectionIter::new(bytes)? {
+            let section = section?;
+            if section.section_type() != Some(core::SectionType::CustomSection) {
+                continue;
+            }
+            let (name, contents) = section.custom_contents()?;
+            if name == TARGET_FEATURES_SECTION {
+                let target_features = TargetFeatures::read(&mut &contents[..])?;
+                let missing = target_features.missing(enabled);
+                if missing.is_empty() {
+                    return Ok(());
+                }
+                return Err(missing_features_error(&missing));
+            }
+        }
+
+        let used = Self::compatibility_report(bytes)?.unsupported_features();
+        let missing: Vec<_> = used
+            .iter()
+            .filter(|feature| !enabled.contains(*feature))
+            .map(|feature| feature.to_string())
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(missing_features_error(&missing))
+        }
+    }
+
+    // What the target_features section says, if the module has one that could be read
+    pub fn target_features(&self) -> Option<&TargetFeatures> {
+        self.target_features.as_ref()
+    }
+
+    // How many times each instruction appears in the function bodies, by its text format name
+    pub fn opcode_stats(&self) -> Result<BTreeMap<&'static str, u64>> {
+        let mut stats = BTreeMap::new();
+        for func in self.funcs() {
+            for instruction in func.instructions() {
+                let (_, instr) = instruction?;
+                if let Some(opcode) = instr.opcode() {
+                    *stats.entry(opcode.name()).or_insert(0) += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    // The features the module actually uses, from the instructions in its function bodies and
+    // from what its sections declare
+    pub fn required_features(&self) -> Result<FeatureSet> {
+        let mut features = FeatureSet::default();
+
+        for func in self.funcs() {
+            for instruction in func.instructions() {
+                let (_, instr) = instruction?;
+                if let Some(feature) = instr.opcode().and_then(Feature::for_opcode) {
+                    features.insert(feature);
+                }
+            }
+        }
+
+        if self.types().iter().any(|t| t.results().len() > 1) {
+            features.insert(Feature::MultiValue);
+        }
+
+        // Only immutable globals could be imported or exported in the MVP
+        let mutable_import = self.imports().iter().any(|import| match import.desc() {
+            core::ImportDesc::GlobalType(global_type) => global_type.is_mutable(),
+            _ => false,
+        });
+        let num_imported_globals = self
+            .imports()
+            .iter()
+            .filter_map(|import| match import.desc() {
+                core::ImportDesc::GlobalType(global_type) => Some(global_type),
+                _ => None,
+            })
+            .count();
+        let mutable_export = self.exports().iter().any(|export| match export.d {
+            core::ExportDesc::Global(idx) if idx >= num_imported_globals => {
+                match self.globals().get(idx - num_imported_globals) {
+                    Some(global) => global.global_type().is_mutable(),
+                    None => false,
+                }
+            }
+            _ => false,
+        });
+        if mutable_import || mutable_export {
+            features.insert(Feature::MutableGlobals);
+        }
+
+        Ok(features)
+    }
+}
@@ -1,7 +1,9 @@
-use crate::core::{stack_entry::StackEntry, GlobalType, ValueType};
+use crate::core::{stack_entry::StackEntry, GlobalType, MutableType, ValueType};
 use anyhow::{anyhow, Result};
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Global {
     global_type: GlobalType,
     value: StackEntry,
@@ -35,6 +37,21 @@ impl Global {
         Ok(Global { global_type, value })
     }
 
+    // For the host to make a global to satisfy an import with. The type comes from the value, so
+    // this can't fail.
+    pub fn new_host(value: StackEntry, mutable: bool) -> Self {
+        let mutable_type = if mutable {
+            MutableType::Var
+        } else {
+            MutableType::Const
+        };
+
+        Global {
+            global_type: GlobalType::new(value.ty(), mutable_type),
+            value,
+        }
+    }
+
     pub fn global_type(&self) -> &GlobalType {
         &self.global_type
     }
@@ -52,6 +69,12 @@ impl Global {
         &self.value
     }
 
+    // Overwrites the value with zero, even for a constant, with a write the compiler can't leave
+    // out. For scrubbing what the guest kept in it once nothing is going to read it again.
+    pub(crate) fn zeroize(&mut self) {
+        zeroize_value(&mut self.value);
+    }
+
     pub fn set_value(&mut self, value: StackEntry) -> Result<()> {
         if self.is_mutable() {
             self.value = check_value_type(self.global_type(), value)?;
@@ -61,3 +84,15 @@ impl Global {
         }
     }
 }
+
+// Zeroes the value in place, keeping its type
+fn zeroize_value(value: &mut StackEntry) {
+    let zero = match value {
+        StackEntry::I32Entry(_) => StackEntry::I32Entry(0),
+        StackEntry::I64Entry(_) => StackEntry::I64Entry(0),
+        StackEntry::F32Entry(_) => StackEntry::F32Entry(0.0),
+        StackEntry::F64Entry(_) => StackEntry::F64Entry(0.0),
+    };
+    unsafe { ptr::write_volatile(value, zero) };
+    compiler_fence(Ordering::SeqCst);
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+
+use crate::core::{ExportKind, FuncType, GlobalType, Limits};
+
+// Why an import couldn't be resolved
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportErrorReason {
+    // A function import's type index isn't one of the module's types
+    InvalidTypeIndex {
+        type_idx: usize,
+        type_count: usize,
+    },
+    // The resolver returned an error, which is the cause of this one
+    Unresolved,
+    // The resolver provided a different kind of extern, such as a memory for a table import
+    KindMismatch {
+        provided: ExportKind,
+    },
+    FunctionTypeMismatch {
+        expected: FuncType,
+        provided: FuncType,
+    },
+    GlobalTypeMismatch {
+        expected: GlobalType,
+        provided: GlobalType,
+    },
+    // A memory or table that's too small, or that can grow bigger than the import allows. The
+    // minimum provided is the size it is now, which may be more than it started with.
+    LimitsMismatch {
+        expected: Limits,
+        provided: Limits,
+    },
+}
+
+impl fmt::Display for ImportErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportErrorReason::InvalidTypeIndex {
+                type_idx,
+                type_count,
+            } => write!(
+                f,
+                "type index {} is out of range, as the module's type count is {}",
+                type_idx, type_count
+            ),
+            ImportErrorReason::Unresolved => write!(f, "the resolver couldn't provide it"),
+            ImportErrorReason::KindMismatch { provided } => {
+                write!(f, "the resolver provided a {} instead", provided)
+            }
+            ImportErrorReason::FunctionTypeMismatch { expected, provided } => write!(
+                f,
+                "declared as {}, but the resolver provided {}",
+                expected, provided
+            ),
+            ImportErrorReason::GlobalTypeMismatch { expected, provided } => write!(
+                f,
+                "declared as {}, but the resolver provided {}",
+                expected, provided
+            ),
+            ImportErrorReason::LimitsMismatch { expected, provided } => write!(
+                f,
+                "declared with limits {}, but the resolver provided {}",
+                expected, provided
+            ),
+        }
+    }
+}
+
+// An import that couldn't be resolved. Like traps it is carried inside an anyhow error, and the
+// fields are there for embedders that want to report it in their own way. When the resolver
+// itself failed, its error is kept as the cause, so it can still be downcast to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportError {
+    pub module: String,
+    pub field: String,
+    pub kind: ExportKind,
+    pub reason: ImportErrorReason,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "import `{}`::`{}` ({}): {}",
+            self.module, self.field, self.kind, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ImportError {}
@@ -0,0 +1,1202 @@
+use anyhow::{anyhow, Context, Result};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::core::{
+    self, evaluate_constant_expression, evaluate_reference_expression, exit_code, name_trap_memory,
+    phase_trace::{self, Field, Phase},
+    stack_entry::StackEntry,
+    store_access::{CellRefMutType, CellRefType, RefType},
+    CallObserver, Callable, Caller, ConstantExpressionStore, CountingStore, ExecutionSummary,
+    ExportNotFound, ExpressionStore, Extern, ExternType, FuncType, Global, ImportError,
+    ImportErrorReason, InstancePool, InstantiationBudgetExceeded, InterruptHandle, Limits, Memory,
+    Module, NameSection, PoolSlot, PooledStack, ResourceMetrics, SnapshotLimits, Stack, Table,
+    Trap, TrapKind, DATA_BYTES_PER_FUEL,
+};
+use crate::parser::InstructionSource;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExportKind {
+    Function,
+    Table,
+    Memory,
+    Global,
+}
+
+impl fmt::Display for ExportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ExportKind::Function => "function",
+            ExportKind::Table => "table",
+            ExportKind::Memory => "memory",
+            ExportKind::Global => "global",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The names the entities were known by before they were the same for imports and exports
+pub type ExportValue = Extern;
+pub type ImportType = ExternType;
+
+// What one of the module's imports was resolved to. The value is the same handle the instance
+// uses, so it can be compared with Rc::ptr_eq against other instances or the resolver's objects.
+#[derive(Debug, Clone)]
+pub struct ResolvedImport {
+    pub mod_name: String,
+    pub name: String,
+    pub import_type: ExternType,
+    pub value: Extern,
+}
+
+impl ResolvedImport {
+    pub fn kind(&self) -> ExportKind {
+        self.import_type.kind()
+    }
+
+    // Whether a function import is implemented by the host rather than by a wasm function
+    // exported from another module. None for imports that aren't functions.
+    pub fn is_host_function(&self) -> Option<bool> {
+        self.value.as_function().map(|f| f.borrow().is_host())
+    }
+}
+
+// How a call into an instance finished
+#[derive(Debug, Clone, PartialEq)]
+pub enum Completion {
+    Returned(Vec<StackEntry>),
+    // The program exited with an exit code, such as with WASI's proc_exit
+    Exited(u32),
+}
+
+// How an instance is set up, for Module::instantiate_with_options
+#[derive(Debug, Clone, Default)]
+pub struct InstanceOptions {
+    // Zeroes what the guest kept in the instance's memories and globals once the instance is
+    // done with them, so secrets it handled aren't left in memory that's been freed. The
+    // instance's own memories are zeroed when they're dropped, and their old contents whenever
+    // memory.grow moves them. That holds even if the host kept hold of an exported one and drops
+    // it later. Imported memories and globals belong to whatever made them, and may be shared
+    // with other instances, so they're only zeroed if the instance had the last reference to them
+    // when it was dropped. Set zero_on_drop on an imported memory to cover its growth as well.
+    //
+    // The data segments are the module's rather than the instance's, and it doesn't copy them, so
+    // there's nothing of theirs for it to zero. The values left on the stack by a call aren't
+    // covered either.
+    pub zero_on_drop: bool,
+    // The fuel instantiation can use, for copying the segments and running the start function.
+    // The instance keeps whatever is left for the calls made to it afterwards, and set_fuel can
+    // change that. None is no limit.
+    pub fuel: Option<u64>,
+    // Stops instantiation, and after that the instance's calls, when it's interrupted
+    pub interrupt: Option<InterruptHandle>,
+    // Leaves the start function for run_start to call, rather than calling it as part of
+    // instantiation, so the host can set things up on the instance first
+    pub defer_start: bool,
+    // Takes the instance's memories and value stack from the pool rather than allocating them.
+    // InstancePool::instantiate sets it.
+    pub pool: Option<InstancePool>,
+}
+
+// An instance is everything a module needs while it is running, with all of its imports resolved
+// and its state initialized. Any number of instances can be made from the same module.
+#[derive(Debug)]
+pub struct Instance {
+    pub functions: Vec<Rc<RefCell<Callable>>>,
+    pub tables: Vec<Rc<RefCell<Table>>>,
+    pub memories: Vec<Rc<RefCell<Memory>>>,
+    pub globals: Vec<Rc<RefCell<Global>>>,
+    pub exports: HashMap<String, Extern>,
+    // The names of the exports in the order the module declares them
+    export_order: Vec<String>,
+    func_types: Vec<Rc<FuncType>>,
+    resolved_imports: Vec<ResolvedImport>,
+    call_observer: Option<Rc<dyn CallObserver>>,
+    trap_snapshot_limits: Option<SnapshotLimits>,
+    // The peaks and counts, with the current sizes filled in when they're asked for
+    metrics: ResourceMetrics,
+    zero_on_drop: bool,
+    fuel: Option<u64>,
+    interrupt: Option<InterruptHandle>,
+    // The start function, if it was deferred and hasn't been run yet, and how to describe it
+    pending_start: Option<(usize, String)>,
+    // The value stack from the pool, if the instance came from one, which calls into the instance
+    // use when it isn't already being used by one further out
+    pooled_stack: Option<PooledStack>,
+    // The module's names, for the error messages
+    names: Rc<NameSection>,
+}
+
+impl Instance {
+    fn new() -> Self {
+        Self {
+            functions: Vec::new(),
+            tables: Vec::new(),
+            memories: Vec::new(),
+            globals: Vec::new(),
+            exports: HashMap::new(),
+            export_order: Vec::new(),
+            func_types: Vec::new(),
+            resolved_imports: Vec::new(),
+            call_observer: None,
+            trap_snapshot_limits: None,
+            metrics: ResourceMetrics::default(),
+            zero_on_drop: false,
+            fuel: None,
+            interrupt: None,
+            pending_start: None,
+            pooled_stack: None,
+            names: Rc::default(),
+        }
+    }
+
+    // The imports of the module in the order they were declared, along with what each was
+    // resolved to
+    pub fn resolved_imports(&self) -> &[ResolvedImport] {
+        &self.resolved_imports
+    }
+
+    // Tells the observer about every call made from now on, or stops telling anyone if there isn't
+    // one. The start function has already run by the time there's an instance to set it on.
+    pub fn set_call_observer(&mut self, observer: Option<Rc<dyn CallObserver>>) {
+        self.call_observer = observer;
+    }
+
+    // Keeps a snapshot of the stack with each trap, within the limits, or stops keeping them.
+    // They're off to start with, since copying the stack out costs something on every trap.
+    pub fn set_trap_snapshots(&mut self, limits: Option<SnapshotLimits>) {
+        self.trap_snapshot_limits = limits;
+    }
+
+    // What the instance is using now, and the most it has used since it was made, or since the
+    // metrics were last reset. A fork starts off with the metrics of what it was forked from.
+    pub fn resource_metrics(&self) -> ResourceMetrics {
+        let mut metrics = self.metrics;
+        metrics.record_memory(self.memory_bytes());
+        metrics.table_elements = self.tables.iter().map(|table| table.borrow().len()).sum();
+        metrics
+    }
+
+    // The fuel the guest has left, or None if it can run for as long as it likes
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Gives the guest this much fuel for the calls from now on, or lets it run without a limit
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    // A handle that interrupts whatever the instance is running, which is made the first time
+    // it's asked for if the instance wasn't given one when it was made
+    pub fn interrupt_handle(&mut self) -> InterruptHandle {
+        self.interrupt
+            .get_or_insert_with(InterruptHandle::new)
+            .clone()
+    }
+
+    // Takes the fuel for some of the guest's work, or traps if there isn't enough of it left or
+    // the instance has been interrupted
+    fn charge_fuel(&mut self, amount: u64) -> Result<()> {
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel < amount {
+                *fuel = 0;
+                return Err(Trap::new(TrapKind::OutOfFuel).into());
+            }
+            *fuel -= amount;
+        }
+        match &self.interrupt {
+            Some(interrupt) if interrupt.take() => Err(Trap::new(TrapKind::Interrupted).into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Starts the peaks again from what's in use now, and the host call count from zero
+    pub fn reset_resource_metrics(&mut self) {
+        self.metrics = ResourceMetrics::default();
+        self.metrics.record_memory(self.memory_bytes());
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.memories
+            .iter()
+            .map(|memory| memory.borrow().len())
+            .sum()
+    }
+
+    // A copy of the instance that can go its own way from here on. The memories, tables and
+    // mutable globals are copied, and the functions are shared, since nothing about them changes
+    // as they run. That includes the host functions that were imported, so any state they keep
+    // is shared too.
+    //
+    // Imported memories, tables and mutable globals belong to whatever they were imported from,
+    // and may be shared with other instances, so an instance with any of them can't be forked.
+    // Watchpoints and write traces on the memories aren't carried over to the copy. The copy
+    // starts with the fuel this one has left, and the same interrupt handle stops either.
+    pub fn fork(&self) -> Result<Instance> {
+        for import in &self.resolved_imports {
+            let shared = match &import.value {
+                Extern::Func(_) => None,
+                Extern::Global(global) if !global.borrow().is_mutable() => None,
+                Extern::Global(_) => Some("mutable global"),
+                Extern::Table(_) => Some("table"),
+                Extern::Memory(_) => Some("memory"),
+            };
+            if let Some(what) = shared {
+                return Err(anyhow!(
+                    "Can't fork an instance that imports a {} {}::{}, since its state isn't the \
+                     instance's to copy",
+                    what,
+                    import.mod_name,
+                    import.name
+                ));
+            }
+        }
+
+        let mut tables = Vec::with_capacity(self.tables.len());
+        for table in &self.tables {
+            tables.push(Rc::new(RefCell::new(table.borrow().clone())));
+        }
+        let mut memories = Vec::with_capacity(self.memories.len());
+        for memory in &self.memories {
+            memories.push(Rc::new(RefCell::new(memory.borrow().try_clone()?)));
+        }
+        // Immutable globals can be shared like the functions are
+        let globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|global| {
+                if global.borrow().is_mutable() {
+                    Rc::new(RefCell::new(global.borrow().clone()))
+                } else {
+                    global.clone()
+                }
+            })
+            .collect();
+
+        // Exports are pointed at the copies of what they exported
+        let exports = self
+            .exports
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Extern::Func(f) => Extern::Func(f.clone()),
+                    Extern::Table(t) => Extern::Table(copy_of(t, &self.tables, &tables)),
+                    Extern::Memory(m) => Extern::Memory(copy_of(m, &self.memories, &memories)),
+                    Extern::Global(g) => Extern::Global(copy_of(g, &self.globals, &globals)),
+                };
+                (name.clone(), value)
+            })
+            .collect();
+
+        Ok(Instance {
+            functions: self.functions.clone(),
+            tables,
+            memories,
+            globals,
+            exports,
+            export_order: self.export_order.clone(),
+            func_types: self.func_types.clone(),
+            resolved_imports: self.resolved_imports.clone(),
+            call_observer: self.call_observer.clone(),
+            trap_snapshot_limits: self.trap_snapshot_limits,
+            metrics: self.metrics,
+            zero_on_drop: self.zero_on_drop,
+            fuel: self.fuel,
+            interrupt: self.interrupt.clone(),
+            pending_start: self.pending_start.clone(),
+            pooled_stack: None,
+            names: self.names.clone(),
+        })
+    }
+
+    // Calls an exported function with the given arguments, and returns its results. The arguments
+    // have to match the function's signature exactly. If the function exits rather than returning,
+    // an exit code of 0 is a success without any results, and anything else is an error with a
+    // TrapKind::Exit in it.
+    pub fn invoke_export(&mut self, name: &str, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        match self.run_export(name, args)? {
+            Completion::Returned(results) => Ok(results),
+            Completion::Exited(0) => Ok(Vec::new()),
+            Completion::Exited(code) => Err(Trap::new(TrapKind::Exit(code)).into()),
+        }
+    }
+
+    // Like invoke_export, except that exiting is never an error, whatever the exit code is
+    pub fn run_export(&mut self, name: &str, args: &[StackEntry]) -> Result<Completion> {
+        match self.call_export(name, args) {
+            Ok(results) => Ok(Completion::Returned(results)),
+            Err(error) => match exit_code(&error) {
+                Some(code) => Ok(Completion::Exited(code)),
+                None => Err(error),
+            },
+        }
+    }
+
+    // Like invoke_export, but also says how much work the call did. If the call fails, the summary
+    // is attached to the error, and ExecutionSummary::from_error gets it back out. The counting is
+    // done by wrapping the instance in another store, so invoke_export itself doesn't pay for it.
+    pub fn invoke_export_counted(
+        &mut self,
+        name: &str,
+        args: &[StackEntry],
+    ) -> Result<(Vec<StackEntry>, ExecutionSummary)> {
+        let function = self.export_function(name, args)?;
+        let fuel = self.fuel;
+        enter_call(name, &function);
+        let (result, summary) = self.with_stack(|instance, stack| {
+            let mut store = CountingStore::new(instance);
+            let result = call_function(&function, args, stack, &mut store);
+            (result, store.summary())
+        });
+        let result = result.map_err(|error| name_trap_memory(error, &self.names));
+        exit_call(&result, fuel, self.fuel);
+
+        match result {
+            Ok(results) => Ok((results, summary)),
+            Err(error) => match exit_code(&error) {
+                Some(0) => Ok((Vec::new(), summary)),
+                _ => Err(error.context(summary)),
+            },
+        }
+    }
+
+    pub(crate) fn call_export(
+        &mut self,
+        name: &str,
+        args: &[StackEntry],
+    ) -> Result<Vec<StackEntry>> {
+        let function = self.export_function(name, args)?;
+        let fuel = self.fuel;
+        enter_call(name, &function);
+        let result = self
+            .with_stack(|instance, stack| call_function(&function, args, stack, instance))
+            .map_err(|error| name_trap_memory(error, &self.names));
+        exit_call(&result, fuel, self.fuel);
+        result
+    }
+
+    // Calls one of the instance's own functions, which needn't be exported, with arguments that
+    // have already been checked against it. It's for something that hands the instance's
+    // functions out to be called on their own, such as the dynamic linker, and isn't a call from
+    // outside, so it has no wasm.call phase of its own.
+    pub(crate) fn call_own_function(
+        &mut self,
+        function: &Rc<RefCell<Callable>>,
+        args: &[StackEntry],
+    ) -> Result<Vec<StackEntry>> {
+        self.with_stack(|instance, stack| call_function(function, args, stack, instance))
+            .map_err(|error| name_trap_memory(error, &self.names))
+    }
+
+    // The exports, in the order the module declares them
+    pub fn exports_in_order(&self) -> impl Iterator<Item = (&str, &Extern)> {
+        self.export_order
+            .iter()
+            .map(move |name| (name.as_str(), &self.exports[name]))
+    }
+
+    // The export with the given name, which has to be of the given kind. If there isn't one, the
+    // error is an ExportNotFound, with the names that might have been meant.
+    pub fn get_export(&self, name: &str, kind: ExportKind) -> Result<&Extern> {
+        match self.exports.get(name) {
+            Some(export) if export.kind() == kind => Ok(export),
+            Some(other) => Err(anyhow!("Export {} is a {}, not a {}", name, other, kind)),
+            None => Err(ExportNotFound::new(
+                name,
+                kind,
+                self.exports_in_order()
+                    .map(|(name, export)| (name, export.kind())),
+            )
+            .into()),
+        }
+    }
+
+    pub fn get_function(&self, name: &str) -> Result<Rc<RefCell<Callable>>> {
+        match self.get_export(name, ExportKind::Function)? {
+            Extern::Func(function) => Ok(function.clone()),
+            _ => unreachable!(),
+        }
+    }
+
+    // The exported function with the given name, once the arguments have been checked against it
+    fn export_function(&self, name: &str, args: &[StackEntry]) -> Result<Rc<RefCell<Callable>>> {
+        let function = self.get_function(name)?;
+
+        let func_type = function.borrow().func_type().clone();
+        if args.len() != func_type.params().len() {
+            return Err(anyhow!(
+                "Function {} {} takes {} arguments, but {} were given",
+                name,
+                func_type,
+                func_type.params().len(),
+                args.len()
+            ));
+        }
+
+        for (idx, (arg, param_type)) in args.iter().zip(func_type.params()).enumerate() {
+            if arg.ty() != *param_type {
+                return Err(anyhow!(
+                    "Argument {} of function {} {} should be {}, but {} was given",
+                    idx,
+                    name,
+                    func_type,
+                    param_type,
+                    arg.ty()
+                ));
+            }
+        }
+
+        Ok(function)
+    }
+
+    fn link_imports<Resolver: core::Resolver>(
+        &mut self,
+        imports: &[core::Import],
+        types: &[FuncType],
+        resolver: &Resolver,
+    ) -> Result<()> {
+        phase_trace::enter(Phase::Link, || {
+            vec![Field::u64("imports", imports.len() as u64)]
+        });
+        let result = self.resolve_imports(imports.iter(), types, resolver);
+        phase_trace::exit_with(Phase::Link, &result, |_| Vec::new());
+        result
+    }
+
+    fn resolve_imports<'a, Iter: Iterator<Item = &'a core::Import>, Resolver: core::Resolver>(
+        &mut self,
+        imports: Iter,
+        types: &[FuncType],
+        resolver: &Resolver,
+    ) -> Result<()> {
+        for import in imports {
+            let error = |kind, reason| ImportError {
+                module: import.mod_name().to_string(),
+                field: import.name().to_string(),
+                kind,
+                reason,
+            };
+
+            let import_type = match import.desc() {
+                core::ImportDesc::TypeIdx(type_idx) => {
+                    let func_type = types.get(*type_idx).ok_or_else(|| {
+                        error(
+                            ExportKind::Function,
+                            ImportErrorReason::InvalidTypeIndex {
+                                type_idx: *type_idx,
+                                type_count: types.len(),
+                            },
+                        )
+                    })?;
+                    ExternType::Func(func_type.clone())
+                }
+                core::ImportDesc::TableType(table_type) => ExternType::Table(table_type.clone()),
+                core::ImportDesc::MemType(mem_type) => ExternType::Memory(mem_type.clone()),
+                core::ImportDesc::GlobalType(global_type) => {
+                    ExternType::Global(global_type.clone())
+                }
+            };
+
+            let kind = import_type.kind();
+            let value = resolver
+                .resolve(import.mod_name(), import.name(), &import_type)
+                .with_context(|| error(kind, ImportErrorReason::Unresolved))?;
+            match (&import_type, &value) {
+                (ExternType::Func(func_type), Extern::Func(function)) => {
+                    let provided = function.borrow().func_type().clone();
+                    if provided != *func_type {
+                        return Err(error(
+                            kind,
+                            ImportErrorReason::FunctionTypeMismatch {
+                                expected: func_type.clone(),
+                                provided,
+                            },
+                        )
+                        .into());
+                    }
+                    self.functions.push(function.clone());
+                }
+                (ExternType::Table(table_type), Extern::Table(table)) => {
+                    let provided = {
+                        let table = table.borrow();
+                        Limits::new(table.current_size(), table.max_size())
+                    };
+                    check_limits(table_type.limits(), provided)
+                        .map_err(|reason| error(kind, reason))?;
+                    self.tables.push(table.clone());
+                }
+                (ExternType::Memory(mem_type), Extern::Memory(memory)) => {
+                    let provided = {
+                        let memory = memory.borrow();
+                        Limits::new(memory.current_size(), memory.max_size())
+                    };
+                    check_limits(mem_type.limits(), provided)
+                        .map_err(|reason| error(kind, reason))?;
+                    self.memories.push(memory.clone());
+                }
+                (ExternType::Global(global_type), Extern::Global(global)) => {
+                    let provided = global.borrow().global_type().clone();
+                    if provided != *global_type {
+                        return Err(error(
+                            kind,
+                            ImportErrorReason::GlobalTypeMismatch {
+                                expected: global_type.clone(),
+                                provided,
+                            },
+                        )
+                        .into());
+                    }
+                    self.globals.push(global.clone());
+                }
+                _ => {
+                    return Err(error(
+                        kind,
+                        ImportErrorReason::KindMismatch {
+                            provided: value.kind(),
+                        },
+                    )
+                    .into())
+                }
+            }
+
+            phase_trace::event(Phase::Link, "import", || {
+                vec![
+                    Field::str("module", import.mod_name()),
+                    Field::str("name", import.name()),
+                    Field::str("kind", &kind.to_string()),
+                ]
+            });
+            self.resolved_imports.push(ResolvedImport {
+                mod_name: import.mod_name().to_string(),
+                name: import.name().to_string(),
+                import_type,
+                value,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn add_functions<'a, Iter: Iterator<Item = (&'a usize, &'a core::Func)>>(
+        &mut self,
+        functions: Iter,
+        types: &[Rc<FuncType>],
+    ) -> Result<()> {
+        for (type_idx, func) in functions {
+            if *type_idx >= types.len() {
+                return Err(anyhow!("Function has invalid type index"));
+            }
+
+            let func_idx = self.functions.len();
+            self.functions
+                .push(Rc::new(RefCell::new(core::WasmExprCallable::new_at_index(
+                    func_idx,
+                    types[*type_idx].clone(),
+                    func,
+                ))));
+        }
+        Ok(())
+    }
+
+    fn add_tables<'a, Iter: Iterator<Item = &'a core::TableType>>(
+        &mut self,
+        tables: Iter,
+    ) -> Result<()> {
+        for table in tables {
+            self.tables
+                .push(Rc::new(RefCell::new(Table::new(table.clone()))));
+        }
+
+        Ok(())
+    }
+
+    // Runs the call on the pooled stack if there is one and it's free, and on a new one otherwise
+    fn with_stack<T>(&mut self, call: impl FnOnce(&mut Self, &mut Stack) -> T) -> T {
+        match self.pooled_stack.take() {
+            Some(mut pooled) => {
+                let result = call(self, pooled.stack_mut());
+                pooled.stack_mut().clear();
+                self.pooled_stack = Some(pooled);
+                result
+            }
+            None => call(self, &mut Stack::new()),
+        }
+    }
+
+    fn add_pooled_memories<'a, Iter: Iterator<Item = &'a core::MemType>>(
+        &mut self,
+        memories: Iter,
+        slot: PoolSlot,
+    ) -> Result<()> {
+        self.pooled_stack = Some(slot.stack);
+        for (memory, buffer) in memories.zip(slot.memories) {
+            let memory = Memory::new_pooled(memory.clone(), buffer).with_context(|| {
+                format!(
+                    "Memory {} needs {} pages",
+                    self.memories.len(),
+                    memory.limits().min()
+                )
+            })?;
+            self.memories.push(Rc::new(RefCell::new(memory)));
+        }
+
+        Ok(())
+    }
+
+    fn add_memories<'a, Iter: Iterator<Item = &'a core::MemType>>(
+        &mut self,
+        memories: Iter,
+    ) -> Result<()> {
+        for memory in memories {
+            self.memories
+                .push(Rc::new(RefCell::new(Memory::new(memory.clone()))));
+        }
+
+        Ok(())
+    }
+
+    // Errors name the global, which is after any that were imported
+    fn add_globals(&mut self, module: &Module) -> Result<()> {
+        for global in module.raw_module().globals.iter() {
+            let global_type = global.global_type().clone();
+            let init_expr = global.init_expr();
+
+            let global_idx = self.globals.len();
+            let global = evaluate_constant_expression(init_expr, self, 1)
+                .and_then(|results| Global::new(global_type, results[0]))
+                .with_context(|| {
+                    format!("Couldn't initialize {}", module.describe_global(global_idx))
+                })?;
+
+            self.globals.push(Rc::new(RefCell::new(global)));
+        }
+
+        Ok(())
+    }
+
+    fn collect_single_export<T>(idx: usize, items: &Vec<std::rc::Rc<T>>) -> Result<std::rc::Rc<T>> {
+        if idx >= items.len() {
+            return Err(anyhow!("Export has invalid index"));
+        }
+
+        Ok(items[idx].clone())
+    }
+
+    fn collect_exports<'a, Iter: Iterator<Item = &'a core::Export>>(
+        &mut self,
+        exports: Iter,
+    ) -> Result<()> {
+        for core::Export { nm, d } in exports {
+            let nm = nm.clone();
+            if !self.exports.contains_key(&nm) {
+                self.export_order.push(nm.clone());
+            }
+            match *d {
+                core::ExportDesc::Func(idx) => {
+                    self.exports.insert(
+                        nm,
+                        Extern::Func(Self::collect_single_export(idx, &self.functions)?),
+                    );
+                }
+                core::ExportDesc::Table(idx) => {
+                    self.exports.insert(
+                        nm,
+                        Extern::Table(Self::collect_single_export(idx, &self.tables)?),
+                    );
+                }
+                core::ExportDesc::Mem(idx) => {
+                    self.exports.insert(
+                        nm,
+                        Extern::Memory(Self::collect_single_export(idx, &self.memories)?),
+                    );
+                }
+                core::ExportDesc::Global(idx) => {
+                    self.exports.insert(
+                        nm,
+                        Extern::Global(Self::collect_single_export(idx, &self.globals)?),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_func_types(&mut self, func_types: &[Rc<FuncType>]) -> Result<()> {
+        self.func_types = func_types.to_vec();
+        Ok(())
+    }
+
+    fn pre_execute_validate(&self) -> Result<()> {
+        if self.tables.len() > 1 {
+            Err(anyhow!("Too many tables"))
+        } else if self.memories.len() > 1 {
+            Err(anyhow!("Too many memoryies"))
+        } else {
+            Ok(())
+        }
+    }
+
+    // The function each of the segment's entries refers to, or None for a null reference
+    fn element_func_refs(&self, element: &core::Element) -> Result<Vec<Option<usize>>> {
+        let func_refs = match element.items() {
+            core::ElementItems::FuncIndices(func_indices) => func_indices
+                .iter()
+                .map(|func_idx| Some(*func_idx))
+                .collect(),
+            core::ElementItems::Exprs(exprs) => exprs
+                .iter()
+                .map(|expr| evaluate_reference_expression(expr, self))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        if let Some(func_idx) = func_refs
+            .iter()
+            .flatten()
+            .find(|idx| **idx >= self.functions.len())
+        {
+            return Err(anyhow!("Function index {} out of range", func_idx));
+        }
+        Ok(func_refs)
+    }
+
+    // Only an active segment is written into its table. The others are still evaluated, so that
+    // a segment that refers to a function that isn't there fails here either way.
+    fn initialize_table_element(&self, element: &core::Element) -> Result<()> {
+        let func_refs = self.element_func_refs(element)?;
+        match element.mode() {
+            core::ElementMode::Active { table_idx, offset } => {
+                if *table_idx >= self.tables.len() {
+                    return Err(anyhow!("Table initializer table idx out of range"));
+                }
+                let offset = self.evaluate_offset_expression(offset)?;
+                self.tables[*table_idx].borrow_mut().init_from_func_refs(
+                    offset,
+                    &func_refs,
+                    &self.functions,
+                )
+            }
+            core::ElementMode::Passive | core::ElementMode::Declared => Ok(()),
+        }
+    }
+
+    fn initialize_table_elements<'a, Iter: Iterator<Item = &'a core::Element>>(
+        &mut self,
+        iter: Iter,
+    ) -> Result<()> {
+        let mut segments = 0;
+        for element in iter {
+            self.charge_fuel(element.len() as u64)?;
+            self.initialize_table_element(element)?;
+            segments += 1;
+        }
+
+        phase_trace::event(Phase::Instantiate, "elements", || {
+            vec![Field::u64("segments", segments)]
+        });
+        Ok(())
+    }
+
+    fn initialize_memory_data(&self, data: &core::Data) -> Result<()> {
+        if data.mem_idx() >= self.memories.len() {
+            Err(anyhow!("Memory initializer mem idx out of range"))
+        } else {
+            let memory = &self.memories[data.mem_idx()];
+            let offset = self.evaluate_offset_expression(data.expr())?;
+
+            let data = data.bytes();
+
+            memory.borrow_mut().set_data(offset, data)?;
+
+            Ok(())
+        }
+    }
+
+    fn initialize_memory(&mut self, module: &Module) -> Result<()> {
+        let names = module.raw_module().names();
+        for (data_idx, data) in module.raw_module().data.iter().enumerate() {
+            let len = data.bytes().len();
+            let cost = len / DATA_BYTES_PER_FUEL + (len % DATA_BYTES_PER_FUEL != 0) as usize;
+            self.charge_fuel(cost as u64)?;
+            self.initialize_memory_data(data).with_context(|| {
+                let segment = match names.data_name(data_idx) {
+                    Some(name) => format!("{} '{}'", data_idx, name),
+                    None => format!("{}", data_idx),
+                };
+                format!(
+                    "Data segment {} couldn't be written to {}",
+                    segment,
+                    module.describe_memory(data.mem_idx())
+                )
+            })?;
+        }
+
+        phase_trace::event(Phase::Instantiate, "data", || {
+            let data = &module.raw_module().data;
+            let bytes: usize = data.iter().map(|data| data.bytes().len()).sum();
+            vec![
+                Field::u64("segments", data.len() as u64),
+                Field::u64("bytes", bytes as u64),
+            ]
+        });
+        Ok(())
+    }
+
+    fn evaluate_offset_expression(&self, expr: &impl InstructionSource) -> Result<usize> {
+        let result = evaluate_constant_expression(expr, self, 1)?;
+
+        match result[0] {
+            StackEntry::I32Entry(i) => Ok(usize::try_from(i).unwrap()),
+            _ => Err(anyhow!("Type mismatch in offset expression")),
+        }
+    }
+
+    pub fn new_from_module<Resolver: core::Resolver>(
+        module: &Module,
+        resolver: &Resolver,
+    ) -> Result<Instance> {
+        Self::new_from_module_with_options(module, resolver, &InstanceOptions::default())
+    }
+
+    // Running out of fuel or being interrupted comes back as an InstantiationBudgetExceeded,
+    // with the trap that stopped it as its cause
+    pub fn new_from_module_with_options<Resolver: core::Resolver>(
+        module: &Module,
+        resolver: &Resolver,
+        options: &InstanceOptions,
+    ) -> Result<Instance> {
+        phase_trace::enter(Phase::Instantiate, || {
+            let raw = module.raw_module();
+            vec![
+                Field::u64(
+                    "functions",
+                    (module.num_imported_functions() + raw.funcs.len()) as u64,
+                ),
+                Field::u64(
+                    "tables",
+                    (module.num_imported_tables() + raw.tables.len()) as u64,
+                ),
+                Field::u64(
+                    "memories",
+                    (module.num_imported_memories() + raw.mems.len()) as u64,
+                ),
+                Field::u64(
+                    "globals",
+                    (module.num_imported_globals() + raw.globals.len()) as u64,
+                ),
+            ]
+        });
+        let result = Self::instantiate(module, resolver, options).map_err(budget_context);
+        phase_trace::exit_with(Phase::Instantiate, &result, |_| Vec::new());
+        result
+    }
+
+    // Runs the start function, if instantiation was told to defer it and it hasn't been run yet.
+    // It fails the same way it would have as part of instantiation, and either way it isn't run
+    // again.
+    pub fn run_start(&mut self) -> Result<()> {
+        match self.pending_start.take() {
+            Some((start, description)) => {
+                self.call_start(start, &description).map_err(budget_context)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn has_pending_start(&self) -> bool {
+        self.pending_start.is_some()
+    }
+
+    // The start function can be an imported one, as it's an index into the function index space
+    // with the imports first, the same as the instance's functions
+    fn call_start(&mut self, start: usize, description: &str) -> Result<()> {
+        // Copied out, so it isn't borrowed while it runs
+        let start_func = self.functions[start].borrow().clone();
+        let fuel = self.fuel;
+        enter_call(description, &self.functions[start]);
+        let result = self
+            .with_stack(|instance, stack| start_func.call(stack, instance))
+            .map_err(|error| name_trap_memory(error, &self.names));
+        exit_call(&result, fuel, self.fuel);
+        result.with_context(|| format!("Start function {} failed", description))
+    }
+
+    fn instantiate<Resolver: core::Resolver>(
+        module: &Module,
+        resolver: &Resolver,
+        options: &InstanceOptions,
+    ) -> Result<Instance> {
+        let raw = module.raw_module();
+        let types = &raw.metadata.types;
+        if let Some(start) = raw.start {
+            check_start_type(module, start)?;
+        }
+
+        let mut instance = Self::new();
+        instance.names = raw.names.clone();
+        instance.zero_on_drop = options.zero_on_drop;
+        instance.fuel = options.fuel;
+        instance.interrupt = options.interrupt.clone();
+        instance.link_imports(&raw.imports, types, resolver)?;
+        instance.add_functions(
+            raw.typeidx.iter().zip(raw.funcs.iter()),
+            module.shared_func_types(),
+        )?;
+        instance.add_tables(raw.tables.iter())?;
+        match &options.pool {
+            Some(pool) => {
+                let slot = pool.acquire(raw.mems.len())?;
+                instance.add_pooled_memories(raw.mems.iter(), slot)?;
+            }
+            None => instance.add_memories(raw.mems.iter())?,
+        }
+        if options.zero_on_drop {
+            for memory in &instance.memories[module.num_imported_memories()..] {
+                memory.borrow_mut().set_zero_on_drop(true);
+            }
+        }
+        instance.add_globals(module)?;
+        instance.collect_exports(raw.exports.iter())?;
+        instance.add_func_types(module.shared_func_types())?;
+
+        // Everything prior to this point is setting up the environment so that we
+        // can start executing things, so make sure that everything is sane once we're
+        // at that point.
+        instance.pre_execute_validate()?;
+
+        // The next step is to initialize the tables and memories.
+        instance.initialize_table_elements(raw.elem.iter())?;
+        instance.initialize_memory(module)?;
+
+        // Finally, if there is a start function specified then execute it.
+        if let Some(start) = raw.start {
+            let description = module.describe_func(start);
+            phase_trace::event(Phase::Instantiate, "start", || {
+                vec![
+                    Field::u64("func_idx", start as u64),
+                    Field::bool("deferred", options.defer_start),
+                ]
+            });
+            if options.defer_start {
+                instance.pending_start = Some((start, description));
+            } else {
+                instance.call_start(start, &description)?;
+            }
+        }
+
+        Ok(instance)
+    }
+}
+
+// The start function has to exist, and take and return nothing. An imported one is checked
+// against the type its import declares, which is what it was resolved against.
+fn check_start_type(module: &Module, start: usize) -> Result<()> {
+    match module.func_type(start) {
+        Some(func_type) if func_type.params().is_empty() && func_type.results().is_empty() => {
+            Ok(())
+        }
+        Some(func_type) => Err(anyhow!(
+            "Start function {} has type {}, but it must take and return nothing",
+            module.describe_func(start),
+            func_type
+        )),
+        None => Err(anyhow!(
+            "Start function {} not found",
+            module.describe_func(start)
+        )),
+    }
+}
+
+// Says the budget was exceeded, if that's what stopped the start function or instantiation
+fn budget_context(error: anyhow::Error) -> anyhow::Error {
+    match InstantiationBudgetExceeded::from_error(&error) {
+        Some(exceeded) => error.context(exceeded),
+        None => error,
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        if !self.zero_on_drop {
+            return;
+        }
+
+        // The exports and the resolved imports hold references of their own, which have to go
+        // before it can be told whether the instance has the last one
+        self.exports.clear();
+        self.resolved_imports.clear();
+        for memory in &self.memories {
+            if Rc::strong_count(memory) == 1 {
+                memory.borrow_mut().set_zero_on_drop(true);
+            }
+        }
+        for global in &self.globals {
+            if Rc::strong_count(global) == 1 {
+                global.borrow_mut().zeroize();
+            }
+        }
+    }
+}
+
+fn check_limits(expected: &Limits, provided: Limits) -> Result<(), ImportErrorReason> {
+    if provided.matches(expected) {
+        Ok(())
+    } else {
+        Err(ImportErrorReason::LimitsMismatch {
+            expected: expected.clone(),
+            provided,
+        })
+    }
+}
+
+// The copy of an item that's at the same index in the copies as it is in the originals
+fn copy_of<T>(
+    item: &Rc<RefCell<T>>,
+    items: &[Rc<RefCell<T>>],
+    copies: &[Rc<RefCell<T>>],
+) -> Rc<RefCell<T>> {
+    let idx = items.iter().position(|i| Rc::ptr_eq(i, item));
+    copies[idx.expect("An export should be one of the instance's own")].clone()
+}
+
+// Runs a function whose arguments have already been checked, against whichever store is given
+fn enter_call(name: &str, function: &Rc<RefCell<Callable>>) {
+    phase_trace::enter(Phase::Call, || {
+        let mut fields = vec![Field::str("name", name)];
+        if let Some(func_idx) = function.borrow().func_idx() {
+            fields.push(Field::u64("func_idx", func_idx as u64));
+        }
+        fields
+    });
+}
+
+// The fuel the call used is how much less there is after it than before, which is only known if
+// there was a limit on it
+fn exit_call<T>(result: &Result<T>, fuel_before: Option<u64>, fuel_after: Option<u64>) {
+    phase_trace::exit(Phase::Call, || {
+        let outcome = match result {
+            Ok(_) => "returned",
+            Err(error) if exit_code(error).is_some() => "exited",
+            Err(_) => "trapped",
+        };
+        let mut fields = vec![Field::str("outcome", outcome)];
+        if let (Some(before), Some(after)) = (fuel_before, fuel_after) {
+            fields.push(Field::u64("fuel_consumed", before.saturating_sub(after)));
+        }
+        fields
+    });
+}
+
+fn call_function(
+    function: &Rc<RefCell<Callable>>,
+    args: &[StackEntry],
+    stack: &mut Stack,
+    store: &mut impl ExpressionStore,
+) -> Result<Vec<StackEntry>> {
+    // The function is copied out, so it isn't borrowed while it runs
+    let function = function.borrow().clone();
+    let result_count = function.func_type().results().len();
+
+    stack.push_from_slice(args);
+    function.call(stack, store)?;
+
+    Ok(stack.working_top(result_count).to_vec())
+}
+
+impl ConstantExpressionStore for Instance {
+    type GlobalRef = CellRefType<Global>;
+
+    fn global_idx<'a>(&'a self, idx: usize) -> Result<Ref<'a, Global>> {
+        if idx < self.globals.len() {
+            Ok(self.globals[idx].borrow())
+        } else {
+            Err(anyhow!("Global index out of range"))
+        }
+    }
+}
+
+impl ExpressionStore for Instance {
+    type GlobalRefMut = CellRefMutType<Global>;
+    type FuncTypeRef = RefType<FuncType>;
+    type TableRef = CellRefType<Table>;
+    type CallableRef = CellRefType<Callable>;
+    type MemoryRef = CellRefType<Memory>;
+    type MemoryRefMut = CellRefMutType<Memory>;
+
+    fn global_idx_mut<'a>(&'a mut self, idx: usize) -> Result<RefMut<'a, Global>> {
+        if idx < self.globals.len() {
+            Ok(self.globals[idx].borrow_mut())
+        } else {
+            Err(anyhow!("Global index out of range"))
+        }
+    }
+
+    fn func_type_idx<'a>(&'a self, idx: usize) -> Result<&'a FuncType> {
+        if idx < self.func_types.len() {
+            Ok(&*self.func_types[idx])
+        } else {
+            Err(anyhow!("FuncType index out of range"))
+        }
+    }
+
+    fn table_idx<'a>(&'a self, idx: usize) -> Result<Ref<'a, Table>> {
+        if idx < self.tables.len() {
+            Ok(self.tables[idx].borrow())
+        } else {
+            Err(anyhow!("Table index out of range"))
+        }
+    }
+
+    fn callable_idx<'a>(&'a self, idx: usize) -> Result<Ref<'a, Callable>> {
+        if idx < self.functions.len() {
+            Ok(self.functions[idx].borrow())
+        } else {
+            Err(anyhow!("Callable index out of range"))
+        }
+    }
+
+    fn mem_idx<'a>(&'a self, idx: usize) -> Result<Ref<'a, Memory>> {
+        if idx < self.memories.len() {
+            Ok(self.memories[idx].borrow())
+        } else {
+            Err(anyhow!("Memory index out of range"))
+        }
+    }
+
+    fn mem_idx_mut<'a>(&'a mut self, idx: usize) -> Result<RefMut<'a, Memory>> {
+        if idx < self.memories.len() {
+            Ok(self.memories[idx].borrow_mut())
+        } else {
+            Err(anyhow!("Memory index out of range"))
+        }
+    }
+
+    fn call_observer(&self) -> Option<Rc<dyn CallObserver>> {
+        self.call_observer.clone()
+    }
+
+    fn caller(&mut self) -> Caller<'_> {
+        Caller::new(self)
+    }
+
+    fn trap_snapshot_limits(&self) -> Option<SnapshotLimits> {
+        self.trap_snapshot_limits
+    }
+
+    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<()> {
+        self.mem_idx_mut(mem_idx)?.grow_by(grow_by)?;
+        self.metrics.record_memory(self.memory_bytes());
+        Ok(())
+    }
+
+    fn on_call(&mut self, stack: &Stack, is_host: bool) {
+        self.metrics.record_call(stack, is_host);
+    }
+
+    fn on_instruction(&mut self, _stack: &Stack) -> Result<()> {
+        self.charge_fuel(1)
+    }
+}
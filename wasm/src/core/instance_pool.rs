@@ -0,0 +1,352 @@
+// Memories and value stacks allocated up front and reused, for a host that makes and drops a lot
+// of short lived instances, where allocating them would otherwise be most of the cost. An
+// instance made through the pool takes a slot, which is a value stack and up to
+// memories_per_instance memory buffers, and gives it back when it's dropped.
+//
+// A pooled memory is a buffer as big as the pool's memories can be, so growing it never moves
+// it, and it can't grow any bigger than that: memory.grow returns -1, as it does for a memory
+// that's at its maximum. A module whose memories start bigger than that can't be instantiated
+// from the pool. Each memory goes back to the pool when it's dropped, which is when the instance
+// is unless the host or another instance has kept hold of it, and the part of it that was used is
+// zeroed first. The value stack, which is where the locals are kept too, goes back when the
+// instance is dropped.
+//
+// The pool can be shared between threads, each making instances of its own from it. When every
+// slot is taken, instantiating either fails with PoolExhausted or waits for another thread to give
+// one back, depending on the pool's PoolExhaustion.
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use crate::core::{
+    memory_page::WASM_PAGE_SIZE_IN_BYTES, stack_entry::StackEntry, Instance, InstanceOptions,
+    Module, Resolver, Stack,
+};
+
+// What instantiating does when the pool has no slots free
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolExhaustion {
+    // Fails with PoolExhausted
+    Error,
+    // Waits for an instance on another thread to be dropped, for up to the timeout if there is
+    // one, and then fails with PoolExhausted. With no timeout and no other thread using the pool,
+    // it waits forever.
+    Block(Option<Duration>),
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    // How many instances can be using the pool at once
+    pub instances: usize,
+    // How many memories each instance can define. Imported ones don't count.
+    pub memories_per_instance: usize,
+    // The most pages each memory can have
+    pub memory_pages: usize,
+    // How many values each instance's stack has room for before it has to grow. It can still grow
+    // past that, but then it's reallocated like any other stack.
+    pub stack_entries: usize,
+    pub exhaustion: PoolExhaustion,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            instances: 16,
+            memories_per_instance: 1,
+            memory_pages: 16,
+            stack_entries: 1024,
+            exhaustion: PoolExhaustion::Error,
+        }
+    }
+}
+
+// Instantiation couldn't get a slot from the pool. It's the error itself, so it can be found with
+// downcast_ref. Memories is when there were instance slots free, but the memories of instances
+// that have been dropped were being kept alive elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolExhausted {
+    Instances(usize),
+    Memories(usize),
+}
+
+impl fmt::Display for PoolExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolExhausted::Instances(count) => {
+                write!(f, "All {} of the pool's instance slots are in use", count)
+            }
+            PoolExhausted::Memories(count) => {
+                write!(f, "All {} of the pool's memories are in use", count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PoolExhausted {}
+
+#[derive(Debug)]
+struct FreeSlots {
+    memories: Vec<Vec<u8>>,
+    stacks: Vec<Vec<StackEntry>>,
+    // How many threads are waiting for something to be given back, so that giving it back only
+    // wakes them when there are some
+    waiting: usize,
+}
+
+#[derive(Debug)]
+struct PoolShared {
+    config: PoolConfig,
+    free: Mutex<FreeSlots>,
+    // Signalled when anything is given back while there are threads waiting
+    returned: Condvar,
+}
+
+impl PoolShared {
+    fn memory_len(&self) -> usize {
+        self.config.memory_pages * WASM_PAGE_SIZE_IN_BYTES
+    }
+
+    // A panic on another thread while it held the lock can't have left the free lists half
+    // changed, so they're used as they are
+    fn lock(&self) -> MutexGuard<'_, FreeSlots> {
+        self.free
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn give_back(&self, give_back: impl FnOnce(&mut FreeSlots)) {
+        let mut free = self.lock();
+        give_back(&mut free);
+        if free.waiting > 0 {
+            self.returned.notify_all();
+        }
+    }
+}
+
+// Cloning it gives another handle to the same pool
+#[derive(Clone)]
+pub struct InstancePool {
+    shared: Arc<PoolShared>,
+}
+
+impl InstancePool {
+    // Allocates everything the pool hands out. The memories are allocated zeroed, which the
+    // allocator can usually do without touching them until they're used.
+    pub fn new(config: PoolConfig) -> Self {
+        let memory_len = config.memory_pages * WASM_PAGE_SIZE_IN_BYTES;
+        let memories = (0..config.instances * config.memories_per_instance)
+            .map(|_| vec![0; memory_len])
+            .collect();
+        let stacks = (0..config.instances)
+            .map(|_| Vec::with_capacity(config.stack_entries))
+            .collect();
+
+        Self {
+            shared: Arc::new(PoolShared {
+                config,
+                free: Mutex::new(FreeSlots {
+                    memories,
+                    stacks,
+                    waiting: 0,
+                }),
+                returned: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.shared.config
+    }
+
+    // How many more instances could be made right now
+    pub fn available(&self) -> usize {
+        self.shared.lock().stacks.len()
+    }
+
+    pub fn available_memories(&self) -> usize {
+        self.shared.lock().memories.len()
+    }
+
+    pub fn instantiate<R: Resolver>(&self, module: &Module, resolver: &R) -> Result<Instance> {
+        self.instantiate_with_options(module, resolver, &InstanceOptions::default())
+    }
+
+    // The options' pool is replaced with this one
+    pub fn instantiate_with_options<R: Resolver>(
+        &self,
+        module: &Module,
+        resolver: &R,
+        options: &InstanceOptions,
+    ) -> Result<Instance> {
+        let options = InstanceOptions {
+            pool: Some(self.clone()),
+            ..options.clone()
+        };
+        module.instantiate_with_options(resolver, &options)
+    }
+
+    // A slot with the given number of memories, waiting for one if the pool is set to
+    pub(crate) fn acquire(&self, memories: usize) -> Result<PoolSlot> {
+        let config = &self.shared.config;
+        if memories > config.memories_per_instance {
+            return Err(anyhow!(
+                "The module defines {} memories, but instances from the pool can only have {}",
+                memories,
+                config.memories_per_instance
+            ));
+        }
+
+        let deadline = match config.exhaustion {
+            PoolExhaustion::Block(Some(timeout)) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+        let mut free = self.shared.lock();
+        loop {
+            let exhausted = if free.stacks.is_empty() {
+                Some(PoolExhausted::Instances(config.instances))
+            } else if free.memories.len() < memories {
+                Some(PoolExhausted::Memories(
+                    config.instances * config.memories_per_instance,
+                ))
+            } else {
+                None
+            };
+
+            let exhausted = match exhausted {
+                Some(exhausted) => exhausted,
+                None => break,
+            };
+            let timeout = match (config.exhaustion, deadline) {
+                (PoolExhaustion::Error, _) => return Err(exhausted.into()),
+                (PoolExhaustion::Block(_), None) => None,
+                (PoolExhaustion::Block(_), Some(deadline)) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(exhausted.into());
+                    }
+                    Some(deadline - now)
+                }
+            };
+
+            free.waiting += 1;
+            let returned = &self.shared.returned;
+            free = match timeout {
+                None => returned
+                    .wait(free)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+                Some(timeout) => {
+                    returned
+                        .wait_timeout(free, timeout)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .0
+                }
+            };
+            free.waiting -= 1;
+        }
+
+        let stack = free.stacks.pop().unwrap();
+        let split = free.memories.len() - memories;
+        let buffers = free.memories.split_off(split);
+        drop(free);
+
+        Ok(PoolSlot {
+            stack: PooledStack {
+                stack: Stack::from_entries(stack),
+                pool: self.shared.clone(),
+            },
+            memories: buffers
+                .into_iter()
+                .map(|bytes| PooledBuffer {
+                    bytes,
+                    pool: self.shared.clone(),
+                })
+                .collect(),
+        })
+    }
+}
+
+impl fmt::Debug for InstancePool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstancePool")
+            .field("config", &self.shared.config)
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+pub(crate) struct PoolSlot {
+    pub(crate) stack: PooledStack,
+    pub(crate) memories: Vec<PooledBuffer>,
+}
+
+// A memory's contents, which are as long as the memory is now, in a buffer with room for as many
+// pages as the pool's memories can have
+pub(crate) struct PooledBuffer {
+    bytes: Vec<u8>,
+    pool: Arc<PoolShared>,
+}
+
+impl PooledBuffer {
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub(crate) fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    // Its capacity is the pool's memory size, so it's never reallocated. It's only made shorter
+    // when it's handed out, which is when it's all zeroes anyway.
+    pub(crate) fn resize(&mut self, new_len: usize) -> Result<()> {
+        if new_len > self.pool.memory_len() {
+            return Err(anyhow!(
+                "Memories from the pool can't be bigger than {} pages",
+                self.pool.config.memory_pages
+            ));
+        }
+        self.bytes.resize(new_len, 0);
+        Ok(())
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut bytes = std::mem::take(&mut self.bytes);
+        zero(&mut bytes);
+        self.pool.give_back(|free| free.memories.push(bytes));
+    }
+}
+
+// Compiles to a memset. It doesn't need to be volatile, as the pool keeps the buffer.
+fn zero(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        *byte = 0;
+    }
+}
+
+pub(crate) struct PooledStack {
+    stack: Stack,
+    pool: Arc<PoolShared>,
+}
+
+impl PooledStack {
+    pub(crate) fn stack_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+}
+
+impl Drop for PooledStack {
+    fn drop(&mut self) {
+        let stack = std::mem::replace(&mut self.stack, Stack::new());
+        self.pool
+            .give_back(|free| free.stacks.push(stack.into_entries()));
+    }
+}
+
+impl fmt::Debug for PooledStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledStack").finish()
+    }
+}
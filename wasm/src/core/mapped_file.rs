@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::AsRawFd;
+
+const PROT_READ: c_int = 1;
+const MAP_PRIVATE: c_int = 2;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+// A whole file mapped read only into memory. The pages are only read in from the file when
+// they're touched, and they can be dropped again under memory pressure since they're backed by
+// the file rather than by swap.
+pub(crate) struct MappedFile {
+    addr: *mut c_void,
+    len: usize,
+}
+
+impl MappedFile {
+    // The file mustn't be changed while the mapping is alive, since the bytes handed out by
+    // bytes() would change under whoever is holding them
+    pub(crate) unsafe fn open(path: &str) -> Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(anyhow!("Can't map {} because it's empty", path));
+        }
+
+        // The mapping stays valid after the file is closed
+        let addr = mmap(
+            std::ptr::null_mut(),
+            len,
+            PROT_READ,
+            MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        );
+        if addr as isize == -1 {
+            return Err(anyhow!(
+                "Failed to map {}: {}",
+                path,
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        Ok(Self { addr, len })
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.addr, self.len);
+        }
+    }
+}
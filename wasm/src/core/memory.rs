@@ -1,65 +1,311 @@
 use std::{
-    cmp::min,
-    ops::{Index, IndexMut},
+    fmt,
+    ops::{Index, IndexMut, Range},
+    ptr,
+    rc::Rc,
+    sync::atomic::{compiler_fence, Ordering},
 };
 
-use crate::core::{memory_page::*, Limits, MemType};
+use crate::core::write_trace::WriteTrace;
+use crate::core::{executor::memory_access::LEByteConvert, memory_page::*, Limits, MemType};
+use crate::core::{PooledBuffer, Trap, TrapKind, WriteSink, WriteTraceOptions};
 use anyhow::{anyhow, Result};
+use generic_array::GenericArray;
 
-#[derive(Debug)]
-pub struct Memory {
-    minimum_pages: usize,
-    maximum_pages: Option<usize>,
-    pages: Vec<MemoryPage>,
+#[cfg(all(
+    feature = "guard-pages",
+    any(unix, windows),
+    target_pointer_width = "64"
+))]
+use crate::core::reserved_memory::ReservedMemory;
+
+// Where the contents are kept
+enum Storage {
+    Heap(Vec<u8>),
+    Pooled(PooledBuffer),
+    #[cfg(all(
+        feature = "guard-pages",
+        any(unix, windows),
+        target_pointer_width = "64"
+    ))]
+    Reserved(ReservedMemory),
 }
 
-impl Memory {
-    pub fn new(mem_type: MemType) -> Self {
-        let (minimum_pages, maximum_pages): (usize, Option<usize>) = match mem_type.limits() {
-            Limits::Bounded(minimum_pages, maximum_pages) => (*minimum_pages, Some(*maximum_pages)),
-            Limits::Unbounded(minimum_pages) => (*minimum_pages, None),
-        };
+impl Storage {
+    #[cfg(all(
+        feature = "guard-pages",
+        any(unix, windows),
+        target_pointer_width = "64"
+    ))]
+    fn new(mem_type: &MemType) -> Self {
+        let max_pages = mem_type.limits().max().unwrap_or(WASM_MAX_PAGES);
+        let reserved =
+            ReservedMemory::reserve(max_pages.min(WASM_MAX_PAGES) * WASM_PAGE_SIZE_IN_BYTES)
+                .and_then(|mut reserved| {
+                    reserved.commit(mem_type.limits().min() * WASM_PAGE_SIZE_IN_BYTES)?;
+                    Ok(reserved)
+                });
+
+        // Running out of address space isn't a reason to fail, since the heap still works
+        match reserved {
+            Ok(reserved) => Storage::Reserved(reserved),
+            Err(_) => Storage::Heap(vec![0; mem_type.limits().min() * WASM_PAGE_SIZE_IN_BYTES]),
+        }
+    }
 
-        Self::new_from_bounds(minimum_pages, maximum_pages)
+    #[cfg(not(all(
+        feature = "guard-pages",
+        any(unix, windows),
+        target_pointer_width = "64"
+    )))]
+    fn new(mem_type: &MemType) -> Self {
+        Storage::Heap(vec![0; mem_type.limits().min() * WASM_PAGE_SIZE_IN_BYTES])
     }
 
-    pub fn new_from_bounds(minimum_pages: usize, maximum_pages: Option<usize>) -> Self {
-        let mut pages = Vec::with_capacity(minimum_pages);
-        for _ in 0..minimum_pages {
-            pages.push(MemoryPage::new())
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Storage::Heap(bytes) => bytes,
+            Storage::Pooled(buffer) => buffer.bytes(),
+            #[cfg(all(
+                feature = "guard-pages",
+                any(unix, windows),
+                target_pointer_width = "64"
+            ))]
+            Storage::Reserved(reserved) => reserved.as_slice(),
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        match self {
+            Storage::Heap(bytes) => bytes,
+            Storage::Pooled(buffer) => buffer.bytes_mut(),
+            #[cfg(all(
+                feature = "guard-pages",
+                any(unix, windows),
+                target_pointer_width = "64"
+            ))]
+            Storage::Reserved(reserved) => reserved.as_mut_slice(),
+        }
+    }
+
+    // The new bytes are zeroed. When the contents have to move to make room, the old copy is
+    // zeroed before it's freed if scrub is set.
+    fn resize(&mut self, new_len: usize, scrub: bool) -> Result<()> {
+        match self {
+            Storage::Heap(bytes) if scrub && new_len > bytes.capacity() => {
+                let mut moved = Vec::with_capacity(new_len);
+                moved.extend_from_slice(bytes);
+                moved.resize(new_len, 0);
+                zeroize(bytes);
+                *bytes = moved;
+            }
+            Storage::Heap(bytes) => bytes.resize(new_len, 0),
+            Storage::Pooled(buffer) => buffer.resize(new_len)?,
+            #[cfg(all(
+                feature = "guard-pages",
+                any(unix, windows),
+                target_pointer_width = "64"
+            ))]
+            Storage::Reserved(reserved) => reserved.commit(new_len)?,
+        }
+        Ok(())
+    }
+
+    // Reserved and pooled memory never move, so there's only ever the one copy to zero
+    fn zeroize(&mut self) {
+        zeroize(self.bytes_mut());
+    }
+
+    fn has_stable_address(&self) -> bool {
+        match self {
+            Storage::Heap(_) => false,
+            Storage::Pooled(_) => true,
+            #[cfg(all(
+                feature = "guard-pages",
+                any(unix, windows),
+                target_pointer_width = "64"
+            ))]
+            Storage::Reserved(_) => true,
         }
+    }
+}
+
+// Zeroes the bytes with volatile writes, which the compiler can't leave out because nothing reads
+// them afterwards
+pub(crate) fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+// Which accesses a watchpoint is triggered by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+}
+
+// Where in the guest an access came from. The instruction offset is from the start of the
+// function's expression, after its locals. An access the host makes through the memory's own
+// functions has neither.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessSite {
+    pub func_idx: Option<usize>,
+    pub instruction_offset: Option<usize>,
+}
+
+// An access that touched a watched range. The bytes are the ones written, or the ones read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: WatchKind,
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub site: AccessSite,
+}
+
+type WatchCallback = Rc<dyn Fn(&MemoryAccess) -> Result<()>>;
+
+struct Watchpoint {
+    range: Range<usize>,
+    kind: WatchKind,
+    callback: WatchCallback,
+}
+
+// The contents are kept in a single allocation so that they can be handed to the host as one
+// slice. Growing the memory may move that allocation, so any slice or pointer into it is only
+// valid until the next grow, whether that comes from the guest or the host. The memory lives
+// behind a RefCell, so a borrowed slice can't be held while wasm runs; data_ptr doesn't get that
+// protection.
+//
+// With the guard-pages feature the address space for the largest the memory can be is reserved
+// when it's created, so growing it never moves it. See has_stable_address. A memory from an
+// InstancePool doesn't move either, as its buffer is already as big as it can grow.
+//
+// With zero_on_drop set the contents are zeroed before they're freed, both when the memory is
+// dropped and when growing it moves them, so whatever the guest kept in them isn't left behind in
+// memory the process gives back to the allocator.
+pub struct Memory {
+    mem_type: MemType,
+    storage: Storage,
+    watchpoints: Vec<Watchpoint>,
+    write_trace: Option<WriteTrace>,
+    // Whether there are any watchpoints or a write trace, so that an access only has to check one
+    // thing to know there's nothing else to do
+    observed: bool,
+    zero_on_drop: bool,
+}
 
-        // Make the memory object
+impl Memory {
+    pub fn new(mem_type: MemType) -> Self {
+        let storage = Storage::new(&mem_type);
         Memory {
-            minimum_pages,
-            maximum_pages,
-            pages,
+            mem_type,
+            storage,
+            watchpoints: Vec::new(),
+            write_trace: None,
+            observed: false,
+            zero_on_drop: false,
         }
     }
 
+    // A memory in a buffer from an instance pool, which it's limited to the size of
+    pub(crate) fn new_pooled(mem_type: MemType, buffer: PooledBuffer) -> Result<Self> {
+        let mut storage = Storage::Pooled(buffer);
+        storage.resize(mem_type.limits().min() * WASM_PAGE_SIZE_IN_BYTES, false)?;
+        Ok(Memory {
+            mem_type,
+            storage,
+            watchpoints: Vec::new(),
+            write_trace: None,
+            observed: false,
+            zero_on_drop: false,
+        })
+    }
+
+    pub fn new_from_bounds(minimum_pages: usize, maximum_pages: Option<usize>) -> Self {
+        Self::new(MemType::new(Limits::new(minimum_pages, maximum_pages)))
+    }
+
     #[allow(dead_code)]
     pub fn min_size(&self) -> usize {
-        self.minimum_pages
+        self.mem_type.limits().min()
     }
 
     #[allow(dead_code)]
     pub fn max_size(&self) -> Option<usize> {
-        self.maximum_pages
+        self.mem_type.limits().max()
     }
 
     #[allow(dead_code)]
     pub fn current_size(&self) -> usize {
-        self.pages.len()
+        self.data().len() / WASM_PAGE_SIZE_IN_BYTES
+    }
+
+    // The whole of the current contents
+    pub fn data(&self) -> &[u8] {
+        self.storage.bytes()
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.storage.bytes_mut()
+    }
+
+    // For handing the contents over FFI. The pointer is valid for len() bytes, but only until
+    // the memory is next grown or dropped, and nothing stops it being used after that.
+    pub fn data_ptr(&mut self) -> *mut u8 {
+        self.storage.bytes_mut().as_mut_ptr()
+    }
+
+    // A copy of the contents at their current size, in storage of its own. Watchpoints and the
+    // write trace stay with this memory, and the copy is zeroed on drop if this one is.
+    pub fn try_clone(&self) -> Result<Memory> {
+        let mut storage = Storage::new(&self.mem_type);
+        storage.resize(self.len(), self.zero_on_drop)?;
+        storage.bytes_mut().copy_from_slice(self.data());
+
+        Ok(Memory {
+            mem_type: self.mem_type.clone(),
+            storage,
+            watchpoints: Vec::new(),
+            write_trace: None,
+            observed: false,
+            zero_on_drop: self.zero_on_drop,
+        })
+    }
+
+    // Zeroes the contents when the memory is dropped, and the old contents whenever growing it
+    // moves them
+    pub fn set_zero_on_drop(&mut self, zero_on_drop: bool) {
+        self.zero_on_drop = zero_on_drop;
+    }
+
+    pub fn zero_on_drop(&self) -> bool {
+        self.zero_on_drop
+    }
+
+    // Whether growing the memory leaves it where it is, so that data_ptr stays valid until the
+    // memory is dropped
+    pub fn has_stable_address(&self) -> bool {
+        self.storage.has_stable_address()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data().is_empty()
     }
 
     pub fn grow_by(&mut self, grow_by: usize) -> Result<()> {
         match self.current_size().checked_add(grow_by) {
-            Some(new_size) if new_size <= self.max_size().unwrap_or(new_size) => {
-                for _ in 0..grow_by {
-                    self.pages.push(MemoryPage::new())
-                }
-
-                Ok(())
+            Some(new_size)
+                if new_size <= self.max_size().unwrap_or(WASM_MAX_PAGES)
+                    && new_size <= WASM_MAX_PAGES =>
+            {
+                self.storage
+                    .resize(new_size * WASM_PAGE_SIZE_IN_BYTES, self.zero_on_drop)
             }
 
             _ => Err(anyhow!("New memory is too big")),
@@ -67,84 +313,279 @@ impl Memory {
     }
 
     pub fn set_data(&mut self, offset: usize, data: &[u8]) -> Result<()> {
-        self.check_bounds(offset, data.len())?;
-
-        let (mut current_page, mut current_page_offset) = split_page_from_address(offset);
-        let mut data_start = 0;
-        let mut data_remaining = data.len();
-
-        while data_remaining > 0 {
-            let bytes_to_copy = min(
-                data_remaining,
-                WASM_PAGE_SIZE_IN_BYTES - current_page_offset,
-            );
-            let page = &mut self.pages[current_page];
-
-            page[current_page_offset..current_page_offset + bytes_to_copy]
-                .copy_from_slice(&data[data_start..data_start + bytes_to_copy]);
-
-            data_start += bytes_to_copy;
-            data_remaining -= bytes_to_copy;
-            current_page += 1;
-            current_page_offset = 0;
+        self.set_data_from(offset, data, AccessSite::default)
+    }
+
+    pub fn get_data(&self, offset: usize, data: &mut [u8]) -> Result<()> {
+        self.get_data_from(offset, data, AccessSite::default)
+    }
+
+    // The guest's accesses, which say where they came from if a watchpoint or the write trace
+    // needs to know. With neither that's never worked out, and all they cost is one check.
+    pub(crate) fn set_data_from(
+        &mut self,
+        offset: usize,
+        data: &[u8],
+        site: impl FnOnce() -> AccessSite,
+    ) -> Result<()> {
+        self.check_bounds(offset, data.len(), true)?;
+        self.data_mut()[offset..offset + data.len()].copy_from_slice(data);
+        if self.observed {
+            let site = site();
+            if let Some(trace) = &mut self.write_trace {
+                trace.record(offset, data, site)?;
+            }
+            self.notify_watchpoints(WatchKind::Write, offset, data, site)?;
         }
+        Ok(())
+    }
 
+    pub(crate) fn get_data_from(
+        &self,
+        offset: usize,
+        data: &mut [u8],
+        site: impl FnOnce() -> AccessSite,
+    ) -> Result<()> {
+        self.check_bounds(offset, data.len(), false)?;
+        data.copy_from_slice(&self.data()[offset..offset + data.len()]);
+        if self.observed {
+            self.notify_watchpoints(WatchKind::Read, offset, data, site())?;
+        }
         Ok(())
     }
 
-    pub fn get_data(&self, offset: usize, data: &mut [u8]) -> Result<()> {
-        self.check_bounds(offset, data.len())?;
-
-        let (mut current_page, mut current_page_offset) = split_page_from_address(offset);
-        let mut data_start = 0;
-        let mut data_remaining = data.len();
-
-        while data_remaining > 0 {
-            let bytes_to_copy = min(
-                data_remaining,
-                WASM_PAGE_SIZE_IN_BYTES - current_page_offset,
-            );
-            let page = &self.pages[current_page];
-
-            data[data_start..data_start + bytes_to_copy]
-                .copy_from_slice(&page[current_page_offset..current_page_offset + bytes_to_copy]);
-
-            data_start += bytes_to_copy;
-            data_remaining -= bytes_to_copy;
-            current_page += 1;
-            current_page_offset = 0;
+    // Calls the callback after each access of the given kind that touches any of the range,
+    // whether it comes from the guest or from the host through this memory's functions. Going
+    // through data or data_mut isn't seen. An error from the callback fails the access, which
+    // stops the guest right where it made it. The memory is borrowed while the callback runs, so
+    // the callback can't look at it.
+    pub fn add_watchpoint(
+        &mut self,
+        range: Range<usize>,
+        kind: WatchKind,
+        callback: impl Fn(&MemoryAccess) -> Result<()> + 'static,
+    ) {
+        self.watchpoints.push(Watchpoint {
+            range,
+            kind,
+            callback: Rc::new(callback),
+        });
+        self.update_observed();
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+        self.update_observed();
+    }
+
+    // Hands every write from now on to the sink, a batch at a time, whether it comes from the
+    // guest or from the host through this memory's functions. It replaces any trace that's
+    // already going, which is flushed first.
+    pub fn start_write_trace(
+        &mut self,
+        sink: impl WriteSink + 'static,
+        options: WriteTraceOptions,
+    ) -> Result<()> {
+        self.stop_write_trace()?;
+        self.write_trace = Some(WriteTrace::new(Box::new(sink), options));
+        self.update_observed();
+        Ok(())
+    }
+
+    // Hands the sink what's been recorded since it was last given a batch
+    pub fn flush_write_trace(&mut self) -> Result<()> {
+        match &mut self.write_trace {
+            Some(trace) => trace.flush(),
+            None => Ok(()),
         }
+    }
 
+    pub fn stop_write_trace(&mut self) -> Result<()> {
+        self.flush_write_trace()?;
+        self.write_trace = None;
+        self.update_observed();
         Ok(())
     }
 
-    fn check_bounds(&self, offset: usize, length: usize) -> Result<()> {
+    fn update_observed(&mut self) {
+        self.observed = !self.watchpoints.is_empty() || self.write_trace.is_some();
+    }
+
+    pub fn has_watchpoints(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    fn notify_watchpoints(
+        &self,
+        kind: WatchKind,
+        address: usize,
+        bytes: &[u8],
+        site: AccessSite,
+    ) -> Result<()> {
+        let end = address + bytes.len();
+        let touched = self
+            .watchpoints
+            .iter()
+            .filter(|w| w.kind == kind && w.range.start < end && address < w.range.end);
+
+        for watchpoint in touched {
+            (watchpoint.callback)(&MemoryAccess {
+                kind,
+                address,
+                bytes: bytes.to_vec(),
+                site,
+            })?;
+        }
+        Ok(())
+    }
+
+    // Every access to the contents goes through here, whether it comes from the guest or the host
+    pub(crate) fn check_bounds(&self, offset: usize, length: usize, write: bool) -> Result<()> {
         match offset.checked_add(length) {
-            None => Err(anyhow!("Length overflow when accessing memory")),
-            Some(end) if end > self.current_size() * WASM_PAGE_SIZE_IN_BYTES => {
-                Err(anyhow!("Attempting to access outside allocated memory"))
-            }
-            _ => Ok(()),
+            Some(end) if end <= self.data().len() => Ok(()),
+            _ => Err(Trap::new(TrapKind::MemoryOutOfBounds {
+                mem_idx: 0,
+                address: offset as u64,
+                width: length,
+                write,
+                memory_size: self.data().len(),
+            })
+            .into()),
+        }
+    }
+}
+
+// Sizes and limits in the units the specification uses. The limits are the ones the memory was
+// created with, which for an imported memory are the resolver's rather than the import's.
+impl Memory {
+    pub fn ty(&self) -> &MemType {
+        &self.mem_type
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.data().len()
+    }
+
+    pub fn size_pages(&self) -> u32 {
+        self.current_size() as u32
+    }
+
+    pub fn min_pages(&self) -> u32 {
+        self.min_size() as u32
+    }
+
+    pub fn max_pages(&self) -> Option<u32> {
+        self.max_size().map(|max| max as u32)
+    }
+}
+
+impl Memory {
+    // Little endian reads and writes of single values, which trap the same way the guest's
+    // loads and stores do when they don't fit in the memory
+    pub fn read<T: LEByteConvert>(&self, offset: usize) -> Result<T> {
+        let mut bytes: GenericArray<u8, T::ArrayLength> = Default::default();
+        self.get_data(offset, &mut bytes)?;
+        Ok(T::from_bytes(bytes))
+    }
+
+    pub fn write<T: LEByteConvert>(&mut self, offset: usize, value: T) -> Result<()> {
+        self.set_data(offset, &value.to_bytes())
+    }
+
+    pub fn read_u8(&self, offset: usize) -> Result<u8> {
+        self.read(offset)
+    }
+
+    pub fn read_u16(&self, offset: usize) -> Result<u16> {
+        self.read(offset)
+    }
+
+    pub fn read_u32(&self, offset: usize) -> Result<u32> {
+        self.read(offset)
+    }
+
+    pub fn read_u64(&self, offset: usize) -> Result<u64> {
+        self.read(offset)
+    }
+
+    pub fn read_i32(&self, offset: usize) -> Result<i32> {
+        self.read(offset)
+    }
+
+    pub fn read_i64(&self, offset: usize) -> Result<i64> {
+        self.read(offset)
+    }
+
+    pub fn read_f32(&self, offset: usize) -> Result<f32> {
+        self.read(offset)
+    }
+
+    pub fn read_f64(&self, offset: usize) -> Result<f64> {
+        self.read(offset)
+    }
+
+    pub fn write_u8(&mut self, offset: usize, value: u8) -> Result<()> {
+        self.write(offset, value)
+    }
+
+    pub fn write_u16(&mut self, offset: usize, value: u16) -> Result<()> {
+        self.write(offset, value)
+    }
+
+    pub fn write_u32(&mut self, offset: usize, value: u32) -> Result<()> {
+        self.write(offset, value)
+    }
+
+    pub fn write_u64(&mut self, offset: usize, value: u64) -> Result<()> {
+        self.write(offset, value)
+    }
+
+    pub fn write_i32(&mut self, offset: usize, value: i32) -> Result<()> {
+        self.write(offset, value)
+    }
+
+    pub fn write_i64(&mut self, offset: usize, value: i64) -> Result<()> {
+        self.write(offset, value)
+    }
+
+    pub fn write_f32(&mut self, offset: usize, value: f32) -> Result<()> {
+        self.write(offset, value)
+    }
+
+    pub fn write_f64(&mut self, offset: usize, value: f64) -> Result<()> {
+        self.write(offset, value)
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if self.zero_on_drop {
+            self.storage.zeroize();
         }
     }
 }
 
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Memory")
+            .field("mem_type", &self.mem_type)
+            .field("current_pages", &self.current_size())
+            .field("watchpoints", &self.watchpoints.len())
+            .field("write_trace", &self.write_trace)
+            .finish()
+    }
+}
+
 impl Index<usize> for Memory {
     type Output = u8;
 
     fn index(&self, address: usize) -> &Self::Output {
-        let (page, offset) = split_page_from_address(address);
-
-        let page = &self.pages[page];
-        &page[offset]
+        &self.data()[address]
     }
 }
 
 impl IndexMut<usize> for Memory {
     fn index_mut(&mut self, address: usize) -> &mut Self::Output {
-        let (page, offset) = split_page_from_address(address);
-
-        let page = &mut self.pages[page];
-        &mut page[offset]
+        &mut self.data_mut()[address]
     }
 }
@@ -0,0 +1,172 @@
+// Cursors over a region of a memory, for handing guest memory to code that reads or writes
+// through std::io, such as a parser or a decoder, without copying it out to a Vec first.
+//
+// A cursor holds on to the memory it's over for as long as it exists. That's either a plain
+// reference, from Memory::reader or Memory::writer, or the Ref or RefMut from the RefCell the
+// memory is shared through, with MemReader::new or MemWriter::new:
+//
+//     let mut reader = MemReader::new(instance.memories[0].borrow(), offset, len)?;
+//     io::copy(&mut reader, &mut file)?;
+//
+// Running any wasm from the instance borrows its memories too, so while a cursor exists the
+// guest can't run, and the memory can't grow under it. Calling into the guest with a writer still
+// around fails with a BorrowMutError panic, so drop the cursor first.
+//
+// The region is checked against the memory when the cursor is made. Reading stops at the end of
+// it, and writing is cut short there, so write_all fails with WriteZero if there's more to write
+// than there's room for. Accesses go through the memory's own functions, so watchpoints and the
+// write trace see them.
+use anyhow::Result;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+
+use crate::core::{AccessSite, Memory};
+
+// Where a cursor is in its region, which is where it's got to from its start
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    offset: usize,
+    len: usize,
+    position: usize,
+}
+
+impl Region {
+    fn new(memory: &Memory, offset: usize, len: usize, write: bool) -> Result<Self> {
+        memory.check_bounds(offset, len, write)?;
+        Ok(Self {
+            offset,
+            len,
+            position: 0,
+        })
+    }
+
+    // How much of an access of the given size fits before the end, and where in memory it is
+    fn next(&self, size: usize) -> (usize, usize) {
+        let remaining = self.len.saturating_sub(self.position);
+        (self.offset + self.position, size.min(remaining))
+    }
+
+    // Positions past the end are allowed, as they are for io::Cursor, and accesses there do
+    // nothing
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(position) => (0, position as i64),
+            SeekFrom::End(delta) => (self.len as i64, delta),
+            SeekFrom::Current(delta) => (self.position as i64, delta),
+        };
+        match base.checked_add(delta) {
+            Some(position) if position >= 0 => {
+                self.position = position as usize;
+                Ok(position as u64)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to before the start of the region",
+            )),
+        }
+    }
+}
+
+// Only a watchpoint or the write trace can fail an access once the region has been checked.
+// io::Error::other would do, but it's much newer than anything else this crate needs.
+#[allow(clippy::io_other_error)]
+fn to_io_error(error: anyhow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+pub struct MemReader<M: Deref<Target = Memory>> {
+    memory: M,
+    region: Region,
+}
+
+impl<M: Deref<Target = Memory>> MemReader<M> {
+    pub fn new(memory: M, offset: usize, len: usize) -> Result<Self> {
+        let region = Region::new(&memory, offset, len, false)?;
+        Ok(Self { memory, region })
+    }
+
+    // How many bytes there are left to read
+    pub fn remaining(&self) -> usize {
+        self.region.len.saturating_sub(self.region.position)
+    }
+
+    pub fn into_inner(self) -> M {
+        self.memory
+    }
+}
+
+impl<M: Deref<Target = Memory>> Read for MemReader<M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (address, size) = self.region.next(buf.len());
+        if size == 0 {
+            return Ok(0);
+        }
+        self.memory
+            .get_data_from(address, &mut buf[..size], AccessSite::default)
+            .map_err(to_io_error)?;
+        self.region.position += size;
+        Ok(size)
+    }
+}
+
+impl<M: Deref<Target = Memory>> Seek for MemReader<M> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.region.seek(pos)
+    }
+}
+
+pub struct MemWriter<M: DerefMut<Target = Memory>> {
+    memory: M,
+    region: Region,
+}
+
+impl<M: DerefMut<Target = Memory>> MemWriter<M> {
+    pub fn new(memory: M, offset: usize, len: usize) -> Result<Self> {
+        let region = Region::new(&memory, offset, len, true)?;
+        Ok(Self { memory, region })
+    }
+
+    // How much room there is left to write into
+    pub fn remaining(&self) -> usize {
+        self.region.len.saturating_sub(self.region.position)
+    }
+
+    pub fn into_inner(self) -> M {
+        self.memory
+    }
+}
+
+impl<M: DerefMut<Target = Memory>> Write for MemWriter<M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (address, size) = self.region.next(buf.len());
+        if size == 0 {
+            return Ok(0);
+        }
+        self.memory
+            .set_data_from(address, &buf[..size], AccessSite::default)
+            .map_err(to_io_error)?;
+        self.region.position += size;
+        Ok(size)
+    }
+
+    // Everything goes straight into the memory
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<M: DerefMut<Target = Memory>> Seek for MemWriter<M> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.region.seek(pos)
+    }
+}
+
+impl Memory {
+    pub fn reader(&self, offset: usize, len: usize) -> Result<MemReader<&Memory>> {
+        MemReader::new(self, offset, len)
+    }
+
+    pub fn writer(&mut self, offset: usize, len: usize) -> Result<MemWriter<&mut Memory>> {
+        MemWriter::new(self, offset, len)
+    }
+}
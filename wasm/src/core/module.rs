@@ -1,112 +1,222 @@
-use anyhow::{anyhow, Result};
-use std::cell::{Ref, RefCell, RefMut};
+use anyhow::{anyhow, Context, Result};
 use std::collections::HashMap;
-use std::convert::TryFrom;
+use std::fmt::Write;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
+use std::io::Write as IoWrite;
+use std::mem::{size_of, size_of_val};
 use std::rc::Rc;
 
-use crate::core::{
-    self, evaluate_constant_expression,
-    stack_entry::StackEntry,
-    store_access::{CellRefMutType, CellRefType, RefType},
-    Callable, ConstantExpressionStore, ExpressionStore, FuncType, Global, Memory, Stack, Table,
+use crate::core::phase_trace::{self, Field, Phase};
+use crate::core::{self, ExportKind, Instance};
+use crate::reader::{
+    CustomSectionHandlers, ModuleBuilder, Section, SectionFramingError, SectionIter, SliceReader,
+    TypeReader, MODULE_HEADER,
 };
-use crate::parser::InstructionSource;
-use crate::reader::{ModuleBuilder, ReaderUtil, ScopedReader, TypeReader};
+use crate::writer::{TypeWriter, WriterUtil};
 
-#[derive(Debug)]
-struct RawModuleMetadata {
-    types: Vec<core::FuncType>,
+#[derive(Debug, Clone)]
+pub(crate) struct RawModuleMetadata {
+    pub(crate) types: Vec<core::FuncType>,
 }
 
-#[derive(Debug)]
+// Function bodies and data segments are reference counted, so cloning a raw module is cheap
+#[derive(Debug, Clone)]
 pub struct RawModule {
-    metadata: RawModuleMetadata,
-    typeidx: Vec<usize>,
-    funcs: Vec<core::Func>,
-    tables: Vec<core::TableType>,
-    mems: Vec<core::MemType>,
-    globals: Vec<core::GlobalDef>,
-    elem: Vec<core::Element>,
-    data: Vec<core::Data>,
-    start: Option<usize>,
-    imports: Vec<core::Import>,
-    exports: Vec<core::Export>,
+    pub(crate) metadata: RawModuleMetadata,
+    pub(crate) typeidx: Vec<usize>,
+    pub(crate) funcs: Vec<core::Func>,
+    pub(crate) tables: Vec<core::TableType>,
+    pub(crate) mems: Vec<core::MemType>,
+    pub(crate) globals: Vec<core::GlobalDef>,
+    pub(crate) elem: Vec<core::Element>,
+    pub(crate) data: Vec<core::Data>,
+    pub(crate) start: Option<usize>,
+    pub(crate) imports: Vec<core::Import>,
+    pub(crate) exports: Vec<core::Export>,
+    // The names from the name section, which are all empty if the module doesn't have one
+    pub(crate) names: Rc<core::NameSection>,
+    pub(crate) target_features: Option<core::TargetFeatures>,
+    pub(crate) dylink: Option<core::DylinkInfo>,
+    pub(crate) custom_sections: Vec<core::CustomSection>,
+    // The hashes of the bytes the module was read from, which are worked out again from what
+    // encode writes if it wasn't read, or it's been changed since
+    pub(crate) hashes: Option<core::ModuleHashes>,
 }
 
 impl TypeReader for core::RawModule {
+    // The whole module is read in first, so that the function bodies and data segments can share
+    // its buffer rather than each having their own
     fn read<T: Read>(reader: &mut T) -> Result<Self> {
-        const HEADER_LENGTH: usize = 8;
-        const EXPECTED_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::from_shared(bytes.into())
+    }
+}
 
-        let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+// How far past its end a section's contents go, when it failed to read because they do. That's
+// found by reading it again with the rest of the module after it as though it were part of it, so
+// it's only known if that works.
+fn overrun(
+    module: &core::SharedBytes,
+    section: &Section,
+    section_type: core::SectionType,
+) -> Option<usize> {
+    let rest = module
+        .slice(
+            section.payload_offset(),
+            module.len() - section.payload_offset(),
+        )
+        .ok()?;
+    let mut reader = SliceReader::new(&rest);
+    ModuleBuilder::new()
+        .process_section(section_type, &rest, &mut reader)
+        .ok()?;
+    match reader.offset().checked_sub(section.payload().len()) {
+        Some(overrun) if overrun > 0 => Some(overrun),
+        _ => None,
+    }
+}
 
-        // Read in the header
-        reader.read_exact(&mut header)?;
+impl RawModule {
+    // Reads the module the same way read does, with the custom sections going to the handlers as
+    // they're come across
+    pub fn read_with_handlers<T: Read>(
+        reader: &mut T,
+        handlers: &mut CustomSectionHandlers<'_>,
+    ) -> Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::from_shared_with_handlers(bytes.into(), handlers)
+    }
 
-        if header != EXPECTED_HEADER {
-            Err(anyhow!("Invalid module header"))
-        } else {
-            let mut current_section_type: Option<core::SectionType> =
-                Some(core::SectionType::TypeSection);
-            let mut module_builder = ModuleBuilder::new();
-
-            loop {
-                if let Ok(section_type) = ModuleBuilder::read_next_section_header(reader) {
-                    // Read the section length
-                    let section_length = usize::try_from(reader.read_leb_u32()?).unwrap();
-                    // And make a scoped reader for the section
-                    let mut section_reader = ScopedReader::new(reader, section_length);
-
-                    // Always skip custom sections wherever they appear
-                    if section_type == core::SectionType::CustomSection {
-                        // Read the section name
-                        let section_name = section_reader.read_name()?;
-                        let _section_body = section_reader.read_bytes_to_end()?;
-
-                        println!("Skipping custom section \"{}\"", section_name);
-                    } else {
-                        while let Some(expected_section_type) = current_section_type {
-                            if expected_section_type == section_type {
-                                // This is the correct section type so we process it and move on
-                                module_builder
-                                    .process_section(section_type, &mut section_reader)?;
-
-                                // And the next section type is the same as this one
-                                current_section_type = Some(expected_section_type);
-                                break;
-                            } else {
-                                // The section type doesn't match, so we move on to see if it
-                                // is the next valid section
-                                current_section_type =
-                                    ModuleBuilder::get_next_section_type(expected_section_type);
-                            }
-                        }
-
-                        if current_section_type == None {
-                            assert!(false, "Sections are in unexpected order");
-                            return Err(anyhow!("Invalid section order"));
-                        }
-                    }
-
-                    if !section_reader.is_at_end() {
-                        assert!(false, "Failed to read whole section");
-                        return Err(anyhow!("Failed to read whole section"));
-                    }
-                } else {
-                    // End of file, so we can break out of the loop
+    pub(crate) fn from_shared(module: core::SharedBytes) -> Result<Self> {
+        Self::from_shared_with_handlers(module, &mut CustomSectionHandlers::new())
+    }
+
+    fn from_shared_with_handlers(
+        module: core::SharedBytes,
+        handlers: &mut CustomSectionHandlers<'_>,
+    ) -> Result<Self> {
+        phase_trace::enter(Phase::Decode, || {
+            vec![Field::u64("size", module.len() as u64)]
+        });
+        let result = Self::decode(module, handlers);
+        phase_trace::exit_with(Phase::Decode, &result, |module| {
+            vec![
+                Field::u64("types", module.metadata.types.len() as u64),
+                Field::u64("imports", module.imports.len() as u64),
+                Field::u64("functions", module.funcs.len() as u64),
+                Field::u64("tables", module.tables.len() as u64),
+                Field::u64("memories", module.mems.len() as u64),
+                Field::u64("globals", module.globals.len() as u64),
+                Field::u64("exports", module.exports.len() as u64),
+                Field::u64("elements", module.elem.len() as u64),
+                Field::u64("data", module.data.len() as u64),
+                Field::u64("custom_sections", module.custom_sections.len() as u64),
+            ]
+        });
+        result
+    }
+
+    fn decode(module: core::SharedBytes, handlers: &mut CustomSectionHandlers<'_>) -> Result<Self> {
+        let mut current_section_type: Option<core::SectionType> =
+            Some(core::SectionType::TypeSection);
+        let mut last_section_type: Option<core::SectionType> = None;
+        let mut module_builder = ModuleBuilder::new();
+        let mut hasher = core::ModuleHasher::new();
+
+        for section in SectionIter::from_shared(module.clone())? {
+            let section = section?;
+            hasher.add_section(section.id(), section.payload());
+            let section_type = section.section_type().ok_or_else(|| {
+                anyhow!(
+                    "Unknown section type {} at offset 0x{:x}",
+                    section.id(),
+                    section.offset()
+                )
+            })?;
+            let mut section_reader = SliceReader::new(section.payload());
+
+            // Custom sections can appear anywhere, and don't affect the order of the others
+            if section_type == core::SectionType::CustomSection {
+                let (section_name, section_body) = section.custom_contents()?;
+
+                module_builder.process_custom_section(
+                    section_name,
+                    section_body,
+                    last_section_type,
+                    section.offset(),
+                    handlers,
+                )?;
+                continue;
+            }
+
+            while let Some(expected_section_type) = current_section_type {
+                if expected_section_type == section_type {
+                    // This is the correct section type so we process it and move on
+                    module_builder
+                        .process_section(
+                            section_type,
+                            section.shared_payload(),
+                            &mut section_reader,
+                        )
+                        .with_context(|| {
+                            format!(
+                                "Failed to read {:?} at offset 0x{:x}",
+                                section_type,
+                                section.offset()
+                            )
+                        })
+                        .map_err(|error| match overrun(&module, &section, section_type) {
+                            Some(overrun) => error.context(SectionFramingError::ContentsTooLong {
+                                id: section.id(),
+                                offset: section.offset(),
+                                declared: section.payload().len(),
+                                overrun,
+                            }),
+                            None => error,
+                        })?;
+                    last_section_type = Some(section_type);
+
+                    // And the next section type is the same as this one
+                    current_section_type = Some(expected_section_type);
                     break;
+                } else {
+                    // The section type doesn't match, so we move on to see if it
+                    // is the next valid section
+                    current_section_type =
+                        ModuleBuilder::get_next_section_type(expected_section_type);
+                }
+            }
+
+            if current_section_type == None {
+                return Err(SectionFramingError::OutOfOrder {
+                    id: section.id(),
+                    offset: section.offset(),
+                    // Only a section after some other one can be out of order
+                    after: last_section_type.map_or(0, u8::from),
                 }
+                .into());
             }
 
-            module_builder.make_module()
+            if !section_reader.is_at_end() {
+                return Err(SectionFramingError::ContentsTooShort {
+                    id: section.id(),
+                    offset: section.offset(),
+                    declared: section.payload().len(),
+                    used: section_reader.offset(),
+                }
+                .into());
+            }
         }
+
+        let mut module = module_builder.make_module()?;
+        module.hashes = Some(hasher.finish());
+        Ok(module)
     }
-}
 
-impl RawModule {
     pub fn new(
         types: Vec<core::FuncType>,
         typeidx: Vec<usize>,
@@ -132,379 +242,578 @@ impl RawModule {
             start,
             imports,
             exports,
+            names: Rc::default(),
+            target_features: None,
+            dylink: None,
+            custom_sections: Vec::new(),
+            hashes: None,
         }
     }
 }
 
-#[derive(Debug)]
-pub enum ExportValue {
-    Function(Rc<RefCell<Callable>>),
-    Table(Rc<RefCell<Table>>),
-    Memory(Rc<RefCell<Memory>>),
-    Global(Rc<RefCell<Global>>),
-}
+// Read access to the contents of each section, for tools that want to look at a module before
+// deciding how to instantiate it
+impl RawModule {
+    pub fn types(&self) -> &[core::FuncType] {
+        &self.metadata.types
+    }
 
-#[derive(Debug)]
-pub struct Module {
-    pub functions: Vec<Rc<RefCell<Callable>>>,
-    pub tables: Vec<Rc<RefCell<Table>>>,
-    pub memories: Vec<Rc<RefCell<Memory>>>,
-    pub globals: Vec<Rc<RefCell<Global>>>,
-    pub exports: HashMap<String, ExportValue>,
-    func_types: Vec<FuncType>,
-}
+    // The type index of each function defined in the module, in the same order as funcs
+    pub fn func_type_indices(&self) -> &[usize] {
+        &self.typeidx
+    }
 
-impl Module {
-    pub fn new() -> Self {
-        Self {
-            functions: Vec::new(),
-            tables: Vec::new(),
-            memories: Vec::new(),
-            globals: Vec::new(),
-            exports: HashMap::new(),
-            func_types: Vec::new(),
-        }
+    pub fn funcs(&self) -> &[core::Func] {
+        &self.funcs
     }
 
-    pub fn load_module_from_path<R: core::Resolver>(
-        file: &str,
-        resolver: &R,
-    ) -> anyhow::Result<Self> {
-        let mut buf = BufReader::new(File::open(file)?);
-        let raw_module = core::RawModule::read(&mut buf)?;
-        let module = core::Module::resolve_raw_module(raw_module, resolver)?;
-        Ok(module)
+    pub fn tables(&self) -> &[core::TableType] {
+        &self.tables
     }
 
-    fn resolve_imports<Iter: Iterator<Item = core::Import>, Resolver: core::Resolver>(
-        &mut self,
-        imports: Iter,
-        metadata: &RawModuleMetadata,
-        resolver: &Resolver,
-    ) -> Result<()> {
-        for import in imports {
-            match import.desc() {
-                core::ImportDesc::TypeIdx(type_index) => {
-                    if *type_index >= metadata.types.len() {
-                        return Err(anyhow!(
-                            "Function import {} from module {} has invalid type index",
-                            import.mod_name(),
-                            import.name()
-                        ));
-                    }
-
-                    let resolved_function = resolver.resolve_function(
-                        import.mod_name(),
-                        import.name(),
-                        &metadata.types[*type_index],
-                    )?;
-                    self.functions.push(resolved_function);
-                }
-                core::ImportDesc::TableType(table_type) => {
-                    let resolved_table =
-                        resolver.resolve_table(import.mod_name(), import.name(), table_type)?;
-                    self.tables.push(resolved_table);
-                }
-                core::ImportDesc::MemType(mem_type) => {
-                    let resolved_memory =
-                        resolver.resolve_memory(import.mod_name(), import.name(), mem_type)?;
-                    self.memories.push(resolved_memory);
-                }
-                core::ImportDesc::GlobalType(global_type) => {
-                    let resolved_global =
-                        resolver.resolve_global(import.mod_name(), import.name(), global_type)?;
-                    self.globals.push(resolved_global);
-                }
-            }
-        }
+    pub fn mems(&self) -> &[core::MemType] {
+        &self.mems
+    }
 
-        Ok(())
+    pub fn globals(&self) -> &[core::GlobalDef] {
+        &self.globals
     }
 
-    fn add_functions<Iter: Iterator<Item = (usize, core::Func)>>(
-        &mut self,
-        functions: Iter,
-        metadata: &RawModuleMetadata,
-    ) -> Result<()> {
-        for (type_idx, func) in functions {
-            if type_idx >= metadata.types.len() {
-                return Err(anyhow!("Function has invalid type index"));
-            }
+    pub fn elems(&self) -> &[core::Element] {
+        &self.elem
+    }
 
-            self.functions
-                .push(Rc::new(RefCell::new(core::WasmExprCallable::new(
-                    metadata.types[type_idx].clone(),
-                    func.clone(),
-                ))));
-        }
-        Ok(())
+    pub fn data(&self) -> &[core::Data] {
+        &self.data
     }
 
-    fn add_tables<Iter: Iterator<Item = core::TableType>>(&mut self, tables: Iter) -> Result<()> {
-        for table in tables {
-            self.tables.push(Rc::new(RefCell::new(Table::new(table))));
-        }
+    pub fn start(&self) -> Option<usize> {
+        self.start
+    }
 
-        Ok(())
+    pub fn imports(&self) -> &[core::Import] {
+        &self.imports
     }
 
-    fn add_memories<Iter: Iterator<Item = core::MemType>>(&mut self, memories: Iter) -> Result<()> {
-        for memory in memories {
-            self.memories
-                .push(Rc::new(RefCell::new(Memory::new(memory))));
-        }
+    pub fn exports(&self) -> &[core::Export] {
+        &self.exports
+    }
 
-        Ok(())
+    // The name the name section gives to a function, by its index in the function index space
+    pub fn func_name(&self, func_idx: usize) -> Option<&str> {
+        self.names.func_name(func_idx)
     }
 
-    fn add_globals(&mut self, globals: impl Iterator<Item = core::GlobalDef>) -> Result<()> {
-        for global in globals {
-            let global_type = global.global_type().clone();
-            let init_expr = global.init_expr();
+    // Everything else the name section names, such as locals, globals and memories
+    pub fn names(&self) -> &core::NameSection {
+        &self.names
+    }
 
-            let results = evaluate_constant_expression(init_expr, self, 1)?;
-            let global = Global::new(global_type, results[0])?;
+    // The custom sections from the binary, including the name section, in the order they appeared
+    pub fn custom_sections(&self) -> &[core::CustomSection] {
+        &self.custom_sections
+    }
 
-            self.globals.push(Rc::new(RefCell::new(global)));
-        }
+    // A hash of everything in the module, which is the same for the same bytes. See ModuleHash.
+    pub fn content_hash(&self) -> core::ModuleHash {
+        self.hashes().content
+    }
 
-        Ok(())
+    // A hash of everything but the custom sections, so that it's the same for builds that only
+    // differ in their debug info or names
+    pub fn code_hash(&self) -> core::ModuleHash {
+        self.hashes().code
     }
 
-    fn collect_single_export<T>(idx: usize, items: &Vec<std::rc::Rc<T>>) -> Result<std::rc::Rc<T>> {
-        if idx >= items.len() {
-            return Err(anyhow!("Export has invalid index"));
+    // Hands the canonical stream that content_hash and code_hash are taken over to write, for
+    // hashing it some other way, with a std::hash::Hasher's write or a cryptographic hash. The
+    // stream is of what encode writes, so for a module that was read it's the stream of the bytes
+    // it was read from as long as encode writes its sections back as they were.
+    pub fn hash_with<F: FnMut(&[u8])>(&self, sections: core::HashedSections, write: F) {
+        core::write_stream(&self.encode(), sections, write)
+            .expect("Encoded modules have whole sections");
+    }
+
+    fn hashes(&self) -> core::ModuleHashes {
+        if let Some(hashes) = self.hashes {
+            return hashes;
         }
 
-        Ok(items[idx].clone())
+        let mut hasher = core::ModuleHasher::new();
+        let sections =
+            SectionIter::from_shared(self.encode().into()).expect("Encoded modules have a header");
+        for section in sections {
+            let section = section.expect("Encoded modules have whole sections");
+            hasher.add_section(section.id(), section.payload());
+        }
+        hasher.finish()
     }
+}
 
-    fn collect_exports<Iter: Iterator<Item = core::Export>>(
-        &mut self,
-        exports: Iter,
-    ) -> Result<()> {
-        for core::Export { nm, d } in exports {
-            match d {
-                core::ExportDesc::Func(idx) => {
-                    self.exports.insert(
-                        nm,
-                        ExportValue::Function(Self::collect_single_export(idx, &self.functions)?),
-                    );
-                }
-                core::ExportDesc::Table(idx) => {
-                    self.exports.insert(
-                        nm,
-                        ExportValue::Table(Self::collect_single_export(idx, &self.tables)?),
-                    );
-                }
-                core::ExportDesc::Mem(idx) => {
-                    self.exports.insert(
-                        nm,
-                        ExportValue::Memory(Self::collect_single_export(idx, &self.memories)?),
-                    );
-                }
-                core::ExportDesc::Global(idx) => {
-                    self.exports.insert(
-                        nm,
-                        ExportValue::Global(Self::collect_single_export(idx, &self.globals)?),
-                    );
-                }
+// Changes that can be made before writing a module back out with encode
+impl RawModule {
+    // Each of these leaves the module with nothing to say it's the one that was read, so its
+    // hashes are of what encode writes from then on
+    pub fn exports_mut(&mut self) -> &mut Vec<core::Export> {
+        self.hashes = None;
+        &mut self.exports
+    }
+
+    pub fn data_mut(&mut self) -> &mut Vec<core::Data> {
+        self.hashes = None;
+        &mut self.data
+    }
+
+    pub fn custom_sections_mut(&mut self) -> &mut Vec<core::CustomSection> {
+        self.hashes = None;
+        &mut self.custom_sections
+    }
+}
+
+fn write_section<T: IoWrite, F: Fn(&mut Vec<u8>) -> Result<()>>(
+    writer: &mut T,
+    section_type: core::SectionType,
+    write_fn: F,
+) -> Result<()> {
+    // Sections are prefixed with their size, so the contents have to be written out first
+    let mut contents = Vec::new();
+    write_fn(&mut contents)?;
+
+    writer.write_u8(section_type.into())?;
+    writer.write_leb_usize(contents.len())?;
+    writer.write_all(&contents)?;
+    Ok(())
+}
+
+fn write_custom_sections<T: IoWrite>(
+    writer: &mut T,
+    custom_sections: &[core::CustomSection],
+    after: Option<core::SectionType>,
+) -> Result<()> {
+    for custom_section in custom_sections.iter().filter(|c| c.after() == after) {
+        write_section(writer, core::SectionType::CustomSection, |w| {
+            w.write_name(custom_section.name())?;
+            w.write_all(custom_section.bytes())?;
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+impl TypeWriter for RawModule {
+    fn write<T: IoWrite>(&self, writer: &mut T) -> Result<()> {
+        use crate::core::SectionType::*;
+
+        writer.write_all(&MODULE_HEADER)?;
+        write_custom_sections(writer, &self.custom_sections, None)?;
+
+        let mut section_type = Some(TypeSection);
+        while let Some(current) = section_type {
+            // Empty sections are left out, the same as other tools do
+            let has_contents = match current {
+                TypeSection => !self.metadata.types.is_empty(),
+                ImportSection => !self.imports.is_empty(),
+                FunctionSection | CodeSection => !self.funcs.is_empty(),
+                TableSection => !self.tables.is_empty(),
+                MemorySection => !self.mems.is_empty(),
+                GlobalSection => !self.globals.is_empty(),
+                ExportSection => !self.exports.is_empty(),
+                StartSection => self.start.is_some(),
+                ElementSection => !self.elem.is_empty(),
+                DataSection => !self.data.is_empty(),
+                CustomSection => false,
+            };
+
+            if has_contents {
+                write_section(writer, current, |w| match current {
+                    TypeSection => w.write_vec(&self.metadata.types, |w, t| t.write(w)),
+                    ImportSection => w.write_vec(&self.imports, |w, i| i.write(w)),
+                    FunctionSection => w.write_vec(&self.typeidx, |w, t| w.write_leb_usize(*t)),
+                    TableSection => w.write_vec(&self.tables, |w, t| t.write(w)),
+                    MemorySection => w.write_vec(&self.mems, |w, m| m.write(w)),
+                    GlobalSection => w.write_vec(&self.globals, |w, g| g.write(w)),
+                    ExportSection => w.write_vec(&self.exports, |w, e| e.write(w)),
+                    StartSection => w.write_leb_usize(self.start.unwrap()),
+                    ElementSection => w.write_vec(&self.elem, |w, e| e.write(w)),
+                    CodeSection => w.write_vec(&self.funcs, |w, f| f.write(w)),
+                    DataSection => w.write_vec(&self.data, |w, d| d.write(w)),
+                    CustomSection => unreachable!(),
+                })?;
             }
+
+            write_custom_sections(writer, &self.custom_sections, Some(current))?;
+            section_type = ModuleBuilder::get_next_section_type(current);
         }
 
         Ok(())
     }
+}
 
-    fn add_func_types(&mut self, func_types: Vec<FuncType>) -> Result<()> {
-        self.func_types = func_types;
-        Ok(())
+impl RawModule {
+    // Writes the module back out in the binary format, with any custom sections where they were
+    // in the original
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes)
+            .expect("Writing to a vector can't fail");
+        bytes
     }
+}
 
-    fn pre_execute_validate(&self) -> Result<()> {
-        if self.tables.len() > 1 {
-            Err(anyhow!("Too many tables"))
-        } else if self.memories.len() > 1 {
-            Err(anyhow!("Too many memoryies"))
-        } else {
-            Ok(())
+// How many of each kind of import a module has. Imports come first in each index space, so these
+// are also where the module's own definitions start.
+#[derive(Debug, Clone, Default)]
+struct ImportCounts {
+    functions: usize,
+    tables: usize,
+    memories: usize,
+    globals: usize,
+}
+
+impl ImportCounts {
+    fn new(imports: &[core::Import]) -> Self {
+        let mut counts = Self::default();
+        for import in imports {
+            match import.desc() {
+                core::ImportDesc::TypeIdx(_) => counts.functions += 1,
+                core::ImportDesc::TableType(_) => counts.tables += 1,
+                core::ImportDesc::MemType(_) => counts.memories += 1,
+                core::ImportDesc::GlobalType(_) => counts.globals += 1,
+            }
         }
+        counts
     }
+}
 
-    fn initialize_table_element(&self, element: core::Element) -> Result<()> {
-        if element.table_idx() >= self.tables.len() {
-            Err(anyhow!("Table initializer table idx out of range"))
-        } else {
-            let table = &self.tables[element.table_idx()];
-            let offset = self.evaluate_offset_expression(element.expr())?;
-
-            let functions = element.func_indices();
-            let functions: Result<Vec<_>> = functions
-                .into_iter()
-                .map(|idx| {
-                    if *idx < self.functions.len() {
-                        Ok(self.functions[*idx].clone())
-                    } else {
-                        Err(anyhow!("Function index out of range"))
-                    }
-                })
-                .collect();
-            let functions = functions?;
+// The strong and weak counts in front of everything that's behind an Rc
+const RC_OVERHEAD: usize = 2 * size_of::<usize>();
 
-            table.borrow_mut().set_entries(offset, &functions);
+fn vec_size<T>(items: &Vec<T>) -> usize {
+    items.capacity() * size_of::<T>()
+}
 
-            Ok(())
+// A module is the decoded form of a wasm binary. It never changes once it has been loaded, and
+// all of the running state lives in the instances made from it.
+#[derive(Debug, Clone)]
+pub struct Module {
+    raw: RawModule,
+    import_counts: ImportCounts,
+    // The types are shared by every function that has them, in every instance
+    func_types: Vec<Rc<core::FuncType>>,
+}
+
+impl Module {
+    pub fn new(raw: RawModule) -> Self {
+        let import_counts = ImportCounts::new(&raw.imports);
+        let func_types = raw.types().iter().cloned().map(Rc::new).collect();
+        Self {
+            raw,
+            import_counts,
+            func_types,
         }
     }
 
-    fn initialize_table_elements<Iter: Iterator<Item = core::Element>>(
-        &self,
-        iter: Iter,
-    ) -> Result<()> {
-        for element in iter {
-            self.initialize_table_element(element)?;
-        }
+    pub fn from_reader<T: Read>(reader: &mut T) -> Result<Self> {
+        Ok(Self::new(core::RawModule::read(reader)?))
+    }
 
-        Ok(())
+    pub fn load_module_from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_reader(&mut &bytes[..])
     }
 
-    fn initialize_memory_data(&self, data: core::Data) -> Result<()> {
-        if data.mem_idx() >= self.memories.len() {
-            Err(anyhow!("Memory initializer mem idx out of range"))
-        } else {
-            let memory = &self.memories[data.mem_idx()];
-            let offset = self.evaluate_offset_expression(data.expr())?;
+    // The same, with the custom sections going to the handlers as they're found
+    pub fn load_module_with_handlers(
+        bytes: &[u8],
+        handlers: &mut CustomSectionHandlers<'_>,
+    ) -> Result<Self> {
+        Ok(Self::new(RawModule::read_with_handlers(
+            &mut &bytes[..],
+            handlers,
+        )?))
+    }
 
-            let data = data.bytes();
+    // The same, but the module is turned away up front if it uses a feature that isn't enabled.
+    // FeatureSet::supported() has everything that the interpreter can run.
+    pub fn load_module_with_features(bytes: &[u8], enabled: &core::FeatureSet) -> Result<Self> {
+        RawModule::check_features(bytes, enabled)?;
+        Self::load_module_from_bytes(bytes)
+    }
 
-            memory.borrow_mut().set_data(offset, data)?;
+    /// Maps the file into memory and decodes it from there. The function bodies and data segments
+    /// stay in the mapping rather than being copied out of it, so they are only read in from the
+    /// file as they're used. The file is unmapped once the module and all its instances are gone.
+    ///
+    /// # Safety
+    ///
+    /// The module reads straight from the mapping, as does anything decoded from it. The file
+    /// mustn't be written to or truncated, by this process or any other, while the module or any
+    /// of its instances are alive, the same as for memmap's Mmap::map. Deleting or renaming it is
+    /// fine, since the mapping keeps what it had.
+    #[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+    pub unsafe fn load_module_from_mmap(file: &str) -> Result<Self> {
+        let mapping = core::SharedBytes::mapped(core::MappedFile::open(file)?);
+        Ok(Self::new(RawModule::from_shared(mapping)?))
+    }
 
-            Ok(())
-        }
+    pub fn load_module_from_path(file: &str) -> Result<Self> {
+        let mut buf = BufReader::new(File::open(file)?);
+        let raw_module = core::RawModule::read(&mut buf)?;
+        Ok(Self::new(raw_module))
     }
 
-    fn initialize_memory<Iter: Iterator<Item = core::Data>>(&self, iter: Iter) -> Result<()> {
-        for data in iter {
-            self.initialize_memory_data(data)?;
-        }
+    pub fn raw_module(&self) -> &RawModule {
+        &self.raw
+    }
 
-        Ok(())
+    pub fn content_hash(&self) -> core::ModuleHash {
+        self.raw.content_hash()
     }
 
-    fn evaluate_offset_expression(&self, expr: &impl InstructionSource) -> Result<usize> {
-        let result = evaluate_constant_expression(expr, self, 1)?;
+    pub fn code_hash(&self) -> core::ModuleHash {
+        self.raw.code_hash()
+    }
 
-        match result[0] {
-            StackEntry::I32Entry(i) => Ok(usize::try_from(i).unwrap()),
-            _ => Err(anyhow!("Type mismatch in offset expression")),
-        }
+    pub(crate) fn shared_func_types(&self) -> &[Rc<core::FuncType>] {
+        &self.func_types
     }
 
-    pub fn resolve_raw_module<Resolver: core::Resolver>(
-        module: RawModule,
-        resolver: &Resolver,
-    ) -> Result<Module> {
-        let mut ret_module = Self::new();
-        ret_module.resolve_imports(module.imports.into_iter(), &module.metadata, resolver)?;
-        ret_module.add_functions(
-            module.typeidx.into_iter().zip(module.funcs.into_iter()),
-            &module.metadata,
-        )?;
-        ret_module.add_tables(module.tables.into_iter())?;
-        ret_module.add_memories(module.mems.into_iter())?;
-        ret_module.add_globals(module.globals.into_iter())?;
-        ret_module.collect_exports(module.exports.into_iter())?;
-        ret_module.add_func_types(module.metadata.types)?;
-
-        // Everything prior to this point is setting up the environment so that we
-        // can start executing things, so make sure that everything is sane once we're
-        // at that point.
-        ret_module.pre_execute_validate()?;
-
-        // The next step is to initialize the tables and memories.
-        ret_module.initialize_table_elements(module.elem.into_iter())?;
-        ret_module.initialize_memory(module.data.into_iter())?;
-
-        // Finally, if there is a start function specified then execute it.
-        if let Some(start) = module.start {
-            if start >= ret_module.functions.len() {
-                return Err(anyhow!("Start function not found"));
-            }
+    pub fn instantiate<R: core::Resolver>(&self, resolver: &R) -> Result<Instance> {
+        Instance::new_from_module(self, resolver)
+    }
 
-            let start = ret_module.functions[start].clone();
+    pub fn instantiate_with_options<R: core::Resolver>(
+        &self,
+        resolver: &R,
+        options: &core::InstanceOptions,
+    ) -> Result<Instance> {
+        Instance::new_from_module_with_options(self, resolver, options)
+    }
 
-            let mut stack = Stack::new();
-            start.borrow().call(&mut stack, &mut ret_module)?;
-        }
+    // The start function, by its index in the function index space. Every instance has already
+    // run it by the time instantiate returns, unless it was told to defer it.
+    pub fn start_func_index(&self) -> Option<usize> {
+        self.raw.start
+    }
 
-        Ok(ret_module)
+    pub fn num_imported_functions(&self) -> usize {
+        self.import_counts.functions
     }
-}
 
-impl ConstantExpressionStore for Module {
-    type GlobalRef = CellRefType<Global>;
+    pub fn num_defined_functions(&self) -> usize {
+        self.raw.funcs.len()
+    }
 
-    fn global_idx<'a>(&'a self, idx: usize) -> Result<Ref<'a, Global>> {
-        if idx < self.globals.len() {
-            Ok(self.globals[idx].borrow())
-        } else {
-            Err(anyhow!("Global index out of range"))
-        }
+    pub fn num_imported_tables(&self) -> usize {
+        self.import_counts.tables
     }
-}
 
-impl ExpressionStore for Module {
-    type GlobalRefMut = CellRefMutType<Global>;
-    type FuncTypeRef = RefType<FuncType>;
-    type TableRef = CellRefType<Table>;
-    type CallableRef = CellRefType<Callable>;
-    type MemoryRef = CellRefType<Memory>;
-    type MemoryRefMut = CellRefMutType<Memory>;
-
-    fn global_idx_mut<'a>(&'a mut self, idx: usize) -> Result<RefMut<'a, Global>> {
-        if idx < self.globals.len() {
-            Ok(self.globals[idx].borrow_mut())
-        } else {
-            Err(anyhow!("Global index out of range"))
-        }
+    pub fn num_defined_tables(&self) -> usize {
+        self.raw.tables.len()
     }
 
-    fn func_type_idx<'a>(&'a self, idx: usize) -> Result<&'a FuncType> {
-        if idx < self.func_types.len() {
-            Ok(&self.func_types[idx])
-        } else {
-            Err(anyhow!("FuncType index out of range"))
-        }
+    pub fn num_imported_memories(&self) -> usize {
+        self.import_counts.memories
     }
 
-    fn table_idx<'a>(&'a self, idx: usize) -> Result<Ref<'a, Table>> {
-        if idx < self.tables.len() {
-            Ok(self.tables[idx].borrow())
-        } else {
-            Err(anyhow!("Table index out of range"))
+    pub fn num_defined_memories(&self) -> usize {
+        self.raw.mems.len()
+    }
+
+    pub fn num_imported_globals(&self) -> usize {
+        self.import_counts.globals
+    }
+
+    pub fn num_defined_globals(&self) -> usize {
+        self.raw.globals.len()
+    }
+
+    // Roughly how many bytes the decoded module takes up on the heap, counting the bodies, data
+    // and custom sections along with the tables of types, functions, imports and exports. Anything
+    // shared through an Rc is only counted once, however many times it's referred to, and a module
+    // that was mapped from a file doesn't count the mapping.
+    pub fn approx_memory_usage(&self) -> usize {
+        let raw = &self.raw;
+        let func_type_size = |func_type: &core::FuncType| {
+            size_of::<core::FuncType>()
+                + (func_type.params().len() + func_type.results().len())
+                    * size_of::<core::ValueType>()
+        };
+        // The buffers the expressions and data segments are slices of, by their address
+        let mut buffers = HashMap::new();
+        let exprs = raw.funcs.iter().map(|func| func.expr());
+        let exprs = exprs.chain(raw.globals.iter().map(|global| global.init_expr()));
+        let exprs = exprs.chain(raw.elem.iter().filter_map(|elem| elem.offset_expr()));
+        let exprs = exprs.chain(raw.elem.iter().flat_map(|elem| match elem.items() {
+            core::ElementItems::FuncIndices(_) => &[],
+            core::ElementItems::Exprs(exprs) => exprs.as_slice(),
+        }));
+        let exprs = exprs.chain(raw.data.iter().map(|data| data.expr()));
+        let data = raw.data.iter().map(|data| data.shared_bytes());
+        for bytes in exprs.map(|expr| expr.shared_bytes()).chain(data) {
+            let (buffer, heap_bytes) = bytes.buffer_usage();
+            buffers.insert(buffer, RC_OVERHEAD + heap_bytes);
         }
+
+        let mut usage = size_of::<Module>();
+        usage += raw.metadata.types.iter().map(func_type_size).sum::<usize>();
+        usage += self
+            .func_types
+            .iter()
+            .map(|func_type| RC_OVERHEAD + func_type_size(func_type))
+            .sum::<usize>();
+        usage += vec_size(&raw.typeidx);
+        usage += vec_size(&raw.funcs);
+        usage += raw
+            .funcs
+            .iter()
+            .map(|func| RC_OVERHEAD + size_of_val(func.locals()))
+            .sum::<usize>();
+        usage += vec_size(&raw.tables) + vec_size(&raw.mems);
+        usage += vec_size(&raw.globals);
+        usage += vec_size(&raw.elem);
+        usage += raw
+            .elem
+            .iter()
+            .map(|elem| match elem.items() {
+                core::ElementItems::FuncIndices(func_indices) => {
+                    size_of_val(func_indices.as_slice())
+                }
+                core::ElementItems::Exprs(exprs) => size_of_val(exprs.as_slice()),
+            })
+            .sum::<usize>();
+        usage += vec_size(&raw.data);
+        usage += vec_size(&raw.imports);
+        usage += raw
+            .imports
+            .iter()
+            .map(|import| import.mod_name().len() + import.name().len())
+            .sum::<usize>();
+        usage += vec_size(&raw.exports);
+        usage += raw
+            .exports
+            .iter()
+            .map(|export| export.nm.capacity())
+            .sum::<usize>();
+        usage += raw.names.approx_memory_usage();
+        usage += vec_size(&raw.custom_sections);
+        usage += raw
+            .custom_sections
+            .iter()
+            .map(|section| section.name().len() + RC_OVERHEAD + section.bytes().len())
+            .sum::<usize>();
+        usage += buffers.values().sum::<usize>();
+
+        usage
     }
 
-    fn callable_idx<'a>(&'a self, idx: usize) -> Result<Ref<'a, Callable>> {
-        if idx < self.functions.len() {
-            Ok(self.functions[idx].borrow())
+    // The type of a function, by its index in the function index space. An imported function's is
+    // the type its import declares.
+    pub fn func_type(&self, func_idx: usize) -> Option<&core::FuncType> {
+        let type_idx = if func_idx < self.num_imported_functions() {
+            self.raw
+                .imports
+                .iter()
+                .filter_map(|import| match import.desc() {
+                    core::ImportDesc::TypeIdx(type_idx) => Some(*type_idx),
+                    _ => None,
+                })
+                .nth(func_idx)?
         } else {
-            Err(anyhow!("Callable index out of range"))
-        }
+            *self
+                .raw
+                .typeidx
+                .get(func_idx - self.num_imported_functions())?
+        };
+        self.raw.metadata.types.get(type_idx)
     }
 
-    fn mem_idx<'a>(&'a self, idx: usize) -> Result<Ref<'a, Memory>> {
-        if idx < self.memories.len() {
-            Ok(self.memories[idx].borrow())
+    // Describes a function index for error messages, such as "func 3 (imported from env::log)"
+    // or "func 7 (defined)"
+    pub fn describe_func(&self, func_idx: usize) -> String {
+        if func_idx < self.num_imported_functions() {
+            let (mod_name, name) = self
+                .raw
+                .imports
+                .iter()
+                .filter_map(|import| match import.desc() {
+                    core::ImportDesc::TypeIdx(_) => Some((import.mod_name(), import.name())),
+                    _ => None,
+                })
+                .nth(func_idx)
+                .unwrap();
+            format!("func {} (imported from {}::{})", func_idx, mod_name, name)
+        } else if func_idx < self.num_imported_functions() + self.num_defined_functions() {
+            format!("func {} (defined)", func_idx)
         } else {
-            Err(anyhow!("Memory index out of range"))
+            format!("func {} (out of range)", func_idx)
         }
     }
 
-    fn mem_idx_mut<'a>(&'a mut self, idx: usize) -> Result<RefMut<'a, Memory>> {
-        if idx < self.memories.len() {
-            Ok(self.memories[idx].borrow_mut())
-        } else {
-            Err(anyhow!("Memory index out of range"))
+    // Describes a global index for error messages, by its name from the name section if it has
+    // one, such as "global 1 'stack_pointer'", or otherwise by what it was imported from
+    pub fn describe_global(&self, global_idx: usize) -> String {
+        let name = self.raw.names.global_name(global_idx);
+        describe_item(&self.raw.imports, ExportKind::Global, global_idx, name)
+    }
+
+    // The same as describe_global, for a memory
+    pub fn describe_memory(&self, mem_idx: usize) -> String {
+        let name = self.raw.names.memory_name(mem_idx);
+        describe_item(&self.raw.imports, ExportKind::Memory, mem_idx, name)
+    }
+
+    // Lists the filled slots of one of an instance's tables, one per line, naming the functions
+    // from this module's name section where it can. The instance should be one made from this
+    // module, otherwise the names won't mean anything.
+    pub fn dump_table(&self, instance: &Instance, table_idx: usize) -> Result<String> {
+        let table = match instance.tables.get(table_idx) {
+            Some(table) => table.borrow(),
+            None => return Err(anyhow!("Table index {} is out of range", table_idx)),
+        };
+
+        let mut dump = String::new();
+        for (slot, entry) in table.iter().enumerate() {
+            match entry {
+                Some((_, Some(func_idx))) => match self.raw.func_name(func_idx) {
+                    Some(name) => writeln!(dump, "slot {} -> func {} '{}'", slot, func_idx, name)?,
+                    None => writeln!(dump, "slot {} -> func {}", slot, func_idx)?,
+                },
+                Some((_, None)) => writeln!(dump, "slot {} -> unknown func", slot)?,
+                None => {}
+            }
         }
+
+        Ok(dump)
+    }
+}
+
+fn import_kind(desc: &core::ImportDesc) -> ExportKind {
+    match desc {
+        core::ImportDesc::TypeIdx(_) => ExportKind::Function,
+        core::ImportDesc::TableType(_) => ExportKind::Table,
+        core::ImportDesc::MemType(_) => ExportKind::Memory,
+        core::ImportDesc::GlobalType(_) => ExportKind::Global,
+    }
+}
+
+fn describe_item(
+    imports: &[core::Import],
+    kind: ExportKind,
+    idx: usize,
+    name: Option<&str>,
+) -> String {
+    let mut imported = imports
+        .iter()
+        .filter(|import| import_kind(import.desc()) == kind);
+    match (name, imported.nth(idx)) {
+        (Some(name), _) => format!("{} {} '{}'", kind, idx, name),
+        (None, Some(import)) => format!(
+            "{} {} (imported from {}::{})",
+            kind,
+            idx,
+            import.mod_name(),
+            import.name()
+        ),
+        (None, None) => format!("{} {}", kind, idx),
     }
 }
@@ -0,0 +1,127 @@
+// Stable identities for a module's contents, for keying caches of modules and instances or
+// recording which module ran.
+//
+// What's hashed is a canonical stream of the module's bytes: the module header, then each
+// section as its id, its length as eight little endian bytes and then its payload, so the same
+// sections give the same stream however their sizes were encoded. The content stream has every
+// section in it, and the code stream leaves out the custom sections, so a rebuild that only
+// changed the debug info or the names has the same code hash. RawModule::hash_with hands the
+// stream to any hash, and content_hash and code_hash run it through FNV-1a.
+//
+// FNV-1a is the write trace's hash, which is quick and the same on every platform and version,
+// but not cryptographic. Where the modules could be made to collide on purpose, give hash_with a
+// hash that's made for that. The FNV hashes of a module that's read are worked out as it's read,
+// so the bytes don't have to be kept around for them.
+use anyhow::Result;
+use std::fmt;
+
+use crate::core::SectionType;
+use crate::reader::{SectionIter, MODULE_HEADER};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ModuleHash(pub u64);
+
+impl fmt::Display for ModuleHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+// Which sections go into the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashedSections {
+    // Every section, for the content hash
+    All,
+    // Every section but the custom ones, for the code hash
+    Code,
+}
+
+impl HashedSections {
+    fn includes(self, id: u8) -> bool {
+        self == HashedSections::All || id != u8::from(SectionType::CustomSection)
+    }
+}
+
+fn write_module_header<F: FnMut(&[u8])>(write: &mut F) {
+    write(&MODULE_HEADER);
+}
+
+fn write_section<F: FnMut(&[u8])>(id: u8, payload: &[u8], write: &mut F) {
+    let mut header = [0; 9];
+    header[0] = id;
+    header[1..].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+    write(&header);
+    write(payload);
+}
+
+// Hands the stream for an encoded module to write, a piece at a time
+pub(crate) fn write_stream<F: FnMut(&[u8])>(
+    module: &[u8],
+    sections: HashedSections,
+    mut write: F,
+) -> Result<()> {
+    write_module_header(&mut write);
+    for section in SectionIter::new(module)? {
+        let section = section?;
+        if sections.includes(section.id()) {
+            write_section(section.id(), section.payload(), &mut write);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+// Both hashes, as they're built up a section at a time
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ModuleHasher {
+    content: Fnv1a,
+    code: Fnv1a,
+}
+
+// Both hashes of a whole module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ModuleHashes {
+    pub(crate) content: ModuleHash,
+    pub(crate) code: ModuleHash,
+}
+
+impl ModuleHasher {
+    pub(crate) fn new() -> Self {
+        let mut hash = Fnv1a::new();
+        write_module_header(&mut |bytes| hash.write(bytes));
+        Self {
+            content: hash,
+            code: hash,
+        }
+    }
+
+    pub(crate) fn add_section(&mut self, id: u8, payload: &[u8]) {
+        let content = &mut self.content;
+        write_section(id, payload, &mut |bytes| content.write(bytes));
+        if HashedSections::Code.includes(id) {
+            let code = &mut self.code;
+            write_section(id, payload, &mut |bytes| code.write(bytes));
+        }
+    }
+
+    pub(crate) fn finish(&self) -> ModuleHashes {
+        ModuleHashes {
+            content: ModuleHash(self.content.0),
+            code: ModuleHash(self.code.0),
+        }
+    }
+}
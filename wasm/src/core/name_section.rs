@@ -0,0 +1,234 @@
+use crate::reader::{ReaderUtil, ScopedReader, TypeReader};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::Read;
+
+type NameMap = HashMap<usize, String>;
+type IndirectNameMap = HashMap<usize, NameMap>;
+
+// The names from the name section, including the subsections the extended name section proposal
+// adds. Every index is in its own index space, so imported items come first, and local and label
+// indices are within the function they're in. Labels are numbered in the order their blocks
+// start, the same as the disassembler's @0, @1 and so on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NameSection {
+    module: Option<String>,
+    functions: NameMap,
+    locals: IndirectNameMap,
+    labels: IndirectNameMap,
+    types: NameMap,
+    tables: NameMap,
+    memories: NameMap,
+    globals: NameMap,
+    elems: NameMap,
+    data: NameMap,
+    fields: IndirectNameMap,
+}
+
+fn lookup(names: &NameMap, idx: usize) -> Option<&str> {
+    names.get(&idx).map(|name| name.as_str())
+}
+
+fn lookup_indirect(names: &IndirectNameMap, outer: usize, inner: usize) -> Option<&str> {
+    names.get(&outer).and_then(|names| lookup(names, inner))
+}
+
+fn read_name_map<T: Read>(reader: &mut T) -> Result<NameMap> {
+    Ok(reader
+        .read_vec(|r| Ok((r.read_leb_usize()?, r.read_name()?)))?
+        .into_iter()
+        .collect())
+}
+
+fn read_indirect_name_map<T: Read>(reader: &mut T) -> Result<IndirectNameMap> {
+    Ok(reader
+        .read_vec(|r| Ok((r.read_leb_usize()?, read_name_map(r)?)))?
+        .into_iter()
+        .collect())
+}
+
+impl NameSection {
+    pub fn module_name(&self) -> Option<&str> {
+        self.module.as_deref()
+    }
+
+    pub fn func_name(&self, func_idx: usize) -> Option<&str> {
+        lookup(&self.functions, func_idx)
+    }
+
+    pub fn local_name(&self, func_idx: usize, local_idx: usize) -> Option<&str> {
+        lookup_indirect(&self.locals, func_idx, local_idx)
+    }
+
+    pub fn label_name(&self, func_idx: usize, label_idx: usize) -> Option<&str> {
+        lookup_indirect(&self.labels, func_idx, label_idx)
+    }
+
+    pub fn type_name(&self, type_idx: usize) -> Option<&str> {
+        lookup(&self.types, type_idx)
+    }
+
+    pub fn table_name(&self, table_idx: usize) -> Option<&str> {
+        lookup(&self.tables, table_idx)
+    }
+
+    pub fn memory_name(&self, mem_idx: usize) -> Option<&str> {
+        lookup(&self.memories, mem_idx)
+    }
+
+    pub fn global_name(&self, global_idx: usize) -> Option<&str> {
+        lookup(&self.globals, global_idx)
+    }
+
+    pub fn elem_name(&self, elem_idx: usize) -> Option<&str> {
+        lookup(&self.elems, elem_idx)
+    }
+
+    pub fn data_name(&self, data_idx: usize) -> Option<&str> {
+        lookup(&self.data, data_idx)
+    }
+
+    pub fn field_name(&self, type_idx: usize, field_idx: usize) -> Option<&str> {
+        lookup_indirect(&self.fields, type_idx, field_idx)
+    }
+
+    // Roughly how many bytes the names take up on the heap
+    pub(crate) fn approx_memory_usage(&self) -> usize {
+        let map_size = |names: &NameMap| {
+            names
+                .values()
+                .map(|name| std::mem::size_of::<(usize, String)>() + name.capacity())
+                .sum::<usize>()
+        };
+        let indirect_size = |names: &IndirectNameMap| {
+            names
+                .values()
+                .map(|names| std::mem::size_of::<(usize, NameMap)>() + map_size(names))
+                .sum::<usize>()
+        };
+
+        self.module.as_ref().map_or(0, |name| name.capacity())
+            + map_size(&self.functions)
+            + indirect_size(&self.locals)
+            + indirect_size(&self.labels)
+            + map_size(&self.types)
+            + map_size(&self.tables)
+            + map_size(&self.memories)
+            + map_size(&self.globals)
+            + map_size(&self.elems)
+            + map_size(&self.data)
+            + indirect_size(&self.fields)
+    }
+}
+
+// Reads the payload of a custom section called "name". It's a sequence of subsections, each with
+// an id and a size, and any with an id that isn't known are skipped.
+impl TypeReader for NameSection {
+    fn read<T: Read>(reader: &mut T) -> Result<Self> {
+        let mut names = NameSection::default();
+
+        while let Ok(subsection_id) = reader.read_u8() {
+            let subsection_length = reader.read_leb_usize()?;
+            let mut subsection_reader = ScopedReader::new(reader, subsection_length);
+
+            match subsection_id {
+                0 => names.module = Some(subsection_reader.read_name()?),
+                1 => names
+                    .functions
+                    .extend(read_name_map(&mut subsection_reader)?),
+                2 => names
+                    .locals
+                    .extend(read_indirect_name_map(&mut subsection_reader)?),
+                3 => names
+                    .labels
+                    .extend(read_indirect_name_map(&mut subsection_reader)?),
+                4 => names.types.extend(read_name_map(&mut subsection_reader)?),
+                5 => names.tables.extend(read_name_map(&mut subsection_reader)?),
+                6 => names
+                    .memories
+                    .extend(read_name_map(&mut subsection_reader)?),
+                7 => names.globals.extend(read_name_map(&mut subsection_reader)?),
+                8 => names.elems.extend(read_name_map(&mut subsection_reader)?),
+                9 => names.data.extend(read_name_map(&mut subsection_reader)?),
+                10 => names
+                    .fields
+                    .extend(read_indirect_name_map(&mut subsection_reader)?),
+                _ => {}
+            }
+
+            subsection_reader.read_bytes_to_end()?;
+        }
+
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn name_map(out: &mut Vec<u8>, names: &[(u8, &str)]) {
+        out.push(names.len() as u8);
+        for (idx, name) in names {
+            out.push(*idx);
+            out.push(name.len() as u8);
+            out.extend_from_slice(name.as_bytes());
+        }
+    }
+
+    fn subsection(out: &mut Vec<u8>, id: u8, payload: &[u8]) {
+        out.push(id);
+        out.push(payload.len() as u8);
+        out.extend_from_slice(payload);
+    }
+
+    #[test]
+    fn test_every_subsection() -> Result<()> {
+        let mut section = Vec::new();
+        subsection(&mut section, 0, b"\x04mod1");
+        let direct = [
+            (1, "func"),
+            (4, "type"),
+            (5, "table"),
+            (6, "memory"),
+            (7, "global"),
+            (8, "elem"),
+            (9, "data"),
+        ];
+        for (id, name) in direct.iter() {
+            let mut payload = Vec::new();
+            name_map(&mut payload, &[(0, "unused"), (2, name)]);
+            subsection(&mut section, *id, &payload);
+        }
+        for (id, name) in [(2, "local"), (3, "label"), (10, "field")].iter() {
+            let mut payload = vec![1, 3];
+            name_map(&mut payload, &[(1, name)]);
+            subsection(&mut section, *id, &payload);
+        }
+        // An id from some later proposal is skipped over
+        subsection(&mut section, 11, &[1, 0, 3, b'a', b'b', b'c']);
+
+        let names = NameSection::read(&mut &section[..])?;
+        assert_eq!(names.module_name(), Some("mod1"));
+        assert_eq!(names.func_name(2), Some("func"));
+        assert_eq!(names.func_name(1), None);
+        assert_eq!(names.type_name(2), Some("type"));
+        assert_eq!(names.table_name(2), Some("table"));
+        assert_eq!(names.memory_name(2), Some("memory"));
+        assert_eq!(names.global_name(2), Some("global"));
+        assert_eq!(names.elem_name(2), Some("elem"));
+        assert_eq!(names.data_name(0), Some("unused"));
+        assert_eq!(names.data_name(2), Some("data"));
+        assert_eq!(names.local_name(3, 1), Some("local"));
+        assert_eq!(names.local_name(1, 3), None);
+        assert_eq!(names.label_name(3, 1), Some("label"));
+        assert_eq!(names.field_name(3, 1), Some("field"));
+
+        // A subsection that doesn't match its size spoils the section
+        let mut bad = Vec::new();
+        subsection(&mut bad, 7, &[1, 0, 9, b'x']);
+        assert!(NameSection::read(&mut &bad[..]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+// Spans for the interpreter's phases, for an application that wants them alongside its own:
+// wasm.decode for reading a module, wasm.link for resolving its imports, wasm.instantiate for
+// setting up an instance, and wasm.call for each call made into one from outside. They're
+// reported to the PhaseSubscriber set for the thread, which is only there with the
+// phase-trace feature. Without it every hook is empty, and the fields are never worked out.
+//
+// Nothing here depends on the tracing crate. A subscriber that forwards to it keeps a stack of
+// the spans it has entered, pushing in enter and popping in exit, and records events in the
+// innermost one:
+//
+//     fn enter(&self, phase: Phase, fields: &[Field]) {
+//         let span = match phase {
+//             Phase::Decode => tracing::info_span!("wasm.decode", size = Empty, types = Empty, ...),
+//             ...
+//         };
+//         for field in fields {
+//             match &field.value {
+//                 FieldValue::U64(value) => span.record(field.name, value),
+//                 FieldValue::Bool(value) => span.record(field.name, value),
+//                 FieldValue::Str(value) => span.record(field.name, value.as_str()),
+//             };
+//         }
+//         self.spans.borrow_mut().push(span.entered());
+//     }
+//
+// A phase is always exited before the one it's in is, including when it fails, and a host
+// function calling back into an instance gets its own wasm.call inside the outer one, so the
+// stack always matches. The hooks are called with nothing borrowed, so a subscriber can use the
+// interpreter itself.
+#[cfg(feature = "phase-trace")]
+use std::cell::RefCell;
+use std::fmt;
+#[cfg(feature = "phase-trace")]
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Decode,
+    Link,
+    Instantiate,
+    Call,
+}
+
+impl Phase {
+    // The span name, which is the same for every phase of this kind
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Decode => "wasm.decode",
+            Phase::Link => "wasm.link",
+            Phase::Instantiate => "wasm.instantiate",
+            Phase::Call => "wasm.call",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldValue {
+    U64(u64),
+    Bool(bool),
+    Str(String),
+}
+
+// A named value on a span or an event. The names are fixed for each phase, so they can be
+// matched on, and a value that isn't known, such as the fuel a call used when there's no limit
+// on it, is left out rather than given a placeholder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub value: FieldValue,
+}
+
+impl Field {
+    pub(crate) fn u64(name: &'static str, value: u64) -> Self {
+        Self {
+            name,
+            value: FieldValue::U64(value),
+        }
+    }
+
+    pub(crate) fn bool(name: &'static str, value: bool) -> Self {
+        Self {
+            name,
+            value: FieldValue::Bool(value),
+        }
+    }
+
+    pub(crate) fn str(name: &'static str, value: &str) -> Self {
+        Self {
+            name,
+            value: FieldValue::Str(value.to_string()),
+        }
+    }
+}
+
+// Looks a field up by its name
+#[cfg(feature = "phase-trace")]
+pub fn find_field<'a>(fields: &'a [Field], name: &str) -> Option<&'a FieldValue> {
+    fields
+        .iter()
+        .find(|field| field.name == name)
+        .map(|field| &field.value)
+}
+
+// Told as each phase starts and finishes, and about what happens within them:
+//
+//     wasm.decode       enter: size
+//                       exit: outcome, and when it's "ok", types, imports, functions, tables,
+//                             memories, globals, exports, elements, data and custom_sections
+//     wasm.link         enter: imports
+//                       event "import" for each resolved one: module, name, kind
+//                       exit: outcome
+//     wasm.instantiate  enter: functions, tables, memories, globals
+//                       event "elements": segments; event "data": segments, bytes;
+//                       event "start": func_idx, deferred
+//                       exit: outcome
+//     wasm.call         enter: name, and func_idx for a wasm function rather than a host one
+//                       exit: outcome, and fuel_consumed when there's a fuel limit
+//
+// The outcome is "ok" or "failed" for the first three, and "returned", "exited" or "trapped" for
+// a call. Linking happens within wasm.instantiate, and the start function's call is a wasm.call
+// within it too.
+#[cfg(feature = "phase-trace")]
+pub trait PhaseSubscriber {
+    fn enter(&self, phase: Phase, fields: &[Field]);
+
+    fn event(&self, phase: Phase, name: &'static str, fields: &[Field]);
+
+    fn exit(&self, phase: Phase, fields: &[Field]);
+}
+
+#[cfg(feature = "phase-trace")]
+thread_local! {
+    static SUBSCRIBER: RefCell<Option<Rc<dyn PhaseSubscriber>>> = RefCell::new(None);
+}
+
+// Sets the subscriber for the phases on this thread, or takes it away, giving back the one there
+// was before. It's per thread rather than per instance because decoding doesn't have an instance.
+#[cfg(feature = "phase-trace")]
+pub fn set_phase_subscriber(
+    subscriber: Option<Rc<dyn PhaseSubscriber>>,
+) -> Option<Rc<dyn PhaseSubscriber>> {
+    SUBSCRIBER.with(|current| current.replace(subscriber))
+}
+
+// The subscriber is copied out first, so it can set another one, or be told about nested phases
+#[cfg(feature = "phase-trace")]
+fn with_subscriber(f: impl FnOnce(&dyn PhaseSubscriber)) {
+    let subscriber = SUBSCRIBER.with(|current| current.borrow().clone());
+    if let Some(subscriber) = subscriber {
+        f(subscriber.as_ref());
+    }
+}
+
+// The hooks the phases call. The fields are made by a closure, which is only called when there's
+// a subscriber to give them to.
+#[inline(always)]
+pub(crate) fn enter(phase: Phase, fields: impl FnOnce() -> Vec<Field>) {
+    #[cfg(feature = "phase-trace")]
+    with_subscriber(|subscriber| subscriber.enter(phase, &fields()));
+    #[cfg(not(feature = "phase-trace"))]
+    let _ = (phase, fields);
+}
+
+#[inline(always)]
+pub(crate) fn event(phase: Phase, name: &'static str, fields: impl FnOnce() -> Vec<Field>) {
+    #[cfg(feature = "phase-trace")]
+    with_subscriber(|subscriber| subscriber.event(phase, name, &fields()));
+    #[cfg(not(feature = "phase-trace"))]
+    let _ = (phase, name, fields);
+}
+
+#[inline(always)]
+pub(crate) fn exit(phase: Phase, fields: impl FnOnce() -> Vec<Field>) {
+    #[cfg(feature = "phase-trace")]
+    with_subscriber(|subscriber| subscriber.exit(phase, &fields()));
+    #[cfg(not(feature = "phase-trace"))]
+    let _ = (phase, fields);
+}
+
+// Exits the phase with an outcome of "ok" or "failed", depending on the result, and any other
+// fields, which are only made if it's ok
+#[inline(always)]
+pub(crate) fn exit_with<T, E>(
+    phase: Phase,
+    result: &Result<T, E>,
+    fields: impl FnOnce(&T) -> Vec<Field>,
+) {
+    exit(phase, || match result {
+        Ok(value) => {
+            let mut all = vec![Field::str("outcome", "ok")];
+            all.extend(fields(value));
+            all
+        }
+        Err(_) => vec![Field::str("outcome", "failed")],
+    });
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use std::os::raw::c_void;
+
+// What's reserved past the largest the memory can be, so that an access at the end of it with the
+// largest offset a load or store can have still lands in the reservation
+const GUARD_SIZE: usize = 4 << 30;
+
+// Address space for the whole of a linear memory, reserved up front with only the pages it
+// currently has made accessible. Growing just makes more of the reservation accessible, so the
+// contents never move, and nothing has to be copied. The rest of the reservation, including the
+// guard region after the largest the memory can be, faults if it's touched. Accesses are still
+// bounds checked before they're made, so the guard is there as a backstop rather than as the
+// check itself.
+pub(crate) struct ReservedMemory {
+    base: *mut u8,
+    reserved: usize,
+    committed: usize,
+}
+
+impl ReservedMemory {
+    // Reserves room for max_len bytes plus the guard region, with none of it accessible
+    pub(crate) fn reserve(max_len: usize) -> Result<Self> {
+        let reserved = max_len
+            .checked_add(GUARD_SIZE)
+            .ok_or_else(|| anyhow!("Memory of {} bytes is too big to reserve", max_len))?;
+        let base = sys::reserve(reserved)?;
+        Ok(Self {
+            base,
+            reserved,
+            committed: 0,
+        })
+    }
+
+    // Makes the first new_len bytes accessible. New pages are zeroed by the system.
+    pub(crate) fn commit(&mut self, new_len: usize) -> Result<()> {
+        if new_len > self.reserved - GUARD_SIZE {
+            return Err(anyhow!("New memory is too big"));
+        }
+        if new_len > self.committed {
+            unsafe {
+                sys::commit(self.base.add(self.committed), new_len - self.committed)?;
+            }
+            self.committed = new_len;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.base, self.committed) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.base, self.committed) }
+    }
+}
+
+impl Drop for ReservedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            sys::release(self.base, self.reserved);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use super::*;
+    use std::os::raw::c_int;
+
+    const PROT_NONE: c_int = 0;
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const MAP_PRIVATE: c_int = 0x02;
+    #[cfg(target_os = "linux")]
+    const MAP_ANONYMOUS: c_int = 0x20;
+    #[cfg(not(target_os = "linux"))]
+    const MAP_ANONYMOUS: c_int = 0x1000;
+    #[cfg(target_os = "linux")]
+    const MAP_NORESERVE: c_int = 0x4000;
+    #[cfg(not(target_os = "linux"))]
+    const MAP_NORESERVE: c_int = 0x40;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+
+    pub(super) fn reserve(len: usize) -> Result<*mut u8> {
+        let addr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_NONE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr as isize == -1 {
+            Err(anyhow!(
+                "Failed to reserve {} bytes: {}",
+                len,
+                std::io::Error::last_os_error()
+            ))
+        } else {
+            Ok(addr as *mut u8)
+        }
+    }
+
+    pub(super) unsafe fn commit(addr: *mut u8, len: usize) -> Result<()> {
+        if mprotect(addr as *mut c_void, len, PROT_READ | PROT_WRITE) == 0 {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Failed to commit {} bytes: {}",
+                len,
+                std::io::Error::last_os_error()
+            ))
+        }
+    }
+
+    pub(super) unsafe fn release(addr: *mut u8, len: usize) {
+        munmap(addr as *mut c_void, len);
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+
+    const MEM_COMMIT: u32 = 0x1000;
+    const MEM_RESERVE: u32 = 0x2000;
+    const MEM_RELEASE: u32 = 0x8000;
+    const PAGE_NOACCESS: u32 = 0x01;
+    const PAGE_READWRITE: u32 = 0x04;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn VirtualAlloc(
+            addr: *mut c_void,
+            size: usize,
+            allocation_type: u32,
+            protect: u32,
+        ) -> *mut c_void;
+        fn VirtualFree(addr: *mut c_void, size: usize, free_type: u32) -> i32;
+    }
+
+    pub(super) fn reserve(len: usize) -> Result<*mut u8> {
+        let addr = unsafe { VirtualAlloc(std::ptr::null_mut(), len, MEM_RESERVE, PAGE_NOACCESS) };
+        if addr.is_null() {
+            Err(anyhow!(
+                "Failed to reserve {} bytes: {}",
+                len,
+                std::io::Error::last_os_error()
+            ))
+        } else {
+            Ok(addr as *mut u8)
+        }
+    }
+
+    pub(super) unsafe fn commit(addr: *mut u8, len: usize) -> Result<()> {
+        if VirtualAlloc(addr as *mut c_void, len, MEM_COMMIT, PAGE_READWRITE).is_null() {
+            Err(anyhow!(
+                "Failed to commit {} bytes: {}",
+                len,
+                std::io::Error::last_os_error()
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(super) unsafe fn release(addr: *mut u8, _len: usize) {
+        VirtualFree(addr as *mut c_void, 0, MEM_RELEASE);
+    }
+}
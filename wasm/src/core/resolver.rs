@@ -1,8 +1,12 @@
 use anyhow::{anyhow, Result};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::core::{Callable, FuncType, Global, GlobalType, MemType, Memory, Table, TableType};
+use crate::core::{
+    Callable, ExportKind, Extern, ExternType, FuncType, Global, GlobalType, Instance, Limits,
+    MemType, Memory, Table, TableType,
+};
 
 pub trait Resolver {
     fn resolve_function(
@@ -29,6 +33,27 @@ pub trait Resolver {
         name: &str,
         global_type: &GlobalType,
     ) -> Result<Rc<RefCell<Global>>>;
+
+    // Resolves an import of any kind, which is what an instance asks for. By default it goes to
+    // the method for the kind, so only a resolver that handles every kind the same way, such as
+    // one that looks them up by name, needs to provide it. The instance checks that what it gets
+    // is the kind and type it asked for.
+    fn resolve(&self, mod_name: &str, name: &str, extern_type: &ExternType) -> Result<Extern> {
+        Ok(match extern_type {
+            ExternType::Func(func_type) => {
+                Extern::Func(self.resolve_function(mod_name, name, func_type)?)
+            }
+            ExternType::Table(table_type) => {
+                Extern::Table(self.resolve_table(mod_name, name, table_type)?)
+            }
+            ExternType::Memory(mem_type) => {
+                Extern::Memory(self.resolve_memory(mod_name, name, mem_type)?)
+            }
+            ExternType::Global(global_type) => {
+                Extern::Global(self.resolve_global(mod_name, name, global_type)?)
+            }
+        })
+    }
 }
 
 pub struct EmptyResolver {}
@@ -75,3 +100,279 @@ impl EmptyResolver {
         &EMPTY_RESOLVER_INSTANCE
     }
 }
+
+// A resolver that hands out the externs it's been given, by the module and name they were
+// defined with. Everything a linked module needs can be put in it, including all of another
+// instance's exports at once, so modules can import from each other.
+#[derive(Debug, Clone, Default)]
+pub struct ImportObject {
+    externs: HashMap<(String, String), Extern>,
+}
+
+impl ImportObject {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces whatever was defined with the same module and name before
+    pub fn define(&mut self, mod_name: &str, name: &str, value: impl Into<Extern>) -> &mut Self {
+        self.externs
+            .insert((mod_name.to_string(), name.to_string()), value.into());
+        self
+    }
+
+    // Defines every export of the instance under the module name
+    pub fn define_instance(&mut self, mod_name: &str, instance: &Instance) -> &mut Self {
+        for (name, value) in instance.exports_in_order() {
+            self.define(mod_name, name, value.clone());
+        }
+        self
+    }
+
+    pub fn get(&self, mod_name: &str, name: &str) -> Option<&Extern> {
+        self.externs.get(&(mod_name.to_string(), name.to_string()))
+    }
+
+    // The extern as the kind asked for, or an error naming the kind it is
+    fn get_as<T>(
+        &self,
+        mod_name: &str,
+        name: &str,
+        kind: ExportKind,
+        into: impl FnOnce(Extern) -> std::result::Result<T, Extern>,
+    ) -> Result<T> {
+        let value = self
+            .get(mod_name, name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Imported {} {}:{} not found", kind, mod_name, name))?;
+        into(value).map_err(|value| {
+            anyhow!(
+                "Imported {} {}:{} is a {}",
+                kind,
+                mod_name,
+                name,
+                value.kind()
+            )
+        })
+    }
+}
+
+impl Resolver for ImportObject {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        self.get_as(mod_name, name, ExportKind::Function, Extern::into_function)
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        self.get_as(mod_name, name, ExportKind::Table, Extern::into_table)
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        self.get_as(mod_name, name, ExportKind::Memory, Extern::into_memory)
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        self.get_as(mod_name, name, ExportKind::Global, Extern::into_global)
+    }
+
+    // Whatever was defined, whatever kind it is, so that an import of the wrong kind is reported
+    // as that by the instance
+    fn resolve(&self, mod_name: &str, name: &str, extern_type: &ExternType) -> Result<Extern> {
+        self.get(mod_name, name).cloned().ok_or_else(|| {
+            anyhow!(
+                "Imported {} {}:{} not found",
+                extern_type.kind(),
+                mod_name,
+                name
+            )
+        })
+    }
+}
+
+type CachePolicy = Box<dyn Fn(&str, &str, ExportKind) -> bool>;
+
+// Wraps another resolver, and remembers what it resolved each import to, so that importing the
+// same thing again, from the same module or any other made with this resolver, hands back the
+// same object rather than asking the inner resolver again. Only successful resolutions are kept.
+//
+// What's in the cache is only used for an import it fits, the same way an instance checks
+// what a resolver gives it, so that two modules can import the same name at different types.
+// An import it doesn't fit goes to the inner resolver, and the cache keeps what it had.
+//
+// Everything is cached unless a policy says otherwise, which is how imports that each instance
+// should have its own of, such as a memory, are kept out of it.
+pub struct CachingResolver<R: Resolver> {
+    inner: R,
+    cache: RefCell<HashMap<(String, String, ExportKind), Extern>>,
+    policy: CachePolicy,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_policy(inner, |_, _, _| true)
+    }
+
+    // The policy is given the module and name of each import and what kind it is, and says
+    // whether it can be cached
+    pub fn with_policy(
+        inner: R,
+        policy: impl Fn(&str, &str, ExportKind) -> bool + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            cache: RefCell::new(HashMap::new()),
+            policy: Box::new(policy),
+        }
+    }
+
+    // Forgets everything, so that every import is resolved afresh
+    pub fn invalidate(&self) {
+        self.cache.borrow_mut().clear();
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    fn cached<T>(
+        &self,
+        mod_name: &str,
+        name: &str,
+        kind: ExportKind,
+        fitting: impl FnOnce(&Extern) -> Option<Rc<RefCell<T>>>,
+        resolve: impl FnOnce() -> Result<Rc<RefCell<T>>>,
+        wrap: impl FnOnce(Rc<RefCell<T>>) -> Extern,
+    ) -> Result<Rc<RefCell<T>>> {
+        if !(self.policy)(mod_name, name, kind) {
+            return resolve();
+        }
+
+        let key = (mod_name.to_string(), name.to_string(), kind);
+        let hit = self.cache.borrow().get(&key).and_then(fitting);
+        if let Some(value) = hit {
+            return Ok(value);
+        }
+
+        let value = resolve()?;
+        self.cache
+            .borrow_mut()
+            .entry(key)
+            .or_insert_with(|| wrap(value.clone()));
+        Ok(value)
+    }
+}
+
+// Whether something of the given size and maximum can be used for an import with the limits
+fn fits_limits(size: usize, max: Option<usize>, limits: &Limits) -> bool {
+    let max_fits = match (limits.max(), max) {
+        (None, _) => true,
+        (Some(limit), Some(max)) => max <= limit,
+        (Some(_), None) => false,
+    };
+    size >= limits.min() && max_fits
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        self.cached(
+            mod_name,
+            name,
+            ExportKind::Function,
+            |value| {
+                let function = value.as_function()?;
+                let fits = function.borrow().func_type() == func_type;
+                Some(function.clone()).filter(|_| fits)
+            },
+            || self.inner.resolve_function(mod_name, name, func_type),
+            Extern::Func,
+        )
+    }
+
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        self.cached(
+            mod_name,
+            name,
+            ExportKind::Table,
+            |value| {
+                let table = value.as_table()?;
+                let fits = {
+                    let table = table.borrow();
+                    table.ty().elem_type() == table_type.elem_type()
+                        && fits_limits(table.current_size(), table.max_size(), table_type.limits())
+                };
+                Some(table.clone()).filter(|_| fits)
+            },
+            || self.inner.resolve_table(mod_name, name, table_type),
+            Extern::Table,
+        )
+    }
+
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        self.cached(
+            mod_name,
+            name,
+            ExportKind::Memory,
+            |value| {
+                let memory = value.as_memory()?;
+                let fits = {
+                    let memory = memory.borrow();
+                    fits_limits(memory.current_size(), memory.max_size(), mem_type.limits())
+                };
+                Some(memory.clone()).filter(|_| fits)
+            },
+            || self.inner.resolve_memory(mod_name, name, mem_type),
+            Extern::Memory,
+        )
+    }
+
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        self.cached(
+            mod_name,
+            name,
+            ExportKind::Global,
+            |value| {
+                let global = value.as_global()?;
+                let fits = global.borrow().global_type() == global_type;
+                Some(global.clone()).filter(|_| fits)
+            },
+            || self.inner.resolve_global(mod_name, name, global_type),
+            Extern::Global,
+        )
+    }
+}
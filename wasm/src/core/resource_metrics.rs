@@ -0,0 +1,35 @@
+use crate::core::Stack;
+
+// What an instance is using, and the most it has used since it was made or the metrics were last
+// reset. The memory and table figures cover every memory and table the instance has, including
+// any it imported and shares with other instances.
+//
+// The peaks are kept up to date as the guest runs, with a little work when a function is called
+// and when memory.grow succeeds. Memory the host grows itself is caught the next time the metrics
+// are read. The stack depth is the value stack, which holds the parameters and locals of every
+// frame as well as their operands, as it is when each function starts, so a frame that pushes
+// a lot of operands without calling anything can take it past the peak recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceMetrics {
+    pub memory_bytes: usize,
+    pub peak_memory_bytes: usize,
+    pub table_elements: usize,
+    pub host_calls: u64,
+    pub peak_stack_depth: usize,
+    pub peak_call_depth: usize,
+}
+
+impl ResourceMetrics {
+    pub(crate) fn record_call(&mut self, stack: &Stack, is_host: bool) {
+        if is_host {
+            self.host_calls += 1;
+        }
+        self.peak_stack_depth = self.peak_stack_depth.max(stack.height());
+        self.peak_call_depth = self.peak_call_depth.max(stack.frame_count());
+    }
+
+    pub(crate) fn record_memory(&mut self, memory_bytes: usize) {
+        self.memory_bytes = memory_bytes;
+        self.peak_memory_bytes = self.peak_memory_bytes.max(memory_bytes);
+    }
+}
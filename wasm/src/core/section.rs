@@ -1,9 +1,9 @@
 use crate::reader::{ReaderUtil, TypeReader};
 use anyhow::{anyhow, Result};
-use num_enum::TryFromPrimitive;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::io::Read;
 
-#[derive(Debug, PartialEq, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum SectionType {
     CustomSection,
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
+
+#[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+use crate::core::MappedFile;
+
+// Where the bytes of a loaded module live
+enum Buffer {
+    Heap(Box<[u8]>),
+    #[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+    Mapped(MappedFile),
+}
+
+impl Buffer {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Buffer::Heap(bytes) => bytes,
+            #[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+            Buffer::Mapped(file) => file.bytes(),
+        }
+    }
+}
+
+// A range of a buffer that's shared with everything else decoded from the same module. Function
+// bodies and data segments are slices of the module they came from rather than copies of it, and
+// the buffer is freed, or unmapped, once the last of them is dropped.
+#[derive(Clone)]
+pub(crate) struct SharedBytes {
+    buffer: Rc<Buffer>,
+    start: usize,
+    end: usize,
+}
+
+impl SharedBytes {
+    #[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+    pub(crate) fn mapped(file: MappedFile) -> Self {
+        let end = file.bytes().len();
+        Self {
+            buffer: Rc::new(Buffer::Mapped(file)),
+            start: 0,
+            end,
+        }
+    }
+
+    // Part of these bytes, sharing the same buffer. The range is relative to the start of these
+    // bytes, not of the buffer.
+    pub(crate) fn slice(&self, offset: usize, length: usize) -> Result<Self> {
+        if offset > self.len() || length > self.len() - offset {
+            return Err(anyhow!(
+                "{} bytes at offset {} are past the end of {} bytes",
+                length,
+                offset,
+                self.len()
+            ));
+        }
+
+        Ok(Self {
+            buffer: self.buffer.clone(),
+            start: self.start + offset,
+            end: self.start + offset + length,
+        })
+    }
+
+    // An identity for the buffer these bytes are in, and how many heap bytes it takes up. Mapped
+    // buffers aren't on the heap, so they take up none.
+    pub(crate) fn buffer_usage(&self) -> (usize, usize) {
+        let heap_bytes = match &*self.buffer {
+            Buffer::Heap(bytes) => bytes.len(),
+            #[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+            Buffer::Mapped(_) => 0,
+        };
+        (&*self.buffer as *const Buffer as usize, heap_bytes)
+    }
+}
+
+impl From<Vec<u8>> for SharedBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        let end = bytes.len();
+        Self {
+            buffer: Rc::new(Buffer::Heap(bytes.into_boxed_slice())),
+            start: 0,
+            end,
+        }
+    }
+}
+
+impl Deref for SharedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer.bytes()[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for SharedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -1,5 +1,11 @@
-use crate::core::{stack_entry::StackEntry, FuncType, Locals, ValueType};
+use crate::core::{
+    stack_entry::StackEntry, AccessSite, FrameSnapshot, FuncType, Locals, SnapshotLimits,
+    StackSnapshot, ValueType,
+};
 use anyhow::{anyhow, Result};
+use std::fmt::Write;
+use std::ops::Range;
+use std::rc::Rc;
 
 struct LocalsFlatteningIterator<'a, T: Iterator<Item = &'a Locals>> {
     iter: T,
@@ -61,25 +67,32 @@ struct StackLabel {
 #[derive(Debug)]
 pub struct StackFrame {
     sp: usize,
+    func_idx: Option<usize>,
     parameter_count: usize,
     local_count: usize,
     label_stack: Vec<StackLabel>,
-    return_types: Vec<ValueType>,
+    // Shared with the function being called, so pushing a frame doesn't copy its result types
+    func_type: Rc<FuncType>,
+    // Where the function's expression is, so that an instruction can be placed in it
+    code: Range<usize>,
 }
 
 impl StackFrame {
     pub fn new(
         sp: usize,
+        func_idx: Option<usize>,
         parameter_count: usize,
         local_count: usize,
-        return_types: Vec<ValueType>,
+        func_type: Rc<FuncType>,
     ) -> Self {
         Self {
             sp,
+            func_idx,
             parameter_count,
             local_count,
             label_stack: Vec::new(),
-            return_types,
+            func_type,
+            code: 0..0,
         }
     }
 
@@ -144,10 +157,23 @@ impl StackFrame {
     }
 }
 
+// A read only summary of one call frame, for diagnostics
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameInfo {
+    // The index of the function in its module's function index space, when it is known
+    pub func_idx: Option<usize>,
+    pub parameter_count: usize,
+    pub local_count: usize,
+    // Values pushed above the locals, including ones that belong to enclosing blocks
+    pub operand_count: usize,
+}
+
 #[derive(Debug)]
 pub struct Stack {
     frames: Vec<StackFrame>,
     entries: Vec<StackEntry>,
+    // The label stacks of frames that have been popped, kept so that calls don't allocate new ones
+    spare_labels: Vec<Vec<StackLabel>>,
 }
 
 impl Stack {
@@ -156,7 +182,31 @@ impl Stack {
         Stack {
             frames: Vec::new(),
             entries: Vec::new(),
+            spare_labels: Vec::new(),
+        }
+    }
+
+    // A stack whose values go in the given storage, which is cleared first
+    pub(crate) fn from_entries(mut entries: Vec<StackEntry>) -> Self {
+        entries.clear();
+        Stack {
+            frames: Vec::new(),
+            entries,
+            spare_labels: Vec::new(),
+        }
+    }
+
+    // The storage the values were in, for from_entries to use again
+    pub(crate) fn into_entries(self) -> Vec<StackEntry> {
+        self.entries
+    }
+
+    // Empties the stack, keeping what it's allocated so it can be used for another call
+    pub(crate) fn clear(&mut self) {
+        while !self.frames.is_empty() {
+            self.pop_frame();
         }
+        self.entries.clear();
     }
 
     #[allow(dead_code)]
@@ -237,8 +287,8 @@ impl Stack {
         &self.entries[base..limit]
     }
 
-    #[allow(dead_code)]
-    pub fn frame_mut(&mut self) -> &mut [StackEntry] {
+    #[cfg(test)]
+    pub(crate) fn frame_mut(&mut self) -> &mut [StackEntry] {
         let (base, limit) = (self.frame_base(), self.frame_limit());
         &mut self.entries[base..limit]
     }
@@ -249,8 +299,7 @@ impl Stack {
         &self.entries[base..limit]
     }
 
-    #[allow(dead_code)]
-    pub fn local_mut(&mut self) -> &mut [StackEntry] {
+    pub(crate) fn local_mut(&mut self) -> &mut [StackEntry] {
         let (base, limit) = (self.parameter_base(), self.local_limit());
         &mut self.entries[base..limit]
     }
@@ -261,31 +310,27 @@ impl Stack {
         &self.entries[base..limit]
     }
 
-    #[allow(dead_code)]
-    pub fn push(&mut self, entry: StackEntry) {
+    pub(crate) fn push(&mut self, entry: StackEntry) {
         self.entries.push(entry);
     }
 
-    #[allow(dead_code)]
-    pub fn push_from_slice(&mut self, entries: &[StackEntry]) {
+    pub(crate) fn push_from_slice(&mut self, entries: &[StackEntry]) {
         self.entries.extend_from_slice(entries);
     }
 
-    #[allow(dead_code)]
-    pub fn pop(&mut self) {
+    pub(crate) fn pop(&mut self) {
         assert!(self.working_count() > 0);
 
         self.entries.pop();
     }
 
-    #[allow(dead_code)]
-    pub fn pop_n(&mut self, n: usize) {
+    pub(crate) fn pop_n(&mut self, n: usize) {
         assert!(self.working_count() >= n);
 
         self.entries.truncate(self.entries.len() - n);
     }
 
-    pub fn drop_entries(&mut self, to_drop: usize, arity: usize) {
+    pub(crate) fn drop_entries(&mut self, to_drop: usize, arity: usize) {
         assert!(self.working_count() >= to_drop + arity);
 
         let old_result_base = self.working_limit() - arity;
@@ -307,7 +352,21 @@ impl Stack {
         self.push_typed_frame(&func_type, &locals)
     }
 
-    pub fn push_typed_frame(&mut self, func_type: &FuncType, locals: &Vec<Locals>) -> Result<()> {
+    #[cfg(test)]
+    pub(crate) fn push_typed_frame(
+        &mut self,
+        func_type: &FuncType,
+        locals: &[Locals],
+    ) -> Result<()> {
+        self.push_function_frame(None, &Rc::new(func_type.clone()), locals)
+    }
+
+    pub(crate) fn push_function_frame(
+        &mut self,
+        func_idx: Option<usize>,
+        func_type: &Rc<FuncType>,
+        locals: &[Locals],
+    ) -> Result<()> {
         let arg_count = func_type.arg_types().len();
         let local_count = locals.iter().map(|l| l.count() as usize).sum();
         if arg_count > self.working_count() {
@@ -333,12 +392,16 @@ impl Stack {
             match matched_args {
                 Err(e) => Err(e),
                 _ => {
-                    let frame = StackFrame::new(
+                    let mut frame = StackFrame::new(
                         self.height() - arg_count,
+                        func_idx,
                         arg_count,
                         local_count,
-                        func_type.return_types().clone(),
+                        func_type.clone(),
                     );
+                    if let Some(label_stack) = self.spare_labels.pop() {
+                        frame.label_stack = label_stack;
+                    }
 
                     // Push on zeroed out entries for the locals
                     for (_, l) in flatten_locals(locals.iter()).enumerate() {
@@ -360,9 +423,35 @@ impl Stack {
         }
     }
 
-    pub fn pop_typed_frame(&mut self) -> Result<()> {
+    // Records where the code of the function in the top frame is
+    pub(crate) fn set_frame_code(&mut self, code: &[u8]) {
+        if let Some(frame) = self.frames.last_mut() {
+            let start = code.as_ptr() as usize;
+            frame.code = start..start + code.len();
+        }
+    }
+
+    // Where an instruction the function in the top frame is running is, for a watchpoint
+    pub(crate) fn access_site(&self, instruction: &[u8]) -> AccessSite {
+        match self.frames.last() {
+            Some(frame) => {
+                let address = instruction.as_ptr() as usize;
+                AccessSite {
+                    func_idx: frame.func_idx,
+                    instruction_offset: if frame.code.contains(&address) {
+                        Some(address - frame.code.start)
+                    } else {
+                        None
+                    },
+                }
+            }
+            None => AccessSite::default(),
+        }
+    }
+
+    pub(crate) fn pop_typed_frame(&mut self) -> Result<()> {
         let last_frame = self.frames.last().unwrap();
-        let return_types = &last_frame.return_types;
+        let return_types = last_frame.func_type.results();
 
         if self.working_count() < return_types.len() {
             Err(anyhow!("Insufficient return values"))
@@ -393,7 +482,7 @@ impl Stack {
                     let new_len = self.frame_base() + arity;
 
                     // Pop the frame entry off the stack now as we don't need it any more
-                    self.frames.pop();
+                    self.pop_frame();
 
                     for i in 0..arity {
                         self.entries[new_result_base + i] = self.entries[old_result_base + i];
@@ -407,12 +496,28 @@ impl Stack {
         }
     }
 
-    pub fn push_label(&mut self, arity: usize) {
+    // Throws away the top frame without looking at its results, for when execution of the
+    // function has failed. This leaves the stack as it was before the arguments were pushed.
+    pub(crate) fn unwind_frame(&mut self) {
+        let frame_base = self.frame_base();
+        self.pop_frame();
+        self.entries.truncate(frame_base);
+    }
+
+    fn pop_frame(&mut self) {
+        if let Some(frame) = self.frames.pop() {
+            let mut label_stack = frame.label_stack;
+            label_stack.clear();
+            self.spare_labels.push(label_stack);
+        }
+    }
+
+    pub(crate) fn push_label(&mut self, arity: usize) {
         let sp = self.height();
         self.frames.last_mut().unwrap().push_label(sp, arity);
     }
 
-    pub fn pop_n_labels(&mut self, count: usize) {
+    pub(crate) fn pop_n_labels(&mut self, count: usize) {
         // We ask the frame to drop the labels and tell us how to fix up the
         // stack
         let (sp, arity) = self.frames.last_mut().unwrap().pop_n_labels(count);
@@ -420,6 +525,117 @@ impl Stack {
     }
 }
 
+// Read only introspection, so that an embedder can log what the interpreter was doing without
+// being able to disturb it
+impl Stack {
+    pub fn operand_count(&self) -> usize {
+        self.height() - self.local_limit()
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Each frame along with the end of its part of the stack, from the innermost outwards
+    fn frames_with_limits(&self) -> impl Iterator<Item = (&StackFrame, usize)> {
+        let limits: Vec<_> = self
+            .frames
+            .iter()
+            .skip(1)
+            .map(|f| f.frame_base())
+            .chain(std::iter::once(self.height()))
+            .collect();
+
+        self.frames.iter().zip(limits).rev()
+    }
+
+    // The frames from the innermost outwards, so the first one is the function that is running
+    pub fn frames(&self) -> impl Iterator<Item = FrameInfo> + '_ {
+        self.frames_with_limits().map(|(frame, limit)| FrameInfo {
+            func_idx: frame.func_idx,
+            parameter_count: frame.parameter_count(),
+            local_count: frame.local_count(),
+            operand_count: limit - frame.local_limit(),
+        })
+    }
+
+    // Copies the values of each frame, keeping as many as the limits allow
+    pub fn snapshot(&self, limits: SnapshotLimits) -> StackSnapshot {
+        let frames = self
+            .frames_with_limits()
+            .take(limits.max_frames)
+            .map(|(frame, limit)| {
+                let locals = &self.entries[frame.parameter_base()..frame.local_limit()];
+                let kept_locals = locals.len().min(limits.max_values);
+                let operands = &self.entries[frame.local_limit()..limit];
+                let kept_operands = operands.len().min(limits.max_values);
+
+                FrameSnapshot {
+                    func_idx: frame.func_idx,
+                    parameter_count: frame.parameter_count(),
+                    locals: locals[..kept_locals].to_vec(),
+                    omitted_locals: locals.len() - kept_locals,
+                    operands: operands[operands.len() - kept_operands..].to_vec(),
+                    omitted_operands: operands.len() - kept_operands,
+                }
+            })
+            .collect();
+
+        StackSnapshot {
+            frames,
+            omitted_frames: self.frame_count().saturating_sub(limits.max_frames),
+        }
+    }
+
+    // Formats at most max_frames frames, and at most max_operands of the topmost operands of each,
+    // so that it stays readable however deep the stack has got
+    pub fn debug_string(&self, max_frames: usize, max_operands: usize) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "stack: {} frames, {} entries",
+            self.frame_count(),
+            self.height()
+        );
+
+        for (depth, (frame, limit)) in self.frames_with_limits().enumerate() {
+            if depth == max_frames {
+                let _ = writeln!(out, "  ... {} more frames", self.frame_count() - depth);
+                break;
+            }
+
+            let func = match frame.func_idx {
+                Some(idx) => format!("func {}", idx),
+                None => "func ?".to_string(),
+            };
+            let operands = &self.entries[frame.local_limit()..limit];
+            let shown = &operands[operands.len().saturating_sub(max_operands)..];
+
+            let _ = write!(
+                out,
+                "  #{} {}: {} params, {} locals, {} operands [",
+                depth,
+                func,
+                frame.parameter_count(),
+                frame.local_count(),
+                operands.len()
+            );
+            if shown.len() < operands.len() {
+                let _ = write!(out, "..., ");
+            }
+            for (idx, entry) in shown.iter().enumerate() {
+                if idx > 0 {
+                    let _ = write!(out, ", ");
+                }
+                let _ = write!(out, "{:?}", entry);
+            }
+            let _ = writeln!(out, "]");
+        }
+
+        out
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -702,4 +918,63 @@ mod test {
         assert_eq!(stack.working_top(2)[0], 26.0_f64.into());
         assert_eq!(stack.working_top(2)[1], 52.0_f64.into());
     }
+
+    #[test]
+    fn test_frame_introspection() {
+        let mut stack = Stack::new();
+        assert_eq!(stack.frame_count(), 0);
+        assert_eq!(stack.frames().count(), 0);
+
+        let outer_type = FuncType::new(vec![], vec![]);
+        let outer_locals = vec![Locals::new(2, ValueType::I32)];
+        assert!(stack
+            .push_function_frame(Some(3), &Rc::new(outer_type), &outer_locals)
+            .is_ok());
+        for i in 0..5 {
+            stack.push(StackEntry::I32Entry(i));
+        }
+
+        // The top two operands become the arguments of the inner call
+        let inner_type = FuncType::new(vec![ValueType::I32, ValueType::I32], vec![]);
+        let inner_locals = vec![Locals::new(1, ValueType::I64)];
+        assert!(stack
+            .push_function_frame(Some(7), &Rc::new(inner_type), &inner_locals)
+            .is_ok());
+        stack.push(StackEntry::I64Entry(9));
+        stack.push_label(0);
+        stack.push(StackEntry::I64Entry(10));
+
+        assert_eq!(stack.frame_count(), 2);
+        assert_eq!(stack.operand_count(), 2);
+        assert_eq!(
+            stack.frames().collect::<Vec<_>>(),
+            vec![
+                FrameInfo {
+                    func_idx: Some(7),
+                    parameter_count: 2,
+                    local_count: 1,
+                    operand_count: 2,
+                },
+                FrameInfo {
+                    func_idx: Some(3),
+                    parameter_count: 0,
+                    local_count: 2,
+                    operand_count: 3,
+                },
+            ]
+        );
+
+        assert_eq!(
+            stack.debug_string(1, 1),
+            "stack: 2 frames, 10 entries\n  \
+             #0 func 7: 2 params, 1 locals, 2 operands [..., I64Entry(10)]\n  \
+             ... 1 more frames\n"
+        );
+        assert_eq!(
+            stack.debug_string(5, 5),
+            "stack: 2 frames, 10 entries\n  \
+             #0 func 7: 2 params, 1 locals, 2 operands [I64Entry(9), I64Entry(10)]\n  \
+             #1 func 3: 0 params, 2 locals, 3 operands [I32Entry(0), I32Entry(1), I32Entry(2)]\n"
+        );
+    }
 }
@@ -1,8 +1,47 @@
-use anyhow::{anyhow, Error};
+use crate::core::ValueType;
+use anyhow::Error;
 use std::convert::{From, TryFrom};
+use std::fmt;
 
-static INVALID_CONVERSION_MESSAGE: &'static str = "Cannot convert stack entry";
+// The error the conversions to Rust scalars fail with, wrapped up in an anyhow error. It names the
+// type that was asked for and the type of the entry that was there instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackEntryConversionError {
+    target: &'static str,
+    found: ValueType,
+}
+
+impl StackEntryConversionError {
+    fn new(target: &'static str, found: &StackEntry) -> Self {
+        Self {
+            target,
+            found: found.ty(),
+        }
+    }
+
+    pub fn target(&self) -> &'static str {
+        self.target
+    }
+
+    pub fn found(&self) -> &ValueType {
+        &self.found
+    }
+}
+
+impl fmt::Display for StackEntryConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cannot convert {} stack entry to {}",
+            self.found, self.target
+        )
+    }
+}
+
+impl std::error::Error for StackEntryConversionError {}
 
+// Equality is the float comparison for float entries, so NaN is not equal to itself and the two
+// zeros are equal. Use bitwise_eq where the exact bits matter, such as when checking NaN results.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum StackEntry {
     I32Entry(u32),
@@ -21,6 +60,26 @@ impl StackEntry {
             _ => false,
         }
     }
+
+    pub fn ty(&self) -> ValueType {
+        match self {
+            StackEntry::I32Entry(_) => ValueType::I32,
+            StackEntry::I64Entry(_) => ValueType::I64,
+            StackEntry::F32Entry(_) => ValueType::F32,
+            StackEntry::F64Entry(_) => ValueType::F64,
+        }
+    }
+
+    // True when both entries are the same type and hold exactly the same bits
+    pub fn bitwise_eq(&self, other: &StackEntry) -> bool {
+        match (self, other) {
+            (StackEntry::I32Entry(a), StackEntry::I32Entry(b)) => a == b,
+            (StackEntry::I64Entry(a), StackEntry::I64Entry(b)) => a == b,
+            (StackEntry::F32Entry(a), StackEntry::F32Entry(b)) => a.to_bits() == b.to_bits(),
+            (StackEntry::F64Entry(a), StackEntry::F64Entry(b)) => a.to_bits() == b.to_bits(),
+            _ => false,
+        }
+    }
 }
 
 impl From<u32> for StackEntry {
@@ -37,14 +96,14 @@ impl TryFrom<StackEntry> for u32 {
             StackEntry::I32Entry(u) => Ok(u),
             // Should this handle the case where it is an I64Entry and the value fits? That would simplify
             // some things, but may complicate other things by not being strict enough
-            _ => Err(anyhow!(INVALID_CONVERSION_MESSAGE)),
+            other => Err(StackEntryConversionError::new("u32", &other).into()),
         }
     }
 }
 
 impl From<i32> for StackEntry {
     fn from(i: i32) -> StackEntry {
-        Self::from(unsafe { std::mem::transmute::<i32, u32>(i) })
+        Self::from(i as u32)
     }
 }
 
@@ -52,7 +111,10 @@ impl TryFrom<StackEntry> for i32 {
     type Error = Error;
 
     fn try_from(i: StackEntry) -> Result<Self, Self::Error> {
-        u32::try_from(i).map(|i| unsafe { std::mem::transmute::<u32, i32>(i) })
+        match i {
+            StackEntry::I32Entry(u) => Ok(u as i32),
+            other => Err(StackEntryConversionError::new("i32", &other).into()),
+        }
     }
 }
 
@@ -70,14 +132,14 @@ impl TryFrom<StackEntry> for u64 {
             StackEntry::I64Entry(u) => Ok(u),
             // Should this handle the case where it is an I32Entry? That would simplify
             // some things, but may complicate other things by not being strict enough
-            _ => Err(anyhow!(INVALID_CONVERSION_MESSAGE)),
+            other => Err(StackEntryConversionError::new("u64", &other).into()),
         }
     }
 }
 
 impl From<i64> for StackEntry {
     fn from(i: i64) -> Self {
-        Self::from(unsafe { std::mem::transmute::<i64, u64>(i) })
+        Self::from(i as u64)
     }
 }
 
@@ -85,7 +147,10 @@ impl TryFrom<StackEntry> for i64 {
     type Error = Error;
 
     fn try_from(i: StackEntry) -> Result<Self, Self::Error> {
-        u64::try_from(i).map(|i| unsafe { std::mem::transmute::<u64, i64>(i) })
+        match i {
+            StackEntry::I64Entry(u) => Ok(u as i64),
+            other => Err(StackEntryConversionError::new("i64", &other).into()),
+        }
     }
 }
 
@@ -101,7 +166,7 @@ impl TryFrom<StackEntry> for f32 {
     fn try_from(i: StackEntry) -> Result<Self, Self::Error> {
         match i {
             StackEntry::F32Entry(f) => Ok(f),
-            _ => Err(anyhow!(INVALID_CONVERSION_MESSAGE)),
+            other => Err(StackEntryConversionError::new("f32", &other).into()),
         }
     }
 }
@@ -118,7 +183,7 @@ impl TryFrom<StackEntry> for f64 {
     fn try_from(i: StackEntry) -> Result<Self, Self::Error> {
         match i {
             StackEntry::F64Entry(f) => Ok(f),
-            _ => Err(anyhow!(INVALID_CONVERSION_MESSAGE)),
+            other => Err(StackEntryConversionError::new("f64", &other).into()),
         }
     }
 }
@@ -210,4 +275,48 @@ mod test {
         assert!(f64::try_from(StackEntry::F32Entry(32.0)).is_err());
         assert_eq!(f64::try_from(StackEntry::F64Entry(32.0)).ok(), Some(32.0));
     }
+
+    #[test]
+    fn test_conversion_errors() {
+        let error = i32::try_from(StackEntry::I64Entry(1)).unwrap_err();
+        assert_eq!(
+            format!("{}", error),
+            "Cannot convert i64 stack entry to i32"
+        );
+
+        let error = u64::try_from(StackEntry::F32Entry(1.0)).unwrap_err();
+        let error = error.downcast_ref::<StackEntryConversionError>().unwrap();
+        assert_eq!(error.target(), "u64");
+        assert_eq!(error.found(), &ValueType::F32);
+
+        let error = f64::try_from(StackEntry::I32Entry(1)).unwrap_err();
+        assert_eq!(
+            format!("{}", error),
+            "Cannot convert i32 stack entry to f64"
+        );
+    }
+
+    #[test]
+    fn test_ty_and_bitwise_eq() {
+        assert_eq!(StackEntry::from(1_u32).ty(), ValueType::I32);
+        assert_eq!(StackEntry::from(1_i64).ty(), ValueType::I64);
+        assert_eq!(StackEntry::from(1_f32).ty(), ValueType::F32);
+        assert_eq!(StackEntry::from(1_f64).ty(), ValueType::F64);
+
+        // NaNs are only equal to themselves bitwise
+        let nan = StackEntry::from(std::f32::NAN);
+        assert_ne!(nan, nan);
+        assert!(nan.bitwise_eq(&nan));
+        assert!(!nan.bitwise_eq(&StackEntry::from(-std::f32::NAN)));
+
+        // And the two zeros are only different bitwise
+        let zero = StackEntry::from(0.0_f64);
+        let negative_zero = StackEntry::from(-0.0_f64);
+        assert_eq!(zero, negative_zero);
+        assert!(!zero.bitwise_eq(&negative_zero));
+
+        // Entries of different types are never equal, even with the same bits
+        assert!(!StackEntry::from(0_u32).bitwise_eq(&StackEntry::from(0_u64)));
+        assert!(!StackEntry::from(0_u32).bitwise_eq(&StackEntry::from(0.0_f32)));
+    }
 }
@@ -0,0 +1,124 @@
+use crate::core::{stack_entry::StackEntry, Module};
+use std::fmt::Write;
+
+// How much of the stack a snapshot keeps, so that a deep stack or a frame with a great many
+// locals doesn't make a snapshot megabytes long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotLimits {
+    pub max_frames: usize,
+    // For each frame, how many of its parameters and locals, and how many of its operands
+    pub max_values: usize,
+}
+
+impl Default for SnapshotLimits {
+    fn default() -> Self {
+        Self {
+            max_frames: 32,
+            max_values: 16,
+        }
+    }
+}
+
+// One call frame's values, copied off the stack
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameSnapshot {
+    pub func_idx: Option<usize>,
+    pub parameter_count: usize,
+    // The parameters and then the locals, from the first one, and how many more there were
+    pub locals: Vec<StackEntry>,
+    pub omitted_locals: usize,
+    // The topmost operands, with the top one last, and how many more there were under them
+    pub operands: Vec<StackEntry>,
+    pub omitted_operands: usize,
+}
+
+// The values on the stack at one moment, such as when a trap fired, with the innermost frame
+// first. It is a copy, so it stays around once the stack has been unwound.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackSnapshot {
+    pub frames: Vec<FrameSnapshot>,
+    pub omitted_frames: usize,
+}
+
+impl StackSnapshot {
+    // A report along the lines of a core dump. Functions are named from the module if there is
+    // one.
+    pub fn report(&self, module: Option<&Module>) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "stack snapshot: {} frames",
+            self.frames.len() + self.omitted_frames
+        );
+
+        for (depth, frame) in self.frames.iter().enumerate() {
+            let _ = write!(out, "#{} ", depth);
+            match frame.func_idx {
+                Some(func_idx) => {
+                    let _ = write!(out, "func {}", func_idx);
+                    if let Some(name) = module.and_then(|m| m.raw_module().func_name(func_idx)) {
+                        let _ = write!(out, " '{}'", name);
+                    }
+                }
+                None => out.push_str("func ?"),
+            }
+            out.push('\n');
+
+            // The limit can cut off some of the parameters as well as the locals
+            let kept_params = frame.parameter_count.min(frame.locals.len());
+            let omitted_params = frame.parameter_count - kept_params;
+            let (params, locals) = frame.locals.split_at(kept_params);
+            write_values(&mut out, "params", params, omitted_params, false);
+            write_values(
+                &mut out,
+                "locals",
+                locals,
+                frame.omitted_locals - omitted_params,
+                false,
+            );
+            write_values(
+                &mut out,
+                "operands",
+                &frame.operands,
+                frame.omitted_operands,
+                true,
+            );
+        }
+
+        if self.omitted_frames > 0 {
+            let _ = writeln!(out, "... {} more frames", self.omitted_frames);
+        }
+        out
+    }
+}
+
+// Omitted values are at the end of a list, or at the start of it for the operands, which are
+// kept from the top of the stack down
+fn write_values(
+    out: &mut String,
+    what: &str,
+    values: &[StackEntry],
+    omitted: usize,
+    omitted_first: bool,
+) {
+    let mut items: Vec<String> = values.iter().map(format_value).collect();
+    if omitted > 0 {
+        let more = format!("... {} more", omitted);
+        if omitted_first {
+            items.insert(0, more);
+        } else {
+            items.push(more);
+        }
+    }
+    let _ = writeln!(out, "    {}: [{}]", what, items.join(", "));
+}
+
+// Integers are shown signed, which is how they're usually meant
+fn format_value(value: &StackEntry) -> String {
+    match value {
+        StackEntry::I32Entry(v) => format!("i32 {}", *v as i32),
+        StackEntry::I64Entry(v) => format!("i64 {}", *v as i64),
+        StackEntry::F32Entry(v) => format!("f32 {}", v),
+        StackEntry::F64Entry(v) => format!("f64 {}", v),
+    }
+}
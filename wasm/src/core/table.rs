@@ -6,54 +6,51 @@ use std::{
     slice::SliceIndex,
 };
 
-use crate::core::{Callable, ElemType, Limits, TableType};
+use crate::core::{Callable, ElemType, Limits, TableType, Trap, TrapKind};
 
 type RefCallable = Rc<RefCell<Callable>>;
 type OptRefCallable = Option<RefCallable>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Table {
-    minimum_entries: usize,
-    maximum_entries: Option<usize>,
+    table_type: TableType,
     entries: Vec<OptRefCallable>,
+    // The function index each entry was initialized from, where it is known. This is only for
+    // diagnostics, and entries written directly through IndexMut don't update it.
+    func_indices: Vec<Option<usize>>,
 }
 
 impl Table {
     pub fn new(table_type: TableType) -> Self {
         assert!(*table_type.elem_type() == ElemType::FuncRef);
 
-        let (minimum_entries, maximum_entries): (usize, Option<usize>) = match table_type.limits() {
-            Limits::Bounded(minimum_entries, maximum_entries) => {
-                (*minimum_entries, Some(*maximum_entries))
-            }
-            Limits::Unbounded(minimum_entries) => (*minimum_entries, None),
-        };
-
-        Self::new_from_bounds(minimum_entries, maximum_entries)
-    }
-
-    pub fn new_from_bounds(minimum_entries: usize, maximum_entries: Option<usize>) -> Self {
-        let mut entries = Vec::with_capacity(minimum_entries);
-        for _ in 0..minimum_entries {
+        let mut entries = Vec::with_capacity(table_type.limits().min());
+        for _ in 0..table_type.limits().min() {
             entries.push(None)
         }
 
-        // Make the memory object
         Table {
-            minimum_entries,
-            maximum_entries,
+            table_type,
+            func_indices: vec![None; entries.len()],
             entries,
         }
     }
 
+    pub fn new_from_bounds(minimum_entries: usize, maximum_entries: Option<usize>) -> Self {
+        Self::new(TableType::new(
+            ElemType::FuncRef,
+            Limits::new(minimum_entries, maximum_entries),
+        ))
+    }
+
     #[allow(dead_code)]
     pub fn min_size(&self) -> usize {
-        self.minimum_entries
+        self.table_type.limits().min()
     }
 
     #[allow(dead_code)]
     pub fn max_size(&self) -> Option<usize> {
-        self.maximum_entries
+        self.table_type.limits().max()
     }
 
     #[allow(dead_code)]
@@ -61,22 +58,120 @@ impl Table {
         self.entries.len()
     }
 
+    pub fn ty(&self) -> &TableType {
+        &self.table_type
+    }
+
+    // Sizes and limits in elements, as u32 like the specification has them
+    pub fn size(&self) -> u32 {
+        self.current_size() as u32
+    }
+
+    pub fn min(&self) -> u32 {
+        self.min_size() as u32
+    }
+
+    pub fn max(&self) -> Option<u32> {
+        self.max_size().map(|max| max as u32)
+    }
+
+    // Adds empty entries on the end, as long as the table stays within its maximum
+    pub fn grow_by(&mut self, grow_by: usize) -> Result<()> {
+        match self.entries.len().checked_add(grow_by) {
+            Some(new_size) if new_size <= self.max_size().unwrap_or(std::u32::MAX as usize) => {
+                self.entries.resize(new_size, None);
+                self.func_indices.resize(new_size, None);
+                Ok(())
+            }
+
+            _ => Err(anyhow!("New table is too big")),
+        }
+    }
+
     pub fn get_entry(&self, idx: usize) -> Result<RefCallable> {
         if idx < self.entries.len() {
             match &self.entries[idx] {
                 Some(callable) => Ok(callable.clone()),
-                _ => Err(anyhow!("Table entry {} is not defined", idx)),
+                _ => Err(Trap::new(TrapKind::UninitializedElement(idx)).into()),
             }
         } else {
-            Err(anyhow!("Table index {} is out of range", idx))
+            Err(Trap::new(TrapKind::UndefinedElement {
+                index: idx,
+                table_size: self.entries.len(),
+            })
+            .into())
         }
     }
 
     pub fn set_entries(&mut self, offset: usize, functions: &[RefCallable]) {
         for (idx, value) in functions.iter().enumerate() {
             self.entries[offset + idx] = Some(value.clone());
+            self.func_indices[offset + idx] = None;
+        }
+    }
+
+    // Applies an element segment, which names its functions by their index in the instance's
+    // functions, or has a null reference in place of one. Each function goes straight into its
+    // slot, and the slot remembers the index it came from, while a null clears the slot. Nothing
+    // is written unless the whole segment fits and every index is in range.
+    pub fn init_from_func_refs(
+        &mut self,
+        offset: usize,
+        func_refs: &[Option<usize>],
+        functions: &[RefCallable],
+    ) -> Result<()> {
+        let end = offset
+            .checked_add(func_refs.len())
+            .filter(|end| *end <= self.entries.len())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Element segment of {} entries at {} doesn't fit in a table of {}",
+                    func_refs.len(),
+                    offset,
+                    self.entries.len()
+                )
+            })?;
+        if let Some(func_idx) = func_refs
+            .iter()
+            .flatten()
+            .find(|idx| **idx >= functions.len())
+        {
+            return Err(anyhow!("Function index {} out of range", func_idx));
+        }
+
+        let slots = self.entries[offset..end]
+            .iter_mut()
+            .zip(&mut self.func_indices[offset..end]);
+        for ((entry, entry_func_idx), func_ref) in slots.zip(func_refs) {
+            *entry = func_ref.map(|func_idx| functions[func_idx].clone());
+            *entry_func_idx = *func_ref;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // The function index the entry was initialized from, if it is filled and the index is known
+    pub fn entry_func_idx(&self, idx: usize) -> Option<usize> {
+        match self.entries.get(idx) {
+            Some(Some(_)) => self.func_indices[idx],
+            _ => None,
         }
     }
+
+    // Every slot in order, with the function and the index it came from for the filled ones
+    pub fn iter(&self) -> impl Iterator<Item = Option<(&RefCallable, Option<usize>)>> {
+        self.entries
+            .iter()
+            .zip(self.func_indices.iter())
+            .map(|(entry, func_idx)| entry.as_ref().map(|callable| (callable, *func_idx)))
+    }
 }
 
 impl<I: SliceIndex<[OptRefCallable]>> Index<I> for Table {
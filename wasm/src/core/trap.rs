@@ -0,0 +1,303 @@
+use crate::core::{AccessSite, NameSection, StackSnapshot};
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+// Traps are the errors the specification defines for executing a valid module. Everything else
+// that can go wrong during execution is down to an invalid module or a bug in the interpreter,
+// and is reported as a plain error. Traps are carried inside the usual anyhow errors, so an
+// embedder that cares can get at the kind with `downcast_ref::<Trap>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapKind {
+    Unreachable,
+    IntegerOverflow,
+    InvalidConversionToInteger,
+    // An access that doesn't fit in the memory. The address is the effective one, which can be
+    // more than 32 bits can hold, and the width and memory size are in bytes.
+    MemoryOutOfBounds {
+        mem_idx: usize,
+        address: u64,
+        width: usize,
+        write: bool,
+        memory_size: usize,
+    },
+    // An indirect call through an index past the end of the table
+    UndefinedElement {
+        index: usize,
+        table_size: usize,
+    },
+    // An indirect call through a slot in the table that has nothing in it
+    UninitializedElement(usize),
+    // Not a trap in the specification's sense, but the way a host function such as WASI's
+    // proc_exit stops the program. It unwinds the same way a trap does, and the invoke functions
+    // turn it back into an exit code.
+    Exit(u32),
+    // Not traps in the specification's sense either, but how the guest is stopped when it has
+    // used up its fuel or the host interrupts it
+    OutOfFuel,
+    Interrupted,
+    // A trap the host raised for its own reasons, with Trap::host or Trap::with_payload
+    Host,
+}
+
+// Where a trap was raised. Host traps are the ones made with Trap::host or Trap::with_payload.
+// The rest are the guest's, including the ones a host function makes with Trap::new, since those
+// stand for the conditions the specification defines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapOrigin {
+    Guest,
+    Host,
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_kind(f, self, None)
+    }
+}
+
+// The memory's name is given when it's known, and then the memory is always mentioned
+fn write_kind(f: &mut fmt::Formatter<'_>, kind: &TrapKind, mem_name: Option<&str>) -> fmt::Result {
+    let message = match kind {
+        TrapKind::Unreachable => "unreachable",
+        TrapKind::IntegerOverflow => "integer overflow",
+        TrapKind::InvalidConversionToInteger => "invalid conversion to integer",
+        TrapKind::OutOfFuel => "out of fuel",
+        TrapKind::Interrupted => "interrupted",
+        TrapKind::Host => "host trap",
+        TrapKind::MemoryOutOfBounds {
+            mem_idx,
+            address,
+            width,
+            write,
+            memory_size,
+        } => {
+            write!(
+                f,
+                "out of bounds {} of {} byte{} at {}",
+                if *write { "write" } else { "read" },
+                width,
+                if *width == 1 { "" } else { "s" },
+                grouped_hex(*address)
+            )?;
+            match mem_name {
+                Some(name) => write!(f, " in memory {} '{}'", mem_idx, name)?,
+                None if *mem_idx != 0 => write!(f, " in memory {}", mem_idx)?,
+                None => {}
+            }
+            return write!(f, ", memory size {}", grouped_hex(*memory_size as u64));
+        }
+        TrapKind::UndefinedElement { index, table_size } => {
+            return write!(f, "undefined element {}, table size {}", index, table_size)
+        }
+        TrapKind::UninitializedElement(index) => {
+            return write!(f, "uninitialized element {}", index)
+        }
+        TrapKind::Exit(code) => return write!(f, "exit with code {}", code),
+    };
+    write!(f, "{}", message)
+}
+
+// At least eight hex digits, in groups of four so that addresses are easy to read and compare
+fn grouped_hex(value: u64) -> String {
+    let digits = format!("{:08x}", value);
+    let mut out = String::from("0x");
+    for (idx, digit) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx) % 4 == 0 {
+            out.push('_');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct Trap {
+    kind: TrapKind,
+    // Which function and instruction trapped, for the traps the executor knows that for
+    site: Option<AccessSite>,
+    // The stack as it was when the trap fired, if the instance was asked to keep it
+    snapshot: Option<Box<StackSnapshot>>,
+    // The name section's name for the memory an access was to
+    mem_name: Option<String>,
+    // What the host said when it raised the trap
+    message: Option<String>,
+    // Whatever the host raised the trap with, which comes back out however many frames it
+    // unwinds through. It's shared rather than copied when the trap is cloned.
+    payload: Option<Arc<dyn Any + Send + Sync>>,
+    payload_type: Option<&'static str>,
+}
+
+impl Trap {
+    pub fn new(kind: TrapKind) -> Self {
+        Self {
+            kind,
+            site: None,
+            snapshot: None,
+            mem_name: None,
+            message: None,
+            payload: None,
+            payload_type: None,
+        }
+    }
+
+    // A trap raised by a host function, for a reason of its own rather than one of the ones the
+    // specification defines
+    pub fn host(message: impl Into<String>) -> Self {
+        Self {
+            message: Some(message.into()),
+            ..Self::new(TrapKind::Host)
+        }
+    }
+
+    // A host trap carrying a value the embedder can recognize when it comes back out of the
+    // invoke call, with payload_ref. It has to be Sync as well as Send, since the trap is carried
+    // in an anyhow error.
+    pub fn with_payload<T: Any + Send + Sync>(payload: T) -> Self {
+        Self {
+            payload: Some(Arc::new(payload)),
+            payload_type: Some(std::any::type_name::<T>()),
+            ..Self::new(TrapKind::Host)
+        }
+    }
+
+    pub fn kind(&self) -> TrapKind {
+        self.kind
+    }
+
+    pub fn site(&self) -> Option<AccessSite> {
+        self.site
+    }
+
+    pub fn snapshot(&self) -> Option<&StackSnapshot> {
+        self.snapshot.as_deref()
+    }
+
+    pub fn mem_name(&self) -> Option<&str> {
+        self.mem_name.as_deref()
+    }
+
+    pub fn origin(&self) -> TrapOrigin {
+        match self.kind {
+            TrapKind::Host => TrapOrigin::Host,
+            _ => TrapOrigin::Guest,
+        }
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    pub fn payload(&self) -> Option<&(dyn Any + Send + Sync)> {
+        self.payload.as_deref()
+    }
+
+    // The payload, if there is one and it's a T
+    pub fn payload_ref<T: Any>(&self) -> Option<&T> {
+        self.payload()
+            .and_then(|payload| payload.downcast_ref::<T>())
+    }
+}
+
+// Two traps with payloads are only the same if they share the one payload, since there's no
+// telling whether a payload can be compared
+impl PartialEq for Trap {
+    fn eq(&self, other: &Self) -> bool {
+        let same_payload = match (&self.payload, &other.payload) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        self.kind == other.kind
+            && self.site == other.site
+            && self.snapshot == other.snapshot
+            && self.mem_name == other.mem_name
+            && self.message == other.message
+            && same_payload
+    }
+}
+
+// Keeps the stack with a trap, before unwinding loses it. It's taken where the trap first comes
+// through a wasm frame, so it has every frame in it. An exit isn't a failure, so it doesn't get one.
+pub(crate) fn attach_snapshot(error: &mut anyhow::Error, snapshot: impl FnOnce() -> StackSnapshot) {
+    if let Some(trap) = error.downcast_mut::<Trap>() {
+        if let TrapKind::Exit(_) = trap.kind {
+            return;
+        }
+        if trap.snapshot.is_none() {
+            trap.snapshot = Some(Box::new(snapshot()));
+        }
+    }
+}
+
+// Says where a trap came from, if the error is one and doesn't say already. The memory an access
+// was to is filled in too, since a memory doesn't know its own index.
+pub(crate) fn locate_trap(
+    mut error: anyhow::Error,
+    mem_idx: Option<usize>,
+    site: AccessSite,
+) -> anyhow::Error {
+    if let Some(trap) = error.downcast_mut::<Trap>() {
+        if trap.site.is_none() {
+            trap.site = Some(site);
+        }
+        if let (TrapKind::MemoryOutOfBounds { mem_idx: idx, .. }, Some(mem_idx)) =
+            (&mut trap.kind, mem_idx)
+        {
+            *idx = mem_idx;
+        }
+    }
+    error
+}
+
+// Gives the memory a trap was in its name, if the error is an out of bounds access and the
+// module's name section names the memory
+pub(crate) fn name_trap_memory(mut error: anyhow::Error, names: &NameSection) -> anyhow::Error {
+    if let Some(trap) = error.downcast_mut::<Trap>() {
+        if let TrapKind::MemoryOutOfBounds { mem_idx, .. } = trap.kind {
+            if trap.mem_name.is_none() {
+                trap.mem_name = names.memory_name(mem_idx).map(|name| name.to_string());
+            }
+        }
+    }
+    error
+}
+
+// The exit code of an error that is an exit rather than a failure
+pub fn exit_code(error: &anyhow::Error) -> Option<u32> {
+    match error.downcast_ref::<Trap>().map(|trap| trap.kind()) {
+        Some(TrapKind::Exit(code)) => Some(code),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Trap: ")?;
+        write_kind(f, &self.kind, self.mem_name())?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        if let Some(payload_type) = self.payload_type {
+            write!(f, " ({})", payload_type)?;
+        }
+        match self.site {
+            Some(AccessSite {
+                func_idx: Some(func_idx),
+                instruction_offset: Some(offset),
+            }) => write!(f, " (func {}, offset {:#x})", func_idx, offset),
+            Some(AccessSite {
+                func_idx: Some(func_idx),
+                ..
+            }) => write!(f, " (func {})", func_idx),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+impl From<TrapKind> for Trap {
+    fn from(kind: TrapKind) -> Self {
+        Self::new(kind)
+    }
+}
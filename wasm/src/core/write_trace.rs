@@ -0,0 +1,291 @@
+use anyhow::{anyhow, Result};
+use std::fmt::{self, Write as _};
+use std::io;
+
+use crate::core::AccessSite;
+
+// One write to memory, numbered in the order they happened. Only the first bytes of a long write
+// are kept, but the hash is of all of them, so traces can still be compared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteRecord {
+    pub seq: u64,
+    pub site: AccessSite,
+    pub address: usize,
+    pub len: usize,
+    pub bytes: Vec<u8>,
+    pub hash: u64,
+}
+
+impl WriteRecord {
+    // Whether two writes put the same bytes in the same place, wherever they came from, since
+    // another engine won't have the same functions and offsets
+    pub fn same_write(&self, other: &WriteRecord) -> bool {
+        self.address == other.address && self.len == other.len && self.hash == other.hash
+    }
+
+    // A line of text, with a dash for a part of the site that isn't known:
+    //
+    //     <seq> <func> <offset> <address> <len> <hash> <bytes>
+    //
+    // The numbers other than the sequence number and the function are in hex.
+    pub fn to_line(&self) -> String {
+        let optional = |value: Option<usize>, hex: bool| match value {
+            Some(v) if hex => format!("{:#x}", v),
+            Some(v) => format!("{}", v),
+            None => "-".to_string(),
+        };
+
+        let mut line = format!(
+            "{} {} {} {:#x} {:#x} {:016x} ",
+            self.seq,
+            optional(self.site.func_idx, false),
+            optional(self.site.instruction_offset, true),
+            self.address,
+            self.len,
+            self.hash
+        );
+        for byte in &self.bytes {
+            let _ = write!(line, "{:02x}", byte);
+        }
+        line
+    }
+
+    pub fn from_line(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || fields.len() > 7 {
+            return Err(anyhow!("Write record {:?} doesn't have 7 fields", line));
+        }
+
+        let number = |text: &str| -> Result<usize> {
+            let parsed = match text.get(..2) {
+                Some("0x") => usize::from_str_radix(&text[2..], 16),
+                _ => text.parse(),
+            };
+            parsed.map_err(|_| anyhow!("{} isn't a number in write record {:?}", text, line))
+        };
+        let optional = |text: &str| -> Result<Option<usize>> {
+            match text {
+                "-" => Ok(None),
+                _ => number(text).map(Some),
+            }
+        };
+
+        let hex = fields.get(6).cloned().unwrap_or("");
+        if hex.len() % 2 != 0 {
+            return Err(anyhow!(
+                "Odd number of hex digits in write record {:?}",
+                line
+            ));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| anyhow!("Bad bytes in write record {:?}", line))?;
+
+        Ok(Self {
+            seq: number(fields[0])? as u64,
+            site: AccessSite {
+                func_idx: optional(fields[1])?,
+                instruction_offset: optional(fields[2])?,
+            },
+            address: number(fields[3])?,
+            len: number(fields[4])?,
+            hash: u64::from_str_radix(fields[5], 16)
+                .map_err(|_| anyhow!("Bad hash in write record {:?}", line))?,
+            bytes,
+        })
+    }
+}
+
+// Where a trace's records go, a batch at a time. Any closure that takes a batch is a sink, and
+// LineSink writes them out as text.
+pub trait WriteSink {
+    fn write_records(&mut self, records: &[WriteRecord]) -> Result<()>;
+}
+
+impl<F: FnMut(&[WriteRecord]) -> Result<()>> WriteSink for F {
+    fn write_records(&mut self, records: &[WriteRecord]) -> Result<()> {
+        self(records)
+    }
+}
+
+// Writes each record as a line of text
+pub struct LineSink<W: io::Write>(pub W);
+
+impl<W: io::Write> WriteSink for LineSink<W> {
+    fn write_records(&mut self, records: &[WriteRecord]) -> Result<()> {
+        for record in records {
+            writeln!(self.0, "{}", record.to_line())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteTraceOptions {
+    // How many bytes of each write are kept
+    pub max_bytes: usize,
+    // How many records are handed to the sink at once
+    pub batch_size: usize,
+}
+
+impl Default for WriteTraceOptions {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64,
+            batch_size: 256,
+        }
+    }
+}
+
+pub(crate) struct WriteTrace {
+    sink: Box<dyn WriteSink>,
+    options: WriteTraceOptions,
+    batch: Vec<WriteRecord>,
+    next_seq: u64,
+}
+
+impl WriteTrace {
+    pub fn new(sink: Box<dyn WriteSink>, options: WriteTraceOptions) -> Self {
+        Self {
+            sink,
+            options,
+            batch: Vec::with_capacity(options.batch_size),
+            next_seq: 0,
+        }
+    }
+
+    pub fn record(&mut self, address: usize, data: &[u8], site: AccessSite) -> Result<()> {
+        self.batch.push(WriteRecord {
+            seq: self.next_seq,
+            site,
+            address,
+            len: data.len(),
+            bytes: data[..data.len().min(self.options.max_bytes)].to_vec(),
+            hash: fnv1a(data),
+        });
+        self.next_seq += 1;
+
+        if self.batch.len() >= self.options.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let result = self.sink.write_records(&self.batch);
+        self.batch.clear();
+        result
+    }
+}
+
+// Whatever hasn't been handed over yet goes to the sink when the trace is dropped with its memory,
+// though there's nowhere for an error to go by then
+impl Drop for WriteTrace {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl fmt::Debug for WriteTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteTrace")
+            .field("options", &self.options)
+            .field("next_seq", &self.next_seq)
+            .finish()
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// Where two traces first differ. Either side is None if that trace ended first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteMismatch {
+    pub index: usize,
+    pub left: Option<WriteRecord>,
+    pub right: Option<WriteRecord>,
+}
+
+impl fmt::Display for WriteMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = |record: &Option<WriteRecord>| match record {
+            Some(record) => record.to_line(),
+            None => "end of trace".to_string(),
+        };
+        write!(
+            f,
+            "Write {} differs\n  left:  {}\n  right: {}",
+            self.index,
+            side(&self.left),
+            side(&self.right)
+        )
+    }
+}
+
+// The first write where two traces differ, comparing what was written where but not where it came
+// from. None if they're the same all the way through.
+pub fn first_write_mismatch(
+    left: impl IntoIterator<Item = WriteRecord>,
+    right: impl IntoIterator<Item = WriteRecord>,
+) -> Option<WriteMismatch> {
+    let mut left = left.into_iter();
+    let mut right = right.into_iter();
+    let mut index = 0;
+
+    loop {
+        match (left.next(), right.next()) {
+            (None, None) => return None,
+            (Some(l), Some(r)) if l.same_write(&r) => index += 1,
+            (l, r) => {
+                return Some(WriteMismatch {
+                    index,
+                    left: l,
+                    right: r,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_record_lines() {
+        let record = WriteRecord {
+            seq: 12,
+            site: AccessSite {
+                func_idx: Some(3),
+                instruction_offset: None,
+            },
+            address: 0x1040,
+            len: 4,
+            bytes: vec![0x44, 0x33, 0x22, 0x11],
+            hash: fnv1a(&[0x44, 0x33, 0x22, 0x11]),
+        };
+
+        let line = record.to_line();
+        assert!(line.starts_with("12 3 - 0x1040 0x4 "));
+        assert!(line.ends_with(" 44332211"));
+        assert_eq!(WriteRecord::from_line(&line).unwrap(), record);
+
+        // An empty write has no bytes at the end
+        let empty = WriteRecord {
+            len: 0,
+            bytes: vec![],
+            hash: fnv1a(&[]),
+            ..record
+        };
+        assert_eq!(WriteRecord::from_line(&empty.to_line()).unwrap(), empty);
+        assert!(WriteRecord::from_line("1 2 3").is_err());
+    }
+}
@@ -0,0 +1,394 @@
+// Running the same call under this interpreter and under another engine, and saying where they
+// disagree. It's for tests and fuzz targets, which bring the other engine, such as wasmi or
+// wasmtime, by implementing Engine for it:
+//
+//     let invocation = Invocation::new("run", vec![1_i32.into()]);
+//     if let Some(divergence) = differential::check(&bytes, &invocation, &mut reference, options) {
+//         panic!("{}", divergence);
+//     }
+//
+// A divergence prints everything needed to reproduce it, module bytes included.
+use std::fmt::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::core::{self, stack_entry::StackEntry, Trap, TrapKind};
+use crate::replay::value_text;
+
+// Traps are compared by what sort of trap they are, since engines word them differently and
+// don't agree on the details. Other is for anything an engine can't put in one of the others,
+// and matches any trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapClass {
+    Unreachable,
+    IntegerOverflow,
+    IntegerDivideByZero,
+    InvalidConversionToInteger,
+    MemoryOutOfBounds,
+    // An indirect call through an element that's missing, empty, or of the wrong type
+    IndirectCall,
+    StackExhausted,
+    Other,
+}
+
+impl TrapClass {
+    pub fn matches(self, other: TrapClass) -> bool {
+        self == other || self == TrapClass::Other || other == TrapClass::Other
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome {
+    Returned(Vec<StackEntry>),
+    Trapped { class: TrapClass, message: String },
+    // The module couldn't be loaded or instantiated, or the export couldn't be called with the
+    // arguments
+    Failed(String),
+    // The engine panicked, which is always a bug
+    Panicked(String),
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunOutcome::Returned(results) => {
+                let results: Vec<_> = results.iter().map(value_text).collect();
+                write!(f, "returned ({})", results.join(", "))
+            }
+            RunOutcome::Trapped { class, message } => {
+                write!(f, "trapped ({:?}): {}", class, message)
+            }
+            RunOutcome::Failed(message) => write!(f, "failed: {}", message),
+            RunOutcome::Panicked(message) => write!(f, "panicked: {}", message),
+        }
+    }
+}
+
+// What an engine made of a call, and the contents of the memory the module exports as "memory"
+// afterwards, if it was asked for and there is one
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineOutput {
+    pub outcome: RunOutcome,
+    pub memory: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invocation {
+    pub export: String,
+    pub args: Vec<StackEntry>,
+}
+
+impl Invocation {
+    pub fn new(export: &str, args: Vec<StackEntry>) -> Self {
+        Self {
+            export: export.to_string(),
+            args,
+        }
+    }
+}
+
+impl fmt::Display for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args: Vec<_> = self.args.iter().map(value_text).collect();
+        write!(f, "{}({})", self.export, args.join(", "))
+    }
+}
+
+// An engine that can instantiate a module with no imports and call one of its exports. Each
+// run starts from a fresh instance.
+pub trait Engine {
+    fn name(&self) -> &str;
+
+    fn run(&mut self, module: &[u8], invocation: &Invocation, capture_memory: bool)
+        -> EngineOutput;
+}
+
+// This interpreter, as an Engine
+#[derive(Debug, Default)]
+pub struct Interpreter;
+
+impl Engine for Interpreter {
+    fn name(&self) -> &str {
+        "wasm_interp"
+    }
+
+    fn run(
+        &mut self,
+        module: &[u8],
+        invocation: &Invocation,
+        capture_memory: bool,
+    ) -> EngineOutput {
+        let mut memory = None;
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            let module = core::Module::load_module_from_bytes(module)
+                .map_err(|e| RunOutcome::Failed(format!("{:#}", e)))?;
+            let mut instance = module
+                .instantiate(core::EmptyResolver::instance())
+                .map_err(|e| RunOutcome::Failed(format!("{:#}", e)))?;
+            let result = instance.invoke_export(&invocation.export, &invocation.args);
+
+            if capture_memory {
+                memory = instance
+                    .exports
+                    .get("memory")
+                    .and_then(|e| e.as_memory())
+                    .map(|m| m.borrow().data().to_vec());
+            }
+            result.map_err(|e| classify_error(&e))
+        }));
+
+        let outcome = match run {
+            Ok(Ok(results)) => RunOutcome::Returned(results),
+            Ok(Err(outcome)) => outcome,
+            Err(payload) => RunOutcome::Panicked(panic_message(&*payload)),
+        };
+        EngineOutput { outcome, memory }
+    }
+}
+
+// Errors from calling an export that aren't traps are the ones where the call couldn't be made
+// at all, such as the wrong arguments, apart from the traps the executor doesn't have a kind for
+fn classify_error(error: &anyhow::Error) -> RunOutcome {
+    let message = format!("{:#}", error);
+    let class = match error.downcast_ref::<Trap>().map(Trap::kind) {
+        Some(TrapKind::Unreachable) => TrapClass::Unreachable,
+        Some(TrapKind::IntegerOverflow) => TrapClass::IntegerOverflow,
+        Some(TrapKind::InvalidConversionToInteger) => TrapClass::InvalidConversionToInteger,
+        Some(TrapKind::MemoryOutOfBounds { .. }) => TrapClass::MemoryOutOfBounds,
+        Some(TrapKind::UndefinedElement { .. }) | Some(TrapKind::UninitializedElement(_)) => {
+            TrapClass::IndirectCall
+        }
+        Some(TrapKind::Exit(_))
+        | Some(TrapKind::OutOfFuel)
+        | Some(TrapKind::Interrupted)
+        | Some(TrapKind::Host) => TrapClass::Other,
+        None if message.starts_with("Export ") || message.contains(" arguments, but ") => {
+            return RunOutcome::Failed(message)
+        }
+        None if message.contains("Indirect function call type") => TrapClass::IndirectCall,
+        None => TrapClass::Other,
+    };
+    RunOutcome::Trapped { class, message }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "(no message)".to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DifferentialOptions {
+    // Whether the memory the module exports as "memory" has to end up the same too
+    pub compare_memory: bool,
+}
+
+impl Default for DifferentialOptions {
+    fn default() -> Self {
+        Self {
+            compare_memory: true,
+        }
+    }
+}
+
+// Two engines disagreeing about a call, with all it takes to reproduce it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub module: Vec<u8>,
+    pub invocation: Invocation,
+    pub reference_engine: String,
+    pub ours: EngineOutput,
+    pub reference: EngineOutput,
+    // What it was that differed
+    pub difference: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Diverged from {}: {}",
+            self.reference_engine, self.difference
+        )?;
+        writeln!(f, "invocation: {}", self.invocation)?;
+        writeln!(f, "wasm_interp: {}", self.ours.outcome)?;
+        writeln!(f, "{}: {}", self.reference_engine, self.reference.outcome)?;
+        writeln!(f, "module ({} bytes):", self.module.len())?;
+        for chunk in self.module.chunks(32) {
+            let mut line = String::new();
+            for byte in chunk {
+                let _ = write!(line, "{:02x}", byte);
+            }
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs the invocation under this interpreter and the reference engine, and returns how they
+// differ, if they do. Floats are compared by their bits, so NaNs have to match exactly.
+pub fn check(
+    module: &[u8],
+    invocation: &Invocation,
+    reference: &mut dyn Engine,
+    options: DifferentialOptions,
+) -> Option<Divergence> {
+    let ours = Interpreter.run(module, invocation, options.compare_memory);
+    let theirs = reference.run(module, invocation, options.compare_memory);
+
+    let mut difference = compare_outcomes(&ours.outcome, &theirs.outcome);
+    if difference.is_none() && options.compare_memory {
+        difference = compare_memory(ours.memory.as_deref(), theirs.memory.as_deref());
+    }
+
+    difference.map(|difference| Divergence {
+        module: module.to_vec(),
+        invocation: invocation.clone(),
+        reference_engine: reference.name().to_string(),
+        ours,
+        reference: theirs,
+        difference,
+    })
+}
+
+fn compare_outcomes(ours: &RunOutcome, theirs: &RunOutcome) -> Option<String> {
+    match (ours, theirs) {
+        (RunOutcome::Panicked(_), _) | (_, RunOutcome::Panicked(_)) => {
+            Some("an engine panicked".to_string())
+        }
+        (RunOutcome::Returned(a), RunOutcome::Returned(b)) => {
+            if a.len() != b.len() {
+                return Some(format!("{} results against {}", a.len(), b.len()));
+            }
+            a.iter()
+                .zip(b)
+                .position(|(a, b)| !a.bitwise_eq(b))
+                .map(|idx| format!("result {} differs", idx))
+        }
+        (RunOutcome::Trapped { class: a, .. }, RunOutcome::Trapped { class: b, .. }) => {
+            if a.matches(*b) {
+                None
+            } else {
+                Some(format!("trapped with {:?} against {:?}", a, b))
+            }
+        }
+        (RunOutcome::Failed(_), RunOutcome::Failed(_)) => None,
+        _ => Some("one returned and the other didn't".to_string()),
+    }
+}
+
+fn compare_memory(ours: Option<&[u8]>, theirs: Option<&[u8]>) -> Option<String> {
+    match (ours, theirs) {
+        (Some(a), Some(b)) if a.len() != b.len() => Some(format!(
+            "memory is {:#x} bytes against {:#x}",
+            a.len(),
+            b.len()
+        )),
+        (Some(a), Some(b)) => a
+            .iter()
+            .zip(b)
+            .position(|(a, b)| a != b)
+            .map(|offset| format!("memory differs at {:#x}", offset)),
+        (None, None) => None,
+        _ => Some("only one engine has a memory".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::{Instr, RawModuleBuilder};
+    use crate::core::{FuncType, ValueType};
+    use crate::parser::Opcode;
+
+    // An engine that always gives the same output
+    struct Canned(EngineOutput);
+
+    impl Engine for Canned {
+        fn name(&self) -> &str {
+            "canned"
+        }
+
+        fn run(&mut self, _: &[u8], _: &Invocation, _: bool) -> EngineOutput {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_differential_check() {
+        let module = RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+            .add_memory(1, None)
+            .add_function(
+                0,
+                vec![],
+                vec![
+                    Instr::I32Const(8),
+                    Instr::LocalGet(0),
+                    Instr::Memory(Opcode::I32Store, 2, 0),
+                    Instr::I32Const(100),
+                    Instr::LocalGet(0),
+                    Instr::Op(Opcode::I32DivU),
+                ],
+            )
+            .export_func("run", 0)
+            .export_memory("memory", 0)
+            .build()
+            .encode();
+        let run = Invocation::new("run", vec![5_i32.into()]);
+        let options = DifferentialOptions::default();
+
+        // Against itself there's nothing to find
+        assert_eq!(check(&module, &run, &mut Interpreter, options), None);
+
+        let mut memory = vec![0; 0x1_0000];
+        memory[8] = 5;
+        let mut reference = Canned(EngineOutput {
+            outcome: RunOutcome::Returned(vec![20_i32.into()]),
+            memory: Some(memory.clone()),
+        });
+        assert_eq!(check(&module, &run, &mut reference, options), None);
+
+        memory[9] = 1;
+        let mut reference = Canned(EngineOutput {
+            outcome: RunOutcome::Returned(vec![20_i32.into()]),
+            memory: Some(memory),
+        });
+        let divergence = check(&module, &run, &mut reference, options).unwrap();
+        assert_eq!(divergence.difference, "memory differs at 0x9");
+        let compare_results = DifferentialOptions {
+            compare_memory: false,
+        };
+        assert_eq!(check(&module, &run, &mut reference, compare_results), None);
+
+        // Any trap matches one that couldn't be classified
+        let mut reference = Canned(EngineOutput {
+            outcome: RunOutcome::Trapped {
+                class: TrapClass::IntegerDivideByZero,
+                message: "integer divide by zero".to_string(),
+            },
+            memory: None,
+        });
+        let divergence = check(&module, &run, &mut reference, compare_results).unwrap();
+        assert_eq!(divergence.difference, "one returned and the other didn't");
+        let report = format!("{}", divergence);
+        assert!(report.starts_with(
+            "Diverged from canned: one returned and the other didn't\n\
+             invocation: run(i32:5)\n\
+             wasm_interp: returned (i32:20)\n\
+             canned: trapped (IntegerDivideByZero): integer divide by zero\n"
+        ));
+        assert!(report.contains("\n0061736d01000000"));
+
+        // As does a panic, which is always a divergence
+        let mut reference = Canned(EngineOutput {
+            outcome: RunOutcome::Panicked("overflow".to_string()),
+            memory: None,
+        });
+        let divergence = check(&module, &run, &mut reference, compare_results).unwrap();
+        assert_eq!(divergence.difference, "an engine panicked");
+    }
+}
@@ -0,0 +1,891 @@
+// Making random modules that are valid by construction, for fuzz targets that want to get past the
+// parser and exercise instantiation and execution. Any bytes make a module: the generator takes its
+// choices from them, and once they run out every choice is the first one, which still makes a
+// valid module.
+//
+//     let mut generator = ModuleGenerator::new(data);
+//     let generated = generator.generate();
+//     let bytes = generated.module.encode();
+//     for export in &generated.exports {
+//         let args = generator.args(export.func_type.params());
+//         ...
+//     }
+//
+// The result can be handed to differential::check, with an Invocation for each export.
+//
+// The modules are a constrained subset of what wasm allows: a few function types, a few functions
+// whose bodies only use opcodes from the lists below, an optional one page memory with a data
+// segment, and a couple of mutable globals. Functions only call those before them, and loops
+// never branch back, so everything terminates.
+//
+// Bodies are put together with BodyBuilder, which keeps track of the types on the stack and in
+// each block, and panics if an instruction wouldn't validate. A mistake in the generator is found
+// as a crash in the generator, rather than as a module that's rejected or misbehaves.
+use crate::builder::{Instr, RawModuleBuilder};
+use crate::core::{
+    stack_entry::StackEntry, BlockType, FuncType, GlobalType, MutableType, RawModule, ValueType,
+};
+use crate::parser::Opcode;
+
+const MAX_TYPES: usize = 3;
+const MAX_PARAMS: usize = 2;
+const MAX_FUNCTIONS: usize = 4;
+const MAX_LOCALS: usize = 2;
+const MAX_GLOBALS: usize = 2;
+const MAX_STATEMENTS: usize = 4;
+// How deeply expressions and blocks nest
+const MAX_DEPTH: usize = 3;
+// Addresses are usually masked to this, to keep most accesses within the first page
+const ADDRESS_MASK: i32 = 0xfff;
+
+const VALUE_TYPES: [ValueType; 4] = [
+    ValueType::I32,
+    ValueType::I64,
+    ValueType::F32,
+    ValueType::F64,
+];
+
+// Instructions taking one operand, with its type and the result's. Division and remainder are left
+// out of the binary operators, as the executor doesn't trap on a zero divisor yet.
+const UNARY: &[(Opcode, ValueType, ValueType)] = &[
+    (Opcode::I32Eqz, ValueType::I32, ValueType::I32),
+    (Opcode::I32Clz, ValueType::I32, ValueType::I32),
+    (Opcode::I32Ctz, ValueType::I32, ValueType::I32),
+    (Opcode::I32Popcnt, ValueType::I32, ValueType::I32),
+    (Opcode::I32Extend8S, ValueType::I32, ValueType::I32),
+    (Opcode::I32Extend16S, ValueType::I32, ValueType::I32),
+    (Opcode::I64Eqz, ValueType::I64, ValueType::I32),
+    (Opcode::I64Clz, ValueType::I64, ValueType::I64),
+    (Opcode::I64Ctz, ValueType::I64, ValueType::I64),
+    (Opcode::I64Popcnt, ValueType::I64, ValueType::I64),
+    (Opcode::I64Extend8S, ValueType::I64, ValueType::I64),
+    (Opcode::I64Extend16S, ValueType::I64, ValueType::I64),
+    (Opcode::I64Extend32S, ValueType::I64, ValueType::I64),
+    (Opcode::F32Abs, ValueType::F32, ValueType::F32),
+    (Opcode::F32Neg, ValueType::F32, ValueType::F32),
+    (Opcode::F32Ceil, ValueType::F32, ValueType::F32),
+    (Opcode::F32Floor, ValueType::F32, ValueType::F32),
+    (Opcode::F32Trunc, ValueType::F32, ValueType::F32),
+    (Opcode::F32Nearest, ValueType::F32, ValueType::F32),
+    (Opcode::F32Sqrt, ValueType::F32, ValueType::F32),
+    (Opcode::F64Abs, ValueType::F64, ValueType::F64),
+    (Opcode::F64Neg, ValueType::F64, ValueType::F64),
+    (Opcode::F64Ceil, ValueType::F64, ValueType::F64),
+    (Opcode::F64Floor, ValueType::F64, ValueType::F64),
+    (Opcode::F64Trunc, ValueType::F64, ValueType::F64),
+    (Opcode::F64Nearest, ValueType::F64, ValueType::F64),
+    (Opcode::F64Sqrt, ValueType::F64, ValueType::F64),
+    (Opcode::I32WrapI64, ValueType::I64, ValueType::I32),
+    (Opcode::I32TruncF32S, ValueType::F32, ValueType::I32),
+    (Opcode::I32TruncF32U, ValueType::F32, ValueType::I32),
+    (Opcode::I32TruncF64S, ValueType::F64, ValueType::I32),
+    (Opcode::I32TruncF64U, ValueType::F64, ValueType::I32),
+    (Opcode::I64ExtendI32S, ValueType::I32, ValueType::I64),
+    (Opcode::I64ExtendI32U, ValueType::I32, ValueType::I64),
+    (Opcode::I64TruncF32S, ValueType::F32, ValueType::I64),
+    (Opcode::I64TruncF32U, ValueType::F32, ValueType::I64),
+    (Opcode::I64TruncF64S, ValueType::F64, ValueType::I64),
+    (Opcode::I64TruncF64U, ValueType::F64, ValueType::I64),
+    (Opcode::F32ConvertI32S, ValueType::I32, ValueType::F32),
+    (Opcode::F32ConvertI32U, ValueType::I32, ValueType::F32),
+    (Opcode::F32ConvertI64S, ValueType::I64, ValueType::F32),
+    (Opcode::F32ConvertI64U, ValueType::I64, ValueType::F32),
+    (Opcode::F32DemoteF64, ValueType::F64, ValueType::F32),
+    (Opcode::F64ConvertI32S, ValueType::I32, ValueType::F64),
+    (Opcode::F64ConvertI32U, ValueType::I32, ValueType::F64),
+    (Opcode::F64ConvertI64S, ValueType::I64, ValueType::F64),
+    (Opcode::F64ConvertI64U, ValueType::I64, ValueType::F64),
+    (Opcode::F64PromoteF32, ValueType::F32, ValueType::F64),
+    (Opcode::I32ReinterpretF32, ValueType::F32, ValueType::I32),
+    (Opcode::I64ReinterpretF64, ValueType::F64, ValueType::I64),
+    (Opcode::F32ReinterpretI32, ValueType::I32, ValueType::F32),
+    (Opcode::F64ReinterpretI64, ValueType::I64, ValueType::F64),
+];
+
+// Instructions taking two operands of the same type, with that type and the result's
+const BINARY: &[(Opcode, ValueType, ValueType)] = &[
+    (Opcode::I32Eq, ValueType::I32, ValueType::I32),
+    (Opcode::I32Ne, ValueType::I32, ValueType::I32),
+    (Opcode::I32LtS, ValueType::I32, ValueType::I32),
+    (Opcode::I32LtU, ValueType::I32, ValueType::I32),
+    (Opcode::I32GtS, ValueType::I32, ValueType::I32),
+    (Opcode::I32GeU, ValueType::I32, ValueType::I32),
+    (Opcode::I32Add, ValueType::I32, ValueType::I32),
+    (Opcode::I32Sub, ValueType::I32, ValueType::I32),
+    (Opcode::I32Mul, ValueType::I32, ValueType::I32),
+    (Opcode::I32And, ValueType::I32, ValueType::I32),
+    (Opcode::I32Or, ValueType::I32, ValueType::I32),
+    (Opcode::I32Xor, ValueType::I32, ValueType::I32),
+    (Opcode::I32Shl, ValueType::I32, ValueType::I32),
+    (Opcode::I32ShrS, ValueType::I32, ValueType::I32),
+    (Opcode::I32ShrU, ValueType::I32, ValueType::I32),
+    (Opcode::I32Rotl, ValueType::I32, ValueType::I32),
+    (Opcode::I32Rotr, ValueType::I32, ValueType::I32),
+    (Opcode::I64Eq, ValueType::I64, ValueType::I32),
+    (Opcode::I64Ne, ValueType::I64, ValueType::I32),
+    (Opcode::I64LtS, ValueType::I64, ValueType::I32),
+    (Opcode::I64GtU, ValueType::I64, ValueType::I32),
+    (Opcode::I64LeS, ValueType::I64, ValueType::I32),
+    (Opcode::I64GeU, ValueType::I64, ValueType::I32),
+    (Opcode::I64Add, ValueType::I64, ValueType::I64),
+    (Opcode::I64Sub, ValueType::I64, ValueType::I64),
+    (Opcode::I64Mul, ValueType::I64, ValueType::I64),
+    (Opcode::I64And, ValueType::I64, ValueType::I64),
+    (Opcode::I64Or, ValueType::I64, ValueType::I64),
+    (Opcode::I64Xor, ValueType::I64, ValueType::I64),
+    (Opcode::I64Shl, ValueType::I64, ValueType::I64),
+    (Opcode::I64ShrS, ValueType::I64, ValueType::I64),
+    (Opcode::I64ShrU, ValueType::I64, ValueType::I64),
+    (Opcode::I64Rotl, ValueType::I64, ValueType::I64),
+    (Opcode::I64Rotr, ValueType::I64, ValueType::I64),
+    (Opcode::F32Eq, ValueType::F32, ValueType::I32),
+    (Opcode::F32Ne, ValueType::F32, ValueType::I32),
+    (Opcode::F32Lt, ValueType::F32, ValueType::I32),
+    (Opcode::F32Ge, ValueType::F32, ValueType::I32),
+    (Opcode::F32Add, ValueType::F32, ValueType::F32),
+    (Opcode::F32Sub, ValueType::F32, ValueType::F32),
+    (Opcode::F32Mul, ValueType::F32, ValueType::F32),
+    (Opcode::F32Div, ValueType::F32, ValueType::F32),
+    (Opcode::F32Min, ValueType::F32, ValueType::F32),
+    (Opcode::F32Max, ValueType::F32, ValueType::F32),
+    (Opcode::F32CopySign, ValueType::F32, ValueType::F32),
+    (Opcode::F64Eq, ValueType::F64, ValueType::I32),
+    (Opcode::F64Ne, ValueType::F64, ValueType::I32),
+    (Opcode::F64Gt, ValueType::F64, ValueType::I32),
+    (Opcode::F64Le, ValueType::F64, ValueType::I32),
+    (Opcode::F64Add, ValueType::F64, ValueType::F64),
+    (Opcode::F64Sub, ValueType::F64, ValueType::F64),
+    (Opcode::F64Mul, ValueType::F64, ValueType::F64),
+    (Opcode::F64Div, ValueType::F64, ValueType::F64),
+    (Opcode::F64Min, ValueType::F64, ValueType::F64),
+    (Opcode::F64Max, ValueType::F64, ValueType::F64),
+    (Opcode::F64CopySign, ValueType::F64, ValueType::F64),
+];
+
+// Loads and stores, with the type of the value and the natural alignment exponent
+const LOADS: &[(Opcode, ValueType, u32)] = &[
+    (Opcode::I32Load, ValueType::I32, 2),
+    (Opcode::I64Load, ValueType::I64, 3),
+    (Opcode::F32Load, ValueType::F32, 2),
+    (Opcode::F64Load, ValueType::F64, 3),
+    (Opcode::I32Load8S, ValueType::I32, 0),
+    (Opcode::I32Load8U, ValueType::I32, 0),
+    (Opcode::I32Load16S, ValueType::I32, 1),
+    (Opcode::I32Load16U, ValueType::I32, 1),
+    (Opcode::I64Load8S, ValueType::I64, 0),
+    (Opcode::I64Load16U, ValueType::I64, 1),
+    (Opcode::I64Load32S, ValueType::I64, 2),
+    (Opcode::I64Load32U, ValueType::I64, 2),
+];
+
+const STORES: &[(Opcode, ValueType, u32)] = &[
+    (Opcode::I32Store, ValueType::I32, 2),
+    (Opcode::I64Store, ValueType::I64, 3),
+    (Opcode::F32Store, ValueType::F32, 2),
+    (Opcode::F64Store, ValueType::F64, 3),
+    (Opcode::I32Store8, ValueType::I32, 0),
+    (Opcode::I32Store16, ValueType::I32, 1),
+    (Opcode::I64Store8, ValueType::I64, 0),
+    (Opcode::I64Store16, ValueType::I64, 1),
+    (Opcode::I64Store32, ValueType::I64, 2),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameKind {
+    Function,
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+#[derive(Debug)]
+struct Frame {
+    kind: FrameKind,
+    result: Option<ValueType>,
+    // How many values were on the stack when the frame started
+    height: usize,
+}
+
+impl Frame {
+    // The types a branch to the frame takes with it, which for a loop are its parameters
+    fn label_type(&self) -> Option<&ValueType> {
+        match self.kind {
+            FrameKind::Loop => None,
+            _ => self.result.as_ref(),
+        }
+    }
+}
+
+// Builds a function body while checking it the way validation would. It panics on anything that
+// wouldn't validate, such as popping a value of the wrong type, leaving values behind at the end
+// of a block, or an if with a result but no else.
+//
+// Only the instructions the generator uses are covered, and there's nothing that makes the stack
+// polymorphic, such as br or return, so the types on the stack are always known.
+#[derive(Debug)]
+pub struct BodyBuilder {
+    locals: Vec<ValueType>,
+    stack: Vec<ValueType>,
+    frames: Vec<Frame>,
+    instrs: Vec<Instr>,
+}
+
+impl BodyBuilder {
+    // The locals are the function's parameters followed by its declared locals
+    pub fn new(locals: Vec<ValueType>, result: Option<ValueType>) -> Self {
+        Self {
+            locals,
+            stack: Vec::new(),
+            frames: vec![Frame {
+                kind: FrameKind::Function,
+                result,
+                height: 0,
+            }],
+            instrs: Vec::new(),
+        }
+    }
+
+    pub fn locals(&self) -> &[ValueType] {
+        &self.locals
+    }
+
+    // How many blocks are open, not counting the function itself
+    pub fn depth(&self) -> usize {
+        self.frames.len() - 1
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames
+            .last()
+            .expect("The function's frame is never closed")
+    }
+
+    fn pop(&mut self, expected: &ValueType) {
+        let height = self.frame().height;
+        assert!(
+            self.stack.len() > height,
+            "Popping {} from an empty block stack",
+            expected
+        );
+        let actual = self.stack.pop().unwrap();
+        assert!(
+            actual == *expected,
+            "Popping {} where there's a {}",
+            expected,
+            actual
+        );
+    }
+
+    // Adds an instruction that pops the params, last first, and pushes the result
+    pub fn instr(&mut self, instr: Instr, params: &[ValueType], result: Option<ValueType>) {
+        for param in params.iter().rev() {
+            self.pop(param);
+        }
+        self.stack.extend(result);
+        self.instrs.push(instr);
+    }
+
+    pub fn local_get(&mut self, idx: u32) {
+        let ty = self.locals[idx as usize].clone();
+        self.instr(Instr::LocalGet(idx), &[], Some(ty));
+    }
+
+    pub fn local_set(&mut self, idx: u32) {
+        let ty = self.locals[idx as usize].clone();
+        self.instr(Instr::LocalSet(idx), &[ty], None);
+    }
+
+    pub fn local_tee(&mut self, idx: u32) {
+        let ty = self.locals[idx as usize].clone();
+        self.instr(
+            Instr::LocalTee(idx),
+            std::slice::from_ref(&ty),
+            Some(ty.clone()),
+        );
+    }
+
+    pub fn select(&mut self, ty: ValueType) {
+        self.instr(
+            Instr::Select,
+            &[ty.clone(), ty.clone(), ValueType::I32],
+            Some(ty),
+        );
+    }
+
+    pub fn drop_value(&mut self) {
+        let height = self.frame().height;
+        assert!(
+            self.stack.len() > height,
+            "Dropping from an empty block stack"
+        );
+        self.stack.pop();
+        self.instrs.push(Instr::Drop);
+    }
+
+    fn begin(&mut self, kind: FrameKind, result: Option<ValueType>) {
+        let block_type = result.clone().map_or(BlockType::None, BlockType::from);
+        let instr = match kind {
+            FrameKind::Block => Instr::Block(block_type),
+            FrameKind::Loop => Instr::Loop(block_type),
+            _ => {
+                self.pop(&ValueType::I32);
+                Instr::If(block_type)
+            }
+        };
+        self.instrs.push(instr);
+        self.frames.push(Frame {
+            kind,
+            result,
+            height: self.stack.len(),
+        });
+    }
+
+    pub fn block(&mut self, result: Option<ValueType>) {
+        self.begin(FrameKind::Block, result);
+    }
+
+    pub fn loop_block(&mut self, result: Option<ValueType>) {
+        self.begin(FrameKind::Loop, result);
+    }
+
+    // Pops the condition
+    pub fn if_block(&mut self, result: Option<ValueType>) {
+        self.begin(FrameKind::If, result);
+    }
+
+    // Checks that the block's stack holds exactly its result, and takes it off
+    fn close_frame(&mut self) -> Frame {
+        let frame = self.frames.pop().unwrap();
+        let values = &self.stack[frame.height..];
+        assert!(
+            values.len() == frame.result.iter().count() && values.iter().eq(frame.result.iter()),
+            "A {:?} block ends with {:?} on the stack, rather than {:?}",
+            frame.kind,
+            values,
+            frame.result
+        );
+        self.stack.truncate(frame.height);
+        frame
+    }
+
+    pub fn else_block(&mut self) {
+        assert!(self.frame().kind == FrameKind::If, "An else outside an if");
+        let frame = self.close_frame();
+        self.instrs.push(Instr::Else);
+        self.frames.push(Frame {
+            kind: FrameKind::Else,
+            ..frame
+        });
+    }
+
+    pub fn end(&mut self) {
+        assert!(self.depth() > 0, "The function's end is added by finish");
+        let frame = self.close_frame();
+        assert!(
+            frame.kind != FrameKind::If || frame.result.is_none(),
+            "An if with a result needs an else"
+        );
+        self.stack.extend(frame.result);
+        self.instrs.push(Instr::End);
+    }
+
+    // Pops the condition. The label's values stay on the stack either way.
+    pub fn br_if(&mut self, label: u32) {
+        self.pop(&ValueType::I32);
+        let frame = &self.frames[self.frames.len() - 1 - label as usize];
+        if let Some(ty) = frame.label_type() {
+            assert!(
+                self.stack.len() > self.frame().height && self.stack.last() == Some(ty),
+                "Branching to a label that takes {} without one on the stack",
+                ty
+            );
+        }
+        self.instrs.push(Instr::BrIf(label));
+    }
+
+    // The body, without the final end, which RawModuleBuilder adds
+    pub fn finish(mut self) -> Vec<Instr> {
+        assert!(self.depth() == 0, "{} blocks are still open", self.depth());
+        self.close_frame();
+        self.instrs
+    }
+}
+
+// Hands out choices from the input bytes, then zeros once they've run out
+#[derive(Debug)]
+struct Entropy<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Entropy<'a> {
+    fn byte(&mut self) -> u8 {
+        let byte = self.data.get(self.pos).cloned().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+
+    fn u32(&mut self) -> u32 {
+        (0..4).fold(0, |value, _| (value << 8) | u32::from(self.byte()))
+    }
+
+    fn u64(&mut self) -> u64 {
+        (u64::from(self.u32()) << 32) | u64::from(self.u32())
+    }
+
+    // A number in 0..count
+    fn below(&mut self, count: usize) -> usize {
+        if count <= 1 {
+            return 0;
+        }
+        usize::from(self.byte()) % count
+    }
+
+    // A number in 0..=max
+    fn up_to(&mut self, max: usize) -> usize {
+        self.below(max + 1)
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        self.byte() % 100 < percent
+    }
+
+    fn pick<'t, T>(&mut self, items: &'t [T]) -> &'t T {
+        &items[self.below(items.len())]
+    }
+}
+
+// An exported function of a generated module
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedExport {
+    pub name: String,
+    pub func_type: FuncType,
+}
+
+#[derive(Debug)]
+pub struct GeneratedModule {
+    pub module: RawModule,
+    // Every function is exported, in order
+    pub exports: Vec<GeneratedExport>,
+}
+
+pub struct ModuleGenerator<'a> {
+    entropy: Entropy<'a>,
+}
+
+// What's in the module so far, which bodies can refer to
+struct Context {
+    globals: Vec<ValueType>,
+    // The types of the functions defined so far
+    functions: Vec<FuncType>,
+    has_memory: bool,
+}
+
+impl<'a> ModuleGenerator<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            entropy: Entropy { data, pos: 0 },
+        }
+    }
+
+    pub fn generate(&mut self) -> GeneratedModule {
+        let mut builder = RawModuleBuilder::new();
+
+        let types: Vec<FuncType> = (0..=self.entropy.below(MAX_TYPES))
+            .map(|_| self.func_type())
+            .collect();
+        for func_type in &types {
+            builder = builder.add_type(func_type.clone());
+        }
+
+        let has_memory = self.entropy.chance(60);
+        if has_memory {
+            builder = builder.add_memory(1, Some(2)).export_memory("memory", 0);
+            let len = self.entropy.below(16);
+            let bytes = (0..len).map(|_| self.entropy.byte()).collect();
+            let offset = self.entropy.below(64) as i32;
+            builder = builder.add_data(0, vec![Instr::I32Const(offset)], bytes);
+        }
+
+        let mut context = Context {
+            globals: Vec::new(),
+            functions: Vec::new(),
+            has_memory,
+        };
+        for _ in 0..self.entropy.up_to(MAX_GLOBALS) {
+            let ty = self.entropy.pick(&VALUE_TYPES).clone();
+            let init = self.constant(&ty);
+            builder = builder.add_global(GlobalType::new(ty.clone(), MutableType::Var), vec![init]);
+            context.globals.push(ty);
+        }
+
+        let mut exports = Vec::new();
+        for func_idx in 0..=self.entropy.below(MAX_FUNCTIONS) {
+            let type_idx = self.entropy.below(types.len());
+            let func_type = types[type_idx].clone();
+            let declared: Vec<ValueType> = (0..self.entropy.up_to(MAX_LOCALS))
+                .map(|_| self.entropy.pick(&VALUE_TYPES).clone())
+                .collect();
+
+            let mut locals = func_type.params().to_vec();
+            locals.extend(declared.iter().cloned());
+            let result = func_type.results().first().cloned();
+            let mut body = BodyBuilder::new(locals, result.clone());
+            for _ in 0..self.entropy.up_to(MAX_STATEMENTS) {
+                self.statement(&mut body, &context, 0);
+            }
+            if let Some(ty) = &result {
+                self.value(&mut body, &context, ty, 0);
+            }
+
+            let name = format!("f{}", func_idx);
+            builder = builder
+                .add_function(type_idx, declared, body.finish())
+                .export_func(&name, func_idx);
+            exports.push(GeneratedExport {
+                name,
+                func_type: func_type.clone(),
+            });
+            context.functions.push(func_type);
+        }
+
+        GeneratedModule {
+            module: builder.build(),
+            exports,
+        }
+    }
+
+    // Arguments for calling a function, from what's left of the input
+    pub fn args(&mut self, params: &[ValueType]) -> Vec<StackEntry> {
+        params
+            .iter()
+            .map(|ty| match ty {
+                ValueType::I32 => StackEntry::from(self.entropy.u32()),
+                ValueType::I64 => StackEntry::from(self.entropy.u64()),
+                ValueType::F32 => StackEntry::from(f32::from_bits(self.entropy.u32())),
+                ValueType::F64 => StackEntry::from(f64::from_bits(self.entropy.u64())),
+            })
+            .collect()
+    }
+
+    fn func_type(&mut self) -> FuncType {
+        let params = (0..self.entropy.up_to(MAX_PARAMS))
+            .map(|_| self.entropy.pick(&VALUE_TYPES).clone())
+            .collect();
+        let results = if self.entropy.chance(70) {
+            vec![self.entropy.pick(&VALUE_TYPES).clone()]
+        } else {
+            vec![]
+        };
+        FuncType::new(params, results)
+    }
+
+    // Mostly small numbers and the edge cases, with the odd arbitrary one
+    fn constant(&mut self, ty: &ValueType) -> Instr {
+        let small = i64::from(self.entropy.byte() as i8);
+        let bits = match self.entropy.below(4) {
+            0 => self.entropy.u64(),
+            1 => [0, 1, !0, 0x8000_0000, 0x7fff_ffff, 0x8000_0000_0000_0000][self.entropy.below(6)],
+            _ => small as u64,
+        };
+        match ty {
+            ValueType::I32 => Instr::I32Const(bits as i32),
+            ValueType::I64 => Instr::I64Const(bits as i64),
+            ValueType::F32 if bits == small as u64 => Instr::F32Const(small as f32),
+            ValueType::F32 => Instr::F32Const(f32::from_bits(bits as u32)),
+            ValueType::F64 if bits == small as u64 => Instr::F64Const(small as f64),
+            ValueType::F64 => Instr::F64Const(f64::from_bits(bits)),
+        }
+    }
+
+    // Leaves one value of the type on the stack
+    fn value(&mut self, body: &mut BodyBuilder, context: &Context, ty: &ValueType, depth: usize) {
+        if depth < MAX_DEPTH && self.entropy.chance(60) {
+            match self.entropy.below(8) {
+                0 => {
+                    let ops: Vec<_> = UNARY.iter().filter(|op| op.2 == *ty).collect();
+                    let (opcode, param, _) = *self.entropy.pick(&ops);
+                    self.value(body, context, param, depth + 1);
+                    body.instr(
+                        Instr::Op(*opcode),
+                        std::slice::from_ref(param),
+                        Some(ty.clone()),
+                    );
+                    return;
+                }
+                1 | 2 => {
+                    let ops: Vec<_> = BINARY.iter().filter(|op| op.2 == *ty).collect();
+                    let (opcode, param, _) = *self.entropy.pick(&ops);
+                    self.value(body, context, param, depth + 1);
+                    self.value(body, context, param, depth + 1);
+                    let params = [param.clone(), param.clone()];
+                    body.instr(Instr::Op(*opcode), &params, Some(ty.clone()));
+                    return;
+                }
+                3 if context.has_memory => {
+                    let loads: Vec<_> = LOADS.iter().filter(|load| load.1 == *ty).collect();
+                    let (opcode, _, align) = *self.entropy.pick(&loads);
+                    self.address(body, context, depth);
+                    let offset = self.entropy.below(16) as u32;
+                    let instr = Instr::Memory(*opcode, *align, offset);
+                    body.instr(instr, &[ValueType::I32], Some(ty.clone()));
+                    return;
+                }
+                4 => {
+                    let callees: Vec<_> = (0..context.functions.len())
+                        .filter(|&idx| context.functions[idx].results().first() == Some(ty))
+                        .collect();
+                    if !callees.is_empty() {
+                        let callee = *self.entropy.pick(&callees);
+                        self.call(body, context, callee, depth);
+                        return;
+                    }
+                }
+                5 => {
+                    self.value(body, context, ty, depth + 1);
+                    self.value(body, context, ty, depth + 1);
+                    self.value(body, context, &ValueType::I32, depth + 1);
+                    body.select(ty.clone());
+                    return;
+                }
+                6 => {
+                    self.value(body, context, &ValueType::I32, depth + 1);
+                    body.if_block(Some(ty.clone()));
+                    self.statements(body, context, depth + 1);
+                    self.value(body, context, ty, depth + 1);
+                    body.else_block();
+                    self.value(body, context, ty, depth + 1);
+                    body.end();
+                    return;
+                }
+                7 => {
+                    // A block that may leave early with its value
+                    body.block(Some(ty.clone()));
+                    self.value(body, context, ty, depth + 1);
+                    self.value(body, context, &ValueType::I32, depth + 1);
+                    body.br_if(0);
+                    self.statements(body, context, depth + 1);
+                    body.drop_value();
+                    self.value(body, context, ty, depth + 1);
+                    body.end();
+                    return;
+                }
+                _ => {}
+            }
+        }
+        self.leaf(body, context, ty);
+    }
+
+    // A value that doesn't take any others
+    fn leaf(&mut self, body: &mut BodyBuilder, context: &Context, ty: &ValueType) {
+        let locals: Vec<_> = (0..body.locals().len())
+            .filter(|&idx| body.locals()[idx] == *ty)
+            .collect();
+        let globals: Vec<_> = (0..context.globals.len())
+            .filter(|&idx| context.globals[idx] == *ty)
+            .collect();
+
+        match self.entropy.below(4) {
+            0 | 1 if !locals.is_empty() => body.local_get(*self.entropy.pick(&locals) as u32),
+            2 if !globals.is_empty() => {
+                let idx = *self.entropy.pick(&globals) as u32;
+                body.instr(Instr::GlobalGet(idx), &[], Some(ty.clone()));
+            }
+            3 if context.has_memory && *ty == ValueType::I32 => {
+                body.instr(Instr::MemorySize, &[], Some(ValueType::I32))
+            }
+            _ => {
+                let constant = self.constant(ty);
+                body.instr(constant, &[], Some(ty.clone()));
+            }
+        }
+    }
+
+    // An address that's usually masked to within the first page, with the odd wild one to make
+    // sure accesses out of bounds trap
+    fn address(&mut self, body: &mut BodyBuilder, context: &Context, depth: usize) {
+        self.value(body, context, &ValueType::I32, depth + 1);
+        if !self.entropy.chance(10) {
+            body.instr(Instr::I32Const(ADDRESS_MASK), &[], Some(ValueType::I32));
+            let params = [ValueType::I32, ValueType::I32];
+            body.instr(Instr::Op(Opcode::I32And), &params, Some(ValueType::I32));
+        }
+    }
+
+    fn call(&mut self, body: &mut BodyBuilder, context: &Context, callee: usize, depth: usize) {
+        let func_type = &context.functions[callee];
+        for param in func_type.params() {
+            self.value(body, context, param, depth + 1);
+        }
+        let result = func_type.results().first().cloned();
+        body.instr(Instr::Call(callee as u32), func_type.params(), result);
+    }
+
+    fn statements(&mut self, body: &mut BodyBuilder, context: &Context, depth: usize) {
+        for _ in 0..self.entropy.up_to(MAX_STATEMENTS / 2) {
+            self.statement(body, context, depth);
+        }
+    }
+
+    // Leaves the stack as it was
+    fn statement(&mut self, body: &mut BodyBuilder, context: &Context, depth: usize) {
+        let nested = depth < MAX_DEPTH;
+        match self.entropy.below(9) {
+            0 if !body.locals().is_empty() => {
+                let idx = self.entropy.below(body.locals().len());
+                let ty = body.locals()[idx].clone();
+                self.value(body, context, &ty, depth);
+                if self.entropy.chance(30) {
+                    body.local_tee(idx as u32);
+                    body.drop_value();
+                } else {
+                    body.local_set(idx as u32);
+                }
+            }
+            1 if !context.globals.is_empty() => {
+                let idx = self.entropy.below(context.globals.len());
+                let ty = context.globals[idx].clone();
+                self.value(body, context, &ty, depth);
+                body.instr(Instr::GlobalSet(idx as u32), &[ty], None);
+            }
+            2 | 3 if context.has_memory => {
+                let (opcode, ty, align) = self.entropy.pick(STORES);
+                self.address(body, context, depth);
+                self.value(body, context, ty, depth);
+                let offset = self.entropy.below(16) as u32;
+                let instr = Instr::Memory(*opcode, *align, offset);
+                body.instr(instr, &[ValueType::I32, ty.clone()], None);
+            }
+            4 if context.has_memory => {
+                // Never past the maximum of two pages, so it usually fails with -1
+                self.value(body, context, &ValueType::I32, depth);
+                body.instr(Instr::I32Const(3), &[], Some(ValueType::I32));
+                let params = [ValueType::I32, ValueType::I32];
+                body.instr(Instr::Op(Opcode::I32And), &params, Some(ValueType::I32));
+                body.instr(Instr::MemoryGrow, &[ValueType::I32], Some(ValueType::I32));
+                body.drop_value();
+            }
+            5 if nested => {
+                self.value(body, context, &ValueType::I32, depth);
+                body.if_block(None);
+                self.statements(body, context, depth + 1);
+                if self.entropy.chance(50) {
+                    body.else_block();
+                    self.statements(body, context, depth + 1);
+                }
+                body.end();
+            }
+            6 if nested => {
+                body.block(None);
+                self.statements(body, context, depth + 1);
+                self.value(body, context, &ValueType::I32, depth + 1);
+                body.br_if(0);
+                self.statements(body, context, depth + 1);
+                body.end();
+            }
+            7 if nested => {
+                // Nothing branches back to the loop, so it runs once
+                body.loop_block(None);
+                self.statements(body, context, depth + 1);
+                body.end();
+            }
+            8 if !context.functions.is_empty() => {
+                let callee = self.entropy.below(context.functions.len());
+                self.call(body, context, callee, depth);
+                if !context.functions[callee].results().is_empty() {
+                    body.drop_value();
+                }
+            }
+            _ => {
+                let ty = self.entropy.pick(&VALUE_TYPES).clone();
+                self.value(body, context, &ty, depth);
+                body.drop_value();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{EmptyResolver, Module, Trap};
+
+    // Bytes from a seed, for when there's no fuzzer to provide them
+    fn seeded_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_generated_modules_run() {
+        for seed in 0..300 {
+            let data = seeded_bytes(seed, 2048);
+            let mut generator = ModuleGenerator::new(&data);
+            let generated = generator.generate();
+
+            let bytes = generated.module.encode();
+            let module = Module::load_module_from_bytes(&bytes)
+                .unwrap_or_else(|e| panic!("Seed {} doesn't load: {:#}", seed, e));
+            let mut instance = module
+                .instantiate(EmptyResolver::instance())
+                .unwrap_or_else(|e| panic!("Seed {} doesn't instantiate: {:#}", seed, e));
+
+            for export in &generated.exports {
+                let args = generator.args(export.func_type.params());
+                match instance.invoke_export(&export.name, &args) {
+                    Ok(results) => assert_eq!(
+                        results.len(),
+                        export.func_type.results().len(),
+                        "Seed {} {}",
+                        seed,
+                        export.name
+                    ),
+                    Err(e) => assert!(
+                        e.downcast_ref::<Trap>().is_some(),
+                        "Seed {} {} failed without trapping: {:#}",
+                        seed,
+                        export.name,
+                        e
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_input_makes_a_module() {
+        let generated = ModuleGenerator::new(&[]).generate();
+        assert_eq!(generated.exports.len(), 1);
+        assert!(Module::load_module_from_bytes(&generated.module.encode()).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "Popping i64 where there's a i32")]
+    fn test_body_builder_checks_types() {
+        let mut body = BodyBuilder::new(vec![ValueType::I32], None);
+        body.local_get(0);
+        body.instr(
+            Instr::Op(Opcode::I64Eqz),
+            &[ValueType::I64],
+            Some(ValueType::I32),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "An if with a result needs an else")]
+    fn test_body_builder_checks_if_results() {
+        let mut body = BodyBuilder::new(vec![], Some(ValueType::I32));
+        body.instr(Instr::I32Const(1), &[], Some(ValueType::I32));
+        body.if_block(Some(ValueType::I32));
+        body.instr(Instr::I32Const(2), &[], Some(ValueType::I32));
+        body.end();
+    }
+}
@@ -1,3 +1,13 @@
+pub mod builder;
+pub mod canonical_abi;
+pub mod component;
 pub mod core;
+#[cfg(feature = "differential")]
+pub mod differential;
+#[cfg(feature = "generator")]
+pub mod generator;
 pub mod parser;
 pub mod reader;
+pub mod replay;
+pub mod wasi;
+pub mod writer;
@@ -1,18 +1,168 @@
-mod core;
-mod parser;
-mod reader;
+mod repl;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use std::env;
+use std::rc::Rc;
+
+use wasm::core::{self, stack_entry::StackEntry, ValueType};
+use wasm::wasi;
+
+fn radix_and_digits(arg: &str) -> (u32, &str) {
+    match arg.get(..2) {
+        Some("0x") => (16, &arg[2..]),
+        _ => (10, arg),
+    }
+}
+
+// Integers can be in hex with 0x, and either signed or unsigned, so -1 and 0xffffffff are the same
+// i32. They're returned as the bits of a value of the given width.
+fn parse_integer(arg: &str, bits: u32) -> Option<u64> {
+    let (negative, digits) = match arg.as_bytes().first() {
+        Some(b'-') => (true, &arg[1..]),
+        _ => (false, arg),
+    };
+    let (radix, digits) = radix_and_digits(digits);
+    let magnitude = u64::from_str_radix(digits, radix).ok()?;
+
+    let mask = if bits == 64 { !0 } else { (1 << bits) - 1 };
+    if negative {
+        if magnitude > 1 << (bits - 1) {
+            return None;
+        }
+        Some(magnitude.wrapping_neg() & mask)
+    } else if magnitude > mask {
+        None
+    } else {
+        Some(magnitude)
+    }
+}
+
+fn parse_argument(value_type: &ValueType, arg: &str) -> Result<StackEntry> {
+    let value = match value_type {
+        ValueType::I32 => parse_integer(arg, 32).map(|bits| StackEntry::from(bits as u32)),
+        ValueType::I64 => parse_integer(arg, 64).map(StackEntry::from),
+        ValueType::F32 => arg.parse::<f32>().ok().map(StackEntry::from),
+        ValueType::F64 => arg.parse::<f64>().ok().map(StackEntry::from),
+    };
+
+    value.ok_or_else(|| anyhow!("Cannot parse \"{}\" as {}", arg, value_type))
+}
+
+fn format_result(result: &StackEntry) -> String {
+    match result {
+        StackEntry::I32Entry(i) => format!("{}", *i as i32),
+        StackEntry::I64Entry(i) => format!("{}", *i as i64),
+        StackEntry::F32Entry(f) => format!("{}", f),
+        StackEntry::F64Entry(f) => format!("{}", f),
+    }
+}
+
+// Functions can be given by index, or by the name they are exported as
+fn find_function(module: &core::Module, name: &str) -> Result<usize> {
+    if let Ok(func_idx) = name.parse::<usize>() {
+        return Ok(func_idx);
+    }
+
+    module
+        .raw_module()
+        .exports()
+        .iter()
+        .find_map(|export| match export.d {
+            core::ExportDesc::Func(func_idx) if export.nm == name => Some(func_idx),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            let exports = module.raw_module().exports().iter();
+            core::ExportNotFound::new(
+                name,
+                core::ExportKind::Function,
+                exports.map(|export| (export.nm.as_str(), export.d.kind())),
+            )
+            .into()
+        })
+}
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
         println!("wasm [mod_name]");
+        println!("wasm [mod_name] [function] [args...]");
+        println!("wasm [mod_name] --disassemble [function]");
+        println!("wasm [mod_name] --features");
+        println!("wasm [mod_name] --repl");
     } else {
-        core::Module::load_module_from_path(&args[1], core::EmptyResolver::instance())
+        let module = core::Module::load_module_from_path(&args[1])
             .with_context(|| format!("Failed to read module from {}", &args[1]))?;
+
+        if args.len() > 2 && args[2] == "--features" {
+            let raw = module.raw_module();
+            println!("features: {}", raw.required_features()?);
+            if let Some(target_features) = raw.target_features() {
+                println!("target features: {}", target_features);
+            }
+            for (name, count) in raw.opcode_stats()? {
+                println!("{:>8} {}", count, name);
+            }
+            return Ok(());
+        }
+
+        if args.len() > 3 && args[2] == "--disassemble" {
+            let func_idx = find_function(&module, &args[3])?;
+            print!("{}", module.disassemble_function(func_idx)?);
+            return Ok(());
+        }
+
+        // WASI modules get the real stdio
+        let mut instance = wasi::instantiate(&module, Rc::new(wasi::WasiCtx::new()))
+            .with_context(|| format!("Failed to instantiate module from {}", &args[1]))?;
+
+        if args.len() > 2 && args[2] == "--repl" {
+            let stdin = std::io::stdin();
+            return repl::Repl::new(&module, instance).run(stdin.lock(), &mut std::io::stdout());
+        }
+
+        // Without a function to call, the module is run as a command
+        if args.len() == 2 {
+            let code = wasi::run_command(&mut instance)
+                .with_context(|| format!("Failed to run {}", &args[1]))?;
+            if code != 0 {
+                std::process::exit(code as i32);
+            }
+        } else {
+            let name = &args[2];
+            let func_type = instance.get_function(name)?.borrow().func_type().clone();
+
+            let call_args = &args[3..];
+            if call_args.len() != func_type.params().len() {
+                return Err(anyhow!(
+                    "{} {} takes {} arguments, but {} were given",
+                    name,
+                    func_type,
+                    func_type.params().len(),
+                    call_args.len()
+                ));
+            }
+
+            let call_args: Result<Vec<_>> = func_type
+                .params()
+                .iter()
+                .zip(call_args)
+                .map(|(value_type, arg)| parse_argument(value_type, arg))
+                .collect();
+
+            let completion = instance
+                .run_export(name, &call_args?)
+                .with_context(|| format!("Failed to call {}", name))?;
+            match completion {
+                core::Completion::Returned(results) => {
+                    let results: Vec<_> = results.iter().map(format_result).collect();
+                    println!("{}", results.join(" "));
+                }
+                // The program's exit code is ours
+                core::Completion::Exited(code) => std::process::exit(code as i32),
+            }
+        }
     }
 
     Ok(())
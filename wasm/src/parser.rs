@@ -1,13 +1,17 @@
 mod expression_reader;
+mod instr;
 mod instruction_accumulator;
 mod instruction_category;
 mod instruction_iterator;
 mod opcode;
+mod unsupported;
 
-pub use expression_reader::read_expression_bytes;
+pub use expression_reader::{expression_length, read_expression_bytes};
+pub use instr::{Instr, InstrIterator};
 pub use instruction_accumulator::{
     make_slice_accumulator, InstructionAccumulator, SliceInstructionAccumulator,
 };
 pub use instruction_category::{InstructionCategory, InstructionData};
 pub use instruction_iterator::{Instruction, InstructionSource};
 pub use opcode::Opcode;
+pub use unsupported::{decode_unsupported, UnsupportedInstr};
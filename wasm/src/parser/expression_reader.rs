@@ -79,3 +79,60 @@ pub fn read_expression_bytes<T: Read>(reader: &mut T) -> anyhow::Result<Vec<u8>>
 
     Ok(acc.instr_bytes())
 }
+
+// Accumulates instructions that are already in memory, so they only need to be walked over
+struct SliceInstructionAccumulator<'a> {
+    bytes: &'a [u8],
+    next_inst: usize,
+    inst_end: usize,
+}
+
+impl<'a> SliceInstructionAccumulator<'a> {
+    fn move_to_next(&mut self) -> anyhow::Result<bool> {
+        self.next_inst = self.inst_end;
+        self.ensure_bytes(1)?;
+
+        let lead_byte = self.get_byte(0);
+        let instruction_category = InstructionCategory::from_lead_byte(lead_byte)?;
+        instruction_category.ensure_instruction(self, 0)?;
+
+        Ok(instruction_category != InstructionCategory::End)
+    }
+}
+
+impl<'a> InstructionAccumulator for SliceInstructionAccumulator<'a> {
+    fn ensure_bytes(&mut self, bytes: usize) -> anyhow::Result<()> {
+        let required_bytes = self.next_inst + bytes;
+        if required_bytes > self.bytes.len() {
+            Err(anyhow::anyhow!(
+                "Not enough instruction bytes in expression"
+            ))
+        } else {
+            self.inst_end = std::cmp::max(self.inst_end, required_bytes);
+            Ok(())
+        }
+    }
+
+    fn get_bytes(&self, idx: usize, length: usize) -> &[u8] {
+        assert!(
+            self.inst_end >= self.next_inst + idx + length,
+            "Byte is not available"
+        );
+
+        &self.bytes[self.next_inst + idx..self.next_inst + idx + length]
+    }
+}
+
+// The length of the expression at the start of the bytes, up to and including its end. This checks
+// the expression in the same way as read_expression_bytes, but doesn't copy it.
+pub fn expression_length(bytes: &[u8]) -> anyhow::Result<usize> {
+    let mut acc = SliceInstructionAccumulator {
+        bytes,
+        next_inst: 0,
+        inst_end: 0,
+    };
+
+    while acc.move_to_next()? {}
+
+    Ok(acc.inst_end)
+}
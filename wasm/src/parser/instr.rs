@@ -0,0 +1,195 @@
+use crate::{
+    core::BlockType,
+    parser::{
+        decode_unsupported, make_slice_accumulator, InstructionAccumulator, InstructionCategory,
+        Opcode,
+    },
+};
+use anyhow::{anyhow, Context, Result};
+
+/// A single instruction with its immediates decoded.
+///
+/// This is what `Func::instructions` produces, and what `RawModuleBuilder` takes to make function
+/// bodies and constant expressions. The instructions with immediates have their own variants.
+/// `Op` covers all of the instructions without any, such as `i32.add`, and `Raw` allows anything
+/// else to be written out directly. Decoding never produces `Raw`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Unreachable,
+    Nop,
+    Block(BlockType),
+    Loop(BlockType),
+    If(BlockType),
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    // The targets and then the default target
+    BrTable(Vec<u32>, u32),
+    Return,
+    Call(u32),
+    // The type index, the table is always table 0
+    CallIndirect(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    // A load or store opcode, with the alignment exponent and the offset
+    Memory(Opcode, u32, u32),
+    MemorySize,
+    MemoryGrow,
+    I32Const(i32),
+    I64Const(i64),
+    F32Const(f32),
+    F64Const(f64),
+    Op(Opcode),
+    Raw(Vec<u8>),
+}
+
+impl Instr {
+    // The opcode the instruction is written with, which is all of them apart from raw bytes
+    pub fn opcode(&self) -> Option<Opcode> {
+        let opcode = match self {
+            Instr::Unreachable => Opcode::Unreachable,
+            Instr::Nop => Opcode::Nop,
+            Instr::Block(_) => Opcode::Block,
+            Instr::Loop(_) => Opcode::Loop,
+            Instr::If(_) => Opcode::If,
+            Instr::Else => Opcode::Else,
+            Instr::End => Opcode::End,
+            Instr::Br(_) => Opcode::Br,
+            Instr::BrIf(_) => Opcode::BrIf,
+            Instr::BrTable(_, _) => Opcode::BrTable,
+            Instr::Return => Opcode::Return,
+            Instr::Call(_) => Opcode::Call,
+            Instr::CallIndirect(_) => Opcode::CallIndirect,
+            Instr::Drop => Opcode::Drop,
+            Instr::Select => Opcode::Select,
+            Instr::LocalGet(_) => Opcode::LocalGet,
+            Instr::LocalSet(_) => Opcode::LocalSet,
+            Instr::LocalTee(_) => Opcode::LocalTee,
+            Instr::GlobalGet(_) => Opcode::GlobalGet,
+            Instr::GlobalSet(_) => Opcode::GlobalSet,
+            Instr::Memory(opcode, _, _) => *opcode,
+            Instr::MemorySize => Opcode::MemorySize,
+            Instr::MemoryGrow => Opcode::MemoryGrow,
+            Instr::I32Const(_) => Opcode::I32Const,
+            Instr::I64Const(_) => Opcode::I64Const,
+            Instr::F32Const(_) => Opcode::F32Const,
+            Instr::F64Const(_) => Opcode::F64Const,
+            Instr::Op(opcode) => *opcode,
+            Instr::Raw(_) => return None,
+        };
+
+        Some(opcode)
+    }
+}
+
+// Walks an expression one instruction at a time, including the ones inside blocks and all of the
+// ends, along with the offset of each instruction from the start of the expression. It uses the
+// same instruction categories as the interpreter to find where each instruction ends.
+#[derive(Debug)]
+pub struct InstrIterator<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    failed: bool,
+}
+
+impl<'a> InstrIterator<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    fn decode(&mut self) -> Result<(usize, Instr)> {
+        let offset = self.offset;
+        let mut acc = make_slice_accumulator(self.bytes);
+
+        let opcode = match Opcode::from_byte(acc.get_byte(offset)) {
+            Ok(opcode) => opcode,
+            Err(err) => {
+                return Err(match decode_unsupported(self.bytes, offset) {
+                    Some(unsupported) => anyhow!(
+                        "{} needs the {} feature, which isn't supported",
+                        unsupported.name,
+                        unsupported.feature
+                    ),
+                    None => err.into(),
+                })
+            }
+        };
+        let cat = InstructionCategory::from_opcode(opcode);
+        let length = cat.ensure_flat_instruction(&mut acc, offset)?;
+
+        let instr = match opcode {
+            Opcode::Unreachable => Instr::Unreachable,
+            Opcode::Nop => Instr::Nop,
+            Opcode::Block => Instr::Block(cat.get_block_type(&acc, offset)),
+            Opcode::Loop => Instr::Loop(cat.get_block_type(&acc, offset)),
+            Opcode::If => Instr::If(cat.get_block_type(&acc, offset)),
+            Opcode::Else => Instr::Else,
+            Opcode::End => Instr::End,
+            Opcode::Br => Instr::Br(cat.get_single_u32_arg(&acc, offset)),
+            Opcode::BrIf => Instr::BrIf(cat.get_single_u32_arg(&acc, offset)),
+            Opcode::BrTable => {
+                let mut targets: Vec<u32> = cat
+                    .get_block_table_targets(&acc, offset)
+                    .into_iter()
+                    .map(|target| target as u32)
+                    .collect();
+                // There is always a default target at the end
+                let default = targets.pop().unwrap();
+                Instr::BrTable(targets, default)
+            }
+            Opcode::Return => Instr::Return,
+            Opcode::Call => Instr::Call(cat.get_single_u32_arg(&acc, offset)),
+            Opcode::CallIndirect => Instr::CallIndirect(cat.get_pair_u32_arg(&acc, offset).0),
+            Opcode::Drop => Instr::Drop,
+            Opcode::Select => Instr::Select,
+            Opcode::LocalGet => Instr::LocalGet(cat.get_single_u32_arg(&acc, offset)),
+            Opcode::LocalSet => Instr::LocalSet(cat.get_single_u32_arg(&acc, offset)),
+            Opcode::LocalTee => Instr::LocalTee(cat.get_single_u32_arg(&acc, offset)),
+            Opcode::GlobalGet => Instr::GlobalGet(cat.get_single_u32_arg(&acc, offset)),
+            Opcode::GlobalSet => Instr::GlobalSet(cat.get_single_u32_arg(&acc, offset)),
+            Opcode::MemorySize => Instr::MemorySize,
+            Opcode::MemoryGrow => Instr::MemoryGrow,
+            Opcode::I32Const => Instr::I32Const(cat.get_single_i32_arg(&acc, offset)),
+            Opcode::I64Const => Instr::I64Const(cat.get_single_i64_arg(&acc, offset)),
+            Opcode::F32Const => Instr::F32Const(cat.get_single_f32_arg(&acc, offset)),
+            Opcode::F64Const => Instr::F64Const(cat.get_single_f64_arg(&acc, offset)),
+            // The only other instructions with two integers are the loads and stores
+            _ if cat == InstructionCategory::TwoLebInteger => {
+                let (align, memory_offset) = cat.get_pair_u32_arg(&acc, offset);
+                Instr::Memory(opcode, align, memory_offset)
+            }
+            _ => Instr::Op(opcode),
+        };
+
+        self.offset += length;
+        Ok((offset, instr))
+    }
+}
+
+impl<'a> Iterator for InstrIterator<'a> {
+    type Item = Result<(usize, Instr)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.bytes.len() {
+            return None;
+        }
+
+        let offset = self.offset;
+        let result = self
+            .decode()
+            .with_context(|| format!("Invalid instruction at offset 0x{:x}", offset));
+        // There is no way to know where the next instruction starts after a bad one
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
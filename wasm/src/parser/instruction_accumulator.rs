@@ -64,8 +64,9 @@ pub trait InstructionAccumulator {
 
     fn get_leb_i32_at(&self, offset: usize) -> i32 {
         // To encode a 32 bit number in LEB form can use a maximum of 5 chunks, of which
-        // the highest must only use 4 bits
+        // the highest must only use 4 bits. Negative numbers fill the rest with the sign bit.
         static HIGHEST_CHUNK: usize = 4;
+        static HIGHEST_CHUNK_BITS: u32 = 4;
         static HIGHEST_CHUNK_MASK: u8 = 0x0F;
 
         let mut pos: usize = offset;
@@ -78,7 +79,10 @@ pub trait InstructionAccumulator {
 
             let byte = self.get_byte(pos);
 
-            if pos == (offset + HIGHEST_CHUNK) && (byte & HIGHEST_CHUNK_MASK) != byte {
+            if pos == (offset + HIGHEST_CHUNK)
+                && (byte & HIGHEST_CHUNK_MASK) != byte
+                && !is_sign_extended(byte, HIGHEST_CHUNK_BITS)
+            {
                 panic!("LEB integer is too big");
             }
 
@@ -88,8 +92,8 @@ pub trait InstructionAccumulator {
 
             if (byte & 0x80) == 0 {
                 // At this point we have a shift bit unsigned number, so we need to sign extend it.
-                // This ought to work.
-                let mut result = unsafe { std::mem::transmute(result) };
+                // The cast keeps the bits as they are, whatever the host's byte order.
+                let mut result = result as i32;
 
                 if shift < 32 {
                     result = result << (32 - shift);
@@ -132,8 +136,9 @@ pub trait InstructionAccumulator {
 
     fn get_leb_i64_at(&self, offset: usize) -> i64 {
         // To encode a 64 bit number in LEB form can use a maximum of 10 chunks, of which
-        // the highest must only use 1 bits
+        // the highest must only use 1 bit. Negative numbers fill the rest with the sign bit.
         static HIGHEST_CHUNK: usize = 9;
+        static HIGHEST_CHUNK_BITS: u32 = 1;
         static HIGHEST_CHUNK_MASK: u8 = 0x01;
 
         let mut pos: usize = offset;
@@ -146,7 +151,10 @@ pub trait InstructionAccumulator {
 
             let byte = self.get_byte(pos);
 
-            if pos == (offset + HIGHEST_CHUNK) && (byte & HIGHEST_CHUNK_MASK) != byte {
+            if pos == (offset + HIGHEST_CHUNK)
+                && (byte & HIGHEST_CHUNK_MASK) != byte
+                && !is_sign_extended(byte, HIGHEST_CHUNK_BITS)
+            {
                 panic!("LEB integer is too big");
             }
 
@@ -156,8 +164,8 @@ pub trait InstructionAccumulator {
 
             if (byte & 0x80) == 0 {
                 // At this point we have a shift bit unsigned number, so we need to sign extend it.
-                // This ought to work.
-                let mut result = unsafe { std::mem::transmute(result) };
+                // The cast keeps the bits as they are, whatever the host's byte order.
+                let mut result = result as i64;
 
                 if shift < 64 {
                     result = result << (64 - shift);
@@ -186,6 +194,13 @@ pub trait InstructionAccumulator {
     }
 }
 
+// Whether the last chunk of a signed LEB, which only has room for the top bits of the number, is
+// a negative number's, with the rest of its bits copies of the sign bit
+fn is_sign_extended(byte: u8, bits: u32) -> bool {
+    let chunk = ((byte << 1) as i8) >> 1;
+    byte & 0x80 == 0 && chunk >> (bits - 1) == chunk >> 6
+}
+
 #[derive(Debug)]
 pub struct SliceInstructionAccumulator<'a> {
     slice: &'a [u8],
@@ -139,6 +139,23 @@ impl InstructionCategory {
         }
     }
 
+    // Like ensure_instruction, except that a block only covers the opcode and block type, rather
+    // than everything up to its end. Returns the length of the instruction.
+    pub fn ensure_flat_instruction<T: InstructionAccumulator>(
+        &self,
+        acc: &mut T,
+        offset: usize,
+    ) -> Result<usize> {
+        match self {
+            InstructionCategory::Block(_) => {
+                acc.ensure_bytes(offset + 2)?;
+                BlockType::try_from(acc.get_byte(offset + 1))?;
+                Ok(2)
+            }
+            _ => Ok(self.ensure_instruction(acc, offset)?.length()),
+        }
+    }
+
     fn ensure_two_leb_integer<T: InstructionAccumulator>(
         &self,
         acc: &mut T,
@@ -396,7 +413,7 @@ impl InstructionCategory {
 
         for _ in 0..(vector_length + 1) {
             let number_size = acc.get_leb_size_at(offset + instr_size);
-            ret.push(acc.get_leb_u32_at(instr_size).try_into().unwrap());
+            ret.push(acc.get_leb_u32_at(offset + instr_size).try_into().unwrap());
             instr_size += number_size;
         }
 
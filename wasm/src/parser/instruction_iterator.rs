@@ -32,6 +32,11 @@ impl<'a> Instruction<'a> {
         }
     }
 
+    // The encoded instruction, which is a slice of the expression it was read from
+    pub(crate) fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
     #[allow(dead_code)]
     fn lead_byte(&self) -> u8 {
         self.bytes[0]
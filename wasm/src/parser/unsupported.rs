@@ -0,0 +1,257 @@
+use std::convert::TryFrom;
+
+use crate::core::Feature;
+
+// An instruction from a proposal that the decoder recognises but the interpreter doesn't
+// implement. These are told apart from bytes that aren't an instruction at all, so a module can be
+// reported as needing a feature rather than as malformed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedInstr {
+    pub name: &'static str,
+    pub feature: Feature,
+    // The length of the whole instruction, when its immediates are simple enough to skip over.
+    // Anything after an instruction without a length can't be decoded.
+    pub length: Option<usize>,
+}
+
+// The length of the LEB128 integer starting at `at`
+fn leb_length(bytes: &[u8], at: usize) -> Option<usize> {
+    bytes
+        .get(at..)?
+        .iter()
+        .take(10)
+        .position(|byte| byte & 0x80 == 0)
+        .map(|idx| idx + 1)
+}
+
+// The length of `count` LEB128 integers one after the other starting at `at`
+fn lebs_length(bytes: &[u8], at: usize, count: usize) -> Option<usize> {
+    let mut length = 0;
+    for _ in 0..count {
+        length += leb_length(bytes, at + length)?;
+    }
+    Some(length)
+}
+
+fn leb_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let length = leb_length(bytes, at)?;
+    let mut value: u64 = 0;
+    for (idx, byte) in bytes[at..at + length].iter().enumerate().take(5) {
+        value |= u64::from(byte & 0x7f) << (idx * 7);
+    }
+    u32::try_from(value).ok()
+}
+
+fn unsupported(name: &'static str, feature: Feature, length: Option<usize>) -> UnsupportedInstr {
+    UnsupportedInstr {
+        name,
+        feature,
+        length,
+    }
+}
+
+// The 0xfc prefixed instructions, which are the saturating truncations, bulk memory and the
+// reference types table instructions
+fn decode_misc(bytes: &[u8], offset: usize) -> Option<UnsupportedInstr> {
+    let sub_opcode = leb_u32(bytes, offset + 1)?;
+    let prefix_length = 1 + leb_length(bytes, offset + 1)?;
+    let immediates = offset + prefix_length;
+    // The number of LEB128 immediates after the sub opcode. The zero bytes reserved for memory
+    // indices are a single byte integer too.
+    let (name, feature, num_immediates) = match sub_opcode {
+        0x00 => ("i32.trunc_sat_f32_s", Feature::SaturatingFloatToInt, 0),
+        0x01 => ("i32.trunc_sat_f32_u", Feature::SaturatingFloatToInt, 0),
+        0x02 => ("i32.trunc_sat_f64_s", Feature::SaturatingFloatToInt, 0),
+        0x03 => ("i32.trunc_sat_f64_u", Feature::SaturatingFloatToInt, 0),
+        0x04 => ("i64.trunc_sat_f32_s", Feature::SaturatingFloatToInt, 0),
+        0x05 => ("i64.trunc_sat_f32_u", Feature::SaturatingFloatToInt, 0),
+        0x06 => ("i64.trunc_sat_f64_s", Feature::SaturatingFloatToInt, 0),
+        0x07 => ("i64.trunc_sat_f64_u", Feature::SaturatingFloatToInt, 0),
+        0x08 => ("memory.init", Feature::BulkMemory, 2),
+        0x09 => ("data.drop", Feature::BulkMemory, 1),
+        0x0a => ("memory.copy", Feature::BulkMemory, 2),
+        0x0b => ("memory.fill", Feature::BulkMemory, 1),
+        0x0c => ("table.init", Feature::BulkMemory, 2),
+        0x0d => ("elem.drop", Feature::BulkMemory, 1),
+        0x0e => ("table.copy", Feature::BulkMemory, 2),
+        0x0f => ("table.grow", Feature::ReferenceTypes, 1),
+        0x10 => ("table.size", Feature::ReferenceTypes, 1),
+        0x11 => ("table.fill", Feature::ReferenceTypes, 1),
+        _ => return None,
+    };
+
+    let length = lebs_length(bytes, immediates, num_immediates).map(|len| prefix_length + len);
+    Some(unsupported(name, feature, length))
+}
+
+// The 0xfe prefixed atomic instructions. All of them apart from the fence take a memory argument.
+fn decode_atomic(bytes: &[u8], offset: usize) -> Option<UnsupportedInstr> {
+    let sub_opcode = leb_u32(bytes, offset + 1)?;
+    let prefix_length = 1 + leb_length(bytes, offset + 1)?;
+    let (name, num_immediates) = match sub_opcode {
+        0x00 => ("memory.atomic.notify", 2),
+        0x01 => ("memory.atomic.wait32", 2),
+        0x02 => ("memory.atomic.wait64", 2),
+        0x03 => ("atomic.fence", 1),
+        // The loads, stores and read-modify-write instructions aren't named one by one
+        0x10..=0x4e => ("atomic memory access", 2),
+        _ => return None,
+    };
+
+    let length =
+        lebs_length(bytes, offset + prefix_length, num_immediates).map(|len| prefix_length + len);
+    Some(unsupported(name, Feature::Threads, length))
+}
+
+// The 0xfd prefixed vector instructions. Only the relaxed ones are told apart, as they're the only
+// ones without immediates, so they're the only ones that can be skipped over.
+// Running them waits on there being a v128 value to run them on, so for now a module that uses
+// them is only reported as needing relaxed-simd.
+fn decode_simd(bytes: &[u8], offset: usize) -> UnsupportedInstr {
+    let name = match leb_u32(bytes, offset + 1) {
+        Some(0x100) => "i8x16.relaxed_swizzle",
+        Some(0x101) => "i32x4.relaxed_trunc_f32x4_s",
+        Some(0x102) => "i32x4.relaxed_trunc_f32x4_u",
+        Some(0x103) => "i32x4.relaxed_trunc_f64x2_s_zero",
+        Some(0x104) => "i32x4.relaxed_trunc_f64x2_u_zero",
+        Some(0x105) => "f32x4.relaxed_madd",
+        Some(0x106) => "f32x4.relaxed_nmadd",
+        Some(0x107) => "f64x2.relaxed_madd",
+        Some(0x108) => "f64x2.relaxed_nmadd",
+        Some(0x109) => "i8x16.relaxed_laneselect",
+        Some(0x10a) => "i16x8.relaxed_laneselect",
+        Some(0x10b) => "i32x4.relaxed_laneselect",
+        Some(0x10c) => "i64x2.relaxed_laneselect",
+        Some(0x10d) => "f32x4.relaxed_min",
+        Some(0x10e) => "f32x4.relaxed_max",
+        Some(0x10f) => "f64x2.relaxed_min",
+        Some(0x110) => "f64x2.relaxed_max",
+        Some(0x111) => "i16x8.relaxed_q15mulr_s",
+        Some(0x112) => "i16x8.relaxed_dot_i8x16_i7x16_s",
+        Some(0x113) => "i32x4.relaxed_dot_i8x16_i7x16_add_s",
+        // The SIMD immediates are too varied to skip over without decoding them properly
+        _ => return unsupported("v128 instruction", Feature::Simd, None),
+    };
+    let length = leb_length(bytes, offset + 1).map(|len| 1 + len);
+    unsupported(name, Feature::RelaxedSimd, length)
+}
+
+// Classifies the instruction at `offset` that the decoder couldn't read as one of the proposal
+// instructions it knows about. Returns None if it isn't one, in which case it's malformed.
+pub fn decode_unsupported(bytes: &[u8], offset: usize) -> Option<UnsupportedInstr> {
+    let lead_byte = *bytes.get(offset)?;
+    let after = offset + 1;
+
+    let instr = match lead_byte {
+        // Only the block types that are a single byte can be skipped over
+        0x06 => match bytes.get(after) {
+            Some(0x40) | Some(0x6f..=0x70) | Some(0x7b..=0x7f) => {
+                unsupported("try", Feature::ExceptionHandling, Some(2))
+            }
+            _ => unsupported("try", Feature::ExceptionHandling, None),
+        },
+        0x07 => unsupported(
+            "catch",
+            Feature::ExceptionHandling,
+            leb_length(bytes, after).map(|len| 1 + len),
+        ),
+        0x08 => unsupported(
+            "throw",
+            Feature::ExceptionHandling,
+            leb_length(bytes, after).map(|len| 1 + len),
+        ),
+        0x09 => unsupported(
+            "rethrow",
+            Feature::ExceptionHandling,
+            leb_length(bytes, after).map(|len| 1 + len),
+        ),
+        0x12 => unsupported(
+            "return_call",
+            Feature::TailCall,
+            leb_length(bytes, after).map(|len| 1 + len),
+        ),
+        0x13 => unsupported(
+            "return_call_indirect",
+            Feature::TailCall,
+            lebs_length(bytes, after, 2).map(|len| 1 + len),
+        ),
+        0x18 => unsupported(
+            "delegate",
+            Feature::ExceptionHandling,
+            leb_length(bytes, after).map(|len| 1 + len),
+        ),
+        0x19 => unsupported("catch_all", Feature::ExceptionHandling, Some(1)),
+        0x1c => {
+            // A vector of value types
+            let length = leb_u32(bytes, after).and_then(|count| {
+                let count_length = leb_length(bytes, after)?;
+                Some(1 + count_length + count as usize)
+            });
+            unsupported("select", Feature::ReferenceTypes, length)
+        }
+        0x25 => unsupported(
+            "table.get",
+            Feature::ReferenceTypes,
+            leb_length(bytes, after).map(|len| 1 + len),
+        ),
+        0x26 => unsupported(
+            "table.set",
+            Feature::ReferenceTypes,
+            leb_length(bytes, after).map(|len| 1 + len),
+        ),
+        0xd0 => unsupported("ref.null", Feature::ReferenceTypes, Some(2)),
+        0xd1 => unsupported("ref.is_null", Feature::ReferenceTypes, Some(1)),
+        0xd2 => unsupported(
+            "ref.func",
+            Feature::ReferenceTypes,
+            leb_length(bytes, after).map(|len| 1 + len),
+        ),
+        0xfc => decode_misc(bytes, offset)?,
+        0xfd => decode_simd(bytes, offset),
+        0xfe => decode_atomic(bytes, offset)?,
+        _ => return None,
+    };
+
+    // An instruction that runs past the end of the bytes has no length to skip over
+    let length = instr.length.filter(|length| offset + length <= bytes.len());
+    Some(UnsupportedInstr { length, ..instr })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_unsupported() {
+        let bytes = [0x41, 0x00, 0xfc, 0x0b, 0x00, 0x12, 0x85, 0x01, 0xfd, 0x0c];
+
+        assert_eq!(
+            decode_unsupported(&bytes, 2),
+            Some(unsupported("memory.fill", Feature::BulkMemory, Some(3)))
+        );
+        assert_eq!(
+            decode_unsupported(&bytes, 5),
+            Some(unsupported("return_call", Feature::TailCall, Some(3)))
+        );
+        assert_eq!(
+            decode_unsupported(&bytes, 8),
+            Some(unsupported("v128 instruction", Feature::Simd, None))
+        );
+        assert_eq!(
+            decode_unsupported(&[0xfd, 0x85, 0x02, 0x0b], 0),
+            Some(unsupported(
+                "f32x4.relaxed_madd",
+                Feature::RelaxedSimd,
+                Some(3)
+            ))
+        );
+        // An i32.const isn't from a proposal, and neither is an unknown 0xfc instruction
+        assert_eq!(decode_unsupported(&bytes, 0), None);
+        assert_eq!(decode_unsupported(&[0xfc, 0x7f], 0), None);
+        // Running off the end means the length isn't known
+        assert_eq!(
+            decode_unsupported(&[0xfc, 0x08, 0x00], 0),
+            Some(unsupported("memory.init", Feature::BulkMemory, None))
+        );
+    }
+}
@@ -1,9 +1,19 @@
+mod custom_sections;
+mod decode_error;
+mod framing_error;
 mod module_reader;
 mod reader_util;
 mod scoped_reader;
+mod section_iter;
+mod slice_reader;
 mod type_reader;
 
+pub use custom_sections::{CustomSectionHandlers, HandlerErrorPolicy, UnhandledSections};
+pub use decode_error::DecodeError;
+pub use framing_error::SectionFramingError;
 pub use module_reader::*;
 pub use reader_util::*;
 pub use scoped_reader::*;
+pub use section_iter::*;
+pub use slice_reader::*;
 pub use type_reader::*;
@@ -0,0 +1,136 @@
+// Handlers that are handed the custom sections as the module is read, rather than going through
+// the copies the module keeps once it's been read. A handler gets the section's contents after
+// its name, straight out of the buffer the module is read into, so a big section can be written
+// out somewhere else without another copy of it being made:
+//
+//     let mut handlers = CustomSectionHandlers::new();
+//     handlers
+//         .on_custom_section("asset", HandlerErrorPolicy::Fail, |bytes| {
+//             Ok(file.write_all(bytes)?)
+//         })
+//         .unhandled(UnhandledSections::Skip);
+//     let module = Module::load_module_with_handlers(&bytes, &mut handlers)?;
+//
+// Every handler for a section's name is called, in the order they were added. A section that
+// has a handler isn't kept in the module, and one that doesn't is kept or skipped as unhandled
+// says. Keeping them is the default, and is what lets the module be written back out whole.
+//
+// The name, target_features and dylink.0 sections are read by handlers of the same kind that every set of
+// handlers starts off with. Those only read the section into the module, and don't count as
+// handling it, so it's still kept or skipped like any other.
+use anyhow::Result;
+
+use crate::core;
+
+// What a failed handler does to the load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerErrorPolicy {
+    // The load fails with the handler's error
+    Fail,
+    // The load carries on, and the error is kept in the warnings
+    Warn,
+}
+
+// What happens to the custom sections that no handler was added for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnhandledSections {
+    Retain,
+    Skip,
+}
+
+// The sections the module itself reads
+pub(crate) enum BuiltinSection {
+    Names,
+    TargetFeatures,
+    Dylink,
+}
+
+type Callback<'a> = Box<dyn FnMut(&[u8]) -> Result<()> + 'a>;
+
+pub(crate) enum HandlerKind<'a> {
+    Builtin(BuiltinSection),
+    Callback(Callback<'a>),
+}
+
+pub(crate) struct CustomSectionHandler<'a> {
+    pub(crate) name: String,
+    pub(crate) policy: HandlerErrorPolicy,
+    pub(crate) kind: HandlerKind<'a>,
+}
+
+pub struct CustomSectionHandlers<'a> {
+    handlers: Vec<CustomSectionHandler<'a>>,
+    unhandled: UnhandledSections,
+    warnings: Vec<String>,
+}
+
+impl<'a> Default for CustomSectionHandlers<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> CustomSectionHandlers<'a> {
+    // Just the built in handlers, which warn rather than fail when a section doesn't parse,
+    // since the module can run without them
+    pub fn new() -> Self {
+        let builtin = |name: &str, section| CustomSectionHandler {
+            name: name.to_string(),
+            policy: HandlerErrorPolicy::Warn,
+            kind: HandlerKind::Builtin(section),
+        };
+        Self {
+            handlers: vec![
+                builtin("name", BuiltinSection::Names),
+                builtin(
+                    core::TARGET_FEATURES_SECTION,
+                    BuiltinSection::TargetFeatures,
+                ),
+                builtin(core::DYLINK_SECTION, BuiltinSection::Dylink),
+            ],
+            unhandled: UnhandledSections::Retain,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn on_custom_section(
+        &mut self,
+        name: &str,
+        policy: HandlerErrorPolicy,
+        handler: impl FnMut(&[u8]) -> Result<()> + 'a,
+    ) -> &mut Self {
+        self.handlers.push(CustomSectionHandler {
+            name: name.to_string(),
+            policy,
+            kind: HandlerKind::Callback(Box::new(handler)),
+        });
+        self
+    }
+
+    pub fn unhandled(&mut self, unhandled: UnhandledSections) -> &mut Self {
+        self.unhandled = unhandled;
+        self
+    }
+
+    // The errors from the handlers that warn rather than fail, in the order they happened
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub(crate) fn handlers_for<'b>(
+        &'b mut self,
+        name: &'b str,
+    ) -> impl Iterator<Item = &'b mut CustomSectionHandler<'a>> + 'b {
+        self.handlers
+            .iter_mut()
+            .filter(move |handler| handler.name == name)
+    }
+
+    pub(crate) fn retains_unhandled(&self) -> bool {
+        self.unhandled == UnhandledSections::Retain
+    }
+
+    pub(crate) fn warn(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+}
@@ -0,0 +1,48 @@
+// What's wrong with a value the reader helpers were asked to decode. It's carried in the anyhow
+// error like SectionFramingError is, so tools built on the helpers can tell what went wrong with
+// downcast_ref rather than by matching on the message. Errors from the underlying reader other
+// than running out of input are passed on as the io::Error they are.
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    // The input ended in the middle of the value
+    UnexpectedEnd,
+    // A LEB128 integer that goes on for more bytes than one of its width can have. The width is
+    // in bits, and 33 is the signed width block types are encoded with.
+    LebTooLong { bits: u32, signed: bool },
+    // A LEB128 integer that has bits set in its last byte beyond its width, or for a signed one,
+    // bits there that aren't copies of its sign bit
+    LebTooLarge { bits: u32, signed: bool },
+    InvalidUtf8Name,
+    UnknownLimitsTag(u8),
+    InvalidValueType(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = |signed: bool| if signed { "an s" } else { "a u" };
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "Unexpected end of input"),
+            DecodeError::LebTooLong { bits, signed } => write!(
+                f,
+                "LEB128 integer is too long for {}{}",
+                width(*signed),
+                bits
+            ),
+            DecodeError::LebTooLarge { bits, signed } => write!(
+                f,
+                "LEB128 integer is too large for {}{}",
+                width(*signed),
+                bits
+            ),
+            DecodeError::InvalidUtf8Name => write!(f, "Invalid UTF8 in name"),
+            DecodeError::UnknownLimitsTag(tag) => write!(f, "Unknown limits tag 0x{:02x}", tag),
+            DecodeError::InvalidValueType(byte) => {
+                write!(f, "Invalid value type byte 0x{:02x}", byte)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
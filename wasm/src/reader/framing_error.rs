@@ -0,0 +1,157 @@
+// What's wrong with the way a section is framed, as opposed to what's in it. A download that was
+// cut short shows up as one of the first two. It's carried in the anyhow error like ImportError
+// is, so it can be found with downcast_ref, with whatever the reader ran into as its cause.
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::core::SectionType;
+use crate::reader::DecodeError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SectionFramingError {
+    // The file ends in the middle of a section's id and size
+    TruncatedHeader {
+        offset: usize,
+    },
+    // A section's size goes past the end of the file
+    PastEndOfInput {
+        id: u8,
+        offset: usize,
+        declared: usize,
+        remaining: usize,
+    },
+    // A section's contents end before its size says they do
+    ContentsTooShort {
+        id: u8,
+        offset: usize,
+        declared: usize,
+        used: usize,
+    },
+    // A section's contents go on past its size, by how many bytes they'd need if the bytes after
+    // the section were part of it
+    ContentsTooLong {
+        id: u8,
+        offset: usize,
+        declared: usize,
+        overrun: usize,
+    },
+    // A section that has to come before one that's already been read, which is given as after
+    OutOfOrder {
+        id: u8,
+        offset: usize,
+        after: u8,
+    },
+}
+
+impl SectionFramingError {
+    // Whether the error is the file ending before the reader expected, which is what a truncated
+    // file gives whatever was being read
+    pub(crate) fn is_end_of_input(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| {
+            cause.downcast_ref::<DecodeError>() == Some(&DecodeError::UnexpectedEnd)
+                || cause
+                    .downcast_ref::<std::io::Error>()
+                    .map(|error| error.kind())
+                    == Some(std::io::ErrorKind::UnexpectedEof)
+        })
+    }
+}
+
+// "code section", or the id for one this crate doesn't know
+struct SectionName(u8);
+
+impl fmt::Display for SectionName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match SectionType::try_from(self.0) {
+            Ok(SectionType::CustomSection) => "custom",
+            Ok(SectionType::TypeSection) => "type",
+            Ok(SectionType::ImportSection) => "import",
+            Ok(SectionType::FunctionSection) => "function",
+            Ok(SectionType::TableSection) => "table",
+            Ok(SectionType::MemorySection) => "memory",
+            Ok(SectionType::GlobalSection) => "global",
+            Ok(SectionType::ExportSection) => "export",
+            Ok(SectionType::StartSection) => "start",
+            Ok(SectionType::ElementSection) => "element",
+            Ok(SectionType::CodeSection) => "code",
+            Ok(SectionType::DataSection) => "data",
+            Err(_) => return write!(f, "section {}", self.0),
+        };
+        write!(f, "{} section", name)
+    }
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+impl fmt::Display for SectionFramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SectionFramingError::TruncatedHeader { offset } => write!(
+                f,
+                "The file ends in the middle of the header of the section at offset 0x{:x}",
+                offset
+            ),
+            SectionFramingError::PastEndOfInput {
+                id,
+                offset,
+                declared,
+                remaining,
+            } => write!(
+                f,
+                "The {} at offset 0x{:x} claims {} byte{} but only {} remain",
+                SectionName(*id),
+                offset,
+                declared,
+                plural(*declared),
+                remaining
+            ),
+            SectionFramingError::ContentsTooShort {
+                id,
+                offset,
+                declared,
+                used,
+            } => write!(
+                f,
+                "The {} at offset 0x{:x} claims {} byte{} but its contents end {} byte{} \
+                 before that",
+                SectionName(*id),
+                offset,
+                declared,
+                plural(*declared),
+                declared - used,
+                plural(declared - used)
+            ),
+            SectionFramingError::ContentsTooLong {
+                id,
+                offset,
+                declared,
+                overrun,
+            } => write!(
+                f,
+                "The {} at offset 0x{:x} claims {} byte{} but its contents run {} byte{} past \
+                 that",
+                SectionName(*id),
+                offset,
+                declared,
+                plural(*declared),
+                overrun,
+                plural(*overrun)
+            ),
+            SectionFramingError::OutOfOrder { id, offset, after } => write!(
+                f,
+                "The {} at offset 0x{:x} comes after the {}, but has to come before it",
+                SectionName(*id),
+                offset,
+                SectionName(*after)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SectionFramingError {}
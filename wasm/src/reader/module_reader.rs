@@ -1,9 +1,12 @@
-use std::io::prelude::*;
-
 use crate::core;
-use crate::reader::{ReaderUtil, TypeReader};
-use anyhow::{anyhow, Result};
+use crate::parser;
+use crate::reader::custom_sections::{BuiltinSection, HandlerKind};
+use crate::reader::{
+    CustomSectionHandlers, HandlerErrorPolicy, ReaderUtil, SliceReader, TypeReader,
+};
+use anyhow::{anyhow, Context, Result};
 use std::convert::TryFrom;
+use std::rc::Rc;
 
 fn append_to_vector<R>(target: &mut Vec<R>, mut extra: Vec<R>) {
     target.append(&mut extra);
@@ -22,6 +25,10 @@ pub struct ModuleBuilder {
     start: Option<usize>,
     imports: Vec<core::Import>,
     exports: Vec<core::Export>,
+    names: core::NameSection,
+    target_features: Option<core::TargetFeatures>,
+    dylink: Option<core::DylinkInfo>,
+    custom_sections: Vec<core::CustomSection>,
 }
 
 impl ModuleBuilder {
@@ -38,13 +45,20 @@ impl ModuleBuilder {
             start: None,
             imports: Vec::new(),
             exports: Vec::new(),
+            names: core::NameSection::default(),
+            target_features: None,
+            dylink: None,
+            custom_sections: Vec::new(),
         }
     }
 
-    pub fn process_section<T: Read>(
+    // The reader is over the section's payload, which function bodies and data segments are
+    // sliced out of
+    pub(crate) fn process_section(
         &mut self,
         section_type: core::SectionType,
-        reader: &mut T,
+        payload: &core::SharedBytes,
+        reader: &mut SliceReader<'_>,
     ) -> anyhow::Result<()> {
         match section_type {
             core::SectionType::TypeSection => Ok(append_to_vector(
@@ -57,7 +71,7 @@ impl ModuleBuilder {
             )),
             core::SectionType::FunctionSection => Ok(append_to_vector(
                 &mut self.typeidx,
-                reader.read_vec(T::read_leb_usize)?,
+                reader.read_vec(SliceReader::read_leb_usize)?,
             )),
             core::SectionType::TableSection => Ok(append_to_vector(
                 &mut self.tables,
@@ -84,17 +98,140 @@ impl ModuleBuilder {
             )),
             core::SectionType::CodeSection => Ok(append_to_vector(
                 &mut self.funcs,
-                reader.read_vec(core::Func::read)?,
+                Self::read_code_section(payload, reader)?,
             )),
             core::SectionType::DataSection => Ok(append_to_vector(
                 &mut self.data,
-                reader.read_vec(core::Data::read)?,
+                Self::read_data_section(payload, reader)?,
             )),
 
             _ => panic!("Cannot read unknown or custom sections"),
         }
     }
 
+    // An expression that starts where the reader is, sliced out of the payload
+    fn read_shared_expr(
+        payload: &core::SharedBytes,
+        reader: &mut SliceReader<'_>,
+    ) -> Result<core::Expr> {
+        let length = parser::expression_length(reader.remaining())?;
+        let expr = payload.slice(reader.offset(), length)?;
+        reader.read_bytes(length)?;
+        Ok(core::Expr::from_shared(expr))
+    }
+
+    // The code and data sections are most of a module, so they're split up straight from the
+    // payload rather than going through TypeReader, and the bodies and segments share its buffer.
+    // They have the same checks as core::Func::read and core::Data::read make.
+    fn read_code_section(
+        payload: &core::SharedBytes,
+        reader: &mut SliceReader<'_>,
+    ) -> Result<Vec<core::Func>> {
+        let count = reader.read_leb_usize()?;
+        let mut funcs = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let size = reader.read_leb_usize()?;
+            let body_end = reader.offset() + size;
+            if body_end > payload.len() {
+                return Err(anyhow!("Function body runs past the end of the section"));
+            }
+
+            let locals = reader.read_vec(core::Locals::read)?;
+            let expr = Self::read_shared_expr(payload, reader)?;
+            if reader.offset() != body_end {
+                return Err(anyhow!("Function body doesn't end with its expression"));
+            }
+
+            funcs.push(core::Func::new(locals, expr));
+        }
+
+        Ok(funcs)
+    }
+
+    fn read_data_section(
+        payload: &core::SharedBytes,
+        reader: &mut SliceReader<'_>,
+    ) -> Result<Vec<core::Data>> {
+        let count = reader.read_leb_usize()?;
+        let mut data = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let mem_idx = reader.read_leb_usize()?;
+            let expr = Self::read_shared_expr(payload, reader)?;
+            let size = reader.read_leb_usize()?;
+            let bytes = payload.slice(reader.offset(), size)?;
+            reader.read_bytes(size)?;
+
+            data.push(core::Data::from_shared(mem_idx, expr, bytes));
+        }
+
+        Ok(data)
+    }
+
+    // Hands the section to each of its handlers, and keeps it if none of them took it and the
+    // handlers say to. The offset is the section's, for the errors.
+    pub fn process_custom_section(
+        &mut self,
+        section_name: String,
+        body: &[u8],
+        after: Option<core::SectionType>,
+        offset: usize,
+        handlers: &mut CustomSectionHandlers<'_>,
+    ) -> Result<()> {
+        let mut handled = false;
+        let mut warnings = Vec::new();
+        for handler in handlers.handlers_for(&section_name) {
+            let result = match &mut handler.kind {
+                HandlerKind::Builtin(section) => self.read_builtin_section(section, body),
+                HandlerKind::Callback(callback) => {
+                    handled = true;
+                    callback(body)
+                }
+            };
+            let result = result.with_context(|| {
+                format!(
+                    "Custom section handler for {} at offset 0x{:x} failed",
+                    section_name, offset
+                )
+            });
+            match (result, handler.policy) {
+                (Ok(()), _) => {}
+                (Err(error), HandlerErrorPolicy::Fail) => return Err(error),
+                (Err(error), HandlerErrorPolicy::Warn) => warnings.push(format!("{:#}", error)),
+            }
+        }
+        for warning in warnings {
+            handlers.warn(warning);
+        }
+
+        // Custom sections are kept as they are so the module can be written back out
+        if !handled && handlers.retains_unhandled() {
+            self.custom_sections
+                .push(core::CustomSection::new(section_name, body.to_vec(), after));
+        }
+        Ok(())
+    }
+
+    // The name section is only there to help with debugging, the target features are only
+    // checked if they're asked for, and the dylink section is only needed to link the module
+    // dynamically, so the module is loaded without them if they don't parse
+    fn read_builtin_section(&mut self, section: &BuiltinSection, body: &[u8]) -> Result<()> {
+        match section {
+            BuiltinSection::Names => self.names = core::NameSection::read(&mut &body[..])?,
+            BuiltinSection::TargetFeatures => {
+                // One that doesn't parse leaves the module with none, as it always has
+                self.target_features = None;
+                self.target_features = Some(core::TargetFeatures::read(&mut &body[..])?);
+            }
+            BuiltinSection::Dylink => {
+                self.dylink = None;
+                self.dylink = Some(core::DylinkInfo::read(&mut &body[..])?);
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_next_section_type(
         current_section_type: core::SectionType,
     ) -> Option<core::SectionType> {
@@ -123,7 +260,7 @@ impl ModuleBuilder {
         } else {
             // TODOTODOTODO - this will get more complicated - there is more processing to be done here
             // to tie up the functions table
-            Ok(core::RawModule::new(
+            let mut module = core::RawModule::new(
                 self.types,
                 self.typeidx,
                 self.funcs,
@@ -135,7 +272,16 @@ impl ModuleBuilder {
                 self.start,
                 self.imports,
                 self.exports,
-            ))
+            );
+            // The functions are added to a section at a time, so trim what was allocated ahead
+            module.typeidx.shrink_to_fit();
+            module.funcs.shrink_to_fit();
+            module.names = Rc::new(self.names);
+            module.target_features = self.target_features;
+            module.dylink = self.dylink;
+            module.custom_sections = self.custom_sections;
+
+            Ok(module)
         }
     }
 
@@ -147,8 +293,4 @@ impl ModuleBuilder {
             Ok(())
         }
     }
-
-    pub fn read_next_section_header<T: Read>(reader: &mut T) -> Result<core::SectionType> {
-        core::SectionType::read(reader)
-    }
 }
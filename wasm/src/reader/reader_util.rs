@@ -1,12 +1,106 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use std::convert::TryFrom;
 use std::io;
 
+use crate::reader::DecodeError;
+
+// Decodes a LEB128 integer of the given width, as strictly as the specification says to. It can
+// have no more bytes than it takes to hold that many bits, and the bits of its last byte that are
+// past the width have to be zero, or for a signed one, copies of its sign bit. A signed value
+// comes back sign extended to 64 bits.
+fn read_leb<R: io::Read + ?Sized>(reader: &mut R, bits: u32, signed: bool) -> Result<u64> {
+    let max_bytes = (bits - 1) / 7 + 1;
+    let mut result: u64 = 0;
+
+    for idx in 0..max_bytes {
+        let byte = read_byte(reader)?;
+        if idx == max_bytes - 1 {
+            if byte & 0x80 != 0 {
+                return Err(DecodeError::LebTooLong { bits, signed }.into());
+            }
+            // The bits of the last byte that are past the width
+            let used = bits - 7 * idx;
+            let unused = (0x7f << used) & 0x7f;
+            let expected = if signed && (byte >> (used - 1)) & 1 != 0 {
+                unused
+            } else {
+                0
+            };
+            if byte & unused != expected {
+                return Err(DecodeError::LebTooLarge { bits, signed }.into());
+            }
+        }
+
+        let shift = 7 * idx;
+        result |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            if signed && shift + 7 < 64 && byte & 0x40 != 0 {
+                result |= !0 << (shift + 7);
+            }
+            return Ok(result);
+        }
+    }
+
+    unreachable!("the last byte of a LEB128 integer always ends it")
+}
+
+fn read_byte<R: io::Read + ?Sized>(reader: &mut R) -> Result<u8> {
+    let mut buf = [0; 1];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(buf[0]),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(DecodeError::UnexpectedEnd.into())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+// Decodes the LEB128 u32 at the start of the bytes, along with how many bytes it took up. This is
+// the same as ReaderUtil::read_leb_u32, without going through io::Read a byte at a time.
+pub(crate) fn decode_leb_u32(bytes: &[u8]) -> Result<(u32, usize)> {
+    let mut remaining = bytes;
+    let value = read_leb(&mut remaining, 32, false)?;
+    Ok((value as u32, bytes.len() - remaining.len()))
+}
+
+/// The primitives of the binary format, for anything that reads it, whether that's a whole module
+/// or just enough of one to pick out a section. They're implemented for every `io::Read`.
+///
+/// They're as strict as the specification is. A LEB128 integer can only have as many bytes as
+/// its width needs, and can't have bits set past its width, or for a signed one, bits that
+/// aren't copies of its sign bit, though padding it out with bytes of zeroes (or of ones, for a
+/// negative one) within that is allowed. A name has to be valid UTF-8. Anything else fails with a
+/// `DecodeError`, which can be picked out of the error with `downcast_ref`, and so does running out
+/// of input. Any other error from the reader is passed on as it was.
+///
+/// ```
+/// use wasm::reader::{DecodeError, ReaderUtil};
+///
+/// let mut bytes: &[u8] = &[0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f, 0x03, b'a', b'b', b'c'];
+/// assert_eq!(bytes.read_leb_u32().unwrap(), 624_485);
+/// assert_eq!(bytes.read_leb_s32().unwrap(), -1);
+/// assert_eq!(bytes.read_leb_s64().unwrap(), -128);
+/// assert_eq!(bytes.read_name().unwrap(), "abc");
+///
+/// let error = (&mut &[0xff, 0xff, 0xff, 0xff, 0x1f][..]).read_leb_u32().unwrap_err();
+/// assert_eq!(
+///     error.downcast_ref::<DecodeError>(),
+///     Some(&DecodeError::LebTooLarge { bits: 32, signed: false })
+/// );
+/// let error = (&mut &[0x80][..]).read_leb_u64().unwrap_err();
+/// assert_eq!(error.downcast_ref::<DecodeError>(), Some(&DecodeError::UnexpectedEnd));
+/// ```
 pub trait ReaderUtil {
     fn read_u8(&mut self) -> Result<u8>;
     fn read_leb_u32(&mut self) -> Result<u32>;
+    fn read_leb_u64(&mut self) -> Result<u64>;
+    fn read_leb_s32(&mut self) -> Result<i32>;
+    // A signed 33 bit integer, which is how a block type that's a type index is encoded
+    fn read_leb_s33(&mut self) -> Result<i64>;
+    fn read_leb_s64(&mut self) -> Result<i64>;
     fn read_leb_usize(&mut self) -> Result<usize>;
 
+    // A LEB128 u32 count followed by that many of what read_fn reads
     fn read_vec<R, T: Fn(&mut Self) -> Result<R>>(&mut self, read_fn: T) -> Result<Vec<R>>;
 
     fn read_name(&mut self) -> Result<String>;
@@ -18,23 +112,27 @@ where
     T: io::Read,
 {
     fn read_u8(&mut self) -> Result<u8> {
-        let mut buf: [u8; 1] = [0; 1];
-        self.read_exact(&mut buf)?;
-        Ok(buf[0])
+        read_byte(self)
     }
 
     fn read_leb_u32(&mut self) -> Result<u32> {
-        let mut result: u32 = 0;
-        let mut shift = 0;
-
-        loop {
-            let byte = self.read_u8()?;
-            result |= u32::from(byte & 0x7f) << shift;
-            if (byte & 0x80) == 0 {
-                return Ok(result);
-            }
-            shift += 7;
-        }
+        Ok(read_leb(self, 32, false)? as u32)
+    }
+
+    fn read_leb_u64(&mut self) -> Result<u64> {
+        read_leb(self, 64, false)
+    }
+
+    fn read_leb_s32(&mut self) -> Result<i32> {
+        Ok(read_leb(self, 32, true)? as i32)
+    }
+
+    fn read_leb_s33(&mut self) -> Result<i64> {
+        Ok(read_leb(self, 33, true)? as i64)
+    }
+
+    fn read_leb_s64(&mut self) -> Result<i64> {
+        Ok(read_leb(self, 64, true)? as i64)
     }
 
     fn read_leb_usize(&mut self) -> Result<usize> {
@@ -43,7 +141,10 @@ where
 
     fn read_vec<R, T2: Fn(&mut Self) -> Result<R>>(&mut self, read_fn: T2) -> Result<Vec<R>> {
         let vector_length = self.read_leb_u32()?;
-        let mut ret = Vec::with_capacity(usize::try_from(vector_length).unwrap());
+        // The count hasn't been checked against how much input there is, so only so much room is
+        // made for it up front
+        let mut ret =
+            Vec::with_capacity(std::cmp::min(usize::try_from(vector_length).unwrap(), 4096));
 
         for _ in 0..vector_length {
             ret.push(read_fn(self)?);
@@ -57,7 +158,7 @@ where
 
         match String::from_utf8(bytes) {
             Ok(s) => Ok(s),
-            Err(_) => Err(anyhow!("Invalid UTF8 in name")),
+            Err(_) => Err(DecodeError::InvalidUtf8Name.into()),
         }
     }
 
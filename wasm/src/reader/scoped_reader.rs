@@ -1,6 +1,22 @@
 use std::io;
 use std::io::prelude::*;
 
+/// Reads no more than the given number of bytes from another reader, such as the payload of a
+/// section or a function body whose size came first. It ends there as though the input did, so
+/// whatever reads from it can't run on into what comes after, and `is_at_end` says whether all of
+/// it was read.
+///
+/// ```
+/// use wasm::reader::{ReaderUtil, ScopedReader};
+///
+/// // A name, then a byte that isn't part of it
+/// let mut bytes: &[u8] = &[0x03, b'a', b'b', b'c', 0x2a];
+/// let mut scoped = ScopedReader::new(&mut bytes, 4);
+/// assert_eq!(scoped.read_name().unwrap(), "abc");
+/// assert!(scoped.is_at_end());
+/// assert!(scoped.read_u8().is_err());
+/// assert_eq!(bytes.read_u8().unwrap(), 0x2a);
+/// ```
 pub struct ScopedReader<'a, I: io::Read> {
     src: &'a mut I,
     offset: usize,
@@ -31,7 +47,7 @@ where
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // We need to limit the size of the read
         let bytes_to_read = if buf.len() > (self.size - self.offset) {
-            (self.size - self.offset)
+            self.size - self.offset
         } else {
             buf.len()
         };
@@ -0,0 +1,254 @@
+use std::convert::TryFrom;
+use std::io;
+use std::io::prelude::*;
+
+use crate::core;
+use crate::reader::{ReaderUtil, SectionFramingError};
+use anyhow::{anyhow, Context, Result};
+
+pub const MODULE_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+// Keeps track of how far into the file we are, so sections and errors can report their offsets
+struct CountingReader<R: Read> {
+    src: R,
+    offset: usize,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.src.read(buf)?;
+        self.offset += bytes_read;
+        Ok(bytes_read)
+    }
+}
+
+// One section of a module, with its payload still in the binary format
+#[derive(Debug, Clone)]
+pub struct Section {
+    id: u8,
+    offset: usize,
+    payload_offset: usize,
+    payload: core::SharedBytes,
+}
+
+impl Section {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    // None for ids this crate doesn't know about
+    pub fn section_type(&self) -> Option<core::SectionType> {
+        core::SectionType::try_from(self.id).ok()
+    }
+
+    // The offset of the section id in the file
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // The offset of the first byte of the payload in the file
+    pub fn payload_offset(&self) -> usize {
+        self.payload_offset
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub(crate) fn shared_payload(&self) -> &core::SharedBytes {
+        &self.payload
+    }
+
+    // Splits a custom section's payload into its name and the rest of the contents
+    pub fn custom_contents(&self) -> Result<(String, &[u8])> {
+        if self.section_type() != Some(core::SectionType::CustomSection) {
+            return Err(anyhow!(
+                "Section at offset 0x{:x} is not a custom section",
+                self.offset
+            ));
+        }
+
+        let mut contents: &[u8] = self.payload();
+        let name = contents
+            .read_name()
+            .with_context(|| format!("Invalid custom section at offset 0x{:x}", self.offset))?;
+        Ok((name, contents))
+    }
+}
+
+// Walks the sections of a module without decoding them. This is what RawModule::read uses to
+// find the sections, so it understands the framing in exactly the same way.
+pub struct SectionIter<R: Read> {
+    reader: CountingReader<R>,
+    // When the whole module is in memory already the sections are read from that instead, and
+    // their payloads are slices of it
+    module: Option<core::SharedBytes>,
+    failed: bool,
+}
+
+impl<R: Read> SectionIter<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let mut iter = Self {
+            reader: CountingReader {
+                src: reader,
+                offset: 0,
+            },
+            module: None,
+            failed: false,
+        };
+        iter.read_header()?;
+        Ok(iter)
+    }
+
+    fn read_header(&mut self) -> Result<()> {
+        let mut header = [0; MODULE_HEADER.len()];
+        self.read_exact(&mut header)
+            .context("Module is too short to have a header")?;
+
+        if header[..4] == MODULE_HEADER[..4] && header[6..] == [0x01, 0x00] {
+            // The layer field is 1 for a component rather than a core module
+            Err(anyhow!(
+                "This is a component, not a core module. Components can't be instantiated, but \
+                 the core modules in one can be read with component::Component::read"
+            ))
+        } else if header != MODULE_HEADER {
+            Err(anyhow!("Invalid module header"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match &self.module {
+            Some(module) => {
+                let mut remaining = &module[self.reader.offset..];
+                remaining.read_exact(buf)?;
+                self.reader.offset += buf.len();
+                Ok(())
+            }
+            None => self.reader.read_exact(buf),
+        }
+    }
+
+    fn read_leb_usize(&mut self) -> Result<usize> {
+        match &self.module {
+            Some(module) => {
+                let mut remaining = &module[self.reader.offset..];
+                let value = remaining.read_leb_usize()?;
+                self.reader.offset = module.len() - remaining.len();
+                Ok(value)
+            }
+            None => self.reader.read_leb_usize(),
+        }
+    }
+
+    // The payload, or how much of it there is if the file ends before the end of it
+    fn read_payload(&mut self, size: usize) -> Result<core::SharedBytes, usize> {
+        match &self.module {
+            Some(module) => {
+                let remaining = module.len() - self.reader.offset;
+                if size > remaining {
+                    return Err(remaining);
+                }
+                let payload = module
+                    .slice(self.reader.offset, size)
+                    .expect("The payload is within the module");
+                self.reader.offset += size;
+                Ok(payload)
+            }
+            None => {
+                // The counting reader moves the offset on. A size that's far too big is only
+                // read as far as the end of the file, rather than all being allocated up front.
+                let mut payload = Vec::new();
+                let read = (&mut self.reader)
+                    .take(size as u64)
+                    .read_to_end(&mut payload);
+                match read {
+                    Ok(read) if read == size => Ok(payload.into()),
+                    _ => Err(payload.len()),
+                }
+            }
+        }
+    }
+
+    // The id of the next section, or None at the end of the module
+    fn read_id(&mut self) -> io::Result<Option<u8>> {
+        let mut id = [0; 1];
+        let bytes_read = match &self.module {
+            Some(module) => {
+                let mut remaining = &module[self.reader.offset..];
+                let bytes_read = remaining.read(&mut id)?;
+                self.reader.offset += bytes_read;
+                bytes_read
+            }
+            None => self.reader.read(&mut id)?,
+        };
+        Ok(if bytes_read == 0 { None } else { Some(id[0]) })
+    }
+
+    fn read_section(&mut self, id: u8, offset: usize) -> Result<Section> {
+        let size = self.read_leb_usize().map_err(|error| {
+            if SectionFramingError::is_end_of_input(&error) {
+                error.context(SectionFramingError::TruncatedHeader { offset })
+            } else {
+                error
+            }
+        })?;
+        let payload_offset = self.reader.offset;
+
+        let payload =
+            self.read_payload(size)
+                .map_err(|remaining| SectionFramingError::PastEndOfInput {
+                    id,
+                    offset,
+                    declared: size,
+                    remaining,
+                })?;
+
+        Ok(Section {
+            id,
+            offset,
+            payload_offset,
+            payload,
+        })
+    }
+}
+
+impl<R: Read> Iterator for SectionIter<R> {
+    type Item = Result<Section>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let offset = self.reader.offset;
+        let section = match self.read_id() {
+            // The end of the file is the end of the module
+            Ok(None) => return None,
+            Ok(Some(id)) => self.read_section(id, offset),
+            Err(e) => Err(e.into()),
+        };
+
+        let section =
+            section.with_context(|| format!("Failed to read section at offset 0x{:x}", offset));
+        self.failed = section.is_err();
+        Some(section)
+    }
+}
+
+impl SectionIter<io::Empty> {
+    // Walks the sections of a module that's all in memory, without copying any of it
+    pub(crate) fn from_shared(module: core::SharedBytes) -> Result<Self> {
+        let mut iter = Self {
+            reader: CountingReader {
+                src: io::empty(),
+                offset: 0,
+            },
+            module: Some(module),
+            failed: false,
+        };
+        iter.read_header()?;
+        Ok(iter)
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::io;
+
+use crate::reader::{decode_leb_u32, DecodeError};
+
+// Reads from bytes that are all in memory already, such as a section payload. It's an io::Read like
+// any other, so everything that reads the binary format works with it, but the integers and byte
+// runs that most of a module is made of are read straight out of the slice.
+#[derive(Debug, Clone)]
+pub struct SliceReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    // How far into the bytes the reader is
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.offset..]
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.offset == self.bytes.len()
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.offset)
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    pub fn read_leb_u32(&mut self) -> Result<u32> {
+        // Most integers in a module are small enough for one byte
+        if let Some(&byte) = self.bytes.get(self.offset) {
+            if byte & 0x80 == 0 {
+                self.offset += 1;
+                return Ok(u32::from(byte));
+            }
+        }
+
+        let (value, length) = decode_leb_u32(self.remaining())?;
+        self.offset += length;
+        Ok(value)
+    }
+
+    pub fn read_leb_usize(&mut self) -> Result<usize> {
+        Ok(usize::try_from(self.read_leb_u32()?).unwrap())
+    }
+
+    // The next `length` bytes, without copying them
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        if length > self.bytes.len() - self.offset {
+            return Err(anyhow!(
+                "{} bytes are needed, but only {} are left",
+                length,
+                self.bytes.len() - self.offset
+            ));
+        }
+
+        let bytes = &self.bytes[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(bytes)
+    }
+}
+
+impl<'a> io::Read for SliceReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = std::cmp::min(buf.len(), self.bytes.len() - self.offset);
+        buf[..length].copy_from_slice(&self.bytes[self.offset..self.offset + length]);
+        self.offset += length;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reader::ReaderUtil;
+
+    #[test]
+    fn test_read_leb_u32() {
+        let bytes = [
+            0x05, 0xe5, 0x8e, 0x26, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x80, 0x80, 0x80, 0x80, 0x00,
+        ];
+
+        let mut reader = SliceReader::new(&bytes);
+        assert_eq!(reader.read_leb_u32().unwrap(), 5);
+        assert_eq!(reader.read_leb_u32().unwrap(), 624_485);
+        assert_eq!(reader.read_leb_u32().unwrap(), 0xffff_ffff);
+        // Padding out to the full five bytes is allowed
+        assert_eq!(reader.read_leb_u32().unwrap(), 0);
+        assert!(reader.is_at_end());
+
+        // The fast path reads the same values as going through io::Read
+        let mut generic = &bytes[..];
+        for _ in 0..4 {
+            let mut reader = SliceReader::new(generic);
+            let value = reader.read_leb_u32().unwrap();
+            assert_eq!(ReaderUtil::read_leb_u32(&mut generic).unwrap(), value);
+        }
+
+        // Both are strict about what doesn't fit in a u32
+        for bad in &[
+            &[0xff, 0xff, 0xff, 0xff, 0x1f][..],
+            &[0x80, 0x80, 0x80, 0x80, 0x80, 0x00][..],
+            &[0x80, 0x80][..],
+        ] {
+            assert!(SliceReader::new(bad).read_leb_u32().is_err());
+            assert!(ReaderUtil::read_leb_u32(&mut &bad[..]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_leb_widths() {
+        use crate::reader::DecodeError;
+
+        let decode_error = |result: anyhow::Result<i64>| {
+            result
+                .unwrap_err()
+                .downcast_ref::<DecodeError>()
+                .cloned()
+                .unwrap()
+        };
+
+        // The extremes of each signed width, minimal and padded out to the full length
+        let s32 = |bytes: &[u8]| ReaderUtil::read_leb_s32(&mut &bytes[..]).map(i64::from);
+        assert_eq!(s32(&[0x7f]).unwrap(), -1);
+        assert_eq!(s32(&[0xff, 0xff, 0xff, 0xff, 0x07]).unwrap(), 0x7fff_ffff);
+        assert_eq!(s32(&[0x80, 0x80, 0x80, 0x80, 0x78]).unwrap(), -0x8000_0000);
+        assert_eq!(s32(&[0xff, 0xff, 0xff, 0xff, 0x7f]).unwrap(), -1);
+        assert_eq!(
+            decode_error(s32(&[0xff, 0xff, 0xff, 0xff, 0x0f])),
+            DecodeError::LebTooLarge {
+                bits: 32,
+                signed: true
+            }
+        );
+        assert_eq!(
+            decode_error(s32(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00])),
+            DecodeError::LebTooLong {
+                bits: 32,
+                signed: true
+            }
+        );
+
+        let s33 = |bytes: &[u8]| ReaderUtil::read_leb_s33(&mut &bytes[..]);
+        assert_eq!(s33(&[0x40]).unwrap(), -64);
+        assert_eq!(s33(&[0xff, 0xff, 0xff, 0xff, 0x0f]).unwrap(), 0xffff_ffff);
+        assert_eq!(
+            s33(&[0x80, 0x80, 0x80, 0x80, 0x70]).unwrap(),
+            -0x1_0000_0000
+        );
+        assert_eq!(
+            decode_error(s33(&[0xff, 0xff, 0xff, 0xff, 0x1f])),
+            DecodeError::LebTooLarge {
+                bits: 33,
+                signed: true
+            }
+        );
+
+        let s64 = |bytes: &[u8]| ReaderUtil::read_leb_s64(&mut &bytes[..]);
+        let mut max = vec![0xff; 9];
+        max.push(0x00);
+        assert_eq!(s64(&max).unwrap(), std::i64::MAX);
+        let mut min = vec![0x80; 9];
+        min.push(0x7f);
+        assert_eq!(s64(&min).unwrap(), std::i64::MIN);
+        let mut too_large = vec![0xff; 9];
+        too_large.push(0x01);
+        assert_eq!(
+            decode_error(s64(&too_large)),
+            DecodeError::LebTooLarge {
+                bits: 64,
+                signed: true
+            }
+        );
+
+        let mut u64_max = vec![0xff; 9];
+        u64_max.push(0x01);
+        assert_eq!(
+            ReaderUtil::read_leb_u64(&mut &u64_max[..]).unwrap(),
+            std::u64::MAX
+        );
+        assert_eq!(decode_error(s64(&[0x80, 0x80])), DecodeError::UnexpectedEnd);
+    }
+}
@@ -5,9 +5,28 @@ use std::convert::TryFrom;
 
 use crate::core;
 use crate::parser;
-use crate::reader::{ReaderUtil, ScopedReader};
+use crate::reader::{DecodeError, ReaderUtil, ScopedReader};
+use crate::writer::WriterUtil;
 use anyhow::anyhow;
 
+/// Reads one of the binary format's types, such as a value type, limits or a function type. The
+/// tags they start with have to be ones the specification defines, and the integers in them are
+/// read as strictly as `ReaderUtil` reads them. A bad tag is a `DecodeError` like the rest.
+///
+/// ```
+/// use wasm::core::{Limits, ValueType};
+/// use wasm::reader::{DecodeError, TypeReader};
+///
+/// let mut bytes: &[u8] = &[0x01, 0x01, 0x80, 0x02, 0x7e, 0x02];
+/// assert_eq!(Limits::read(&mut bytes).unwrap(), Limits::Bounded(1, 256));
+/// assert_eq!(ValueType::read(&mut bytes).unwrap(), ValueType::I64);
+///
+/// let error = Limits::read(&mut bytes).unwrap_err();
+/// assert_eq!(
+///     error.downcast_ref::<DecodeError>(),
+///     Some(&DecodeError::UnknownLimitsTag(0x02))
+/// );
+/// ```
 pub trait TypeReader
 where
     Self: std::marker::Sized,
@@ -36,15 +55,16 @@ impl TypeReader for core::ElemType {
 impl TypeReader for core::Limits {
     fn read<T: io::Read>(reader: &mut T) -> anyhow::Result<Self> {
         match reader.read_u8()? {
-            0x00 => Ok(core::Limits::Unbounded(reader.read_leb_usize()?)),
+            // Limits are u32 in the binary format, which the size queries rely on
+            0x00 => Ok(core::Limits::Unbounded(reader.read_leb_u32()? as usize)),
             0x01 => {
-                let min = reader.read_leb_usize()?;
-                let max = reader.read_leb_usize()?;
+                let min = reader.read_leb_u32()? as usize;
+                let max = reader.read_leb_u32()? as usize;
 
                 Ok(core::Limits::Bounded(min, max))
             }
 
-            _ => Err(anyhow!("Unknown Limits tag")),
+            tag => Err(DecodeError::UnknownLimitsTag(tag).into()),
         }
     }
 }
@@ -147,13 +167,81 @@ impl TypeReader for core::Export {
     }
 }
 
+// One of the constant expressions an element segment can give its entries as. These give a
+// reference rather than a value, so they have instructions of their own that the expression
+// reader doesn't know.
+fn read_element_expr<T: io::Read>(reader: &mut T) -> anyhow::Result<core::Expr> {
+    let mut bytes = Vec::new();
+    match reader.read_u8()? {
+        0xd0 => {
+            let heap_type = reader.read_u8()?;
+            if heap_type != core::ElemType::FuncRef as u8 {
+                return Err(anyhow!("Unknown reference type 0x{:02x}", heap_type));
+            }
+            bytes.extend_from_slice(&[0xd0, heap_type]);
+        }
+        opcode @ 0xd2 | opcode @ 0x23 => {
+            bytes.push(opcode);
+            bytes.write_leb_u32(reader.read_leb_u32()?)?;
+        }
+        opcode => {
+            return Err(anyhow!(
+                "Opcode 0x{:02x} is not valid in an element expression",
+                opcode
+            ))
+        }
+    }
+
+    match reader.read_u8()? {
+        0x0b => bytes.push(0x0b),
+        _ => return Err(anyhow!("Element expression has more than one instruction")),
+    }
+    Ok(core::Expr::new(bytes))
+}
+
 impl TypeReader for core::Element {
+    // The flags say how the segment is laid out. The lowest bit is set for a segment that isn't
+    // active, and the next one then tells a declared segment from a passive one, or for an active
+    // segment says that it names its table. The third is set when the entries are expressions.
     fn read<T: io::Read>(reader: &mut T) -> anyhow::Result<Self> {
-        let x = reader.read_leb_usize()?;
-        let e = core::Expr::read(reader)?;
-        let y = reader.read_vec(T::read_leb_usize)?;
+        let flags = reader.read_leb_u32()?;
+        if flags > 7 {
+            return Err(anyhow!("Invalid element segment flags {}", flags));
+        }
 
-        Ok(Self::new(x, e, y))
+        let mode = match flags & 0x3 {
+            0 => core::ElementMode::Active {
+                table_idx: 0,
+                offset: core::Expr::read(reader)?,
+            },
+            1 => core::ElementMode::Passive,
+            2 => core::ElementMode::Active {
+                table_idx: reader.read_leb_usize()?,
+                offset: core::Expr::read(reader)?,
+            },
+            _ => core::ElementMode::Declared,
+        };
+
+        let exprs = flags & 0x4 != 0;
+        // Only the segments that can be told apart from the MVP one say what's in them. The ones
+        // given as indices have an element kind, where 0 is the function references, and the ones
+        // given as expressions have a reference type.
+        let et = match (flags & 0x3 != 0, exprs) {
+            (false, _) => core::ElemType::FuncRef,
+            (true, false) => match reader.read_u8()? {
+                0x00 => core::ElemType::FuncRef,
+                kind => return Err(anyhow!("Unknown element kind 0x{:02x}", kind)),
+            },
+            (true, true) => core::ElemType::read(reader)?,
+        };
+
+        let items = if exprs {
+            core::ElementItems::Exprs(reader.read_vec(read_element_expr)?)
+        } else {
+            core::ElementItems::FuncIndices(reader.read_vec(T::read_leb_usize)?)
+        };
+
+        Ok(Self::with_mode(mode, et, items))
     }
 }
 
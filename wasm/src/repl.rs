@@ -0,0 +1,304 @@
+// An interactive session with one instance of a module. Each line either calls an export, like
+// `add 1 2`, or is one of the commands that start with a colon. The instance lives for the whole
+// session, so what a call leaves in memory or in the globals is there for the next one, and a trap
+// only ends the call it happened in.
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, Write};
+
+use crate::{format_result, parse_argument, radix_and_digits};
+use wasm::core::{self, Extern};
+
+const HELP: &str = "\
+<export> [args...]   call an exported function, with arguments of the types it takes
+:exports             list the exports
+:globals             list the globals and their values
+:mem <addr> <len>    dump some of memory 0
+:help                show this
+:quit                leave, as does the end of the input
+";
+
+pub struct Repl<'a> {
+    module: &'a core::Module,
+    instance: core::Instance,
+}
+
+// What to do after a line
+#[derive(Debug, PartialEq)]
+enum Step {
+    Continue,
+    Quit,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(module: &'a core::Module, instance: core::Instance) -> Self {
+        Self { module, instance }
+    }
+
+    pub fn run(&mut self, input: impl BufRead, out: &mut impl Write) -> Result<()> {
+        self.list_exports(out)?;
+        write!(out, "> ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            // Anything that goes wrong with a line is reported, and the session carries on
+            match self.execute_line(&line?, out) {
+                Ok(Step::Quit) => return Ok(()),
+                Ok(Step::Continue) => {}
+                Err(error) => writeln!(out, "error: {:#}", error)?,
+            }
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+
+        writeln!(out)?;
+        Ok(())
+    }
+
+    fn execute_line(&mut self, line: &str, out: &mut impl Write) -> Result<Step> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            [":quit"] | [":q"] => return Ok(Step::Quit),
+            [":help"] => write!(out, "{}", HELP)?,
+            [":exports"] => self.list_exports(out)?,
+            [":globals"] => self.list_globals(out)?,
+            [":mem", addr, len] => self.dump_memory(parse_usize(addr)?, parse_usize(len)?, out)?,
+            [command, ..] if command.starts_with(':') => {
+                return Err(anyhow!("Unknown command {}, :help lists them", command))
+            }
+            [name, ..] => self.call(name, &words[1..], out)?,
+        }
+        Ok(Step::Continue)
+    }
+
+    // In the order the module declares them, rather than whatever order the map has them in
+    fn list_exports(&self, out: &mut impl Write) -> Result<()> {
+        for export in self.module.raw_module().exports() {
+            let description = match self.instance.exports.get(&export.nm) {
+                Some(Extern::Func(function)) => {
+                    format!("func {}", function.borrow().func_type())
+                }
+                Some(Extern::Global(global)) => {
+                    format!("global {}", global.borrow().global_type())
+                }
+                Some(Extern::Memory(memory)) => {
+                    format!("memory of {} pages", memory.borrow().size_pages())
+                }
+                Some(Extern::Table(table)) => {
+                    format!("table of {} entries", table.borrow().size())
+                }
+                None => continue,
+            };
+            writeln!(out, "{}: {}", export.nm, description)?;
+        }
+        Ok(())
+    }
+
+    fn list_globals(&self, out: &mut impl Write) -> Result<()> {
+        for (global_idx, global) in self.instance.globals.iter().enumerate() {
+            let global = global.borrow();
+            writeln!(
+                out,
+                "{}: {} = {}",
+                self.module.describe_global(global_idx),
+                global.global_type(),
+                format_result(global.get_value())
+            )?;
+        }
+        Ok(())
+    }
+
+    // Sixteen bytes a line, in hex and then as text
+    fn dump_memory(&self, addr: usize, len: usize, out: &mut impl Write) -> Result<()> {
+        let memory = match self.instance.memories.first() {
+            Some(memory) => memory.borrow(),
+            None => return Err(anyhow!("The module has no memory")),
+        };
+        let mut bytes = vec![0; len];
+        memory.get_data(addr, &mut bytes)?;
+
+        for (line, chunk) in bytes.chunks(16).enumerate() {
+            let hex: Vec<_> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(
+                out,
+                "{:08x}  {:<47}  |{}|",
+                addr + line * 16,
+                hex.join(" "),
+                text
+            )?;
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, name: &str, args: &[&str], out: &mut impl Write) -> Result<()> {
+        let func_type = self
+            .instance
+            .get_function(name)?
+            .borrow()
+            .func_type()
+            .clone();
+        if args.len() != func_type.params().len() {
+            return Err(anyhow!(
+                "{} {} takes {} arguments, but {} were given",
+                name,
+                func_type,
+                func_type.params().len(),
+                args.len()
+            ));
+        }
+
+        let args = func_type
+            .params()
+            .iter()
+            .zip(args)
+            .map(|(value_type, arg)| parse_argument(value_type, arg))
+            .collect::<Result<Vec<_>>>()?;
+        match self.instance.run_export(name, &args)? {
+            core::Completion::Returned(results) => {
+                let results: Vec<_> = results.iter().map(format_result).collect();
+                writeln!(out, "{}", results.join(" "))?;
+            }
+            core::Completion::Exited(code) => writeln!(out, "exited with code {}", code)?,
+        }
+        Ok(())
+    }
+}
+
+fn parse_usize(arg: &str) -> Result<usize> {
+    let (radix, digits) = radix_and_digits(arg);
+    usize::from_str_radix(digits, radix)
+        .map_err(|_| anyhow!("Cannot parse \"{}\" as an address or length", arg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wasm::builder::{Instr, RawModuleBuilder};
+    use wasm::core::{stack_entry::StackEntry, ValueType};
+    use wasm::parser::Opcode;
+
+    fn session(module: &core::Module, input: &str) -> Result<String> {
+        let instance = module.instantiate(core::EmptyResolver::instance())?;
+        let mut out = Vec::new();
+        Repl::new(module, instance).run(input.as_bytes(), &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn test_repl_keeps_state() -> Result<()> {
+        let i32_type = core::GlobalType::new(ValueType::I32, core::MutableType::Var);
+        let module = core::Module::new(
+            RawModuleBuilder::new()
+                .add_type(core::FuncType::new(
+                    vec![ValueType::I32],
+                    vec![ValueType::I32],
+                ))
+                .add_type(core::FuncType::new(
+                    vec![ValueType::I64, ValueType::F32],
+                    vec![ValueType::I64, ValueType::F32],
+                ))
+                // Adds its argument to the total, stores the total at 16 and returns it
+                .add_function(
+                    0,
+                    vec![],
+                    vec![
+                        Instr::GlobalGet(0),
+                        Instr::LocalGet(0),
+                        Instr::Op(Opcode::I32Add),
+                        Instr::GlobalSet(0),
+                        Instr::I32Const(16),
+                        Instr::GlobalGet(0),
+                        Instr::Memory(Opcode::I32Store, 2, 0),
+                        Instr::GlobalGet(0),
+                    ],
+                )
+                .add_function(1, vec![], vec![Instr::LocalGet(0), Instr::LocalGet(1)])
+                .add_function(0, vec![], vec![Instr::Op(Opcode::Unreachable)])
+                .add_memory(1, None)
+                .add_global(i32_type, vec![Instr::I32Const(0)])
+                .export_func("add", 0)
+                .export_func("pair", 1)
+                .export_func("crash", 2)
+                .export_global("total", 0)
+                .build(),
+        );
+
+        let output = session(
+            &module,
+            "add 0x10\n\
+             add -1\n\
+             crash 1\n\
+             add 2\n\
+             pair -1 1.5\n\
+             :globals\n\
+             :mem 16 4\n\
+             add\n\
+             add 1.5\n\
+             :nonsense\n\
+             :quit\n\
+             add 1\n",
+        )?;
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines[0], "add: func (i32) -> i32");
+        assert_eq!(lines[1], "pair: func (i64, f32) -> (i64, f32)");
+        assert_eq!(lines[3], "total: global (mut i32)");
+        assert_eq!(lines[4], "> 16");
+        assert_eq!(lines[5], "> 15");
+        assert!(lines[6].starts_with("> error: "), "{}", lines[6]);
+        assert_eq!(lines[7], "> 17");
+        assert_eq!(lines[8], "> -1 1.5");
+        assert_eq!(lines[9], "> global 0: (mut i32) = 17");
+        assert_eq!(
+            lines[10],
+            format!("> 00000010  {:<47}  |....|", "11 00 00 00")
+        );
+        assert_eq!(
+            lines[11],
+            "> error: add (i32) -> i32 takes 1 arguments, but 0 were given"
+        );
+        assert_eq!(lines[12], "> error: Cannot parse \"1.5\" as i32");
+        assert_eq!(
+            lines[13],
+            "> error: Unknown command :nonsense, :help lists them"
+        );
+        // Nothing after :quit is run
+        assert_eq!(lines[14], "> ");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_argument_parsing() -> Result<()> {
+        let parse = |value_type, arg| parse_argument(&value_type, arg);
+        assert_eq!(parse(ValueType::I32, "0x10")?, StackEntry::from(16_i32));
+        assert_eq!(parse(ValueType::I32, "-1")?, StackEntry::from(-1_i32));
+        assert_eq!(
+            parse(ValueType::I32, "0xffffffff")?,
+            StackEntry::from(-1_i32)
+        );
+        assert_eq!(
+            parse(ValueType::I32, "-0x80000000")?,
+            StackEntry::from(-2_147_483_648_i32)
+        );
+        assert!(parse(ValueType::I32, "0x100000000").is_err());
+        assert!(parse(ValueType::I32, "-2147483649").is_err());
+        assert_eq!(
+            parse(ValueType::I64, "18446744073709551615")?,
+            StackEntry::from(-1_i64)
+        );
+        assert_eq!(parse(ValueType::F32, "1.5")?, StackEntry::from(1.5_f32));
+        assert_eq!(parse(ValueType::F64, "-1")?, StackEntry::from(-1.0_f64));
+        assert!(parse(ValueType::F64, "0x10").is_err());
+        Ok(())
+    }
+}
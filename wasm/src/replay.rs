@@ -0,0 +1,10 @@
+// Recording what a module's host functions return, so that a run can be replayed exactly later,
+// such as in a test, without the host functions it called. Only the arguments and results are
+// recorded, so anything a host function does to memory isn't replayed.
+mod resolver;
+mod trace;
+
+pub use resolver::{RecordingResolver, ReplayResolver};
+#[cfg(feature = "differential")]
+pub(crate) use trace::value_text;
+pub use trace::{HostCall, HostCallOutcome, HostCallTrace};
@@ -0,0 +1,283 @@
+use anyhow::{anyhow, Result};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+use crate::core::{
+    exit_code, stack_entry::StackEntry, Callable, Caller, FuncType, Global, GlobalType,
+    HostCallable, MemType, Memory, Resolver, Table, TableType, Trap, TrapKind,
+};
+use crate::replay::{HostCall, HostCallOutcome, HostCallTrace};
+
+// Wraps another resolver, and records every call to a host function it resolves. Functions that
+// are exported from other modules are wasm, so they're passed through as they are.
+pub struct RecordingResolver<R: Resolver> {
+    inner: R,
+    trace: Rc<RefCell<HostCallTrace>>,
+}
+
+impl<R: Resolver> RecordingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            trace: Rc::new(RefCell::new(HostCallTrace::new())),
+        }
+    }
+
+    // The calls recorded so far, from every instance made with this resolver
+    pub fn trace(&self) -> HostCallTrace {
+        self.trace.borrow().clone()
+    }
+}
+
+struct RecordingFunction {
+    mod_name: String,
+    name: String,
+    inner: Rc<dyn HostCallable>,
+    trace: Rc<RefCell<HostCallTrace>>,
+}
+
+impl fmt::Debug for RecordingFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RecordingFunction({}::{})", self.mod_name, self.name)
+    }
+}
+
+impl HostCallable for RecordingFunction {
+    fn func_type(&self) -> &FuncType {
+        self.inner.func_type()
+    }
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        self.record(args, self.inner.call(args))
+    }
+
+    // Passed on, so a function that gets at its caller still can when it's being recorded
+    fn call_with_caller(
+        &self,
+        caller: &mut Caller<'_>,
+        args: &[StackEntry],
+    ) -> Result<Vec<StackEntry>> {
+        self.record(args, self.inner.call_with_caller(caller, args))
+    }
+}
+
+impl RecordingFunction {
+    fn record(
+        &self,
+        args: &[StackEntry],
+        result: Result<Vec<StackEntry>>,
+    ) -> Result<Vec<StackEntry>> {
+        let outcome = match &result {
+            Ok(results) => HostCallOutcome::Returned(results.clone()),
+            Err(error) => match exit_code(error) {
+                Some(code) => HostCallOutcome::Exited(code),
+                None => HostCallOutcome::Failed(format!("{}", error)),
+            },
+        };
+
+        self.trace.borrow_mut().calls.push(HostCall {
+            mod_name: self.mod_name.clone(),
+            name: self.name.clone(),
+            args: args.to_vec(),
+            outcome,
+        });
+        result
+    }
+}
+
+impl<R: Resolver> Resolver for RecordingResolver<R> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        let function = self.inner.resolve_function(mod_name, name, func_type)?;
+        let host = match &*function.borrow() {
+            Callable::Host(host) => host.clone(),
+            Callable::WasmExpr(_) => return Ok(function.clone()),
+        };
+
+        let recording = RecordingFunction {
+            mod_name: mod_name.to_string(),
+            name: name.to_string(),
+            inner: host,
+            trace: self.trace.clone(),
+        };
+        Ok(Rc::new(RefCell::new(Callable::from_host(Rc::new(
+            recording,
+        )))))
+    }
+
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        self.inner.resolve_table(mod_name, name, table_type)
+    }
+
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        self.inner.resolve_memory(mod_name, name, mem_type)
+    }
+
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        self.inner.resolve_global(mod_name, name, global_type)
+    }
+}
+
+// Answers function imports from a recording rather than calling anything. The calls have to come
+// in the same order with the same arguments as they were recorded, and any that don't are an
+// error that shows what was expected. Everything else is resolved by the inner resolver, which
+// can be an EmptyResolver if there isn't anything else.
+pub struct ReplayResolver<R: Resolver> {
+    inner: R,
+    replay: Rc<Replay>,
+}
+
+struct Replay {
+    trace: HostCallTrace,
+    next: Cell<usize>,
+}
+
+impl<R: Resolver> ReplayResolver<R> {
+    pub fn new(inner: R, trace: HostCallTrace) -> Self {
+        Self {
+            inner,
+            replay: Rc::new(Replay {
+                trace,
+                next: Cell::new(0),
+            }),
+        }
+    }
+
+    // How many of the recorded calls haven't been made yet
+    pub fn remaining(&self) -> usize {
+        self.replay.trace.calls.len() - self.replay.next.get()
+    }
+}
+
+struct ReplayFunction {
+    mod_name: String,
+    name: String,
+    func_type: FuncType,
+    replay: Rc<Replay>,
+}
+
+impl fmt::Debug for ReplayFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReplayFunction({}::{})", self.mod_name, self.name)
+    }
+}
+
+impl HostCallable for ReplayFunction {
+    fn func_type(&self) -> &FuncType {
+        &self.func_type
+    }
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        let idx = self.replay.next.get();
+        let actual = HostCall {
+            mod_name: self.mod_name.clone(),
+            name: self.name.clone(),
+            args: args.to_vec(),
+            outcome: HostCallOutcome::Returned(Vec::new()),
+        };
+
+        let expected = match self.replay.trace.calls.get(idx) {
+            Some(expected) => expected,
+            None => {
+                return Err(anyhow!(
+                    "Host call {} wasn't recorded\n  actual:   {}",
+                    idx,
+                    describe_call(&actual)
+                ))
+            }
+        };
+
+        let same_args = expected.args.len() == args.len()
+            && expected.args.iter().zip(args).all(|(a, b)| a.bitwise_eq(b));
+        if expected.mod_name != self.mod_name || expected.name != self.name || !same_args {
+            return Err(anyhow!(
+                "Host call {} diverged from the recording\n  expected: {}\n  actual:   {}",
+                idx,
+                describe_call(expected),
+                describe_call(&actual)
+            ));
+        }
+
+        self.replay.next.set(idx + 1);
+        match &expected.outcome {
+            HostCallOutcome::Returned(results) => Ok(results.clone()),
+            HostCallOutcome::Exited(code) => Err(Trap::new(TrapKind::Exit(*code)).into()),
+            HostCallOutcome::Failed(message) => Err(anyhow!("{}", message)),
+        }
+    }
+}
+
+// A call without what it returned, which is what the diff is about
+fn describe_call(call: &HostCall) -> String {
+    let call = format!("{}", call);
+    match call.rfind(" -> ") {
+        Some(idx) => call[..idx].to_string(),
+        None => call,
+    }
+}
+
+impl<R: Resolver> Resolver for ReplayResolver<R> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        let function = ReplayFunction {
+            mod_name: mod_name.to_string(),
+            name: name.to_string(),
+            func_type: func_type.clone(),
+            replay: self.replay.clone(),
+        };
+        Ok(Rc::new(RefCell::new(Callable::from_host(Rc::new(
+            function,
+        )))))
+    }
+
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        self.inner.resolve_table(mod_name, name, table_type)
+    }
+
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        self.inner.resolve_memory(mod_name, name, mem_type)
+    }
+
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        self.inner.resolve_global(mod_name, name, global_type)
+    }
+}
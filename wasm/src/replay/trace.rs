@@ -0,0 +1,314 @@
+use anyhow::{anyhow, Result};
+use std::fmt::{self, Write};
+
+use crate::core::stack_entry::StackEntry;
+
+// How a host call finished
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostCallOutcome {
+    Returned(Vec<StackEntry>),
+    // The host function exited, such as with WASI's proc_exit
+    Exited(u32),
+    // Any other error, of which only the message is kept
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostCall {
+    pub mod_name: String,
+    pub name: String,
+    pub args: Vec<StackEntry>,
+    pub outcome: HostCallOutcome,
+}
+
+impl fmt::Display for HostCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}::{}", self.mod_name, self.name)?;
+        write_values(f, &self.args)?;
+        match &self.outcome {
+            HostCallOutcome::Returned(results) => {
+                write!(f, " -> ")?;
+                write_values(f, results)
+            }
+            HostCallOutcome::Exited(code) => write!(f, " -> exit {}", code),
+            HostCallOutcome::Failed(message) => write!(f, " -> error {:?}", message),
+        }
+    }
+}
+
+fn write_values(f: &mut fmt::Formatter<'_>, values: &[StackEntry]) -> fmt::Result {
+    write!(f, "(")?;
+    for (idx, value) in values.iter().enumerate() {
+        if idx > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", value_text(value))?;
+    }
+    write!(f, ")")
+}
+
+// Every host call a run made, in order. It's saved as text, one call to a line, with floats
+// written as their bits so that NaNs come back exactly as they were:
+//
+//     call "env" "random" => i32:1234
+//     call "env" "log" i32:16 f64:0x400921fb54442d18 =>
+//     call "env" "exit" i32:3 => exit 3
+//     call "env" "open" i32:0 => error "No such file"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostCallTrace {
+    pub calls: Vec<HostCall>,
+}
+
+impl HostCallTrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for call in &self.calls {
+            let _ = write!(out, "call {:?} {:?}", call.mod_name, call.name);
+            for arg in &call.args {
+                let _ = write!(out, " {}", value_text(arg));
+            }
+            out.push_str(" =>");
+            match &call.outcome {
+                HostCallOutcome::Returned(results) => {
+                    for result in results {
+                        let _ = write!(out, " {}", value_text(result));
+                    }
+                }
+                HostCallOutcome::Exited(code) => {
+                    let _ = write!(out, " exit {}", code);
+                }
+                HostCallOutcome::Failed(message) => {
+                    let _ = write!(out, " error {:?}", message);
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn from_text(text: &str) -> Result<Self> {
+        let calls = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(idx, line)| {
+                parse_call(line).map_err(|e| anyhow!("Line {} of the trace: {}", idx + 1, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { calls })
+    }
+}
+
+// A value as it's written in a trace, with floats as their bits
+pub(crate) fn value_text(value: &StackEntry) -> String {
+    match value {
+        StackEntry::I32Entry(v) => format!("i32:{}", v),
+        StackEntry::I64Entry(v) => format!("i64:{}", v),
+        StackEntry::F32Entry(v) => format!("f32:{:#010x}", v.to_bits()),
+        StackEntry::F64Entry(v) => format!("f64:{:#018x}", v.to_bits()),
+    }
+}
+
+fn hex_digits(digits: &str) -> Result<&str> {
+    match digits.get(..2) {
+        Some("0x") => Ok(&digits[2..]),
+        _ => Err(anyhow!("Float bits {} aren't in hex", digits)),
+    }
+}
+
+fn parse_value(text: &str) -> Result<StackEntry> {
+    let value = match text.find(':').map(|idx| (&text[..idx], &text[idx + 1..])) {
+        Some(("i32", v)) => v.parse::<u32>().ok().map(StackEntry::I32Entry),
+        Some(("i64", v)) => v.parse::<u64>().ok().map(StackEntry::I64Entry),
+        Some(("f32", v)) => u32::from_str_radix(hex_digits(v)?, 16)
+            .ok()
+            .map(|bits| StackEntry::F32Entry(f32::from_bits(bits))),
+        Some(("f64", v)) => u64::from_str_radix(hex_digits(v)?, 16)
+            .ok()
+            .map(|bits| StackEntry::F64Entry(f64::from_bits(bits))),
+        _ => None,
+    };
+    value.ok_or_else(|| anyhow!("{} isn't a value", text))
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+}
+
+fn expect_quoted(tokens: &mut impl Iterator<Item = Token>, what: &str) -> Result<String> {
+    match tokens.next() {
+        Some(Token::Quoted(s)) => Ok(s),
+        _ => Err(anyhow!("Expected the {} name in quotes", what)),
+    }
+}
+
+fn parse_call(line: &str) -> Result<HostCall> {
+    let mut tokens = tokenize(line)?.into_iter();
+    match tokens.next() {
+        Some(Token::Word(ref word)) if word == "call" => {}
+        _ => return Err(anyhow!("Expected a call")),
+    }
+    let mod_name = expect_quoted(&mut tokens, "module")?;
+    let name = expect_quoted(&mut tokens, "function")?;
+
+    let mut args = Vec::new();
+    let mut results = Vec::new();
+    let mut outcome = None;
+    let mut after_arrow = false;
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) if word == "=>" && !after_arrow => after_arrow = true,
+            Token::Word(word) if word == "exit" && after_arrow && results.is_empty() => {
+                let code = match tokens.next() {
+                    Some(Token::Word(code)) => code.parse::<u32>().ok(),
+                    _ => None,
+                };
+                let code = code.ok_or_else(|| anyhow!("Expected an exit code"))?;
+                outcome = Some(HostCallOutcome::Exited(code));
+            }
+            Token::Word(word) if word == "error" && after_arrow && results.is_empty() => {
+                match tokens.next() {
+                    Some(Token::Quoted(message)) => {
+                        outcome = Some(HostCallOutcome::Failed(message))
+                    }
+                    _ => return Err(anyhow!("Expected an error message in quotes")),
+                }
+            }
+            Token::Word(word) if outcome.is_none() => {
+                let value = parse_value(&word)?;
+                if after_arrow {
+                    results.push(value);
+                } else {
+                    args.push(value);
+                }
+            }
+            _ => return Err(anyhow!("Unexpected {:?}", token)),
+        }
+    }
+
+    if !after_arrow {
+        return Err(anyhow!("Expected => before the results"));
+    }
+    Ok(HostCall {
+        mod_name,
+        name,
+        args,
+        outcome: outcome.unwrap_or(HostCallOutcome::Returned(results)),
+    })
+}
+
+// Splits on spaces, apart from in quoted strings, which are unescaped
+fn tokenize(line: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            tokens.push(Token::Quoted(unescape(&mut chars)?));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+    Ok(tokens)
+}
+
+// Reads up to the closing quote, undoing the escapes that {:?} puts in a string
+fn unescape(chars: &mut impl Iterator<Item = char>) -> Result<String> {
+    let mut out = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(out),
+            Some('\\') => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some('0') => out.push('\0'),
+                Some('u') => {
+                    let code: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    let c = Some(code.as_str())
+                        .filter(|code| code.starts_with('{'))
+                        .and_then(|code| u32::from_str_radix(&code[1..], 16).ok())
+                        .and_then(std::char::from_u32)
+                        .ok_or_else(|| anyhow!("Bad escape \\u{}}}", code))?;
+                    out.push(c);
+                }
+                Some(c) => out.push(c),
+                None => return Err(anyhow!("Unfinished escape")),
+            },
+            Some(c) => out.push(c),
+            None => return Err(anyhow!("Missing closing quote")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trace_text_round_trip() {
+        let trace = HostCallTrace {
+            calls: vec![
+                HostCall {
+                    mod_name: "env".to_string(),
+                    name: "random \"bytes\"\n".to_string(),
+                    args: vec![
+                        StackEntry::I32Entry(0xffff_ffff),
+                        StackEntry::F32Entry(f32::from_bits(0x7fc0_0001)),
+                        StackEntry::F64Entry(-0.0),
+                    ],
+                    outcome: HostCallOutcome::Returned(vec![StackEntry::I64Entry(7)]),
+                },
+                HostCall {
+                    mod_name: "wasi".to_string(),
+                    name: "proc_exit".to_string(),
+                    args: vec![StackEntry::I32Entry(3)],
+                    outcome: HostCallOutcome::Exited(3),
+                },
+                HostCall {
+                    mod_name: "env".to_string(),
+                    name: "fail".to_string(),
+                    args: vec![],
+                    outcome: HostCallOutcome::Failed("Broken \u{1f4a5} pipe".to_string()),
+                },
+            ],
+        };
+
+        let text = trace.to_text();
+        assert_eq!(
+            text.lines().next().unwrap(),
+            "call \"env\" \"random \\\"bytes\\\"\\n\" i32:4294967295 f32:0x7fc00001 \
+             f64:0x8000000000000000 => i64:7"
+        );
+
+        let read = HostCallTrace::from_text(&text).unwrap();
+        assert_eq!(read.calls.len(), 3);
+        assert_eq!(read.calls[0].name, trace.calls[0].name);
+        assert!(read.calls[0]
+            .args
+            .iter()
+            .zip(&trace.calls[0].args)
+            .all(|(a, b)| a.bitwise_eq(b)));
+        assert_eq!(read.calls[1..], trace.calls[1..]);
+
+        assert!(HostCallTrace::from_text("call \"env\" \"f\" i32:1").is_err());
+        assert!(HostCallTrace::from_text("call \"env\" \"f\" => f32:1.5").is_err());
+    }
+}
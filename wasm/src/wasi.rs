@@ -0,0 +1,14 @@
+mod clock;
+mod command;
+mod ctx;
+mod errno;
+mod functions;
+mod pipe;
+mod resolver;
+
+pub use clock::{HostClock, ManualClock, WasiClock};
+pub use command::{instantiate, module_kind, run_command, WasiModuleKind};
+pub use ctx::{Descriptor, HostFile, WasiCtx};
+pub use errno::Errno;
+pub use pipe::{InMemoryPipe, OutputStream};
+pub use resolver::{WasiResolver, WASI_MODULE};
@@ -0,0 +1,86 @@
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Where the WASI functions get the time from, and how they wait. All of the times are in
+// nanoseconds.
+pub trait WasiClock: fmt::Debug {
+    // The time since the Unix epoch
+    fn realtime(&self) -> u64;
+
+    // A time that only ever goes forwards, from some arbitrary starting point
+    fn monotonic(&self) -> u64;
+
+    fn sleep(&self, nanos: u64);
+}
+
+// The host's clocks, where sleeping really does wait
+#[derive(Debug)]
+pub struct HostClock {
+    start: Instant,
+}
+
+impl Default for HostClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl WasiClock for HostClock {
+    fn realtime(&self) -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_nanos() as u64,
+            Err(_) => 0,
+        }
+    }
+
+    fn monotonic(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    fn sleep(&self, nanos: u64) {
+        thread::sleep(Duration::from_nanos(nanos));
+    }
+}
+
+// A deterministic clock that only moves when it's told to or when the guest sleeps, which then
+// returns straight away. Clones share the same time, so one can be kept to look at or move the
+// time while another is installed in the context.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<u64>>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Rc::new(Cell::new(now)),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now.get()
+    }
+
+    pub fn advance(&self, nanos: u64) {
+        self.now.set(self.now.get().saturating_add(nanos));
+    }
+}
+
+impl WasiClock for ManualClock {
+    fn realtime(&self) -> u64 {
+        self.now()
+    }
+
+    fn monotonic(&self) -> u64 {
+        self.now()
+    }
+
+    fn sleep(&self, nanos: u64) {
+        self.advance(nanos);
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::{anyhow, Context, Result};
+use std::rc::Rc;
+
+use crate::core::{Completion, Extern, Instance, Module};
+use crate::wasi::{WasiCtx, WasiResolver};
+
+// How a WASI module expects to be run, going by what it exports. A command exports _start, which
+// is the whole program. A reactor exports _initialize, which has to be called once before any of
+// its other exports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WasiModuleKind {
+    Command,
+    Reactor,
+    // Neither, such as a module that's only a library of functions
+    Other,
+}
+
+// Exporting both _start and _initialize is an error, since it isn't clear which applies
+pub fn module_kind(instance: &Instance) -> Result<WasiModuleKind> {
+    let exports_function = |name| {
+        instance
+            .exports
+            .get(name)
+            .and_then(Extern::as_function)
+            .is_some()
+    };
+
+    match (exports_function("_start"), exports_function("_initialize")) {
+        (true, true) => Err(anyhow!(
+            "The module exports both _start and _initialize, so it's both a command and a reactor"
+        )),
+        (true, false) => Ok(WasiModuleKind::Command),
+        (false, true) => Ok(WasiModuleKind::Reactor),
+        (false, false) => Ok(WasiModuleKind::Other),
+    }
+}
+
+// Instantiates a module with the WASI functions from the context, and attaches its memory if it
+// exports one. A reactor is initialized before it's handed back, so any of its exports can be
+// called straight away.
+pub fn instantiate(module: &Module, ctx: Rc<WasiCtx>) -> Result<Instance> {
+    let mut instance = module.instantiate(&WasiResolver::new(ctx.clone()))?;
+    if let Some(memory) = instance.exports.get("memory").and_then(|e| e.as_memory()) {
+        ctx.set_memory(memory.clone());
+    }
+
+    if module_kind(&instance)? == WasiModuleKind::Reactor {
+        instance
+            .invoke_export("_initialize", &[])
+            .context("The reactor's _initialize failed")?;
+    }
+    Ok(instance)
+}
+
+// Runs a command's _start, and returns its exit code. Returning from _start is the same as
+// exiting with 0.
+pub fn run_command(instance: &mut Instance) -> Result<u32> {
+    match module_kind(instance)? {
+        WasiModuleKind::Command => {}
+        WasiModuleKind::Reactor => {
+            return Err(anyhow!(
+                "The module is a reactor, so it doesn't have a _start to run"
+            ))
+        }
+        WasiModuleKind::Other => return Err(anyhow!("The module doesn't export _start")),
+    }
+
+    match instance.run_export("_start", &[])? {
+        Completion::Returned(_) => Ok(0),
+        Completion::Exited(code) => Ok(code),
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+use crate::core::{Instance, Memory};
+use crate::wasi::errno::{Errno, WasiResult};
+use crate::wasi::{HostClock, InMemoryPipe, OutputStream, WasiClock};
+
+// A file on the host that the guest can have a descriptor for. An in-memory io::Cursor works as
+// well as a real file.
+pub trait HostFile: Read + Write + Seek + fmt::Debug {}
+
+impl<T: Read + Write + Seek + fmt::Debug> HostFile for T {}
+
+// What a file descriptor refers to
+#[derive(Debug, Clone)]
+pub enum Descriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(Rc<RefCell<dyn HostFile>>),
+}
+
+// The state the WASI functions share: the guest's file descriptors, where its stdio goes to and
+// comes from, the clock, and the memory it passes buffers in. The memory is only known once the module has
+// been instantiated, so it has to be attached before any of the functions are called.
+pub struct WasiCtx {
+    stdin: RefCell<Box<dyn Read>>,
+    stdout: RefCell<Box<dyn Write>>,
+    stderr: RefCell<Box<dyn Write>>,
+    capture: Option<Capture>,
+    tee: bool,
+    fds: RefCell<BTreeMap<u32, Descriptor>>,
+    clock: Box<dyn WasiClock>,
+    memory: RefCell<Option<Rc<RefCell<Memory>>>>,
+}
+
+// The pipes stdout and stderr are captured into, and the order the writes to them happened in
+struct Capture {
+    stdout: InMemoryPipe,
+    stderr: InMemoryPipe,
+    log: RefCell<Vec<(OutputStream, Vec<u8>)>>,
+}
+
+impl Default for WasiCtx {
+    // The host's own stdio and clocks
+    fn default() -> Self {
+        let mut fds = BTreeMap::new();
+        fds.insert(0, Descriptor::Stdin);
+        fds.insert(1, Descriptor::Stdout);
+        fds.insert(2, Descriptor::Stderr);
+
+        Self {
+            stdin: RefCell::new(Box::new(io::stdin())),
+            stdout: RefCell::new(Box::new(io::stdout())),
+            stderr: RefCell::new(Box::new(io::stderr())),
+            capture: None,
+            tee: false,
+            fds: RefCell::new(fds),
+            clock: Box::new(HostClock::default()),
+            memory: RefCell::new(None),
+        }
+    }
+}
+
+impl fmt::Debug for WasiCtx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasiCtx")
+            .field("capturing", &self.capture.is_some())
+            .field("tee", &self.tee)
+            .field("fds", &self.fds.borrow())
+            .field("memory_attached", &self.memory.borrow().is_some())
+            .finish()
+    }
+}
+
+impl WasiCtx {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Where fd 0 reads from
+    pub fn stdin<R: Read + 'static>(self, stdin: R) -> Self {
+        self.stdin.replace(Box::new(stdin));
+        self
+    }
+
+    // Where fd 1 writes to
+    pub fn stdout<W: Write + 'static>(self, stdout: W) -> Self {
+        self.stdout.replace(Box::new(stdout));
+        self
+    }
+
+    // Where fd 2 writes to
+    pub fn stderr<W: Write + 'static>(self, stderr: W) -> Self {
+        self.stderr.replace(Box::new(stderr));
+        self
+    }
+
+    // Captures what the guest writes to fd 1 and fd 2 in memory, for take_stdout, take_stderr and
+    // take_output_log. This replaces any stdout and stderr set before.
+    pub fn capture_stdio(mut self) -> Self {
+        let capture = Capture {
+            stdout: InMemoryPipe::new(),
+            stderr: InMemoryPipe::new(),
+            log: RefCell::new(Vec::new()),
+        };
+        self.stdout.replace(Box::new(capture.stdout.clone()));
+        self.stderr.replace(Box::new(capture.stderr.clone()));
+        self.capture = Some(capture);
+        self
+    }
+
+    // Also writes the guest's stdout and stderr to the host's own, on top of wherever they go to
+    pub fn tee_to_host(mut self, tee: bool) -> Self {
+        self.tee = tee;
+        self
+    }
+
+    // What the guest wrote to stdout since the last take. Empty unless stdio is captured.
+    pub fn take_stdout(&self) -> Vec<u8> {
+        self.capture
+            .as_ref()
+            .map_or_else(Vec::new, |capture| capture.stdout.take())
+    }
+
+    // What the guest wrote to stderr since the last take. Empty unless stdio is captured.
+    pub fn take_stderr(&self) -> Vec<u8> {
+        self.capture
+            .as_ref()
+            .map_or_else(Vec::new, |capture| capture.stderr.take())
+    }
+
+    // Both streams since the last take, in the order they were written. Consecutive writes to the
+    // same stream are joined together. This is kept apart from take_stdout and take_stderr, so
+    // taking one doesn't empty the other.
+    pub fn take_output_log(&self) -> Vec<(OutputStream, Vec<u8>)> {
+        self.capture
+            .as_ref()
+            .map_or_else(Vec::new, |capture| capture.log.replace(Vec::new()))
+    }
+
+    // Replaces the host's clocks, such as with a ManualClock to make sleeps and timers
+    // deterministic
+    pub fn clock<C: WasiClock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    // The time on one of the WASI clocks, which are realtime, monotonic, and the process and thread
+    // CPU time clocks. There is no CPU time as such, so those are monotonic as well.
+    pub(crate) fn now(&self, clock_id: u32) -> WasiResult<u64> {
+        match clock_id {
+            0 => Ok(self.clock.realtime()),
+            1..=3 => Ok(self.clock.monotonic()),
+            _ => Err(Errno::Inval),
+        }
+    }
+
+    pub(crate) fn sleep(&self, nanos: u64) {
+        self.clock.sleep(nanos);
+    }
+
+    pub fn set_memory(&self, memory: Rc<RefCell<Memory>>) {
+        self.memory.replace(Some(memory));
+    }
+
+    // Uses the memory an instance exports, which is where WASI modules expect it to be
+    pub fn attach(&self, instance: &Instance) -> Result<()> {
+        let memory = instance
+            .exports
+            .get("memory")
+            .and_then(|export| export.as_memory())
+            .ok_or_else(|| anyhow!("WASI modules have to export their memory as \"memory\""))?;
+        self.set_memory(memory.clone());
+        Ok(())
+    }
+
+    pub(crate) fn memory(&self) -> Result<Rc<RefCell<Memory>>> {
+        self.memory
+            .borrow()
+            .clone()
+            .ok_or_else(|| anyhow!("A WASI function was called before any memory was attached"))
+    }
+
+    pub fn descriptor(&self, fd: u32) -> Option<Descriptor> {
+        self.fds.borrow().get(&fd).cloned()
+    }
+
+    // Gives the guest a descriptor for a file, as if it had been opened before the module started.
+    // Returns the new descriptor, which is the lowest one that isn't in use.
+    pub fn preopen_file<F: HostFile + 'static>(&self, file: F) -> u32 {
+        let mut fds = self.fds.borrow_mut();
+        let fd = (0..).find(|fd| !fds.contains_key(fd)).unwrap();
+        fds.insert(fd, Descriptor::File(Rc::new(RefCell::new(file))));
+        fd
+    }
+
+    pub(crate) fn close(&self, fd: u32) -> WasiResult<()> {
+        match self.fds.borrow_mut().remove(&fd) {
+            Some(_) => Ok(()),
+            None => Err(Errno::Badf),
+        }
+    }
+
+    // Only files can be seeked in, stdio is a pipe
+    pub(crate) fn seek(&self, fd: u32, pos: SeekFrom) -> WasiResult<u64> {
+        match self.descriptor(fd).ok_or(Errno::Badf)? {
+            Descriptor::File(file) => Ok(file.borrow_mut().seek(pos)?),
+            _ => Err(Errno::Spipe),
+        }
+    }
+
+    // The size of a file, without moving its position
+    pub(crate) fn size(&self, fd: u32) -> WasiResult<u64> {
+        match self.descriptor(fd).ok_or(Errno::Badf)? {
+            Descriptor::File(file) => {
+                let mut file = file.borrow_mut();
+                let position = file.seek(SeekFrom::Current(0))?;
+                let size = file.seek(SeekFrom::End(0))?;
+                file.seek(SeekFrom::Start(position))?;
+                Ok(size)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    // Reads as much as there is up to the size of the buffer, which is less than that only at
+    // the end of the input or when the source has no more to give right now
+    pub(crate) fn read(&self, fd: u32, buf: &mut [u8]) -> WasiResult<usize> {
+        let descriptor = self.descriptor(fd).ok_or(Errno::Badf)?;
+        loop {
+            let result = match &descriptor {
+                Descriptor::Stdin => self.stdin.borrow_mut().read(buf),
+                Descriptor::File(file) => file.borrow_mut().read(buf),
+                Descriptor::Stdout | Descriptor::Stderr => return Err(Errno::Badf),
+            };
+            match result {
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    pub(crate) fn write(&self, fd: u32, buf: &[u8]) -> WasiResult<()> {
+        match self.descriptor(fd).ok_or(Errno::Badf)? {
+            Descriptor::Stdout => self.write_stdio(OutputStream::Stdout, buf),
+            Descriptor::Stderr => self.write_stdio(OutputStream::Stderr, buf),
+            Descriptor::File(file) => Ok(file.borrow_mut().write_all(buf)?),
+            Descriptor::Stdin => Err(Errno::Badf),
+        }
+    }
+}
+
+impl WasiCtx {
+    fn write_stdio(&self, stream: OutputStream, buf: &[u8]) -> WasiResult<()> {
+        match stream {
+            OutputStream::Stdout => write_and_flush(&mut *self.stdout.borrow_mut(), buf)?,
+            OutputStream::Stderr => write_and_flush(&mut *self.stderr.borrow_mut(), buf)?,
+        }
+
+        if let Some(capture) = &self.capture {
+            let mut log = capture.log.borrow_mut();
+            match log.last_mut() {
+                Some((last, bytes)) if *last == stream => bytes.extend_from_slice(buf),
+                _ => log.push((stream, buf.to_vec())),
+            }
+        }
+
+        if self.tee {
+            match stream {
+                OutputStream::Stdout => write_and_flush(&mut io::stdout(), buf)?,
+                OutputStream::Stderr => write_and_flush(&mut io::stderr(), buf)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_and_flush(sink: &mut dyn Write, buf: &[u8]) -> WasiResult<()> {
+    sink.write_all(buf)?;
+    sink.flush()?;
+    Ok(())
+}
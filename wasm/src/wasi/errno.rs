@@ -0,0 +1,122 @@
+use std::io;
+
+use crate::core::stack_entry::StackEntry;
+
+// The error numbers the WASI functions return, with the values from wasi_snapshot_preview1. Only
+// the ones that are actually returned are here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Errno {
+    Success = 0,
+    Acces = 2,
+    Addrinuse = 3,
+    Addrnotavail = 4,
+    Again = 6,
+    Badf = 8,
+    Connaborted = 13,
+    Connrefused = 14,
+    Connreset = 15,
+    Exist = 20,
+    Fault = 21,
+    Fbig = 22,
+    Ilseq = 25,
+    Intr = 27,
+    Inval = 28,
+    Io = 29,
+    Isdir = 31,
+    Loop = 32,
+    Mfile = 33,
+    Nametoolong = 37,
+    Noent = 44,
+    Nospc = 51,
+    Notconn = 53,
+    Notdir = 54,
+    Notempty = 55,
+    Perm = 63,
+    Pipe = 64,
+    Rofs = 69,
+    Spipe = 70,
+    Timedout = 73,
+    Xdev = 75,
+}
+
+// The host's own error numbers say more than the error kinds do, but they're only the same as
+// these ones on Linux
+#[cfg(target_os = "linux")]
+fn from_raw_os_error(code: i32) -> Option<Errno> {
+    let errno = match code {
+        1 => Errno::Perm,
+        9 => Errno::Badf,
+        18 => Errno::Xdev,
+        20 => Errno::Notdir,
+        21 => Errno::Isdir,
+        24 => Errno::Mfile,
+        27 => Errno::Fbig,
+        28 => Errno::Nospc,
+        29 => Errno::Spipe,
+        30 => Errno::Rofs,
+        36 => Errno::Nametoolong,
+        39 => Errno::Notempty,
+        40 => Errno::Loop,
+        _ => return None,
+    };
+    Some(errno)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn from_raw_os_error(_code: i32) -> Option<Errno> {
+    None
+}
+
+impl From<io::Error> for Errno {
+    fn from(err: io::Error) -> Self {
+        if let Some(errno) = err.raw_os_error().and_then(from_raw_os_error) {
+            return errno;
+        }
+
+        match err.kind() {
+            io::ErrorKind::NotFound => Errno::Noent,
+            io::ErrorKind::PermissionDenied => Errno::Acces,
+            io::ErrorKind::ConnectionRefused => Errno::Connrefused,
+            io::ErrorKind::ConnectionReset => Errno::Connreset,
+            io::ErrorKind::ConnectionAborted => Errno::Connaborted,
+            io::ErrorKind::NotConnected => Errno::Notconn,
+            io::ErrorKind::AddrInUse => Errno::Addrinuse,
+            io::ErrorKind::AddrNotAvailable => Errno::Addrnotavail,
+            io::ErrorKind::BrokenPipe => Errno::Pipe,
+            io::ErrorKind::AlreadyExists => Errno::Exist,
+            io::ErrorKind::WouldBlock => Errno::Again,
+            io::ErrorKind::InvalidInput => Errno::Inval,
+            io::ErrorKind::InvalidData => Errno::Ilseq,
+            io::ErrorKind::TimedOut => Errno::Timedout,
+            io::ErrorKind::Interrupted => Errno::Intr,
+            _ => Errno::Io,
+        }
+    }
+}
+
+impl From<Errno> for StackEntry {
+    fn from(errno: Errno) -> StackEntry {
+        StackEntry::from(errno as u32)
+    }
+}
+
+// What the functions use internally, so that ? can be used on anything that fails with an errno
+pub type WasiResult<T> = std::result::Result<T, Errno>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_errno_from_io_error() {
+        let errno = |kind| Errno::from(io::Error::from(kind));
+        assert_eq!(errno(io::ErrorKind::NotFound), Errno::Noent);
+        assert_eq!(errno(io::ErrorKind::PermissionDenied), Errno::Acces);
+        assert_eq!(errno(io::ErrorKind::InvalidInput), Errno::Inval);
+        assert_eq!(errno(io::ErrorKind::UnexpectedEof), Errno::Io);
+
+        #[cfg(target_os = "linux")]
+        assert_eq!(Errno::from(io::Error::from_raw_os_error(29)), Errno::Spipe);
+    }
+}
@@ -0,0 +1,406 @@
+use anyhow::Result;
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+
+use crate::core::{stack_entry::StackEntry, Memory, Trap, TrapKind};
+use crate::wasi::ctx::{Descriptor, WasiCtx};
+use crate::wasi::errno::{Errno, WasiResult};
+
+// The file types and rights that fdstat and filestat report
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+const RIGHT_FD_READ: u64 = 1 << 1;
+const RIGHT_FD_SEEK: u64 = 1 << 2;
+const RIGHT_FD_TELL: u64 = 1 << 5;
+const RIGHT_FD_WRITE: u64 = 1 << 6;
+const RIGHT_FD_FILESTAT_GET: u64 = 1 << 21;
+const RIGHT_POLL_FD_READWRITE: u64 = 1 << 27;
+
+// The kinds of subscription and event for poll_oneoff, and the flag for a clock subscription's
+// timeout being an absolute time
+const EVENTTYPE_CLOCK: u8 = 0;
+const EVENTTYPE_FD_READ: u8 = 1;
+const EVENTTYPE_FD_WRITE: u8 = 2;
+const SUBCLOCKFLAGS_ABSTIME: u16 = 1;
+const SUBSCRIPTION_SIZE: u32 = 48;
+const EVENT_SIZE: u32 = 32;
+
+// fd_seek's whence
+const WHENCE_SET: u32 = 0;
+const WHENCE_CUR: u32 = 1;
+const WHENCE_END: u32 = 2;
+
+fn arg_u32(args: &[StackEntry], idx: usize) -> Result<u32> {
+    u32::try_from(args[idx])
+}
+
+fn arg_i64(args: &[StackEntry], idx: usize) -> Result<i64> {
+    i64::try_from(args[idx])
+}
+
+fn errno(result: WasiResult<()>) -> Errno {
+    match result {
+        Ok(()) => Errno::Success,
+        Err(errno) => errno,
+    }
+}
+
+// The bounds of a buffer the guest passed in, which has to be entirely inside its memory
+fn guest_range(memory: &Memory, ptr: u32, len: u32) -> WasiResult<std::ops::Range<usize>> {
+    let start = ptr as usize;
+    match start.checked_add(len as usize) {
+        Some(end) if end <= memory.len() => Ok(start..end),
+        _ => Err(Errno::Fault),
+    }
+}
+
+fn read_u32(memory: &Memory, ptr: u32) -> WasiResult<u32> {
+    memory.read_u32(ptr as usize).map_err(|_| Errno::Fault)
+}
+
+fn read_bytes(memory: &Memory, ptr: u32, bytes: &mut [u8]) -> WasiResult<()> {
+    memory
+        .get_data(ptr as usize, bytes)
+        .map_err(|_| Errno::Fault)
+}
+
+fn write_u32(memory: &mut Memory, ptr: u32, value: u32) -> WasiResult<()> {
+    memory
+        .write_u32(ptr as usize, value)
+        .map_err(|_| Errno::Fault)
+}
+
+fn write_u64(memory: &mut Memory, ptr: u32, value: u64) -> WasiResult<()> {
+    memory
+        .write_u64(ptr as usize, value)
+        .map_err(|_| Errno::Fault)
+}
+
+fn write_bytes(memory: &mut Memory, ptr: u32, bytes: &[u8]) -> WasiResult<()> {
+    let range = guest_range(memory, ptr, bytes.len() as u32)?;
+    memory.data_mut()[range].copy_from_slice(bytes);
+    Ok(())
+}
+
+fn file_type(descriptor: &Descriptor) -> u8 {
+    match descriptor {
+        Descriptor::File(_) => FILETYPE_REGULAR_FILE,
+        _ => FILETYPE_CHARACTER_DEVICE,
+    }
+}
+
+// An array of iovecs, which are a pointer and a length each
+fn read_iovecs(
+    memory: &Memory,
+    iovs: u32,
+    iovs_len: u32,
+) -> WasiResult<Vec<std::ops::Range<usize>>> {
+    (0..iovs_len)
+        .map(|idx| {
+            let iov = iovs.checked_add(idx * 8).ok_or(Errno::Fault)?;
+            let buf = read_u32(memory, iov)?;
+            let buf_len = read_u32(memory, iov + 4)?;
+            guest_range(memory, buf, buf_len)
+        })
+        .collect()
+}
+
+// fd_read(fd, iovs, iovs_len, nread) -> errno
+pub fn fd_read(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let fd = arg_u32(args, 0)?;
+    let (iovs, iovs_len, nread) = (arg_u32(args, 1)?, arg_u32(args, 2)?, arg_u32(args, 3)?);
+    let memory = ctx.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    Ok(errno(
+        read_to_iovecs(ctx, &mut memory, fd, iovs, iovs_len)
+            .and_then(|total| write_u32(&mut memory, nread, total as u32)),
+    ))
+}
+
+// Fills the buffers in order, and stops at the first one that isn't filled, like readv. Reading
+// nothing at all means the end of the input.
+fn read_to_iovecs(
+    ctx: &WasiCtx,
+    memory: &mut Memory,
+    fd: u32,
+    iovs: u32,
+    iovs_len: u32,
+) -> WasiResult<usize> {
+    let mut total = 0;
+    for range in read_iovecs(memory, iovs, iovs_len)? {
+        let wanted = range.len();
+        let got = ctx.read(fd, &mut memory.data_mut()[range])?;
+        total += got;
+        if got < wanted {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+// fd_write(fd, iovs, iovs_len, nwritten) -> errno
+pub fn fd_write(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let fd = arg_u32(args, 0)?;
+    let (iovs, iovs_len, nwritten) = (arg_u32(args, 1)?, arg_u32(args, 2)?, arg_u32(args, 3)?);
+    let memory = ctx.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    Ok(errno(
+        write_from_iovecs(ctx, &memory, fd, iovs, iovs_len)
+            .and_then(|total| write_u32(&mut memory, nwritten, total as u32)),
+    ))
+}
+
+fn write_from_iovecs(
+    ctx: &WasiCtx,
+    memory: &Memory,
+    fd: u32,
+    iovs: u32,
+    iovs_len: u32,
+) -> WasiResult<usize> {
+    let mut total = 0;
+    for range in read_iovecs(memory, iovs, iovs_len)? {
+        total += range.len();
+        ctx.write(fd, &memory.data()[range])?;
+    }
+    Ok(total)
+}
+
+// fd_fdstat_get(fd, fdstat) -> errno
+pub fn fd_fdstat_get(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let fd = arg_u32(args, 0)?;
+    let fdstat = arg_u32(args, 1)?;
+    let memory = ctx.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    Ok(errno(write_fdstat(ctx, &mut memory, fd, fdstat)))
+}
+
+// The fdstat is the file type as a u8, the flags as a u16 at offset 2, and then the base and
+// inheriting rights as u64s at offsets 8 and 16
+fn write_fdstat(ctx: &WasiCtx, memory: &mut Memory, fd: u32, fdstat: u32) -> WasiResult<()> {
+    let descriptor = ctx.descriptor(fd).ok_or(Errno::Badf)?;
+    let rights = match descriptor {
+        Descriptor::Stdin => RIGHT_FD_READ | RIGHT_POLL_FD_READWRITE,
+        Descriptor::Stdout | Descriptor::Stderr => RIGHT_FD_WRITE | RIGHT_POLL_FD_READWRITE,
+        Descriptor::File(_) => {
+            RIGHT_FD_READ | RIGHT_FD_SEEK | RIGHT_FD_TELL | RIGHT_FD_WRITE | RIGHT_FD_FILESTAT_GET
+        }
+    };
+
+    let mut bytes = [0u8; 24];
+    bytes[0] = file_type(&descriptor);
+    bytes[8..16].copy_from_slice(&rights.to_le_bytes());
+    write_bytes(memory, fdstat, &bytes)
+}
+
+// fd_filestat_get(fd, filestat) -> errno
+pub fn fd_filestat_get(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let fd = arg_u32(args, 0)?;
+    let filestat = arg_u32(args, 1)?;
+    let memory = ctx.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    Ok(errno(write_filestat(ctx, &mut memory, fd, filestat)))
+}
+
+// The filestat is the device, inode, file type, link count, size and then the access,
+// modification and status change times. They're all u64s apart from the file type, which is a u8.
+// Host files don't have devices, inodes or times, so those are all zero.
+fn write_filestat(ctx: &WasiCtx, memory: &mut Memory, fd: u32, filestat: u32) -> WasiResult<()> {
+    let descriptor = ctx.descriptor(fd).ok_or(Errno::Badf)?;
+    let size = ctx.size(fd)?;
+
+    let mut bytes = [0u8; 64];
+    bytes[16] = file_type(&descriptor);
+    bytes[24..32].copy_from_slice(&1u64.to_le_bytes());
+    bytes[32..40].copy_from_slice(&size.to_le_bytes());
+    write_bytes(memory, filestat, &bytes)
+}
+
+// fd_seek(fd, offset: i64, whence, newoffset) -> errno
+pub fn fd_seek(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let fd = arg_u32(args, 0)?;
+    let offset = arg_i64(args, 1)?;
+    let (whence, newoffset) = (arg_u32(args, 2)?, arg_u32(args, 3)?);
+    let memory = ctx.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    let pos = match whence {
+        WHENCE_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        WHENCE_CUR => SeekFrom::Current(offset),
+        WHENCE_END => SeekFrom::End(offset),
+        _ => return Ok(Errno::Inval),
+    };
+    Ok(errno(ctx.seek(fd, pos).and_then(|position| {
+        write_u64(&mut memory, newoffset, position)
+    })))
+}
+
+// fd_tell(fd, offset) -> errno
+pub fn fd_tell(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let fd = arg_u32(args, 0)?;
+    let offset = arg_u32(args, 1)?;
+    let memory = ctx.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    Ok(errno(ctx.seek(fd, SeekFrom::Current(0)).and_then(
+        |position| write_u64(&mut memory, offset, position),
+    )))
+}
+
+// fd_close(fd) -> errno
+pub fn fd_close(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let fd = arg_u32(args, 0)?;
+    Ok(errno(ctx.close(fd)))
+}
+
+// clock_time_get(id, precision: i64, time) -> errno
+pub fn clock_time_get(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let clock_id = arg_u32(args, 0)?;
+    let time = arg_u32(args, 2)?;
+    let memory = ctx.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    Ok(errno(
+        ctx.now(clock_id)
+            .and_then(|now| write_u64(&mut memory, time, now)),
+    ))
+}
+
+// proc_exit(code)
+//
+// Unwinds all the way out of the guest, which is then finished with. The invoke functions turn
+// it into the exit code.
+pub fn proc_exit(_ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let code = arg_u32(args, 0)?;
+    Err(Trap::new(TrapKind::Exit(code)).into())
+}
+
+// sched_yield() -> errno
+//
+// There is only ever the one thread, so there is nothing to yield to
+pub fn sched_yield(_ctx: &WasiCtx, _args: &[StackEntry]) -> Result<Errno> {
+    Ok(Errno::Success)
+}
+
+// What happened to one of the subscriptions
+struct Event {
+    userdata: u64,
+    error: Errno,
+    event_type: u8,
+}
+
+// poll_oneoff(subscriptions, events, nsubscriptions, nevents) -> errno
+pub fn poll_oneoff(ctx: &WasiCtx, args: &[StackEntry]) -> Result<Errno> {
+    let (subscriptions, events) = (arg_u32(args, 0)?, arg_u32(args, 1)?);
+    let (nsubscriptions, nevents) = (arg_u32(args, 2)?, arg_u32(args, 3)?);
+    let memory = ctx.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    Ok(errno(
+        poll(ctx, &memory, subscriptions, nsubscriptions)
+            .and_then(|ready| write_events(&mut memory, events, &ready))
+            .and_then(|count| write_u32(&mut memory, nevents, count)),
+    ))
+}
+
+// Waits for at least one of the subscriptions. The stdio and file descriptors are always ready,
+// so the only thing that is ever waited for is the first of the clocks to go off.
+//
+// A subscription is the userdata as a u64, the type as a u8 at offset 8, and then from offset 16
+// either the descriptor as a u32, or a clock id as a u32, the timeout as a u64 at 24, the
+// precision as a u64 at 32 and the flags as a u16 at 40.
+fn poll(ctx: &WasiCtx, memory: &Memory, subscriptions: u32, count: u32) -> WasiResult<Vec<Event>> {
+    if count == 0 {
+        return Err(Errno::Inval);
+    }
+
+    let mut ready = Vec::new();
+    // How long until each of the clocks goes off, and its userdata
+    let mut timers = Vec::new();
+    for idx in 0..count {
+        let offset = idx
+            .checked_mul(SUBSCRIPTION_SIZE)
+            .and_then(|offset| subscriptions.checked_add(offset))
+            .ok_or(Errno::Fault)?;
+        let mut bytes = [0u8; SUBSCRIPTION_SIZE as usize];
+        read_bytes(memory, offset, &mut bytes)?;
+
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let u64_at = |at: usize| u64::from(u32_at(at)) | u64::from(u32_at(at + 4)) << 32;
+        let userdata = u64_at(0);
+
+        match bytes[8] {
+            EVENTTYPE_CLOCK => {
+                let (clock_id, timeout) = (u32_at(16), u64_at(24));
+                let flags = u16::from_le_bytes([bytes[40], bytes[41]]);
+                match ctx.now(clock_id) {
+                    Ok(now) if flags & SUBCLOCKFLAGS_ABSTIME != 0 => {
+                        timers.push((timeout.saturating_sub(now), userdata))
+                    }
+                    Ok(_) => timers.push((timeout, userdata)),
+                    Err(error) => ready.push(Event {
+                        userdata,
+                        error,
+                        event_type: EVENTTYPE_CLOCK,
+                    }),
+                }
+            }
+            event_type @ EVENTTYPE_FD_READ | event_type @ EVENTTYPE_FD_WRITE => {
+                let error = match ctx.descriptor(u32_at(16)) {
+                    Some(_) => Errno::Success,
+                    None => Errno::Badf,
+                };
+                ready.push(Event {
+                    userdata,
+                    error,
+                    event_type,
+                });
+            }
+            _ => return Err(Errno::Inval),
+        }
+    }
+
+    // If nothing else is ready then it's a sleep until the first clock
+    let waited = match timers.iter().map(|(delay, _)| *delay).min() {
+        Some(delay) if ready.is_empty() => {
+            ctx.sleep(delay);
+            delay
+        }
+        _ => 0,
+    };
+    for (delay, userdata) in timers {
+        if delay <= waited {
+            ready.push(Event {
+                userdata,
+                error: Errno::Success,
+                event_type: EVENTTYPE_CLOCK,
+            });
+        }
+    }
+
+    Ok(ready)
+}
+
+// An event is the userdata as a u64, the error as a u16 at offset 8, the type as a u8 at 10, and
+// for descriptors, the number of bytes available as a u64 at 16 and flags as a u16 at 24. There's
+// no telling how much can be read or written, so that is always zero.
+fn write_events(memory: &mut Memory, events: u32, ready: &[Event]) -> WasiResult<u32> {
+    for (idx, event) in ready.iter().enumerate() {
+        let mut bytes = [0u8; EVENT_SIZE as usize];
+        bytes[0..8].copy_from_slice(&event.userdata.to_le_bytes());
+        bytes[8..10].copy_from_slice(&(event.error as u16).to_le_bytes());
+        bytes[10] = event.event_type;
+
+        let offset = (idx as u32)
+            .checked_mul(EVENT_SIZE)
+            .and_then(|offset| events.checked_add(offset))
+            .ok_or(Errno::Fault)?;
+        write_bytes(memory, offset, &bytes)?;
+    }
+    Ok(ready.len() as u32)
+}
@@ -0,0 +1,48 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+// A sink for the guest's output that the embedder can read back. Clones share the same buffer, so
+// one can be given to the WasiCtx and another kept to take what was written.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryPipe {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl InMemoryPipe {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Everything written since the last take, leaving the pipe empty
+    pub fn take(&self) -> Vec<u8> {
+        self.buffer.replace(Vec::new())
+    }
+
+    // Everything written since the last take, leaving it in the pipe
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.borrow().clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.borrow().is_empty()
+    }
+}
+
+impl Write for InMemoryPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Which of the guest's output streams a write went to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::core::{
+    stack_entry::StackEntry, Callable, FuncType, Global, GlobalType, HostCallable, MemType, Memory,
+    Resolver, Table, TableType, ValueType,
+};
+use crate::wasi::errno::Errno;
+use crate::wasi::{functions, WasiCtx};
+
+// The module name WASI functions are imported from
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+type WasiFn = fn(&WasiCtx, &[StackEntry]) -> Result<Errno>;
+
+// The implementation of a WASI function and its signature. They all return an errno apart from
+// proc_exit, which never returns at all.
+fn lookup(name: &str) -> Option<(WasiFn, FuncType)> {
+    use ValueType::{I32, I64};
+    let errno = |params| FuncType::new(params, vec![I32]);
+
+    let function: (WasiFn, FuncType) = match name {
+        "fd_read" => (functions::fd_read, errno(vec![I32; 4])),
+        "fd_write" => (functions::fd_write, errno(vec![I32; 4])),
+        "fd_seek" => (functions::fd_seek, errno(vec![I32, I64, I32, I32])),
+        "fd_tell" => (functions::fd_tell, errno(vec![I32; 2])),
+        "fd_close" => (functions::fd_close, errno(vec![I32])),
+        "fd_fdstat_get" => (functions::fd_fdstat_get, errno(vec![I32; 2])),
+        "fd_filestat_get" => (functions::fd_filestat_get, errno(vec![I32; 2])),
+        "clock_time_get" => (functions::clock_time_get, errno(vec![I32, I64, I32])),
+        "poll_oneoff" => (functions::poll_oneoff, errno(vec![I32; 4])),
+        "sched_yield" => (functions::sched_yield, errno(vec![])),
+        "proc_exit" => (functions::proc_exit, FuncType::new(vec![I32], vec![])),
+        _ => return None,
+    };
+    Some(function)
+}
+
+struct WasiFunction {
+    name: String,
+    func_type: FuncType,
+    ctx: Rc<WasiCtx>,
+    function: WasiFn,
+}
+
+impl fmt::Debug for WasiFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WasiFunction({} {})", self.name, self.func_type)
+    }
+}
+
+impl HostCallable for WasiFunction {
+    fn func_type(&self) -> &FuncType {
+        &self.func_type
+    }
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        let errno = (self.function)(&self.ctx, args)?;
+        if self.func_type.results().is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(vec![errno.into()])
+    }
+}
+
+// Resolves the WASI imports against a context. Nothing else can be imported.
+#[derive(Debug, Clone)]
+pub struct WasiResolver {
+    ctx: Rc<WasiCtx>,
+}
+
+impl WasiResolver {
+    pub fn new(ctx: Rc<WasiCtx>) -> Self {
+        Self { ctx }
+    }
+
+    pub fn ctx(&self) -> &Rc<WasiCtx> {
+        &self.ctx
+    }
+}
+
+impl Resolver for WasiResolver {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        let (function, func_type) = match lookup(name) {
+            Some(function) if mod_name == WASI_MODULE => function,
+            _ => return Err(anyhow!("Imported function {}:{} not found", mod_name, name)),
+        };
+
+        let function = WasiFunction {
+            name: name.to_string(),
+            func_type,
+            ctx: self.ctx.clone(),
+            function,
+        };
+        Ok(Rc::new(RefCell::new(Callable::from_host(Rc::new(
+            function,
+        )))))
+    }
+
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        Err(anyhow!("Imported table {}:{} not found", mod_name, name))
+    }
+
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        Err(anyhow!("Imported memory {}:{} not found", mod_name, name))
+    }
+
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        Err(anyhow!("Imported global {}:{} not found", mod_name, name))
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::prelude::*;
+
+use crate::core;
+use crate::parser::InstructionSource;
+use crate::writer::WriterUtil;
+
+// The counterpart of TypeReader, each type writes itself out in the binary format
+pub trait TypeWriter {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()>;
+}
+
+impl TypeWriter for core::ValueType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_u8(self.clone() as u8)
+    }
+}
+
+impl TypeWriter for core::MutableType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_u8(self.clone() as u8)
+    }
+}
+
+impl TypeWriter for core::ElemType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_u8(self.clone() as u8)
+    }
+}
+
+impl TypeWriter for core::Limits {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        match self {
+            core::Limits::Unbounded(min) => {
+                writer.write_u8(0x00)?;
+                writer.write_leb_usize(*min)
+            }
+            core::Limits::Bounded(min, max) => {
+                writer.write_u8(0x01)?;
+                writer.write_leb_usize(*min)?;
+                writer.write_leb_usize(*max)
+            }
+        }
+    }
+}
+
+impl TypeWriter for core::TableType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        self.elem_type().write(writer)?;
+        self.limits().write(writer)
+    }
+}
+
+impl TypeWriter for core::MemType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        self.limits().write(writer)
+    }
+}
+
+impl TypeWriter for core::GlobalType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        self.value_type().write(writer)?;
+        let mutable_type = if self.is_mutable() {
+            core::MutableType::Var
+        } else {
+            core::MutableType::Const
+        };
+        mutable_type.write(writer)
+    }
+}
+
+impl TypeWriter for core::FuncType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_u8(0x60)?;
+        writer.write_vec(self.params(), |w, value_type| value_type.write(w))?;
+        writer.write_vec(self.results(), |w, value_type| value_type.write(w))
+    }
+}
+
+impl TypeWriter for core::ImportDesc {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        match self {
+            Self::TypeIdx(type_idx) => {
+                writer.write_u8(0x00)?;
+                writer.write_leb_usize(*type_idx)
+            }
+            Self::TableType(table_type) => {
+                writer.write_u8(0x01)?;
+                table_type.write(writer)
+            }
+            Self::MemType(mem_type) => {
+                writer.write_u8(0x02)?;
+                mem_type.write(writer)
+            }
+            Self::GlobalType(global_type) => {
+                writer.write_u8(0x03)?;
+                global_type.write(writer)
+            }
+        }
+    }
+}
+
+impl TypeWriter for core::Import {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_name(self.mod_name())?;
+        writer.write_name(self.name())?;
+        self.desc().write(writer)
+    }
+}
+
+impl TypeWriter for core::Expr {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        // The expression bytes already include the end instruction
+        writer.write_all(self.get_instruction_bytes())?;
+        Ok(())
+    }
+}
+
+impl TypeWriter for core::GlobalDef {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        self.global_type().write(writer)?;
+        self.init_expr().write(writer)
+    }
+}
+
+impl TypeWriter for core::ExportDesc {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        let (tag, idx) = match self {
+            core::ExportDesc::Func(idx) => (0x00, idx),
+            core::ExportDesc::Table(idx) => (0x01, idx),
+            core::ExportDesc::Mem(idx) => (0x02, idx),
+            core::ExportDesc::Global(idx) => (0x03, idx),
+        };
+        writer.write_u8(tag)?;
+        writer.write_leb_usize(*idx)
+    }
+}
+
+impl TypeWriter for core::Export {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_name(&self.nm)?;
+        self.d.write(writer)
+    }
+}
+
+impl TypeWriter for core::Element {
+    // Written the shortest way the segment can be, which is the MVP layout where that will do
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        let func_ref = *self.elem_type() == core::ElemType::FuncRef;
+        let mut flags = match self.mode() {
+            core::ElementMode::Active { table_idx: 0, .. } if func_ref => 0,
+            core::ElementMode::Active { .. } => 2,
+            core::ElementMode::Passive => 1,
+            core::ElementMode::Declared => 3,
+        };
+        if let core::ElementItems::Exprs(_) = self.items() {
+            flags |= 0x4;
+        }
+        writer.write_leb_u32(flags)?;
+
+        if let core::ElementMode::Active { table_idx, offset } = self.mode() {
+            if flags & 0x2 != 0 {
+                writer.write_leb_usize(*table_idx)?;
+            }
+            offset.write(writer)?;
+        }
+
+        match self.items() {
+            core::ElementItems::FuncIndices(func_indices) => {
+                if flags != 0 {
+                    // The element kind for function references
+                    writer.write_u8(0x00)?;
+                }
+                writer.write_vec(func_indices, |w, func_idx| w.write_leb_usize(*func_idx))
+            }
+            core::ElementItems::Exprs(exprs) => {
+                if flags != 4 {
+                    self.elem_type().write(writer)?;
+                }
+                writer.write_vec(exprs, |w, expr| expr.write(w))
+            }
+        }
+    }
+}
+
+impl TypeWriter for core::Locals {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_leb_u32(self.count())?;
+        self.value_type().write(writer)
+    }
+}
+
+impl TypeWriter for core::Func {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        // The body is prefixed with its size, so it has to be written out separately first
+        let mut payload = Vec::new();
+        payload.write_vec(self.locals(), |w, locals| locals.write(w))?;
+        self.expr().write(&mut payload)?;
+
+        writer.write_leb_usize(payload.len())?;
+        writer.write_all(&payload)?;
+        Ok(())
+    }
+}
+
+impl TypeWriter for core::Data {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_leb_usize(self.mem_idx())?;
+        self.expr().write(writer)?;
+        writer.write_leb_usize(self.bytes().len())?;
+        writer.write_all(self.bytes())?;
+        Ok(())
+    }
+}
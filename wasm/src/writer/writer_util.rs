@@ -0,0 +1,85 @@
+use anyhow::Result;
+use std::convert::TryFrom;
+use std::io;
+
+// The counterpart of ReaderUtil, for producing the binary format
+pub trait WriterUtil {
+    fn write_u8(&mut self, value: u8) -> Result<()>;
+    fn write_leb_u32(&mut self, value: u32) -> Result<()>;
+    fn write_leb_usize(&mut self, value: usize) -> Result<()>;
+    fn write_leb_i32(&mut self, value: i32) -> Result<()>;
+    fn write_leb_i64(&mut self, value: i64) -> Result<()>;
+
+    fn write_vec<R, T: Fn(&mut Self, &R) -> Result<()>>(
+        &mut self,
+        items: &[R],
+        write_fn: T,
+    ) -> Result<()>;
+
+    fn write_name(&mut self, name: &str) -> Result<()>;
+}
+
+impl<T> WriterUtil for T
+where
+    T: io::Write,
+{
+    fn write_u8(&mut self, value: u8) -> Result<()> {
+        self.write_all(&[value])?;
+        Ok(())
+    }
+
+    fn write_leb_u32(&mut self, value: u32) -> Result<()> {
+        let mut value = value;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+
+            if value == 0 {
+                return self.write_u8(byte);
+            }
+            self.write_u8(byte | 0x80)?;
+        }
+    }
+
+    fn write_leb_usize(&mut self, value: usize) -> Result<()> {
+        self.write_leb_u32(u32::try_from(value)?)
+    }
+
+    fn write_leb_i32(&mut self, value: i32) -> Result<()> {
+        self.write_leb_i64(i64::from(value))
+    }
+
+    fn write_leb_i64(&mut self, value: i64) -> Result<()> {
+        let mut value = value;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            // This is an arithmetic shift, so negative numbers end up as -1 rather than 0
+            value >>= 7;
+
+            // Stop once the rest of the value is just the sign extension of this byte
+            let sign_bit_clear = (byte & 0x40) == 0;
+            if (value == 0 && sign_bit_clear) || (value == -1 && !sign_bit_clear) {
+                return self.write_u8(byte);
+            }
+            self.write_u8(byte | 0x80)?;
+        }
+    }
+
+    fn write_vec<R, T2: Fn(&mut Self, &R) -> Result<()>>(
+        &mut self,
+        items: &[R],
+        write_fn: T2,
+    ) -> Result<()> {
+        self.write_leb_usize(items.len())?;
+        for item in items {
+            write_fn(self, item)?;
+        }
+        Ok(())
+    }
+
+    fn write_name(&mut self, name: &str) -> Result<()> {
+        self.write_leb_usize(name.len())?;
+        self.write_all(name.as_bytes())?;
+        Ok(())
+    }
+}
@@ -1,10 +1,24 @@
 use anyhow::{anyhow, Result};
-use std::{cell::RefCell, rc::Rc};
+use std::convert::TryFrom;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+use wasm::builder::{Instr, RawModuleBuilder};
 use wasm::core;
+use wasm::core::memory_page::WASM_PAGE_SIZE_IN_BYTES;
 use wasm::core::{
-    Callable, FuncType, Global, GlobalType, MemType, Memory, MutableType, Table, TableType,
+    stack_entry::StackEntry, BlockType, Callable, Caller, ElemType, Export, ExportDesc, ExportKind,
+    ExportValue, Expr, ExternRef, ExternRefError, ExternRefTable, Func, FuncType, Global,
+    GlobalType, HostCallable, Import, ImportDesc, ImportError, ImportErrorReason, ImportType,
+    Limits, MemType, Memory, MutableType, RawModule, Table, TableType, Trap, TrapKind, TrapOrigin,
     ValueType,
 };
+use wasm::parser::Opcode;
+use wasm::reader::{
+    CustomSectionHandlers, HandlerErrorPolicy, SectionFramingError, SectionIter, TypeReader,
+    UnhandledSections,
+};
 
 struct TestResolver {
     global_zero: Rc<RefCell<Global>>,
@@ -67,7 +81,9 @@ impl core::Resolver for TestResolver {
 fn test_load_module() -> std::result::Result<(), String> {
     let resolver = TestResolver::new();
 
-    match core::Module::load_module_from_path("../test_app/test.wasm", &resolver) {
+    match core::Module::load_module_from_path("../test_app/test.wasm")
+        .and_then(|m| m.instantiate(&resolver))
+    {
         Ok(m) => {
             assert_eq!(m.exports.len(), 4);
             assert!(m.exports.contains_key("fib"));
@@ -76,7 +92,7 @@ fn test_load_module() -> std::result::Result<(), String> {
             assert!(m.exports.contains_key("one"));
 
             let exported_fn = match &m.exports["fib"] {
-                core::ExportValue::Function(f) => f,
+                core::Extern::Func(f) => f,
                 _ => panic!("Unexpected export type"),
             };
 
@@ -161,3 +177,6290 @@ fn test_load_module() -> std::result::Result<(), String> {
     }
     Ok(())
 }
+
+#[test]
+fn test_export_value_accessors() -> Result<()> {
+    let resolver = TestResolver::new();
+    let mut m =
+        core::Module::load_module_from_path("../test_app/test.wasm")?.instantiate(&resolver)?;
+
+    let fib = &m.exports["fib"];
+    assert_eq!(fib.kind(), ExportKind::Function);
+    assert!(fib.as_function().is_some());
+    assert!(fib.as_table().is_none());
+    assert!(fib.as_memory().is_none());
+    assert!(fib.as_global().is_none());
+
+    let fib7 = &m.exports["fib7"];
+    assert_eq!(fib7.kind(), ExportKind::Global);
+    assert!(fib7.as_function().is_none());
+    assert_eq!(
+        fib7.as_global().unwrap().borrow().get_value().clone(),
+        13_u32.into()
+    );
+
+    // The consuming forms hand back the export when it's the wrong kind
+    let fib = m.exports.remove("fib").unwrap();
+    let fib = fib.into_global().unwrap_err();
+    let fib = fib.into_table().unwrap_err();
+    let fib = fib.into_memory().unwrap_err();
+    assert_eq!(format!("{}", fib), "function export");
+    assert!(Rc::ptr_eq(&fib.into_function().unwrap(), &m.functions[0]));
+
+    let fib7 = m.exports.remove("fib7").unwrap();
+    let fib7 = fib7.into_function().unwrap_err();
+    assert_eq!(format!("{}", fib7), "global export");
+    assert!(fib7.into_global().is_ok());
+
+    // The test module doesn't export its table or memory, so wrap them up directly
+    let table = ExportValue::Table(m.tables[0].clone());
+    assert_eq!(table.kind(), ExportKind::Table);
+    assert!(table.as_memory().is_none());
+    assert!(Rc::ptr_eq(table.as_table().unwrap(), &m.tables[0]));
+    let table = table.into_memory().unwrap_err();
+    assert_eq!(format!("{}", table), "table export");
+    assert!(table.into_table().is_ok());
+
+    let memory = ExportValue::Memory(m.memories[0].clone());
+    assert_eq!(memory.kind(), ExportKind::Memory);
+    assert!(memory.as_table().is_none());
+    assert!(Rc::ptr_eq(memory.as_memory().unwrap(), &m.memories[0]));
+    let memory = memory.into_function().unwrap_err();
+    assert_eq!(format!("{}", memory), "memory export");
+    assert!(memory.into_memory().is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_instantiate_twice() -> Result<()> {
+    let resolver = TestResolver::new();
+    let module = core::Module::load_module_from_path("../test_app/test.wasm")?;
+
+    let first = module.instantiate(&resolver)?;
+    let second = module.instantiate(&resolver)?;
+
+    // Each instance gets its own copy of everything the module defines...
+    assert!(!Rc::ptr_eq(&first.memories[0], &second.memories[0]));
+    assert!(!Rc::ptr_eq(&first.tables[0], &second.tables[0]));
+    assert!(!Rc::ptr_eq(&first.functions[0], &second.functions[0]));
+
+    let first_fib7 = first.exports["fib7"].as_global().unwrap();
+    let second_fib7 = second.exports["fib7"].as_global().unwrap();
+    assert!(!Rc::ptr_eq(first_fib7, second_fib7));
+
+    first_fib7.borrow_mut().set_value(1_u32.into())?;
+    assert_eq!(first_fib7.borrow().get_value().clone(), 1_u32.into());
+    assert_eq!(second_fib7.borrow().get_value().clone(), 13_u32.into());
+
+    first.memories[0].borrow_mut().set_data(0, b"TEST")?;
+    let mut buf: [u8; 4] = [0; 4];
+    second.memories[0].borrow().get_data(0, &mut buf)?;
+    assert_eq!(&buf, b"test");
+
+    // ...but imports are shared with whatever the resolver handed out
+    let first_zero = first.exports["zero"].as_global().unwrap();
+    let second_zero = second.exports["zero"].as_global().unwrap();
+    assert!(Rc::ptr_eq(first_zero, second_zero));
+
+    Ok(())
+}
+
+#[test]
+fn test_inspect_raw_module_before_instantiating() -> Result<()> {
+    let mut file = std::fs::File::open("../test_app/test.wasm")?;
+    let raw = RawModule::read(&mut file)?;
+
+    // Look at the imports to decide how the module should be resolved
+    assert_eq!(raw.imports().len(), 1);
+    let import = &raw.imports()[0];
+    assert_eq!(import.mod_name(), "test");
+    assert_eq!(import.name(), "zero");
+    match import.desc() {
+        ImportDesc::GlobalType(_) => {}
+        desc => panic!("Expected test.zero to be a global import, got {:?}", desc),
+    }
+
+    assert_eq!(raw.funcs().len(), raw.func_type_indices().len());
+    assert!(raw.exports().iter().any(|e| e.nm == "fib7"));
+
+    // The same parse can be instantiated without reading the file again
+    let resolver = TestResolver::new();
+    let instance = core::Module::new(raw.clone()).instantiate(&resolver)?;
+    assert_eq!(instance.exports.len(), raw.exports().len());
+
+    Ok(())
+}
+
+#[test]
+fn test_resolved_imports() -> Result<()> {
+    let resolver = TestResolver::new();
+    let module = core::Module::load_module_from_path("../test_app/test.wasm")?;
+
+    let first = module.instantiate(&resolver)?;
+    let second = module.instantiate(&resolver)?;
+
+    let imports = first.resolved_imports();
+    assert_eq!(imports.len(), 1);
+
+    let zero = &imports[0];
+    assert_eq!(zero.mod_name, "test");
+    assert_eq!(zero.name, "zero");
+    assert_eq!(zero.kind(), ExportKind::Global);
+    assert_eq!(zero.is_host_function(), None);
+    match &zero.import_type {
+        ImportType::Global(global_type) => {
+            assert_eq!(
+                global_type,
+                &GlobalType::new(ValueType::I32, MutableType::Const)
+            );
+        }
+        import_type => panic!("Expected a global import type, got {:?}", import_type),
+    }
+
+    // Both instances were handed the resolver's global, and it is the one they use
+    let first_zero = zero.value.as_global().unwrap();
+    let second_zero = second.resolved_imports()[0].value.as_global().unwrap();
+    assert!(Rc::ptr_eq(first_zero, &resolver.global_zero));
+    assert!(Rc::ptr_eq(first_zero, second_zero));
+    assert!(Rc::ptr_eq(first_zero, &first.globals[0]));
+
+    Ok(())
+}
+
+fn is_out_of_bounds<T: std::fmt::Debug>(result: Result<T>) -> bool {
+    match result {
+        Err(e) => match e.downcast_ref::<Trap>().map(|t| t.kind()) {
+            Some(TrapKind::MemoryOutOfBounds { .. }) => true,
+            _ => false,
+        },
+        Ok(_) => false,
+    }
+}
+
+#[test]
+fn test_memory_typed_access() -> Result<()> {
+    const END: usize = 65536;
+    let mut memory = Memory::new_from_bounds(1, None);
+
+    // Values are little endian, and the last value that fits ends on the last byte
+    memory.write_u8(END - 1, 0x12)?;
+    assert_eq!(memory.read_u8(END - 1)?, 0x12);
+    assert!(is_out_of_bounds(memory.read_u8(END)));
+    assert!(is_out_of_bounds(memory.write_u8(END, 0)));
+
+    memory.write_u16(END - 2, 0x1234)?;
+    assert_eq!(memory.data()[END - 2..], [0x34, 0x12]);
+    assert_eq!(memory.read_u16(END - 2)?, 0x1234);
+    assert!(is_out_of_bounds(memory.read_u16(END - 1)));
+    assert!(is_out_of_bounds(memory.write_u16(END - 1, 0)));
+
+    memory.write_u32(END - 4, 0x1234_5678)?;
+    assert_eq!(memory.data()[END - 4..], [0x78, 0x56, 0x34, 0x12]);
+    assert_eq!(memory.read_u32(END - 4)?, 0x1234_5678);
+    assert!(is_out_of_bounds(memory.read_u32(END - 3)));
+    assert!(is_out_of_bounds(memory.write_u32(END - 3, 0)));
+
+    memory.write_i32(END - 4, -2)?;
+    assert_eq!(memory.read_i32(END - 4)?, -2);
+    assert_eq!(memory.read_u32(END - 4)?, 0xffff_fffe);
+    assert!(is_out_of_bounds(memory.read_i32(END - 3)));
+    assert!(is_out_of_bounds(memory.write_i32(END - 3, 0)));
+
+    memory.write_u64(END - 8, 0x0102_0304_0506_0708)?;
+    assert_eq!(
+        memory.data()[END - 8..],
+        [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+    );
+    assert_eq!(memory.read_u64(END - 8)?, 0x0102_0304_0506_0708);
+    assert!(is_out_of_bounds(memory.read_u64(END - 7)));
+    assert!(is_out_of_bounds(memory.write_u64(END - 7, 0)));
+
+    memory.write_i64(END - 8, -3)?;
+    assert_eq!(memory.read_i64(END - 8)?, -3);
+    assert!(is_out_of_bounds(memory.read_i64(END - 7)));
+    assert!(is_out_of_bounds(memory.write_i64(END - 7, 0)));
+
+    memory.write_f32(END - 4, 1.5)?;
+    assert_eq!(memory.read_u32(END - 4)?, 0x3fc0_0000);
+    assert_eq!(memory.read_f32(END - 4)?, 1.5);
+    assert!(is_out_of_bounds(memory.read_f32(END - 3)));
+    assert!(is_out_of_bounds(memory.write_f32(END - 3, 0.0)));
+
+    memory.write_f64(END - 8, -0.25)?;
+    assert_eq!(memory.read_u64(END - 8)?, 0xbfd0_0000_0000_0000);
+    assert_eq!(memory.read_f64(END - 8)?, -0.25);
+    assert!(is_out_of_bounds(memory.read_f64(END - 7)));
+    assert!(is_out_of_bounds(memory.write_f64(END - 7, 0.0)));
+
+    // Offsets so large they wrap around are out of bounds too, rather than a panic
+    assert!(is_out_of_bounds(memory.read_u64(usize::max_value() - 3)));
+
+    // A failed write leaves the memory alone
+    assert_eq!(memory.read_f64(END - 8)?, -0.25);
+
+    Ok(())
+}
+
+// Hands out a fresh memory or table built from exactly the type the import asked for
+struct LimitsResolver {}
+
+impl core::Resolver for LimitsResolver {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        Err(anyhow!("Imported function {}:{} not found", mod_name, name))
+    }
+    fn resolve_table(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        Ok(Rc::new(RefCell::new(Table::new(table_type.clone()))))
+    }
+    fn resolve_memory(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        Ok(Rc::new(RefCell::new(Memory::new(mem_type.clone()))))
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        Err(anyhow!("Imported global {}:{} not found", mod_name, name))
+    }
+}
+
+#[test]
+fn test_size_and_limits_queries() -> Result<()> {
+    let table_type = TableType::new(ElemType::FuncRef, Limits::Unbounded(3));
+    let mem_type = MemType::new(Limits::Bounded(1, 4));
+
+    let raw = RawModule::new(
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        None,
+        vec![
+            Import::new(
+                "env".to_string(),
+                "table".to_string(),
+                ImportDesc::TableType(table_type.clone()),
+            ),
+            Import::new(
+                "env".to_string(),
+                "memory".to_string(),
+                ImportDesc::MemType(mem_type.clone()),
+            ),
+        ],
+        vec![
+            Export::new("table".to_string(), ExportDesc::Table(0)),
+            Export::new("memory".to_string(), ExportDesc::Mem(0)),
+        ],
+    );
+    let instance = core::Module::new(raw).instantiate(&LimitsResolver {})?;
+
+    // The limits come through the import and back out of the export unchanged
+    {
+        let memory = instance.exports["memory"].as_memory().unwrap().borrow();
+        assert_eq!(memory.ty(), &mem_type);
+        assert_eq!(memory.min_pages(), 1);
+        assert_eq!(memory.max_pages(), Some(4));
+        assert_eq!(memory.size_pages(), 1);
+        assert_eq!(memory.size_bytes(), 65536);
+
+        let table = instance.exports["table"].as_table().unwrap().borrow();
+        assert_eq!(table.ty(), &table_type);
+        assert_eq!(table.min(), 3);
+        assert_eq!(table.max(), None);
+        assert_eq!(table.size(), 3);
+    }
+
+    // The size follows growth while the limits stay where they were declared
+    let mut memory = instance.memories[0].borrow_mut();
+    memory.grow_by(2)?;
+    assert_eq!(memory.size_pages(), 3);
+    assert_eq!(memory.size_bytes(), 3 * 65536);
+    assert_eq!(memory.min_pages(), 1);
+    assert_eq!(memory.max_pages(), Some(4));
+
+    Ok(())
+}
+
+// Hands out a new memory and table with the limits it was made with, whatever was asked for
+struct SuppliedLimitsResolver {
+    memory: Limits,
+    table: Limits,
+}
+
+impl core::Resolver for SuppliedLimitsResolver {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        Err(anyhow!("Imported function {}:{} not found", mod_name, name))
+    }
+    fn resolve_table(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        _table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        let table_type = TableType::new(ElemType::FuncRef, self.table.clone());
+        Ok(Rc::new(RefCell::new(Table::new(table_type))))
+    }
+    fn resolve_memory(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        _mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        Ok(Rc::new(RefCell::new(Memory::new(MemType::new(
+            self.memory.clone(),
+        )))))
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        Err(anyhow!("Imported global {}:{} not found", mod_name, name))
+    }
+}
+
+#[test]
+fn test_import_limits_matching() -> Result<()> {
+    let memory_module = |min, max| {
+        core::Module::new(
+            RawModuleBuilder::new()
+                .import_memory("env", "memory", min, max)
+                .build(),
+        )
+    };
+    let table_module = |min, max| {
+        core::Module::new(
+            RawModuleBuilder::new()
+                .import_table("env", "table", min, max)
+                .build(),
+        )
+    };
+    let supplying = |memory: Limits, table: Limits| SuppliedLimitsResolver { memory, table };
+    let any_table = Limits::Unbounded(0);
+    let any_memory = Limits::Unbounded(0);
+
+    // Bigger to start with and allowed to grow less is fine
+    let bounded = memory_module(2, Some(10));
+    bounded.instantiate(&supplying(Limits::Bounded(2, 10), any_table.clone()))?;
+    bounded.instantiate(&supplying(Limits::Bounded(3, 5), any_table.clone()))?;
+
+    // Too small, or allowed to grow too big, whether that's a bigger max or none at all
+    let error = bounded
+        .instantiate(&supplying(Limits::Bounded(1, 10), any_table.clone()))
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ImportError>(),
+        Some(&ImportError {
+            module: "env".to_string(),
+            field: "memory".to_string(),
+            kind: ExportKind::Memory,
+            reason: ImportErrorReason::LimitsMismatch {
+                expected: Limits::Bounded(2, 10),
+                provided: Limits::Bounded(1, 10),
+            },
+        })
+    );
+    assert_eq!(
+        format!("{}", error),
+        "import `env`::`memory` (memory): declared with limits min 2, max 10, but the resolver \
+         provided min 1, max 10"
+    );
+    let error = bounded
+        .instantiate(&supplying(Limits::Bounded(2, 20), any_table.clone()))
+        .unwrap_err();
+    assert!(format!("{}", error).ends_with("but the resolver provided min 2, max 20"));
+    let error = bounded
+        .instantiate(&supplying(Limits::Unbounded(2), any_table.clone()))
+        .unwrap_err();
+    assert!(format!("{}", error).ends_with("but the resolver provided min 2, no max"));
+
+    // Without a declared max, the one provided can have any max or none
+    let unbounded = memory_module(2, None);
+    unbounded.instantiate(&supplying(Limits::Unbounded(2), any_table.clone()))?;
+    unbounded.instantiate(&supplying(Limits::Bounded(4, 8), any_table.clone()))?;
+    assert!(unbounded
+        .instantiate(&supplying(Limits::Unbounded(1), any_table))
+        .is_err());
+
+    // Tables are matched the same way
+    let table = table_module(3, Some(6));
+    table.instantiate(&supplying(any_memory.clone(), Limits::Bounded(4, 6)))?;
+    let error = table
+        .instantiate(&supplying(any_memory.clone(), Limits::Bounded(2, 6)))
+        .unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "import `env`::`table` (table): declared with limits min 3, max 6, but the resolver \
+         provided min 2, max 6"
+    );
+    assert!(table
+        .instantiate(&supplying(any_memory.clone(), Limits::Bounded(3, 7)))
+        .is_err());
+    assert!(table
+        .instantiate(&supplying(any_memory.clone(), Limits::Unbounded(3)))
+        .is_err());
+    table_module(3, None).instantiate(&supplying(any_memory, Limits::Bounded(3, 7)))?;
+
+    Ok(())
+}
+
+// The size a memory has grown to counts, rather than the minimum it was made with
+struct GrownMemoryResolver {
+    memory: Rc<RefCell<Memory>>,
+}
+
+impl core::Resolver for GrownMemoryResolver {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        Err(anyhow!("Imported function {}:{} not found", mod_name, name))
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        Err(anyhow!("Imported table {}:{} not found", mod_name, name))
+    }
+    fn resolve_memory(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        _mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        Ok(self.memory.clone())
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        Err(anyhow!("Imported global {}:{} not found", mod_name, name))
+    }
+}
+
+#[test]
+fn test_import_limits_use_current_size() -> Result<()> {
+    let module = core::Module::new(
+        RawModuleBuilder::new()
+            .import_memory("env", "memory", 2, Some(10))
+            .build(),
+    );
+    let resolver = GrownMemoryResolver {
+        memory: Rc::new(RefCell::new(Memory::new_from_bounds(1, Some(10)))),
+    };
+    assert!(module.instantiate(&resolver).is_err());
+
+    resolver.memory.borrow_mut().grow_by(1)?;
+    module.instantiate(&resolver)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_dump_table() -> Result<()> {
+    let bytes: Vec<u8> = [
+        &[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00][..],
+        // One type, () -> ()
+        &[0x01, 0x04, 0x01, 0x60, 0x00, 0x00],
+        // Two functions of that type
+        &[0x03, 0x03, 0x02, 0x00, 0x00],
+        // A funcref table with three slots
+        &[0x04, 0x04, 0x01, 0x70, 0x00, 0x03],
+        // Slots 1 and 2 are functions 1 and 0
+        &[0x09, 0x08, 0x01, 0x00, 0x41, 0x01, 0x0b, 0x02, 0x01, 0x00],
+        // Both function bodies are empty
+        &[0x0a, 0x07, 0x02, 0x02, 0x00, 0x0b, 0x02, 0x00, 0x0b],
+        // A name section with a module name and a name for function 1 only
+        &[0x00, 0x1b, 0x04],
+        b"name",
+        &[0x00, 0x03, 0x02],
+        b"my",
+        &[0x01, 0x0f, 0x01, 0x01, 0x0c],
+        b"dispatch_add",
+    ]
+    .concat();
+
+    let module = core::Module::from_reader(&mut &bytes[..])?;
+    assert_eq!(module.raw_module().func_name(0), None);
+    assert_eq!(module.raw_module().func_name(1), Some("dispatch_add"));
+
+    let instance = module.instantiate(&TestResolver::new())?;
+    {
+        let table = instance.tables[0].borrow();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.entry_func_idx(0), None);
+        assert_eq!(table.entry_func_idx(1), Some(1));
+        assert_eq!(table.entry_func_idx(2), Some(0));
+
+        let entries: Vec<_> = table.iter().collect();
+        assert!(entries[0].is_none());
+        let (callable, func_idx) = entries[1].unwrap();
+        assert!(Rc::ptr_eq(callable, &instance.functions[1]));
+        assert_eq!(func_idx, Some(1));
+    }
+
+    assert_eq!(
+        module.dump_table(&instance, 0)?,
+        "slot 1 -> func 1 'dispatch_add'\nslot 2 -> func 0\n"
+    );
+    assert!(module.dump_table(&instance, 1).is_err());
+
+    // Entries set without an index still show up, they just can't be identified
+    let function = instance.functions[0].clone();
+    instance.tables[0].borrow_mut().set_entries(1, &[function]);
+    assert_eq!(
+        module.dump_table(&instance, 0)?,
+        "slot 1 -> unknown func\nslot 2 -> func 0\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_element_segment_bounds() -> Result<()> {
+    let make_module = |offset: i32, func_indices: Vec<usize>| {
+        core::Module::new(
+            RawModuleBuilder::new()
+                .add_type(FuncType::new(vec![], vec![]))
+                .add_function(0, vec![], vec![])
+                .add_function(0, vec![], vec![])
+                .add_table(3, None)
+                .add_elem(0, vec![Instr::I32Const(offset)], func_indices)
+                .build(),
+        )
+    };
+
+    let instance = make_module(1, vec![1, 0]).instantiate(core::EmptyResolver::instance())?;
+    let table = instance.tables[0].borrow();
+    assert_eq!(table.entry_func_idx(0), None);
+    assert_eq!(table.entry_func_idx(1), Some(1));
+    assert_eq!(table.entry_func_idx(2), Some(0));
+    assert!(Rc::ptr_eq(
+        table[2].as_ref().unwrap(),
+        &instance.functions[0]
+    ));
+
+    let error = make_module(2, vec![0, 1])
+        .instantiate(core::EmptyResolver::instance())
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Element segment of 2 entries at 2 doesn't fit in a table of 3"
+    );
+    let error = make_module(0, vec![0, 2])
+        .instantiate(core::EmptyResolver::instance())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Function index 2 out of range");
+
+    Ok(())
+}
+
+#[test]
+fn test_element_segment_flags() -> Result<()> {
+    // One segment for each of the layouts the flags give, each with func 2 and then func 1 or a
+    // null as its entries, and those that are active at offset 1 in table 0
+    let segments: [&[u8]; 8] = [
+        &[0x00, 0x41, 0x01, 0x0b, 0x02, 0x02, 0x01],
+        &[0x01, 0x00, 0x02, 0x02, 0x01],
+        &[0x02, 0x00, 0x41, 0x01, 0x0b, 0x00, 0x02, 0x02, 0x01],
+        &[0x03, 0x00, 0x02, 0x02, 0x01],
+        &[
+            0x04, 0x41, 0x01, 0x0b, 0x02, 0xd2, 0x02, 0x0b, 0xd0, 0x70, 0x0b,
+        ],
+        &[0x05, 0x70, 0x02, 0xd2, 0x02, 0x0b, 0xd0, 0x70, 0x0b],
+        &[
+            0x06, 0x00, 0x41, 0x01, 0x0b, 0x70, 0x02, 0xd2, 0x02, 0x0b, 0xd0, 0x70, 0x0b,
+        ],
+        &[0x07, 0x70, 0x02, 0xd2, 0x02, 0x0b, 0xd0, 0x70, 0x0b],
+    ];
+
+    for (flags, bytes) in segments.iter().enumerate() {
+        let mut reader = *bytes;
+        let element = core::Element::read(&mut reader)?;
+        assert!(reader.is_empty());
+        assert_eq!(element.len(), 2);
+        assert_eq!(*element.elem_type(), core::ElemType::FuncRef);
+
+        let active = match element.mode() {
+            core::ElementMode::Active { table_idx, .. } => {
+                assert_eq!(*table_idx, 0);
+                true
+            }
+            core::ElementMode::Passive => {
+                assert_eq!(flags & 0x3, 1);
+                false
+            }
+            core::ElementMode::Declared => {
+                assert_eq!(flags & 0x3, 3);
+                false
+            }
+        };
+        assert_eq!(active, flags & 0x1 == 0);
+        let exprs = flags & 0x4 != 0;
+        match element.items() {
+            core::ElementItems::FuncIndices(func_indices) => {
+                assert!(!exprs);
+                assert_eq!(func_indices, &[2, 1]);
+            }
+            core::ElementItems::Exprs(_) => {
+                assert!(exprs);
+                assert!(element.func_indices().is_empty());
+            }
+        }
+        let expected_funcs: &[usize] = if exprs { &[2] } else { &[2, 1] };
+        assert_eq!(element.referenced_funcs(), expected_funcs);
+
+        // Slot 2 is filled first, so a null written over it can be seen
+        let raw = RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![], vec![]))
+            .add_function(0, vec![], vec![])
+            .add_function(0, vec![], vec![])
+            .add_function(0, vec![], vec![])
+            .add_table(4, None)
+            .add_elem(0, vec![Instr::I32Const(2)], vec![0])
+            .add_element(element)
+            .build();
+
+        // Written out and read back, it's the same segment, if not always the same bytes
+        let encoded = raw.encode();
+        assert_same_structure(&raw, &read_raw_module(&encoded)?);
+        assert!(RawModule::compatibility_report(&encoded[..])?.is_compatible());
+
+        let instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+        let table = instance.tables[0].borrow();
+        let slots: Vec<_> = (0..4).map(|idx| table.entry_func_idx(idx)).collect();
+        let expected = match (active, exprs) {
+            (true, false) => [None, Some(2), Some(1), None],
+            (true, true) => [None, Some(2), None, None],
+            (false, _) => [None, None, Some(0), None],
+        };
+        assert_eq!(slots, expected, "element segment flags {}", flags);
+    }
+
+    let error = core::Element::read(&mut &[0x08, 0x00][..]).unwrap_err();
+    assert_eq!(error.to_string(), "Invalid element segment flags 8");
+    let error = core::Element::read(&mut &[0x05, 0x70, 0x01, 0x41, 0x00, 0x0b][..]).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Opcode 0x41 is not valid in an element expression"
+    );
+
+    // A declared segment has nothing in it at runtime, but what it refers to still has to be there
+    let declared = core::Element::read(&mut &[0x03, 0x00, 0x01, 0x05][..])?;
+    let error = core::Module::new(
+        RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![], vec![]))
+            .add_function(0, vec![], vec![])
+            .add_element(declared)
+            .build(),
+    )
+    .instantiate(core::EmptyResolver::instance())
+    .unwrap_err();
+    assert_eq!(error.to_string(), "Function index 5 out of range");
+
+    Ok(())
+}
+
+// Position independent code has its segments at offsets given by imported globals, which come
+// first in the global index space, before the module's own
+#[test]
+fn test_segment_offsets_from_imported_globals() -> Result<()> {
+    let i32_type = GlobalType::new(ValueType::I32, MutableType::Const);
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .import_global("env", "__memory_base", i32_type.clone())
+        .import_global("env", "__table_base", i32_type.clone())
+        .add_function(0, vec![], vec![])
+        .add_function(0, vec![], vec![])
+        .add_memory(1, None)
+        .add_table(4, None)
+        .add_global(i32_type.clone(), vec![Instr::GlobalGet(0)])
+        .add_data(0, vec![Instr::GlobalGet(0)], vec![1, 2, 3, 4])
+        .add_data(0, vec![Instr::GlobalGet(2)], vec![5])
+        .add_data(0, vec![Instr::GlobalGet(1)], vec![6])
+        .add_elem(0, vec![Instr::GlobalGet(1)], vec![1, 0])
+        .build();
+    // Read back from its bytes, the way a module from a toolchain would be
+    let module = core::Module::new(read_raw_module(&raw.encode())?);
+
+    let instantiate = |memory_base: u32, table_base: u32| {
+        let mut imports = core::ImportObject::new();
+        let global = |value: u32| Global::new(i32_type.clone(), value.into()).unwrap();
+        imports.define(
+            "env",
+            "__memory_base",
+            Rc::new(RefCell::new(global(memory_base))),
+        );
+        imports.define(
+            "env",
+            "__table_base",
+            Rc::new(RefCell::new(global(table_base))),
+        );
+        module.instantiate(&imports)
+    };
+
+    let instance = instantiate(1024, 2)?;
+    let memory = instance.memories[0].borrow();
+    assert_eq!(memory.read_u32(1024)?, 0x0403_0205);
+    assert_eq!(memory.read_u8(1028)?, 0);
+    assert_eq!(memory.read_u8(2)?, 6);
+    assert_eq!(memory.read_u16(0)?, 0);
+    let table = instance.tables[0].borrow();
+    let slots: Vec<_> = (0..4).map(|idx| table.entry_func_idx(idx)).collect();
+    assert_eq!(slots, [None, None, Some(1), Some(0)]);
+
+    // The segments move with the imports, as the module's own global does
+    let instance = instantiate(64, 0)?;
+    assert_eq!(*instance.globals[2].borrow().get_value(), 64_u32.into());
+    let memory = instance.memories[0].borrow();
+    assert_eq!(memory.read_u32(64)?, 0x0403_0205);
+    assert_eq!(memory.read_u8(0)?, 6);
+    let table = instance.tables[0].borrow();
+    assert_eq!(table.entry_func_idx(0), Some(1));
+    assert_eq!(table.entry_func_idx(1), Some(0));
+
+    // Once they're where the imports put them, they have to fit there
+    let error = instantiate(1024, 3).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Element segment of 2 entries at 3 doesn't fit in a table of 4"
+    );
+
+    Ok(())
+}
+
+// Hands out the same host global for every global import
+struct HostGlobalResolver {
+    global: Rc<RefCell<Global>>,
+}
+
+impl core::Resolver for HostGlobalResolver {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        Err(anyhow!("Imported function {}:{} not found", mod_name, name))
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        Err(anyhow!("Imported table {}:{} not found", mod_name, name))
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        Err(anyhow!("Imported memory {}:{} not found", mod_name, name))
+    }
+    fn resolve_global(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        Ok(self.global.clone())
+    }
+}
+
+// A module that imports env.counter with the given type, and increments it from its start function
+fn make_counter_module(counter_type: GlobalType) -> core::Module {
+    let increment = Func::new(
+        vec![],
+        Expr::new(vec![
+            0x23, 0x00, // global.get 0
+            0x41, 0x01, // i32.const 1
+            0x6a, // i32.add
+            0x24, 0x00, // global.set 0
+            0x0b, // end
+        ]),
+    );
+
+    core::Module::new(RawModule::new(
+        vec![FuncType::new(vec![], vec![])],
+        vec![0],
+        vec![increment],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        Some(0),
+        vec![Import::new(
+            "env".to_string(),
+            "counter".to_string(),
+            ImportDesc::GlobalType(counter_type),
+        )],
+        vec![],
+    ))
+}
+
+#[test]
+fn test_host_global_import() -> Result<()> {
+    let counter = Global::new_host(41_i32.into(), true);
+    assert_eq!(
+        counter.global_type(),
+        &GlobalType::new(ValueType::I32, MutableType::Var)
+    );
+
+    let resolver = HostGlobalResolver {
+        global: Rc::new(RefCell::new(counter)),
+    };
+    let module = make_counter_module(GlobalType::new(ValueType::I32, MutableType::Var));
+
+    // The start function increments the host's global in place, every time it is instantiated
+    module.instantiate(&resolver)?;
+    assert_eq!(resolver.global.borrow().get_value().clone(), 42_i32.into());
+    module.instantiate(&resolver)?;
+    assert_eq!(resolver.global.borrow().get_value().clone(), 43_i32.into());
+
+    // The host can still change it too
+    resolver.global.borrow_mut().set_value(7_i32.into())?;
+    module.instantiate(&resolver)?;
+    assert_eq!(resolver.global.borrow().get_value().clone(), 8_i32.into());
+
+    Ok(())
+}
+
+#[test]
+fn test_host_global_import_type_mismatch() -> Result<()> {
+    let var_i32 = GlobalType::new(ValueType::I32, MutableType::Var);
+
+    // The mutability has to match the import, in both directions
+    let constant = HostGlobalResolver {
+        global: Rc::new(RefCell::new(Global::new_host(1_i32.into(), false))),
+    };
+    assert!(make_counter_module(var_i32.clone())
+        .instantiate(&constant)
+        .is_err());
+    assert_eq!(constant.global.borrow().get_value().clone(), 1_i32.into());
+
+    let error = make_counter_module(var_i32.clone())
+        .instantiate(&constant)
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ImportError>(),
+        Some(&ImportError {
+            module: "env".to_string(),
+            field: "counter".to_string(),
+            kind: ExportKind::Global,
+            reason: ImportErrorReason::GlobalTypeMismatch {
+                expected: var_i32.clone(),
+                provided: GlobalType::new(ValueType::I32, MutableType::Const),
+            },
+        })
+    );
+    assert_eq!(
+        format!("{}", error),
+        "import `env`::`counter` (global): declared as (mut i32), but the resolver provided i32"
+    );
+
+    let variable = HostGlobalResolver {
+        global: Rc::new(RefCell::new(Global::new_host(1_i32.into(), true))),
+    };
+    let const_i32 = GlobalType::new(ValueType::I32, MutableType::Const);
+    let module = core::Module::new(RawModule::new(
+        vec![FuncType::new(vec![], vec![])],
+        vec![0],
+        vec![Func::new(vec![], Expr::new(vec![0x0b]))],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        None,
+        vec![Import::new(
+            "env".to_string(),
+            "counter".to_string(),
+            ImportDesc::GlobalType(const_i32),
+        )],
+        vec![],
+    ));
+    assert!(module.instantiate(&variable).is_err());
+
+    // And so does the value type
+    let wide = HostGlobalResolver {
+        global: Rc::new(RefCell::new(Global::new_host(1_i64.into(), true))),
+    };
+    assert!(make_counter_module(var_i32).instantiate(&wide).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_export_signatures() -> Result<()> {
+    let resolver = TestResolver::new();
+    let mut m =
+        core::Module::load_module_from_path("../test_app/test.wasm")?.instantiate(&resolver)?;
+
+    let fib_type = m.exports["fib"]
+        .as_function()
+        .unwrap()
+        .borrow()
+        .func_type()
+        .clone();
+    assert_eq!(fib_type.params(), &[ValueType::I32]);
+    assert_eq!(fib_type.results(), &[ValueType::I32]);
+    assert_eq!(format!("{}", fib_type), "(i32) -> i32");
+
+    assert_eq!(
+        format!(
+            "{}",
+            FuncType::new(vec![ValueType::I32, ValueType::F64], vec![])
+        ),
+        "(i32, f64) -> ()"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            FuncType::new(vec![], vec![ValueType::I64, ValueType::F32])
+        ),
+        "() -> (i64, f32)"
+    );
+
+    assert_eq!(m.invoke_export("fib", &[10_i32.into()])?, [55_i32.into()]);
+
+    // Calls that don't match the signature are rejected before anything runs
+    let error = m.invoke_export("fib", &[]).unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "Function fib (i32) -> i32 takes 1 arguments, but 0 were given"
+    );
+    let error = m.invoke_export("fib", &[10_i64.into()]).unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "Argument 0 of function fib (i32) -> i32 should be i32, but i64 was given"
+    );
+    assert!(m.invoke_export("fib7", &[]).is_err());
+    assert!(m.invoke_export("missing", &[]).is_err());
+
+    Ok(())
+}
+
+// A host add function that can be told to trap or to return the wrong type
+#[derive(Debug)]
+struct HostAdd {
+    func_type: FuncType,
+    calls: RefCell<Vec<Vec<StackEntry>>>,
+    trap: bool,
+    bad_result: bool,
+}
+
+impl HostAdd {
+    fn new() -> Self {
+        Self {
+            func_type: FuncType::new(vec![ValueType::I32, ValueType::I32], vec![ValueType::I32]),
+            calls: RefCell::new(Vec::new()),
+            trap: false,
+            bad_result: false,
+        }
+    }
+}
+
+impl HostCallable for HostAdd {
+    fn func_type(&self) -> &FuncType {
+        &self.func_type
+    }
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        self.calls.borrow_mut().push(args.to_vec());
+
+        if self.trap {
+            Err(Trap::new(TrapKind::Unreachable).into())
+        } else if self.bad_result {
+            Ok(vec![1_i64.into()])
+        } else {
+            let (a, b) = (i32::try_from(args[0])?, i32::try_from(args[1])?);
+            Ok(vec![a.wrapping_add(b).into()])
+        }
+    }
+}
+
+// Hands out the same host function for every function import
+struct HostFunctionResolver {
+    function: Rc<RefCell<Callable>>,
+}
+
+impl core::Resolver for HostFunctionResolver {
+    fn resolve_function(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        _func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        Ok(self.function.clone())
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        Err(anyhow!("Imported table {}:{} not found", mod_name, name))
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        Err(anyhow!("Imported memory {}:{} not found", mod_name, name))
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        Err(anyhow!("Imported global {}:{} not found", mod_name, name))
+    }
+}
+
+// A module that imports env.add and exports add_one, which calls it
+fn make_add_one_module() -> core::Module {
+    let add_one = Func::new(
+        vec![],
+        Expr::new(vec![
+            0x20, 0x00, // local.get 0
+            0x41, 0x01, // i32.const 1
+            0x10, 0x00, // call 0
+            0x0b, // end
+        ]),
+    );
+
+    core::Module::new(RawModule::new(
+        vec![
+            FuncType::new(vec![ValueType::I32, ValueType::I32], vec![ValueType::I32]),
+            FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+        ],
+        vec![1],
+        vec![add_one],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        None,
+        vec![Import::new(
+            "env".to_string(),
+            "add".to_string(),
+            ImportDesc::TypeIdx(0),
+        )],
+        vec![Export::new("add_one".to_string(), ExportDesc::Func(1))],
+    ))
+}
+
+fn instantiate_add_one(host: HostAdd) -> Result<(Rc<HostAdd>, core::Instance)> {
+    let host = Rc::new(host);
+    let resolver = HostFunctionResolver {
+        function: Rc::new(RefCell::new(Callable::from_host(host.clone()))),
+    };
+    let instance = make_add_one_module().instantiate(&resolver)?;
+    Ok((host, instance))
+}
+
+#[test]
+fn test_host_callable() -> Result<()> {
+    let (host, mut instance) = instantiate_add_one(HostAdd::new())?;
+    assert_eq!(
+        instance.resolved_imports()[0].is_host_function(),
+        Some(true)
+    );
+
+    assert_eq!(
+        instance.invoke_export("add_one", &[41_i32.into()])?,
+        [42_i32.into()]
+    );
+    assert_eq!(
+        instance.invoke_export("add_one", &[(-1_i32).into()])?,
+        [0_i32.into()]
+    );
+    assert_eq!(
+        *host.calls.borrow(),
+        vec![
+            vec![41_i32.into(), 1_i32.into()],
+            vec![(-1_i32).into(), 1_i32.into()]
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_host_callable_failures() -> Result<()> {
+    // A trap from the host comes back out as a trap
+    let mut trapping = HostAdd::new();
+    trapping.trap = true;
+    let (_, mut instance) = instantiate_add_one(trapping)?;
+    let error = instance
+        .invoke_export("add_one", &[1_i32.into()])
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(|t| t.kind()),
+        Some(TrapKind::Unreachable)
+    );
+
+    // Results that don't match the signature are caught before wasm sees them
+    let mut bad_result = HostAdd::new();
+    bad_result.bad_result = true;
+    let (host, mut instance) = instantiate_add_one(bad_result)?;
+    let error = instance
+        .invoke_export("add_one", &[1_i32.into()])
+        .unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "Host function (i32, i32) -> i32 result 0 should be i32, but is i64"
+    );
+    assert_eq!(host.calls.borrow().len(), 1);
+
+    Ok(())
+}
+
+// A host function made from a closure, for the tests that need it to do something different
+type HostFnCall = Box<dyn Fn(&mut Caller, &[StackEntry]) -> Result<Vec<StackEntry>>>;
+
+struct HostFn {
+    func_type: FuncType,
+    call: HostFnCall,
+}
+
+impl std::fmt::Debug for HostFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HostFn({})", self.func_type)
+    }
+}
+
+impl HostCallable for HostFn {
+    fn func_type(&self) -> &FuncType {
+        &self.func_type
+    }
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        (self.call)(&mut Caller::detached(), args)
+    }
+
+    fn call_with_caller(
+        &self,
+        caller: &mut Caller<'_>,
+        args: &[StackEntry],
+    ) -> Result<Vec<StackEntry>> {
+        (self.call)(caller, args)
+    }
+}
+
+// A resolver for a host function that takes two i32s and returns one
+fn add_host_fn_resolver(
+    call: impl Fn(&mut Caller, &[StackEntry]) -> Result<Vec<StackEntry>> + 'static,
+) -> HostFunctionResolver {
+    let host = HostFn {
+        func_type: FuncType::new(vec![ValueType::I32, ValueType::I32], vec![ValueType::I32]),
+        call: Box::new(call),
+    };
+    HostFunctionResolver {
+        function: Rc::new(RefCell::new(Callable::from_host(Rc::new(host)))),
+    }
+}
+
+fn instantiate_add_one_with(
+    call: impl Fn(&mut Caller, &[StackEntry]) -> Result<Vec<StackEntry>> + 'static,
+) -> Result<core::Instance> {
+    make_add_one_module().instantiate(&add_host_fn_resolver(call))
+}
+
+#[derive(Debug, PartialEq)]
+enum Denied {
+    Permission(&'static str),
+    Quota { used: u32, limit: u32 },
+}
+
+#[test]
+fn test_host_traps() -> Result<()> {
+    // The outer instance's host function calls into the inner instance, whose host function
+    // raises the trap, so it unwinds through wasm, host, wasm and host frames on its way out
+    let nest = |raise: Rc<dyn Fn() -> Trap>| {
+        let inner = instantiate_add_one_with(move |_, _| Err(raise().into()))?;
+        let inner = RefCell::new(inner);
+        instantiate_add_one_with(move |_, args| {
+            inner.borrow_mut().invoke_export("add_one", &args[..1])
+        })
+    };
+
+    let raised: Vec<Rc<dyn Fn() -> Denied>> = vec![
+        Rc::new(|| Denied::Permission("open")),
+        Rc::new(|| Denied::Quota {
+            used: 11,
+            limit: 10,
+        }),
+    ];
+    for denied in raised {
+        let raise = denied.clone();
+        let mut outer = nest(Rc::new(move || Trap::with_payload(raise())))?;
+        let error = outer.invoke_export("add_one", &[1_i32.into()]).unwrap_err();
+        let trap = error.downcast_ref::<Trap>().unwrap();
+        assert_eq!(trap.kind(), TrapKind::Host);
+        assert_eq!(trap.origin(), TrapOrigin::Host);
+        assert_eq!(trap.payload_ref::<Denied>(), Some(&denied()));
+        assert_eq!(trap.payload_ref::<String>(), None);
+        assert!(format!("{}", error).contains("Denied"), "{}", error);
+    }
+
+    let mut outer = nest(Rc::new(|| Trap::host("not allowed to open files")))?;
+    let error = outer.invoke_export("add_one", &[1_i32.into()]).unwrap_err();
+    let trap = error.downcast_ref::<Trap>().unwrap();
+    assert_eq!(trap.origin(), TrapOrigin::Host);
+    assert_eq!(trap.message(), Some("not allowed to open files"));
+    assert!(trap.payload().is_none());
+    assert!(format!("{}", error).starts_with("Trap: host trap: not allowed to open files"));
+
+    // A kind the specification defines is the guest's, even when a host function raises it
+    let mut outer = nest(Rc::new(|| Trap::new(TrapKind::Unreachable)))?;
+    let error = outer.invoke_export("add_one", &[1_i32.into()]).unwrap_err();
+    let trap = error.downcast_ref::<Trap>().unwrap();
+    assert_eq!(trap.origin(), TrapOrigin::Guest);
+    assert!(trap.payload().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_trap_through_host_function() -> Result<()> {
+    // top calls middle, which calls the host function, which calls back into leaf. Each of top
+    // and middle stores its argument before making its call, and leaf traps on zero.
+    let store_arg = |address| {
+        vec![
+            Instr::I32Const(address),
+            Instr::LocalGet(0),
+            Instr::Memory(Opcode::I32Store, 2, 0),
+        ]
+    };
+    let call_and_add_one = |func_idx| {
+        vec![
+            Instr::LocalGet(0),
+            Instr::Call(func_idx),
+            Instr::I32Const(1),
+            Instr::Op(Opcode::I32Add),
+        ]
+    };
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .import_func("env", "host", 0)
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Eqz),
+                Instr::If(BlockType::None),
+                Instr::Unreachable,
+                Instr::End,
+                Instr::LocalGet(0),
+            ],
+        )
+        .add_function(0, vec![], [store_arg(8), call_and_add_one(0)].concat())
+        .add_function(0, vec![], [store_arg(16), call_and_add_one(2)].concat())
+        .add_memory(1, None)
+        .export_func("leaf", 1)
+        .export_func("top", 3)
+        .export_memory("memory", 0)
+        .build();
+
+    // The host function passes on whatever the call back in gave it, unless it's told to fail
+    // itself, and keeps the kind of any trap it saw
+    let fail_in_host = Rc::new(Cell::new(false));
+    let seen = Rc::new(RefCell::new(None));
+    let (fail, seen_by_host) = (fail_in_host.clone(), seen.clone());
+    let host = HostFn {
+        func_type: FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+        call: Box::new(move |caller, args| {
+            if fail.get() {
+                return Err(anyhow!("host function failed"));
+            }
+            let result = caller.invoke_export("leaf", args);
+            if let Err(error) = &result {
+                *seen_by_host.borrow_mut() = error.downcast_ref::<Trap>().map(Trap::kind);
+            }
+            result
+        }),
+    };
+    let resolver = HostFunctionResolver {
+        function: Rc::new(RefCell::new(Callable::from_host(Rc::new(host)))),
+    };
+    let mut instance = core::Module::new(raw).instantiate(&resolver)?;
+    let memory = instance
+        .exports
+        .get("memory")
+        .and_then(|e| e.as_memory())
+        .unwrap()
+        .clone();
+    let stored = |address: usize| memory.borrow().data()[address..address + 4].to_vec();
+
+    // The trap comes out of leaf, through the host function and both wasm frames, unchanged
+    memory.borrow_mut().data_mut()[8..20].copy_from_slice(&[0xff; 12]);
+    let error = instance.invoke_export("top", &[0_i32.into()]).unwrap_err();
+    let trap = error.downcast_ref::<Trap>().unwrap();
+    assert_eq!(trap.kind(), TrapKind::Unreachable);
+    assert_eq!(trap.origin(), TrapOrigin::Guest);
+    assert_eq!(*seen.borrow(), Some(TrapKind::Unreachable));
+
+    // The stores made on the way in stay put
+    assert_eq!(stored(8), [0; 4]);
+    assert_eq!(stored(16), [0; 4]);
+
+    // An error the host function makes itself isn't turned into a trap or wrapped in anything
+    fail_in_host.set(true);
+    let error = instance.invoke_export("top", &[5_i32.into()]).unwrap_err();
+    assert!(error.downcast_ref::<Trap>().is_none());
+    assert_eq!(format!("{:#}", error), "host function failed");
+    assert_eq!(stored(8), [5, 0, 0, 0]);
+
+    // And the next call runs as normal, with nothing left over on the stack from the others
+    fail_in_host.set(false);
+    let results = instance.invoke_export("top", &[7_i32.into()])?;
+    assert_eq!(results, [9_i32.into()]);
+    assert_eq!(
+        instance.invoke_export("leaf", &[3_i32.into()])?,
+        [3_i32.into()]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_imported_start_function() -> Result<()> {
+    let calls = Rc::new(Cell::new(0));
+    let instantiate = |func_type: FuncType, fail: bool, defer_start: bool| {
+        let counted = calls.clone();
+        let host = HostFn {
+            func_type: func_type.clone(),
+            call: Box::new(move |_, _| {
+                counted.set(counted.get() + 1);
+                if fail {
+                    Err(Trap::host("not ready").into())
+                } else {
+                    Ok(vec![])
+                }
+            }),
+        };
+        let resolver = HostFunctionResolver {
+            function: Rc::new(RefCell::new(Callable::from_host(Rc::new(host)))),
+        };
+        let raw = RawModuleBuilder::new()
+            .add_type(func_type)
+            .import_func("env", "init", 0)
+            .start(0)
+            .build();
+        let options = core::InstanceOptions {
+            defer_start,
+            ..Default::default()
+        };
+        core::Module::new(raw).instantiate_with_options(&resolver, &options)
+    };
+    let no_args = FuncType::new(vec![], vec![]);
+
+    instantiate(no_args.clone(), false, false)?;
+    assert_eq!(calls.get(), 1);
+
+    // Its errors are the start function's
+    let error = instantiate(no_args.clone(), true, false).unwrap_err();
+    assert_eq!(calls.get(), 2);
+    assert_eq!(
+        error.to_string(),
+        "Start function func 0 (imported from env::init) failed"
+    );
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(|trap| trap.message()),
+        Some(Some("not ready"))
+    );
+
+    // Deferring it works the same way as for a defined one
+    let mut instance = instantiate(no_args.clone(), false, true)?;
+    assert_eq!(calls.get(), 2);
+    assert!(instance.has_pending_start());
+    instance.run_start()?;
+    instance.run_start()?;
+    assert_eq!(calls.get(), 3);
+    assert!(!instance.has_pending_start());
+
+    let mut instance = instantiate(no_args, true, true)?;
+    let error = instance.run_start().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Start function func 0 (imported from env::init) failed"
+    );
+
+    // It's the import's type that's checked, and it's checked before anything is called
+    let error = instantiate(FuncType::new(vec![ValueType::I32], vec![]), false, false).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Start function func 0 (imported from env::init) has type (i32) -> (), but it must take \
+         and return nothing"
+    );
+    assert_eq!(calls.get(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_host_caller() -> Result<()> {
+    // The host function is passed a buffer in memory, writes it back reversed, and calls back
+    // into the guest with the global, which it then sets to what that returned
+    let resolver = add_host_fn_resolver(|caller, args| {
+        let (ptr, len) = (u32::try_from(args[0])?, u32::try_from(args[1])?);
+        let mut buffer = vec![0; len as usize];
+        caller.read_memory(0, ptr as usize, &mut buffer)?;
+        buffer.reverse();
+        caller.write_memory(0, 16, &buffer)?;
+        assert_eq!(caller.memory(0)?.borrow().current_size(), 1);
+
+        let value = caller.global_value(0)?;
+        let doubled = caller.invoke_export("double", &[value])?;
+        caller.set_global_value(0, doubled[0])?;
+        Ok(vec![doubled[0]])
+    });
+    let call_host = [Instr::I32Const(0), Instr::I32Const(5)];
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(
+            vec![ValueType::I32, ValueType::I32],
+            vec![ValueType::I32],
+        ))
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .import_func("env", "host", 0)
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        .add_function(2, vec![], [&call_host[..], &[Instr::Call(0)]].concat())
+        .add_function(
+            2,
+            vec![],
+            [
+                &call_host[..],
+                &[Instr::I32Const(0), Instr::CallIndirect(0)],
+            ]
+            .concat(),
+        )
+        .add_memory(1, None)
+        .add_data(0, vec![Instr::I32Const(0)], b"hello".to_vec())
+        .add_table(1, None)
+        .add_elem(0, vec![Instr::I32Const(0)], vec![0])
+        .add_global(
+            GlobalType::new(ValueType::I32, MutableType::Var),
+            vec![Instr::I32Const(21)],
+        )
+        .export_func("double", 1)
+        .export_func("run", 2)
+        .export_func("run_indirect", 3)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(&resolver)?;
+
+    // Both a direct and an indirect call leave the instance free for the host to use
+    assert_eq!(instance.invoke_export("run", &[])?, [42_i32.into()]);
+    assert_eq!(
+        instance.invoke_export("run_indirect", &[])?,
+        [84_i32.into()]
+    );
+    let mut reversed = [0; 5];
+    instance.memories[0].borrow().get_data(16, &mut reversed)?;
+    assert_eq!(&reversed, b"olleh");
+    assert_eq!(*instance.globals[0].borrow().get_value(), 84_i32.into());
+
+    // Called from outside of wasm there's no instance to get at
+    let host = resolver.function.borrow().clone();
+    let error = match host {
+        Callable::Host(host) => host.call(&[0_i32.into(), 5_i32.into()]).unwrap_err(),
+        _ => unreachable!(),
+    };
+    assert_eq!(
+        error.to_string(),
+        "The host function wasn't called from an instance"
+    );
+
+    Ok(())
+}
+
+// Returns the length of the name a host handle refers to
+#[derive(Debug)]
+struct HostNameLen {
+    func_type: FuncType,
+    names: Rc<RefCell<ExternRefTable<Rc<String>>>>,
+}
+
+impl HostCallable for HostNameLen {
+    fn func_type(&self) -> &FuncType {
+        &self.func_type
+    }
+
+    fn call(&self, args: &[StackEntry]) -> Result<Vec<StackEntry>> {
+        let handle = ExternRef::try_from(args[0])?;
+        let len = self.names.borrow().lookup(&handle)?.len();
+        Ok(vec![(len as u32).into()])
+    }
+}
+
+#[test]
+fn test_extern_ref_handles() -> Result<()> {
+    // The guest keeps the handle in a global and passes it back to the host when asked
+    let module = core::Module::new(
+        RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![ValueType::I64], vec![ValueType::I32]))
+            .add_type(FuncType::new(vec![ValueType::I64], vec![]))
+            .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+            .import_func("env", "name_len", 0)
+            .add_global(
+                GlobalType::new(ValueType::I64, MutableType::Var),
+                vec![Instr::I64Const(0)],
+            )
+            .add_function(1, vec![], vec![Instr::LocalGet(0), Instr::GlobalSet(0)])
+            .add_function(2, vec![], vec![Instr::GlobalGet(0), Instr::Call(0)])
+            .export_func("keep", 1)
+            .export_func("name_len", 2)
+            .build(),
+    );
+
+    let names = Rc::new(RefCell::new(ExternRefTable::new()));
+    let host = HostNameLen {
+        func_type: FuncType::new(vec![ValueType::I64], vec![ValueType::I32]),
+        names: names.clone(),
+    };
+    let resolver = HostFunctionResolver {
+        function: Rc::new(RefCell::new(Callable::from_host(Rc::new(host)))),
+    };
+    let mut instance = module.instantiate(&resolver)?;
+
+    let alice = Rc::new("alice".to_string());
+    let handle = names.borrow_mut().insert(alice.clone());
+    instance.invoke_export("keep", &[handle.into()])?;
+    assert_eq!(instance.invoke_export("name_len", &[])?, [5_u32.into()]);
+
+    // Once it's removed, the guest's copy is stale, even though the slot has been used again
+    names.borrow_mut().remove(&handle);
+    let bob = names.borrow_mut().insert(Rc::new("bob".to_string()));
+    let error = instance.invoke_export("name_len", &[]).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ExternRefError>(),
+        Some(&ExternRefError::Stale(handle))
+    );
+
+    // As is one from another table, or one that the guest made up
+    let foreign = ExternRefTable::new().insert(alice.clone());
+    instance.invoke_export("keep", &[foreign.into()])?;
+    let error = instance.invoke_export("name_len", &[]).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ExternRefError>(),
+        Some(&ExternRefError::Foreign(foreign))
+    );
+    instance.invoke_export("keep", &[0_u64.into()])?;
+    let error = instance.invoke_export("name_len", &[]).unwrap_err();
+    assert_eq!(format!("{}", error), "Null host handle");
+
+    instance.invoke_export("keep", &[bob.into()])?;
+    assert_eq!(instance.invoke_export("name_len", &[])?, [3_u32.into()]);
+
+    // What's left in the table goes with the instance, once the host's own references to it have
+    // gone
+    let alice_again = names.borrow_mut().insert(alice.clone());
+    assert_eq!(Rc::strong_count(&alice), 2);
+    drop(names);
+    drop(resolver);
+    assert_eq!(Rc::strong_count(&alice), 2);
+    drop(instance);
+    assert_eq!(Rc::strong_count(&alice), 1);
+    assert!(!alice_again.is_null());
+
+    Ok(())
+}
+
+#[test]
+fn test_call_observer() -> Result<()> {
+    use core::{CallOutcome, TraceEventKind, TraceRecorder};
+
+    fn calls(recorder: &TraceRecorder) -> Vec<(Option<usize>, TraceEventKind)> {
+        let events = recorder.events();
+        assert!(events.windows(2).all(|pair| pair[0].time <= pair[1].time));
+        events.iter().map(|e| (e.func_idx, e.kind)).collect()
+    }
+
+    let recorder = Rc::new(TraceRecorder::new());
+    let (_, mut instance) = instantiate_add_one(HostAdd::new())?;
+    instance.set_call_observer(Some(recorder.clone()));
+    instance.invoke_export("add_one", &[41_i32.into()])?;
+    assert_eq!(
+        calls(&recorder),
+        vec![
+            (Some(1), TraceEventKind::Enter { is_host: false }),
+            (Some(0), TraceEventKind::Enter { is_host: true }),
+            (Some(0), TraceEventKind::Exit(CallOutcome::Returned)),
+            (Some(1), TraceEventKind::Exit(CallOutcome::Returned)),
+        ]
+    );
+
+    let trace = recorder.to_chrome_trace(Some(&make_add_one_module()));
+    assert!(trace.contains("\"name\":\"func 0 (imported from env::add)\",\"cat\":\"host\""));
+
+    // Every frame the trap unwinds still gets its exit
+    let mut trapping = HostAdd::new();
+    trapping.trap = true;
+    let (_, mut instance) = instantiate_add_one(trapping)?;
+    instance.set_call_observer(Some(recorder.clone()));
+    recorder.clear();
+    instance
+        .invoke_export("add_one", &[1_i32.into()])
+        .unwrap_err();
+    assert_eq!(
+        calls(&recorder),
+        vec![
+            (Some(1), TraceEventKind::Enter { is_host: false }),
+            (Some(0), TraceEventKind::Enter { is_host: true }),
+            (Some(0), TraceEventKind::Exit(CallOutcome::Trapped)),
+            (Some(1), TraceEventKind::Exit(CallOutcome::Trapped)),
+        ]
+    );
+
+    // Nothing is told once the observer is taken away
+    instance.set_call_observer(None);
+    recorder.clear();
+    instance
+        .invoke_export("add_one", &[1_i32.into()])
+        .unwrap_err();
+    assert!(recorder.events().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_record_and_replay_host_calls() -> Result<()> {
+    use wasm::replay::{HostCallOutcome, HostCallTrace, RecordingResolver, ReplayResolver};
+
+    let host = Rc::new(HostAdd::new());
+    let recorder = RecordingResolver::new(HostFunctionResolver {
+        function: Rc::new(RefCell::new(Callable::from_host(host.clone()))),
+    });
+    let mut instance = make_add_one_module().instantiate(&recorder)?;
+    instance.invoke_export("add_one", &[41_i32.into()])?;
+    instance.invoke_export("add_one", &[(-1_i32).into()])?;
+
+    let trace = recorder.trace();
+    assert_eq!(trace.calls.len(), 2);
+    assert_eq!(
+        trace.calls[0].outcome,
+        HostCallOutcome::Returned(vec![42_i32.into()])
+    );
+
+    // The replay doesn't call the host at all
+    let trace = HostCallTrace::from_text(&trace.to_text())?;
+    let replayer = ReplayResolver::new(core::EmptyResolver {}, trace);
+    let mut instance = make_add_one_module().instantiate(&replayer)?;
+    assert_eq!(
+        instance.invoke_export("add_one", &[41_i32.into()])?,
+        [42_i32.into()]
+    );
+    assert_eq!(replayer.remaining(), 1);
+
+    // A call that isn't the one that was recorded is an error that says how they differ
+    let error = instance
+        .invoke_export("add_one", &[5_i32.into()])
+        .unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "Host call 1 diverged from the recording
+  expected: env::add(i32:4294967295, i32:1)
+  actual:   env::add(i32:5, i32:1)"
+    );
+
+    instance.invoke_export("add_one", &[(-1_i32).into()])?;
+    let error = instance
+        .invoke_export("add_one", &[(-1_i32).into()])
+        .unwrap_err();
+    assert!(format!("{}", error).starts_with("Host call 2 wasn't recorded"));
+    assert_eq!(host.calls.borrow().len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_index_space_boundaries() -> Result<()> {
+    // The test module only imports a global
+    let module = core::Module::load_module_from_path("../test_app/test.wasm")?;
+    assert_eq!(module.start_func_index(), Some(1));
+    assert_eq!(module.num_imported_functions(), 0);
+    assert_eq!(module.num_defined_functions(), 2);
+    assert_eq!(module.num_imported_tables(), 0);
+    assert_eq!(module.num_defined_tables(), 1);
+    assert_eq!(module.num_imported_memories(), 0);
+    assert_eq!(module.num_defined_memories(), 1);
+    assert_eq!(module.num_imported_globals(), 1);
+    assert_eq!(module.num_defined_globals(), 2);
+    assert_eq!(module.describe_func(0), "func 0 (defined)");
+    assert_eq!(module.describe_func(2), "func 2 (out of range)");
+
+    // Imported functions come before the defined ones
+    let module = make_add_one_module();
+    assert_eq!(module.start_func_index(), None);
+    assert_eq!(module.num_imported_functions(), 1);
+    assert_eq!(module.num_defined_functions(), 1);
+    assert_eq!(module.num_imported_globals(), 0);
+    assert_eq!(module.num_defined_globals(), 0);
+    assert_eq!(module.describe_func(0), "func 0 (imported from env::add)");
+    assert_eq!(module.describe_func(1), "func 1 (defined)");
+
+    // And a failing start function says which one it was
+    let module = core::Module::new(RawModule::new(
+        vec![FuncType::new(vec![], vec![])],
+        vec![0],
+        vec![Func::new(vec![], Expr::new(vec![0x00, 0x0b]))],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        Some(0),
+        vec![],
+        vec![],
+    ));
+    let error = module.instantiate(&TestResolver::new()).unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "Start function func 0 (defined) failed"
+    );
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(|t| t.kind()),
+        Some(TrapKind::Unreachable)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_function_import_type_mismatch() -> Result<()> {
+    let mut host = HostAdd::new();
+    host.func_type = FuncType::new(vec![ValueType::I32], vec![ValueType::I64]);
+
+    let error = instantiate_add_one(host).err().unwrap();
+    let import_error = error.downcast_ref::<ImportError>().unwrap();
+    assert_eq!(import_error.module, "env");
+    assert_eq!(import_error.field, "add");
+    assert_eq!(import_error.kind, ExportKind::Function);
+    match &import_error.reason {
+        ImportErrorReason::FunctionTypeMismatch { expected, provided } => {
+            assert_eq!(format!("{}", expected), "(i32, i32) -> i32");
+            assert_eq!(format!("{}", provided), "(i32) -> i64");
+        }
+        other => panic!("Expected a function type mismatch, got {:?}", other),
+    }
+    assert_eq!(
+        format!("{}", error),
+        "import `env`::`add` (function): declared as (i32, i32) -> i32, but the resolver provided \
+         (i32) -> i64"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_function_import_checked_at_instantiation() {
+    let i32_to_i32 = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+    let i64_to_i64 = FuncType::new(vec![ValueType::I64], vec![ValueType::I64]);
+
+    let mut host = HostAdd::new();
+    host.func_type = i32_to_i32.clone();
+    let host = Rc::new(host);
+    let resolver = HostFunctionResolver {
+        function: Rc::new(RefCell::new(Callable::from_host(host.clone()))),
+    };
+
+    // The function is never called, so the mismatch can only have been found while linking
+    let raw = RawModuleBuilder::new()
+        .add_type(i64_to_i64.clone())
+        .import_func("env", "double", 0)
+        .add_function(0, vec![], vec![Instr::LocalGet(0), Instr::Call(0)])
+        .export_func("double", 1)
+        .build();
+    let error = core::Module::new(raw).instantiate(&resolver).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ImportError>().map(|e| &e.reason),
+        Some(&ImportErrorReason::FunctionTypeMismatch {
+            expected: i64_to_i64,
+            provided: i32_to_i32,
+        })
+    );
+    assert!(host.calls.borrow().is_empty());
+}
+
+#[test]
+fn test_import_errors() -> Result<()> {
+    // The resolver's own error is kept as the cause
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .import_func("env", "log", 0)
+        .build();
+    let error = core::Module::new(raw)
+        .instantiate(core::EmptyResolver::instance())
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ImportError>().map(|e| &e.reason),
+        Some(&ImportErrorReason::Unresolved)
+    );
+    assert_eq!(
+        format!("{:#}", error),
+        "import `env`::`log` (function): the resolver couldn't provide it: Imported function \
+         env:log not found"
+    );
+
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .import_func("env", "log", 3)
+        .build();
+    let error = core::Module::new(raw)
+        .instantiate(core::EmptyResolver::instance())
+        .unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "import `env`::`log` (function): type index 3 is out of range, as the module's type \
+         count is 1"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_link_through_externs() -> Result<()> {
+    use core::{Extern, ExternType, ImportObject, Resolver};
+
+    let i32_type = GlobalType::new(ValueType::I32, MutableType::Const);
+    let provider = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        .add_memory(1, None)
+        .add_global(i32_type.clone(), vec![Instr::I32Const(40)])
+        .export_func("add_one", 0)
+        .export_memory("memory", 0)
+        .export_global("forty", 0)
+        .build();
+    let provider = core::Module::new(provider).instantiate(core::EmptyResolver::instance())?;
+
+    let mut imports = ImportObject::new();
+    imports.define_instance("provider", &provider);
+    let memory = Rc::new(RefCell::new(Memory::new(MemType::new(Limits::new(
+        1, None,
+    )))));
+    imports.define("host", "scratch", memory.clone());
+
+    // An extern knows its type, with the size a memory has now as its minimum
+    assert_eq!(
+        imports.get("provider", "forty").unwrap().ty(),
+        ExternType::Global(i32_type.clone())
+    );
+    assert_eq!(
+        imports.get("host", "scratch").unwrap().ty(),
+        ExternType::Memory(MemType::new(Limits::new(1, None)))
+    );
+
+    // Stores add_one(forty) in the provider's memory and reads it back
+    let user = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .import_func("provider", "add_one", 0)
+        .import_memory("provider", "memory", 1, None)
+        .import_global("provider", "forty", i32_type.clone())
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::I32Const(8),
+                Instr::GlobalGet(0),
+                Instr::Call(0),
+                Instr::Memory(Opcode::I32Store, 2, 0),
+                Instr::I32Const(8),
+                Instr::Memory(Opcode::I32Load, 2, 0),
+            ],
+        )
+        .export_func("run", 1)
+        .build();
+    let mut user = core::Module::new(user).instantiate(&imports)?;
+    assert_eq!(user.invoke_export("run", &[])?, [41_i32.into()]);
+    let provided_memory = provider.exports["memory"].as_memory().unwrap();
+    assert!(Rc::ptr_eq(provided_memory, &user.memories[0]));
+    assert_eq!(provided_memory.borrow().read_i32(8)?, 41);
+
+    // What's defined is handed out whatever kind it is, and the instance says when it's the wrong
+    // kind for the import
+    let mismatched = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .import_func("host", "scratch", 0)
+        .build();
+    let error = core::Module::new(mismatched)
+        .instantiate(&imports)
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ImportError>().map(|e| &e.reason),
+        Some(&ImportErrorReason::KindMismatch {
+            provided: ExportKind::Memory
+        })
+    );
+    assert_eq!(
+        error.to_string(),
+        "import `host`::`scratch` (function): the resolver provided a memory instead"
+    );
+
+    // The unified method goes to the one for the kind for resolvers that don't provide it
+    let global_type = ExternType::Global(i32_type);
+    let error = core::EmptyResolver::instance()
+        .resolve("env", "zero", &global_type)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Imported global env:zero not found");
+    let value = TestResolver::new().resolve("test", "zero", &global_type)?;
+    assert_eq!(value.kind(), ExportKind::Global);
+    match imports.resolve("provider", "add_one", &global_type)? {
+        Extern::Func(function) => assert!(Rc::ptr_eq(&function, &provider.functions[0])),
+        value => panic!("Expected a function, got {:?}", value),
+    }
+
+    Ok(())
+}
+
+// A dylink.0 section saying how much memory and how many table slots the module needs, what it
+// needs loaded first, and which of its imports are weak. Every number fits in one LEB byte.
+fn dylink_section(
+    memory_size: u8,
+    table_size: u8,
+    needed: &[&str],
+    weak_imports: &[(&str, &str)],
+) -> Vec<u8> {
+    fn name(bytes: &mut Vec<u8>, name: &str) {
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    fn subsection(section: &mut Vec<u8>, id: u8, body: Vec<u8>) {
+        section.push(id);
+        section.push(body.len() as u8);
+        section.extend(body);
+    }
+
+    let mut section = Vec::new();
+    subsection(&mut section, 1, vec![memory_size, 2, table_size, 0]);
+    let mut body = vec![needed.len() as u8];
+    needed.iter().for_each(|needed| name(&mut body, needed));
+    subsection(&mut section, 2, body);
+    let mut body = vec![weak_imports.len() as u8];
+    for (mod_name, field) in weak_imports {
+        name(&mut body, mod_name);
+        name(&mut body, field);
+        body.push(core::SYMBOL_WEAK as u8);
+    }
+    subsection(&mut section, 4, body);
+    // One the linker doesn't know, which is skipped
+    subsection(&mut section, 9, vec![1, 2, 3]);
+    section
+}
+
+// Reads the module back from its bytes with the section in front, as the toolchain puts it
+fn with_dylink(mut raw: RawModule, dylink: Vec<u8>) -> Result<core::Module> {
+    raw.custom_sections_mut().push(core::CustomSection::new(
+        core::DYLINK_SECTION.to_string(),
+        dylink,
+        None,
+    ));
+    core::Module::load_module_from_bytes(&raw.encode())
+}
+
+// The side module, as clang -fPIC -shared would lay it out: its data and function pointers are at
+// __memory_base and __table_base, and what it takes from the main module is either called
+// directly or reached through the GOT
+fn make_side_module() -> Result<core::Module> {
+    let i32_type = GlobalType::new(ValueType::I32, MutableType::Const);
+    let got_type = GlobalType::new(ValueType::I32, MutableType::Var);
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .import_func("env", "main_helper", 1)
+        .import_memory("env", "memory", 1, None)
+        .import_table("env", "__indirect_function_table", 1, None)
+        .import_global("env", "__memory_base", i32_type.clone())
+        .import_global("env", "__table_base", i32_type.clone())
+        .import_global("GOT.mem", "main_value", got_type.clone())
+        .import_global("GOT.func", "main_triple", got_type.clone())
+        .import_global("GOT.mem", "not_linked", got_type)
+        // __wasm_apply_data_relocs points side_ptr at side_value
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::GlobalGet(0),
+                Instr::GlobalGet(0),
+                Instr::Memory(Opcode::I32Store, 2, 4),
+            ],
+        )
+        // side_double
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        // side_entry, which is *side_ptr + main_helper(x) + main_triple(x) through a function
+        // pointer + main_value + (&not_linked == 0)
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::GlobalGet(0),
+                Instr::Memory(Opcode::I32Load, 2, 4),
+                Instr::Memory(Opcode::I32Load, 2, 0),
+                Instr::LocalGet(0),
+                Instr::Call(0),
+                Instr::Op(Opcode::I32Add),
+                Instr::LocalGet(0),
+                Instr::GlobalGet(3),
+                Instr::CallIndirect(1),
+                Instr::Op(Opcode::I32Add),
+                Instr::GlobalGet(2),
+                Instr::Memory(Opcode::I32Load, 2, 0),
+                Instr::Op(Opcode::I32Add),
+                Instr::GlobalGet(4),
+                Instr::Op(Opcode::I32Eqz),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        // side_double_ptr
+        .add_function(2, vec![], vec![Instr::GlobalGet(1)])
+        .add_global(i32_type, vec![Instr::I32Const(0)])
+        .add_elem(0, vec![Instr::GlobalGet(1)], vec![2])
+        .add_data(0, vec![Instr::GlobalGet(0)], vec![40, 0, 0, 0, 0, 0, 0, 0])
+        .export_func("__wasm_apply_data_relocs", 1)
+        .export_func("side_double", 2)
+        .export_func("side_entry", 3)
+        .export_func("side_double_ptr", 4)
+        .export_global("side_value", 5)
+        .build();
+    with_dylink(raw, dylink_section(8, 1, &[], &[("GOT.mem", "not_linked")]))
+}
+
+// The main module, which needs the side module, and calls it both directly and through the
+// function pointer it hands back
+fn make_main_module(needed: &[&str]) -> Result<core::Module> {
+    let i32_type = GlobalType::new(ValueType::I32, MutableType::Const);
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(
+            vec![ValueType::I32, ValueType::I32],
+            vec![ValueType::I32],
+        ))
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .import_func("env", "side_entry", 1)
+        .import_func("env", "side_double_ptr", 2)
+        .import_func("env", "host_add", 0)
+        .import_memory("env", "memory", 1, None)
+        .import_table("env", "__indirect_function_table", 1, None)
+        .import_global("env", "__memory_base", i32_type.clone())
+        .import_global(
+            "env",
+            "__stack_pointer",
+            GlobalType::new(ValueType::I32, MutableType::Var),
+        )
+        // main_helper
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::I32Const(100),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        // main_triple
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::I32Const(3),
+                Instr::Op(Opcode::I32Mul),
+            ],
+        )
+        // run, which is host_add(side_entry(x), side_double(x))
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::Call(0),
+                Instr::LocalGet(0),
+                Instr::Call(1),
+                Instr::CallIndirect(1),
+                Instr::Call(2),
+            ],
+        )
+        .add_global(i32_type, vec![Instr::I32Const(0)])
+        .add_data(0, vec![Instr::GlobalGet(0)], vec![5, 0, 0, 0])
+        .export_func("main_helper", 3)
+        .export_func("main_triple", 4)
+        .export_func("run", 5)
+        .export_global("main_value", 2)
+        .build();
+    with_dylink(raw, dylink_section(4, 0, needed, &[]))
+}
+
+#[test]
+fn test_dylink_section() -> Result<()> {
+    let module = make_side_module()?;
+    let dylink = module.raw_module().dylink().unwrap();
+    assert_eq!((dylink.memory_size, dylink.memory_alignment), (8, 2),);
+    assert_eq!((dylink.table_size, dylink.table_alignment), (1, 0));
+    assert!(dylink.needed.is_empty());
+    assert!(dylink.is_weak_import("GOT.mem", "not_linked"));
+    assert!(!dylink.is_weak_import("GOT.mem", "main_value"));
+    assert_eq!(
+        make_main_module(&["libside.so"])?
+            .raw_module()
+            .dylink()
+            .unwrap()
+            .needed,
+        ["libside.so"]
+    );
+
+    // It's kept, so the module is written back out with it
+    let bytes = module.raw_module().encode();
+    let raw = read_raw_module(&bytes)?;
+    assert_eq!(raw.dylink(), Some(dylink));
+    assert_eq!(raw.custom_sections()[0].name(), core::DYLINK_SECTION);
+
+    // One that doesn't parse leaves the module without it
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_function(0, vec![], vec![])
+        .build();
+    let module = with_dylink(raw, vec![1, 9, 0])?;
+    assert!(module.raw_module().dylink().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_dynamic_linker() -> Result<()> {
+    let host_add = Rc::new(HostAdd::new());
+    let mut linker = core::DynamicLinker::new(core::DynamicLinkerConfig::default())?;
+    linker
+        .define(
+            "env",
+            "host_add",
+            Rc::new(RefCell::new(Callable::from_host(host_add.clone()))),
+        )
+        .add_module("libside.so", make_side_module()?)
+        .add_module("main", make_main_module(&["libside.so"])?);
+    linker.load("main")?;
+
+    // The side module is loaded first, as main needs it, and each module's data goes after the
+    // stack, which is at 1024 and 64KiB long
+    let modules: Vec<_> = linker
+        .modules()
+        .iter()
+        .map(|module| (module.name.as_str(), module.memory_base, module.table_base))
+        .collect();
+    assert_eq!(modules, [("libside.so", 66560, 1), ("main", 66568, 2)]);
+    assert_eq!(linker.data_end(), 66572);
+    assert_eq!(
+        *linker.stack_pointer().borrow().get_value(),
+        66560_u32.into()
+    );
+    assert_eq!(linker.memory().borrow().size_pages(), 2);
+
+    // The relocations have been applied, and the data symbols are exported at their addresses
+    let address = |name: &str| {
+        let exports = linker.exports();
+        let global = exports[name].as_global().unwrap().borrow();
+        u32::try_from(*global.get_value()).unwrap()
+    };
+    assert_eq!(address("side_value"), 66560);
+    assert_eq!(address("main_value"), 66568);
+    let memory = linker.memory();
+    assert_eq!(memory.borrow().read_u32(66564)?, 66560);
+    assert_eq!(memory.borrow().read_u32(66568)?, 5);
+
+    // main calls into the side module, which calls back into main directly and through the GOT,
+    // and main calls the function pointer the side module gives it. That's 40 + 101 + 3 + 5 + 1
+    // from side_entry, and 2 from side_double.
+    assert_eq!(linker.invoke("run", &[1_i32.into()])?, [152_i32.into()]);
+    assert_eq!(host_add.calls.borrow()[0], [150_i32.into(), 2_i32.into()]);
+    assert_eq!(
+        linker.invoke("side_entry", &[2_i32.into()])?,
+        [154_i32.into()]
+    );
+
+    // main_triple was given a slot of its own when its address was taken
+    let table = linker.table();
+    assert_eq!(table.borrow().len(), 3);
+    assert!(table.borrow()[0].is_none());
+
+    // An exported function runs on the module it's from, whoever calls it
+    let exports = linker.exports();
+    let side_double = exports["side_double"]
+        .as_function()
+        .unwrap()
+        .borrow()
+        .clone();
+    match side_double {
+        Callable::Host(host) => assert_eq!(host.call(&[21_i32.into()])?, [42_i32.into()]),
+        _ => panic!("Expected the function to be bound to its instance"),
+    }
+    drop(exports);
+
+    let error = linker.invoke("side_triple", &[]).unwrap_err();
+    assert_eq!(error.to_string(), "None of the modules export side_triple");
+
+    Ok(())
+}
+
+#[test]
+fn test_dynamic_linker_failures() -> Result<()> {
+    let new_linker = || core::DynamicLinker::new(core::DynamicLinkerConfig::default());
+
+    // What main needs has to have been added
+    let mut linker = new_linker()?;
+    linker.add_module("main", make_main_module(&["libside.so"])?);
+    let error = linker.load("main").unwrap_err();
+    assert_eq!(
+        format!("{:#}", error),
+        "main needs libside.so: There's no module called libside.so"
+    );
+    assert!(linker.modules().is_empty());
+
+    // A module has to have been built for dynamic linking
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_function(0, vec![], vec![])
+        .build();
+    linker.add_module("libside.so", core::Module::new(raw));
+    let error = linker.load("main").unwrap_err();
+    assert_eq!(
+        format!("{:#}", error),
+        "main needs libside.so: libside.so has no dylink.0 section, so it can't be linked dynamically"
+    );
+
+    // Without main, what the side module needs from it isn't there, and only the weak symbol can
+    // be left undefined. The failed load leaves nothing behind.
+    let mut linker = new_linker()?;
+    linker.add_module("libside.so", make_side_module()?);
+    let error = linker.load("libside.so").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Undefined symbol main_value, from GOT.mem"
+    );
+    assert!(linker.modules().is_empty());
+    assert!(linker.exports().is_empty());
+
+    // Loading main afterwards brings the side module in with it
+    linker.add_module("main", make_main_module(&["libside.so"])?);
+    linker.define(
+        "env",
+        "host_add",
+        Rc::new(RefCell::new(Callable::from_host(Rc::new(HostAdd::new())))),
+    );
+    linker.load("main")?;
+    assert_eq!(linker.modules().len(), 2);
+    assert_eq!(linker.invoke("run", &[1_i32.into()])?, [152_i32.into()]);
+
+    // This time the side module's GOT entries for main's symbols are filled in before the load
+    // fails, on host_add not being defined. Loading again puts both modules somewhere new, so
+    // what the first attempt filled in mustn't still be there, and the table slot it gave
+    // main_triple mustn't be handed out either, as it runs on the main module that was dropped.
+    let mut linker = new_linker()?;
+    linker
+        .add_module("libside.so", make_side_module()?)
+        .add_module("main", make_main_module(&["libside.so"])?);
+    let error = linker.load("main").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Undefined symbol host_add, which main imports"
+    );
+    assert!(linker.modules().is_empty());
+    let table = linker.table();
+    assert!((0..table.borrow().len()).all(|idx| table.borrow()[idx].is_none()));
+
+    linker.define(
+        "env",
+        "host_add",
+        Rc::new(RefCell::new(Callable::from_host(Rc::new(HostAdd::new())))),
+    );
+    linker.load("main")?;
+    // The first attempt's 12 bytes of data aren't reused, so the side module's 8 come after them
+    let main_base = linker.module("main").unwrap().memory_base;
+    assert_eq!(main_base, 66560 + 12 + 8);
+    let exports = linker.exports();
+    let main_value = exports["main_value"].as_global().unwrap().borrow();
+    assert_eq!(u32::try_from(*main_value.get_value())?, main_base);
+    drop(main_value);
+    drop(exports);
+    assert_eq!(linker.invoke("run", &[1_i32.into()])?, [152_i32.into()]);
+
+    Ok(())
+}
+
+// The main and side modules in test_app/dylink were built by llc and wasm-ld from C, with
+// build.sh there, so they're laid out the way a real toolchain lays them out
+#[test]
+fn test_dynamic_linker_toolchain_modules() -> Result<()> {
+    let load =
+        |name: &str| core::Module::load_module_from_path(&format!("../test_app/dylink/{}", name));
+    let mut linker = core::DynamicLinker::new(core::DynamicLinkerConfig::default())?;
+    linker
+        .add_module("side.wasm", load("side.wasm")?)
+        .add_module("main.wasm", load("main.wasm")?);
+    linker.load("main.wasm")?;
+
+    // side.wasm is main.wasm's needed library, so it's loaded first. Each has four bytes of data
+    // and no function pointers of its own.
+    let modules: Vec<_> = linker
+        .modules()
+        .iter()
+        .map(|module| (module.name.as_str(), module.memory_base, module.table_base))
+        .collect();
+    assert_eq!(modules, [("side.wasm", 66560, 1), ("main.wasm", 66564, 1)]);
+    assert_eq!(linker.data_end(), 66568);
+
+    // main calls side_add, which adds 3 to side_counter and calls back into main_callback. That's
+    // 7 * 3 + 7 + 103 from side_add, and 103 more from main reading side_counter through its GOT.
+    assert_eq!(linker.invoke("run", &[3_i32.into()])?, [234_i32.into()]);
+    // side_add called from the host still calls back into main. 7 * 2 + 7 + 105.
+    assert_eq!(
+        linker.invoke("side_add", &[2_i32.into()])?,
+        [126_i32.into()]
+    );
+
+    let address = |name: &str| {
+        let exports = linker.exports();
+        let global = exports[name].as_global().unwrap().borrow();
+        u32::try_from(*global.get_value()).unwrap() as usize
+    };
+    let memory = linker.memory();
+    assert_eq!(memory.borrow().read_u32(address("side_counter"))?, 105);
+    assert_eq!(memory.borrow().read_u32(address("main_value"))?, 7);
+
+    // side_pick hands main a pointer to main_callback, which was given the first slot after the
+    // null one when the side module's GOT entry for it was filled in
+    assert_eq!(
+        linker.invoke("run_indirect", &[5_i32.into()])?,
+        [35_i32.into()]
+    );
+    assert_eq!(linker.table().borrow().len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_module_builder() -> Result<()> {
+    let i32_type = GlobalType::new(ValueType::I32, MutableType::Const);
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        // Sums 1..=n with a loop and returns the result through memory
+        .add_function(
+            0,
+            vec![ValueType::I32],
+            vec![
+                Instr::Block(BlockType::None),
+                Instr::Loop(BlockType::None),
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Eqz),
+                Instr::BrIf(1),
+                Instr::LocalGet(1),
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Add),
+                Instr::LocalSet(1),
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Sub),
+                Instr::LocalSet(0),
+                Instr::Br(0),
+                Instr::End,
+                Instr::End,
+                Instr::GlobalGet(0),
+                Instr::LocalGet(1),
+                Instr::Memory(Opcode::I32Store, 2, 0),
+                Instr::GlobalGet(0),
+                Instr::Memory(Opcode::I32Load, 2, 0),
+            ],
+        )
+        .add_function(1, vec![], vec![Instr::I32Const(10), Instr::Call(0)])
+        .add_memory(1, None)
+        .add_global(i32_type, vec![Instr::I32Const(1024)])
+        .export_func("sum", 0)
+        .export_func("run", 1)
+        .export_memory("memory", 0)
+        .build();
+
+    let mut instance = core::Module::new(raw).instantiate(&TestResolver::new())?;
+    assert_eq!(instance.invoke_export("run", &[])?, [55_i32.into()]);
+    assert_eq!(
+        instance.invoke_export("sum", &[100_i32.into()])?,
+        [5050_i32.into()]
+    );
+
+    let memory = instance.exports["memory"].as_memory().unwrap();
+    assert_eq!(memory.borrow().read_i32(1024)?, 5050);
+
+    // Raw bytes can be mixed in for anything that doesn't have its own variant
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![ValueType::I64]))
+        .add_function(0, vec![], vec![Instr::Raw(vec![0x42, 0x7f])])
+        .export_func("minus_one", 0)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(&TestResolver::new())?;
+    assert_eq!(instance.invoke_export("minus_one", &[])?, [(-1_i64).into()]);
+
+    Ok(())
+}
+
+fn read_raw_module(bytes: &[u8]) -> Result<RawModule> {
+    RawModule::read(&mut &bytes[..])
+}
+
+// Compares the parts of two modules that the encoder writes out
+fn assert_same_structure(a: &RawModule, b: &RawModule) {
+    assert_eq!(format!("{:?}", a.types()), format!("{:?}", b.types()));
+    assert_eq!(format!("{:?}", a.imports()), format!("{:?}", b.imports()));
+    assert_eq!(a.func_type_indices(), b.func_type_indices());
+    assert_eq!(format!("{:?}", a.funcs()), format!("{:?}", b.funcs()));
+    assert_eq!(format!("{:?}", a.tables()), format!("{:?}", b.tables()));
+    assert_eq!(format!("{:?}", a.mems()), format!("{:?}", b.mems()));
+    assert_eq!(format!("{:?}", a.globals()), format!("{:?}", b.globals()));
+    assert_eq!(format!("{:?}", a.exports()), format!("{:?}", b.exports()));
+    assert_eq!(a.start(), b.start());
+    assert_eq!(format!("{:?}", a.elems()), format!("{:?}", b.elems()));
+    assert_eq!(format!("{:?}", a.data()), format!("{:?}", b.data()));
+    assert_eq!(
+        format!("{:?}", a.custom_sections()),
+        format!("{:?}", b.custom_sections())
+    );
+}
+
+#[test]
+fn test_encode_round_trip() -> Result<()> {
+    let original = std::fs::read("../test_app/test.wasm")?;
+    let raw = read_raw_module(&original)?;
+
+    let encoded = raw.encode();
+    let decoded = read_raw_module(&encoded)?;
+    assert_same_structure(&raw, &decoded);
+
+    // The test module only uses minimal LEBs, so it comes back byte for byte
+    assert_eq!(encoded, original);
+    assert_eq!(decoded.encode(), encoded);
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_modified_module() -> Result<()> {
+    let mut raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .add_function(
+            0,
+            vec![],
+            vec![Instr::I32Const(0), Instr::Memory(Opcode::I32Load, 2, 0)],
+        )
+        .add_memory(1, Some(1))
+        .add_data(0, vec![Instr::I32Const(0)], vec![1, 0, 0, 0])
+        .export_func("load", 0)
+        .build();
+    raw.custom_sections_mut().push(core::CustomSection::new(
+        "extra".to_string(),
+        vec![1, 2, 3],
+        Some(core::SectionType::CodeSection),
+    ));
+
+    let decoded = read_raw_module(&raw.encode())?;
+    assert_same_structure(&raw, &decoded);
+
+    // Rename the export, patch the data and strip the custom sections
+    let mut raw = decoded;
+    raw.exports_mut()[0].nm = "load_first".to_string();
+    raw.data_mut()[0] = core::Data::new(0, Expr::new(vec![0x41, 0x00, 0x0b]), vec![42, 0, 0, 0]);
+    raw.custom_sections_mut().clear();
+
+    let encoded = raw.encode();
+    let module = core::Module::from_reader(&mut &encoded[..])?;
+    assert!(module.raw_module().custom_sections().is_empty());
+
+    let mut instance = module.instantiate(&TestResolver::new())?;
+    assert!(instance.invoke_export("load", &[]).is_err());
+    assert_eq!(instance.invoke_export("load_first", &[])?, [42_i32.into()]);
+
+    Ok(())
+}
+
+#[test]
+fn test_module_hashes() -> Result<()> {
+    let build = |value| {
+        RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+            .add_function(0, vec![], vec![Instr::I32Const(value)])
+            .export_func("value", 0)
+            .build()
+    };
+    let raw = build(1);
+    let bytes = raw.encode();
+    let read = read_raw_module(&bytes)?;
+    assert_eq!(read.content_hash(), raw.content_hash());
+    assert_eq!(read.code_hash(), raw.code_hash());
+    assert_ne!(read.content_hash(), build(2).content_hash());
+    assert_ne!(read.code_hash(), build(2).code_hash());
+
+    let module = core::Module::load_module_from_bytes(&bytes)?;
+    assert_eq!(module.content_hash(), read.content_hash());
+    assert_eq!(module.code_hash(), read.code_hash());
+    assert_eq!(format!("{}", module.code_hash()).len(), 16);
+
+    // Debug info changes what's in the module, but not its code
+    let mut with_names = read.clone();
+    with_names
+        .custom_sections_mut()
+        .push(core::CustomSection::new(
+            "name".to_string(),
+            vec![],
+            Some(core::SectionType::CodeSection),
+        ));
+    let with_names = read_raw_module(&with_names.encode())?;
+    assert_ne!(with_names.content_hash(), read.content_hash());
+    assert_eq!(with_names.code_hash(), read.code_hash());
+
+    // A section's size can be encoded in more bytes than it needs, and still hash the same
+    assert_eq!(bytes[8], u8::from(core::SectionType::TypeSection));
+    assert!(bytes[9] < 0x80);
+    let mut padded = bytes[..9].to_vec();
+    padded.extend_from_slice(&[bytes[9] | 0x80, 0x00]);
+    padded.extend_from_slice(&bytes[10..]);
+    let padded = read_raw_module(&padded)?;
+    assert_eq!(padded.content_hash(), read.content_hash());
+
+    // A module that's been changed since it was read hashes as it is now
+    let mut renamed = read.clone();
+    renamed.exports_mut()[0].nm = "other".to_string();
+    assert_ne!(renamed.content_hash(), read.content_hash());
+    assert_eq!(
+        renamed.content_hash(),
+        read_raw_module(&renamed.encode())?.content_hash()
+    );
+
+    // The hashes are FNV-1a over the stream hash_with gives, which can go to any other hash
+    let stream = |raw: &RawModule, sections| {
+        let mut stream = Vec::new();
+        raw.hash_with(sections, |bytes| stream.extend_from_slice(bytes));
+        stream
+    };
+    let fnv = |stream: &[u8]| {
+        let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+        for byte in stream {
+            hash = (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+        core::ModuleHash(hash)
+    };
+    let content = stream(&read, core::HashedSections::All);
+    assert_eq!(fnv(&content), read.content_hash());
+    assert_eq!(
+        fnv(&stream(&read, core::HashedSections::Code)),
+        read.code_hash()
+    );
+    assert_eq!(stream(&padded, core::HashedSections::All), content);
+    assert_ne!(stream(&with_names, core::HashedSections::All), content);
+    assert_eq!(
+        stream(&with_names, core::HashedSections::Code),
+        stream(&read, core::HashedSections::Code)
+    );
+
+    use std::hash::Hasher;
+    let std_hash = |raw: &RawModule| {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        raw.hash_with(core::HashedSections::Code, |bytes| hasher.write(bytes));
+        hasher.finish()
+    };
+    assert_eq!(std_hash(&with_names), std_hash(&read));
+    assert_ne!(std_hash(&build(2)), std_hash(&read));
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_section_handlers() -> Result<()> {
+    let mut raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_function(0, vec![], vec![])
+        .build();
+    let sections = vec![
+        ("sourceMappingURL", b"main.wasm.map".to_vec()),
+        ("asset", vec![7; 1000]),
+        // A name section that doesn't parse
+        ("name", vec![0xff]),
+        ("other", vec![1, 2, 3]),
+        ("broken", vec![]),
+    ];
+    for (name, bytes) in sections {
+        raw.custom_sections_mut().push(core::CustomSection::new(
+            name.to_string(),
+            bytes,
+            Some(core::SectionType::CodeSection),
+        ));
+    }
+    let bytes = raw.encode();
+
+    // With no handlers of its own it loads as it always has, keeping everything
+    let module = core::Module::load_module_from_bytes(&bytes)?;
+    assert_eq!(module.raw_module().custom_sections().len(), 5);
+
+    let mut source_map = String::new();
+    let mut asset = Vec::new();
+    let mut calls = Vec::new();
+    let mut handlers = CustomSectionHandlers::new();
+    handlers
+        .on_custom_section("sourceMappingURL", HandlerErrorPolicy::Fail, |bytes| {
+            source_map = String::from_utf8(bytes.to_vec())?;
+            Ok(())
+        })
+        .on_custom_section("asset", HandlerErrorPolicy::Fail, |bytes| {
+            asset.extend_from_slice(bytes);
+            Ok(())
+        })
+        .on_custom_section("broken", HandlerErrorPolicy::Warn, |_| {
+            Err(anyhow!("Nothing in it"))
+        })
+        .on_custom_section("broken", HandlerErrorPolicy::Warn, |_| {
+            calls.push("second");
+            Ok(())
+        });
+    let module = core::Module::load_module_with_handlers(&bytes, &mut handlers)?;
+    assert_eq!(
+        handlers.warnings(),
+        [
+            "Custom section handler for name at offset 0x429 failed: Unexpected end of input",
+            "Custom section handler for broken at offset 0x43c failed: Nothing in it",
+        ]
+        .iter()
+        .map(|warning| warning.to_string())
+        .collect::<Vec<_>>()
+        .as_slice()
+    );
+    drop(handlers);
+    assert_eq!(source_map, "main.wasm.map");
+    assert_eq!(asset, vec![7; 1000]);
+    assert_eq!(calls, ["second"]);
+    // The handled ones aren't kept, and the name section wasn't handled, just read
+    let kept: Vec<_> = module
+        .raw_module()
+        .custom_sections()
+        .iter()
+        .map(|section| section.name())
+        .collect();
+    assert_eq!(kept, ["name", "other"]);
+
+    // Or the unhandled ones can be skipped too
+    let mut handlers = CustomSectionHandlers::new();
+    handlers.unhandled(UnhandledSections::Skip);
+    let module = core::Module::load_module_with_handlers(&bytes, &mut handlers)?;
+    assert!(module.raw_module().custom_sections().is_empty());
+
+    // A handler that fails the load says which section it was
+    let mut handlers = CustomSectionHandlers::new();
+    handlers.on_custom_section("asset", HandlerErrorPolicy::Fail, |_| {
+        Err(anyhow!("Disk full"))
+    });
+    let error = core::Module::load_module_with_handlers(&bytes, &mut handlers).unwrap_err();
+    assert_eq!(
+        format!("{:#}", error),
+        "Custom section handler for asset at offset 0x38 failed: Disk full"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_section_iter() -> Result<()> {
+    let bytes = std::fs::read("../test_app/test.wasm")?;
+
+    // The sections cover the whole file after the header
+    let sections = SectionIter::new(&bytes[..])?.collect::<Result<Vec<_>>>()?;
+    let mut offset = 8;
+    for section in &sections {
+        assert_eq!(section.offset(), offset);
+        assert_eq!(
+            &bytes[section.payload_offset()..section.payload_offset() + section.payload().len()],
+            section.payload()
+        );
+        offset = section.payload_offset() + section.payload().len();
+    }
+    assert_eq!(offset, bytes.len());
+
+    let code = sections
+        .iter()
+        .find(|s| s.section_type() == Some(core::SectionType::CodeSection))
+        .unwrap();
+    assert_eq!(code.id(), 10);
+    assert!(code.custom_contents().is_err());
+
+    // Custom sections can be picked out without decoding anything else
+    let names: Vec<String> = sections
+        .iter()
+        .filter(|s| s.section_type() == Some(core::SectionType::CustomSection))
+        .map(|s| s.custom_contents().map(|(name, _)| name))
+        .collect::<Result<_>>()?;
+    let raw = read_raw_module(&bytes)?;
+    let expected: Vec<&str> = raw.custom_sections().iter().map(|c| c.name()).collect();
+    assert_eq!(names, expected);
+
+    // Errors say where the problem is
+    assert_eq!(
+        SectionIter::new(&bytes[..4]).err().unwrap().to_string(),
+        "Module is too short to have a header"
+    );
+    let truncated = &bytes[..code.payload_offset() + 1];
+    let error = SectionIter::new(truncated)?.find_map(|s| s.err()).unwrap();
+    assert_eq!(
+        error.to_string(),
+        format!("Failed to read section at offset 0x{:x}", code.offset())
+    );
+    assert_eq!(
+        format!("{:?}", read_raw_module(truncated).unwrap_err().root_cause()),
+        format!("{:?}", error.root_cause())
+    );
+
+    let mut unknown = bytes[..8].to_vec();
+    unknown.extend_from_slice(&[0x20, 0x01, 0x00]);
+    let section = SectionIter::new(&unknown[..])?.next().unwrap()?;
+    assert_eq!(section.id(), 0x20);
+    assert_eq!(section.section_type(), None);
+    assert_eq!(section.payload(), [0x00]);
+    assert_eq!(
+        read_raw_module(&unknown).unwrap_err().to_string(),
+        "Unknown section type 32 at offset 0x8"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_section_framing_errors() -> Result<()> {
+    let bytes = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .add_function(0, vec![], vec![Instr::I32Const(7)])
+        .export_func("seven", 0)
+        .build()
+        .encode();
+    let sections = SectionIter::new(&bytes[..])?.collect::<Result<Vec<_>>>()?;
+    let framing_error =
+        |error: &anyhow::Error| error.downcast_ref::<SectionFramingError>().cloned();
+
+    // Wherever the file is cut short, it's the section it was cut in that's reported
+    for section in &sections {
+        let end = section.payload_offset() + section.payload().len();
+        for &len in &[
+            section.offset() + 1,
+            section.payload_offset(),
+            section.payload_offset() + 1,
+            end - 1,
+        ] {
+            if len <= section.offset() || len >= end {
+                continue;
+            }
+            let expected = if len < section.payload_offset() {
+                SectionFramingError::TruncatedHeader {
+                    offset: section.offset(),
+                }
+            } else {
+                SectionFramingError::PastEndOfInput {
+                    id: section.id(),
+                    offset: section.offset(),
+                    declared: section.payload().len(),
+                    remaining: len - section.payload_offset(),
+                }
+            };
+            let truncated = &bytes[..len];
+            let error = read_raw_module(truncated).unwrap_err();
+            assert_eq!(framing_error(&error), Some(expected.clone()));
+            let error = SectionIter::new(truncated)?.find_map(|s| s.err()).unwrap();
+            assert_eq!(framing_error(&error), Some(expected));
+        }
+    }
+    let error = read_raw_module(&bytes[..bytes.len() - 1]).unwrap_err();
+    assert!(format!("{:#}", error).contains("claims"), "{:#}", error);
+
+    // The type section's size is in its second byte, and it has just the one type in it
+    let types = &sections[0];
+    assert_eq!(types.section_type(), Some(core::SectionType::TypeSection));
+    assert_eq!(types.payload_offset(), types.offset() + 2);
+    let size_at = types.offset() + 1;
+
+    // A size that's one too big leaves a byte over at the end of the section
+    let mut too_big = bytes.clone();
+    too_big[size_at] += 1;
+    too_big.insert(types.payload_offset() + types.payload().len(), 0x00);
+    let error = read_raw_module(&too_big).unwrap_err();
+    assert_eq!(
+        framing_error(&error),
+        Some(SectionFramingError::ContentsTooShort {
+            id: types.id(),
+            offset: types.offset(),
+            declared: types.payload().len() + 1,
+            used: types.payload().len(),
+        })
+    );
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "The type section at offset 0x{:x} claims {} bytes but its contents end 1 byte \
+             before that",
+            types.offset(),
+            types.payload().len() + 1
+        )
+    );
+
+    // and one that's too small cuts off the end of the type
+    let mut too_small = bytes.clone();
+    too_small[size_at] -= 1;
+    let error = read_raw_module(&too_small).unwrap_err();
+    assert_eq!(
+        framing_error(&error),
+        Some(SectionFramingError::ContentsTooLong {
+            id: types.id(),
+            offset: types.offset(),
+            declared: types.payload().len() - 1,
+            overrun: 1,
+        })
+    );
+
+    // Moving the function section in front of the type section leaves the type section out of
+    // order, rather than the function section, since it's the one read second
+    let functions = &sections[1];
+    assert_eq!(
+        functions.section_type(),
+        Some(core::SectionType::FunctionSection)
+    );
+    let whole = |section: &wasm::reader::Section| {
+        &bytes[section.offset()..section.payload_offset() + section.payload().len()]
+    };
+    let mut swapped = bytes[..types.offset()].to_vec();
+    swapped.extend_from_slice(whole(functions));
+    let moved_types = swapped.len();
+    swapped.extend_from_slice(whole(types));
+    swapped.extend_from_slice(&bytes[functions.offset() + whole(functions).len()..]);
+    let error = read_raw_module(&swapped).unwrap_err();
+    assert_eq!(
+        framing_error(&error),
+        Some(SectionFramingError::OutOfOrder {
+            id: types.id(),
+            offset: moved_types,
+            after: functions.id(),
+        })
+    );
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "The type section at offset 0x{:x} comes after the function section, but has to \
+             come before it",
+            moved_types
+        )
+    );
+
+    Ok(())
+}
+
+// The bytes the guest's stores leave in memory, and the values its loads and constants give,
+// worked out by hand from the specification rather than by running anything, so a conversion that
+// used the host's byte order would fail this on a big endian host
+#[test]
+fn test_little_endian_known_answers() -> Result<()> {
+    let store = |address, value, opcode, align| {
+        vec![
+            Instr::I32Const(address),
+            value,
+            Instr::Memory(opcode, align, 0),
+        ]
+    };
+    let stores = [
+        store(0, Instr::I32Const(0x0102_0304), Opcode::I32Store, 2),
+        store(
+            4,
+            Instr::I64Const(0x0102_0304_0506_0708),
+            Opcode::I64Store,
+            3,
+        ),
+        store(12, Instr::F32Const(1.5), Opcode::F32Store, 2),
+        store(16, Instr::F64Const(-2.0), Opcode::F64Store, 3),
+        store(24, Instr::I32Const(0xaabb), Opcode::I32Store16, 1),
+        store(26, Instr::I64Const(0x1122_3344), Opcode::I64Store32, 2),
+        store(30, Instr::I32Const(0x7f), Opcode::I32Store8, 0),
+    ]
+    .concat();
+    let load = |opcode, align| vec![Instr::I32Const(64), Instr::Memory(opcode, align, 0)];
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I64]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .add_function(0, vec![], stores)
+        .add_function(1, vec![], load(Opcode::I64Load, 3))
+        .add_function(2, vec![], load(Opcode::I32Load16S, 1))
+        .add_function(
+            2,
+            vec![],
+            vec![Instr::F32Const(1.5), Instr::Op(Opcode::I32ReinterpretF32)],
+        )
+        .add_function(
+            1,
+            vec![],
+            vec![Instr::F64Const(-2.0), Instr::Op(Opcode::I64ReinterpretF64)],
+        )
+        .add_memory(1, None)
+        .add_data(
+            0,
+            vec![Instr::I32Const(64)],
+            vec![0x01, 0x82, 0x03, 0x04, 0x05, 0x06, 0x07, 0x88],
+        )
+        .export_func("store", 0)
+        .export_func("load_i64", 1)
+        .export_func("load_i16", 2)
+        .export_func("f32_bits", 3)
+        .export_func("f64_bits", 4)
+        .export_memory("memory", 0)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+
+    instance.invoke_export("store", &[])?;
+    let mut bytes = [0; 31];
+    instance.memories[0].borrow().get_data(0, &mut bytes)?;
+    #[rustfmt::skip]
+    assert_eq!(
+        bytes,
+        [
+            0x04, 0x03, 0x02, 0x01,
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+            0x00, 0x00, 0xc0, 0x3f,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0,
+            0xbb, 0xaa,
+            0x44, 0x33, 0x22, 0x11,
+            0x7f,
+        ]
+    );
+
+    assert_eq!(
+        instance.invoke_export("load_i64", &[])?,
+        [0x8807_0605_0403_8201_u64.into()]
+    );
+    assert_eq!(
+        instance.invoke_export("load_i16", &[])?,
+        [(-0x7dff_i32).into()]
+    );
+    assert_eq!(
+        instance.invoke_export("f32_bits", &[])?,
+        [0x3fc0_0000_u32.into()]
+    );
+    assert_eq!(
+        instance.invoke_export("f64_bits", &[])?,
+        [0xc000_0000_0000_0000_u64.into()]
+    );
+
+    // The host's accessors use the same order as the guest
+    let memory = instance.memories[0].clone();
+    assert_eq!(memory.borrow().read_u32(0)?, 0x0102_0304);
+    assert_eq!(memory.borrow().read_f64(16)?, -2.0);
+    memory.borrow_mut().write_u64(32, 0x0102_0304_0506_0708)?;
+    let mut written = [0; 8];
+    memory.borrow().get_data(32, &mut written)?;
+    assert_eq!(written, [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+
+    Ok(())
+}
+
+#[test]
+fn test_func_instructions() -> Result<()> {
+    let body = vec![
+        Instr::Block(BlockType::I32),
+        Instr::LocalGet(0),
+        Instr::If(BlockType::None),
+        Instr::I64Const(-200),
+        Instr::Drop,
+        Instr::Else,
+        Instr::F32Const(1.5),
+        Instr::F64Const(-2.25),
+        Instr::Op(Opcode::Drop),
+        Instr::Drop,
+        Instr::End,
+        Instr::LocalGet(0),
+        Instr::BrTable(vec![0, 1], 0),
+        Instr::End,
+        Instr::I32Const(4),
+        Instr::Memory(Opcode::I64Store32, 2, 300),
+        Instr::I32Const(0),
+        Instr::CallIndirect(1),
+        Instr::MemoryGrow,
+        Instr::Call(0),
+    ];
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(0, vec![ValueType::I64], body.clone())
+        .build();
+
+    let decoded = raw.funcs()[0]
+        .instructions()
+        .collect::<Result<Vec<(usize, Instr)>>>()?;
+
+    // Drop is the same whichever way it was written, and the body gets its end
+    let mut expected = body;
+    expected[8] = Instr::Drop;
+    expected.push(Instr::End);
+    let instructions: Vec<Instr> = decoded.iter().map(|(_, i)| i.clone()).collect();
+    assert_eq!(instructions, expected);
+
+    let offsets: Vec<usize> = decoded.iter().map(|(offset, _)| *offset).collect();
+    assert_eq!(
+        offsets,
+        [0, 2, 4, 6, 9, 10, 11, 16, 25, 26, 27, 28, 30, 35, 36, 38, 42, 44, 47, 49, 51]
+    );
+
+    // Everything in the test module decodes
+    let module = core::Module::load_module_from_path("../test_app/test.wasm")?;
+    for func in module.raw_module().funcs() {
+        for instruction in func.instructions() {
+            instruction?;
+        }
+    }
+
+    // A bad instruction stops the iteration, with where it was
+    let expr = Expr::new(vec![0x41, 0x01, 0x1a, 0xff, 0x0b]);
+    let mut instructions = expr.instructions();
+    assert_eq!(instructions.next().unwrap()?, (0, Instr::I32Const(1)));
+    assert_eq!(instructions.next().unwrap()?, (2, Instr::Drop));
+    assert_eq!(
+        instructions.next().unwrap().unwrap_err().to_string(),
+        "Invalid instruction at offset 0x3"
+    );
+    assert!(instructions.next().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_disassemble_function() -> Result<()> {
+    let i32_to_i32 = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+    let mut raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(
+            vec![ValueType::I32, ValueType::I32],
+            vec![ValueType::I32],
+        ))
+        .add_type(i32_to_i32)
+        .import_func("env", "add", 0)
+        .import_global(
+            "env",
+            "base",
+            GlobalType::new(ValueType::I32, MutableType::Const),
+        )
+        .add_function(
+            1,
+            vec![ValueType::I64, ValueType::I64],
+            vec![
+                Instr::Block(BlockType::None),
+                Instr::LocalGet(0),
+                Instr::BrTable(vec![0, 1], 0),
+                Instr::End,
+                Instr::LocalGet(0),
+                Instr::GlobalGet(0),
+                Instr::Call(0),
+                Instr::LocalGet(0),
+                Instr::If(BlockType::I32),
+                Instr::F32Const(1.0),
+                Instr::Drop,
+                Instr::I32Const(0),
+                Instr::Memory(Opcode::I32Load, 2, 16),
+                Instr::Else,
+                Instr::I32Const(-1),
+                Instr::End,
+                Instr::Op(Opcode::I32Add),
+                Instr::I32Const(0),
+                Instr::CallIndirect(1),
+            ],
+        )
+        .build();
+
+    // Only the defined function has a name in the name section
+    let mut names = vec![0x01, 0x08, 0x01, 0x01, 0x05];
+    names.extend_from_slice(b"entry");
+    raw.custom_sections_mut().push(core::CustomSection::new(
+        "name".to_string(),
+        names,
+        Some(core::SectionType::CodeSection),
+    ));
+    let module = core::Module::from_reader(&mut &raw.encode()[..])?;
+
+    assert_eq!(
+        module.disassemble_function(1)?,
+        "func 1 'entry' (i32) -> i32
+       local 1 i64
+       local 2 i64
+0x0000   block @0
+0x0002     local.get 0
+0x0004     br_table 0 (@0) 1 (return) 0 (@0)
+0x0009   end @0
+0x000a   local.get 0
+0x000c   global.get 0 'env::base'
+0x000e   call 0 'env::add'
+0x0010   local.get 0
+0x0012   if (result i32) @1
+0x0014     f32.const 1.0
+0x0019     drop
+0x001a     i32.const 0
+0x001c     i32.load offset=16 align=4
+0x001f   else @1
+0x0020     i32.const -1
+0x0022   end @1
+0x0023   i32.add
+0x0024   i32.const 0
+0x0026   call_indirect 1 (i32) -> i32
+0x0029   end
+"
+    );
+
+    assert_eq!(
+        module.disassemble_function(0).unwrap_err().to_string(),
+        "Cannot disassemble func 0 (imported from env::add), it has no body"
+    );
+    assert_eq!(
+        module.disassemble_function(2).unwrap_err().to_string(),
+        "Cannot disassemble func 2 (out of range)"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_extended_names() -> Result<()> {
+    fn name_map(out: &mut Vec<u8>, names: &[(u8, &str)]) {
+        out.push(names.len() as u8);
+        for (idx, name) in names {
+            out.push(*idx);
+            out.push(name.len() as u8);
+            out.extend_from_slice(name.as_bytes());
+        }
+    }
+    fn subsection(out: &mut Vec<u8>, id: u8, payload: Vec<u8>) {
+        out.push(id);
+        out.push(payload.len() as u8);
+        out.extend(payload);
+    }
+
+    // Every subsection there is, with an empty one for a later proposal on the end
+    let mut names = Vec::new();
+    subsection(&mut names, 0, b"\x05named".to_vec());
+    let direct: [(u8, &[(u8, &str)]); 7] = [
+        (1, &[(0, "load")]),
+        (4, &[(0, "unary")]),
+        (5, &[(0, "funcs")]),
+        (6, &[(0, "heap")]),
+        (7, &[(1, "stack_pointer")]),
+        (8, &[(0, "elems")]),
+        (9, &[(0, "greeting")]),
+    ];
+    for (id, map) in direct.iter() {
+        let mut payload = Vec::new();
+        name_map(&mut payload, map);
+        subsection(&mut names, *id, payload);
+    }
+    let indirect: [(u8, &[(u8, &str)]); 3] = [
+        (2, &[(0, "addr"), (1, "tmp")]),
+        (3, &[(0, "exit")]),
+        (10, &[(0, "field")]),
+    ];
+    for (id, map) in indirect.iter() {
+        let mut payload = vec![1, 0];
+        name_map(&mut payload, map);
+        subsection(&mut names, *id, payload);
+    }
+    subsection(&mut names, 11, vec![0]);
+
+    let make_module = |global_init: Instr, data_offset: i32| {
+        let mut raw = RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+            .import_global(
+                "test",
+                "zero",
+                GlobalType::new(ValueType::I32, MutableType::Const),
+            )
+            .add_global(
+                GlobalType::new(ValueType::I32, MutableType::Var),
+                vec![global_init],
+            )
+            .add_function(
+                0,
+                vec![ValueType::I64],
+                vec![
+                    Instr::Block(BlockType::None),
+                    Instr::LocalGet(0),
+                    Instr::BrIf(0),
+                    Instr::End,
+                    Instr::GlobalGet(1),
+                    Instr::Drop,
+                    Instr::LocalGet(0),
+                    Instr::Memory(Opcode::I32Load, 2, 0),
+                ],
+            )
+            .add_memory(1, None)
+            .add_data(0, vec![Instr::I32Const(data_offset)], b"hi".to_vec())
+            .export_func("load", 0)
+            .build();
+        raw.custom_sections_mut().push(core::CustomSection::new(
+            "name".to_string(),
+            names.clone(),
+            Some(core::SectionType::DataSection),
+        ));
+        core::Module::from_reader(&mut &raw.encode()[..])
+    };
+
+    let module = make_module(Instr::I32Const(8), 0)?;
+    let raw = module.raw_module();
+    let names = raw.names();
+    assert_eq!(names.module_name(), Some("named"));
+    assert_eq!(raw.func_name(0), Some("load"));
+    assert_eq!(names.local_name(0, 0), Some("addr"));
+    assert_eq!(names.local_name(0, 1), Some("tmp"));
+    assert_eq!(names.label_name(0, 0), Some("exit"));
+    assert_eq!(names.type_name(0), Some("unary"));
+    assert_eq!(names.table_name(0), Some("funcs"));
+    assert_eq!(names.memory_name(0), Some("heap"));
+    assert_eq!(names.global_name(0), None);
+    assert_eq!(names.global_name(1), Some("stack_pointer"));
+    assert_eq!(names.elem_name(0), Some("elems"));
+    assert_eq!(names.data_name(0), Some("greeting"));
+    assert_eq!(names.field_name(0, 0), Some("field"));
+
+    // The names go back out with the module, and come back the same
+    let reread = core::Module::from_reader(&mut &raw.encode()[..])?;
+    assert_eq!(reread.raw_module().names(), names);
+
+    assert_eq!(
+        module.describe_global(0),
+        "global 0 (imported from test::zero)"
+    );
+    assert_eq!(module.describe_global(1), "global 1 'stack_pointer'");
+    assert_eq!(module.describe_memory(0), "memory 0 'heap'");
+    assert_eq!(
+        module.disassemble_function(0)?,
+        "func 0 'load' (i32) -> i32
+       local 1 'tmp' i64
+0x0000   block @0 'exit'
+0x0002     local.get 0 'addr'
+0x0004     br_if 0 (@0 'exit')
+0x0006   end @0
+0x0007   global.get 1 'stack_pointer'
+0x0009   drop
+0x000a   local.get 0 'addr'
+0x000c   i32.load offset=0 align=4 (memory 0 'heap')
+0x000f   end
+"
+    );
+
+    // Traps and instantiation errors name the memories and globals
+    let mut instance = module.instantiate(&TestResolver::new())?;
+    let error = instance
+        .invoke_export("load", &[0x10000.into()])
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Trap>().unwrap().mem_name(),
+        Some("heap")
+    );
+    assert_eq!(
+        error.to_string(),
+        "Trap: out of bounds read of 4 bytes at 0x0001_0000 in memory 0 'heap', \
+         memory size 0x0001_0000 (func 0, offset 0xc)"
+    );
+
+    let module = make_module(Instr::I64Const(8), 0)?;
+    let error = module.instantiate(&TestResolver::new()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Couldn't initialize global 1 'stack_pointer'"
+    );
+    let module = make_module(Instr::I32Const(8), 0xffff)?;
+    let error = module.instantiate(&TestResolver::new()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Data segment 0 'greeting' couldn't be written to memory 0 'heap'"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_opcode_stats_and_features() -> Result<()> {
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Extend8S),
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        .export_func("extend_and_add", 0)
+        .build();
+
+    let stats = raw.opcode_stats()?;
+    let stats: Vec<(&str, u64)> = stats.into_iter().collect();
+    assert_eq!(
+        stats,
+        [
+            ("end", 1),
+            ("i32.add", 1),
+            ("i32.extend8_s", 1),
+            ("local.get", 2)
+        ]
+    );
+
+    let features = raw.required_features()?;
+    assert!(features.contains(core::Feature::SignExtension));
+    assert_eq!(features.to_string(), "sign-ext");
+
+    let mut instance = core::Module::new(raw).instantiate(&TestResolver::new())?;
+    assert_eq!(
+        instance.invoke_export("extend_and_add", &[0x180_i32.into()])?,
+        [0x100_i32.into()]
+    );
+
+    // Some features come from the sections rather than the instructions
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![ValueType::I32, ValueType::I64]))
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_function(1, vec![], vec![])
+        .add_global(
+            GlobalType::new(ValueType::I32, MutableType::Var),
+            vec![Instr::I32Const(0)],
+        )
+        .export_global("counter", 0)
+        .build();
+    assert_eq!(
+        raw.required_features()?.to_string(),
+        "mutable-globals, multi-value"
+    );
+
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_function(0, vec![], vec![Instr::Nop])
+        .build();
+    assert!(raw.required_features()?.is_empty());
+    assert_eq!(raw.required_features()?.to_string(), "mvp");
+
+    Ok(())
+}
+
+#[test]
+fn test_target_features() -> Result<()> {
+    use core::{Feature, FeatureSet, TargetFeaturePrefix};
+
+    // A v128.const, which can't be decoded
+    let mut v128_const = vec![0xfd, 0x0c];
+    v128_const.extend_from_slice(&[0; 16]);
+    let make_module = |instrs: Vec<Instr>, target_features: &[&str]| {
+        let mut raw = RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![], vec![]))
+            .add_function(0, vec![], instrs)
+            .build();
+        if !target_features.is_empty() {
+            // Each is its prefix, then the rest of it as a name
+            let mut section = vec![target_features.len() as u8];
+            for entry in target_features {
+                section.push(entry.as_bytes()[0]);
+                section.push(entry.len() as u8 - 1);
+                section.extend_from_slice(&entry.as_bytes()[1..]);
+            }
+            raw.custom_sections_mut().push(core::CustomSection::new(
+                core::TARGET_FEATURES_SECTION.to_string(),
+                section,
+                Some(core::SectionType::CodeSection),
+            ));
+        }
+        raw.encode()
+    };
+    let supported = FeatureSet::supported();
+
+    // The section is what's checked, ahead of the instruction that would fail to decode
+    let simd = make_module(
+        vec![Instr::Raw(v128_const.clone()), Instr::Drop],
+        &["+simd128", "+sign-ext", "-atomics", "+extended-const"],
+    );
+    let error = core::Module::load_module_with_features(&simd, &supported).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Module requires simd128 which is not enabled"
+    );
+    assert!(core::Module::load_module_from_bytes(&simd).is_err());
+
+    let mut all = supported.clone();
+    all.insert(Feature::Simd);
+    all.insert(Feature::BulkMemory);
+    assert!(core::RawModule::check_features(&simd, &all).is_ok());
+    let error = core::RawModule::check_features(&simd, &FeatureSet::default()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Module requires simd128, sign-ext which are not enabled"
+    );
+
+    // The section can be read back from a module that loads
+    let plain = make_module(
+        vec![Instr::Nop],
+        &["+sign-ext", "=mutable-globals", "-simd128"],
+    );
+    let module = core::Module::load_module_with_features(&plain, &supported)?;
+    let target_features = module.raw_module().target_features().unwrap();
+    assert_eq!(target_features.entries().len(), 3);
+    assert_eq!(
+        target_features.entries()[1].prefix(),
+        TargetFeaturePrefix::Required
+    );
+    assert_eq!(target_features.entries()[2].feature(), Some(Feature::Simd));
+    assert_eq!(
+        target_features.used().to_string(),
+        "mutable-globals, sign-ext"
+    );
+    assert_eq!(
+        target_features.to_string(),
+        "+sign-ext =mutable-globals -simd128"
+    );
+
+    // Without the section, scanning the module finds what it uses instead
+    let simd = make_module(vec![Instr::Raw(v128_const), Instr::Drop], &[]);
+    let error = core::Module::load_module_with_features(&simd, &supported).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Module requires simd which is not enabled"
+    );
+    let plain = make_module(vec![Instr::Nop], &[]);
+    let module = core::Module::load_module_with_features(&plain, &supported)?;
+    assert!(module.raw_module().target_features().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_call_graph() -> Result<()> {
+    let void = FuncType::new(vec![], vec![]);
+    let i32_to_i32 = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+    let raw = RawModuleBuilder::new()
+        .add_type(void)
+        .add_type(i32_to_i32)
+        // 0 is imported, and called by 2
+        .import_func("env", "log", 0)
+        // 1 is the exported entry point, and calls 2 directly and then through the table
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::Call(2),
+                Instr::I32Const(1),
+                Instr::I32Const(0),
+                Instr::CallIndirect(1),
+                Instr::Drop,
+            ],
+        )
+        .add_function(0, vec![], vec![Instr::Call(0)])
+        // 3 is in the table with the right type, 4 is in the table with the wrong type
+        .add_function(1, vec![], vec![Instr::LocalGet(0)])
+        .add_function(0, vec![], vec![])
+        // And nothing refers to 5 other than itself
+        .add_function(0, vec![], vec![Instr::Call(5)])
+        .add_table(2, None)
+        .add_elem(0, vec![Instr::I32Const(0)], vec![3, 4])
+        .export_func("main", 1)
+        .build();
+
+    let graph = raw.call_graph()?;
+    assert_eq!(graph.num_functions(), 6);
+    assert_eq!(graph.direct_calls(1).collect::<Vec<_>>(), [2]);
+    assert_eq!(graph.direct_calls(2).collect::<Vec<_>>(), [0]);
+    assert_eq!(graph.direct_calls(0).count(), 0);
+    assert_eq!(graph.indirect_call_types(1).collect::<Vec<_>>(), [1]);
+    assert_eq!(graph.table_targets().collect::<Vec<_>>(), [3, 4]);
+    assert_eq!(graph.roots().into_iter().collect::<Vec<_>>(), [1]);
+
+    assert_eq!(
+        graph.reachable().into_iter().collect::<Vec<_>>(),
+        [0, 1, 2, 3]
+    );
+    assert_eq!(graph.unreachable().into_iter().collect::<Vec<_>>(), [4, 5]);
+    assert!(graph.is_reachable(3));
+    assert!(!graph.is_reachable(5));
+
+    assert_eq!(
+        graph.to_dot(),
+        "digraph calls {
+  f0 [label=\"0: env::log\"];
+  f1 [label=\"1\", peripheries=2];
+  f2 [label=\"2\"];
+  f3 [label=\"3\"];
+  f4 [label=\"4\"];
+  f5 [label=\"5\"];
+  f1 -> f2;
+  f1 -> f3 [style=dashed];
+  f2 -> f0;
+  f5 -> f5;
+}
+"
+    );
+
+    // The start function is a root as well
+    let module = core::Module::load_module_from_path("../test_app/test.wasm")?;
+    let graph = module.raw_module().call_graph()?;
+    assert!(graph.roots().contains(&1));
+    assert!(graph.unreachable().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_compatibility_report() -> Result<()> {
+    let void = FuncType::new(vec![], vec![]);
+    let mut bytes = RawModuleBuilder::new()
+        .add_type(void)
+        .import_func("env", "log", 0)
+        .add_function_bytes(
+            0,
+            vec![],
+            vec![
+                // memory.fill, return_call 0 and an empty block the spec's way
+                0xfc, 0x0b, 0x00, 0x12, 0x00, 0x02, 0x40, 0x0b,
+                // A SIMD instruction, and whatever follows can't be scanned
+                0xfd, 0x0c, 0xff, 0x0b,
+            ],
+        )
+        .add_function_bytes(0, vec![], vec![0xff, 0x0b])
+        .add_table(1, None)
+        .add_table(1, None)
+        .build()
+        .encode();
+    // A data count section on the end
+    bytes.extend_from_slice(&[0x0c, 0x01, 0x00]);
+
+    let report = RawModule::compatibility_report(&bytes[..])?;
+    assert!(!report.is_compatible());
+
+    let issues: Vec<(Option<usize>, u8, String)> = report
+        .issues()
+        .iter()
+        .map(|issue| {
+            (
+                issue.func_idx(),
+                bytes[issue.offset()],
+                issue.description().to_string(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        issues,
+        [
+            (None, 0x70, "more than one table".to_string()),
+            (Some(1), 0xfc, "memory.fill".to_string()),
+            (Some(1), 0x12, "return_call".to_string()),
+            (
+                Some(1),
+                0x02,
+                "block with the empty block type 0x40".to_string()
+            ),
+            (Some(1), 0xfd, "v128 instruction".to_string()),
+            (Some(2), 0xff, "Invalid opcode byte 0xff".to_string()),
+            (None, 0x0c, "data count section".to_string()),
+        ]
+    );
+
+    let kinds: Vec<_> = report.issues().iter().map(|issue| issue.kind()).collect();
+    assert_eq!(
+        kinds,
+        [
+            core::CompatibilityIssueKind::Unsupported(core::Feature::ReferenceTypes),
+            core::CompatibilityIssueKind::Unsupported(core::Feature::BulkMemory),
+            core::CompatibilityIssueKind::Unsupported(core::Feature::TailCall),
+            core::CompatibilityIssueKind::Limitation,
+            core::CompatibilityIssueKind::Unsupported(core::Feature::Simd),
+            core::CompatibilityIssueKind::Malformed,
+            core::CompatibilityIssueKind::Unsupported(core::Feature::BulkMemory),
+        ]
+    );
+    assert_eq!(
+        report.unsupported_features().to_string(),
+        "bulk-memory, reference-types, simd, tail-call"
+    );
+    assert!(report
+        .to_string()
+        .contains(" func 1: memory.fill (bulk-memory)\n"));
+
+    // Decoding tells the known but unimplemented instructions apart from bad ones too
+    let err = wasm::parser::InstrIterator::new(&[0xfc, 0x0b, 0x00, 0x0b])
+        .next()
+        .unwrap()
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("memory.fill needs the bulk-memory feature"));
+
+    // A module that loads has nothing to report
+    let bytes = std::fs::read("../test_app/test.wasm")?;
+    let report = RawModule::compatibility_report(&bytes[..])?;
+    assert!(report.is_compatible());
+    assert_eq!(report.to_string(), "no compatibility issues\n");
+
+    Ok(())
+}
+
+// A reader that only gives out a few bytes at a time, like a pipe
+struct Trickle {
+    bytes: Vec<u8>,
+    chunk: usize,
+}
+
+impl std::io::Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.chunk.min(buf.len()).min(self.bytes.len());
+        buf[..len].copy_from_slice(&self.bytes[..len]);
+        self.bytes.drain(..len);
+        Ok(len)
+    }
+}
+
+// Copies stdin to stdout in _start, reading into two 4 byte iovecs at a time. The iovecs are at 0
+// and 8, the count read goes at 16, the iovec for writing is at 24 and the count written at 32.
+fn make_wasi_cat_module() -> core::Module {
+    let i32_load = |offset| Instr::Memory(Opcode::I32Load, 2, offset);
+    let wasi = wasm::wasi::WASI_MODULE;
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32; 4], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_type(FuncType::new(vec![ValueType::I32; 2], vec![ValueType::I32]))
+        .import_func(wasi, "fd_read", 0)
+        .import_func(wasi, "fd_write", 0)
+        .import_func(wasi, "fd_fdstat_get", 2)
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::Block(BlockType::None),
+                Instr::Loop(BlockType::None),
+                Instr::I32Const(0),
+                Instr::I32Const(0),
+                Instr::I32Const(2),
+                Instr::I32Const(16),
+                Instr::Call(0),
+                Instr::Drop,
+                // Stop at the end of the input
+                Instr::I32Const(0),
+                i32_load(16),
+                Instr::Op(Opcode::I32Eqz),
+                Instr::BrIf(1),
+                // Write out however much was read
+                Instr::I32Const(0),
+                Instr::I32Const(0),
+                i32_load(16),
+                Instr::Memory(Opcode::I32Store, 2, 28),
+                Instr::I32Const(1),
+                Instr::I32Const(24),
+                Instr::I32Const(1),
+                Instr::I32Const(32),
+                Instr::Call(1),
+                Instr::Drop,
+                Instr::Br(0),
+                Instr::End,
+                Instr::End,
+            ],
+        )
+        .add_memory(1, None)
+        .add_data(
+            0,
+            vec![Instr::I32Const(0)],
+            vec![64, 0, 0, 0, 4, 0, 0, 0, 68, 0, 0, 0, 4, 0, 0, 0],
+        )
+        .add_data(0, vec![Instr::I32Const(24)], vec![64, 0, 0, 0])
+        .export_memory("memory", 0)
+        .export_func("_start", 3)
+        .export_func("fd_read", 0)
+        .export_func("fd_fdstat_get", 2)
+        .build();
+    core::Module::new(raw)
+}
+
+#[test]
+fn test_wasi_fd_read() -> Result<()> {
+    use wasm::wasi::{Errno, WasiCtx, WasiResolver};
+
+    let input = b"The quick brown fox\njumps over the lazy dog\n".to_vec();
+    let stdout = wasm::wasi::InMemoryPipe::new();
+    let ctx = Rc::new(
+        WasiCtx::new()
+            .stdin(Trickle {
+                bytes: input.clone(),
+                chunk: 3,
+            })
+            .stdout(stdout.clone()),
+    );
+
+    let module = make_wasi_cat_module();
+    let mut instance = module.instantiate(&WasiResolver::new(ctx.clone()))?;
+
+    // Nothing can be read or written until the functions know where the memory is
+    let err = instance.invoke_export("_start", &[]).unwrap_err();
+    assert!(format!("{:#}", err).contains("before any memory was attached"));
+
+    ctx.attach(&instance)?;
+    instance.invoke_export("_start", &[])?;
+    assert_eq!(stdout.take(), input);
+
+    // Reading again is at the end of the input, so reads nothing
+    let results = instance.invoke_export("fd_read", &[0.into(), 0.into(), 2.into(), 16.into()])?;
+    assert_eq!(results, [(Errno::Success as i32).into()]);
+    let memory = instance.exports["memory"].as_memory().unwrap().clone();
+    assert_eq!(memory.borrow().read_u32(16)?, 0);
+
+    // Only stdin can be read from, and the buffers have to be in memory
+    let results = instance.invoke_export("fd_read", &[1.into(), 0.into(), 2.into(), 16.into()])?;
+    assert_eq!(results, [(Errno::Badf as i32).into()]);
+    let results = instance.invoke_export(
+        "fd_read",
+        &[0.into(), 0xffff_fff0u32.into(), 1.into(), 16.into()],
+    )?;
+    assert_eq!(results, [(Errno::Fault as i32).into()]);
+
+    // libc checks the stdio descriptors before it uses them
+    for fd in 0..3 {
+        let results = instance.invoke_export("fd_fdstat_get", &[fd.into(), 40.into()])?;
+        assert_eq!(results, [(Errno::Success as i32).into()]);
+        assert_eq!(memory.borrow().read_u8(40)?, 2);
+        let rights = memory.borrow().read_u64(48)?;
+        let (read, write) = (rights & (1 << 1) != 0, rights & (1 << 6) != 0);
+        assert_eq!((read, write), (fd == 0, fd != 0));
+    }
+    let results = instance.invoke_export("fd_fdstat_get", &[3.into(), 40.into()])?;
+    assert_eq!(results, [(Errno::Badf as i32).into()]);
+
+    Ok(())
+}
+
+// read_at(fd, offset: i64) seeks to the offset, reads 4 bytes to 64 with the iovec at 0 and the
+// count read at 16, and then puts where the file is now at 24. The WASI functions are exported
+// as well, so that they can be called directly.
+fn make_wasi_file_module() -> core::Module {
+    let wasi = wasm::wasi::WASI_MODULE;
+    use ValueType::{I32, I64};
+
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![I32; 4], vec![I32]))
+        .add_type(FuncType::new(vec![I32, I64, I32, I32], vec![I32]))
+        .add_type(FuncType::new(vec![I32; 2], vec![I32]))
+        .add_type(FuncType::new(vec![I32], vec![I32]))
+        .add_type(FuncType::new(vec![I32, I64], vec![I32]))
+        .import_func(wasi, "fd_read", 0)
+        .import_func(wasi, "fd_seek", 1)
+        .import_func(wasi, "fd_tell", 2)
+        .import_func(wasi, "fd_close", 3)
+        .import_func(wasi, "fd_filestat_get", 2)
+        .import_func(wasi, "fd_fdstat_get", 2)
+        .add_function(
+            4,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::LocalGet(1),
+                Instr::I32Const(0),
+                Instr::I32Const(24),
+                Instr::Call(1),
+                Instr::Drop,
+                Instr::LocalGet(0),
+                Instr::I32Const(0),
+                Instr::I32Const(1),
+                Instr::I32Const(16),
+                Instr::Call(0),
+                Instr::Drop,
+                Instr::LocalGet(0),
+                Instr::I32Const(24),
+                Instr::Call(2),
+            ],
+        )
+        .add_memory(1, None)
+        .add_data(0, vec![Instr::I32Const(0)], vec![64, 0, 0, 0, 4, 0, 0, 0])
+        .export_memory("memory", 0)
+        .export_func("read_at", 6)
+        .export_func("fd_seek", 1)
+        .export_func("fd_read", 0)
+        .export_func("fd_close", 3)
+        .export_func("fd_filestat_get", 4)
+        .export_func("fd_fdstat_get", 5)
+        .build();
+    core::Module::new(raw)
+}
+
+#[test]
+fn test_wasi_descriptors() -> Result<()> {
+    use wasm::wasi::{Errno, WasiCtx, WasiResolver};
+
+    let ctx = Rc::new(WasiCtx::new());
+    let fd = ctx.preopen_file(std::io::Cursor::new(b"0123456789abcdef".to_vec()));
+    assert_eq!(fd, 3);
+
+    let module = make_wasi_file_module();
+    let mut instance = module.instantiate(&WasiResolver::new(ctx.clone()))?;
+    ctx.attach(&instance)?;
+    let memory = instance.exports["memory"].as_memory().unwrap().clone();
+    let errno = |errno: Errno| vec![StackEntry::from(errno as i32)];
+
+    // Seeking and reading from the guest moves the file along
+    for offset in &[4i64, 12, 0, 10] {
+        let results = instance.invoke_export("read_at", &[fd.into(), (*offset).into()])?;
+        assert_eq!(results, errno(Errno::Success));
+
+        let mut bytes = [0u8; 4];
+        memory.borrow().get_data(64, &mut bytes)?;
+        let start = *offset as usize;
+        assert_eq!(&bytes, &b"0123456789abcdef"[start..start + 4]);
+        assert_eq!(memory.borrow().read_u32(16)?, 4);
+        assert_eq!(memory.borrow().read_u64(24)?, *offset as u64 + 4);
+    }
+
+    // Reading past the end reads what is left, and then nothing
+    instance.invoke_export("read_at", &[fd.into(), 14i64.into()])?;
+    assert_eq!(memory.borrow().read_u32(16)?, 2);
+    instance.invoke_export("read_at", &[fd.into(), 16i64.into()])?;
+    assert_eq!(memory.borrow().read_u32(16)?, 0);
+
+    // Relative seeks, and ones that end up before the start
+    let seek = |instance: &mut core::Instance, fd: u32, offset: i64, whence: u32| {
+        instance.invoke_export(
+            "fd_seek",
+            &[fd.into(), offset.into(), whence.into(), 24.into()],
+        )
+    };
+    assert_eq!(seek(&mut instance, fd, -6, 2)?, errno(Errno::Success));
+    assert_eq!(memory.borrow().read_u64(24)?, 10);
+    assert_eq!(seek(&mut instance, fd, 3, 1)?, errno(Errno::Success));
+    assert_eq!(memory.borrow().read_u64(24)?, 13);
+    assert_eq!(seek(&mut instance, fd, -20, 1)?, errno(Errno::Inval));
+    assert_eq!(seek(&mut instance, fd, 0, 7)?, errno(Errno::Inval));
+    // stdio can't be seeked in
+    assert_eq!(seek(&mut instance, 0, 0, 0)?, errno(Errno::Spipe));
+
+    // The file's size and type, which doesn't move it
+    let results = instance.invoke_export("fd_filestat_get", &[fd.into(), 128.into()])?;
+    assert_eq!(results, errno(Errno::Success));
+    assert_eq!(memory.borrow().read_u8(128 + 16)?, 4);
+    assert_eq!(memory.borrow().read_u64(128 + 32)?, 16);
+    let results = instance.invoke_export("fd_filestat_get", &[1.into(), 128.into()])?;
+    assert_eq!(results, errno(Errno::Success));
+    assert_eq!(memory.borrow().read_u8(128 + 16)?, 2);
+
+    let results = instance.invoke_export("fd_fdstat_get", &[fd.into(), 128.into()])?;
+    assert_eq!(results, errno(Errno::Success));
+    assert_eq!(memory.borrow().read_u8(128)?, 4);
+    let rights = memory.borrow().read_u64(128 + 8)?;
+    assert_eq!(rights & 0b110, 0b110, "the file can be read and seeked");
+
+    // Closing a descriptor takes it out of the table
+    let results = instance.invoke_export("fd_close", &[fd.into()])?;
+    assert_eq!(results, errno(Errno::Success));
+    let results = instance.invoke_export("fd_close", &[fd.into()])?;
+    assert_eq!(results, errno(Errno::Badf));
+    let results = instance.invoke_export("fd_read", &[fd.into(), 0.into(), 1.into(), 16.into()])?;
+    assert_eq!(results, errno(Errno::Badf));
+    assert_eq!(seek(&mut instance, fd, 0, 0)?, errno(Errno::Badf));
+    assert!(ctx.descriptor(fd).is_none());
+
+    // The lowest free descriptor is used again
+    assert_eq!(ctx.preopen_file(std::io::Cursor::new(Vec::new())), 3);
+
+    Ok(())
+}
+
+// sleep(nanos: i64) polls on the subscription at 0, which is a relative timeout on the monotonic
+// clock, and puts the events at 64 and how many there are at 128
+fn make_wasi_sleep_module() -> core::Module {
+    use ValueType::{I32, I64};
+
+    let wasi = wasm::wasi::WASI_MODULE;
+    let mut subscription = vec![0u8; 48];
+    subscription[0..8].copy_from_slice(&0x1234u64.to_le_bytes());
+    subscription[16] = 1;
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![I32; 4], vec![I32]))
+        .add_type(FuncType::new(vec![], vec![I32]))
+        .add_type(FuncType::new(vec![I32, I64, I32], vec![I32]))
+        .add_type(FuncType::new(vec![I64], vec![I32]))
+        .import_func(wasi, "poll_oneoff", 0)
+        .import_func(wasi, "sched_yield", 1)
+        .import_func(wasi, "clock_time_get", 2)
+        .add_function(
+            3,
+            vec![],
+            vec![
+                Instr::I32Const(0),
+                Instr::LocalGet(0),
+                Instr::Memory(Opcode::I64Store, 3, 24),
+                Instr::I32Const(0),
+                Instr::I32Const(64),
+                Instr::I32Const(1),
+                Instr::I32Const(128),
+                Instr::Call(0),
+            ],
+        )
+        .add_memory(1, None)
+        .add_data(0, vec![Instr::I32Const(0)], subscription)
+        .export_memory("memory", 0)
+        .export_func("sleep", 3)
+        .export_func("poll_oneoff", 0)
+        .export_func("sched_yield", 1)
+        .export_func("clock_time_get", 2)
+        .build();
+    core::Module::new(raw)
+}
+
+#[test]
+fn test_wasi_poll_oneoff() -> Result<()> {
+    use wasm::wasi::{Errno, ManualClock, WasiCtx, WasiResolver};
+
+    let clock = ManualClock::new(1_000_000);
+    let ctx = Rc::new(WasiCtx::new().clock(clock.clone()));
+    let module = make_wasi_sleep_module();
+    let mut instance = module.instantiate(&WasiResolver::new(ctx.clone()))?;
+    ctx.attach(&instance)?;
+    let memory = instance.exports["memory"].as_memory().unwrap().clone();
+    let errno = |errno: Errno| vec![StackEntry::from(errno as i32)];
+    // The userdata, error and type of an event
+    let event = |idx: usize| -> Result<(u64, u16, u8)> {
+        let memory = memory.borrow();
+        let offset = 64 + idx * 32;
+        Ok((
+            memory.read_u64(offset)?,
+            memory.read_u16(offset + 8)?,
+            memory.read_u8(offset + 10)?,
+        ))
+    };
+
+    // Sleeping for 10ms moves the clock on without really waiting
+    let start = std::time::Instant::now();
+    let results = instance.invoke_export("sleep", &[10_000_000i64.into()])?;
+    assert!(start.elapsed() < std::time::Duration::from_millis(10));
+    assert_eq!(results, errno(Errno::Success));
+    assert_eq!(clock.now(), 11_000_000);
+    assert_eq!(memory.borrow().read_u32(128)?, 1);
+    assert_eq!(event(0)?, (0x1234, 0, 0));
+
+    let results = instance.invoke_export("clock_time_get", &[1.into(), 0i64.into(), 256.into()])?;
+    assert_eq!(results, errno(Errno::Success));
+    assert_eq!(memory.borrow().read_u64(256)?, 11_000_000);
+
+    // An absolute timeout only waits for what's left of it
+    memory.borrow_mut().write_u16(40, 1)?;
+    instance.invoke_export("sleep", &[15_000_000i64.into()])?;
+    assert_eq!(clock.now(), 15_000_000);
+    instance.invoke_export("sleep", &[10_000_000i64.into()])?;
+    assert_eq!(clock.now(), 15_000_000);
+    memory.borrow_mut().write_u16(40, 0)?;
+
+    // stdio is always ready, so nothing waits when there's a descriptor in there too
+    let mut subscription = [0u8; 48];
+    subscription[0] = 7;
+    subscription[8] = 1;
+    memory.borrow_mut().set_data(48, &subscription)?;
+    let poll = |instance: &mut core::Instance, count: u32| {
+        instance.invoke_export(
+            "poll_oneoff",
+            &[0.into(), 64.into(), count.into(), 128.into()],
+        )
+    };
+    assert_eq!(poll(&mut instance, 2)?, errno(Errno::Success));
+    assert_eq!(clock.now(), 15_000_000);
+    assert_eq!(memory.borrow().read_u32(128)?, 1);
+    assert_eq!(event(0)?, (7, 0, 1));
+
+    // Unknown descriptors and clocks are errors in their events
+    memory.borrow_mut().write_u32(48 + 16, 9)?;
+    memory.borrow_mut().write_u32(16, 9)?;
+    assert_eq!(poll(&mut instance, 2)?, errno(Errno::Success));
+    assert_eq!(memory.borrow().read_u32(128)?, 2);
+    assert_eq!(event(0)?, (0x1234, Errno::Inval as u16, 0));
+    assert_eq!(event(1)?, (7, Errno::Badf as u16, 1));
+
+    assert_eq!(poll(&mut instance, 0)?, errno(Errno::Inval));
+    assert_eq!(
+        instance.invoke_export("sched_yield", &[])?,
+        errno(Errno::Success)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_wasi_proc_exit() -> Result<()> {
+    use wasm::wasi::{WasiCtx, WasiResolver};
+
+    // run(code) counts the call and then exits from a nested call, and count() says how many
+    // times run has been called
+    let counter_type = GlobalType::new(ValueType::I32, MutableType::Var);
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .import_func(wasm::wasi::WASI_MODULE, "proc_exit", 0)
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::GlobalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Add),
+                Instr::GlobalSet(0),
+                Instr::LocalGet(0),
+                Instr::Call(0),
+                Instr::Unreachable,
+            ],
+        )
+        .add_function(0, vec![], vec![Instr::LocalGet(0), Instr::Call(1)])
+        .add_function(1, vec![], vec![Instr::GlobalGet(0)])
+        .add_global(counter_type, vec![Instr::I32Const(0)])
+        .export_func("run", 2)
+        .export_func("count", 3)
+        .build();
+    let module = core::Module::new(raw);
+    let ctx = Rc::new(WasiCtx::new());
+    let mut instance = module.instantiate(&WasiResolver::new(ctx))?;
+
+    // Exiting is a completion of its own, whatever the code
+    assert_eq!(
+        instance.run_export("run", &[0.into()])?,
+        core::Completion::Exited(0)
+    );
+    assert_eq!(
+        instance.run_export("run", &[3.into()])?,
+        core::Completion::Exited(3)
+    );
+
+    // Exiting with 0 is a success, and anything else is an error the exit code can be got from
+    assert_eq!(instance.invoke_export("run", &[0.into()])?, []);
+    let error = instance.invoke_export("run", &[42.into()]).unwrap_err();
+    assert_eq!(core::exit_code(&error), Some(42));
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(|t| t.kind()),
+        Some(TrapKind::Exit(42))
+    );
+    assert_eq!(format!("{}", error), "Trap: exit with code 42");
+
+    // Nothing is left behind, so the instance can carry on being used
+    assert_eq!(instance.invoke_export("count", &[])?, [4.into()]);
+    assert_eq!(
+        instance.run_export("count", &[])?,
+        core::Completion::Returned(vec![4.into()])
+    );
+
+    Ok(())
+}
+
+// Writes "out1" to stdout, "err1" to stderr and then "out2" to stdout in print. The iovecs are at
+// 0, 8 and 16, the strings at 64, and the count written goes at 32.
+fn make_wasi_print_module() -> core::Module {
+    use ValueType::I32;
+
+    let mut body = Vec::new();
+    for (fd, iovec) in &[(1, 0), (2, 8), (1, 16)] {
+        body.extend(vec![
+            Instr::I32Const(*fd),
+            Instr::I32Const(*iovec),
+            Instr::I32Const(1),
+            Instr::I32Const(32),
+            Instr::Call(0),
+            Instr::Drop,
+        ]);
+    }
+
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![I32, I32, I32, I32], vec![I32]))
+        .add_type(FuncType::new(vec![], vec![]))
+        .import_func(wasm::wasi::WASI_MODULE, "fd_write", 0)
+        .add_function(1, vec![], body)
+        .add_memory(1, None)
+        .add_data(
+            0,
+            vec![Instr::I32Const(0)],
+            vec![
+                64, 0, 0, 0, 4, 0, 0, 0, 68, 0, 0, 0, 4, 0, 0, 0, 72, 0, 0, 0, 4, 0, 0, 0,
+            ],
+        )
+        .add_data(0, vec![Instr::I32Const(64)], b"out1err1out2".to_vec())
+        .export_memory("memory", 0)
+        .export_func("print", 1)
+        .build();
+    core::Module::new(raw)
+}
+
+#[test]
+fn test_wasi_capture_stdio() -> Result<()> {
+    use wasm::wasi::{OutputStream, WasiCtx, WasiResolver};
+
+    let ctx = Rc::new(WasiCtx::new().capture_stdio());
+    let module = make_wasi_print_module();
+    let mut instance = module.instantiate(&WasiResolver::new(ctx.clone()))?;
+    ctx.attach(&instance)?;
+
+    instance.invoke_export("print", &[])?;
+    assert_eq!(ctx.take_stdout(), b"out1out2");
+    assert_eq!(ctx.take_stderr(), b"err1");
+    assert_eq!(
+        ctx.take_output_log(),
+        vec![
+            (OutputStream::Stdout, b"out1".to_vec()),
+            (OutputStream::Stderr, b"err1".to_vec()),
+            (OutputStream::Stdout, b"out2".to_vec()),
+        ]
+    );
+
+    // Taking empties the buffers, so each call's output can be looked at on its own
+    assert!(ctx.take_stdout().is_empty());
+    assert!(ctx.take_output_log().is_empty());
+    instance.invoke_export("print", &[])?;
+    instance.invoke_export("print", &[])?;
+    assert_eq!(ctx.take_stdout(), b"out1out2out1out2");
+    assert_eq!(ctx.take_stderr(), b"err1err1");
+    assert_eq!(ctx.take_output_log().len(), 5);
+
+    // Without capturing there is nothing to take
+    let ctx = WasiCtx::new();
+    assert!(ctx.take_stdout().is_empty());
+    assert!(ctx.take_output_log().is_empty());
+
+    Ok(())
+}
+
+// A module with lots of small functions of the same type, like a big compiled program
+fn make_many_functions_module(count: usize) -> core::Module {
+    let mut builder = RawModuleBuilder::new().add_type(FuncType::new(
+        vec![ValueType::I32, ValueType::I64],
+        vec![ValueType::I32],
+    ));
+    for _ in 0..count {
+        builder = builder.add_function(
+            0,
+            vec![ValueType::I32],
+            vec![
+                Instr::LocalGet(0),
+                Instr::LocalGet(2),
+                Instr::Op(Opcode::I32Add),
+            ],
+        );
+    }
+    core::Module::new(builder.export_func("first", 0).build())
+}
+
+#[test]
+fn test_module_memory_usage() -> Result<()> {
+    let small = make_many_functions_module(1000).approx_memory_usage();
+    let large = make_many_functions_module(2000).approx_memory_usage();
+    let per_function = (large - small) / 1000;
+
+    // Each function is a 6 byte body and one local, and costs about 86 bytes all told on a 64 bit
+    // host. Its type isn't copied for it, and neither are its body and locals when it's
+    // instantiated, which used to be another 100 or so bytes and three allocations per function
+    // in every instance.
+    assert!(per_function < 128, "{} bytes per function", per_function);
+    assert!(small > 1000 * 6);
+
+    Ok(())
+}
+
+// Calls a two argument function n times in a loop, adding up what it returns
+fn make_call_loop_module() -> core::Module {
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(
+            vec![ValueType::I32, ValueType::I32],
+            vec![ValueType::I32],
+        ))
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::LocalGet(1),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        .add_function(
+            1,
+            vec![ValueType::I32],
+            vec![
+                Instr::Block(BlockType::None),
+                Instr::Loop(BlockType::None),
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Eqz),
+                Instr::BrIf(1),
+                Instr::LocalGet(1),
+                Instr::LocalGet(0),
+                Instr::Call(0),
+                Instr::LocalSet(1),
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Sub),
+                Instr::LocalSet(0),
+                Instr::Br(0),
+                Instr::End,
+                Instr::End,
+                Instr::LocalGet(1),
+            ],
+        )
+        .export_func("run", 1)
+        .build();
+    core::Module::new(raw)
+}
+
+#[test]
+fn test_invoke_export_counted() -> Result<()> {
+    let mut instance = make_call_loop_module().instantiate(core::EmptyResolver::instance())?;
+
+    let (results, idle) = instance.invoke_export_counted("run", &[0_i32.into()])?;
+    assert_eq!(results, vec![0_i32.into()]);
+    let (results, busy) = instance.invoke_export_counted("run", &[10_i32.into()])?;
+    assert_eq!(results, instance.invoke_export("run", &[10_i32.into()])?);
+
+    // Each time round, the loop runs twelve instructions of its own and three in the callee
+    assert_eq!(busy.instructions() - idle.instructions(), 10 * 15);
+    assert_eq!(idle.max_call_depth(), 1);
+    assert_eq!(busy.max_call_depth(), 2);
+    // The parameter and local, the two arguments to the call, and the callee's two operands
+    assert_eq!(busy.max_stack_depth(), 6);
+
+    // A trap still has its summary, for however far the call got
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(0, vec![], vec![Instr::I32Const(7), Instr::Unreachable])
+        .export_func("trap", 0)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+
+    let error = instance
+        .invoke_export_counted("trap", &[1_i32.into()])
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(Trap::kind),
+        Some(TrapKind::Unreachable)
+    );
+    let summary = core::ExecutionSummary::from_error(&error).unwrap();
+    assert_eq!(summary.instructions(), 2);
+    assert_eq!(summary.max_stack_depth(), 2);
+    assert_eq!(summary.max_call_depth(), 1);
+
+    Ok(())
+}
+
+// A benchmark of how quickly calls can be made, which is mostly down to how much each one
+// allocates. Run it with `cargo test --release -- --ignored --nocapture test_call_throughput`.
+#[test]
+#[ignore]
+fn test_call_throughput() -> Result<()> {
+    let calls = 1_000_000;
+    let mut instance = make_call_loop_module().instantiate(core::EmptyResolver::instance())?;
+
+    let start = std::time::Instant::now();
+    let results = instance.invoke_export("run", &[calls.into()])?;
+    let elapsed = start.elapsed();
+
+    // The sum wraps around in the guest
+    let sum = i64::from(calls) * i64::from(calls + 1) / 2;
+    assert_eq!(results, [(sum as i32).into()]);
+    println!(
+        "{} calls in {:?}, {:.0} calls per second",
+        calls,
+        elapsed,
+        f64::from(calls) / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+// A benchmark of loading a module of a few megabytes, most of which is function bodies full of
+// LEB128 immediates. Run it with `cargo test --release -- --ignored --nocapture test_load_throughput`.
+#[test]
+#[ignore]
+fn test_load_throughput() -> Result<()> {
+    let mut builder = RawModuleBuilder::new().add_type(FuncType::new(vec![], vec![ValueType::I32]));
+    for func_idx in 0..50_000 {
+        let mut body = vec![Instr::I32Const(func_idx)];
+        for value in 0..10 {
+            body.push(Instr::I32Const(value * 1_000_003));
+            body.push(Instr::Op(Opcode::I32Add));
+        }
+        builder = builder.add_function(0, vec![ValueType::I64], body);
+    }
+    let bytes = builder.build().encode();
+
+    let start = std::time::Instant::now();
+    let module = core::Module::load_module_from_bytes(&bytes)?;
+    let elapsed = start.elapsed();
+
+    assert_eq!(module.num_defined_functions(), 50_000);
+    println!(
+        "loaded {} bytes in {:?}, {:.1} MB/s",
+        bytes.len(),
+        elapsed,
+        bytes.len() as f64 / elapsed.as_secs_f64() / 1_000_000.0
+    );
+    Ok(())
+}
+
+// A small module with a memory, a data segment and some work to do: store(addr, value) writes
+// the value to memory, and sum(n) adds up the first n words of it in a loop
+fn make_pool_test_module(memory_pages: usize) -> RawModule {
+    RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32, ValueType::I32], vec![]))
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::LocalGet(1),
+                Instr::Memory(Opcode::I32Store, 2, 0),
+            ],
+        )
+        .add_function(
+            1,
+            vec![ValueType::I32],
+            vec![
+                Instr::Block(BlockType::None),
+                Instr::Loop(BlockType::None),
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Eqz),
+                Instr::BrIf(1),
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Sub),
+                Instr::LocalSet(0),
+                Instr::LocalGet(0),
+                Instr::I32Const(2),
+                Instr::Op(Opcode::I32Shl),
+                Instr::Memory(Opcode::I32Load, 2, 0),
+                Instr::LocalGet(1),
+                Instr::Op(Opcode::I32Add),
+                Instr::LocalSet(1),
+                Instr::Br(0),
+                Instr::End,
+                Instr::End,
+                Instr::LocalGet(1),
+            ],
+        )
+        .add_function(1, vec![], vec![Instr::LocalGet(0), Instr::MemoryGrow])
+        .add_memory(memory_pages, None)
+        .add_data(0, vec![Instr::I32Const(0)], vec![1, 0, 0, 0, 2, 0, 0, 0])
+        .export_func("store", 0)
+        .export_func("sum", 1)
+        .export_func("grow", 2)
+        .build()
+}
+
+// A benchmark of making, calling and dropping short lived instances, with and without an instance
+// pool. Run it with `cargo test --release -- --ignored --nocapture test_pooled_instantiation_throughput`.
+#[test]
+#[ignore]
+fn test_pooled_instantiation_throughput() -> Result<()> {
+    let instantiations = 20_000;
+    for memory_pages in [1, 16].iter() {
+        let module = core::Module::new(make_pool_test_module(*memory_pages));
+        let pool = core::InstancePool::new(core::PoolConfig {
+            instances: 4,
+            memory_pages: *memory_pages,
+            ..core::PoolConfig::default()
+        });
+
+        for pooled in [false, true].iter() {
+            let start = std::time::Instant::now();
+            for _ in 0..instantiations {
+                let mut instance = if *pooled {
+                    pool.instantiate(&module, core::EmptyResolver::instance())?
+                } else {
+                    module.instantiate(core::EmptyResolver::instance())?
+                };
+                assert_eq!(instance.invoke_export("sum", &[2.into()])?, [3.into()]);
+            }
+            let elapsed = start.elapsed();
+
+            println!(
+                "{} {} instantiations with {} page memories in {:?}, {:.0} instances per second",
+                instantiations,
+                if *pooled { "pooled" } else { "unpooled" },
+                memory_pages,
+                elapsed,
+                f64::from(instantiations) / elapsed.as_secs_f64()
+            );
+        }
+    }
+    Ok(())
+}
+
+// A benchmark of instantiating a module whose table is filled by a 50,000 entry element segment,
+// as a C++ program's vtables can make. Run it with
+// `cargo test --release -- --ignored --nocapture test_table_init_throughput`.
+#[test]
+#[ignore]
+fn test_table_init_throughput() -> Result<()> {
+    let entries = 50_000;
+    let mut builder = RawModuleBuilder::new().add_type(FuncType::new(vec![], vec![ValueType::I32]));
+    for func_idx in 0..1000 {
+        builder = builder.add_function(0, vec![], vec![Instr::I32Const(func_idx)]);
+    }
+    let func_indices = (0..entries).map(|idx| idx % 1000).collect();
+    let module = core::Module::new(
+        builder
+            .add_table(entries, None)
+            .add_elem(0, vec![Instr::I32Const(0)], func_indices)
+            .build(),
+    );
+
+    let instantiations = 100;
+    let start = std::time::Instant::now();
+    for _ in 0..instantiations {
+        let instance = module.instantiate(core::EmptyResolver::instance())?;
+        assert_eq!(
+            instance.tables[0].borrow().entry_func_idx(entries - 1),
+            Some(999)
+        );
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{} instantiations in {:?}, {:?} each",
+        instantiations,
+        elapsed,
+        elapsed / instantiations
+    );
+    Ok(())
+}
+
+// Reads an imported and a defined immutable global, and a mutable one, each time round a loop,
+// like position independent code does with __memory_base
+fn make_global_loop_module() -> RawModule {
+    RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .import_global(
+            "test",
+            "zero",
+            GlobalType::new(ValueType::I32, MutableType::Const),
+        )
+        .add_function(
+            0,
+            vec![ValueType::I32],
+            vec![
+                Instr::Loop(BlockType::None),
+                Instr::LocalGet(1),
+                Instr::GlobalGet(0),
+                Instr::Op(Opcode::I32Add),
+                Instr::GlobalGet(1),
+                Instr::Op(Opcode::I32Add),
+                Instr::GlobalGet(2),
+                Instr::Op(Opcode::I32Add),
+                Instr::LocalSet(1),
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Sub),
+                Instr::LocalTee(0),
+                Instr::BrIf(0),
+                Instr::End,
+                Instr::LocalGet(1),
+            ],
+        )
+        .add_global(
+            GlobalType::new(ValueType::I32, MutableType::Const),
+            vec![Instr::I32Const(3)],
+        )
+        .add_global(
+            GlobalType::new(ValueType::I32, MutableType::Var),
+            vec![Instr::I32Const(1)],
+        )
+        .export_func("sum", 0)
+        .export_global("step", 2)
+        .build()
+}
+
+#[test]
+fn test_global_reads() -> Result<()> {
+    let module = core::Module::new(make_global_loop_module());
+    let resolver = TestResolver::new();
+    let mut instance = module.instantiate(&resolver)?;
+    assert_eq!(
+        instance.invoke_export("sum", &[10_i32.into()])?,
+        [40_i32.into()]
+    );
+
+    // The mutable global is read each time
+    instance.globals[2].borrow_mut().set_value(5_i32.into())?;
+    assert_eq!(
+        instance.invoke_export("sum", &[10_i32.into()])?,
+        [80_i32.into()]
+    );
+
+    // Each instance has the values of the globals it was given
+    let mut seven = TestResolver::new();
+    seven.global_zero = Rc::new(RefCell::new(Global::new_host(7_i32.into(), false)));
+    let mut other = module.instantiate(&seven)?;
+    assert_eq!(
+        other.invoke_export("sum", &[10_i32.into()])?,
+        [110_i32.into()]
+    );
+    assert_eq!(
+        instance.fork()?.invoke_export("sum", &[1_i32.into()])?,
+        [8_i32.into()]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_missing_export_suggestions() -> Result<()> {
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .add_function(0, vec![], vec![Instr::I32Const(1)])
+        .add_memory(1, None)
+        .export_func("fibonacci", 0)
+        .export_memory("fib_table", 0)
+        .export_func("fib_iter", 0)
+        .export_func("main", 0)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+
+    let names: Vec<_> = instance.exports_in_order().map(|(name, _)| name).collect();
+    assert_eq!(names, vec!["fibonacci", "fib_table", "fib_iter", "main"]);
+
+    // Only functions are suggested for a function
+    let error = instance.invoke_export("fib", &[]).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<core::ExportNotFound>(),
+        Some(&core::ExportNotFound {
+            name: "fib".to_string(),
+            kind: ExportKind::Function,
+            suggestions: vec!["fib_iter".to_string(), "fibonacci".to_string()],
+            export_count: 4,
+        })
+    );
+    let error = instance
+        .get_export("mian", ExportKind::Function)
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "No function export `mian`; did you mean `main` (function)? The module has 4 exports, \
+         which exports_in_order lists"
+    );
+    let error = instance
+        .get_export("fib_tabel", ExportKind::Memory)
+        .unwrap_err();
+    assert_eq!(
+        error
+            .downcast_ref::<core::ExportNotFound>()
+            .unwrap()
+            .suggestions,
+        vec!["fib_table"]
+    );
+
+    // An export of the wrong kind isn't missing
+    let error = instance.get_function("fib_table").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Export fib_table is a memory export, not a function"
+    );
+    assert!(instance.get_function("main").is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_resource_metrics() -> Result<()> {
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32; 2], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .import_func("env", "add", 0)
+        .add_function(1, vec![], vec![Instr::LocalGet(0), Instr::MemoryGrow])
+        // Calls itself as many times as it's told, and then the host once
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::Block(BlockType::None),
+                Instr::LocalGet(0),
+                Instr::Op(Opcode::I32Eqz),
+                Instr::BrIf(0),
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Sub),
+                Instr::Call(2),
+                Instr::Op(Opcode::Return),
+                Instr::End,
+                Instr::I32Const(1),
+                Instr::I32Const(2),
+                Instr::Call(0),
+            ],
+        )
+        .add_memory(1, Some(8))
+        .add_table(5, None)
+        .export_func("grow", 1)
+        .export_func("recurse", 2)
+        .build();
+    let resolver = HostFunctionResolver {
+        function: Rc::new(RefCell::new(Callable::from_host(Rc::new(HostAdd::new())))),
+    };
+    let mut instance = core::Module::new(raw).instantiate(&resolver)?;
+
+    let metrics = instance.resource_metrics();
+    assert_eq!(metrics.memory_bytes, WASM_PAGE_SIZE_IN_BYTES);
+    assert_eq!(metrics.peak_memory_bytes, WASM_PAGE_SIZE_IN_BYTES);
+    assert_eq!(metrics.table_elements, 5);
+    assert_eq!(metrics.host_calls, 0);
+    assert_eq!(metrics.peak_call_depth, 0);
+
+    instance.invoke_export("grow", &[2_i32.into()])?;
+    instance.invoke_export("recurse", &[3_i32.into()])?;
+    let metrics = instance.resource_metrics();
+    assert_eq!(metrics.memory_bytes, 3 * WASM_PAGE_SIZE_IN_BYTES);
+    assert_eq!(metrics.peak_memory_bytes, 3 * WASM_PAGE_SIZE_IN_BYTES);
+    assert_eq!(metrics.host_calls, 1);
+    assert_eq!(metrics.peak_call_depth, 4);
+    let shallow_stack = metrics.peak_stack_depth;
+    assert!(shallow_stack > 0);
+
+    instance.invoke_export("recurse", &[10_i32.into()])?;
+    let metrics = instance.resource_metrics();
+    assert_eq!(metrics.host_calls, 2);
+    assert_eq!(metrics.peak_call_depth, 11);
+    assert!(metrics.peak_stack_depth > shallow_stack);
+
+    // A failed grow doesn't count, and one the host makes is seen when the metrics are read
+    assert_eq!(
+        instance.invoke_export("grow", &[100_i32.into()])?,
+        [(-1_i32).into()]
+    );
+    instance.memories[0].borrow_mut().grow_by(1)?;
+    assert_eq!(
+        instance.resource_metrics().peak_memory_bytes,
+        4 * WASM_PAGE_SIZE_IN_BYTES
+    );
+
+    // Resetting starts the peaks from what's in use now
+    instance.reset_resource_metrics();
+    let metrics = instance.resource_metrics();
+    assert_eq!(metrics.peak_memory_bytes, 4 * WASM_PAGE_SIZE_IN_BYTES);
+    assert_eq!(metrics.host_calls, 0);
+    assert_eq!(metrics.peak_call_depth, 0);
+    assert_eq!(metrics.peak_stack_depth, 0);
+    instance.invoke_export("recurse", &[0_i32.into()])?;
+    assert_eq!(instance.resource_metrics().peak_call_depth, 1);
+
+    // The calls counted one at a time are the same calls
+    let (_, summary) = instance.invoke_export_counted("recurse", &[5_i32.into()])?;
+    let metrics = instance.resource_metrics();
+    assert_eq!(metrics.peak_call_depth, summary.max_call_depth());
+    assert_eq!(metrics.host_calls, 2);
+
+    Ok(())
+}
+
+// A start function that never returns, and an export that doesn't either
+fn make_endless_start_module(data_len: usize) -> core::Module {
+    let endless = vec![Instr::Loop(BlockType::None), Instr::Br(0), Instr::End];
+    core::Module::new(
+        RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![], vec![]))
+            .add_type(FuncType::new(vec![ValueType::I32], vec![]))
+            .add_function(0, vec![], endless.clone())
+            // Loops until its argument is used up, and then forever if it was zero
+            .add_function(
+                1,
+                vec![],
+                vec![
+                    Instr::Loop(BlockType::None),
+                    Instr::LocalGet(0),
+                    Instr::I32Const(1),
+                    Instr::Op(Opcode::I32Sub),
+                    Instr::LocalTee(0),
+                    Instr::BrIf(0),
+                    Instr::End,
+                ]
+                .into_iter()
+                .chain(endless)
+                .collect(),
+            )
+            .add_memory(1, None)
+            .add_data(0, vec![Instr::I32Const(0)], vec![7; data_len])
+            .export_func("spin", 1)
+            .start(0)
+            .build(),
+    )
+}
+
+#[test]
+fn test_instantiation_budget() -> Result<()> {
+    let exceeded = |error: &anyhow::Error| {
+        error
+            .downcast_ref::<core::InstantiationBudgetExceeded>()
+            .map(|exceeded| exceeded.kind)
+    };
+
+    let module = make_endless_start_module(1000);
+    let options = core::InstanceOptions {
+        fuel: Some(10_000),
+        ..Default::default()
+    };
+    let error = module
+        .instantiate_with_options(core::EmptyResolver::instance(), &options)
+        .unwrap_err();
+    assert_eq!(exceeded(&error), Some(TrapKind::OutOfFuel));
+    assert!(
+        format!("{:#}", error).starts_with("Instantiation exceeded its budget: out of fuel: "),
+        "{:#}",
+        error
+    );
+
+    // Copying the data costs a unit for every eight bytes, so there isn't enough to start on the
+    // start function
+    let options = core::InstanceOptions {
+        fuel: Some(100),
+        ..Default::default()
+    };
+    let error = module
+        .instantiate_with_options(core::EmptyResolver::instance(), &options)
+        .unwrap_err();
+    assert_eq!(exceeded(&error), Some(TrapKind::OutOfFuel));
+    assert!(format!("{:#}", error).ends_with("Trap: out of fuel"));
+
+    // Another thread can stop it
+    let interrupt = core::InterruptHandle::new();
+    let options = core::InstanceOptions {
+        interrupt: Some(interrupt.clone()),
+        ..Default::default()
+    };
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        interrupt.interrupt();
+    });
+    let error = module
+        .instantiate_with_options(core::EmptyResolver::instance(), &options)
+        .unwrap_err();
+    interrupter.join().unwrap();
+    assert_eq!(exceeded(&error), Some(TrapKind::Interrupted));
+
+    // What isn't used instantiating is left for the calls
+    let module = core::Module::new(
+        RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![ValueType::I32], vec![]))
+            .add_function(
+                0,
+                vec![],
+                vec![
+                    Instr::Loop(BlockType::None),
+                    Instr::LocalGet(0),
+                    Instr::I32Const(1),
+                    Instr::Op(Opcode::I32Sub),
+                    Instr::LocalTee(0),
+                    Instr::BrIf(0),
+                    Instr::End,
+                ],
+            )
+            .add_memory(1, None)
+            .add_data(0, vec![Instr::I32Const(0)], vec![7; 20])
+            .export_func("spin", 0)
+            .build(),
+    );
+    let options = core::InstanceOptions {
+        fuel: Some(100),
+        ..Default::default()
+    };
+    let mut instance =
+        module.instantiate_with_options(core::EmptyResolver::instance(), &options)?;
+    assert_eq!(instance.fuel(), Some(97));
+
+    // Three times round the loop is five instructions each, and then the end of the function
+    instance.invoke_export("spin", &[3_i32.into()])?;
+    assert_eq!(instance.fuel(), Some(81));
+    let error = instance
+        .invoke_export("spin", &[100_i32.into()])
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(|trap| trap.kind()),
+        Some(TrapKind::OutOfFuel)
+    );
+    assert_eq!(instance.fuel(), Some(0));
+
+    // An interrupt stops the one call, and the next runs as normal
+    instance.set_fuel(None);
+    instance.interrupt_handle().interrupt();
+    let error = instance.invoke_export("spin", &[3_i32.into()]).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(|trap| trap.kind()),
+        Some(TrapKind::Interrupted)
+    );
+    instance.invoke_export("spin", &[3_i32.into()])?;
+
+    Ok(())
+}
+
+// Wraps the system allocator to look through each block that's freed while a test is scanning,
+// and count the ones that still have the secret in them. Without realloc of its own, a block that
+// moves goes through dealloc too. Memory with the guard-pages feature doesn't come from the
+// allocator, so it can't be seen this way.
+#[cfg(not(feature = "guard-pages"))]
+mod freed_secrets {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    pub const SECRET: u64 = 0x5ec2_e75e_c2e7_5ec2;
+
+    static SCANNING: AtomicBool = AtomicBool::new(false);
+    static FREED: AtomicUsize = AtomicUsize::new(0);
+
+    struct ScanningAllocator;
+
+    unsafe impl GlobalAlloc for ScanningAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            if SCANNING.load(Ordering::SeqCst) {
+                let block = std::slice::from_raw_parts(ptr, layout.size());
+                if block.windows(8).any(|bytes| bytes == SECRET.to_le_bytes()) {
+                    FREED.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: ScanningAllocator = ScanningAllocator;
+
+    // How many blocks with the secret in them were freed while f ran
+    pub fn count(f: impl FnOnce() -> anyhow::Result<()>) -> anyhow::Result<usize> {
+        let before = FREED.load(Ordering::SeqCst);
+        SCANNING.store(true, Ordering::SeqCst);
+        let result = f();
+        SCANNING.store(false, Ordering::SeqCst);
+        result?;
+        Ok(FREED.load(Ordering::SeqCst) - before)
+    }
+}
+
+#[cfg(not(feature = "guard-pages"))]
+#[test]
+fn test_zero_on_drop() -> Result<()> {
+    use freed_secrets::SECRET;
+
+    let module = core::Module::new(
+        RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+            .add_function(0, vec![], vec![Instr::LocalGet(0), Instr::MemoryGrow])
+            .add_memory(1, Some(4))
+            .add_global(
+                GlobalType::new(ValueType::I64, MutableType::Var),
+                vec![Instr::I64Const(0)],
+            )
+            .export_func("grow", 0)
+            .export_memory("memory", 0)
+            .build(),
+    );
+    let run = |options: &core::InstanceOptions| {
+        freed_secrets::count(|| {
+            let mut instance =
+                module.instantiate_with_options(core::EmptyResolver::instance(), options)?;
+            instance.memories[0].borrow_mut().write_u64(100, SECRET)?;
+            instance.globals[0].borrow_mut().set_value(SECRET.into())?;
+            // The memory starts off with no room to spare, so this moves it
+            instance.invoke_export("grow", &[1_i32.into()])?;
+            Ok(())
+        })
+    };
+
+    // The scan does find the secret when it's left behind, in the memory before and after it
+    // grew and in the global
+    assert!(run(&core::InstanceOptions::default())? >= 3);
+    let options = core::InstanceOptions {
+        zero_on_drop: true,
+        ..Default::default()
+    };
+    assert_eq!(run(&options)?, 0);
+
+    // A memory the host held on to outlives the instance, and is zeroed when it goes
+    let freed = freed_secrets::count(|| {
+        let instance =
+            module.instantiate_with_options(core::EmptyResolver::instance(), &options)?;
+        instance.memories[0].borrow_mut().write_u64(8, SECRET)?;
+        let memory = instance.exports["memory"].as_memory().unwrap().clone();
+        drop(instance);
+        assert_eq!(memory.borrow().read_u64(8)?, SECRET);
+        Ok(())
+    })?;
+    assert_eq!(freed, 0);
+
+    // An imported memory is left alone while something else still has it
+    let module = core::Module::new(
+        RawModuleBuilder::new()
+            .import_memory("env", "memory", 1, None)
+            .build(),
+    );
+    let resolver = GrownMemoryResolver {
+        memory: Rc::new(RefCell::new(Memory::new_from_bounds(1, None))),
+    };
+    let instance = module.instantiate_with_options(&resolver, &options)?;
+    instance.memories[0].borrow_mut().write_u64(8, SECRET)?;
+    drop(instance);
+    assert_eq!(resolver.memory.borrow().read_u64(8)?, SECRET);
+    assert!(!resolver.memory.borrow().zero_on_drop());
+
+    Ok(())
+}
+
+// A module that's mostly one big data segment, like one with embedded assets
+fn make_asset_module(asset_size: usize) -> RawModule {
+    let asset: Vec<u8> = (0..asset_size).map(|idx| (idx % 251) as u8).collect();
+    let pages = asset_size / WASM_PAGE_SIZE_IN_BYTES + 1;
+    RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(
+            0,
+            vec![],
+            vec![Instr::LocalGet(0), Instr::Memory(Opcode::I32Load8U, 0, 0)],
+        )
+        .add_memory(pages, None)
+        .add_data(0, vec![Instr::I32Const(0)], asset)
+        .export_func("peek", 0)
+        .build()
+}
+
+#[test]
+fn test_module_shares_its_buffer() -> Result<()> {
+    let bytes = make_asset_module(1 << 20).encode();
+    let module = core::Module::load_module_from_bytes(&bytes)?;
+
+    // The data segment is a slice of the module as it was read in, rather than a copy of it
+    let usage = module.approx_memory_usage();
+    assert!(usage > bytes.len());
+    assert!(usage < bytes.len() + 4096, "{} bytes", usage);
+
+    let mut instance = module.instantiate(core::EmptyResolver::instance())?;
+    let results = instance.invoke_export("peek", &[1000.into()])?;
+    assert_eq!(results, [(1000 % 251).into()]);
+    Ok(())
+}
+
+#[test]
+fn test_component_core_modules() -> Result<()> {
+    use wasm::component::{self, Component, ComponentItem, ComponentItemKind};
+    use wasm::writer::WriterUtil;
+
+    fn section(out: &mut Vec<u8>, id: u8, payload: &[u8]) -> Result<()> {
+        out.write_u8(id)?;
+        out.write_leb_usize(payload.len())?;
+        out.extend_from_slice(payload);
+        Ok(())
+    }
+    fn name(out: &mut Vec<u8>, name: &str) -> Result<()> {
+        out.write_u8(0x00)?;
+        out.write_leb_usize(name.len())?;
+        out.extend_from_slice(name.as_bytes());
+        Ok(())
+    }
+
+    let mut bytes = component::COMPONENT_HEADER.to_vec();
+    section(&mut bytes, 0, b"\x04noteignored")?;
+    section(&mut bytes, 1, &make_asset_module(300).encode())?;
+
+    // One import of an instance, with type 0, and one of a value of type string
+    let mut imports = vec![2];
+    name(&mut imports, "wasi:cli/stdout")?;
+    imports.extend_from_slice(&[0x05, 0x00]);
+    name(&mut imports, "greeting")?;
+    imports.extend_from_slice(&[0x02, 0x01, 0x73]);
+    section(&mut bytes, 10, &imports)?;
+
+    // A canon section, which is skipped, then exports of a func and the core module
+    section(&mut bytes, 8, &[1, 0x00, 0x00, 0x00, 0x00])?;
+    let mut exports = vec![2];
+    name(&mut exports, "peek")?;
+    exports.extend_from_slice(&[0x01, 0x00, 0x00]);
+    name(&mut exports, "assets")?;
+    exports.extend_from_slice(&[0x00, 0x11, 0x00, 0x00]);
+    section(&mut bytes, 11, &exports)?;
+
+    // Loading it as a core module says what it is
+    assert!(component::is_component(&bytes));
+    let error = core::Module::load_module_from_bytes(&bytes).unwrap_err();
+    assert!(format!("{}", error).contains("This is a component"));
+
+    let component = Component::read(&bytes)?;
+    assert_eq!(component.core_modules.len(), 1);
+    assert_eq!(
+        component.imports,
+        [
+            ComponentItem {
+                name: "wasi:cli/stdout".to_string(),
+                kind: ComponentItemKind::Instance
+            },
+            ComponentItem {
+                name: "greeting".to_string(),
+                kind: ComponentItemKind::Value
+            }
+        ]
+    );
+    assert_eq!(component.exports[0].kind, ComponentItemKind::Func);
+    assert_eq!(component.exports[1].kind, ComponentItemKind::CoreModule);
+    assert_eq!(
+        format!("{}", component),
+        "Component with 1 core module and 0 nested components\n  \
+         import wasi:cli/stdout (instance)\n  import greeting (value)\n  \
+         export peek (func)\n  export assets (core module)\n"
+    );
+
+    // The core module runs on its own
+    let module = core::Module::new(component.core_modules[0].clone());
+    let mut instance = module.instantiate(core::EmptyResolver::instance())?;
+    assert_eq!(instance.invoke_export("peek", &[260.into()])?, [9.into()]);
+
+    // A component nested in it is read too, and a cut off section is an error
+    let mut outer = component::COMPONENT_HEADER.to_vec();
+    section(&mut outer, 4, &bytes)?;
+    let outer_component = Component::read(&outer)?;
+    assert_eq!(outer_component.core_modules.len(), 0);
+    assert_eq!(outer_component.components[0].exports.len(), 2);
+    outer.truncate(outer.len() - 1);
+    assert!(Component::read(&outer).is_err());
+    assert!(Component::read(&make_asset_module(1).encode()).is_err());
+
+    Ok(())
+}
+
+// A file in the temporary directory that's removed when it's dropped, so a test that fails part
+// way through doesn't leave it behind
+struct TempFile {
+    path: std::path::PathBuf,
+}
+
+impl TempFile {
+    fn new(name: &str, contents: &[u8]) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents)?;
+        Ok(TempFile { path })
+    }
+
+    fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // A test can remove the file itself
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+#[test]
+fn test_load_module_from_mmap() -> Result<()> {
+    let bytes = make_asset_module(4 << 20).encode();
+    let file = TempFile::new("wasm_mmap_test.wasm", &bytes)?;
+    let path = file.path();
+
+    let read = core::Module::load_module_from_path(path)?;
+    // Nothing writes to the file while it's mapped, it's only removed
+    let mapped = unsafe { core::Module::load_module_from_mmap(path)? };
+
+    // The mapped module has none of the file on the heap
+    assert!(read.approx_memory_usage() > bytes.len());
+    assert!(mapped.approx_memory_usage() < 4096);
+
+    // The mapping outlives the module while an instance still has the functions
+    let mut instance = mapped.instantiate(core::EmptyResolver::instance())?;
+    drop(mapped);
+    std::fs::remove_file(path)?;
+    let results = instance.invoke_export("peek", &[123_456.into()])?;
+    assert_eq!(results, [(123_456 % 251).into()]);
+
+    assert!(unsafe { core::Module::load_module_from_mmap(path) }.is_err());
+    Ok(())
+}
+
+// A benchmark of reading a module with a 64MB data segment from a file against mapping it. Run it
+// with `cargo test --release --features mmap -- --ignored --nocapture test_mmap_load_throughput`.
+#[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+#[test]
+#[ignore]
+fn test_mmap_load_throughput() -> Result<()> {
+    let file = TempFile::new(
+        "wasm_mmap_bench.wasm",
+        &make_asset_module(64 << 20).encode(),
+    )?;
+
+    let start = std::time::Instant::now();
+    let read = core::Module::load_module_from_path(file.path())?;
+    let read_time = start.elapsed();
+    let start = std::time::Instant::now();
+    // Nothing writes to the file while it's mapped
+    let mapped = unsafe { core::Module::load_module_from_mmap(file.path())? };
+    let map_time = start.elapsed();
+
+    println!(
+        "read: {:?}, {} bytes of heap; mapped: {:?}, {} bytes of heap",
+        read_time,
+        read.approx_memory_usage(),
+        map_time,
+        mapped.approx_memory_usage()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_out_of_bounds_trap_details() -> Result<()> {
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![]))
+        .add_memory(1, None)
+        .add_table(16, None)
+        .add_elem(0, vec![Instr::I32Const(0)], vec![0, 1])
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::I32Const(0),
+                Instr::Memory(Opcode::I32Store, 2, 0xfe),
+            ],
+        )
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::I32Const(0),
+                Instr::LocalGet(0),
+                Instr::CallIndirect(0),
+            ],
+        )
+        .export_func("store", 0)
+        .export_func("call", 1)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+
+    instance.invoke_export("store", &[0xfe00_i32.into()])?;
+    let error = instance
+        .invoke_export("store", &[0xff0e_i32.into()])
+        .unwrap_err();
+    let trap = error.downcast_ref::<Trap>().unwrap();
+    assert_eq!(
+        trap.kind(),
+        TrapKind::MemoryOutOfBounds {
+            mem_idx: 0,
+            address: 0xff0e + 0xfe,
+            width: 4,
+            write: true,
+            memory_size: WASM_PAGE_SIZE_IN_BYTES,
+        }
+    );
+    assert_eq!(
+        format!("{}", error),
+        "Trap: out of bounds write of 4 bytes at 0x0001_000c, memory size 0x0001_0000 \
+         (func 0, offset 0x4)"
+    );
+
+    // The same goes for the host's accesses, which don't have a site
+    let error = instance.memories[0]
+        .borrow()
+        .read_u16(WASM_PAGE_SIZE_IN_BYTES - 1)
+        .unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "Trap: out of bounds read of 2 bytes at 0x0000_ffff, memory size 0x0001_0000"
+    );
+
+    // Calling through the table past its end, or through an empty slot
+    let error = instance
+        .invoke_export("call", &[17_i32.into()])
+        .unwrap_err();
+    assert_eq!(
+        format!("{}", error),
+        "Trap: undefined element 17, table size 16 (func 1, offset 0x4)"
+    );
+    let error = instance.invoke_export("call", &[2_i32.into()]).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Trap>().map(Trap::kind),
+        Some(TrapKind::UninitializedElement(2))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_trap_snapshot() -> Result<()> {
+    use core::{SnapshotLimits, StackSnapshot};
+
+    // outer keeps a value on the stack under the call to inner, which traps
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_type(FuncType::new(
+            vec![ValueType::I32, ValueType::I32],
+            vec![ValueType::I32],
+        ))
+        .add_function(
+            0,
+            vec![ValueType::I64],
+            vec![
+                Instr::I64Const(-2),
+                Instr::LocalSet(1),
+                Instr::I32Const(7),
+                Instr::LocalGet(0),
+                Instr::I32Const(-1),
+                Instr::Call(1),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        .add_function(1, vec![], vec![Instr::I32Const(99), Instr::Unreachable])
+        .export_func("outer", 0)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+
+    fn snapshot(error: &anyhow::Error) -> Option<StackSnapshot> {
+        error
+            .downcast_ref::<Trap>()
+            .and_then(|trap| trap.snapshot().cloned())
+    }
+
+    // Nothing is kept unless it's asked for
+    let error = instance
+        .invoke_export("outer", &[5_i32.into()])
+        .unwrap_err();
+    assert_eq!(snapshot(&error), None);
+
+    instance.set_trap_snapshots(Some(SnapshotLimits::default()));
+    let error = instance
+        .invoke_export("outer", &[5_i32.into()])
+        .unwrap_err();
+    let full = snapshot(&error).unwrap();
+    assert_eq!(full.frames.len(), 2);
+    assert_eq!(full.frames[0].func_idx, Some(1));
+    assert_eq!(full.frames[0].locals, vec![5_i32.into(), (-1_i32).into()]);
+    assert_eq!(full.frames[0].operands, vec![99_i32.into()]);
+    assert_eq!(full.frames[1].func_idx, Some(0));
+    assert_eq!(full.frames[1].locals, vec![5_i32.into(), (-2_i64).into()]);
+    assert_eq!(full.frames[1].operands, vec![7_i32.into()]);
+
+    assert_eq!(
+        full.report(None),
+        "stack snapshot: 2 frames
+#0 func 1
+    params: [i32 5, i32 -1]
+    locals: []
+    operands: [i32 99]
+#1 func 0
+    params: [i32 5]
+    locals: [i64 -2]
+    operands: [i32 7]
+"
+    );
+
+    // Within the limits, it keeps the first locals, the top operands and the innermost frames
+    instance.set_trap_snapshots(Some(SnapshotLimits {
+        max_frames: 1,
+        max_values: 1,
+    }));
+    let error = instance
+        .invoke_export("outer", &[5_i32.into()])
+        .unwrap_err();
+    let limited = snapshot(&error).unwrap();
+    assert_eq!(limited.omitted_frames, 1);
+    assert_eq!(limited.frames[0].locals, vec![5_i32.into()]);
+    assert_eq!(limited.frames[0].omitted_locals, 1);
+    assert!(limited
+        .report(None)
+        .ends_with("    params: [i32 5, ... 1 more]\n    locals: []\n    operands: [i32 99]\n... 1 more frames\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_memory_watchpoints() -> Result<()> {
+    use core::{AccessSite, MemoryAccess, WatchKind};
+
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .add_memory(1, None)
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::I32Const(0x100),
+                Instr::I32Const(1),
+                Instr::Memory(Opcode::I32Store, 2, 0),
+            ],
+        )
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::I32Const(0x1000),
+                Instr::I32Const(0x1122_3344),
+                Instr::Memory(Opcode::I32Store, 2, 0x40),
+            ],
+        )
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::I32Const(0x1042),
+                Instr::Memory(Opcode::I32Load, 0, 0),
+            ],
+        )
+        .export_func("tidy", 0)
+        .export_func("scribble", 1)
+        .export_func("peek", 2)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+
+    // Only the last byte of the store is in the watched range
+    let accesses: Rc<RefCell<Vec<MemoryAccess>>> = Rc::new(RefCell::new(Vec::new()));
+    let seen = accesses.clone();
+    instance.memories[0].borrow_mut().add_watchpoint(
+        0x1043..0x1044,
+        WatchKind::Write,
+        move |access| {
+            seen.borrow_mut().push(access.clone());
+            Ok(())
+        },
+    );
+
+    instance.invoke_export("tidy", &[])?;
+    assert!(accesses.borrow().is_empty());
+    instance.invoke_export("scribble", &[])?;
+    instance.invoke_export("peek", &[])?;
+    assert_eq!(
+        *accesses.borrow(),
+        vec![MemoryAccess {
+            kind: WatchKind::Write,
+            address: 0x1040,
+            bytes: vec![0x44, 0x33, 0x22, 0x11],
+            // After two i32.consts of three and six bytes
+            site: AccessSite {
+                func_idx: Some(1),
+                instruction_offset: Some(9),
+            },
+        }]
+    );
+
+    // The host's writes are seen too, without a site
+    accesses.borrow_mut().clear();
+    instance.memories[0].borrow_mut().write_u8(0x1043, 0xff)?;
+    assert_eq!(accesses.borrow()[0].site, AccessSite::default());
+
+    // A watchpoint that fails stops the guest at the access
+    instance.memories[0]
+        .borrow_mut()
+        .add_watchpoint(0x1000..0x1100, WatchKind::Read, |access| {
+            Err(anyhow!("Read of {:#x}", access.address))
+        });
+    let error = instance.invoke_export("peek", &[]).unwrap_err();
+    assert_eq!(format!("{}", error), "Read of 0x1042");
+
+    instance.memories[0].borrow_mut().clear_watchpoints();
+    assert_eq!(
+        instance.invoke_export("peek", &[])?,
+        vec![0xff22_i32.into()]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_memory_io_cursors() -> Result<()> {
+    use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+    let json = br#"{"name":"probe","count":0,"ok":true}"#;
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .add_memory(1, None)
+        .add_data(0, vec![Instr::I32Const(0x100)], json.to_vec())
+        // Writes the count over the 0
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::I32Const(0x100),
+                Instr::I32Const(i32::from(b'7')),
+                Instr::Memory(Opcode::I32Store8, 0, 24),
+            ],
+        )
+        .add_function(
+            1,
+            vec![],
+            vec![Instr::I32Const(0x200), Instr::Memory(Opcode::I32Load, 2, 0)],
+        )
+        .export_func("count", 0)
+        .export_func("first_word", 1)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+    instance.invoke_export("count", &[])?;
+
+    // Parsing the fields one at a time, straight out of the guest's memory
+    let fields = {
+        let memory = instance.memories[0].borrow();
+        let reader = memory.reader(0x100, json.len())?;
+        BufReader::with_capacity(4, reader)
+            .split(b',')
+            .collect::<io::Result<Vec<_>>>()?
+    };
+    assert_eq!(
+        fields,
+        vec![
+            br#"{"name":"probe""#.to_vec(),
+            br#""count":7"#.to_vec(),
+            br#""ok":true}"#.to_vec(),
+        ]
+    );
+
+    // Holding the Ref itself, and seeking about within the region
+    let mut reader = core::MemReader::new(instance.memories[0].borrow(), 0x100, json.len())?;
+    reader.seek(SeekFrom::End(-5))?;
+    let mut tail = String::new();
+    reader.read_to_string(&mut tail)?;
+    assert_eq!(tail, "true}");
+    assert_eq!(reader.read(&mut [0; 4])?, 0);
+    assert!(reader.seek(SeekFrom::Current(-100)).is_err());
+    reader.seek(SeekFrom::Start(u64::from(u32::max_value())))?;
+    assert_eq!(reader.read(&mut [0; 4])?, 0);
+    drop(reader);
+
+    // Copying a file into memory, for the guest to read
+    let file = TempFile::new("memory_io.bin", &[0x44, 0x33, 0x22, 0x11, 0x55])?;
+    {
+        let mut memory = instance.memories[0].borrow_mut();
+        let mut writer = memory.writer(0x200, 8)?;
+        let copied = io::copy(&mut std::fs::File::open(file.path())?, &mut writer)?;
+        assert_eq!(copied, 5);
+        assert_eq!(writer.remaining(), 3);
+
+        // Whatever doesn't fit in the region is cut short
+        assert_eq!(writer.write(b"abcdef")?, 3);
+        assert_eq!(writer.write(b"g")?, 0);
+        let error = writer.write_all(b"h").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+    }
+    drop(file);
+    assert_eq!(
+        instance.invoke_export("first_word", &[])?,
+        vec![0x1122_3344_i32.into()]
+    );
+    let mut after = [0; 4];
+    instance.memories[0].borrow().get_data(0x205, &mut after)?;
+    assert_eq!(&after, b"abc\0");
+
+    // The region has to be in the memory to begin with
+    let memory = instance.memories[0].borrow();
+    let error = memory.reader(WASM_PAGE_SIZE_IN_BYTES - 4, 8).err().unwrap();
+    assert!(error.downcast_ref::<Trap>().is_some());
+    assert!(memory.reader(WASM_PAGE_SIZE_IN_BYTES - 4, 4).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_memory_write_trace() -> Result<()> {
+    use core::{first_write_mismatch, AccessSite, WriteRecord, WriteTraceOptions};
+
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![]))
+        .add_memory(1, None)
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::I32Const(0x1000),
+                Instr::LocalGet(0),
+                Instr::Memory(Opcode::I32Store, 2, 0x40),
+                Instr::I32Const(0x2000),
+                Instr::LocalGet(0),
+                Instr::Memory(Opcode::I32Store8, 0, 0),
+            ],
+        )
+        .export_func("scribble", 0)
+        .build();
+    let module = core::Module::new(raw);
+
+    // Runs scribble with each value and traces what it and the host write
+    let run = |values: &[u32]| -> Result<(Vec<WriteRecord>, usize)> {
+        let mut instance = module.instantiate(core::EmptyResolver::instance())?;
+        let records: Rc<RefCell<Vec<WriteRecord>>> = Rc::new(RefCell::new(Vec::new()));
+        let batches = Rc::new(RefCell::new(0));
+        let (sink_records, sink_batches) = (records.clone(), batches.clone());
+        let options = WriteTraceOptions {
+            max_bytes: 8,
+            batch_size: 3,
+        };
+        instance.memories[0].borrow_mut().start_write_trace(
+            move |batch: &[WriteRecord]| {
+                sink_records.borrow_mut().extend_from_slice(batch);
+                *sink_batches.borrow_mut() += 1;
+                Ok(())
+            },
+            options,
+        )?;
+
+        for value in values {
+            instance.invoke_export("scribble", &[(*value).into()])?;
+        }
+        instance.memories[0]
+            .borrow_mut()
+            .set_data(0x3000, &[0xab; 100])?;
+        instance.memories[0].borrow_mut().stop_write_trace()?;
+
+        let records = records.borrow().clone();
+        let batches = *batches.borrow();
+        Ok((records, batches))
+    };
+
+    let (left, batches) = run(&[0x1122_3344, 7])?;
+    // Four stores and the host's write, handed over three and then two at a time
+    assert_eq!(left.len(), 5);
+    assert_eq!(batches, 2);
+    assert_eq!(
+        left[0],
+        WriteRecord {
+            seq: 0,
+            // After an i32.const of three bytes and a local.get of two
+            site: AccessSite {
+                func_idx: Some(0),
+                instruction_offset: Some(5),
+            },
+            address: 0x1040,
+            len: 4,
+            bytes: vec![0x44, 0x33, 0x22, 0x11],
+            hash: left[0].hash,
+        }
+    );
+    assert_eq!((left[1].address, &left[1].bytes[..]), (0x2000, &[0x44][..]));
+    assert_eq!(left[3].seq, 3);
+
+    // The host's write has no site, and only its first bytes are kept
+    let host = &left[4];
+    assert_eq!(host.site, AccessSite::default());
+    assert_eq!((host.len, host.bytes.len()), (100, 8));
+    assert_eq!(WriteRecord::from_line(&host.to_line())?, *host);
+
+    // The same run writes the same things
+    let (same, _) = run(&[0x1122_3344, 7])?;
+    assert_eq!(first_write_mismatch(left.clone(), same), None);
+
+    // Storing a different value is found at the first store it changes
+    let (right, _) = run(&[0x1122_3344, 8])?;
+    let mismatch = first_write_mismatch(left.clone(), right.clone()).unwrap();
+    assert_eq!(mismatch.index, 2);
+    assert_eq!(mismatch.right.unwrap().bytes, vec![8, 0, 0, 0]);
+
+    // So is a run that stops early
+    let (short, _) = run(&[0x1122_3344])?;
+    let mismatch = first_write_mismatch(left, short).unwrap();
+    assert_eq!(mismatch.index, 2);
+    assert_eq!(mismatch.right.unwrap().address, 0x3000);
+
+    Ok(())
+}
+
+#[test]
+fn test_memory_grow_keeps_contents() -> Result<()> {
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(0, vec![], vec![Instr::LocalGet(0), Instr::MemoryGrow])
+        .add_memory(1, None)
+        .export_memory("memory", 0)
+        .export_func("grow", 0)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+    let memory = instance.exports["memory"].as_memory().unwrap().clone();
+    memory.borrow_mut().write_u32(100, 0x1234_5678)?;
+
+    let data_ptr = memory.borrow_mut().data_ptr();
+    for pages in 1..64 {
+        let results = instance.invoke_export("grow", &[3.into()])?;
+        assert_eq!(results, [(1 + (pages - 1) * 3).into()]);
+
+        // Only the reserved backend promises that the memory stays where it is
+        if memory.borrow().has_stable_address() {
+            assert_eq!(memory.borrow_mut().data_ptr(), data_ptr);
+        }
+    }
+
+    let memory = memory.borrow();
+    assert_eq!(memory.size_pages(), 1 + 63 * 3);
+    assert_eq!(memory.read_u32(100)?, 0x1234_5678);
+    assert!(memory.data()[WASM_PAGE_SIZE_IN_BYTES..]
+        .iter()
+        .all(|byte| *byte == 0));
+    Ok(())
+}
+
+#[cfg(all(
+    feature = "guard-pages",
+    any(unix, windows),
+    target_pointer_width = "64"
+))]
+#[test]
+fn test_reserved_memory() -> Result<()> {
+    let mut memory = Memory::new_from_bounds(1, Some(4));
+    assert!(memory.has_stable_address());
+    let data_ptr = memory.data_ptr();
+
+    memory.grow_by(3)?;
+    assert_eq!(memory.data_ptr(), data_ptr);
+    assert_eq!(memory.size_bytes(), 4 * WASM_PAGE_SIZE_IN_BYTES);
+    memory.write_u64(4 * WASM_PAGE_SIZE_IN_BYTES - 8, !0)?;
+
+    // The reservation only goes as far as the maximum, and the bounds are still checked
+    assert!(memory.grow_by(1).is_err());
+    assert!(memory.read_u8(4 * WASM_PAGE_SIZE_IN_BYTES).is_err());
+
+    // Lots of memories that could each be 4 GB are only address space until they're used
+    let memories: Vec<_> = (0..16).map(|_| Memory::new_from_bounds(1, None)).collect();
+    assert!(memories.iter().all(|memory| memory.has_stable_address()));
+    Ok(())
+}
+
+#[test]
+fn test_fork_instance() -> Result<()> {
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .add_memory(1, None)
+        .add_table(2, None)
+        .add_global(
+            GlobalType::new(ValueType::I32, MutableType::Var),
+            vec![Instr::I32Const(0)],
+        )
+        // Counts up in the global, and keeps each count in memory
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::GlobalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Add),
+                Instr::GlobalSet(0),
+                Instr::GlobalGet(0),
+                Instr::I32Const(4),
+                Instr::Op(Opcode::I32Mul),
+                Instr::GlobalGet(0),
+                Instr::Memory(Opcode::I32Store, 2, 0),
+                Instr::GlobalGet(0),
+            ],
+        )
+        .add_function(0, vec![], vec![Instr::I32Const(100)])
+        .add_elem(0, vec![Instr::I32Const(0)], vec![0])
+        .export_func("bump", 0)
+        .export_memory("memory", 0)
+        .export_table("table", 0)
+        .export_global("count", 0)
+        .build();
+    let mut original = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+    original.invoke_export("bump", &[])?;
+
+    let mut fork = original.fork()?;
+    original.invoke_export("bump", &[])?;
+    assert_eq!(fork.invoke_export("bump", &[])?, vec![2_i32.into()]);
+    assert_eq!(fork.invoke_export("bump", &[])?, vec![3_i32.into()]);
+    fork.tables[0].borrow_mut()[1] = Some(fork.functions[1].clone());
+
+    // Each side only sees its own changes
+    let count = |instance: &core::Instance| -> Result<StackEntry> {
+        let global = instance.exports["count"].as_global().unwrap().clone();
+        let value = *global.borrow().get_value();
+        Ok(value)
+    };
+    assert_eq!(count(&original)?, 2_i32.into());
+    assert_eq!(count(&fork)?, 3_i32.into());
+
+    let memory = |instance: &core::Instance| -> Result<u32> {
+        let memory = instance.exports["memory"].as_memory().unwrap().clone();
+        let value = memory.borrow().read_u32(12)?;
+        Ok(value)
+    };
+    assert_eq!(memory(&original)?, 0);
+    assert_eq!(memory(&fork)?, 3);
+
+    assert!(original.tables[0].borrow()[1].is_none());
+    assert!(fork.exports["table"].as_table().unwrap().borrow()[1].is_some());
+    // The code itself is shared
+    assert!(Rc::ptr_eq(&original.functions[0], &fork.functions[0]));
+
+    // An imported memory can't be copied
+    let raw = RawModuleBuilder::new()
+        .import_memory("env", "memory", 1, None)
+        .build();
+    let instance = core::Module::new(raw).instantiate(&LimitsResolver {})?;
+    assert_eq!(
+        format!("{}", instance.fork().unwrap_err()),
+        "Can't fork an instance that imports a memory env::memory, since its state isn't the \
+         instance's to copy"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_canonical_abi() -> Result<()> {
+    use wasm::canonical_abi::CanonicalAbi;
+
+    let var_i32 = GlobalType::new(ValueType::I32, MutableType::Var);
+    let raw = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32; 4], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![ValueType::I32; 2], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![ValueType::I32], vec![]))
+        .add_memory(1, None)
+        // The next free address, and how many times results have been freed
+        .add_global(var_i32.clone(), vec![Instr::I32Const(1024)])
+        .add_global(var_i32, vec![Instr::I32Const(0)])
+        // A bump allocator that never frees anything
+        .add_function(
+            0,
+            vec![ValueType::I32],
+            vec![
+                Instr::GlobalGet(0),
+                Instr::LocalGet(2),
+                Instr::Op(Opcode::I32Add),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Sub),
+                Instr::I32Const(0),
+                Instr::LocalGet(2),
+                Instr::Op(Opcode::I32Sub),
+                Instr::Op(Opcode::I32And),
+                Instr::LocalTee(4),
+                Instr::LocalGet(3),
+                Instr::Op(Opcode::I32Add),
+                Instr::GlobalSet(0),
+                Instr::LocalGet(4),
+            ],
+        )
+        // Sums a list of u32s
+        .add_function(
+            1,
+            vec![ValueType::I32],
+            vec![
+                Instr::Block(BlockType::None),
+                Instr::Loop(BlockType::None),
+                Instr::LocalGet(1),
+                Instr::Op(Opcode::I32Eqz),
+                Instr::BrIf(1),
+                Instr::LocalGet(2),
+                Instr::LocalGet(0),
+                Instr::Memory(Opcode::I32Load, 2, 0),
+                Instr::Op(Opcode::I32Add),
+                Instr::LocalSet(2),
+                Instr::LocalGet(0),
+                Instr::I32Const(4),
+                Instr::Op(Opcode::I32Add),
+                Instr::LocalSet(0),
+                Instr::LocalGet(1),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Sub),
+                Instr::LocalSet(1),
+                Instr::Br(0),
+                Instr::End,
+                Instr::End,
+                Instr::LocalGet(2),
+            ],
+        )
+        // Returns the string it was given, through a return area at 16
+        .add_function(
+            1,
+            vec![],
+            vec![
+                Instr::I32Const(16),
+                Instr::LocalGet(0),
+                Instr::Memory(Opcode::I32Store, 2, 0),
+                Instr::I32Const(16),
+                Instr::LocalGet(1),
+                Instr::Memory(Opcode::I32Store, 2, 4),
+                Instr::I32Const(16),
+            ],
+        )
+        .add_function(
+            2,
+            vec![],
+            vec![
+                Instr::GlobalGet(1),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Add),
+                Instr::GlobalSet(1),
+            ],
+        )
+        .export_memory("memory", 0)
+        .export_func("cabi_realloc", 0)
+        .export_func("sum", 1)
+        .export_func("echo", 2)
+        .export_func("cabi_post_echo", 3)
+        .export_global("freed", 1)
+        .build();
+    let mut instance = core::Module::new(raw).instantiate(core::EmptyResolver::instance())?;
+    let mut abi = CanonicalAbi::new(&mut instance)?;
+
+    let (ptr, len) = abi.lower_list::<u32>(&[10, 20, 30])?;
+    assert_eq!((ptr, len), (1024, 3));
+    let sum = abi
+        .instance()
+        .invoke_export("sum", &[ptr.into(), len.into()])?;
+    assert_eq!(sum, vec![60_u32.into()]);
+
+    // A string makes it there and back, and is freed afterwards
+    let (ptr, len) = abi.lower_string("héllo")?;
+    assert_eq!((ptr, len), (1036, 6));
+    let results = abi
+        .instance()
+        .invoke_export("echo", &[ptr.into(), len.into()])?;
+    let (ptr, len) = abi.read_pair(u32::try_from(results[0])?)?;
+    assert_eq!(abi.lift_string(ptr, len)?, "héllo");
+    abi.post_return("echo", &results)?;
+    let freed = abi.instance().exports["freed"].as_global().unwrap().clone();
+    assert_eq!(*freed.borrow().get_value(), 1_i32.into());
+
+    // Lists are aligned to their elements
+    let (ptr, _) = abi.lower_list_u8(&[1, 2, 3])?;
+    assert_eq!(ptr, 1042);
+    let (ptr, len) = abi.lower_list::<u64>(&[u64::max_value(), 7])?;
+    assert_eq!((ptr, len), (1048, 2));
+    assert_eq!(abi.lift_list::<u64>(ptr, len)?, vec![u64::max_value(), 7]);
+    assert_eq!(abi.lift_list::<u8>(1042, 3)?, vec![1, 2, 3]);
+
+    assert!(abi.lift_list::<u16>(1043, 1).is_err());
+    assert!(abi.lift_list_u8(0xffff, 2).is_err());
+    assert!(abi.lift_string(1048, 8).is_err());
+
+    let error = CanonicalAbi::with_allocator(&mut instance, "malloc")
+        .err()
+        .unwrap();
+    assert_eq!(
+        format!("{}", error),
+        "The module doesn't export an allocator malloc"
+    );
+
+    Ok(())
+}
+
+// Makes a new host function or memory for every import, of whatever type was asked for, and
+// counts how many it has made
+#[derive(Default)]
+struct CountingResolver {
+    functions: Cell<usize>,
+    memories: Cell<usize>,
+}
+
+impl core::Resolver for CountingResolver {
+    fn resolve_function(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        func_type: &FuncType,
+    ) -> Result<Rc<RefCell<Callable>>> {
+        self.functions.set(self.functions.get() + 1);
+        let mut host = HostAdd::new();
+        host.func_type = func_type.clone();
+        Ok(Rc::new(RefCell::new(Callable::from_host(Rc::new(host)))))
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _table_type: &TableType,
+    ) -> Result<Rc<RefCell<Table>>> {
+        Err(anyhow!("Imported table {}:{} not found", mod_name, name))
+    }
+    fn resolve_memory(
+        &self,
+        _mod_name: &str,
+        _name: &str,
+        mem_type: &MemType,
+    ) -> Result<Rc<RefCell<Memory>>> {
+        self.memories.set(self.memories.get() + 1);
+        Ok(Rc::new(RefCell::new(Memory::new(mem_type.clone()))))
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Rc<RefCell<Global>>> {
+        Err(anyhow!("Imported global {}:{} not found", mod_name, name))
+    }
+}
+
+#[test]
+fn test_caching_resolver() -> Result<()> {
+    let resolver = core::CachingResolver::with_policy(CountingResolver::default(), |_, _, kind| {
+        kind != ExportKind::Memory
+    });
+    let made = |resolver: &core::CachingResolver<CountingResolver>| {
+        let inner = resolver.inner();
+        (inner.functions.get(), inner.memories.get())
+    };
+
+    // The same function imported twice
+    let i32_module = core::Module::new(
+        RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![ValueType::I32; 2], vec![ValueType::I32]))
+            .import_func("env", "add", 0)
+            .import_func("env", "add", 0)
+            .import_memory("env", "memory", 1, None)
+            .build(),
+    );
+    let first = i32_module.instantiate(&resolver)?;
+    let second = i32_module.instantiate(&resolver)?;
+    assert_eq!(made(&resolver), (1, 2));
+    assert!(Rc::ptr_eq(&first.functions[0], &first.functions[1]));
+    assert!(Rc::ptr_eq(&first.functions[0], &second.functions[0]));
+    // Memories aren't shared, by the policy
+    assert!(!Rc::ptr_eq(&first.memories[0], &second.memories[0]));
+
+    // The cached function doesn't fit an import of another type, which gets one of its own
+    let i64_module = core::Module::new(
+        RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![ValueType::I64; 2], vec![ValueType::I64]))
+            .import_func("env", "add", 0)
+            .build(),
+    );
+    let wide = i64_module.instantiate(&resolver)?;
+    assert_eq!(made(&resolver), (2, 2));
+    assert_eq!(
+        format!("{}", wide.functions[0].borrow().func_type()),
+        "(i64, i64) -> i64"
+    );
+    let third = i32_module.instantiate(&resolver)?;
+    assert!(Rc::ptr_eq(&first.functions[0], &third.functions[0]));
+
+    resolver.invalidate();
+    let fourth = i32_module.instantiate(&resolver)?;
+    assert_eq!(made(&resolver), (3, 4));
+    assert!(!Rc::ptr_eq(&first.functions[0], &fourth.functions[0]));
+
+    Ok(())
+}
+
+#[test]
+fn test_wasi_command_and_reactor() -> Result<()> {
+    use wasm::wasi::{self, WasiCtx, WasiModuleKind};
+
+    let var_i32 = GlobalType::new(ValueType::I32, MutableType::Var);
+    // Exports one function that sets the global to 1 and calls proc_exit with the given code,
+    // under each of the given names, and one that returns the global
+    let make_module = |names: &[&str], code: Option<i32>| {
+        let mut body = vec![Instr::I32Const(1), Instr::GlobalSet(0)];
+        if let Some(code) = code {
+            body.extend(vec![Instr::I32Const(code), Instr::Call(0)]);
+        }
+        let mut builder = RawModuleBuilder::new()
+            .add_type(FuncType::new(vec![ValueType::I32], vec![]))
+            .add_type(FuncType::new(vec![], vec![]))
+            .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+            .import_func(wasi::WASI_MODULE, "proc_exit", 0)
+            .add_global(var_i32.clone(), vec![Instr::I32Const(0)])
+            .add_function(1, vec![], body)
+            .add_function(2, vec![], vec![Instr::GlobalGet(0)])
+            .export_func("get", 2);
+        for name in names {
+            builder = builder.export_func(name, 1);
+        }
+        core::Module::new(builder.build())
+    };
+    let instantiate = |module: &core::Module| wasi::instantiate(module, Rc::new(WasiCtx::new()));
+
+    // A command's exit code comes back, and returning from _start is exiting with 0
+    let mut command = instantiate(&make_module(&["_start"], Some(3)))?;
+    assert_eq!(wasi::module_kind(&command)?, WasiModuleKind::Command);
+    assert_eq!(command.invoke_export("get", &[])?, vec![0_i32.into()]);
+    assert_eq!(wasi::run_command(&mut command)?, 3);
+    let mut command = instantiate(&make_module(&["_start"], None))?;
+    assert_eq!(wasi::run_command(&mut command)?, 0);
+
+    // A reactor is initialized as it's instantiated
+    let mut reactor = instantiate(&make_module(&["_initialize"], None))?;
+    assert_eq!(wasi::module_kind(&reactor)?, WasiModuleKind::Reactor);
+    assert_eq!(reactor.invoke_export("get", &[])?, vec![1_i32.into()]);
+    assert_eq!(
+        format!("{}", wasi::run_command(&mut reactor).unwrap_err()),
+        "The module is a reactor, so it doesn't have a _start to run"
+    );
+    assert!(instantiate(&make_module(&["_initialize"], Some(1))).is_err());
+
+    let mut library = instantiate(&make_module(&[], None))?;
+    assert_eq!(wasi::module_kind(&library)?, WasiModuleKind::Other);
+    assert!(wasi::run_command(&mut library).is_err());
+
+    assert!(instantiate(&make_module(&["_start", "_initialize"], None)).is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "phase-trace")]
+#[test]
+fn test_phase_spans() -> Result<()> {
+    use core::{Field, FieldValue, ImportObject, Phase, PhaseSubscriber};
+
+    #[derive(Default)]
+    struct Recorder {
+        records: RefCell<Vec<(String, Vec<Field>)>>,
+    }
+
+    impl PhaseSubscriber for Recorder {
+        fn enter(&self, phase: Phase, fields: &[Field]) {
+            let record = (format!("enter {}", phase), fields.to_vec());
+            self.records.borrow_mut().push(record);
+        }
+
+        fn event(&self, phase: Phase, name: &'static str, fields: &[Field]) {
+            let record = (format!("{} {}", phase, name), fields.to_vec());
+            self.records.borrow_mut().push(record);
+        }
+
+        fn exit(&self, phase: Phase, fields: &[Field]) {
+            let record = (format!("exit {}", phase), fields.to_vec());
+            self.records.borrow_mut().push(record);
+        }
+    }
+
+    let provider = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_function(
+            0,
+            vec![],
+            vec![
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::Op(Opcode::I32Add),
+            ],
+        )
+        .export_func("add_one", 0)
+        .build();
+    let provider = core::Module::new(provider).instantiate(core::EmptyResolver::instance())?;
+    let mut imports = ImportObject::new();
+    imports.define_instance("provider", &provider);
+
+    let user = RawModuleBuilder::new()
+        .add_type(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![], vec![ValueType::I32]))
+        .add_type(FuncType::new(vec![], vec![]))
+        .import_func("provider", "add_one", 0)
+        .add_function(1, vec![], vec![Instr::I32Const(1), Instr::Call(0)])
+        .add_function(2, vec![], vec![])
+        .add_memory(1, None)
+        .add_data(0, vec![Instr::I32Const(0)], vec![1, 2, 3])
+        .export_func("run", 1)
+        .start(2)
+        .build()
+        .encode();
+
+    let recorder = Rc::new(Recorder::default());
+    core::set_phase_subscriber(Some(recorder.clone()));
+    let module = core::Module::load_module_from_bytes(&user)?;
+    let options = core::InstanceOptions {
+        fuel: Some(100),
+        ..core::InstanceOptions::default()
+    };
+    let mut instance = module.instantiate_with_options(&imports, &options)?;
+    assert_eq!(instance.invoke_export("run", &[])?, [2_i32.into()]);
+    assert!(instance.invoke_export("missing", &[]).is_err());
+    core::set_phase_subscriber(None);
+
+    let records = recorder.records.replace(Vec::new());
+    let names: Vec<&str> = records.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "enter wasm.decode",
+            "exit wasm.decode",
+            "enter wasm.instantiate",
+            "enter wasm.link",
+            "wasm.link import",
+            "exit wasm.link",
+            "wasm.instantiate elements",
+            "wasm.instantiate data",
+            "wasm.instantiate start",
+            "enter wasm.call",
+            "exit wasm.call",
+            "exit wasm.instantiate",
+            "enter wasm.call",
+            "exit wasm.call",
+        ]
+    );
+
+    let field = |idx: usize, name: &str| core::find_field(&records[idx].1, name).cloned();
+    let u64_field = |value| Some(FieldValue::U64(value));
+    let str_field = |value: &str| Some(FieldValue::Str(value.to_string()));
+    assert_eq!(field(0, "size"), u64_field(user.len() as u64));
+    assert_eq!(field(1, "outcome"), str_field("ok"));
+    assert_eq!(field(1, "functions"), u64_field(2));
+    assert_eq!(field(1, "imports"), u64_field(1));
+    assert_eq!(field(2, "functions"), u64_field(3));
+    assert_eq!(field(4, "module"), str_field("provider"));
+    assert_eq!(field(4, "name"), str_field("add_one"));
+    assert_eq!(field(4, "kind"), str_field("function"));
+    assert_eq!(field(7, "bytes"), u64_field(3));
+    assert_eq!(field(8, "func_idx"), u64_field(2));
+    assert_eq!(field(8, "deferred"), Some(FieldValue::Bool(false)));
+    assert_eq!(field(11, "outcome"), str_field("ok"));
+
+    // The call's fuel is one for each instruction, in both functions
+    assert_eq!(field(12, "name"), str_field("run"));
+    assert_eq!(field(12, "func_idx"), u64_field(1));
+    assert_eq!(field(13, "outcome"), str_field("returned"));
+    assert_eq!(field(13, "fuel_consumed"), u64_field(5));
+
+    // With no subscriber, nothing more is heard
+    instance.invoke_export("run", &[])?;
+    assert_eq!(recorder.records.borrow().len(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_instance_pool() -> Result<()> {
+    use core::{InstancePool, PoolConfig, PoolExhausted, PoolExhaustion};
+
+    let module = core::Module::new(make_pool_test_module(1));
+    let pool = InstancePool::new(PoolConfig {
+        instances: 2,
+        memory_pages: 2,
+        ..PoolConfig::default()
+    });
+    let resolver = core::EmptyResolver::instance();
+
+    let mut first = pool.instantiate(&module, resolver)?;
+    let second = pool.instantiate(&module, resolver)?;
+    assert_eq!(pool.available(), 0);
+    assert!(first.memories[0].borrow().has_stable_address());
+
+    // With every slot taken, the pool is exhausted
+    let error = pool.instantiate(&module, resolver).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<PoolExhausted>(),
+        Some(&PoolExhausted::Instances(2))
+    );
+
+    // The memory can grow up to the pool's size and no further
+    assert_eq!(first.invoke_export("grow", &[1.into()])?, [1.into()]);
+    assert_eq!(first.invoke_export("grow", &[1.into()])?, [(-1).into()]);
+    first.invoke_export("store", &[0x1ff00.into(), 7.into()])?;
+    assert_eq!(first.invoke_export("sum", &[3.into()])?, [3.into()]);
+
+    // A slot goes back when its instance is dropped, with the memory that was used zeroed, and the
+    // next instance sees only its own data segment
+    drop(first);
+    assert_eq!(pool.available(), 1);
+    let mut third = pool.instantiate(&module, resolver)?;
+    assert_eq!(third.memories[0].borrow().current_size(), 1);
+    assert_eq!(third.invoke_export("grow", &[1.into()])?, [1.into()]);
+    assert_eq!(third.memories[0].borrow().read_i32(0x1ff00)?, 0);
+    assert_eq!(third.invoke_export("sum", &[2.into()])?, [3.into()]);
+    drop(second);
+    drop(third);
+
+    // A memory kept after its instance is gone keeps its buffer until it's dropped too
+    let instance = pool.instantiate(&module, resolver)?;
+    let kept = instance.memories[0].clone();
+    drop(instance);
+    assert_eq!(pool.available(), 2);
+    assert_eq!(pool.available_memories(), 1);
+    drop(kept);
+    assert_eq!(pool.available_memories(), 2);
+
+    // A module whose memory starts bigger than the pool's can't come from it, and doesn't use up a
+    // slot by trying
+    let big = core::Module::new(make_pool_test_module(3));
+    let error = pool.instantiate(&big, resolver).unwrap_err();
+    assert_eq!(
+        format!("{:#}", error),
+        "Memory 0 needs 3 pages: Memories from the pool can't be bigger than 2 pages"
+    );
+    assert_eq!(pool.available(), 2);
+    assert_eq!(pool.available_memories(), 2);
+
+    // A blocking pool waits for another thread to give a slot back
+    let blocking = InstancePool::new(PoolConfig {
+        instances: 1,
+        memory_pages: 1,
+        exhaustion: PoolExhaustion::Block(Some(std::time::Duration::from_millis(10))),
+        ..PoolConfig::default()
+    });
+    let held = blocking.instantiate(&module, resolver)?;
+    let error = blocking.instantiate(&module, resolver).unwrap_err();
+    assert!(error.downcast_ref::<PoolExhausted>().is_some());
+
+    let waiting = InstancePool::new(PoolConfig {
+        exhaustion: PoolExhaustion::Block(None),
+        ..blocking.config().clone()
+    });
+    let bytes = make_pool_test_module(1).encode();
+    let holder = {
+        let waiting = waiting.clone();
+        let bytes = bytes.clone();
+        std::thread::spawn(move || -> Result<()> {
+            let module = core::Module::load_module_from_bytes(&bytes)?;
+            let instance = waiting.instantiate(&module, core::EmptyResolver::instance())?;
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            drop(instance);
+            Ok(())
+        })
+    };
+    while waiting.available() != 0 {
+        std::thread::yield_now();
+    }
+    let mut instance = waiting.instantiate(&module, resolver)?;
+    holder.join().unwrap()?;
+    assert_eq!(instance.invoke_export("sum", &[2.into()])?, [3.into()]);
+    drop(held);
+
+    Ok(())
+}